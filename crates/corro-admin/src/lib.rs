@@ -1,23 +1,27 @@
-use std::{fmt::Display, time::Duration};
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
 
 use camino::Utf8PathBuf;
-use corro_agent::agent::clear_overwritten_versions;
+use corro_agent::{agent::clear_overwritten_versions, api::public::resync_schema};
 use corro_types::{
     actor::{ActorId, ClusterId},
     agent::{Agent, Bookie, KnownVersion, LockKind, LockMeta, LockState},
     base::Version,
     broadcast::{FocaCmd, FocaInput},
+    pubsub::Handoff,
     sqlite::SqlitePoolError,
     sync::generate_sync,
 };
 use futures::{SinkExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use spawn::spawn_counted;
+use spawn::{spawn_named, Shutdown};
 use time::OffsetDateTime;
 use tokio::{
     net::{UnixListener, UnixStream},
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     task::block_in_place,
 };
 use tokio_serde::{formats::Json, Framed};
@@ -52,7 +56,7 @@ pub fn start_server(
 
     let ln = UnixListener::bind(&config.listen_path)?;
 
-    spawn_counted(async move {
+    spawn_named("admin_listener", Shutdown::Graceful, async move {
         loop {
             let stream = tokio::select! {
                 accept_res = ln.accept() => match accept_res {
@@ -69,7 +73,8 @@ pub fn start_server(
                 }
             };
 
-            spawn_counted({
+            // connections can be long-lived (e.g. `tail`), don't hold up shutdown
+            spawn_named("admin_connection", Shutdown::Abortable, {
                 let agent = agent.clone();
                 let bookie = bookie.clone();
                 let config = config.clone();
@@ -94,6 +99,9 @@ pub enum Command {
     Cluster(ClusterCommand),
     Actor(ActorCommand),
     CompactEmpties,
+    Tail,
+    Schema(SchemaCommand),
+    Subs(SubsCommand),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -101,6 +109,17 @@ pub enum SyncCommand {
     Generate,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SchemaCommand {
+    Resync,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SubsCommand {
+    Dump,
+    Handoff(Handoff),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClusterCommand {
     Rejoin,
@@ -112,6 +131,7 @@ pub enum ClusterCommand {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ActorCommand {
     Version { actor_id: ActorId, version: Version },
+    Gaps,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -312,6 +332,7 @@ async fn handle_conn(
                                     "id": actor_id,
                                     "state": state,
                                     "rtts": rtts,
+                                    "schema_drift": members.schema_drift.get(actor_id),
                                 })
                             })
                             .collect::<Vec<_>>()
@@ -421,6 +442,94 @@ async fn handle_conn(
                         }
                     }
 
+                    send_success(&mut stream).await;
+                }
+                Command::Actor(ActorCommand::Gaps) => {
+                    let gaps = agent.gaps().lock().gaps(Instant::now());
+                    match serde_json::to_value(gaps) {
+                        Ok(j) => _ = send(&mut stream, Response::Json(j)).await,
+                        Err(e) => {
+                            _ = send_error(&mut stream, e).await;
+                            continue;
+                        }
+                    }
+
+                    send_success(&mut stream).await;
+                }
+                Command::Schema(SchemaCommand::Resync) => {
+                    info_log(&mut stream, "resyncing schema with the database...").await;
+
+                    let resync = match resync_schema(&agent).await {
+                        Ok(resync) => resync,
+                        Err(e) => {
+                            send_error(&mut stream, format!("could not resync schema: {e}")).await;
+                            continue;
+                        }
+                    };
+
+                    match serde_json::to_value(&resync) {
+                        Ok(json) => send(&mut stream, Response::Json(json)).await,
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                    send_success(&mut stream).await;
+                }
+                Command::Subs(SubsCommand::Dump) => {
+                    // only the JSON is printed, it's meant to be saved to a file
+                    let definitions = agent.subs_manager().definitions();
+                    match serde_json::to_value(&definitions) {
+                        Ok(json) => send(&mut stream, Response::Json(json)).await,
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                    send_success(&mut stream).await;
+                }
+                Command::Subs(SubsCommand::Handoff(handoff)) => {
+                    let addr = handoff.addr;
+                    let moved = agent.subs_manager().hand_off(handoff);
+                    info_log(
+                        &mut stream,
+                        format!("handed off {moved} subscriptions to {addr}"),
+                    )
+                    .await;
+                    send_success(&mut stream).await;
+                }
+                Command::Tail => {
+                    info_log(&mut stream, "tailing replication activity...").await;
+
+                    let mut rx = agent.activity().subscribe();
+                    loop {
+                        let activity = match rx.recv().await {
+                            Ok(activity) => activity,
+                            Err(broadcast::error::RecvError::Lagged(n)) => {
+                                send_log(
+                                    &mut stream,
+                                    LogLevel::Warn,
+                                    format!("tail is lagging, skipped {n} events"),
+                                )
+                                .await;
+                                continue;
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        };
+
+                        let level = if activity.is_error() {
+                            LogLevel::Error
+                        } else {
+                            LogLevel::Info
+                        };
+
+                        if let Err(e) = stream
+                            .send(Response::Log {
+                                level,
+                                msg: activity.kind.to_string(),
+                                ts: activity.ts,
+                            })
+                            .await
+                        {
+                            debug!("tail connection went away: {e}");
+                            break;
+                        }
+                    }
+
                     send_success(&mut stream).await;
                 }
             },
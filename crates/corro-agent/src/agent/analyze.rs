@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use corro_types::agent::Agent;
+use metrics::{counter, histogram};
+use rusqlite::{Connection, OptionalExtension};
+use tokio::{task::block_in_place, time::interval};
+use tracing::{debug, error, info};
+use tripwire::Tripwire;
+
+/// Tables are analyzed again once their number of rows changed by this
+/// fraction since they were last analyzed...
+const STALE_RATIO: f64 = 0.25;
+/// ...and by at least this many rows, so small tables aren't always stale
+const MIN_ROWS_CHANGED: i64 = 1000;
+
+/// Rows of each index looked at by ANALYZE, keeping it short on the write
+/// connection for tables of any size
+const ANALYSIS_LIMIT: u32 = 1000;
+
+/// Periodically refreshes the query planner's statistics (`sqlite_stat1`)
+/// of tables whose number of rows changed a lot since they were last
+/// analyzed. Stale tables are found with a read connection, the write
+/// connection is only used to analyze them.
+pub async fn analyze_loop(agent: Agent, mut tripwire: Tripwire) {
+    let every = agent.config().db.analyze_interval_secs;
+    if every == 0 {
+        debug!("statistics are never refreshed, db.analyze_interval_secs is 0");
+        return;
+    }
+
+    let mut interval = interval(Duration::from_secs(every));
+    // the first tick completes immediately, let the agent start first
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = &mut tripwire => break,
+        }
+
+        let tables: Vec<String> = agent.schema().read().tables.keys().cloned().collect();
+
+        let stale = match agent.pool().read().await {
+            Ok(conn) => block_in_place(|| stale_tables(&conn, &tables)),
+            Err(e) => {
+                error!("could not acquire read connection to check statistics: {e}");
+                continue;
+            }
+        };
+        let stale = match stale {
+            Ok(stale) if stale.is_empty() => continue,
+            Ok(stale) => stale,
+            Err(e) => {
+                error!("could not check statistics: {e}");
+                continue;
+            }
+        };
+
+        let conn = match agent.pool().write_low().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("could not acquire write connection to analyze tables: {e}");
+                continue;
+            }
+        };
+
+        let start = Instant::now();
+        match block_in_place(|| analyze(&conn, &stale)) {
+            Ok(()) => {
+                let elapsed = start.elapsed();
+                info!(
+                    "refreshed statistics of {} tables in {elapsed:?}",
+                    stale.len()
+                );
+                counter!("corro.db.analyze.tables").increment(stale.len() as u64);
+                histogram!("corro.db.analyze.seconds").record(elapsed);
+            }
+            Err(e) => error!("could not analyze tables: {e}"),
+        }
+    }
+}
+
+/// Tables which were never analyzed, or whose number of rows changed a lot
+/// since they were
+pub fn stale_tables(conn: &Connection, tables: &[String]) -> rusqlite::Result<Vec<String>> {
+    let has_stats: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = 'sqlite_stat1')",
+        [],
+        |row| row.get(0),
+    )?;
+
+    let mut stale = vec![];
+    for table in tables {
+        let count: i64 = conn
+            .prepare_cached(&format!("SELECT count(*) FROM \"{table}\""))?
+            .query_row([], |row| row.get(0))?;
+
+        // the first number of a table's stats is its number of rows
+        let analyzed: Option<i64> = if has_stats {
+            conn.prepare_cached("SELECT stat FROM sqlite_stat1 WHERE tbl = ? LIMIT 1")?
+                .query_row([table], |row| row.get::<_, String>(0))
+                .optional()?
+                .and_then(|stat| stat.split(' ').next()?.parse().ok())
+        } else {
+            None
+        };
+
+        let is_stale = match analyzed {
+            None => count > 0,
+            Some(analyzed) => {
+                let changed = (count - analyzed).abs();
+                changed >= MIN_ROWS_CHANGED && changed as f64 > analyzed as f64 * STALE_RATIO
+            }
+        };
+        if is_stale {
+            debug!("statistics of {table} are stale: {count} rows, analyzed with {analyzed:?}");
+            stale.push(table.clone());
+        }
+    }
+
+    Ok(stale)
+}
+
+/// Analyzes `tables` with a bounded number of rows per index. Connections
+/// load the new statistics when they (re)load the schema, matchers of new
+/// subscriptions use them right away.
+pub fn analyze(conn: &Connection, tables: &[String]) -> rusqlite::Result<()> {
+    conn.pragma_update(None, "analysis_limit", ANALYSIS_LIMIT)?;
+    let res = tables
+        .iter()
+        .try_for_each(|table| conn.execute_batch(&format!("ANALYZE \"{table}\";")))
+        .and_then(|_| conn.execute_batch("PRAGMA optimize;"));
+    conn.pragma_update(None, "analysis_limit", 0)?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_tables() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE small (id INTEGER PRIMARY KEY, name TEXT);
+             CREATE INDEX small_name ON small (name);
+             CREATE TABLE big (id INTEGER PRIMARY KEY, name TEXT);
+             CREATE INDEX big_name ON big (name);
+             CREATE TABLE empty (id INTEGER PRIMARY KEY);
+             INSERT INTO small (name) VALUES ('a'), ('b');
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000)
+                 INSERT INTO big (name) SELECT 'name-' || (i % 10) FROM n;",
+        )?;
+        let tables = vec!["small".to_string(), "big".to_string(), "empty".to_string()];
+
+        assert_eq!(stale_tables(&conn, &tables)?, vec!["small", "big"]);
+
+        analyze(&conn, &["small".to_string(), "big".to_string()])?;
+        assert!(stale_tables(&conn, &tables)?.is_empty());
+
+        // a few more rows don't make stats stale, doubling a table does
+        conn.execute_batch(
+            "INSERT INTO small (name) VALUES ('c');
+             INSERT INTO big (name) SELECT name FROM big;",
+        )?;
+        assert_eq!(stale_tables(&conn, &tables)?, vec!["big"]);
+
+        Ok(())
+    }
+}
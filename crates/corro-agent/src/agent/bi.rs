@@ -71,15 +71,16 @@ pub fn spawn_bipayload_handler(
                                 Ok(b) => {
                                     match BiPayload::read_from_buffer(&b) {
                                         Ok(payload) => {
-                                            match payload {
-                                                BiPayload::V1 {
-                                                    data:
-                                                        BiPayloadV1::SyncStart {
-                                                            actor_id,
-                                                            trace_ctx,
-                                                        },
+                                            match payload.into_data() {
+                                                (
+                                                    Some(BiPayloadV1::SyncStart {
+                                                        actor_id,
+                                                        trace_ctx,
+                                                        compression,
+                                                        rejections,
+                                                    }),
                                                     cluster_id,
-                                                } => {
+                                                ) => {
                                                     trace!(
                                                         "framed read buffer len: {}",
                                                         framed.read_buffer().len()
@@ -87,8 +88,15 @@ pub fn spawn_bipayload_handler(
 
                                                     // println!("got sync state: {state:?}");
                                                     if let Err(e) = serve_sync(
-                                                        &agent, &bookie, actor_id, trace_ctx,
-                                                        cluster_id, framed, tx,
+                                                        &agent,
+                                                        &bookie,
+                                                        actor_id,
+                                                        trace_ctx,
+                                                        compression,
+                                                        rejections,
+                                                        cluster_id,
+                                                        framed,
+                                                        tx,
                                                     )
                                                     .await
                                                     {
@@ -96,6 +104,10 @@ pub fn spawn_bipayload_handler(
                                                     }
                                                     break;
                                                 }
+                                                (None, _) => {
+                                                    // sent by a newer version
+                                                    counter!("corro.peer.payload.unknown", "type" => "bi").increment(1);
+                                                }
                                             }
                                         }
 
@@ -1,8 +1,14 @@
-use crate::agent::RANDOM_NODES_CHOICES;
-use corro_types::{agent::SplitPool, config::DEFAULT_GOSSIP_PORT};
+use crate::{agent::RANDOM_NODES_CHOICES, net::can_reach};
+use corro_types::{
+    agent::SplitPool,
+    config::{GossipConfig, DEFAULT_GOSSIP_PORT},
+};
 
 use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
-use std::{collections::HashSet, net::SocketAddr};
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 use tokio::task::block_in_place;
 use tracing::{debug, error, warn};
 use trust_dns_resolver::{
@@ -12,11 +18,11 @@ use trust_dns_resolver::{
 
 /// Apply the user-provided set of bootstrap nodes
 pub async fn generate_bootstrap(
-    bootstrap: &[String],
+    gossip: &GossipConfig,
     our_addr: SocketAddr,
     pool: &SplitPool,
 ) -> eyre::Result<Vec<SocketAddr>> {
-    let mut addrs = match resolve_bootstrap(bootstrap, our_addr).await {
+    let mut addrs = match resolve_bootstrap(gossip, our_addr).await {
         Ok(addrs) => addrs,
         Err(e) => {
             warn!("could not resolve bootstraps, falling back to in-db nodes: {e}");
@@ -35,10 +41,10 @@ pub async fn generate_bootstrap(
                 node_addrs
                     .flatten()
                     .flat_map(|addr| addr.parse())
-                    .filter(|addr| match (our_addr, addr) {
-                        (SocketAddr::V6(our_ip), SocketAddr::V6(ip)) if our_ip != *ip => true,
-                        (SocketAddr::V4(our_ip), SocketAddr::V4(ip)) if our_ip != *ip => true,
-                        _ => {
+                    .filter(|addr| {
+                        if is_candidate(gossip, our_addr, *addr) {
+                            true
+                        } else {
                             debug!("ignore node with addr: {addr}");
                             false
                         }
@@ -55,13 +61,24 @@ pub async fn generate_bootstrap(
         .choose_multiple(&mut rng, RANDOM_NODES_CHOICES))
 }
 
-async fn resolve_bootstrap(
-    bootstrap: &[String],
+/// Whether a peer can be bootstrapped with: not ourselves and reachable
+/// from the gossip client socket
+fn is_candidate(gossip: &GossipConfig, our_addr: SocketAddr, addr: SocketAddr) -> bool {
+    addr != our_addr && can_reach(gossip.client_addr, gossip.ipv6_only, addr)
+}
+
+/// Resolve the user-provided bootstrap strings (`host:port[@dns_server]`
+/// or plain socket addresses, IPv6 ones in brackets), ignoring our own
+/// address and the ones we can't reach
+pub async fn resolve_bootstrap(
+    gossip: &GossipConfig,
     our_addr: SocketAddr,
 ) -> eyre::Result<HashSet<SocketAddr>> {
     use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
     use trust_dns_resolver::{AsyncResolver, TokioAsyncResolver};
 
+    let bootstrap = &gossip.bootstrap;
+
     let mut addrs = HashSet::new();
 
     if bootstrap.is_empty() {
@@ -71,12 +88,21 @@ async fn resolve_bootstrap(
     let system_resolver = AsyncResolver::tokio_from_system_conf()?;
 
     for s in bootstrap {
-        if let Ok(addr) = s.parse() {
-            addrs.insert(addr);
+        let mut host_port_dns_server = s.split('@');
+        let host_port = host_port_dns_server.next().unwrap();
+        if let Ok(addr) = host_port.parse::<SocketAddr>() {
+            if is_candidate(gossip, our_addr, addr) {
+                addrs.insert(addr);
+            } else {
+                debug!("ignore node with addr: {addr}");
+            }
         } else {
             debug!("attempting to resolve {s}");
-            let mut host_port_dns_server = s.split('@');
-            let mut host_port = host_port_dns_server.next().unwrap().split(':');
+            let (hostname, port) = match host_port.rsplit_once(':') {
+                Some((hostname, port)) => (hostname, port.parse().ok()),
+                None => (host_port, None),
+            };
+            let port = port.unwrap_or(DEFAULT_GOSSIP_PORT);
             let mut resolver = None;
             if let Some(dns_server) = host_port_dns_server.next() {
                 debug!("attempting to use resolver: {dns_server}");
@@ -95,39 +121,36 @@ async fn resolve_bootstrap(
                 )?);
                 debug!("using resolver: {dns_server}");
             }
-            if let Some(hostname) = host_port.next() {
-                debug!("Resolving '{hostname}' to an IP");
+            // peers most likely listen on the same address families as we do
+            let record_types = [
+                (RecordType::A, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+                (
+                    RecordType::AAAA,
+                    SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                ),
+            ]
+            .into_iter()
+            .filter(|(_, any)| can_reach(our_addr, gossip.ipv6_only, *any))
+            .map(|(record_type, _)| record_type);
+
+            for record_type in record_types {
+                debug!("Resolving '{hostname}' to an IP ({record_type})");
                 match resolver
                     .as_ref()
                     .unwrap_or(&system_resolver)
-                    .lookup(
-                        hostname,
-                        if our_addr.is_ipv6() {
-                            RecordType::AAAA
-                        } else {
-                            RecordType::A
-                        },
-                    )
+                    .lookup(hostname, record_type)
                     .await
                 {
                     Ok(response) => {
                         debug!("Successfully resolved things: {response:?}");
-                        let port: u16 = host_port
-                            .next()
-                            .and_then(|p| p.parse().ok())
-                            .unwrap_or(DEFAULT_GOSSIP_PORT);
                         for addr in response.iter().filter_map(|rdata| match rdata {
                             RData::A(ip) => Some(SocketAddr::from((*ip, port))),
                             RData::AAAA(ip) => Some(SocketAddr::from((*ip, port))),
                             _ => None,
                         }) {
-                            match (our_addr, addr) {
-                                (SocketAddr::V4(our_ip), SocketAddr::V4(ip)) if our_ip != ip => {}
-                                (SocketAddr::V6(our_ip), SocketAddr::V6(ip)) if our_ip != ip => {}
-                                _ => {
-                                    debug!("ignore node with addr: {addr}");
-                                    continue;
-                                }
+                            if !is_candidate(gossip, our_addr, addr) {
+                                debug!("ignore node with addr: {addr}");
+                                continue;
                             }
                             addrs.insert(addr);
                         }
@@ -148,3 +171,37 @@ async fn resolve_bootstrap(
 
     Ok(addrs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_bootstrap_ipv6() -> eyre::Result<()> {
+        let our_addr: SocketAddr = "[fdaa::1]:8787".parse()?;
+        let mut gossip: GossipConfig = serde_json::from_value(serde_json::json!({
+            "addr": "[::]:8787",
+            "bootstrap": [
+                "[fdaa::1]:8787",
+                "[fdaa::2]:8787",
+                "[fdaa::3]:8787@[fdaa::53]:53",
+                "10.0.0.1:8787",
+            ],
+        }))?;
+
+        let expected: HashSet<SocketAddr> = [
+            "[fdaa::2]:8787".parse()?,
+            "[fdaa::3]:8787".parse()?,
+            "10.0.0.1:8787".parse()?,
+        ]
+        .into();
+        assert_eq!(resolve_bootstrap(&gossip, our_addr).await?, expected);
+
+        gossip.ipv6_only = true;
+        let expected: HashSet<SocketAddr> =
+            ["[fdaa::2]:8787".parse()?, "[fdaa::3]:8787".parse()?].into();
+        assert_eq!(resolve_bootstrap(&gossip, our_addr).await?, expected);
+
+        Ok(())
+    }
+}
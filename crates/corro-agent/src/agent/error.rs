@@ -1,6 +1,7 @@
 use crate::{api::peer::SyncError, transport::TransportError};
 use corro_types::{
-    agent::ChangeError,
+    actor::ActorId,
+    error::ChangeError,
     sqlite::SqlitePoolError,
     sync::{SyncMessageDecodeError, SyncMessageEncodeError},
 };
@@ -50,6 +51,14 @@ pub enum SyncRecvError {
     UnexpectedEndOfStream,
     #[error("expected sync clock message, received something else")]
     ExpectedClockMessage,
+    #[error("expected sync challenge, received something else")]
+    ExpectedChallenge,
+    #[error("expected sync challenge response, received something else")]
+    ExpectedChallengeResponse,
+    #[error("peer could not prove it is actor {0}")]
+    Unauthenticated(ActorId),
+    #[error("actor {0} is not allowed to sync")]
+    NotAllowed(ActorId),
     #[error("timed out waiting for sync message")]
     TimedOut(#[from] Elapsed),
     #[error("changes channel is closed")]
@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+
+use corro_types::{
+    agent::{Agent, Bookie},
+    gaps::Gap,
+};
+use hyper::{client::HttpConnector, Body, Request};
+use hyper_rustls::HttpsConnector;
+use metrics::{counter, gauge};
+use spawn::spawn_counted;
+use tokio::time::interval;
+use tracing::{debug, warn};
+use tripwire::Tripwire;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Periodically looks for versions remote actors are still missing after
+/// syncing, and alerts when they've been missing for too long.
+pub async fn detect_gaps_loop(agent: Agent, bookie: Bookie, mut tripwire: Tripwire) {
+    let (alert_after, check_interval) = {
+        let config = agent.config();
+        (
+            Duration::from_secs(config.gaps.alert_after_secs),
+            Duration::from_secs(config.gaps.check_interval_secs.max(1)),
+        )
+    };
+    let client: hyper::Client<HttpsConnector<HttpConnector>, Body> = hyper::Client::builder()
+        .build(
+            hyper_rustls::HttpsConnectorBuilder::new()
+                .with_native_roots()
+                .https_or_http()
+                .enable_http1()
+                .build(),
+        );
+
+    let mut interval = interval(check_interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = &mut tripwire => break,
+        }
+
+        let actors: Vec<_> = {
+            let bookie = bookie.read("detect_gaps").await;
+            bookie
+                .iter()
+                .filter(|(actor_id, _)| **actor_id != agent.actor_id())
+                .map(|(actor_id, booked)| (*actor_id, booked.clone()))
+                .collect()
+        };
+
+        let mut needs = Vec::with_capacity(actors.len());
+        for (actor_id, booked) in actors.iter() {
+            let read = booked.read("detect_gaps(booked)").await;
+            needs.push((*actor_id, read.sync_need().clone()));
+        }
+
+        let now = Instant::now();
+        let stale = {
+            let mut gaps = agent.gaps().lock();
+            gaps.retain(|actor_id| actors.iter().any(|(id, _)| id == actor_id));
+            for (actor_id, need) in needs.iter() {
+                gaps.observe(*actor_id, need, now);
+            }
+
+            let all = gaps.gaps(now);
+            gauge!("corro.agent.gaps").set(all.len() as f64);
+            gauge!("corro.agent.gaps.stale")
+                .set(all.iter().filter(|gap| gap.alerted).count() as f64);
+
+            gaps.take_stale(alert_after, now)
+        };
+
+        if stale.is_empty() {
+            continue;
+        }
+
+        for gap in stale.iter() {
+            warn!(
+                actor_id = %gap.actor_id,
+                versions = ?gap.versions,
+                syncs = gap.syncs,
+                "versions have been missing for {:.0}s despite syncing, possible data loss or stuck peer",
+                gap.missing_secs
+            );
+        }
+        counter!("corro.agent.gaps.alerts").increment(stale.len() as u64);
+
+        if let Some(url) = agent.config().gaps.webhook_url.clone() {
+            let client = client.clone();
+            spawn_counted(async move {
+                if let Err(e) = send_webhook(&client, &url, &stale).await {
+                    warn!("could not send gap alert to webhook: {e}");
+                }
+            });
+        }
+    }
+
+    debug!("gap detector is done");
+}
+
+async fn send_webhook(
+    client: &hyper::Client<HttpsConnector<HttpConnector>, Body>,
+    url: &str,
+    gaps: &[Gap],
+) -> eyre::Result<()> {
+    let req = Request::post(url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&serde_json::json!({
+            "alert": "version_gaps",
+            "gaps": gaps,
+        }))?))?;
+
+    let res = tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(req)).await??;
+    if !res.status().is_success() {
+        eyre::bail!("webhook responded with {}", res.status());
+    }
+
+    Ok(())
+}
@@ -6,6 +6,7 @@ use std::{
     cmp,
     collections::VecDeque,
     net::SocketAddr,
+    ops::RangeInclusive,
     time::{Duration, Instant},
 };
 
@@ -15,22 +16,26 @@ use crate::{
     transport::Transport,
 };
 use corro_types::{
+    activity::ActivityKind,
     actor::{Actor, ActorId},
     agent::{Agent, Bookie, SplitPool},
-    base::CrsqlSeq,
+    base::{CrsqlSeq, Version},
+    behind::missing_schema,
     broadcast::{BroadcastInput, BroadcastV1, ChangeSource, ChangeV1, FocaInput},
+    budget::BufferKind,
     channel::CorroReceiver,
+    error::ChangeError,
     members::MemberAddedResult,
     sync::generate_sync,
 };
 
 use bytes::Bytes;
-use foca::Notification;
+use foca::{Codec, Notification};
 use indexmap::IndexMap;
 use metrics::{counter, gauge, histogram};
 use rand::{prelude::IteratorRandom, rngs::StdRng, SeedableRng};
 use rangemap::RangeInclusiveSet;
-use spawn::spawn_counted;
+use spawn::{spawn_counted, spawn_named, Shutdown};
 use tokio::{
     sync::mpsc::Receiver as TokioReceiver,
     task::{block_in_place, JoinSet},
@@ -47,7 +52,7 @@ pub fn spawn_gossipserver_handler(
     tripwire: &Tripwire,
     gossip_server_endpoint: quinn::Endpoint,
 ) {
-    spawn_counted({
+    spawn_named("gossip_server", Shutdown::Graceful, {
         let agent = agent.clone();
         let bookie = bookie.clone();
         let mut tripwire = tripwire.clone();
@@ -89,11 +94,18 @@ pub fn spawn_incoming_connection_handlers(
     let agent = agent.clone();
     let bookie = bookie.clone();
     let tripwire = tripwire.clone();
-    tokio::spawn(async move {
+    spawn_named("incoming_connection", Shutdown::Abortable, async move {
         let remote_addr = connecting.remote_address();
         // let local_ip = connecting.local_ip().unwrap();
         debug!("got a connection from {remote_addr}");
 
+        if !agent.config().gossip.acl.allows_ip(remote_addr.ip()) {
+            debug!("refusing connection from denied address {remote_addr}");
+            counter!("corro.peer.connection.denied").increment(1);
+            // dropping the connection before the handshake completes closes it
+            return;
+        }
+
         let conn = match connecting.await {
             Ok(conn) => conn,
             Err(e) => {
@@ -116,7 +128,7 @@ pub fn spawn_incoming_connection_handlers(
 /// Spawn a single task that accepts chunks from a receiver and
 /// updates cluster member round-trip-times in the agent state.
 pub fn spawn_rtt_handler(agent: &Agent, rtt_rx: TokioReceiver<(SocketAddr, Duration)>) {
-    tokio::spawn({
+    spawn_named("rtt_handler", Shutdown::Abortable, {
         let agent = agent.clone();
         async move {
             let stream = ReceiverStream::new(rtt_rx);
@@ -136,11 +148,13 @@ pub fn spawn_rtt_handler(agent: &Agent, rtt_rx: TokioReceiver<(SocketAddr, Durat
 /// Spawn a single task to listen for `Datagram`s from the transport
 /// and apply FOCA messages to the local SWIM statemachine.
 pub fn spawn_foca_handler(agent: &Agent, tripwire: &Tripwire, conn: &quinn::Connection) {
-    tokio::spawn({
+    spawn_named("foca_datagrams", Shutdown::Abortable, {
         let conn = conn.clone();
         let mut tripwire = tripwire.clone();
         let foca_tx = agent.tx_foca().clone();
+        let agent = agent.clone();
         async move {
+            let mut codec = foca::BincodeCodec(bincode::DefaultOptions::new());
             loop {
                 let b = tokio::select! {
                     b_res = conn.read_datagram() => match b_res {
@@ -160,6 +174,24 @@ pub fn spawn_foca_handler(agent: &Agent, tripwire: &Tripwire, conn: &quinn::Conn
                     }
                 };
 
+                let allowed = {
+                    let config = agent.config();
+                    let acl = &config.gossip.acl;
+                    // actors are only known by decoding the SWIM message header
+                    !acl.filters_actors()
+                        || match codec.decode_header(&b[..]) {
+                            Ok(header) => acl.allows_actor(header.src.id()),
+                            Err(e) => {
+                                debug!("could not decode foca datagram header: {e}");
+                                false
+                            }
+                        }
+                };
+                if !allowed {
+                    counter!("corro.peer.datagram.denied").increment(1);
+                    continue;
+                }
+
                 if let Err(e) = foca_tx.send(FocaInput::Data(b)).await {
                     error!("could not send data foca input: {e}");
                 }
@@ -176,7 +208,7 @@ pub fn spawn_foca_handler(agent: &Agent, tripwire: &Tripwire, conn: &quinn::Conn
 ///
 ///
 pub fn spawn_swim_announcer(agent: &Agent, gossip_addr: SocketAddr) {
-    tokio::spawn({
+    spawn_named("swim_announcer", Shutdown::Abortable, {
         let agent = agent.clone();
         async move {
             let mut boff = backoff::Backoff::new(10)
@@ -189,7 +221,7 @@ pub fn spawn_swim_announcer(agent: &Agent, gossip_addr: SocketAddr) {
                 timer.as_mut().await;
 
                 match bootstrap::generate_bootstrap(
-                    agent.config().gossip.bootstrap.as_slice(),
+                    &agent.config().gossip,
                     gossip_addr,
                     agent.pool(),
                 )
@@ -262,6 +294,10 @@ pub async fn handle_notifications(
         trace!("handle notification");
         match notification {
             Notification::MemberUp(actor) => {
+                if !agent.config().gossip.acl.allows_actor(actor.id()) {
+                    debug!("ignoring denied member {actor:?}");
+                    continue;
+                }
                 let member_added_res = agent.members().write().add_member(&actor);
                 info!("Member Up {actor:?} (result: {member_added_res:?})");
 
@@ -310,6 +346,7 @@ pub async fn handle_notifications(
                 if removed {
                     debug!("Member Down {actor:?}");
                     counter!("corro.gossip.member.removed", "id" => actor.id().0.to_string(), "addr" => actor.addr().to_string()).increment(1);
+                    agent.replay().lock().remove_peer(&actor.addr().ip().to_canonical());
                     // actually removed a member
                     // notify of new cluster size
                     let member_len = { agent.members().read().states.len() as u32 };
@@ -369,7 +406,7 @@ fn db_cleanup(conn: &rusqlite::Connection) -> eyre::Result<()> {
 
 /// See `db_cleanup`
 pub fn spawn_handle_db_cleanup(pool: SplitPool) {
-    tokio::spawn(async move {
+    spawn_named("db_cleanup", Shutdown::Abortable, async move {
         let mut db_cleanup_interval = tokio::time::interval(Duration::from_secs(60 * 15));
         loop {
             db_cleanup_interval.tick().await;
@@ -416,6 +453,21 @@ pub async fn handle_changes(
     const KEEP_SEEN_CACHE_SIZE: usize = 1000;
     let mut seen: IndexMap<_, RangeInclusiveSet<CrsqlSeq>> = IndexMap::new();
 
+    // changes to causal tables are held back for a little while, so they can
+    // be applied in timestamp order, one batch at a time
+    let (causal_tables, causal_window) = {
+        let config = agent.config();
+        (
+            config.db.causal_tables.clone(),
+            Duration::from_millis(config.db.causal_window_ms),
+        )
+    };
+    let mut causal_buf: Vec<(ChangeV1, ChangeSource, Instant)> = vec![];
+    let mut causal_job = JoinSet::new();
+
+    // relays forward new broadcasts to other network segments
+    let relaying = agent.config().gossip.relay.is_some();
+
     // complicated loop to process changes efficiently w/ a max concurrency
     // and a minimum chunk size for bigger and faster SQLite transactions
     loop {
@@ -437,7 +489,8 @@ pub async fn handle_changes(
             }
 
             debug!(count = %tmp_count, "spawning processing multiple changes from beginning of loop");
-            join_set.spawn(util::process_multiple_changes(
+            release_queued(&agent, &buf);
+            join_set.spawn(process_changes(
                 agent.clone(),
                 bookie.clone(),
                 std::mem::take(&mut buf),
@@ -453,8 +506,29 @@ pub async fn handle_changes(
             // but we need to drain it to free up concurrency
             res = join_set.join_next(), if !join_set.is_empty() => {
                 debug!("processed multiple changes concurrently");
-                if let Some(Ok(Err(e))) = res {
-                    error!("could not process multiple changes: {e}");
+                if let Some(Ok((applied, res))) = res {
+                    if let Err(e) = res {
+                        error!("could not process multiple changes: {e}");
+                        agent.activity().publish(ActivityKind::Error {
+                            context: "applying changes".into(),
+                            error: e.to_string(),
+                        });
+                    }
+                    mark_seen(&mut seen, applied);
+                }
+                continue;
+            },
+
+            res = causal_job.join_next(), if !causal_job.is_empty() => {
+                if let Some(Ok((applied, res))) = res {
+                    if let Err(e) = res {
+                        error!("could not process causal changes: {e}");
+                        agent.activity().publish(ActivityKind::Error {
+                            context: "applying causal changes".into(),
+                            error: e.to_string(),
+                        });
+                    }
+                    mark_seen(&mut seen, applied);
                 }
                 continue;
             },
@@ -515,19 +589,21 @@ pub async fn handle_changes(
                 }
 
                 if let Some(recv_lag) = recv_lag {
-                    let src_str: &'static str = src.into();
+                    let src_str: &'static str = (&src).into();
                     histogram!("corro.agent.changes.recv.lag.seconds", "source" => src_str).record(recv_lag.as_secs_f64());
                 }
 
-                // this will only run once for a non-empty changeset
-                for v in change.versions() {
-                    let entry = seen.entry((change.actor_id, v)).or_default();
-                    if let Some(seqs) = change.seqs().cloned() {
-                        entry.extend([seqs]);
-                    }
+                // shed changes are recovered by syncing, but changes from a
+                // sync were explicitly requested from a peer
+                let change_bytes = change.estimated_byte_size();
+                if matches!(src, ChangeSource::Sync | ChangeSource::Ingest) {
+                    agent.budget().reserve(BufferKind::Apply, change_bytes);
+                } else if !agent.budget().try_reserve(BufferKind::Apply, change_bytes) {
+                    counter!("corro.agent.changes.shed").increment(1);
+                    continue;
                 }
 
-                if matches!(src, ChangeSource::Broadcast) && !change.is_empty() {
+                if matches!(src, ChangeSource::Broadcast | ChangeSource::Relay(_) | ChangeSource::Ingest) && !change.is_empty() {
                     if let Err(_e) =
                         agent
                             .tx_bcast()
@@ -535,6 +611,25 @@ pub async fn handle_changes(
                     {
                         debug!("broadcasts are full or done!");
                     }
+
+                    if relaying {
+                        // only new changes are forwarded, so each relay does it at most once
+                        let relayed_by = match &src {
+                            ChangeSource::Relay(relayed_by) => relayed_by.clone(),
+                            _ => vec![],
+                        };
+                        if let Err(_e) = agent.tx_bcast().try_send(BroadcastInput::Relay(
+                            BroadcastV1::Change(change.clone()),
+                            relayed_by,
+                        )) {
+                            debug!("broadcasts are full or done!");
+                        }
+                    }
+                }
+
+                if change.touches_any(&causal_tables) {
+                    causal_buf.push((change, src, Instant::now()));
+                    continue;
                 }
 
                 queue.push_back((change, src, Instant::now()));
@@ -548,14 +643,32 @@ pub async fn handle_changes(
                 gauge!("corro.agent.changes.in_queue").set(count as f64);
                 gauge!("corro.agent.changesets.in_queue").set(queue.len() as f64);
                 gauge!("corro.agent.changes.processing.jobs").set(join_set.len() as f64);
+                gauge!("corro.agent.changesets.causal.buffered").set(causal_buf.len() as f64);
+                agent.load().record_apply_backlog(count);
+
+                // only one causal batch at a time, or they could commit out of order
+                if causal_job.is_empty() {
+                    let ready = take_causal_ready(&mut causal_buf, causal_window);
+                    if !ready.is_empty() {
+                        debug!(count = %ready.len(), "spawning processing causal changes");
+                        release_queued(&agent, &ready);
+                        causal_job.spawn(process_changes(
+                            agent.clone(),
+                            bookie.clone(),
+                            ready,
+                        ));
+                    }
+                }
 
                 if count < max_changes_chunk && !queue.is_empty() && join_set.len() < MAX_CONCURRENT {
                     // we can process this right away
                     debug!(%count, "spawning processing multiple changes from max wait interval");
-                    join_set.spawn(util::process_multiple_changes(
+                    let changes: Vec<_> = queue.drain(..).collect();
+                    release_queued(&agent, &changes);
+                    join_set.spawn(process_changes(
                         agent.clone(),
                         bookie.clone(),
-                        queue.drain(..).collect(),
+                        changes,
                     ));
                     count = 0;
                 }
@@ -582,6 +695,10 @@ pub async fn handle_changes(
     while let Ok((change, src)) = rx_changes.try_recv() {
         let changes_count = std::cmp::max(change.len(), 1);
         counter!("corro.agent.changes.recv").increment(changes_count as u64);
+        if change.touches_any(&causal_tables) {
+            causal_buf.push((change, src, Instant::now()));
+            continue;
+        }
         count += changes_count;
         queue.push_back((change, src, Instant::now()));
         if count >= max_changes_chunk {
@@ -602,10 +719,89 @@ pub async fn handle_changes(
     }
 
     // process the last changes we got!
-    if let Err(e) = util::process_multiple_changes(agent, bookie, queue.into_iter().collect()).await
+    if let Err(e) =
+        util::process_multiple_changes(agent.clone(), bookie.clone(), queue.into_iter().collect())
+            .await
     {
         error!("could not process multiple changes: {e}");
     }
+
+    // let the in-flight causal batch commit first
+    while causal_job.join_next().await.is_some() {}
+
+    if !causal_buf.is_empty() {
+        causal_buf.sort_by_key(|(change, _, _)| change.ts().map(|ts| ts.0));
+        if let Err(e) = util::process_multiple_changes(agent, bookie, causal_buf).await {
+            error!("could not process causal changes: {e}");
+        }
+    }
+}
+
+/// Versions, and their sequences, of a changeset that was applied
+type Applied = (ActorId, RangeInclusive<Version>, Option<RangeInclusive<CrsqlSeq>>);
+
+/// Processes `changes`, handing back the ones to mark as seen. Those that
+/// failed, or are held until the local schema catches up, aren't: a retry,
+/// broadcast again or synced, would be ignored otherwise.
+async fn process_changes(
+    agent: Agent,
+    bookie: Bookie,
+    changes: Vec<(ChangeV1, ChangeSource, Instant)>,
+) -> (Vec<Applied>, Result<(), ChangeError>) {
+    let applied: Vec<Applied> = {
+        let schema = agent.schema().read();
+        changes
+            .iter()
+            .filter(|(change, _, _)| missing_schema(&schema, change.changes()).is_empty())
+            .map(|(change, _, _)| (change.actor_id, change.versions(), change.seqs().cloned()))
+            .collect()
+    };
+
+    match util::process_multiple_changes(agent, bookie, changes).await {
+        Ok(()) => (applied, Ok(())),
+        Err(e) => (vec![], Err(e)),
+    }
+}
+
+fn mark_seen(
+    seen: &mut IndexMap<(ActorId, Version), RangeInclusiveSet<CrsqlSeq>>,
+    applied: Vec<Applied>,
+) {
+    for (actor_id, versions, seqs) in applied {
+        for v in versions {
+            let entry = seen.entry((actor_id, v)).or_default();
+            if let Some(seqs) = seqs.clone() {
+                entry.extend([seqs]);
+            }
+        }
+    }
+}
+
+/// Queued changes are accounted for in the memory budget until they're
+/// handed over to be applied
+fn release_queued(agent: &Agent, changes: &[(ChangeV1, ChangeSource, Instant)]) {
+    agent.budget().release(
+        BufferKind::Apply,
+        changes
+            .iter()
+            .map(|(change, _, _)| change.estimated_byte_size())
+            .sum(),
+    );
+}
+
+/// Takes buffered changes in timestamp order, stopping at the first one that
+/// hasn't waited long enough: changes with an earlier timestamp could still
+/// be on their way.
+fn take_causal_ready(
+    buf: &mut Vec<(ChangeV1, ChangeSource, Instant)>,
+    window: Duration,
+) -> Vec<(ChangeV1, ChangeSource, Instant)> {
+    buf.sort_by_key(|(change, _, _)| change.ts().map(|ts| ts.0));
+    let ready = buf
+        .iter()
+        .take_while(|(_, _, buffered_at)| buffered_at.elapsed() >= window)
+        .count();
+    buf.drain(..ready).collect()
 }
 
 /// Start a new sync with multiple other nodes
@@ -644,7 +840,15 @@ pub async fn handle_sync(
                 .collect::<Vec<(ActorId, u8, SocketAddr)>>()
         };
 
-        if candidates.is_empty() {
+        let relay_peer = agent.config().gossip.relay.as_ref().and_then(|relay| {
+            relay
+                .peers
+                .iter()
+                .choose(&mut StdRng::from_entropy())
+                .copied()
+        });
+
+        if candidates.is_empty() && relay_peer.is_none() {
             return Ok(());
         }
 
@@ -672,6 +876,9 @@ pub async fn handle_sync(
         choices
             .into_iter()
             .map(|(actor_id, _, addr)| (actor_id, addr))
+            // relays also sync with a relay of another segment, its actor ID is
+            // only known once it sends its sync state
+            .chain(relay_peer.map(|addr| (ActorId::default(), addr)))
             .collect()
     };
 
@@ -680,10 +887,19 @@ pub async fn handle_sync(
         return Ok(());
     }
 
+    agent.activity().publish_with(|| ActivityKind::SyncStarted {
+        peers: chosen.iter().map(|(actor_id, _)| *actor_id).collect(),
+    });
+
     let start = Instant::now();
     let n = parallel_sync(agent, transport, chosen.clone(), sync_state).await?;
 
     let elapsed = start.elapsed();
+    agent.gaps().lock().record_sync();
+    agent.activity().publish(ActivityKind::SyncCompleted {
+        changes: n,
+        elapsed_secs: elapsed.as_secs_f64(),
+    });
     if n > 0 {
         info!(
             "synced {n} changes w/ {} in {}s @ {} changes/s",
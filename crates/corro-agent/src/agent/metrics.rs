@@ -1,7 +1,8 @@
 use crate::transport::Transport;
-use corro_types::{actor::ActorId, agent::Agent};
-use metrics::gauge;
-use std::time::Duration;
+use corro_types::{actor::ActorId, agent::Agent, history::MinuteBucket};
+use metrics::{gauge, histogram};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
 use tokio::task::block_in_place;
 use tracing::error;
 
@@ -15,9 +16,26 @@ pub async fn metrics_loop(agent: Agent, transport: Transport) {
     }
 }
 
+/// Interval between two measures of the scheduler delay
+const SCHEDULER_DELAY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Measures how late a sleeping task is woken up, which grows when the
+/// runtime's workers are saturated
+pub async fn scheduler_delay_loop(agent: Agent) {
+    loop {
+        let start = Instant::now();
+        tokio::time::sleep(SCHEDULER_DELAY_INTERVAL).await;
+        let delay = start.elapsed().saturating_sub(SCHEDULER_DELAY_INTERVAL);
+
+        histogram!("corro.runtime.scheduler.delay.seconds").record(delay.as_secs_f64());
+        agent.load().record_scheduler_delay(delay);
+    }
+}
+
 pub fn collect_metrics(agent: &Agent, transport: &Transport) {
     agent.pool().emit_metrics();
     transport.emit_metrics();
+    agent.budget().emit_metrics();
 
     let schema = agent.schema().read();
 
@@ -78,3 +96,41 @@ pub fn collect_metrics(agent: &Agent, transport: &Transport) {
         }
     }
 }
+
+/// Writes the history table's bucket of the previous minute, every minute
+pub async fn history_loop(agent: Agent, retention: Duration) {
+    // align buckets on wall clock minutes
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    tokio::time::sleep(Duration::from_secs((60 - now % 60) as u64)).await;
+
+    // counts from before the first full minute are discarded
+    agent.history().take();
+
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let bucket = agent.history().take();
+        // the bucket covers the minute which just ended
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let minute = now - now % 60 - 60;
+
+        if let Err(e) = write_history(&agent, &bucket, minute, retention).await {
+            error!("could not write history for minute {minute}: {e}");
+        }
+    }
+}
+
+async fn write_history(
+    agent: &Agent,
+    bucket: &MinuteBucket,
+    minute: i64,
+    retention: Duration,
+) -> eyre::Result<()> {
+    let conn = agent.pool().write_low().await?;
+    block_in_place(|| bucket.insert(&conn, minute, retention))?;
+    Ok(())
+}
@@ -4,9 +4,11 @@
 //! clients), manages cluster memberships, and applies propagated
 //! changesets to local data.
 
+mod analyze;
 mod bi;
 mod bootstrap;
 mod error;
+mod gaps;
 mod handlers;
 mod metrics;
 mod run_root;
@@ -24,9 +26,10 @@ use tokio::sync::{broadcast::Sender, RwLock};
 use uuid::Uuid;
 
 // Public exports
+pub use bootstrap::resolve_bootstrap;
 pub use error::{SyncClientError, SyncRecvError};
 pub use run_root::start_with_config;
-pub use setup::{setup, AgentOptions};
+pub use setup::{setup, setup_with_clock, AgentOptions};
 pub use util::{process_multiple_changes, clear_overwritten_versions};
 
 pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(300);
@@ -1,14 +1,16 @@
 //! Start the root agent tasks
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use crate::{
     agent::{
+        analyze, gaps,
         handlers::{self, spawn_handle_db_cleanup},
         metrics, setup, util, AgentOptions,
     },
-    api::public::pubsub::{
-        process_sub_channel, MatcherBroadcastCache, SharedMatcherBroadcastCache,
+    api::public::{
+        fanout::spawn_fanouts,
+        pubsub::{process_sub_channel, MatcherBroadcastCache, SharedMatcherBroadcastCache},
     },
     broadcast::runtime_loop,
 };
@@ -22,7 +24,7 @@ use corro_types::{
 };
 
 use futures::{FutureExt, StreamExt, TryStreamExt};
-use spawn::spawn_counted;
+use spawn::{spawn_named, Shutdown};
 use tokio::{sync::RwLock as TokioRwLock, task::block_in_place};
 use tracing::{error, info};
 use tripwire::Tripwire;
@@ -62,6 +64,9 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
     // Setup subscription handlers
     let subs_bcast_cache = setup_spawn_subscriptions(&agent, &subs_manager, &tripwire).await?;
 
+    // Serve subscription events to local consumers
+    spawn_fanouts(&agent, &subs_bcast_cache, &tripwire);
+
     //// Start PG server to accept query requests from PG clients
     // TODO: pull this out into a separate function?
     if let Some(pg_conf) = agent.config().api.pg.clone() {
@@ -108,23 +113,48 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
     )
     .await?;
 
-    spawn_counted(util::write_empties_loop(
-        agent.clone(),
-        rx_empty,
-        tripwire.clone(),
-    ));
+    spawn_named(
+        "write_empties_loop",
+        Shutdown::Graceful,
+        util::write_empties_loop(agent.clone(), rx_empty, tripwire.clone()),
+    );
 
-    tokio::spawn(util::clear_buffered_meta_loop(agent.clone(), rx_clear_buf));
+    spawn_named(
+        "clear_buffered_meta_loop",
+        Shutdown::Abortable,
+        util::clear_buffered_meta_loop(agent.clone(), rx_clear_buf),
+    );
 
-    tokio::spawn(metrics::metrics_loop(agent.clone(), transport.clone()));
-    tokio::spawn(handlers::handle_gossip_to_send(
-        transport.clone(),
-        to_send_rx,
-    ));
-    tokio::spawn(handlers::handle_notifications(
-        agent.clone(),
-        notifications_rx,
-    ));
+    spawn_named(
+        "metrics_loop",
+        Shutdown::Abortable,
+        metrics::metrics_loop(agent.clone(), transport.clone()),
+    );
+    spawn_named(
+        "scheduler_delay_loop",
+        Shutdown::Abortable,
+        metrics::scheduler_delay_loop(agent.clone()),
+    );
+    if let Some(history) = agent.config().telemetry.history.clone() {
+        spawn_named(
+            "history_loop",
+            Shutdown::Abortable,
+            metrics::history_loop(
+                agent.clone(),
+                Duration::from_secs(history.retention_mins * 60),
+            ),
+        );
+    }
+    spawn_named(
+        "handle_gossip_to_send",
+        Shutdown::Abortable,
+        handlers::handle_gossip_to_send(transport.clone(), to_send_rx),
+    );
+    spawn_named(
+        "handle_notifications",
+        Shutdown::Abortable,
+        handlers::handle_notifications(agent.clone(), notifications_rx),
+    );
 
     spawn_handle_db_cleanup(agent.pool().clone());
 
@@ -135,12 +165,7 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
     }
     {
         let conn = agent.pool().read().await?;
-        let actor_ids: Vec<ActorId> = conn
-            .prepare("SELECT DISTINCT actor_id FROM __corro_bookkeeping")?
-            .query_map([], |row| row.get(0))
-            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())?;
-
-        let pool = agent.pool();
+        let actor_ids: Vec<ActorId> = agent.bookkeeping().actor_ids(&conn)?;
 
         let mut buf = futures::stream::iter(
             actor_ids
@@ -148,14 +173,16 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
                 // don't re-process the current actor!
                 .filter(|other_actor_id| *other_actor_id != agent.actor_id())
                 .map(|actor_id| {
-                    let pool = pool.clone();
+                    let agent = agent.clone();
                     async move {
                         tokio::spawn(async move {
-                            let conn = pool.read().await?;
+                            let conn = agent.pool().read().await?;
 
-                            block_in_place(|| BookedVersions::from_conn(&conn, actor_id))
-                                .map(|bv| (actor_id, bv))
-                                .map_err(eyre::Report::from)
+                            block_in_place(|| {
+                                BookedVersions::from_store(agent.bookkeeping(), &conn, actor_id)
+                            })
+                            .map(|bv| (actor_id, bv))
+                            .map_err(eyre::Report::from)
                         })
                         .await?
                     }
@@ -186,7 +213,9 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
         }
     }
 
-    spawn_counted(
+    spawn_named(
+        "sync_loop",
+        Shutdown::Graceful,
         util::sync_loop(
             agent.clone(),
             bookie.clone(),
@@ -197,25 +226,36 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
         .inspect(|_| info!("corrosion agent sync loop is done")),
     );
 
+    spawn_named(
+        "detect_gaps_loop",
+        Shutdown::Abortable,
+        gaps::detect_gaps_loop(agent.clone(), bookie.clone(), tripwire.clone()),
+    );
+
+    spawn_named(
+        "analyze_loop",
+        Shutdown::Abortable,
+        analyze::analyze_loop(agent.clone(), tripwire.clone()),
+    );
+
     info!("Starting peer API on udp/{gossip_addr} (QUIC)");
 
     //// Start an incoming (corrosion) connection handler.  This
     //// future tree spawns additional message type sub-handlers
     handlers::spawn_gossipserver_handler(&agent, &bookie, &tripwire, gossip_server_endpoint);
 
-    spawn_counted(handlers::handle_changes(
-        agent.clone(),
-        bookie.clone(),
-        rx_changes,
-        tripwire.clone(),
-    ));
+    spawn_named(
+        "handle_changes",
+        Shutdown::Graceful,
+        handlers::handle_changes(agent.clone(), bookie.clone(), rx_changes, tripwire.clone()),
+    );
 
     if let Some(secs) = agent.config().db.clear_overwritten_secs {
-        tokio::spawn(util::clear_overwritten_versions_loop(
-            agent.clone(),
-            bookie.clone(),
-            secs,
-        ));
+        spawn_named(
+            "clear_overwritten_versions_loop",
+            Shutdown::Abortable,
+            util::clear_overwritten_versions_loop(agent.clone(), bookie.clone(), secs),
+        );
     }
 
     Ok(bookie)
@@ -260,12 +300,17 @@ async fn setup_spawn_subscriptions(
 
                     let (sub_tx, _) = tokio::sync::broadcast::channel(10240);
 
-                    tokio::spawn(process_sub_channel(
-                        subs_manager.clone(),
-                        sub_id,
-                        sub_tx.clone(),
-                        created.evt_rx,
-                    ));
+                    spawn_named(
+                        "process_sub_channel",
+                        Shutdown::Abortable,
+                        process_sub_channel(
+                            subs_manager.clone(),
+                            sub_id,
+                            sub_tx.clone(),
+                            created.evt_rx,
+                            agent.config().subscriptions.clone(),
+                        ),
+                    );
 
                     subs_bcast_cache.insert(sub_id, sub_tx);
                 }
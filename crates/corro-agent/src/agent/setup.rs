@@ -16,14 +16,17 @@ use tracing::{debug, info};
 use tripwire::Tripwire;
 
 // Internals
-use crate::{api::peer::gossip_server_endpoint, transport::Transport};
+use crate::{api::peer::gossip_server_endpoint, net::bind_tcp, transport::Transport};
 use corro_types::{
     actor::ActorId,
     agent::{migrate, Agent, AgentConfig, Booked, BookedVersions, LockRegistry, SplitPool},
     base::Version,
+    bookkeeping::{BookkeepingStore, SqliteBookkeeping},
     broadcast::{BroadcastInput, ChangeSource, ChangeV1, FocaInput},
     channel::{bounded, CorroReceiver},
+    clock::Clock,
     config::Config,
+    history::create_history_table,
     members::Members,
     pubsub::SubsManager,
     schema::init_schema,
@@ -49,6 +52,16 @@ pub struct AgentOptions {
 
 /// Setup an agent runtime and state with a configuration
 pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, AgentOptions)> {
+    setup_with_clock(conf, None, tripwire).await
+}
+
+/// Setup an agent runtime and state with a configuration, optionally
+/// replacing the default HLC (e.g. with a deterministic clock in tests)
+pub async fn setup_with_clock(
+    conf: Config,
+    clock: Option<Arc<dyn Clock>>,
+    tripwire: Tripwire,
+) -> eyre::Result<(Agent, AgentOptions)> {
     debug!("setting up corrosion @ {}", conf.db.path);
 
     if let Some(parent) = conf.db.path.parent() {
@@ -75,6 +88,9 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
     let schema = {
         let mut conn = pool.write_priority().await?;
         migrate(&mut conn)?;
+        if conf.telemetry.history.is_some() {
+            create_history_table(&conn)?;
+        }
         let mut schema = init_schema(&conn)?;
         schema.constrain()?;
 
@@ -97,11 +113,13 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
     let (tx_apply, rx_apply) = bounded(conf.perf.apply_channel_len, "apply");
     let (tx_clear_buf, rx_clear_buf) = bounded(conf.perf.clearbuf_channel_len, "clear_buf");
 
+    let bookkeeping: Arc<dyn BookkeepingStore> = Arc::new(SqliteBookkeeping);
+
     let lock_registry = LockRegistry::default();
     let booked = {
         let conn = pool.read().await?;
         Booked::new(
-            BookedVersions::from_conn(&conn, actor_id)?,
+            BookedVersions::from_store(&*bookkeeping, &conn, actor_id)?,
             lock_registry.clone(),
         )
     };
@@ -117,22 +135,25 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
 
     let transport = Transport::new(&conf.gossip, rtt_tx).await?;
 
-    let api_listener = TcpListener::bind(conf.api.bind_addr).await?;
+    let api_listener = bind_tcp(conf.api.bind_addr, conf.api.ipv6_only)?;
     let api_addr = api_listener.local_addr()?;
 
-    let clock = Arc::new(
-        uhlc::HLCBuilder::default()
-            .with_id(actor_id.try_into().unwrap())
-            .with_max_delta(Duration::from_millis(300))
-            .build(),
-    );
+    let clock = match clock {
+        Some(clock) => clock,
+        None => Arc::new(
+            uhlc::HLCBuilder::default()
+                .with_id(actor_id.try_into().unwrap())
+                .with_max_delta(Duration::from_millis(300))
+                .build(),
+        ),
+    };
 
     let (tx_bcast, rx_bcast) = bounded(conf.perf.bcast_channel_len, "bcast");
     let (tx_empty, rx_empty) = bounded(conf.perf.empties_channel_len, "empty");
     let (tx_changes, rx_changes) = bounded(conf.perf.changes_channel_len, "changes");
     let (tx_foca, rx_foca) = bounded(conf.perf.foca_channel_len, "foca");
 
-    let subs_manager = SubsManager::default();
+    let subs_manager = SubsManager::with_max_count(conf.subscriptions.max_count);
 
     let opts = AgentOptions {
         gossip_server_endpoint,
@@ -160,6 +181,7 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
         config: ArcSwap::from_pointee(conf),
         clock,
         booked,
+        bookkeeping,
         tx_bcast,
         tx_apply,
         tx_empty,
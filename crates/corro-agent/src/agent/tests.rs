@@ -16,13 +16,15 @@ use tokio::time::{sleep, timeout, MissedTickBehavior};
 use tracing::{debug, info_span};
 use tripwire::Tripwire;
 
-use crate::agent::util::*;
+use crate::{agent::util::*, api::peer::parallel_sync, transport::Transport};
 use corro_tests::*;
 use corro_types::{
     actor::ActorId,
     agent::migrate,
     api::{ExecResponse, ExecResult, Statement},
     base::{CrsqlDbVersion, CrsqlSeq, Version},
+    bookkeeping::{BookkeepingStore, SqliteBookkeeping},
+    config::{PeerAclConfig, RelayConfig},
     sqlite::CrConn,
     sync::generate_sync,
 };
@@ -256,6 +258,446 @@ async fn insert_rows_and_gossip() -> eyre::Result<()> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn truncate_rows_and_gossip() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+
+    let req_body: Vec<Statement> = (1i64..=100)
+        .map(|i| {
+            Statement::WithParams(
+                "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                vec![i.into(), format!("hello world {i}").into()],
+            )
+        })
+        .collect();
+
+    let res = client
+        .request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&req_body)?.into())?,
+        )
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    sleep(Duration::from_secs(2)).await;
+
+    let count = |conn: &rusqlite::Connection| -> rusqlite::Result<i64> {
+        conn.query_row("SELECT COUNT(*) FROM tests", (), |row| row.get(0))
+    };
+    assert_eq!(count(&*ta2.agent.pool().read().await?)?, 100);
+
+    let res = client
+        .request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/truncations", ta1.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(
+                    serde_json::to_vec(&json!({"table": "tests", "start": 11, "end": 101}))?.into(),
+                )?,
+        )
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body: ExecResponse =
+        serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+    assert!(matches!(
+        body.results[0],
+        ExecResult::Execute {
+            rows_affected: 90,
+            ..
+        }
+    ));
+
+    sleep(Duration::from_secs(2)).await;
+
+    assert_eq!(count(&*ta1.agent.pool().read().await?)?, 10);
+    assert_eq!(count(&*ta2.agent.pool().read().await?)?, 10);
+
+    // the deletes replicate as regular changes of the truncating actor, in a
+    // single version
+    let (db_version, last_seq): (CrsqlDbVersion, CrsqlSeq) =
+        ta2.agent.pool().read().await?.query_row(
+            "SELECT db_version, last_seq FROM __corro_bookkeeping WHERE actor_id = ? AND start_version = 2",
+            [ta1.agent.actor_id()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+    assert_eq!(last_seq, CrsqlSeq(89));
+
+    let deletes: i64 = ta2.agent.pool().read().await?.query_row(
+        "SELECT COUNT(*) FROM crsql_changes WHERE db_version = ? AND cid = '-1' AND site_id = ?",
+        rusqlite::params![db_version, ta1.agent.actor_id()],
+        |row| row.get(0),
+    )?;
+    assert_eq!(deletes, 90);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn relay_between_segments() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+    // two segments that never gossip directly: (ta1, relay1) and (relay2, ta2)
+    let relay2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![relay2.agent.gossip_addr().to_string()])
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+    let relay1 = launch_test_agent(
+        |conf| {
+            conf.relay(RelayConfig {
+                peers: vec![relay2.agent.gossip_addr()],
+                max_hops: 3,
+            })
+            .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+    let ta1 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![relay1.agent.gossip_addr().to_string()])
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+
+    let insert = |agent: &corro_types::agent::Agent, id: i64| {
+        let req_body = vec![Statement::WithParams(
+            "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+            vec![id.into(), format!("hello world {id}").into()],
+        )];
+        client.request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/transactions", agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&req_body).unwrap().into())
+                .unwrap(),
+        )
+    };
+
+    let has_row = |agent: corro_types::agent::Agent, id: i64| async move {
+        for _ in 0..40 {
+            let found: bool = agent.pool().read().await?.query_row(
+                "SELECT EXISTS(SELECT 1 FROM tests WHERE id = ?)",
+                [id],
+                |row| row.get(0),
+            )?;
+            if found {
+                return Ok::<_, eyre::Report>(true);
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+        Ok(false)
+    };
+
+    // broadcasts are forwarded by the relay to the other segment
+    assert_eq!(insert(&ta1.agent, 1).await?.status(), StatusCode::OK);
+    assert!(has_row(ta2.agent.clone(), 1).await?);
+
+    // the relay syncs with the other segment and serves it to its own
+    assert_eq!(insert(&ta2.agent, 2).await?.status(), StatusCode::OK);
+    assert!(has_row(ta1.agent.clone(), 2).await?);
+
+    // segments still don't know about each other
+    assert!(!ta1
+        .agent
+        .members()
+        .read()
+        .states
+        .contains_key(&ta2.agent.actor_id()));
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn insert_rows_and_gossip_ipv6() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let localhost: SocketAddr = "[::1]:0".parse()?;
+    let ta1 = launch_test_agent(
+        |conf| conf.api_addr(localhost).gossip_addr(localhost).build(),
+        tripwire.clone(),
+    )
+    .await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.api_addr(localhost)
+                .gossip_addr(localhost)
+                .bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+
+    let req_body: Vec<Statement> = serde_json::from_value(json!([[
+        "INSERT INTO tests (id,text) VALUES (?,?)",
+        [1, "hello world 1"]
+    ]]))?;
+
+    let res = client
+        .request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&req_body)?.into())?,
+        )
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    sleep(Duration::from_secs(2)).await;
+
+    let text: String = ta2.agent.pool().read().await?.query_row(
+        "SELECT text FROM tests WHERE id = 1",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(text, "hello world 1");
+
+    assert!(ta2
+        .agent
+        .members()
+        .read()
+        .states
+        .get(&ta1.agent.actor_id())
+        .map_or(false, |state| state.addr == ta1.agent.gossip_addr()));
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn peer_acl_denies_actors() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                .acl(PeerAclConfig {
+                    deny_actors: vec![ta1.agent.actor_id()],
+                    ..Default::default()
+                })
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+    let ta3 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![ta2.agent.gossip_addr().to_string()])
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    sleep(Duration::from_secs(5)).await;
+
+    let members = ta2.agent.members().read().states.clone();
+    assert!(members.contains_key(&ta3.agent.actor_id()));
+    assert!(!members.contains_key(&ta1.agent.actor_id()));
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn peer_acl_denies_changes() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                .acl(PeerAclConfig {
+                    deny_actors: vec![ta1.agent.actor_id()],
+                    ..Default::default()
+                })
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+
+    let req_body: Vec<Statement> = vec![Statement::WithParams(
+        "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+        vec![1.into(), "hello world 1".into()],
+    )];
+
+    let res = client
+        .request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&req_body)?.into())?,
+        )
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // long enough for broadcasts and a few syncs
+    sleep(Duration::from_secs(5)).await;
+
+    let count: i64 =
+        ta2.agent
+            .pool()
+            .read()
+            .await?
+            .query_row("SELECT COUNT(*) FROM tests", (), |row| row.get(0))?;
+    assert_eq!(count, 0);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn sync_challenge_authenticates_actors() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(
+        |conf| conf.cluster_secret("s3cr3t").build(),
+        tripwire.clone(),
+    )
+    .await?;
+    let ta2 = launch_test_agent(
+        |conf| conf.cluster_secret("s3cr3t").build(),
+        tripwire.clone(),
+    )
+    .await?;
+    let ta3 = launch_test_agent(
+        |conf| conf.cluster_secret("other").build(),
+        tripwire.clone(),
+    )
+    .await?;
+
+    let sync_with_ta1 = |ta: TestAgent| {
+        let ta1 = ta1.agent.clone();
+        async move {
+            let (rtt_tx, _rtt_rx) = tokio::sync::mpsc::channel(1);
+            let transport = Transport::new(&ta.agent.config().gossip, rtt_tx).await?;
+            let state = generate_sync(&ta.bookie, ta.agent.actor_id()).await;
+            Ok::<_, eyre::Report>(
+                parallel_sync(
+                    &ta.agent,
+                    &transport,
+                    vec![(ta1.actor_id(), ta1.gossip_addr())],
+                    state,
+                )
+                .await,
+            )
+        }
+    };
+
+    assert!(sync_with_ta1(ta2.clone()).await?.is_ok());
+    assert!(sync_with_ta1(ta3.clone()).await?.is_err());
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn sync_detects_schema_drift() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+    let res = client
+        .request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/migrations", ta2.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(
+                    serde_json::to_vec(&[Statement::Simple(
+                        "CREATE TABLE drifted (id INTEGER NOT NULL PRIMARY KEY, text TEXT);".into(),
+                    )])?
+                    .into(),
+                )?,
+        )
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let (rtt_tx, _rtt_rx) = tokio::sync::mpsc::channel(1);
+    let transport = Transport::new(&ta1.agent.config().gossip, rtt_tx).await?;
+    let state = generate_sync(&ta1.bookie, ta1.agent.actor_id()).await;
+    parallel_sync(
+        &ta1.agent,
+        &transport,
+        vec![(ta2.agent.actor_id(), ta2.agent.gossip_addr())],
+        state,
+    )
+    .await?;
+
+    assert_eq!(
+        ta1.agent
+            .members()
+            .read()
+            .schema_drift
+            .get(&ta2.agent.actor_id()),
+        Some(&vec!["drifted".to_string()])
+    );
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn chill_test() -> eyre::Result<()> {
     configurable_stress_test(2, 1, 1).await
@@ -555,7 +997,7 @@ fn test_in_memory_versions_compaction() -> eyre::Result<()> {
     let tx = conn.immediate_transaction()?;
     let actor_id: ActorId = tx.query_row("SELECT crsql_site_id()", [], |row| row.get(0))?;
 
-    let to_clear = find_cleared_db_versions(&tx, &actor_id)?;
+    let to_clear = find_cleared_db_versions(&SqliteBookkeeping, &tx, &actor_id)?;
 
     println!("to_clear: {to_clear:?}");
 
@@ -565,7 +1007,7 @@ fn test_in_memory_versions_compaction() -> eyre::Result<()> {
     tx.execute("DELETE FROM __corro_bookkeeping WHERE db_version = 1", [])?;
     tx.execute("INSERT INTO __corro_bookkeeping (actor_id, start_version, end_version) SELECT crsql_site_id(), 1, 1", [])?;
 
-    let to_clear = find_cleared_db_versions(&tx, &actor_id)?;
+    let to_clear = find_cleared_db_versions(&SqliteBookkeeping, &tx, &actor_id)?;
     assert!(to_clear.is_empty());
 
     tx.execute("INSERT INTO foo2 (a) VALUES (2)", ())?;
@@ -576,14 +1018,14 @@ fn test_in_memory_versions_compaction() -> eyre::Result<()> {
     tx.commit()?;
 
     let tx = conn.immediate_transaction()?;
-    let to_clear = find_cleared_db_versions(&tx, &actor_id)?;
+    let to_clear = find_cleared_db_versions(&SqliteBookkeeping, &tx, &actor_id)?;
     assert!(to_clear.is_empty());
 
     tx.execute("INSERT INTO foo (a) VALUES (1)", ())?;
     tx.commit()?;
 
     let tx = conn.immediate_transaction()?;
-    let to_clear = find_cleared_db_versions(&tx, &actor_id)?;
+    let to_clear = find_cleared_db_versions(&SqliteBookkeeping, &tx, &actor_id)?;
 
     assert!(to_clear.contains(&CrsqlDbVersion(2)));
     assert!(!to_clear.contains(&CrsqlDbVersion(3)));
@@ -594,7 +1036,7 @@ fn test_in_memory_versions_compaction() -> eyre::Result<()> {
         "UPDATE __corro_bookkeeping SET end_version = 2 WHERE start_version = 1;",
         [],
     )?;
-    let to_clear = find_cleared_db_versions(&tx, &actor_id)?;
+    let to_clear = find_cleared_db_versions(&SqliteBookkeeping, &tx, &actor_id)?;
 
     assert!(to_clear.is_empty());
 
@@ -820,8 +1262,10 @@ async fn many_small_changes() -> eyre::Result<()> {
                             serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
 
                         match &body.results[0] {
-                            ExecResult::Execute { .. } => {}
-                            ExecResult::Error { error } => {
+                            ExecResult::Execute { .. }
+                            | ExecResult::Returning { .. }
+                            | ExecResult::Query { .. } => {}
+                            ExecResult::Error { error, .. } => {
                                 eyre::bail!("error: {error}");
                             }
                         }
@@ -881,7 +1325,7 @@ fn test_store_empty_changeset() -> eyre::Result<()> {
     {
         let tx = conn.transaction()?;
         assert_eq!(
-            store_empty_changeset(&tx, actor_id, Version(1)..=Version(2))?,
+            SqliteBookkeeping.insert_cleared(&tx, actor_id, Version(1)..=Version(2))?,
             1
         );
         tx.commit()?;
@@ -917,7 +1361,7 @@ fn test_store_empty_changeset() -> eyre::Result<()> {
     {
         let tx = conn.transaction()?;
         assert_eq!(
-            store_empty_changeset(&tx, actor_id, Version(5)..=Version(7))?,
+            SqliteBookkeeping.insert_cleared(&tx, actor_id, Version(5)..=Version(7))?,
             1
         );
         tx.commit()?;
@@ -964,7 +1408,7 @@ fn test_store_empty_changeset() -> eyre::Result<()> {
     {
         let tx = conn.transaction()?;
         assert_eq!(
-            store_empty_changeset(&tx, actor_id, Version(3)..=Version(6))?,
+            SqliteBookkeeping.insert_cleared(&tx, actor_id, Version(3)..=Version(6))?,
             1
         );
         tx.commit()?;
@@ -1002,7 +1446,7 @@ fn test_store_empty_changeset() -> eyre::Result<()> {
     {
         let tx = conn.transaction()?;
         assert_eq!(
-            store_empty_changeset(&tx, actor_id, Version(1)..=Version(10))?,
+            SqliteBookkeeping.insert_cleared(&tx, actor_id, Version(1)..=Version(10))?,
             1
         );
         tx.commit()?;
@@ -1044,7 +1488,7 @@ fn test_store_empty_changeset() -> eyre::Result<()> {
     {
         let tx = conn.transaction()?;
         assert_eq!(
-            store_empty_changeset(&tx, actor_id, Version(1)..=Version(11))?,
+            SqliteBookkeeping.insert_cleared(&tx, actor_id, Version(1)..=Version(11))?,
             1
         );
         tx.commit()?;
@@ -1091,7 +1535,7 @@ fn test_store_empty_changeset() -> eyre::Result<()> {
     {
         let tx = conn.transaction()?;
         assert_eq!(
-            store_empty_changeset(&tx, actor_id, Version(14)..=Version(14))?,
+            SqliteBookkeeping.insert_cleared(&tx, actor_id, Version(14)..=Version(14))?,
             1
         );
         tx.commit()?;
@@ -1150,7 +1594,7 @@ fn test_store_empty_changeset() -> eyre::Result<()> {
     {
         let tx = conn.transaction()?;
         assert_eq!(
-            store_empty_changeset(&tx, actor_id, Version(12)..=Version(14))?,
+            SqliteBookkeeping.insert_cleared(&tx, actor_id, Version(12)..=Version(14))?,
             1
         );
         tx.commit()?;
@@ -1192,7 +1636,7 @@ fn test_store_empty_changeset() -> eyre::Result<()> {
     {
         let tx = conn.transaction()?;
         assert_eq!(
-            store_empty_changeset(&tx, actor_id, Version(15)..=Version(15))?,
+            SqliteBookkeeping.insert_cleared(&tx, actor_id, Version(15)..=Version(15))?,
             1
         );
         tx.commit()?;
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use corro_types::{
     agent::Agent,
     broadcast::{BroadcastV1, ChangeSource, UniPayload, UniPayloadV1},
@@ -38,6 +40,9 @@ pub fn spawn_unipayload_handler(tripwire: &Tripwire, conn: &quinn::Connection, a
                     conn.remote_address()
                 );
 
+                let remote_addr = conn.remote_address();
+                // IPv4 peers show up as IPv4-mapped IPv6 addresses on dual-stack sockets
+                let remote_ip = remote_addr.ip().to_canonical();
                 tokio::spawn({
                     let agent = agent.clone();
                     async move {
@@ -52,28 +57,61 @@ pub fn spawn_unipayload_handler(tripwire: &Tripwire, conn: &quinn::Connection, a
                                         Ok(payload) => {
                                             trace!("parsed a payload: {payload:?}");
 
-                                            match payload {
-                                                UniPayload::V1 {
-                                                    data:
-                                                        UniPayloadV1::Broadcast(BroadcastV1::Change(
-                                                            change,
-                                                        )),
+                                            let (change, src, cluster_id) = match payload
+                                                .into_data()
+                                            {
+                                                (
+                                                    Some(UniPayloadV1::Broadcast(
+                                                        BroadcastV1::Change(change),
+                                                    )),
+                                                    cluster_id,
+                                                ) => (change, ChangeSource::Broadcast, cluster_id),
+                                                (
+                                                    Some(UniPayloadV1::Relayed {
+                                                        bcast: BroadcastV1::Change(change),
+                                                        relayed_by,
+                                                    }),
                                                     cluster_id,
-                                                } => {
-                                                    if cluster_id != agent.cluster_id() {
+                                                ) => {
+                                                    if relayed_by.contains(&agent.actor_id()) {
+                                                        // relays forwarded it in a loop
+                                                        counter!("corro.relay.loops").increment(1);
                                                         continue;
                                                     }
-                                                    if let Err(e) = agent
-                                                        .tx_changes()
-                                                        .send((change, ChangeSource::Broadcast))
-                                                        .await
-                                                    {
-                                                        error!(
-                                                            "could not send change for processing: {e}"
-                                                        );
-                                                        return;
-                                                    }
+                                                    (
+                                                        change,
+                                                        ChangeSource::Relay(relayed_by),
+                                                        cluster_id,
+                                                    )
                                                 }
+                                                (None, _) => {
+                                                    // sent by a newer version
+                                                    counter!("corro.peer.payload.unknown", "type" => "uni").increment(1);
+                                                    continue;
+                                                }
+                                            };
+
+                                            if cluster_id != agent.cluster_id() {
+                                                continue;
+                                            }
+                                            if !allows_peer(&agent, remote_addr) {
+                                                counter!("corro.broadcast.denied").increment(1);
+                                                continue;
+                                            }
+                                            if !agent
+                                                .replay()
+                                                .lock()
+                                                .insert_from(remote_ip, &change)
+                                            {
+                                                // duplicated or replayed frame
+                                                counter!("corro.broadcast.replayed").increment(1);
+                                                continue;
+                                            }
+                                            if let Err(e) =
+                                                agent.tx_changes().send((change, src)).await
+                                            {
+                                                error!("could not send change for processing: {e}");
+                                                return;
                                             }
                                         }
                                         Err(e) => {
@@ -94,3 +132,22 @@ pub fn spawn_unipayload_handler(tripwire: &Tripwire, conn: &quinn::Connection, a
         }
     });
 }
+
+/// Whether the peer which sent us broadcasts over a connection from
+/// `remote_addr` is allowed, rather than the actor which made the changes.
+/// Peers connect from their gossip address, when actors are filtered only
+/// members known by it are allowed.
+fn allows_peer(agent: &Agent, remote_addr: SocketAddr) -> bool {
+    let config = agent.config();
+    let acl = &config.gossip.acl;
+    if !acl.filters_actors() {
+        return true;
+    }
+    let members = agent.members().read();
+    let canonical = SocketAddr::new(remote_addr.ip().to_canonical(), remote_addr.port());
+    members
+        .by_addr
+        .get(&remote_addr)
+        .or_else(|| members.by_addr.get(&canonical))
+        .map_or(false, |actor_id| acl.allows_actor(*actor_id))
+}
@@ -20,20 +20,54 @@ use crate::{
         handlers, CountedExecutor, CHECK_EMPTIES_TO_INSERT_AFTER, MAX_SYNC_BACKOFF, TO_CLEAR_COUNT,
     },
     api::public::{
-        api_v1_db_schema, api_v1_queries, api_v1_table_stats, api_v1_transactions,
-        pubsub::{api_v1_sub_by_id, api_v1_subs},
+        api_v1_db_schema, api_v1_db_schema_get, api_v1_migrations, api_v1_queries,
+        api_v1_table_stats, api_v1_transactions, api_v1_truncations,
+        backfill::{
+            api_v1_backfill_by_id, api_v1_backfill_cancel, api_v1_backfills,
+            api_v1_backfills_create, SharedBackfills,
+        },
+        cancel::{api_v1_query_cancel, RunningQueries},
+        changes::{api_v1_changes, api_v1_changes_actors, api_v1_changes_ingest},
+        digest::{api_v1_digests, api_v1_digests_rows},
+        export::api_v1_table_export,
+        fence::retry_fenced,
+        import::{api_v1_import_csv, api_v1_table_import, api_v1_upserts},
+        instrument::instrument,
+        pubsub::{
+            api_v1_sub_by_id, api_v1_subs, api_v1_subs_by_hash, api_v1_watch_delete,
+            api_v1_watch_groups,
+        },
+        sessions::{
+            api_v1_transactions_begin, api_v1_transactions_commit, api_v1_transactions_rollback,
+            api_v1_transactions_session, SharedSessions,
+        },
+        shed::shed_load,
+        snapshot::{api_v1_snapshot_release, api_v1_snapshots, SharedSnapshots},
+        statements::{
+            api_v1_statement_register, api_v1_statement_remove, api_v1_statement_run,
+            api_v1_statements,
+        },
+        tokens::{
+            api_v1_token_revoke, api_v1_token_rotate, api_v1_tokens, api_v1_tokens_create,
+            authorize_request, find_token,
+        },
+        v2::{api_v2_queries, api_v2_transactions, deprecate_v1},
+        watches::api_v1_watch_keys,
+        ws::api_v1_ws,
     },
     transport::Transport,
 };
 use corro_types::{
+    activity::ActivityKind,
     actor::{Actor, ActorId},
-    agent::{
-        Agent, Bookie, ChangeError, CurrentVersion, KnownDbVersion, PartialVersion, SplitPool,
-    },
+    agent::{Agent, Bookie, CurrentVersion, KnownDbVersion, PartialVersion, SplitPool},
     base::{CrsqlDbVersion, CrsqlSeq, Version},
+    behind::{missing_schema, skip_dropped_columns, skip_dropped_tables},
+    bookkeeping::{BookkeepingError, BookkeepingStore},
     broadcast::{ChangeSource, ChangeV1, Changeset, ChangesetParts, FocaCmd, FocaInput},
     channel::CorroReceiver,
     config::AuthzConfig,
+    error::ChangeError,
     pubsub::SubsManager,
 };
 
@@ -41,7 +75,7 @@ use axum::{
     error_handling::HandleErrorLayer,
     extract::DefaultBodyLimit,
     headers::{authorization::Bearer, Authorization},
-    routing::{get, post},
+    routing::{delete, get, post, put},
     BoxError, Extension, Router, TypedHeader,
 };
 use foca::Member;
@@ -53,7 +87,7 @@ use rangemap::{RangeInclusiveMap, RangeInclusiveSet};
 use rusqlite::{
     named_params, params, params_from_iter, Connection, OptionalExtension, ToSql, Transaction,
 };
-use spawn::spawn_counted;
+use spawn::{spawn_counted, spawn_named, Shutdown};
 use tokio::{
     net::TcpListener,
     sync::mpsc::Sender,
@@ -232,7 +266,7 @@ pub async fn clear_overwritten_versions(
                 let start = Instant::now();
                 let res = block_in_place(|| {
                     let tx = conn.transaction()?;
-                    find_cleared_db_versions(&tx, &actor_id)
+                    find_cleared_db_versions(agent.bookkeeping(), &tx, &actor_id)
                 });
                 db_elapsed += start.elapsed();
                 match res {
@@ -396,6 +430,13 @@ pub async fn setup_http_api_handler(
     subs_manager: &SubsManager,
     api_listener: TcpListener,
 ) -> eyre::Result<()> {
+    let snapshots = SharedSnapshots::default();
+    spawn_named(
+        "snapshots_expiry",
+        Shutdown::Abortable,
+        snapshots.clone().expire_loop(),
+    );
+
     let api = Router::new()
         // transactions
         .route(
@@ -412,6 +453,31 @@ pub async fn setup_http_api_handler(
                     .layer(ConcurrencyLimitLayer::new(128)),
             ),
         )
+        // interactive transactions hold the write connection between requests
+        .route("/v1/transactions/begin", post(api_v1_transactions_begin))
+        .route("/v1/transactions/:id", post(api_v1_transactions_session))
+        .route(
+            "/v1/transactions/:id/commit",
+            post(api_v1_transactions_commit),
+        )
+        .route(
+            "/v1/transactions/:id/rollback",
+            post(api_v1_transactions_rollback),
+        )
+        .route(
+            "/v1/upserts",
+            post(api_v1_upserts).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
         // queries
         .route(
             "/v1/queries",
@@ -427,6 +493,35 @@ pub async fn setup_http_api_handler(
                     .layer(ConcurrencyLimitLayer::new(128)),
             ),
         )
+        .route("/v1/queries/:id/cancel", post(api_v1_query_cancel))
+        .route(
+            "/v2/transactions",
+            post(api_v2_transactions).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
+        .route(
+            "/v2/queries",
+            post(api_v2_queries).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
         .route(
             "/v1/subscriptions",
             post(api_v1_subs).route_layer(
@@ -455,9 +550,82 @@ pub async fn setup_http_api_handler(
                     .layer(ConcurrencyLimitLayer::new(128)),
             ),
         )
+        .route(
+            "/v1/watches/by-hash",
+            put(api_v1_subs_by_hash).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
+        .route(
+            "/v1/watches/groups",
+            post(api_v1_watch_groups).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
+        .route(
+            "/v1/watches/keys",
+            post(api_v1_watch_keys).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
+        .route("/v1/watches/:id", delete(api_v1_watch_delete))
+        .route(
+            "/v1/ws",
+            get(api_v1_ws).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
+        .route(
+            "/v1/db/schema",
+            get(api_v1_db_schema_get)
+                .post(api_v1_db_schema)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(4)),
+                ),
+        )
         .route(
             "/v1/migrations",
-            post(api_v1_db_schema).route_layer(
+            post(api_v1_migrations).route_layer(
                 tower::ServiceBuilder::new()
                     .layer(HandleErrorLayer::new(|_error: BoxError| async {
                         Ok::<_, Infallible>((
@@ -483,10 +651,154 @@ pub async fn setup_http_api_handler(
                     .layer(ConcurrencyLimitLayer::new(4)),
             ),
         )
+        .route(
+            "/v1/snapshots",
+            post(api_v1_snapshots).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route("/v1/snapshots/:id", delete(api_v1_snapshot_release))
+        .route("/v1/statements", get(api_v1_statements))
+        .route(
+            "/v1/statements/:name",
+            put(api_v1_statement_register)
+                .post(api_v1_statement_run)
+                .delete(api_v1_statement_remove),
+        )
+        .route(
+            "/v1/truncations",
+            post(api_v1_truncations).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/backfills",
+            post(api_v1_backfills_create)
+                .get(api_v1_backfills)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(4)),
+                ),
+        )
+        .route(
+            "/v1/import/csv",
+            post(api_v1_import_csv).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/tables/:name/import",
+            post(api_v1_table_import).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/tables/:name/export",
+            get(api_v1_table_export).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/backfills/:id",
+            get(api_v1_backfill_by_id).delete(api_v1_backfill_cancel),
+        )
+        .route(
+            "/v1/changes",
+            get(api_v1_changes).post(api_v1_changes_ingest),
+        )
+        .route("/v1/changes/actors", get(api_v1_changes_actors))
+        .route(
+            "/v1/digests",
+            post(api_v1_digests).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/digests/rows",
+            post(api_v1_digests_rows).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route("/v1/tokens", post(api_v1_tokens_create).get(api_v1_tokens))
+        .route("/v1/tokens/:id", delete(api_v1_token_revoke))
+        .route("/v1/tokens/:id/rotate", post(api_v1_token_rotate))
+        .layer(axum::middleware::from_fn(deprecate_v1))
+        .layer(axum::middleware::from_fn(retry_fenced))
         .layer(axum::middleware::from_fn(require_authz))
+        .layer(axum::middleware::from_fn(limit_body))
+        // before reading bodies, shed requests should cost next to nothing
+        .layer(axum::middleware::from_fn(shed_load))
+        .layer(axum::middleware::from_fn(instrument))
         .layer(
             tower::ServiceBuilder::new()
                 .layer(Extension(Arc::new(AtomicI64::new(0))))
+                .layer(Extension(SharedBackfills::default()))
+                .layer(Extension(snapshots))
+                .layer(Extension(SharedSessions::default()))
+                .layer(Extension(RunningQueries::default()))
                 .layer(Extension(agent.clone()))
                 .layer(Extension(subs_bcast_cache))
                 .layer(Extension(subs_manager.clone()))
@@ -499,9 +811,18 @@ pub async fn setup_http_api_handler(
     info!("Starting public API server on tcp/{api_addr}");
     let mut incoming = AddrIncoming::from_listener(api_listener)?;
     incoming.set_nodelay(true);
+    let (header_read_timeout, max_concurrent_streams) = {
+        let api_config = &agent.config().api;
+        (
+            Duration::from_secs(api_config.header_read_timeout_secs),
+            api_config.max_concurrent_streams,
+        )
+    };
     spawn_counted(
         axum::Server::builder(incoming)
             .executor(CountedExecutor)
+            .http1_header_read_timeout(header_read_timeout)
+            .http2_max_concurrent_streams(max_concurrent_streams)
             .serve(
                 api.clone()
                     .into_make_service_with_connect_info::<SocketAddr>(),
@@ -517,36 +838,121 @@ pub async fn setup_http_api_handler(
     Ok(())
 }
 
-async fn require_authz<B>(
+/// Buffers request bodies up to the configured size and timeout, so slow or
+/// oversized bodies can't hold on to resources.
+async fn limit_body(
     Extension(agent): Extension<Agent>,
-    maybe_authz_header: Option<TypedHeader<Authorization<Bearer>>>,
-    request: axum::http::Request<B>,
-    next: axum::middleware::Next<B>,
+    request: axum::http::Request<hyper::Body>,
+    next: axum::middleware::Next<hyper::Body>,
 ) -> Result<axum::response::Response, axum::http::StatusCode> {
-    let passed = if let Some(ref authz) = agent.config().api.authorization {
-        match authz {
-            AuthzConfig::BearerToken(token) => maybe_authz_header
-                .map(|h| h.token() == token)
-                .unwrap_or(false),
+    let (max_body_bytes, body_read_timeout) = {
+        let api_config = &agent.config().api;
+        (
+            api_config.max_body_bytes,
+            Duration::from_secs(api_config.body_read_timeout_secs),
+        )
+    };
+
+    let (parts, body) = request.into_parts();
+
+    // imports are read while their rows are applied, they can be much larger
+    // than any other body
+    let segments: Vec<&str> = parts.uri.path().trim_matches('/').split('/').collect();
+    if parts.method == axum::http::Method::POST
+        && matches!(
+            segments[..],
+            ["v1", "tables", _, "import"] | ["v1", "import", "csv"]
+        )
+    {
+        return Ok(next.run(axum::http::Request::from_parts(parts, body)).await);
+    }
+
+    // reject early, without reading anything
+    let content_length = parts
+        .headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.map_or(false, |len| len > max_body_bytes) {
+        counter!("corro.api.body.rejected", "reason" => "too_large").increment(1);
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let body = match tokio::time::timeout(
+        body_read_timeout,
+        hyper::body::to_bytes(http_body::Limited::new(body, max_body_bytes)),
+    )
+    .await
+    {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) if e.is::<http_body::LengthLimitError>() => {
+            counter!("corro.api.body.rejected", "reason" => "too_large").increment(1);
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
         }
-    } else {
-        true
+        Ok(Err(e)) => {
+            debug!("could not read request body: {e}");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Err(_) => {
+            counter!("corro.api.body.rejected", "reason" => "timeout").increment(1);
+            return Err(StatusCode::REQUEST_TIMEOUT);
+        }
+    };
+
+    Ok(next
+        .run(axum::http::Request::from_parts(parts, body.into()))
+        .await)
+}
+
+/// Lets requests with the root token through, requests made with a scoped
+/// token are checked against its scope first.
+async fn require_authz(
+    Extension(agent): Extension<Agent>,
+    maybe_authz_header: Option<TypedHeader<Authorization<Bearer>>>,
+    request: axum::http::Request<hyper::Body>,
+    next: axum::middleware::Next<hyper::Body>,
+) -> Result<axum::response::Response, axum::http::StatusCode> {
+    let Some(ref authz) = agent.config().api.authorization else {
+        return Ok(next.run(request).await);
     };
 
-    if !passed {
+    let Some(header) = maybe_authz_header else {
         return Err(axum::http::StatusCode::UNAUTHORIZED);
+    };
+
+    match authz {
+        AuthzConfig::BearerToken(token) if header.token() == token => {
+            return Ok(next.run(request).await)
+        }
+        AuthzConfig::BearerToken(_) => {}
     }
 
-    Ok(next.run(request).await)
+    let Some(token) = find_token(&agent, header.token()).await? else {
+        return Err(axum::http::StatusCode::UNAUTHORIZED);
+    };
+
+    // the body is needed to know which tables the request touches, it was
+    // already bounded by `limit_body`
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|_| axum::http::StatusCode::BAD_REQUEST)?;
+
+    let body = authorize_request(&agent, &token, &parts, body).await?;
+
+    Ok(next
+        .run(axum::http::Request::from_parts(parts, body.into()))
+        .await)
 }
 
 // DOCME: provide some context for this function
 // TODO: move to a more appropriate module?
 #[tracing::instrument(skip_all)]
 pub fn find_cleared_db_versions(
+    store: &dyn BookkeepingStore,
     tx: &Transaction,
     actor_id: &ActorId,
-) -> rusqlite::Result<BTreeSet<CrsqlDbVersion>> {
+) -> Result<BTreeSet<CrsqlDbVersion>, BookkeepingError> {
     let clock_site_id: Option<u64> = match tx
         .prepare_cached("SELECT ordinal FROM crsql_site_id WHERE site_id = ?")?
         .query_row([actor_id], |row| row.get(0))
@@ -571,26 +977,27 @@ pub fn find_cleared_db_versions(
         return Ok(BTreeSet::new());
     }
 
-    let mut params: Vec<&dyn ToSql> = vec![actor_id];
-    let to_clear_query = format!(
-        "SELECT DISTINCT db_version FROM __corro_bookkeeping WHERE actor_id = ? AND db_version IS NOT NULL
-            EXCEPT SELECT db_version FROM ({});",
-        tables
-            .iter()
-            .map(|table| {
-                params.push(&clock_site_id);
-                format!("SELECT DISTINCT db_version FROM {table} WHERE site_id = ?")
-            })
-            .collect::<Vec<_>>()
-            .join(" UNION ")
-    );
-
-    let cleared_db_versions: BTreeSet<CrsqlDbVersion> = tx
-        .prepare_cached(&to_clear_query)?
+    let mut params: Vec<&dyn ToSql> = vec![];
+    let live_query = tables
+        .iter()
+        .map(|table| {
+            params.push(&clock_site_id);
+            format!("SELECT DISTINCT db_version FROM {table} WHERE site_id = ?")
+        })
+        .collect::<Vec<_>>()
+        .join(" UNION ");
+
+    // db versions still referenced by at least one row
+    let live_db_versions: BTreeSet<CrsqlDbVersion> = tx
+        .prepare_cached(&live_query)?
         .query_map(params_from_iter(params.into_iter()), |row| row.get(0))?
         .collect::<rusqlite::Result<_>>()?;
 
-    Ok(cleared_db_versions)
+    Ok(store
+        .db_versions(tx, *actor_id)?
+        .difference(&live_db_versions)
+        .copied()
+        .collect())
 }
 
 /// Periodically initiate a sync with many other nodes.  Before we do
@@ -651,6 +1058,10 @@ pub async fn sync_loop(
                     tripwire::Outcome::Completed(res) => {
                         if let Err(e) = res {
                             error!("could not sync: {e}");
+                            agent.activity().publish(ActivityKind::Error {
+                                context: "syncing".into(),
+                                error: e.to_string(),
+                            });
                             // keep syncing until we successfully sync
                             continue;
                         }
@@ -826,7 +1237,10 @@ pub async fn process_completed_empties(
 
                 for range in ranges {
                     let mut sp = tx.savepoint()?;
-                    match store_empty_changeset(&sp, actor_id, range.clone()) {
+                    match agent
+                        .bookkeeping()
+                        .insert_cleared(&sp, actor_id, range.clone())
+                    {
                         Ok(count) => {
                             inserted += count;
                             sp.commit()?;
@@ -904,84 +1318,6 @@ pub fn process_single_version(
     Ok((known, changeset))
 }
 
-pub fn store_empty_changeset(
-    conn: &Connection,
-    actor_id: ActorId,
-    versions: RangeInclusive<Version>,
-) -> eyre::Result<usize> {
-    // first, delete "current" versions, they're now gone!
-    let deleted: Vec<RangeInclusive<Version>> = conn
-        .prepare_cached(
-            "
-        DELETE FROM __corro_bookkeeping 
-            WHERE
-                actor_id = :actor_id AND
-                (
-                    -- start_version is between start and end of range AND no end_version
-                    ( start_version BETWEEN :start AND :end AND end_version IS NULL ) OR
-                    
-                    -- start_version and end_version are within the range
-                    ( start_version >= :start AND end_version <= :end ) OR
-
-                    -- range being inserted is partially contained within another
-                    ( start_version <= :end AND end_version >= :end ) OR
-
-                    -- start_version = end + 1 (to collapse ranges)
-                    ( start_version = :end + 1 AND end_version IS NOT NULL ) OR
-
-                    -- end_version = start - 1 (to collapse ranges)
-                    ( end_version = :start - 1 )
-                )
-            RETURNING start_version, end_version",
-        )?
-        .query_map(
-            named_params![
-                ":actor_id": actor_id,
-                ":start": versions.start(),
-                ":end": versions.end(),
-            ],
-            |row| {
-                let start = row.get(0)?;
-                Ok(start..=row.get::<_, Option<Version>>(1)?.unwrap_or(start))
-            },
-        )
-        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())?;
-
-    if !deleted.is_empty() {
-        debug!(
-            "deleted {} still-live versions from database's bookkeeping",
-            deleted.len()
-        );
-    }
-
-    // re-compute the ranges
-    let mut new_ranges = RangeInclusiveSet::from_iter(deleted);
-    new_ranges.insert(versions);
-
-    // we should never have deleted non-contiguous ranges, abort!
-    if new_ranges.len() > 1 {
-        warn!("deleted non-contiguous ranges! {new_ranges:?}");
-        // this serves as a failsafe
-        eyre::bail!("deleted non-contiguous ranges: {new_ranges:?}");
-    }
-
-    let mut inserted = 0;
-
-    for range in new_ranges {
-        // insert cleared versions
-        inserted += conn
-        .prepare_cached(
-            "
-                INSERT INTO __corro_bookkeeping (actor_id, start_version, end_version, db_version, last_seq, ts)
-                    VALUES (?, ?, ?, NULL, NULL, NULL);
-            ",
-        )?
-        .execute(params![actor_id, range.start(), range.end()])?;
-    }
-
-    Ok(inserted)
-}
-
 #[tracing::instrument(skip(agent, bookie), err)]
 pub async fn process_fully_buffered_changes(
     agent: &Agent,
@@ -1028,7 +1364,7 @@ pub async fn process_fully_buffered_changes(
             }
         };
 
-        let tx = conn.immediate_transaction()?;
+        let tx = conn.immediate_transaction_retry("apply_buffered")?;
 
         info!(%actor_id, %version, "Processing buffered changes to crsql_changes (actor: {actor_id}, version: {version}, last_seq: {last_seq})");
 
@@ -1070,33 +1406,18 @@ pub async fn process_fully_buffered_changes(
                 tx.query_row("SELECT crsql_next_db_version()", [], |row| row.get(0))?;
             debug!("db version: {db_version}");
 
-            tx.prepare_cached(
-                "
-                INSERT INTO __corro_bookkeeping (actor_id, start_version, db_version, last_seq, ts)
-                    VALUES (
-                        :actor_id,
-                        :version,
-                        :db_version,
-                        :last_seq,
-                        :ts
-                    );",
-            )?
-            .execute(named_params! {
-                ":actor_id": actor_id,
-                ":version": version,
-                ":db_version": db_version,
-             // ":start_version": 0,
-                ":last_seq": last_seq,
-                ":ts": ts
-            })?;
-
-            debug!(%actor_id, %version, "inserted bookkeeping row after buffered insert");
-
-            Some(KnownDbVersion::Current(CurrentVersion {
+            let current = CurrentVersion {
                 db_version,
                 last_seq,
                 ts,
-            }))
+            };
+            agent
+                .bookkeeping()
+                .insert_current(&tx, actor_id, version, &current)?;
+
+            debug!(%actor_id, %version, "inserted bookkeeping row after buffered insert");
+
+            Some(KnownDbVersion::Current(current))
         } else {
             if let Err(e) = agent.tx_empty().try_send((actor_id, version..=version)) {
                 error!(%actor_id, "could not schedule empties for clear: {e}");
@@ -1163,14 +1484,23 @@ pub async fn process_multiple_changes(
         unknown_changes.push((change, src));
     }
 
-    unknown_changes.sort_by_key(|(change, _src)| change.actor_id);
+    let causal_tables = agent.config().db.causal_tables.clone();
+    if unknown_changes
+        .iter()
+        .any(|(change, _src)| change.touches_any(&causal_tables))
+    {
+        // causal tables need changes applied in timestamp order, even across actors
+        unknown_changes.sort_by_key(|(change, _src)| (change.ts().map(|ts| ts.0), change.actor_id));
+    } else {
+        unknown_changes.sort_by_key(|(change, _src)| change.actor_id);
+    }
 
     let mut conn = agent.pool().write_normal().await?;
 
     let changesets = block_in_place(|| {
         let start = Instant::now();
         let tx = conn
-            .immediate_transaction()
+            .immediate_transaction_retry("apply")
             .map_err(|source| ChangeError::Rusqlite {
                 source,
                 actor_id: None,
@@ -1204,7 +1534,7 @@ pub async fn process_multiple_changes(
 
                 let mut seen = RangeInclusiveMap::new();
 
-                for (change, src) in changes {
+                for (mut change, src) in changes {
                     trace!("handling a single changeset: {change:?}");
                     let seqs = change.seqs();
                     if booked_write.contains_all(change.versions(), change.seqs()) {
@@ -1232,6 +1562,26 @@ pub async fn process_multiple_changes(
                         continue;
                     }
 
+                    // tables and columns dropped here have nowhere to apply
+                    // changes to, the versions are still known
+                    let (skipped, skipped_columns) = {
+                        let schema = agent.schema().read();
+                        (
+                            skip_dropped_tables(&schema, &mut change.changeset),
+                            skip_dropped_columns(&schema, &mut change.changeset),
+                        )
+                    };
+                    if skipped > 0 {
+                        debug!(%actor_id, ?versions, "skipped {skipped} changes to dropped tables");
+                        counter!("corro.agent.changes.dropped_table.skipped")
+                            .increment(skipped as u64);
+                    }
+                    if skipped_columns > 0 {
+                        debug!(%actor_id, ?versions, "skipped {skipped_columns} changes to dropped columns");
+                        counter!("corro.agent.changes.dropped_column.skipped")
+                            .increment(skipped_columns as u64);
+                    }
+
                     // optimizing this, insert later!
                     let known = if change.is_complete() && change.is_empty() {
                         // we never want to block here
@@ -1248,6 +1598,27 @@ pub async fn process_multiple_changes(
                             }
                         }
 
+                        // the peer's schema may be ahead of ours, applying its
+                        // changes would fail until ours catches up
+                        let missing = missing_schema(&agent.schema().read(), change.changes());
+                        if !missing.is_empty() {
+                            let missing: Vec<String> =
+                                missing.iter().map(|missing| missing.to_string()).collect();
+                            warn!(%actor_id, ?versions, "schema behind, holding changeset until it has {}", missing.join(", "));
+                            counter!("corro.agent.changes.schema_behind.held").increment(1);
+                            agent
+                                .activity()
+                                .publish_with(|| ActivityKind::SchemaBehind {
+                                    actor_id,
+                                    versions: versions.clone(),
+                                    missing,
+                                });
+                            if !agent.schema_behind().buffer(change, src) {
+                                warn!(%actor_id, ?versions, "too many changesets held until the schema catches up, dropping it");
+                            }
+                            continue;
+                        }
+
                         let (known, versions) = match process_single_version(
                             &agent,
                             &tx,
@@ -1289,24 +1660,18 @@ pub async fn process_multiple_changes(
                     KnownDbVersion::Partial { .. } => {
                         continue;
                     }
-                    KnownDbVersion::Current(CurrentVersion {
-                        db_version,
-                        last_seq,
-                        ts,
-                    }) => {
+                    KnownDbVersion::Current(current) => {
                         count += 1;
                         let version = versions.start();
-                        debug!(%actor_id, self_actor_id = %agent.actor_id(), %version, "inserting bookkeeping row db_version: {db_version}, ts: {ts:?}");
-                        tx.prepare_cached("
-                            INSERT INTO __corro_bookkeeping ( actor_id,  start_version,  db_version,  last_seq,  ts)
-                                                    VALUES  (:actor_id, :start_version, :db_version, :last_seq, :ts);").map_err(|source| ChangeError::Rusqlite{source, actor_id: Some(*actor_id), version: Some(*version)})?
-                            .execute(named_params!{
-                                ":actor_id": actor_id,
-                                ":start_version": *version,
-                                ":db_version": *db_version,
-                                ":last_seq": *last_seq,
-                                ":ts": *ts
-                            }).map_err(|source| ChangeError::Rusqlite{source, actor_id: Some(*actor_id), version: Some(*version)})?;
+                        debug!(%actor_id, self_actor_id = %agent.actor_id(), %version, "inserting bookkeeping row db_version: {}, ts: {:?}", current.db_version, current.ts);
+                        agent
+                            .bookkeeping()
+                            .insert_current(&tx, *actor_id, *version, current)
+                            .map_err(|e| ChangeError::Rusqlite {
+                                source: e.into(),
+                                actor_id: Some(*actor_id),
+                                version: Some(*version),
+                            })?;
                     }
                     KnownDbVersion::Cleared => {
                         debug!(%actor_id, self_actor_id = %agent.actor_id(), ?versions, "inserting CLEARED bookkeeping");
@@ -1328,10 +1693,13 @@ pub async fn process_multiple_changes(
         })?;
 
         for (_, changeset, _, _) in changesets.iter() {
-            if let Some(ts) = changeset.ts() {
-                let dur = (agent.clock().new_timestamp().get_time() - ts.0).to_duration();
+            let lag = changeset
+                .ts()
+                .map(|ts| (agent.clock().new_timestamp().get_time() - ts.0).to_duration());
+            if let Some(dur) = lag {
                 histogram!("corro.agent.changes.commit.lag.seconds").record(dur);
             }
+            agent.history().record_applied(changeset.len() as u64, lag);
         }
 
         debug!("committed {count} changes in {:?}", start.elapsed());
@@ -1377,10 +1745,17 @@ pub async fn process_multiple_changes(
         Ok::<_, ChangeError>(changesets)
     })?;
 
-    for (_actor_id, changeset, db_version, _src) in changesets {
+    for (actor_id, changeset, db_version, src) in changesets {
         agent
             .subs_manager()
             .match_changes(changeset.changes(), db_version);
+        agent.key_watches().match_changes(changeset.changes());
+        agent.activity().publish_with(|| ActivityKind::Applied {
+            actor_id,
+            versions: changeset.versions(),
+            rows: changeset.len(),
+            source: <&'static str>::from(src).to_string(),
+        });
     }
 
     histogram!("corro.agent.changes.processing.time.seconds").record(start.elapsed());
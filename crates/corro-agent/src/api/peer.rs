@@ -1,5 +1,5 @@
 use std::cmp;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::net::SocketAddr;
 use std::ops::RangeInclusive;
 use std::sync::Arc;
@@ -11,20 +11,24 @@ use corro_types::actor::ClusterId;
 use corro_types::agent::{
     Agent, CurrentVersion, KnownDbVersion, KnownVersion, PartialVersion, SplitPool,
 };
+use corro_types::api::ErrorCode;
 use corro_types::base::{CrsqlSeq, Version};
 use corro_types::broadcast::{
     BiPayload, BiPayloadV1, ChangeSource, ChangeV1, Changeset, Timestamp,
 };
 use corro_types::change::{row_to_change, Change, ChunkedChanges};
 use corro_types::config::{GossipConfig, TlsClientConfig};
+use corro_types::error::ApiError;
+use corro_types::schema::{schema_digest, schema_drift};
 use corro_types::sync::{
-    generate_sync, SyncMessage, SyncMessageEncodeError, SyncMessageV1, SyncNeedV1, SyncRejectionV1,
-    SyncRequestV1, SyncStateV1, SyncTraceContextV1,
+    generate_sync, SyncAuth, SyncChallengeResponseV1, SyncChallengeV1, SyncCompressionV1,
+    SyncMessage, SyncMessageDecodeError, SyncMessageEncodeError, SyncMessageV1, SyncNeedV1,
+    SyncRejectionV1, SyncRequestV1, SyncRole, SyncStateV1, SyncTraceContextV1,
 };
 use futures::stream::FuturesUnordered;
 use futures::{Future, Stream, TryFutureExt, TryStreamExt};
 use itertools::Itertools;
-use metrics::counter;
+use metrics::{counter, gauge};
 use quinn::{RecvStream, SendStream};
 use rand::seq::SliceRandom;
 use rangemap::RangeInclusiveSet;
@@ -42,6 +46,7 @@ use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use crate::agent::SyncRecvError;
+use crate::net::bind_udp;
 use crate::transport::{Transport, TransportError};
 
 use corro_types::{
@@ -61,6 +66,17 @@ pub enum SyncError {
     Transport(#[from] TransportError),
 }
 
+impl ApiError for SyncError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            SyncError::Rejection(_) => ErrorCode::SyncRejected,
+            SyncError::Send(_) | SyncError::Recv(_) | SyncError::Transport(_) => {
+                ErrorCode::SyncFailed
+            }
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum SyncSendError {
     #[error(transparent)]
@@ -217,7 +233,13 @@ async fn build_quinn_server_config(config: &GossipConfig) -> eyre::Result<quinn:
 pub async fn gossip_server_endpoint(config: &GossipConfig) -> eyre::Result<quinn::Endpoint> {
     let server_config = build_quinn_server_config(config).await?;
 
-    Ok(quinn::Endpoint::server(server_config, config.bind_addr)?)
+    let socket = bind_udp(config.bind_addr, config.ipv6_only)?;
+    Ok(quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config),
+        socket,
+        quinn_runtime()?,
+    )?)
 }
 
 fn client_cert_auth(
@@ -317,12 +339,22 @@ async fn build_quinn_client_config(config: &GossipConfig) -> eyre::Result<quinn:
 pub async fn gossip_client_endpoint(config: &GossipConfig) -> eyre::Result<quinn::Endpoint> {
     let client_config = build_quinn_client_config(config).await?;
 
-    let mut client = quinn::Endpoint::client(config.client_addr)?;
+    let socket = bind_udp(config.client_addr, config.ipv6_only)?;
+    let mut client = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        None,
+        socket,
+        quinn_runtime()?,
+    )?;
 
     client.set_default_client_config(client_config);
     Ok(client)
 }
 
+fn quinn_runtime() -> eyre::Result<Arc<dyn quinn::Runtime>> {
+    quinn::default_runtime().ok_or_else(|| eyre::eyre!("no async runtime found for quinn"))
+}
+
 /// Dummy certificate verifier that treats any certificate as valid.
 /// NOTE, such verification is vulnerable to MITM attacks, but convenient for testing.
 struct SkipServerVerification;
@@ -851,6 +883,100 @@ fn encode_sync_msg(
     Ok(())
 }
 
+/// Bytes and CPU time spent compressing, or decompressing, the changesets
+/// of a sync with a peer
+struct CompressionStats {
+    actor_id: ActorId,
+    codec: Option<&'static str>,
+    raw: u64,
+    compressed: u64,
+    elapsed: Duration,
+}
+
+impl CompressionStats {
+    fn new(actor_id: ActorId) -> Self {
+        Self {
+            actor_id,
+            codec: None,
+            raw: 0,
+            compressed: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Compresses changesets, other messages are too small to bother
+    fn compress(
+        &mut self,
+        codec: SyncCompressionV1,
+        encode_buf: &mut BytesMut,
+        msg: SyncMessage,
+    ) -> Result<SyncMessage, SyncSendError> {
+        if !matches!(msg, SyncMessage::V1(SyncMessageV1::Changeset(_))) {
+            return Ok(msg);
+        }
+
+        let start = Instant::now();
+        msg.write_to_stream(encode_buf.writer())
+            .map_err(SyncMessageEncodeError::from)?;
+        let raw = encode_buf.split();
+        let data = codec.compress(&raw)?;
+        self.record(codec, raw.len(), data.len().min(raw.len()), start.elapsed());
+
+        // incompressible, the peer reads both
+        if data.len() >= raw.len() {
+            return Ok(msg);
+        }
+
+        Ok(SyncMessage::V1(SyncMessageV1::Compressed { codec, data }))
+    }
+
+    /// Unwraps compressed messages, others are returned as-is
+    fn decompress(&mut self, msg: SyncMessage) -> Result<SyncMessage, SyncRecvError> {
+        let SyncMessage::V1(SyncMessageV1::Compressed { codec, data }) = msg else {
+            return Ok(msg);
+        };
+
+        let start = Instant::now();
+        let raw = codec.decompress(&data)?;
+        let msg = SyncMessage::from_slice(&raw).map_err(SyncMessageDecodeError::from)?;
+        self.record(codec, raw.len(), data.len(), start.elapsed());
+
+        Ok(msg)
+    }
+
+    fn record(
+        &mut self,
+        codec: SyncCompressionV1,
+        raw: usize,
+        compressed: usize,
+        elapsed: Duration,
+    ) {
+        self.codec = Some(codec.name());
+        self.raw += raw as u64;
+        self.compressed += compressed as u64;
+        self.elapsed += elapsed;
+    }
+
+    /// `op` is either `compress` or `decompress`
+    fn emit(&self, op: &'static str) {
+        let Some(codec) = self.codec else {
+            return;
+        };
+        let actor_id = self.actor_id.to_string();
+
+        counter!("corro.sync.compression.raw.bytes", "actor_id" => actor_id.clone(), "codec" => codec, "op" => op)
+            .increment(self.raw);
+        counter!("corro.sync.compression.compressed.bytes", "actor_id" => actor_id.clone(), "codec" => codec, "op" => op)
+            .increment(self.compressed);
+        counter!("corro.sync.compression.cpu.microseconds", "actor_id" => actor_id.clone(), "codec" => codec, "op" => op)
+            .increment(self.elapsed.as_micros() as u64);
+        if self.compressed > 0 {
+            gauge!("corro.sync.compression.ratio", "actor_id" => actor_id, "op" => op)
+                .set(self.raw as f64 / self.compressed as f64);
+        }
+    }
+}
+
 async fn encode_write_bipayload_msg(
     codec: &mut LengthDelimitedCodec,
     encode_buf: &mut BytesMut,
@@ -888,6 +1014,30 @@ async fn encode_write_sync_msg(
     write_buf(send_buf, write).await
 }
 
+/// Rejects a sync, if the client knows about that kind of rejection. Others
+/// only see the stream end.
+async fn encode_write_sync_rejection(
+    codec: &mut LengthDelimitedCodec,
+    encode_buf: &mut BytesMut,
+    send_buf: &mut BytesMut,
+    rejection: SyncRejectionV1,
+    known_kinds: u32,
+    write: &mut SendStream,
+) -> Result<(), SyncSendError> {
+    if rejection.kind() >= known_kinds {
+        debug!("not sending rejection unknown to the client: {rejection}");
+        return Ok(());
+    }
+    encode_write_sync_msg(
+        codec,
+        encode_buf,
+        send_buf,
+        SyncMessage::V1(SyncMessageV1::Rejection(rejection)),
+        write,
+    )
+    .await
+}
+
 #[tracing::instrument(skip_all, fields(buf_size = send_buf.len()), err)]
 async fn write_buf(send_buf: &mut BytesMut, write: &mut SendStream) -> Result<(), SyncSendError> {
     let len = send_buf.len();
@@ -917,7 +1067,138 @@ pub async fn read_sync_msg<R: Stream<Item = std::io::Result<BytesMut>> + Unpin>(
     }
 }
 
+/// Our schema digest, sent along our sync state. Drift can't be detected
+/// when it's empty, so errors are only logged.
+#[tracing::instrument(skip_all)]
+async fn load_schema_digest(agent: &Agent) -> BTreeMap<String, u64> {
+    let res = match agent.pool().read().await {
+        Ok(conn) => block_in_place(|| schema_digest(&conn)).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    res.unwrap_or_else(|e| {
+        warn!("could not compute schema digest: {e}");
+        BTreeMap::new()
+    })
+}
+
 #[tracing::instrument(skip_all, err)]
+/// Answers the server's challenge, then checks the server answered ours for
+/// the actor id it claims, returning the server's actor id
+#[allow(clippy::too_many_arguments)]
+async fn answer_sync_challenge(
+    agent: &Agent,
+    auth: &SyncAuth,
+    challenge: &SyncChallengeV1,
+    expected: ActorId,
+    codec: &mut LengthDelimitedCodec,
+    encode_buf: &mut BytesMut,
+    send_buf: &mut BytesMut,
+    write: &mut SendStream,
+    read: &mut FramedRead<RecvStream, LengthDelimitedCodec>,
+) -> Result<ActorId, SyncError> {
+    let their_challenge = match timeout(Duration::from_secs(2), read_sync_msg(read))
+        .await
+        .map_err(SyncRecvError::from)??
+    {
+        Some(SyncMessage::V1(SyncMessageV1::Challenge(challenge))) => challenge,
+        Some(SyncMessage::V1(SyncMessageV1::Rejection(rejection))) => return Err(rejection.into()),
+        Some(_) => return Err(SyncRecvError::ExpectedChallenge.into()),
+        None => return Err(SyncRecvError::UnexpectedEndOfStream.into()),
+    };
+
+    encode_write_sync_msg(
+        codec,
+        encode_buf,
+        send_buf,
+        SyncMessage::V1(SyncMessageV1::ChallengeResponse(SyncChallengeResponseV1 {
+            actor_id: agent.actor_id(),
+            tag: auth.respond(SyncRole::Client, agent.actor_id(), &their_challenge),
+        })),
+        write,
+    )
+    .await?;
+    write.flush().await.map_err(SyncSendError::from)?;
+
+    let res = match timeout(Duration::from_secs(2), read_sync_msg(read))
+        .await
+        .map_err(SyncRecvError::from)??
+    {
+        Some(SyncMessage::V1(SyncMessageV1::ChallengeResponse(res))) => res,
+        Some(SyncMessage::V1(SyncMessageV1::Rejection(rejection))) => return Err(rejection.into()),
+        Some(_) => return Err(SyncRecvError::ExpectedChallengeResponse.into()),
+        None => return Err(SyncRecvError::UnexpectedEndOfStream.into()),
+    };
+
+    // relays from other segments are dialed without knowing their actor ID
+    let expected_actor = expected == ActorId::default() || expected == res.actor_id;
+    if !expected_actor || !auth.verify(SyncRole::Server, challenge, &res) {
+        counter!("corro.sync.client.unauthenticated").increment(1);
+        return Err(SyncRecvError::Unauthenticated(res.actor_id).into());
+    }
+
+    Ok(res.actor_id)
+}
+
+/// Challenges the client and checks its answer for the actor id it started
+/// the sync with, only answering the client's own challenge once it passed
+#[allow(clippy::too_many_arguments)]
+async fn challenge_sync_client(
+    agent: &Agent,
+    auth: &SyncAuth,
+    their_actor_id: ActorId,
+    codec: &mut LengthDelimitedCodec,
+    encode_buf: &mut BytesMut,
+    send_buf: &mut BytesMut,
+    write: &mut SendStream,
+    read: &mut FramedRead<RecvStream, LengthDelimitedCodec>,
+) -> Result<bool, SyncError> {
+    let their_challenge = match timeout(Duration::from_secs(2), read_sync_msg(read)).await {
+        Ok(Ok(Some(SyncMessage::V1(SyncMessageV1::Challenge(challenge))))) => challenge,
+        // clients without the cluster secret don't send challenges
+        Ok(Ok(Some(_))) | Err(_) => return Ok(false),
+        Ok(Ok(None)) => return Err(SyncRecvError::UnexpectedEndOfStream.into()),
+        Ok(Err(e)) => return Err(e.into()),
+    };
+
+    let challenge = SyncAuth::challenge(agent.actor_id());
+    encode_write_sync_msg(
+        codec,
+        encode_buf,
+        send_buf,
+        SyncMessage::V1(SyncMessageV1::Challenge(challenge.clone())),
+        write,
+    )
+    .await?;
+    write.flush().await.map_err(SyncSendError::from)?;
+
+    let authenticated = match timeout(Duration::from_secs(2), read_sync_msg(read)).await {
+        Ok(Ok(Some(SyncMessage::V1(SyncMessageV1::ChallengeResponse(res))))) => {
+            res.actor_id == their_actor_id && auth.verify(SyncRole::Client, &challenge, &res)
+        }
+        Ok(Ok(Some(_))) | Err(_) => false,
+        Ok(Ok(None)) => return Err(SyncRecvError::UnexpectedEndOfStream.into()),
+        Ok(Err(e)) => return Err(e.into()),
+    };
+    if !authenticated {
+        return Ok(false);
+    }
+
+    encode_write_sync_msg(
+        codec,
+        encode_buf,
+        send_buf,
+        SyncMessage::V1(SyncMessageV1::ChallengeResponse(SyncChallengeResponseV1 {
+            actor_id: agent.actor_id(),
+            tag: auth.respond(SyncRole::Server, agent.actor_id(), &their_challenge),
+        })),
+        write,
+    )
+    .await?;
+    write.flush().await.map_err(SyncSendError::from)?;
+
+    Ok(true)
+}
+
 pub async fn parallel_sync(
     agent: &Agent,
     transport: &Transport,
@@ -939,6 +1220,17 @@ pub async fn parallel_sync(
         prop.inject_context(&tracing::Span::current().context(), &mut trace_ctx)
     });
 
+    let auth = agent
+        .config()
+        .gossip
+        .cluster_secret
+        .as_deref()
+        .map(SyncAuth::new);
+    let auth = auth.as_ref();
+
+    let our_schema = load_schema_digest(agent).await;
+    let our_schema = &our_schema;
+
     let results = FuturesUnordered::from_iter(members.iter().map(|(actor_id, addr)| {
         let trace_ctx = trace_ctx.clone();
         async {
@@ -954,11 +1246,17 @@ pub async fn parallel_sync(
                     let (mut tx, rx) = transport.open_bi(*addr).await?;
                     let mut read = FramedRead::new(rx, LengthDelimitedCodec::new());
 
+                    // the connection was just used, its stats are fresh
+                    let compression = transport.path_stats(*addr).await.and_then(|(rtt, bandwidth)| {
+                        agent.config().gossip.compression.choose(rtt, bandwidth)
+                    });
+                    debug!(%actor_id, %addr, "requesting changesets compressed with: {compression:?}");
+
                     encode_write_bipayload_msg(
                         &mut codec,
                         &mut encode_buf,
                         &mut send_buf,
-                        BiPayload::V1 {data: BiPayloadV1::SyncStart {actor_id: agent.actor_id(), trace_ctx}, cluster_id: agent.cluster_id()},
+                        BiPayload::new(BiPayloadV1::SyncStart {actor_id: agent.actor_id(), trace_ctx, compression, rejections: Some(SyncRejectionV1::KINDS)}, agent.cluster_id(), agent.config().gossip.tagged_payloads),
                         &mut tx,
                     ).instrument(info_span!("write_sync_start"))
                     .await?;
@@ -975,10 +1273,42 @@ pub async fn parallel_sync(
                     .await?;
 
                     trace!(%actor_id, self_actor_id = %agent.actor_id(), "sent clock payload");
+
+                    let challenge = auth.map(|_| SyncAuth::challenge(agent.actor_id()));
+                    if let Some(challenge) = challenge.clone() {
+                        encode_write_sync_msg(
+                            &mut codec,
+                            &mut encode_buf,
+                            &mut send_buf,
+                            SyncMessage::V1(SyncMessageV1::Challenge(challenge)),
+                            &mut tx,
+                        ).instrument(info_span!("write_sync_challenge"))
+                        .await?;
+                    }
+
                     tx.flush().instrument(info_span!("quic_flush")).await.map_err(SyncSendError::from)?;
 
                     trace!(%actor_id, self_actor_id = %agent.actor_id(), "flushed sync payloads");
 
+                    let authenticated = match auth.zip(challenge.as_ref()) {
+                        Some((auth, challenge)) => Some(
+                            answer_sync_challenge(
+                                agent,
+                                auth,
+                                challenge,
+                                actor_id,
+                                &mut codec,
+                                &mut encode_buf,
+                                &mut send_buf,
+                                &mut tx,
+                                &mut read,
+                            )
+                            .instrument(info_span!("sync_challenge"))
+                            .await?,
+                        ),
+                        None => None,
+                    };
+
                     let their_sync_state = match timeout(Duration::from_secs(2), read_sync_msg(&mut read)).instrument(info_span!("read_sync_state")).await.map_err(SyncRecvError::from)?? {
                         Some(SyncMessage::V1(SyncMessageV1::State(state))) => state,
                         Some(SyncMessage::V1(SyncMessageV1::Rejection(rejection))) => {
@@ -989,6 +1319,16 @@ pub async fn parallel_sync(
                     };
                     trace!(%actor_id, self_actor_id = %agent.actor_id(), "read state payload: {their_sync_state:?}");
 
+                    // the actor ID of relays from other segments is not known in advance
+                    let actor_id = their_sync_state.actor_id;
+                    if authenticated.map_or(false, |id| id != actor_id) {
+                        return Err(SyncRecvError::Unauthenticated(actor_id).into());
+                    }
+                    if !agent.config().gossip.acl.allows_actor(actor_id) {
+                        counter!("corro.sync.client.denied").increment(1);
+                        return Err(SyncRecvError::NotAllowed(actor_id).into());
+                    }
+
                     match timeout(Duration::from_secs(2), read_sync_msg(&mut read)).instrument(info_span!("read_sync_clock")).await.map_err(SyncRecvError::from)??  {
                         Some(SyncMessage::V1(SyncMessageV1::Clock(ts))) => match actor_id.try_into() {
                             Ok(id) => {
@@ -1010,11 +1350,23 @@ pub async fn parallel_sync(
 
                     counter!("corro.sync.client.member", "id" => actor_id.to_string(), "addr" => addr.to_string()).increment(1);
 
+                    // nodes from before schema digests send empty ones
+                    if !their_sync_state.schema.is_empty() {
+                        let drift = schema_drift(our_schema, &their_sync_state.schema);
+                        if !drift.is_empty() {
+                            counter!("corro.sync.schema.drift", "id" => actor_id.to_string()).increment(1);
+                        }
+                        let previous = agent.members().write().schema_drift.insert(actor_id, drift.clone());
+                        if !drift.is_empty() && previous.as_ref() != Some(&drift) {
+                            warn!(%actor_id, "schema drift detected for tables: {}", drift.join(", "));
+                        }
+                    }
+
                     let needs = our_sync_state.compute_available_needs(&their_sync_state);
 
                     trace!(%actor_id, self_actor_id = %agent.actor_id(), "computed needs");
 
-                    Ok::<_, SyncError>((needs, tx, read))
+                    Ok::<_, SyncError>((actor_id, needs, tx, read))
                 }.await
             )
         }.instrument(info_span!("sync_client_handshake", %actor_id, %addr))
@@ -1028,7 +1380,7 @@ pub async fn parallel_sync(
     let syncers = results
         .into_iter()
         .fold(Ok(vec![]), |agg, (actor_id, addr, res)| match res {
-            Ok((needs, tx, read)) => {
+            Ok((actor_id, needs, tx, read)) => {
                 let mut v = agg.unwrap_or_default();
                 v.push((actor_id, addr, needs, tx, read));
                 Ok(v)
@@ -1245,6 +1597,7 @@ pub async fn parallel_sync(
         let tx_changes = agent.tx_changes().clone();
         async move {
             let mut count = 0;
+            let mut stats = CompressionStats::new(actor_id);
 
             loop {
                 match read_sync_msg(&mut read).await {
@@ -1255,7 +1608,7 @@ pub async fn parallel_sync(
                         error!(%actor_id, "sync recv error: {e}");
                         break;
                     }
-                    Ok(Some(msg)) => match msg {
+                    Ok(Some(msg)) => match stats.decompress(msg)? {
                         SyncMessage::V1(SyncMessageV1::Changeset(change)) => {
                             let changes_len = cmp::max(change.len(), 1);
                             // tracing::Span::current().record("changes_len", changes_len);
@@ -1279,6 +1632,16 @@ pub async fn parallel_sync(
                             warn!("received sync clock message unexpectedly, ignoring");
                             continue;
                         }
+                        SyncMessage::V1(
+                            SyncMessageV1::Challenge(_) | SyncMessageV1::ChallengeResponse(_),
+                        ) => {
+                            warn!("received sync challenge message unexpectedly, ignoring");
+                            continue;
+                        }
+                        SyncMessage::V1(SyncMessageV1::Compressed { .. }) => {
+                            warn!("received nested compressed sync message, ignoring");
+                            continue;
+                        }
                         SyncMessage::V1(SyncMessageV1::Rejection(rejection)) => {
                             return Err(rejection.into())
                         }
@@ -1286,6 +1649,8 @@ pub async fn parallel_sync(
                 }
             }
 
+            stats.emit("decompress");
+
             debug!(%actor_id, %count, "done reading sync messages");
 
             Ok(count)
@@ -1304,12 +1669,15 @@ pub async fn parallel_sync(
     Ok(counts.into_iter().flatten().sum::<usize>())
 }
 
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip(agent, bookie, their_actor_id, read, write), fields(actor_id = %their_actor_id), err)]
 pub async fn serve_sync(
     agent: &Agent,
     bookie: &Bookie,
     their_actor_id: ActorId,
     trace_ctx: SyncTraceContextV1,
+    compression: Option<SyncCompressionV1>,
+    rejections: Option<u32>,
     cluster_id: ClusterId,
     mut read: FramedRead<RecvStream, LengthDelimitedCodec>,
     mut write: SendStream,
@@ -1319,6 +1687,7 @@ pub async fn serve_sync(
     tracing::Span::current().set_parent(context);
 
     debug!(actor_id = %their_actor_id, self_actor_id = %agent.actor_id(), "received sync request");
+    let known_rejections = rejections.unwrap_or(SyncRejectionV1::INITIAL_KINDS);
     let mut codec = LengthDelimitedCodec::new();
     let mut send_buf = BytesMut::new();
     let mut encode_buf = BytesMut::new();
@@ -1360,6 +1729,58 @@ pub async fn serve_sync(
 
     trace!(actor_id = %their_actor_id, self_actor_id = %agent.actor_id(), "read clock");
 
+    let auth = agent
+        .config()
+        .gossip
+        .cluster_secret
+        .as_deref()
+        .map(SyncAuth::new);
+    if let Some(auth) = auth {
+        let authenticated = challenge_sync_client(
+            agent,
+            &auth,
+            their_actor_id,
+            &mut codec,
+            &mut encode_buf,
+            &mut send_buf,
+            &mut write,
+            &mut read,
+        )
+        .instrument(info_span!("sync_challenge"))
+        .await?;
+
+        if !authenticated {
+            counter!("corro.sync.server.unauthenticated").increment(1);
+            encode_write_sync_rejection(
+                &mut codec,
+                &mut encode_buf,
+                &mut send_buf,
+                SyncRejectionV1::Unauthenticated,
+                known_rejections,
+                &mut write,
+            )
+            .instrument(info_span!("write_rejection_unauthenticated"))
+            .await?;
+            return Ok(0);
+        }
+    }
+
+    // only checked now that the actor ID is authenticated, when possible
+    if !agent.config().gossip.acl.allows_actor(their_actor_id) {
+        counter!("corro.sync.server.denied").increment(1);
+        encode_write_sync_rejection(
+            &mut codec,
+            &mut encode_buf,
+            &mut send_buf,
+            SyncRejectionV1::NotAllowed,
+            known_rejections,
+            &mut write,
+        )
+        .instrument(info_span!("write_rejection_not_allowed"))
+        .await?;
+        return Ok(0);
+    }
+
     let _permit = match agent.limits().sync.try_acquire() {
         Ok(permit) => permit,
         Err(_) => {
@@ -1379,7 +1800,8 @@ pub async fn serve_sync(
         }
     };
 
-    let sync_state = generate_sync(bookie, agent.actor_id()).await;
+    let mut sync_state = generate_sync(bookie, agent.actor_id()).await;
+    sync_state.schema = load_schema_digest(agent).await;
 
     // first, send the current sync state
     encode_write_sync_msg(
@@ -1426,6 +1848,7 @@ pub async fn serve_sync(
     let (send_res, recv_res) = tokio::join!(
         async move {
             let mut count = 0;
+            let mut stats = CompressionStats::new(their_actor_id);
 
             let mut check_buf = tokio::time::interval(Duration::from_secs(1));
 
@@ -1453,6 +1876,12 @@ pub async fn serve_sync(
                             if let SyncMessage::V1(SyncMessageV1::Changeset(change)) = &msg {
                                 count += change.len();
                             }
+                            let msg = match compression {
+                                Some(compression) => {
+                                    stats.compress(compression, &mut encode_buf, msg)?
+                                }
+                                None => msg,
+                            };
                             encode_sync_msg(&mut codec, &mut encode_buf, &mut send_buf, msg)?;
 
                             if send_buf.len() >= 16 * 1024 {
@@ -1484,6 +1913,8 @@ pub async fn serve_sync(
 
             debug!(actor_id = %agent.actor_id(), "done writing sync messages (count: {count})");
 
+            stats.emit("compress");
+
             counter!("corro.sync.changes.sent", "actor_id" => their_actor_id.to_string()).increment(count as u64);
 
             Ok::<_, SyncError>(count)
@@ -1526,6 +1957,16 @@ pub async fn serve_sync(
                             warn!(actor_id = %their_actor_id, "received sync clock message more than once, ignoring");
                             continue;
                         }
+                        SyncMessage::V1(
+                            SyncMessageV1::Challenge(_) | SyncMessageV1::ChallengeResponse(_),
+                        ) => {
+                            warn!(actor_id = %their_actor_id, "received sync challenge message unexpectedly, ignoring");
+                            continue;
+                        }
+                        SyncMessage::V1(SyncMessageV1::Compressed { .. }) => {
+                            warn!(actor_id = %their_actor_id, "received compressed sync message unexpectedly, ignoring");
+                            continue;
+                        }
                         SyncMessage::V1(SyncMessageV1::Rejection(rejection)) => {
                             return Err(rejection.into())
                         }
@@ -1590,8 +2031,12 @@ mod tests {
         )
         .await?;
 
-        let (status_code, _res) =
-            api_v1_db_schema(Extension(agent.clone()), Json(vec![TEST_SCHEMA.to_owned()])).await;
+        let (status_code, _res) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            Json(vec![TEST_SCHEMA.to_owned()]),
+        )
+        .await;
 
         assert_eq!(status_code, StatusCode::OK);
 
@@ -1821,6 +2266,12 @@ mod tests {
             plaintext: false,
             max_mtu: None,
             disable_gso: false,
+            ipv6_only: false,
+            relay: None,
+            acl: Default::default(),
+            cluster_secret: None,
+            tagged_payloads: false,
+            compression: Default::default(),
         };
 
         let server = gossip_server_endpoint(&gossip_config).await?;
@@ -0,0 +1,667 @@
+//! Guided backfills: update a column for every row of a table in bounded
+//! transactions, paced so the resulting changes don't flood the cluster.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::Extension;
+use corro_types::{
+    agent::Agent,
+    api::{BackfillRequest, BackfillState, BackfillStatus, ErrorCode, ExecResult, SqliteValue},
+    error::{ApiError, ChangeError},
+};
+use hyper::StatusCode;
+use itertools::Itertools;
+use metrics::counter;
+use parking_lot::{Mutex, RwLock};
+use rusqlite::{params_from_iter, ToSql};
+use spawn::{spawn_named, Shutdown};
+use tokio::task::block_in_place;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+use tripwire::Tripwire;
+
+use super::{error::HttpApiError, make_broadcastable_changes};
+
+const DEFAULT_BATCH_SIZE: usize = 500;
+const MAX_BATCH_SIZE: usize = 10_000;
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub type SharedBackfills = Arc<Backfills>;
+
+/// Backfills started on this node, finished ones are kept around so their
+/// outcome can be looked up
+#[derive(Default)]
+pub struct Backfills {
+    next_id: AtomicU64,
+    jobs: RwLock<BTreeMap<u64, Arc<BackfillJob>>>,
+}
+
+struct BackfillJob {
+    status: Mutex<BackfillStatus>,
+    started_at: Instant,
+    cancel: CancellationToken,
+}
+
+impl BackfillJob {
+    fn status(&self) -> BackfillStatus {
+        let mut status = self.status.lock().clone();
+        if status.state == BackfillState::Running {
+            status.elapsed = self.started_at.elapsed().as_secs_f64();
+        }
+        status
+    }
+
+    fn finish(&self, state: BackfillState, error: Option<String>) {
+        let mut status = self.status.lock();
+        status.state = state;
+        status.error = error;
+        status.elapsed = self.started_at.elapsed().as_secs_f64();
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackfillError {
+    #[error("table '{0}' does not exist")]
+    UnknownTable(String),
+    #[error("column '{column}' does not exist in table '{table}'")]
+    UnknownColumn { table: String, column: String },
+    #[error("column '{0}' is part of the primary key and can't be backfilled")]
+    PrimaryKey(String),
+    #[error("invalid backfill expression or filter: {0}")]
+    InvalidSql(rusqlite::Error),
+    #[error(transparent)]
+    Pool(#[from] corro_types::sqlite::SqlitePoolError),
+}
+
+impl ApiError for BackfillError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            BackfillError::UnknownTable(_)
+            | BackfillError::UnknownColumn { .. }
+            | BackfillError::PrimaryKey(_) => ErrorCode::BadRequest,
+            BackfillError::InvalidSql(_) => ErrorCode::InvalidStatement,
+            BackfillError::Pool(_) => ErrorCode::Unavailable,
+        }
+    }
+}
+
+impl From<BackfillError> for (StatusCode, axum::Json<ExecResult>) {
+    fn from(e: BackfillError) -> Self {
+        (e.status(), axum::Json(e.exec_result()))
+    }
+}
+
+/// Statements walking the table in primary key order, `*_after` variants
+/// resume after the last primary key of the previous batch
+struct BackfillSql {
+    pk_len: usize,
+    batch: String,
+    batch_after: String,
+    update: String,
+    update_after: String,
+    /// Sets a different value before `update` so rows already holding the
+    /// right one still produce a change, only when forced
+    touch: Option<String>,
+    touch_after: Option<String>,
+}
+
+impl BackfillSql {
+    fn new(req: &BackfillRequest, pk: &[String]) -> Self {
+        let BackfillRequest {
+            table,
+            column,
+            expr,
+            filter,
+            force,
+            ..
+        } = req;
+
+        let pk_cols = pk.iter().map(|col| format!("\"{col}\"")).join(", ");
+        let placeholders = pk.iter().map(|_| "?").join(", ");
+        let after = format!("({pk_cols}) > ({placeholders})");
+        let upto = format!("({pk_cols}) <= ({placeholders})");
+
+        let filter = filter
+            .as_ref()
+            .map(|filter| format!(" AND ({filter})"))
+            .unwrap_or_default();
+
+        let batch = |cond: &str| {
+            format!("SELECT {pk_cols} FROM \"{table}\" {cond} ORDER BY {pk_cols} LIMIT ?")
+        };
+
+        // rows that already have the right value would produce no-op
+        // changes, skip them. this also makes re-submitting an
+        // interrupted backfill cheap.
+        let update = |cond: &str| {
+            format!(
+                "UPDATE \"{table}\" SET \"{column}\" = ({expr}) WHERE {cond} AND \"{column}\" IS NOT ({expr}){filter}"
+            )
+        };
+
+        // bumps the column's version so the value set by `update` wins
+        // over what other nodes have for the same rows
+        let touch = |cond: &str| {
+            format!(
+                "UPDATE \"{table}\" SET \"{column}\" = CASE WHEN \"{column}\" IS 0 THEN 1 ELSE 0 END WHERE {cond}{filter}"
+            )
+        };
+
+        Self {
+            pk_len: pk.len(),
+            batch: batch(""),
+            batch_after: batch(&format!("WHERE {after}")),
+            update: update(&upto),
+            update_after: update(&format!("{after} AND {upto}")),
+            touch: force.then(|| touch(&upto)),
+            touch_after: force.then(|| touch(&format!("{after} AND {upto}"))),
+        }
+    }
+}
+
+async fn validate_backfill(
+    agent: &Agent,
+    req: &BackfillRequest,
+) -> Result<(BackfillSql, u64), BackfillError> {
+    let sql = {
+        let schema = agent.schema().read();
+        let table = schema
+            .tables
+            .get(&req.table)
+            .ok_or_else(|| BackfillError::UnknownTable(req.table.clone()))?;
+        if !table.columns.contains_key(&req.column) {
+            return Err(BackfillError::UnknownColumn {
+                table: req.table.clone(),
+                column: req.column.clone(),
+            });
+        }
+        if table.pk.contains(&req.column) {
+            return Err(BackfillError::PrimaryKey(req.column.clone()));
+        }
+        BackfillSql::new(req, &table.pk.iter().cloned().collect::<Vec<_>>())
+    };
+
+    let conn = agent.pool().read().await?;
+    let total_rows = block_in_place(|| {
+        // preparing is enough to catch syntax errors and unknown columns
+        conn.prepare(&sql.batch_after)
+            .and_then(|_| conn.prepare(&sql.update_after))
+            .and_then(|_| match sql.touch_after.as_ref() {
+                Some(touch) => conn.prepare(touch).map(|_| ()),
+                None => Ok(()),
+            })
+            .map_err(BackfillError::InvalidSql)?;
+        conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\"", req.table),
+            [],
+            |row| row.get(0),
+        )
+        .map_err(BackfillError::InvalidSql)
+    })?;
+
+    Ok((sql, total_rows))
+}
+
+/// Start a backfill, responds right away with its initial status
+pub async fn api_v1_backfills_create(
+    Extension(agent): Extension<Agent>,
+    Extension(backfills): Extension<SharedBackfills>,
+    Extension(tripwire): Extension<Tripwire>,
+    axum::extract::Json(req): axum::extract::Json<BackfillRequest>,
+) -> Result<(StatusCode, axum::Json<BackfillStatus>), (StatusCode, axum::Json<ExecResult>)> {
+    let status = start_backfill(agent, &backfills, tripwire, req).await?;
+    Ok((StatusCode::ACCEPTED, axum::Json(status)))
+}
+
+/// Validates a backfill and runs it in the background
+pub(crate) async fn start_backfill(
+    agent: Agent,
+    backfills: &SharedBackfills,
+    tripwire: Tripwire,
+    req: BackfillRequest,
+) -> Result<BackfillStatus, BackfillError> {
+    let (sql, total_rows) = validate_backfill(&agent, &req).await?;
+
+    let id = backfills.next_id.fetch_add(1, Ordering::Relaxed);
+    let job = Arc::new(BackfillJob {
+        status: Mutex::new(BackfillStatus {
+            id,
+            table: req.table.clone(),
+            column: req.column.clone(),
+            state: BackfillState::Running,
+            total_rows,
+            rows_scanned: 0,
+            rows_updated: 0,
+            batches: 0,
+            elapsed: 0.0,
+            error: None,
+        }),
+        started_at: Instant::now(),
+        cancel: CancellationToken::new(),
+    });
+
+    backfills.jobs.write().insert(id, job.clone());
+
+    info!(
+        id,
+        table = %req.table,
+        column = %req.column,
+        "starting backfill of {total_rows} rows"
+    );
+
+    spawn_named(
+        "backfill",
+        Shutdown::Graceful,
+        run_backfill(agent, job.clone(), req, sql, tripwire),
+    );
+
+    Ok(job.status())
+}
+
+pub async fn api_v1_backfills(
+    Extension(backfills): Extension<SharedBackfills>,
+) -> axum::Json<Vec<BackfillStatus>> {
+    axum::Json(
+        backfills
+            .jobs
+            .read()
+            .values()
+            .map(|job| job.status())
+            .collect(),
+    )
+}
+
+pub async fn api_v1_backfill_by_id(
+    Extension(backfills): Extension<SharedBackfills>,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+) -> Result<axum::Json<BackfillStatus>, StatusCode> {
+    backfills
+        .jobs
+        .read()
+        .get(&id)
+        .map(|job| axum::Json(job.status()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Stop a running backfill after its current batch
+pub async fn api_v1_backfill_cancel(
+    Extension(backfills): Extension<SharedBackfills>,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+) -> Result<axum::Json<BackfillStatus>, StatusCode> {
+    let job = backfills
+        .jobs
+        .read()
+        .get(&id)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    job.cancel.cancel();
+    Ok(axum::Json(job.status()))
+}
+
+async fn run_backfill(
+    agent: Agent,
+    job: Arc<BackfillJob>,
+    req: BackfillRequest,
+    sql: BackfillSql,
+    mut tripwire: Tripwire,
+) {
+    let batch_size = req
+        .batch_size
+        .unwrap_or(DEFAULT_BATCH_SIZE)
+        .clamp(1, MAX_BATCH_SIZE);
+    let interval = req
+        .interval_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let actor_id = agent.actor_id();
+
+    // primary key of the last row of the previous batch
+    let mut last_pk: Option<Vec<SqliteValue>> = None;
+
+    loop {
+        // let broadcasts drain before producing more changes
+        while agent.tx_bcast().capacity() < agent.tx_bcast().max_capacity() / 2 {
+            tokio::select! {
+                _ = job.cancel.cancelled() => break,
+                _ = &mut tripwire => break,
+                _ = tokio::time::sleep(interval.max(DEFAULT_INTERVAL)) => {}
+            }
+        }
+
+        if job.cancel.is_cancelled() || tripwire.is_shutting_down() {
+            warn!(id = job.status.lock().id, "backfill cancelled");
+            job.finish(BackfillState::Cancelled, None);
+            return;
+        }
+
+        let res = make_broadcastable_changes(&agent, |tx| {
+            let map_err = |source: rusqlite::Error| ChangeError::Rusqlite {
+                source,
+                actor_id: Some(actor_id),
+                version: None,
+            };
+
+            let (batch_sql, update_sql, touch_sql, after) = match last_pk.as_ref() {
+                Some(pk) => (
+                    &sql.batch_after,
+                    &sql.update_after,
+                    &sql.touch_after,
+                    pk.as_slice(),
+                ),
+                None => (&sql.batch, &sql.update, &sql.touch, [].as_slice()),
+            };
+
+            let pks = tx
+                .prepare_cached(batch_sql)
+                .and_then(|mut prepped| {
+                    let params = after
+                        .iter()
+                        .map(|v| v as &dyn ToSql)
+                        .chain(std::iter::once(&batch_size as &dyn ToSql));
+                    let pks = prepped
+                        .query_map(params_from_iter(params), |row| {
+                            (0..sql.pk_len)
+                                .map(|i| row.get::<_, SqliteValue>(i))
+                                .collect::<rusqlite::Result<Vec<_>>>()
+                        })?
+                        .collect::<rusqlite::Result<Vec<_>>>();
+                    pks
+                })
+                .map_err(&map_err)?;
+
+            let Some(upper) = pks.last().cloned() else {
+                return Ok(None);
+            };
+
+            let touched = match touch_sql {
+                Some(touch_sql) => tx
+                    .prepare_cached(touch_sql)
+                    .and_then(|mut prepped| {
+                        prepped.execute(params_from_iter(after.iter().chain(upper.iter())))
+                    })
+                    .map_err(&map_err)?,
+                None => 0,
+            };
+
+            let updated = tx
+                .prepare_cached(update_sql)
+                .and_then(|mut prepped| {
+                    prepped.execute(params_from_iter(after.iter().chain(upper.iter())))
+                })
+                .map_err(&map_err)?;
+
+            // touched rows all end up with a change, even if `update`
+            // found some of them already had the right value
+            Ok(Some((upper, pks.len() as u64, updated.max(touched))))
+        })
+        .await;
+
+        match res {
+            Ok((Some((upper, scanned, updated)), _elapsed)) => {
+                last_pk = Some(upper);
+                counter!("corro.backfill.rows.updated", "table" => req.table.clone())
+                    .increment(updated as u64);
+                let mut status = job.status.lock();
+                status.rows_scanned += scanned;
+                status.rows_updated += updated as u64;
+                status.batches += 1;
+            }
+            Ok((None, _elapsed)) => {
+                let status = job.status();
+                info!(
+                    id = status.id,
+                    "backfill of {}.{} completed, updated {} rows in {} batches",
+                    status.table,
+                    status.column,
+                    status.rows_updated,
+                    status.batches
+                );
+                job.finish(BackfillState::Completed, None);
+                return;
+            }
+            Err(ChangeError::Fenced(e)) => {
+                // not a failure, the batch is retried once the migration is done
+                debug!(id = job.status.lock().id, "backfill fenced: {e}");
+            }
+            Err(e) => {
+                error!(id = job.status.lock().id, "backfill failed: {e}");
+                job.finish(BackfillState::Failed, Some(e.to_string()));
+                return;
+            }
+        }
+
+        tokio::select! {
+            _ = job.cancel.cancelled() => {},
+            _ = &mut tripwire => {},
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::{api::Statement, config::Config};
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::{
+        agent::setup,
+        api::public::{api_v1_db_schema, api_v1_migrations, api_v1_transactions},
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_backfill_in_batches() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                (1i64..=5)
+                    .map(|id| {
+                        Statement::WithParams(
+                            "INSERT INTO tests (id) VALUES (?)".into(),
+                            vec![id.into()],
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let backfills = SharedBackfills::default();
+
+        let res = api_v1_backfills_create(
+            Extension(agent.clone()),
+            Extension(backfills.clone()),
+            Extension(tripwire.clone()),
+            axum::Json(BackfillRequest {
+                table: "tests".into(),
+                column: "nope".into(),
+                expr: "'filled'".into(),
+                filter: None,
+                batch_size: None,
+                interval_ms: None,
+                force: false,
+            }),
+        )
+        .await;
+        assert!(matches!(res, Err((StatusCode::BAD_REQUEST, _))));
+
+        let (status_code, axum::Json(status)) = api_v1_backfills_create(
+            Extension(agent.clone()),
+            Extension(backfills.clone()),
+            Extension(tripwire.clone()),
+            axum::Json(BackfillRequest {
+                table: "tests".into(),
+                column: "text".into(),
+                expr: "'filled-' || id".into(),
+                filter: Some("id != 3".into()),
+                batch_size: Some(2),
+                interval_ms: Some(1),
+                force: false,
+            }),
+        )
+        .await
+        .map_err(|(_, e)| eyre::eyre!("could not create backfill: {e:?}"))?;
+        assert_eq!(status_code, StatusCode::ACCEPTED);
+        assert_eq!(status.total_rows, 5);
+
+        let status = loop {
+            let axum::Json(status) =
+                api_v1_backfill_by_id(Extension(backfills.clone()), axum::extract::Path(status.id))
+                    .await
+                    .map_err(|code| eyre::eyre!("unexpected status code: {code}"))?;
+            if status.state != BackfillState::Running {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        assert_eq!(status.state, BackfillState::Completed);
+        assert_eq!(status.rows_scanned, 5);
+        assert_eq!(status.rows_updated, 4);
+        assert_eq!(status.batches, 3);
+
+        let conn = agent.pool().read().await?;
+        let texts: Vec<String> = conn
+            .prepare("SELECT text FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(
+            texts,
+            vec!["filled-1", "filled-2", "", "filled-4", "filled-5"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_backfill_added_defaults() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let backfills = SharedBackfills::default();
+
+        let (status_code, axum::Json(res)) = api_v1_migrations(
+            Extension(agent.clone()),
+            Extension(backfills.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, text TEXT NOT NULL DEFAULT '');"
+                    .into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(res.backfills.is_empty());
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                (1i64..=3)
+                    .map(|id| {
+                        Statement::WithParams(
+                            "INSERT INTO tests (id) VALUES (?)".into(),
+                            vec![id.into()],
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, axum::Json(res)) = api_v1_migrations(
+            Extension(agent.clone()),
+            Extension(backfills.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Query(serde_json::from_value(serde_json::json!({
+                "backfill_defaults": true
+            }))?),
+            axum::Json(vec![
+                "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, text TEXT NOT NULL DEFAULT '', status TEXT NOT NULL DEFAULT 'new');"
+                    .into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(res.backfills.len(), 1);
+        assert_eq!(res.backfills[0].column, "status");
+
+        let status = loop {
+            let axum::Json(status) = api_v1_backfill_by_id(
+                Extension(backfills.clone()),
+                axum::extract::Path(res.backfills[0].id),
+            )
+            .await
+            .map_err(|code| eyre::eyre!("unexpected status code: {code}"))?;
+            if status.state != BackfillState::Running {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        assert_eq!(status.state, BackfillState::Completed);
+        // every row got a change even though it already had the default
+        assert_eq!(status.rows_updated, 3);
+
+        let conn = agent.pool().read().await?;
+        let statuses: Vec<String> = conn
+            .prepare("SELECT status FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(statuses, vec!["new", "new", "new"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Queries running on this node, by the id returned in their
+//! `corro-query-id` header, so they can be interrupted when they time out,
+//! are cancelled or their client goes away.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::Extension;
+use corro_types::error::QueryError;
+use hyper::StatusCode;
+use metrics::counter;
+use parking_lot::Mutex;
+use rusqlite::{Connection, InterruptHandle};
+use spawn::{spawn_named, Shutdown};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::api::public::error::ApiErrorResponse;
+
+/// Header holding the id of a query, in responses of `/v1/queries`
+pub const QUERY_ID_HEADER: &str = "corro-query-id";
+
+#[derive(Clone, Default)]
+pub struct RunningQueries(Arc<Mutex<HashMap<Uuid, InterruptHandle>>>);
+
+impl RunningQueries {
+    /// Registers the query about to run on `conn`, interrupted after
+    /// `timeout` if any. It's unregistered when the returned guard is
+    /// dropped, which must happen before the connection runs anything else.
+    pub fn register(&self, id: Uuid, conn: &Connection, timeout: Option<Duration>) -> RunningQuery {
+        self.0.lock().insert(id, conn.get_interrupt_handle());
+
+        if let Some(timeout) = timeout {
+            let queries = self.clone();
+            spawn_named("query_timeout", Shutdown::Abortable, async move {
+                tokio::time::sleep(timeout).await;
+                if queries.cancel(&id) {
+                    debug!(%id, "query timed out after {timeout:?}");
+                    counter!("corro.api.queries.interrupted", "reason" => "timeout").increment(1);
+                }
+            });
+        }
+
+        RunningQuery {
+            id,
+            queries: self.clone(),
+        }
+    }
+
+    /// Interrupts a running query, returns `false` if it isn't running
+    pub fn cancel(&self, id: &Uuid) -> bool {
+        // interrupted while locked, the connection can't have moved on to
+        // another statement
+        let mut queries = self.0.lock();
+        match queries.remove(id) {
+            Some(handle) => {
+                handle.interrupt();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Unregisters its query when dropped
+pub struct RunningQuery {
+    id: Uuid,
+    queries: RunningQueries,
+}
+
+impl Drop for RunningQuery {
+    fn drop(&mut self) {
+        self.queries.0.lock().remove(&self.id);
+    }
+}
+
+/// Interrupts a running query, its stream ends with an error event
+pub async fn api_v1_query_cancel(
+    Extension(queries): Extension<RunningQueries>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<StatusCode, ApiErrorResponse<QueryError>> {
+    if !queries.cancel(&id) {
+        return Err(QueryError::QueryNotFound(id).into());
+    }
+    counter!("corro.api.queries.interrupted", "reason" => "cancelled").increment(1);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_cancel_query() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let queries = RunningQueries::default();
+        let id = Uuid::new_v4();
+
+        let running = queries.register(id, &conn, None);
+        assert!(queries.cancel(&id));
+        // the handle is dropped once used
+        assert!(!queries.cancel(&id));
+        drop(running);
+
+        // a never-ending query
+        let mut prepped = conn.prepare(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) SELECT i FROM n",
+        )?;
+        let running = queries.register(id, &conn, Some(Duration::from_millis(50)));
+        let mut rows = prepped.raw_query();
+        let res = tokio::task::block_in_place(|| loop {
+            if let Err(e) = rows.next() {
+                break e;
+            }
+        });
+        assert_eq!(
+            res.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::OperationInterrupted)
+        );
+
+        drop(running);
+        assert!(queries.0.lock().is_empty());
+
+        Ok(())
+    }
+}
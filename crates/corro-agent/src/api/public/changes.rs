@@ -0,0 +1,546 @@
+//! Changes of an actor, in the shape they're broadcast, for external
+//! consumers polling them instead of joining the cluster, and for trusted
+//! external producers (another cluster, a migration tool) to send theirs.
+
+use std::collections::BTreeSet;
+
+use axum::{response::IntoResponse, Extension};
+use bytes::{BufMut, Bytes, BytesMut};
+use corro_types::{
+    actor::ActorId,
+    agent::Agent,
+    api::{row_to_change, ChangesActor, ErrorCode, ExecResult},
+    base::{CrsqlSeq, Version},
+    behind::missing_schema,
+    broadcast::{ChangeSource, ChangeV1, Changeset},
+    change::{ChunkedChanges, MAX_CHANGES_BYTE_SIZE},
+    error::{ApiError, QueryError},
+};
+use hyper::StatusCode;
+use metrics::counter;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use spawn::{spawn_named, Shutdown};
+use tokio::{sync::mpsc, task::block_in_place};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::api::public::error::{impl_into_response, ApiErrorResponse};
+
+const DEFAULT_VERSIONS_LIMIT: usize = 1000;
+const MAX_VERSIONS_LIMIT: usize = 10_000;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ChangesParams {
+    /// Only return versions after this one
+    #[serde(default)]
+    pub since_version: Version,
+    /// Actor whose changes are returned, this node by default
+    #[serde(default)]
+    pub actor: Option<ActorId>,
+    /// Maximum number of versions returned
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Stream the versions of an actor known to this node, as newline-delimited
+/// changesets. Cleared versions are returned as empty changesets, versions
+/// only partially received yet are skipped until they're complete.
+pub async fn api_v1_changes(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Query(params): axum::extract::Query<ChangesParams>,
+) -> hyper::Response<hyper::Body> {
+    let actor_id = params.actor.unwrap_or_else(|| agent.actor_id());
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_VERSIONS_LIMIT)
+        .min(MAX_VERSIONS_LIMIT);
+
+    let mut conn = match agent.pool().read().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                axum::Json(ExecResult::error(ErrorCode::Unavailable, e)),
+            )
+                .into_response()
+        }
+    };
+
+    let (changes_tx, mut changes_rx) = mpsc::channel::<Result<ChangeV1, String>>(512);
+
+    spawn_named("changes_response_read", Shutdown::Abortable, async move {
+        let res = block_in_place(|| {
+            // read transaction, for a consistent view of bookkeeping and changes
+            let tx = conn.transaction()?;
+
+            let mut versions = agent.bookkeeping().versions(&tx, actor_id)?;
+            versions.retain(|(range, _)| *range.end() > params.since_version);
+            versions.sort_by_key(|(range, _)| *range.start());
+
+            let mut prepped = tx.prepare_cached(
+                r#"
+                SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl
+                    FROM crsql_changes
+                    WHERE site_id = ?
+                    AND db_version = ?
+                    ORDER BY seq ASC
+                "#,
+            )?;
+
+            for (range, current) in versions.into_iter().take(limit) {
+                let start = (*range.start()).max(params.since_version + 1);
+
+                let Some(current) = current else {
+                    let changeset = Changeset::Empty {
+                        versions: start..=*range.end(),
+                    };
+                    if changes_tx
+                        .blocking_send(Ok(ChangeV1 {
+                            actor_id,
+                            changeset,
+                        }))
+                        .is_err()
+                    {
+                        return Ok(());
+                    }
+                    continue;
+                };
+
+                let rows =
+                    prepped.query_map(params![actor_id, current.db_version], row_to_change)?;
+                let chunked =
+                    ChunkedChanges::new(rows, CrsqlSeq(0), current.last_seq, MAX_CHANGES_BYTE_SIZE);
+                for chunk in chunked {
+                    let (changes, seqs) = chunk?;
+                    let changeset = Changeset::Full {
+                        version: start,
+                        changes,
+                        seqs,
+                        last_seq: current.last_seq,
+                        ts: current.ts,
+                    };
+                    if changes_tx
+                        .blocking_send(Ok(ChangeV1 {
+                            actor_id,
+                            changeset,
+                        }))
+                        .is_err()
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+
+            Ok::<_, eyre::Report>(())
+        });
+
+        if let Err(e) = res {
+            error!(%actor_id, "could not read changes: {e}");
+            _ = changes_tx.send(Err(e.to_string())).await;
+        }
+    });
+
+    let (mut body_tx, body) = hyper::Body::channel();
+
+    spawn_named("changes_response_body", Shutdown::Abortable, async move {
+        let mut buf = BytesMut::new();
+
+        while let Some(res) = changes_rx.recv().await {
+            {
+                let mut writer = (&mut buf).writer();
+                let res = match res {
+                    Ok(change) => serde_json::to_writer(&mut writer, &change),
+                    Err(error) => serde_json::to_writer(
+                        &mut writer,
+                        &ExecResult::error(ErrorCode::Database, error),
+                    ),
+                };
+                if let Err(e) = res {
+                    error!("could not serialize changeset: {e}");
+                    return;
+                }
+            }
+
+            buf.extend_from_slice(b"\n");
+
+            if let Err(e) = body_tx.send_data(buf.split().freeze()).await {
+                debug!("could not send data through body's channel: {e}");
+                return;
+            }
+        }
+    });
+
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .body(body)
+        .expect("could not build changes response body")
+}
+
+/// Actors this node knows versions of, with the last of them, for consumers
+/// to find out whose changes to poll
+pub async fn api_v1_changes_actors(
+    Extension(agent): Extension<Agent>,
+) -> Result<axum::Json<Vec<ChangesActor>>, ApiErrorResponse<QueryError>> {
+    let conn = agent.pool().read().await.map_err(QueryError::from)?;
+
+    let actors = block_in_place(|| {
+        let bookkeeping = agent.bookkeeping();
+        bookkeeping
+            .actor_ids(&conn)?
+            .into_iter()
+            .map(|actor_id| {
+                let last_version = bookkeeping
+                    .versions(&conn, actor_id)?
+                    .iter()
+                    .map(|(range, _)| *range.end())
+                    .max()
+                    .unwrap_or_default();
+                Ok(ChangesActor {
+                    actor_id: actor_id.0,
+                    last_version,
+                })
+            })
+            .collect::<Result<Vec<_>, rusqlite::Error>>()
+    })
+    .map_err(QueryError::from)?;
+
+    Ok(axum::Json(actors))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IngestParams {
+    /// Name of the external system the changes come from
+    pub producer: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IngestResponse {
+    /// Changesets queued to be applied
+    pub changesets: usize,
+    /// Actors the changes were booked under, in the order of their first
+    /// changeset
+    pub actor_ids: Vec<ActorId>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IngestError {
+    #[error("producer name is required")]
+    NoProducer,
+    #[error("invalid changeset on line {line}: {source}")]
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("changeset of versions {versions} touches unknown {missing}")]
+    UnknownSchema { versions: String, missing: String },
+    #[error("could not queue changes, the agent is shutting down")]
+    Closed,
+}
+
+impl ApiError for IngestError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            IngestError::NoProducer | IngestError::Parse { .. } => ErrorCode::BadRequest,
+            IngestError::UnknownSchema { .. } => ErrorCode::InvalidSchema,
+            IngestError::Closed => ErrorCode::Unavailable,
+        }
+    }
+}
+
+impl_into_response!(IngestError);
+
+/// Actor changes of `actor_id` sent by `producer` are booked under. It's
+/// stable, so a producer sending the same versions again is deduplicated,
+/// and different producers can't overwrite each other's versions.
+pub fn ingest_actor_id(producer: &str, actor_id: ActorId) -> ActorId {
+    ActorId(Uuid::new_v5(&actor_id.0, producer.as_bytes()))
+}
+
+/// Applies newline-delimited changesets, in the shape returned by
+/// `GET /v1/changes`, like changes broadcast by a peer. They're booked under
+/// actors derived from the producer's name and their original actors, and
+/// broadcast to the rest of the cluster. Changesets are validated and
+/// queued before responding, they're applied asynchronously.
+pub async fn api_v1_changes_ingest(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Query(params): axum::extract::Query<IngestParams>,
+    body: Bytes,
+) -> Result<axum::Json<IngestResponse>, IngestError> {
+    let producer = params.producer.trim();
+    if producer.is_empty() {
+        return Err(IngestError::NoProducer);
+    }
+
+    let mut changesets = vec![];
+    for (i, line) in body.split(|b| *b == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let change: ChangeV1 =
+            serde_json::from_slice(line).map_err(|source| IngestError::Parse {
+                line: i + 1,
+                source,
+            })?;
+        changesets.push(change);
+    }
+
+    // nothing is queued unless every changeset can be applied
+    {
+        let schema = agent.schema().read();
+        for change in changesets.iter() {
+            let missing = missing_schema(&schema, change.changes());
+            if let Some(missing) = missing.first() {
+                return Err(IngestError::UnknownSchema {
+                    versions: format!("{:?}", change.versions()),
+                    missing: missing.to_string(),
+                });
+            }
+        }
+    }
+
+    let mut actor_ids = vec![];
+    let mut seen = BTreeSet::new();
+    let count = changesets.len();
+    for mut change in changesets {
+        let actor_id = ingest_actor_id(producer, change.actor_id);
+        if seen.insert(actor_id) {
+            actor_ids.push(actor_id);
+        }
+
+        change.actor_id = actor_id;
+        if let Changeset::Full { changes, .. } = &mut change.changeset {
+            for change in changes.iter_mut() {
+                change.site_id = actor_id.to_bytes();
+            }
+        }
+
+        agent
+            .tx_changes()
+            .send((change, ChangeSource::Ingest))
+            .await
+            .map_err(|_| IngestError::Closed)?;
+    }
+
+    counter!("corro.changes.ingested", "producer" => producer.to_owned()).increment(count as u64);
+    info!(%producer, "queued {count} ingested changesets");
+
+    Ok(axum::Json(IngestResponse {
+        changesets: count,
+        actor_ids,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::{api::Statement, config::Config};
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::{
+        agent::setup,
+        api::public::{api_v1_db_schema, api_v1_transactions},
+    };
+
+    async fn read_changes(agent: &Agent, params: ChangesParams) -> eyre::Result<Vec<ChangeV1>> {
+        let res = api_v1_changes(Extension(agent.clone()), axum::extract::Query(params)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok(body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()?)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_changes_since_version() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        for id in 1i64..=2 {
+            let (status_code, _body) = api_v1_transactions(
+                Extension(agent.clone()),
+                Default::default(),
+                axum::extract::Query(Default::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                        vec![id.into(), format!("service-{id}").into()],
+                    )]
+                    .into(),
+                ),
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+        }
+
+        let changes = read_changes(&agent, ChangesParams::default()).await?;
+        let versions: Vec<Version> = changes
+            .iter()
+            .map(|change| match &change.changeset {
+                Changeset::Full {
+                    version, changes, ..
+                } => {
+                    assert!(!changes.is_empty());
+                    *version
+                }
+                changeset => panic!("unexpected changeset: {changeset:?}"),
+            })
+            .collect();
+        assert_eq!(versions, vec![Version(1), Version(2)]);
+        assert!(changes.iter().all(|c| c.actor_id == agent.actor_id()));
+
+        let changes = read_changes(
+            &agent,
+            ChangesParams {
+                since_version: Version(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(
+            changes[0].changeset,
+            Changeset::Full {
+                version: Version(2),
+                ..
+            }
+        ));
+
+        let changes = read_changes(
+            &agent,
+            ChangesParams {
+                since_version: Version(2),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert!(changes.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_changes_ingest() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::Simple(
+                    "INSERT INTO tests (id, text) VALUES (1, 'bridged')".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let axum::Json(actors) = api_v1_changes_actors(Extension(agent.clone())).await?;
+        assert_eq!(
+            actors,
+            vec![ChangesActor {
+                actor_id: agent.actor_id().0,
+                last_version: Version(1),
+            }]
+        );
+
+        // changes are sent back in the shape they're read
+        let mut body = vec![];
+        for change in read_changes(&agent, ChangesParams::default()).await? {
+            serde_json::to_writer(&mut body, &change)?;
+            body.push(b'\n');
+        }
+
+        let ingest = |producer: &str, body: Vec<u8>| {
+            api_v1_changes_ingest(
+                Extension(agent.clone()),
+                axum::extract::Query(IngestParams {
+                    producer: producer.into(),
+                }),
+                body.into(),
+            )
+        };
+
+        let axum::Json(res) = ingest("bridge", body.clone()).await?;
+        let actor_id = ingest_actor_id("bridge", agent.actor_id());
+        assert_eq!(res.changesets, 1);
+        assert_eq!(res.actor_ids, vec![actor_id]);
+
+        let (change, src) = agent_options.rx_changes.recv().await.unwrap();
+        assert!(matches!(src, ChangeSource::Ingest));
+        assert_eq!(change.actor_id, actor_id);
+        assert!(!change.changes().is_empty());
+        assert!(change
+            .changes()
+            .iter()
+            .all(|change| change.site_id == actor_id.to_bytes()));
+
+        // producers don't share actors
+        assert_ne!(ingest_actor_id("other", agent.actor_id()), actor_id);
+
+        assert!(matches!(
+            ingest("", body.clone()).await,
+            Err(IngestError::NoProducer)
+        ));
+        assert!(matches!(
+            ingest("bridge", b"{}\n".to_vec()).await,
+            Err(IngestError::Parse { line: 1, .. })
+        ));
+
+        let body = String::from_utf8(body)?.replace("\"tests\"", "\"nope\"");
+        assert!(matches!(
+            ingest("bridge", body.into_bytes()).await,
+            Err(IngestError::UnknownSchema { .. })
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! Compression of streamed response bodies, negotiated with the request's
+//! `Accept-Encoding` header. Every chunk sent is flushed through the
+//! compressor, so clients can decode events as soon as they're received
+//! instead of waiting for the compressor's buffer to fill up.
+
+use std::{
+    io::{self, Write},
+    task::{Context, Poll},
+};
+
+use axum::http::{header, HeaderMap};
+use bytes::{buf::Writer, BufMut, Bytes, BytesMut};
+use flate2::{write::GzEncoder, Compression};
+use tracing::warn;
+
+/// zstd's default level, fast enough to keep up with streamed events
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// The encoding accepted by the request, zstd if both are, or `None` to
+    /// leave the body uncompressed
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut accepted = None;
+        for coding in headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|accept| accept.split(','))
+        {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            // `q=0` means the client doesn't want it
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            if refused {
+                continue;
+            }
+            match name {
+                "zstd" => return Some(ContentEncoding::Zstd),
+                "gzip" => accepted = Some(ContentEncoding::Gzip),
+                _ => {}
+            }
+        }
+        accepted
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+}
+
+enum Compressor {
+    Gzip(GzEncoder<Writer<BytesMut>>),
+    Zstd(zstd::Encoder<'static, Writer<BytesMut>>),
+}
+
+impl Compressor {
+    fn new(encoding: ContentEncoding) -> io::Result<Self> {
+        let buf = BytesMut::new().writer();
+        Ok(match encoding {
+            ContentEncoding::Gzip => Compressor::Gzip(GzEncoder::new(buf, Compression::fast())),
+            ContentEncoding::Zstd => Compressor::Zstd(zstd::Encoder::new(buf, ZSTD_LEVEL)?),
+        })
+    }
+
+    /// Compresses a chunk and returns everything compressed so far
+    fn compress(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let buf = match self {
+            Compressor::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Compressor::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(buf.get_mut().split().freeze())
+    }
+
+    /// Ends the compressed stream
+    fn finish(self) -> io::Result<Bytes> {
+        let buf = match self {
+            Compressor::Gzip(encoder) => encoder.finish()?,
+            Compressor::Zstd(encoder) => encoder.finish()?,
+        };
+        Ok(buf.into_inner().freeze())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    #[error(transparent)]
+    Hyper(#[from] hyper::Error),
+    #[error("could not compress response body: {0}")]
+    Compress(#[from] io::Error),
+}
+
+/// Sends chunks of a streamed response body, compressed if the client
+/// accepts it
+pub struct BodySender {
+    tx: hyper::body::Sender,
+    compressor: Option<Compressor>,
+}
+
+impl BodySender {
+    /// Returns the sender along with the content encoding of the body, if
+    /// any, to set on the response
+    pub fn new(
+        tx: hyper::body::Sender,
+        encoding: Option<ContentEncoding>,
+    ) -> (Self, Option<&'static str>) {
+        let compressor = encoding.and_then(|encoding| Compressor::new(encoding).ok());
+        let content_encoding = encoding.filter(|_| compressor.is_some());
+        (
+            Self { tx, compressor },
+            content_encoding.map(ContentEncoding::as_str),
+        )
+    }
+
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<hyper::Result<()>> {
+        self.tx.poll_ready(cx)
+    }
+
+    pub async fn send_data(&mut self, chunk: Bytes) -> Result<(), SendError> {
+        let chunk = match self.compressor.as_mut() {
+            Some(compressor) => compressor.compress(&chunk)?,
+            None => chunk,
+        };
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        Ok(self.tx.send_data(chunk).await?)
+    }
+
+    /// Ends the body with an error, clients can tell it was cut short
+    pub fn abort(self) {
+        self.tx.abort();
+    }
+
+    /// Ends the compressed stream, bodies dropped without being finished
+    /// are cut short for compressing clients
+    pub async fn finish(mut self) {
+        let Some(compressor) = self.compressor.take() else {
+            return;
+        };
+        match compressor.finish() {
+            Ok(chunk) if !chunk.is_empty() => {
+                _ = self.tx.send_data(chunk).await;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("could not finish compressing response body: {e}"),
+        }
+    }
+}
+
+/// Sets the content encoding returned by [`BodySender::new`] on a response
+pub fn with_content_encoding(
+    builder: axum::http::response::Builder,
+    content_encoding: Option<&'static str>,
+) -> axum::http::response::Builder {
+    match content_encoding {
+        Some(content_encoding) => builder.header(header::CONTENT_ENCODING, content_encoding),
+        None => builder,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_content_encoding() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(ContentEncoding::from_headers(&headers), None);
+
+        headers.insert(header::ACCEPT_ENCODING, "gzip, deflate".parse().unwrap());
+        assert_eq!(
+            ContentEncoding::from_headers(&headers),
+            Some(ContentEncoding::Gzip)
+        );
+
+        headers.insert(header::ACCEPT_ENCODING, "gzip, zstd".parse().unwrap());
+        assert_eq!(
+            ContentEncoding::from_headers(&headers),
+            Some(ContentEncoding::Zstd)
+        );
+
+        headers.insert(header::ACCEPT_ENCODING, "gzip;q=0, br".parse().unwrap());
+        assert_eq!(ContentEncoding::from_headers(&headers), None);
+    }
+
+    #[test]
+    fn test_flushed_chunks() -> io::Result<()> {
+        let lines: Vec<String> = (0..100)
+            .map(|i| format!("{{\"row\":[{i},[\"service-{i}\"]]}}\n"))
+            .collect();
+
+        let mut compressor = Compressor::new(ContentEncoding::Gzip)?;
+        let mut compressed = vec![];
+        for line in lines.iter() {
+            let chunk = compressor.compress(line.as_bytes())?;
+            // nothing is held back waiting for more input
+            assert!(!chunk.is_empty());
+            compressed.extend_from_slice(&chunk);
+        }
+        compressed.extend_from_slice(&compressor.finish()?);
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice()).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, lines.concat());
+
+        let mut compressor = Compressor::new(ContentEncoding::Zstd)?;
+        let mut compressed = vec![];
+        for line in lines.iter() {
+            compressed.extend_from_slice(&compressor.compress(line.as_bytes())?);
+        }
+        compressed.extend_from_slice(&compressor.finish()?);
+
+        let mut decoded = String::new();
+        zstd::Decoder::new(compressed.as_slice())?.read_to_string(&mut decoded)?;
+        assert_eq!(decoded, lines.concat());
+
+        Ok(())
+    }
+}
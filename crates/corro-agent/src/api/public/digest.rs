@@ -0,0 +1,93 @@
+//! Digests of tables' contents, compared across nodes by `corrosion diff`
+//! to check they converged.
+
+use axum::Extension;
+use corro_types::{
+    agent::Agent,
+    api::{DigestRequest, ErrorCode, ExecResult, RowDigest, RowDigestRequest, TableDigest},
+    digest::{row_digests, table_digest, DigestError, DIGEST_BUCKETS},
+    schema::Table,
+};
+use hyper::StatusCode;
+use tokio::task::block_in_place;
+
+use crate::api::public::error::status_code;
+
+type DigestResponse<T> = Result<axum::Json<T>, (StatusCode, axum::Json<ExecResult>)>;
+
+fn error_response(code: ErrorCode, error: impl ToString) -> (StatusCode, axum::Json<ExecResult>) {
+    (
+        status_code(code),
+        axum::Json(ExecResult::error(code, error)),
+    )
+}
+
+fn tables(
+    agent: &Agent,
+    names: &[String],
+) -> Result<Vec<Table>, (StatusCode, axum::Json<ExecResult>)> {
+    let schema = agent.schema().read();
+    if names.is_empty() {
+        return Ok(schema.tables.values().cloned().collect());
+    }
+    names
+        .iter()
+        .map(|name| {
+            schema.tables.get(name).cloned().ok_or_else(|| {
+                error_response(
+                    ErrorCode::BadRequest,
+                    format!("table '{name}' does not exist"),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Digest tables, rows are spread in buckets by primary key
+pub async fn api_v1_digests(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(req): axum::extract::Json<DigestRequest>,
+) -> DigestResponse<Vec<TableDigest>> {
+    let tables = tables(&agent, &req.tables)?;
+
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|e| error_response(ErrorCode::Unavailable, e))?;
+
+    block_in_place(|| {
+        tables
+            .iter()
+            .map(|table| table_digest(&conn, table))
+            .collect::<Result<Vec<_>, DigestError>>()
+    })
+    .map(axum::Json)
+    .map_err(|e| error_response(ErrorCode::Database, e))
+}
+
+/// Digest every row of some buckets of a table
+pub async fn api_v1_digests_rows(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(req): axum::extract::Json<RowDigestRequest>,
+) -> DigestResponse<Vec<RowDigest>> {
+    if let Some(bucket) = req.buckets.iter().find(|b| **b >= DIGEST_BUCKETS) {
+        return Err(error_response(
+            ErrorCode::BadRequest,
+            format!("bucket {bucket} is out of range, there are {DIGEST_BUCKETS} buckets"),
+        ));
+    }
+
+    let mut tables = tables(&agent, &[req.table])?;
+    let table = tables.remove(0);
+
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|e| error_response(ErrorCode::Unavailable, e))?;
+
+    block_in_place(|| row_digests(&conn, &table, &req.buckets))
+        .map(axum::Json)
+        .map_err(|e| error_response(ErrorCode::Database, e))
+}
@@ -0,0 +1,159 @@
+//! Binary encodings of responses, for clients spending too much time parsing
+//! JSON. They're picked with the request's `Accept` header, responses of
+//! clients not asking for one are unchanged. Streamed events are written one
+//! after the other, both encodings are self-delimiting.
+
+use axum::{
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use bytes::{BufMut, BytesMut};
+use hyper::StatusCode;
+use serde::Serialize;
+
+pub const MSGPACK_MEDIA_TYPE: &str = "application/msgpack";
+pub const CBOR_MEDIA_TYPE: &str = "application/cbor";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// MessagePack, structs are encoded as maps like their JSON counterpart
+    MsgPack,
+    Cbor,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    MsgPack(#[from] rmp_serde::encode::Error),
+    #[error(transparent)]
+    Cbor(#[from] ciborium::ser::Error<std::io::Error>),
+}
+
+impl Encoding {
+    /// The first binary encoding accepted by the request, JSON otherwise
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|accept| accept.split(','))
+            .find_map(
+                |range| match range.split(';').next().unwrap_or_default().trim() {
+                    MSGPACK_MEDIA_TYPE | "application/x-msgpack" => Some(Encoding::MsgPack),
+                    CBOR_MEDIA_TYPE => Some(Encoding::Cbor),
+                    _ => None,
+                },
+            )
+            .unwrap_or_default()
+    }
+
+    /// Content type of binary responses, JSON responses keep theirs
+    pub fn content_type(self) -> Option<&'static str> {
+        match self {
+            Encoding::Json => None,
+            Encoding::MsgPack => Some(MSGPACK_MEDIA_TYPE),
+            Encoding::Cbor => Some(CBOR_MEDIA_TYPE),
+        }
+    }
+
+    /// Appends an encoded value to `buf`
+    pub fn encode<T: Serialize>(self, buf: &mut BytesMut, value: &T) -> Result<(), EncodeError> {
+        let mut writer = buf.writer();
+        match self {
+            Encoding::Json => serde_json::to_writer(&mut writer, value)?,
+            Encoding::MsgPack => rmp_serde::encode::write_named(&mut writer, value)?,
+            Encoding::Cbor => ciborium::ser::into_writer(value, &mut writer)?,
+        }
+        Ok(())
+    }
+
+    /// Appends an encoded event of a stream, JSON events are lines
+    pub fn encode_event<T: Serialize>(
+        self,
+        buf: &mut BytesMut,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        self.encode(buf, value)?;
+        if self == Encoding::Json {
+            buf.put_u8(b'\n');
+        }
+        Ok(())
+    }
+
+    /// Response holding a single encoded value
+    pub fn response<T: Serialize>(self, status: StatusCode, value: &T) -> Response {
+        let Some(content_type) = self.content_type() else {
+            return (status, axum::Json(value)).into_response();
+        };
+
+        let mut buf = BytesMut::new();
+        match self.encode(&mut buf, value) {
+            Ok(()) => {
+                (status, [(header::CONTENT_TYPE, content_type)], buf.freeze()).into_response()
+            }
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::api::{QueryEvent, RowId, SqliteValue};
+
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Encoding::from_headers(&headers), Encoding::Json);
+
+        headers.insert(
+            header::ACCEPT,
+            "application/json;q=0.5, application/msgpack"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(Encoding::from_headers(&headers), Encoding::MsgPack);
+        headers.insert(header::ACCEPT, CBOR_MEDIA_TYPE.parse().unwrap());
+        assert_eq!(Encoding::from_headers(&headers), Encoding::Cbor);
+
+        let events = [
+            QueryEvent::Columns(vec!["id".into(), "text".into()]),
+            QueryEvent::Row(
+                RowId(1),
+                vec![SqliteValue::Integer(1), SqliteValue::Text("hello".into())],
+            ),
+        ];
+
+        // a stream of events decodes back one event at a time
+        let mut buf = BytesMut::new();
+        for evt in events.iter() {
+            Encoding::MsgPack.encode_event(&mut buf, evt).unwrap();
+        }
+        let mut reader = &buf[..];
+        for evt in events.iter() {
+            let decoded: QueryEvent = rmp_serde::from_read(&mut reader).unwrap();
+            assert_eq!(&decoded, evt);
+        }
+        assert!(reader.is_empty());
+
+        let mut buf = BytesMut::new();
+        for evt in events.iter() {
+            Encoding::Cbor.encode_event(&mut buf, evt).unwrap();
+        }
+        let mut reader = &buf[..];
+        for evt in events.iter() {
+            let decoded: QueryEvent = ciborium::de::from_reader(&mut reader).unwrap();
+            assert_eq!(&decoded, evt);
+        }
+        assert!(reader.is_empty());
+
+        let mut buf = BytesMut::new();
+        Encoding::Json.encode_event(&mut buf, &events[0]).unwrap();
+        assert_eq!(&buf[..], b"{\"columns\":[\"id\",\"text\"]}\n");
+    }
+}
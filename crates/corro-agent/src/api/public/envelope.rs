@@ -0,0 +1,326 @@
+//! Response envelopes of other databases, so clients migrating to corrosion
+//! can keep their response parsers for a while.
+
+use axum::response::{IntoResponse, Response};
+use corro_types::{
+    api::{ColumnName, QueryEvent},
+    change::SqliteValue,
+};
+use hyper::{header, StatusCode};
+use serde::{
+    de::{Error, Unexpected},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc;
+
+use crate::api::public::encoding::Encoding;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Envelope {
+    /// Corrosion's own responses
+    #[default]
+    Corro,
+    /// rqlite's responses: executions also return the last inserted rowid
+    /// and queries are returned as a single object instead of a stream
+    Rqlite,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct EnvelopeParams {
+    #[serde(default)]
+    pub envelope: Envelope,
+    /// Roll back every statement if any fails, like rqlite's `transaction`
+    #[serde(default, deserialize_with = "flag")]
+    pub transaction: bool,
+    #[serde(flatten)]
+    pub rqlite: RqliteOptions,
+    /// Encoding of corrosion's own responses, from the `Accept` header
+    #[serde(skip)]
+    pub encoding: Encoding,
+}
+
+/// rqlite's request options, applied to responses of the rqlite envelope
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct RqliteOptions {
+    /// Indent the response
+    #[serde(default, deserialize_with = "flag")]
+    pub pretty: bool,
+    /// Return the time taken by the request and each statement, rqlite
+    /// leaves them out otherwise
+    #[serde(default, deserialize_with = "flag")]
+    pub timings: bool,
+    /// Return rows as objects keyed by column name, instead of arrays
+    #[serde(default, deserialize_with = "flag")]
+    pub associative: bool,
+}
+
+/// rqlite's flags are set by their mere presence, as in `?pretty&timings`
+pub fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match value.as_str() {
+        "" | "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(D::Error::invalid_value(
+            Unexpected::Str(&value),
+            &"a boolean",
+        )),
+    }
+}
+
+/// A response in the envelope requested
+#[derive(Debug)]
+pub struct Enveloped<T>(pub T, pub EnvelopeParams);
+
+impl<T: Serialize> IntoResponse for Enveloped<T> {
+    fn into_response(self) -> Response {
+        let Enveloped(res, params) = self;
+        match params.envelope {
+            Envelope::Corro => params.encoding.response(StatusCode::OK, &res),
+            Envelope::Rqlite => (
+                [(header::CONTENT_TYPE, "application/json")],
+                rqlite_json(&res, params.rqlite),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Serializes an rqlite response, applying the request's options
+pub fn rqlite_json<T: Serialize>(res: &T, options: RqliteOptions) -> Vec<u8> {
+    let mut value = serde_json::to_value(res).expect("could not serialize response");
+
+    if let Value::Object(res) = &mut value {
+        if !options.timings {
+            res.remove("time");
+        }
+        if let Some(Value::Array(results)) = res.get_mut("results") {
+            for result in results.iter_mut() {
+                if let Value::Object(result) = result {
+                    if !options.timings {
+                        result.remove("time");
+                    }
+                    if options.associative {
+                        associative(result);
+                    }
+                }
+            }
+        }
+    }
+
+    if options.pretty {
+        serde_json::to_vec_pretty(&value)
+    } else {
+        serde_json::to_vec(&value)
+    }
+    .expect("could not serialize response")
+}
+
+/// Turns rows of a result into objects keyed by column name, along with its
+/// column types if any
+fn associative(result: &mut Map<String, Value>) {
+    let Some(Value::Array(columns)) = result.remove("columns") else {
+        return;
+    };
+    let columns: Vec<String> = columns
+        .into_iter()
+        .map(|col| match col {
+            Value::String(col) => col,
+            col => col.to_string(),
+        })
+        .collect();
+
+    if let Some(Value::Array(types)) = result.remove("types") {
+        let types = columns.iter().cloned().zip(types).collect();
+        result.insert("types".into(), Value::Object(types));
+    }
+
+    // rqlite calls them values, corrosion's own results call them rows
+    let rows = match result.remove("values").or_else(|| result.remove("rows")) {
+        Some(Value::Array(rows)) => rows,
+        _ => vec![],
+    };
+    let rows = rows
+        .into_iter()
+        .map(|row| match row {
+            Value::Array(cells) => Value::Object(columns.iter().cloned().zip(cells).collect()),
+            row => row,
+        })
+        .collect();
+    result.insert("rows".into(), Value::Array(rows));
+}
+
+#[derive(Debug, Serialize)]
+pub struct RqliteResponse<T> {
+    pub results: Vec<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum RqliteQueryResult {
+    Rows {
+        columns: Vec<ColumnName>,
+        types: Vec<&'static str>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        values: Vec<Vec<SqliteValue>>,
+        time: f64,
+    },
+    Error {
+        error: String,
+    },
+}
+
+impl RqliteQueryResult {
+    pub fn error(error: impl ToString) -> RqliteResponse<Self> {
+        RqliteResponse {
+            results: vec![RqliteQueryResult::Error {
+                error: error.to_string(),
+            }],
+            time: None,
+        }
+    }
+}
+
+/// Collects the events of a query in a single rqlite result. Column types
+/// aren't known to corrosion, they're inferred from the first non-null value
+/// of each column. Returned along with the cursor of the next page, if the
+/// query was paginated.
+pub async fn rqlite_query_response(
+    mut data_rx: mpsc::Receiver<QueryEvent>,
+) -> (RqliteResponse<RqliteQueryResult>, Option<String>) {
+    let mut columns = vec![];
+    let mut values = vec![];
+    let mut time = 0.0;
+    let mut next_cursor = None;
+
+    while let Some(evt) = data_rx.recv().await {
+        match evt {
+            QueryEvent::Columns(cols) => columns = cols,
+            QueryEvent::Row(_, cells) => values.push(cells),
+            QueryEvent::EndOfQuery {
+                time: elapsed,
+                cursor,
+                ..
+            } => {
+                time = elapsed;
+                next_cursor = cursor;
+                break;
+            }
+            QueryEvent::Error(e) => return (RqliteQueryResult::error(e), None),
+            _ => {}
+        }
+    }
+
+    let types = (0..columns.len())
+        .map(|i| {
+            values
+                .iter()
+                .filter_map(|row| row.get(i))
+                .find_map(value_type)
+                .unwrap_or("")
+        })
+        .collect();
+
+    (
+        RqliteResponse {
+            results: vec![RqliteQueryResult::Rows {
+                columns,
+                types,
+                values,
+                time,
+            }],
+            time: Some(time),
+        },
+        next_cursor,
+    )
+}
+
+fn value_type(value: &SqliteValue) -> Option<&'static str> {
+    match value {
+        SqliteValue::Null => None,
+        SqliteValue::Integer(_) => Some("integer"),
+        SqliteValue::Real(_) => Some("real"),
+        SqliteValue::Text(_) => Some("text"),
+        SqliteValue::Blob(_) => Some("blob"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::api::RowId;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rqlite_query_response() {
+        let (tx, rx) = mpsc::channel(8);
+        for evt in [
+            QueryEvent::Columns(vec![ColumnName("id".into()), ColumnName("name".into())]),
+            QueryEvent::Row(RowId(1), vec![SqliteValue::Integer(1), SqliteValue::Null]),
+            QueryEvent::Row(
+                RowId(2),
+                vec![SqliteValue::Integer(2), SqliteValue::Text("fiona".into())],
+            ),
+            QueryEvent::EndOfQuery {
+                time: 0.5,
+                change_id: None,
+                cursor: Some("5b325d".into()),
+            },
+        ] {
+            tx.send(evt).await.unwrap();
+        }
+
+        let (res, cursor) = rqlite_query_response(rx).await;
+        assert_eq!(cursor.as_deref(), Some("5b325d"));
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            serde_json::json!({
+                "results": [{
+                    "columns": ["id", "name"],
+                    "types": ["integer", "text"],
+                    "values": [[1, null], [2, "fiona"]],
+                    "time": 0.5,
+                }],
+                "time": 0.5,
+            })
+        );
+
+        // rqlite leaves timings out unless asked
+        let body = rqlite_json(
+            &res,
+            RqliteOptions {
+                associative: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            serde_json::json!({
+                "results": [{
+                    "types": {"id": "integer", "name": "text"},
+                    "rows": [{"id": 1, "name": null}, {"id": 2, "name": "fiona"}],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_rqlite_options() {
+        let uri = "/v1/transactions?envelope=rqlite&transaction&pretty=true&timings=0"
+            .parse()
+            .unwrap();
+        let axum::extract::Query(params) =
+            axum::extract::Query::<EnvelopeParams>::try_from_uri(&uri).unwrap();
+        assert_eq!(params.envelope, Envelope::Rqlite);
+        assert!(params.transaction);
+        assert!(params.rqlite.pretty);
+        assert!(!params.rqlite.timings);
+        assert!(!params.rqlite.associative);
+
+        let uri = "/v1/transactions?pretty=maybe".parse().unwrap();
+        assert!(axum::extract::Query::<EnvelopeParams>::try_from_uri(&uri).is_err());
+    }
+}
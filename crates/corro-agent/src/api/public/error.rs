@@ -0,0 +1,106 @@
+//! HTTP side of [`ApiError`]s: the status code each [`ErrorCode`] is
+//! returned with, and responses with an [`ExecResponse`] body holding the
+//! error.
+
+use axum::response::{IntoResponse, Response};
+use corro_types::{
+    api::{ErrorCode, ExecResponse},
+    error::ApiError,
+};
+use hyper::StatusCode;
+
+pub fn status_code(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::BadRequest
+        | ErrorCode::InvalidStatement
+        | ErrorCode::StatementFailed
+        | ErrorCode::InvalidSchema => StatusCode::BAD_REQUEST,
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::Vetoed => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+        ErrorCode::Fenced | ErrorCode::Unavailable | ErrorCode::SyncRejected => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        ErrorCode::SyncFailed => StatusCode::BAD_GATEWAY,
+        ErrorCode::Database | ErrorCode::Internal | ErrorCode::Unknown => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Implemented for every [`ApiError`]
+pub trait HttpApiError: ApiError {
+    fn status(&self) -> StatusCode {
+        status_code(self.code())
+    }
+
+    fn into_exec_response(self) -> (StatusCode, axum::Json<ExecResponse>)
+    where
+        Self: Sized,
+    {
+        (
+            self.status(),
+            axum::Json(ExecResponse {
+                results: vec![self.exec_result()],
+                time: 0.0,
+            }),
+        )
+    }
+}
+
+impl<E: ApiError + ?Sized> HttpApiError for E {}
+
+/// Error of handlers failing with an [`ApiError`] of `corro_types`, which
+/// can't implement [`IntoResponse`] itself
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub struct ApiErrorResponse<E: ApiError>(#[from] pub E);
+
+impl<E: ApiError> IntoResponse for ApiErrorResponse<E> {
+    fn into_response(self) -> Response {
+        self.0.into_exec_response().into_response()
+    }
+}
+
+/// Implements [`IntoResponse`] for the agent's own [`ApiError`]s
+macro_rules! impl_into_response {
+    ($($error:ty),+ $(,)?) => {
+        $(
+            impl ::axum::response::IntoResponse for $error {
+                fn into_response(self) -> ::axum::response::Response {
+                    ::axum::response::IntoResponse::into_response(
+                        $crate::api::public::error::HttpApiError::into_exec_response(self),
+                    )
+                }
+            }
+        )+
+    };
+}
+
+pub(crate) use impl_into_response;
+
+#[cfg(test)]
+mod tests {
+    use corro_types::error::{QueryError, SchemaError};
+
+    use super::*;
+
+    #[test]
+    fn test_error_responses() {
+        let err = QueryError::SnapshotNotFound(uuid::Uuid::nil());
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+
+        let (status, axum::Json(res)) = SchemaError::NoStatements.into_exec_response();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            serde_json::to_value(&res.results).unwrap(),
+            serde_json::json!([{
+                "error": "at least 1 statement is required",
+                "code": "bad_request"
+            }])
+        );
+
+        let res = ApiErrorResponse(SchemaError::NoStatements).into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,476 @@
+//! Table exports, streamed from a read connection as CSV with a header or
+//! as newline-delimited JSON objects. Rows are encoded in chunks sent through
+//! a bounded channel, they're only read as fast as the client receives them.
+//!
+//! Values are written the way imports read them back: blobs are
+//! hex-encoded, and `NULL` is `\N` in CSV, an empty field is an empty string.
+
+use axum::{
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    Extension,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use corro_types::{
+    agent::Agent,
+    api::{ErrorCode, SqliteValue},
+    error::{ApiError, QueryError},
+};
+use hyper::StatusCode;
+use itertools::Itertools;
+use rusqlite::Connection;
+use serde::{ser::SerializeMap, Deserialize, Serialize};
+use spawn::{spawn_blocking_counted_w_handle, spawn_named, Shutdown};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error};
+
+use crate::api::public::{
+    compression::{with_content_encoding, BodySender, ContentEncoding},
+    error::HttpApiError,
+};
+
+/// Size of the chunks sent to the response body
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+/// Chunks encoded ahead of the client, rows aren't read past that
+const EXPORT_CHUNKS_BUFFER: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// CSV, with a header naming the columns
+    #[default]
+    Csv,
+    /// A JSON object per row, keyed by column name
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// SQL condition the exported rows match
+    #[serde(default, rename = "where")]
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("table '{0}' does not exist")]
+    UnknownTable(String),
+    #[error(transparent)]
+    Query(#[from] QueryError),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl ApiError for ExportError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ExportError::UnknownTable(_) => ErrorCode::BadRequest,
+            ExportError::Query(e) => e.code(),
+            ExportError::Csv(_) | ExportError::Json(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<ExportError> for hyper::Response<hyper::Body> {
+    fn from(e: ExportError) -> Self {
+        (e.status(), axum::Json(e.exec_result())).into_response()
+    }
+}
+
+/// Stream the rows of a table, ordered by primary key, optionally filtered
+/// by a `where` condition
+pub async fn api_v1_table_export(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(table): axum::extract::Path<String>,
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<ExportParams>,
+) -> hyper::Response<hyper::Body> {
+    let (sql, columns) = match export_sql(&agent, &table, params.filter.as_deref()) {
+        Ok(res) => res,
+        Err(e) => return e.into(),
+    };
+    let format = params.format;
+
+    let conn = match agent.pool().read().await {
+        Ok(conn) => conn,
+        Err(e) => return ExportError::from(QueryError::Pool(e)).into(),
+    };
+
+    let (res_tx, res_rx) = oneshot::channel();
+    let (chunk_tx, mut chunk_rx) = mpsc::channel(EXPORT_CHUNKS_BUFFER);
+
+    // rows are read off the runtime's workers, for as long as the client
+    // keeps up with them
+    spawn_blocking_counted_w_handle(
+        move || export_rows(&conn, &sql, &columns, format, res_tx, chunk_tx),
+        &tokio::runtime::Handle::current(),
+    );
+
+    if let Err(e) = res_rx.await.unwrap_or(Err(QueryError::Aborted.into())) {
+        return e.into();
+    }
+
+    let (tx, body) = hyper::Body::channel();
+    let (mut tx, content_encoding) = BodySender::new(tx, ContentEncoding::from_headers(&headers));
+
+    spawn_named(
+        "table_export_response_body",
+        Shutdown::Abortable,
+        async move {
+            while let Some(chunk) = chunk_rx.recv().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        error!(%table, "could not export rows: {e}");
+                        tx.abort();
+                        return;
+                    }
+                };
+                if let Err(e) = tx.send_data(chunk).await {
+                    debug!(%table, "could not send exported rows, client is gone: {e}");
+                    return;
+                }
+            }
+            tx.finish().await;
+        },
+    );
+
+    with_content_encoding(hyper::Response::builder(), content_encoding)
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .body(body)
+        .expect("could not build export response body")
+}
+
+/// Query selecting the exported rows and the names of their columns.
+/// Generated columns are left out, they couldn't be imported back.
+fn export_sql(
+    agent: &Agent,
+    table_name: &str,
+    filter: Option<&str>,
+) -> Result<(String, Vec<String>), ExportError> {
+    let schema = agent.schema().read();
+    let table = schema
+        .tables
+        .get(table_name)
+        .ok_or_else(|| ExportError::UnknownTable(table_name.to_owned()))?;
+
+    let columns: Vec<String> = table
+        .columns
+        .values()
+        .filter(|col| col.generated.is_none())
+        .map(|col| col.name.clone())
+        .collect();
+
+    let cols = columns.iter().map(|col| format!("\"{col}\"")).join(",");
+    let pk_cols = table.pk.iter().map(|pk| format!("\"{pk}\"")).join(",");
+
+    let mut sql = format!("SELECT {cols} FROM \"{table_name}\"");
+    if let Some(filter) = filter {
+        sql.push_str(&format!(" WHERE ({filter})"));
+    }
+    sql.push_str(&format!(" ORDER BY {pk_cols}"));
+
+    Ok((sql, columns))
+}
+
+/// Sends back whether the query could be prepared, then the encoded rows
+fn export_rows(
+    conn: &Connection,
+    sql: &str,
+    columns: &[String],
+    format: ExportFormat,
+    res_tx: oneshot::Sender<Result<(), ExportError>>,
+    chunk_tx: mpsc::Sender<Result<Bytes, ExportError>>,
+) {
+    // the filter is the client's
+    let mut prepped = match conn.prepare(sql) {
+        Ok(prepped) => prepped,
+        Err(e) => {
+            _ = res_tx.send(Err(QueryError::InvalidStatement(e).into()));
+            return;
+        }
+    };
+    if !prepped.readonly() {
+        _ = res_tx.send(Err(QueryError::NotReadonly.into()));
+        return;
+    }
+
+    let mut encoder = match RowEncoder::new(format, columns) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            _ = res_tx.send(Err(e));
+            return;
+        }
+    };
+
+    let mut rows = prepped.raw_query();
+    if res_tx.send(Ok(())).is_err() {
+        return;
+    }
+
+    loop {
+        let res = match rows.next() {
+            Ok(Some(row)) => (0..columns.len())
+                .map(|i| row.get::<_, SqliteValue>(i))
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .map_err(|e| ExportError::from(QueryError::from(e)))
+                .and_then(|values| encoder.encode(columns, &values)),
+            Ok(None) => break,
+            Err(e) => Err(QueryError::from(e).into()),
+        };
+
+        let chunk = match res {
+            Ok(()) if encoder.len() < EXPORT_CHUNK_BYTES => continue,
+            Ok(()) => Ok(encoder.take()),
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if chunk_tx.blocking_send(chunk).is_err() || failed {
+            return;
+        }
+    }
+
+    if encoder.len() > 0 {
+        _ = chunk_tx.blocking_send(Ok(encoder.take()));
+    }
+}
+
+enum RowEncoder {
+    Csv(csv::Writer<Vec<u8>>),
+    Ndjson(BytesMut),
+}
+
+impl RowEncoder {
+    fn new(format: ExportFormat, columns: &[String]) -> Result<Self, ExportError> {
+        Ok(match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(vec![]);
+                writer.write_record(columns)?;
+                RowEncoder::Csv(writer)
+            }
+            ExportFormat::Ndjson => RowEncoder::Ndjson(BytesMut::new()),
+        })
+    }
+
+    fn encode(&mut self, columns: &[String], values: &[SqliteValue]) -> Result<(), ExportError> {
+        match self {
+            RowEncoder::Csv(writer) => {
+                writer.write_record(values.iter().map(csv_field))?;
+                // the writer buffers records itself
+                writer.flush().map_err(csv::Error::from)?;
+            }
+            RowEncoder::Ndjson(buf) => {
+                serde_json::to_writer((&mut *buf).writer(), &JsonRow { columns, values })?;
+                buf.put_u8(b'\n');
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            RowEncoder::Csv(writer) => writer.get_ref().len(),
+            RowEncoder::Ndjson(buf) => buf.len(),
+        }
+    }
+
+    /// Encoded rows not taken yet
+    fn take(&mut self) -> Bytes {
+        match self {
+            RowEncoder::Csv(writer) => std::mem::take(writer.get_mut()).into(),
+            RowEncoder::Ndjson(buf) => buf.split().freeze(),
+        }
+    }
+}
+
+/// Written for `NULL` values, empty fields are empty strings
+pub const CSV_NULL: &str = "\\N";
+
+fn csv_field(value: &SqliteValue) -> String {
+    match value {
+        SqliteValue::Null => CSV_NULL.to_owned(),
+        SqliteValue::Integer(i) => i.to_string(),
+        SqliteValue::Real(r) => r.0.to_string(),
+        SqliteValue::Text(text) => text.to_string(),
+        SqliteValue::Blob(blob) => hex::encode(blob),
+    }
+}
+
+/// A row as a JSON object, its keys in the order of the columns
+struct JsonRow<'a> {
+    columns: &'a [String],
+    values: &'a [SqliteValue],
+}
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for (column, value) in self.columns.iter().zip(self.values) {
+            match value {
+                SqliteValue::Blob(blob) => map.serialize_entry(column, &hex::encode(blob))?,
+                value => map.serialize_entry(column, value)?,
+            }
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::{api::UpsertRequest, config::Config};
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::{
+        agent::setup,
+        api::public::{
+            api_v1_db_schema,
+            import::{api_v1_import_csv, api_v1_upserts, ImportCsvParams},
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_table_export() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                corro_tests::TEST_SCHEMA.into(),
+                "CREATE TABLE nullables (id INTEGER NOT NULL PRIMARY KEY, text TEXT, num INTEGER);"
+                    .into(),
+                "CREATE TABLE nullables2 (id INTEGER NOT NULL PRIMARY KEY, text TEXT, num INTEGER);"
+                    .into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let res = api_v1_upserts(
+            Extension(agent.clone()),
+            axum::Json(vec![UpsertRequest {
+                table: "tests".into(),
+                columns: vec!["id".into(), "text".into()],
+                rows: vec![
+                    vec![
+                        SqliteValue::Integer(2),
+                        "with \"quotes\", and commas".into(),
+                    ],
+                    vec![SqliteValue::Integer(1), "one".into()],
+                ],
+            }]),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let export = |table: &'static str, format: ExportFormat, filter: Option<&'static str>| {
+            let agent = agent.clone();
+            async move {
+                let res = api_v1_table_export(
+                    Extension(agent),
+                    axum::extract::Path(table.into()),
+                    HeaderMap::new(),
+                    axum::extract::Query(ExportParams {
+                        format,
+                        filter: filter.map(Into::into),
+                    }),
+                )
+                .await;
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                Ok::<_, eyre::Report>((status, String::from_utf8(body.to_vec())?))
+            }
+        };
+
+        let (status_code, body) = export("tests", ExportFormat::Csv, None).await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(
+            body,
+            "id,text\n1,one\n2,\"with \"\"quotes\"\", and commas\"\n"
+        );
+
+        let (status_code, body) = export("tests", ExportFormat::Ndjson, Some("id > 1")).await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(
+            body,
+            "{\"id\":2,\"text\":\"with \\\"quotes\\\", and commas\"}\n"
+        );
+
+        let (status_code, _) = export("tests", ExportFormat::Csv, Some("nope = 1")).await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let (status_code, _) = export("nope", ExportFormat::Csv, None).await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let res = api_v1_upserts(
+            Extension(agent.clone()),
+            axum::Json(vec![UpsertRequest {
+                table: "nullables".into(),
+                columns: vec!["id".into(), "text".into(), "num".into()],
+                rows: vec![
+                    vec![
+                        SqliteValue::Integer(1),
+                        SqliteValue::Null,
+                        SqliteValue::Null,
+                    ],
+                    vec![SqliteValue::Integer(2), "".into(), SqliteValue::Integer(2)],
+                ],
+            }]),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // NULL and empty strings are told apart, and imported back as such
+        let (status_code, body) = export("nullables", ExportFormat::Csv, None).await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(body, "id,text,num\n1,\\N,\\N\n2,,2\n");
+
+        let res = api_v1_import_csv(
+            Extension(agent.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Query(ImportCsvParams {
+                table: "nullables2".into(),
+                batch_size: None,
+            }),
+            hyper::Body::from(body.clone()),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        hyper::body::to_bytes(res.into_body()).await?;
+
+        let (status_code, imported) = export("nullables2", ExportFormat::Csv, None).await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(imported, body);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+//! Fan-out of subscription events to local consumers through unix sockets
+//! or FIFOs, in a line protocol simple enough for shell scripts.
+//!
+//! Every event is a line of tab-separated fields, starting with its type:
+//!
+//! ```text
+//! columns	id	name
+//! row	1	1	web-1
+//! eoq	0
+//! change	update	1	1	1	web-2
+//! ```
+//!
+//! NULL values are written as `\N`, blobs as hex prefixed with `\x`, and
+//! backslashes, tabs and line breaks in text are escaped.
+
+use std::{
+    fmt::Write as _,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+    time::Duration,
+};
+
+use camino::Utf8PathBuf;
+use corro_types::{
+    agent::Agent,
+    api::{CloseReason, QueryEvent, SqliteValue, Statement},
+    config::{FanoutConfig, FanoutKind},
+    pubsub::ChangeType,
+};
+use spawn::{spawn_counted, spawn_named, Shutdown};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{unix::pipe, UnixListener},
+};
+use tracing::{debug, error, info, trace, warn};
+use tripwire::Tripwire;
+
+use crate::api::public::pubsub::{subscribe_receiver, SharedMatcherBroadcastCache, SubParams};
+
+/// Delay between attempts to open a FIFO without a reader
+const FIFO_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bounds of the delay before accepting connections again after an error,
+/// like running out of file descriptors
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Starts serving every configured fan-out
+pub fn spawn_fanouts(
+    agent: &Agent,
+    bcast_cache: &SharedMatcherBroadcastCache,
+    tripwire: &Tripwire,
+) {
+    for conf in agent.config().subscriptions.fanout.iter().cloned() {
+        let agent = agent.clone();
+        let bcast_cache = bcast_cache.clone();
+        let tripwire = tripwire.clone();
+        spawn_named("fanout", Shutdown::Abortable, async move {
+            let path = conf.path.clone();
+            let res = match conf.kind {
+                FanoutKind::Socket => serve_socket(agent, bcast_cache, tripwire, conf).await,
+                FanoutKind::Fifo => serve_fifo(agent, bcast_cache, tripwire, conf).await,
+            };
+            if let Err(e) = res {
+                error!("could not serve subscription fan-out at {path}: {e}");
+            }
+        });
+    }
+}
+
+async fn serve_socket(
+    agent: Agent,
+    bcast_cache: SharedMatcherBroadcastCache,
+    mut tripwire: Tripwire,
+    conf: FanoutConfig,
+) -> std::io::Result<()> {
+    // the socket of a previous run
+    remove_socket(&conf.path).await?;
+    let listener = UnixListener::bind(&conf.path)?;
+    if let Some(mode) = conf.mode {
+        tokio::fs::set_permissions(&conf.path, std::fs::Permissions::from_mode(mode)).await?;
+    }
+    info!("Serving subscription fan-out on unix socket {}", conf.path);
+
+    let new_backoff = || {
+        backoff::Backoff::new(0)
+            .timeout_range(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF)
+            .iter()
+    };
+    let mut accept_backoff = new_backoff();
+
+    loop {
+        let stream = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    accept_backoff = new_backoff();
+                    stream
+                }
+                Err(e) => {
+                    // errors like EMFILE persist for a while, don't spin on them
+                    let delay = accept_backoff.next().unwrap_or(MAX_ACCEPT_BACKOFF);
+                    warn!("could not accept fan-out connection on {}, retrying in {delay:?}: {e}", conf.path);
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => continue,
+                        _ = &mut tripwire => break,
+                    }
+                }
+            },
+            _ = &mut tripwire => break,
+        };
+
+        let agent = agent.clone();
+        let bcast_cache = bcast_cache.clone();
+        let tripwire = tripwire.clone();
+        let sql = conf.sql.clone();
+        let path = conf.path.clone();
+        spawn_counted(async move {
+            write_events(&agent, &bcast_cache, tripwire, &sql, &path, stream).await;
+        });
+    }
+
+    if let Err(e) = remove_socket(&conf.path).await {
+        warn!("could not remove fan-out socket {}: {e}", conf.path);
+    }
+
+    Ok(())
+}
+
+/// Removes the unix socket at `path`, if any. Anything else at that path is
+/// left alone and fails with `AlreadyExists`.
+async fn remove_socket(path: impl AsRef<Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if meta.file_type().is_socket() => tokio::fs::remove_file(path).await,
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a unix socket", path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn serve_fifo(
+    agent: Agent,
+    bcast_cache: SharedMatcherBroadcastCache,
+    mut tripwire: Tripwire,
+    conf: FanoutConfig,
+) -> std::io::Result<()> {
+    if !tokio::fs::metadata(&conf.path).await?.file_type().is_fifo() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "not a FIFO, create it with mkfifo",
+        ));
+    }
+    info!("Serving subscription fan-out on FIFO {}", conf.path);
+
+    loop {
+        // opening a FIFO for writing fails until it has a reader
+        let sender = match pipe::OpenOptions::new().open_sender(&conf.path) {
+            Ok(sender) => sender,
+            Err(e) => {
+                trace!("could not open FIFO {} for writing: {e}", conf.path);
+                tokio::select! {
+                    _ = tokio::time::sleep(FIFO_RETRY_INTERVAL) => continue,
+                    _ = &mut tripwire => break,
+                }
+            }
+        };
+
+        // a FIFO has a single stream of bytes, readers take turns
+        write_events(
+            &agent,
+            &bcast_cache,
+            tripwire.clone(),
+            &conf.sql,
+            &conf.path,
+            sender,
+        )
+        .await;
+
+        if tripwire.is_shutting_down() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Subscribes to `sql` and writes its events to `w` until the consumer goes
+/// away or the subscription ends
+async fn write_events<W: AsyncWrite + Unpin>(
+    agent: &Agent,
+    bcast_cache: &SharedMatcherBroadcastCache,
+    mut tripwire: Tripwire,
+    sql: &str,
+    path: &Utf8PathBuf,
+    mut w: W,
+) {
+    let stmt = Statement::Simple(sql.into());
+    let (sub_id, mut rx) = match subscribe_receiver(
+        agent,
+        bcast_cache,
+        tripwire.clone(),
+        &stmt,
+        SubParams::default(),
+    )
+    .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            error!("could not subscribe for fan-out at {path}: {e}");
+            let mut line = String::new();
+            write_line(&mut line, &QueryEvent::Error(e.to_string().into()));
+            _ = w.write_all(line.as_bytes()).await;
+            return;
+        }
+    };
+
+    debug!(%sub_id, "fan-out consumer connected to {path}");
+
+    let mut line = String::new();
+    loop {
+        let event_buf = tokio::select! {
+            biased;
+            evt = rx.recv() => match evt {
+                Some((event_buf, _)) => event_buf,
+                None => break,
+            },
+            _ = &mut tripwire => break,
+        };
+
+        let evt: QueryEvent = match serde_json::from_slice(&event_buf) {
+            Ok(evt) => evt,
+            Err(e) => {
+                error!(%sub_id, "could not decode subscription event for fan-out: {e}");
+                break;
+            }
+        };
+
+        line.clear();
+        if !write_line(&mut line, &evt) {
+            continue;
+        }
+        if let Err(e) = w.write_all(line.as_bytes()).await {
+            debug!(%sub_id, "fan-out consumer of {path} went away: {e}");
+            break;
+        }
+
+        if matches!(
+            evt,
+            QueryEvent::Closed { .. } | QueryEvent::Moved { .. } | QueryEvent::Error(_)
+        ) {
+            break;
+        }
+    }
+}
+
+fn write_value(line: &mut String, value: &SqliteValue) {
+    match value {
+        SqliteValue::Null => line.push_str("\\N"),
+        SqliteValue::Integer(i) => line.push_str(&i.to_string()),
+        SqliteValue::Real(r) => line.push_str(&r.0.to_string()),
+        SqliteValue::Text(t) => {
+            for c in t.chars() {
+                match c {
+                    '\\' => line.push_str("\\\\"),
+                    '\t' => line.push_str("\\t"),
+                    '\n' => line.push_str("\\n"),
+                    '\r' => line.push_str("\\r"),
+                    c => line.push(c),
+                }
+            }
+        }
+        SqliteValue::Blob(b) => {
+            line.push_str("\\x");
+            line.push_str(&hex::encode(b.as_slice()));
+        }
+    }
+}
+
+fn write_fields<'a, I: IntoIterator<Item = &'a SqliteValue>>(line: &mut String, values: I) {
+    for value in values {
+        line.push('\t');
+        write_value(line, value);
+    }
+}
+
+/// Formats an event as a line, returns false for events consumers don't get
+fn write_line(line: &mut String, evt: &QueryEvent) -> bool {
+    match evt {
+        QueryEvent::Columns(cols) => {
+            line.push_str("columns");
+            for col in cols {
+                line.push('\t');
+                line.push_str(col);
+            }
+        }
+        QueryEvent::Row(rowid, cells) => {
+            _ = write!(line, "row\t{rowid}");
+            write_fields(line, cells);
+        }
+        QueryEvent::EndOfQuery { change_id, .. } => {
+            line.push_str("eoq\t");
+            if let Some(change_id) = change_id {
+                _ = write!(line, "{}", change_id.0);
+            }
+        }
+        QueryEvent::Change(change_type, rowid, cells, change_id, _) => {
+            let change_type = match change_type {
+                ChangeType::Insert => "insert",
+                ChangeType::Update => "update",
+                ChangeType::Delete => "delete",
+            };
+            _ = write!(line, "change\t{change_type}\t{rowid}\t{}", change_id.0);
+            write_fields(line, cells);
+        }
+        QueryEvent::Resync { reason } => {
+            line.push_str("resync\t");
+            write_value(line, &SqliteValue::Text(reason.clone()));
+        }
+        QueryEvent::Dropped { count } => {
+            _ = write!(line, "dropped\t{count}");
+        }
+        QueryEvent::Closed { reason } => {
+            line.push_str(match reason {
+                CloseReason::MaxLifetime => "closed\tmax_lifetime",
+                CloseReason::Idle => "closed\tidle",
+                CloseReason::Deleted => "closed\tdeleted",
+                CloseReason::TableDropped => "closed\ttable_dropped",
+            });
+        }
+        QueryEvent::Moved { addr, id } => {
+            _ = write!(line, "moved\t{addr}\t{id}");
+        }
+        QueryEvent::Error(e) => {
+            line.push_str("error\t");
+            write_value(line, &SqliteValue::Text(e.clone()));
+        }
+        QueryEvent::Estimate { .. }
+        | QueryEvent::Progress { .. }
+        | QueryEvent::Meta(..)
+        | QueryEvent::Previous(..)
+        | QueryEvent::Skipped { .. } => return false,
+    }
+    line.push('\n');
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::api::{ChangeId, RowId};
+
+    use super::*;
+
+    #[test]
+    fn test_write_line() {
+        let mut line = String::new();
+
+        assert!(write_line(
+            &mut line,
+            &QueryEvent::Columns(vec!["id".into(), "name".into()])
+        ));
+        assert!(write_line(
+            &mut line,
+            &QueryEvent::Row(
+                RowId(1),
+                vec![
+                    SqliteValue::Integer(1),
+                    SqliteValue::Text("tab\there\\".into()),
+                    SqliteValue::Null,
+                    SqliteValue::Blob([0xca, 0xfe].as_slice().into()),
+                ]
+            )
+        ));
+        assert!(write_line(
+            &mut line,
+            &QueryEvent::EndOfQuery {
+                time: 0.1,
+                change_id: Some(ChangeId(0)),
+                cursor: None,
+            }
+        ));
+        assert!(!write_line(
+            &mut line,
+            &QueryEvent::Progress { rows: 1, time: 0.1 }
+        ));
+        assert!(write_line(
+            &mut line,
+            &QueryEvent::Change(
+                ChangeType::Update,
+                RowId(1),
+                vec![
+                    SqliteValue::Integer(1),
+                    SqliteValue::Text("two\nlines".into())
+                ],
+                ChangeId(1),
+                None
+            )
+        ));
+        assert!(write_line(&mut line, &QueryEvent::Dropped { count: 3 }));
+        assert!(write_line(
+            &mut line,
+            &QueryEvent::Moved {
+                addr: "127.0.0.1:8080".parse().unwrap(),
+                id: uuid::Uuid::nil(),
+            }
+        ));
+
+        assert_eq!(
+            line,
+            "columns\tid\tname\n\
+             row\t1\t1\ttab\\there\\\\\t\\N\t\\xcafe\n\
+             eoq\t0\n\
+             change\tupdate\t1\t1\t1\ttwo\\nlines\n\
+             dropped\t3\n\
+             moved\t127.0.0.1:8080\t00000000-0000-0000-0000-000000000000\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_socket() -> eyre::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+
+        // nothing to remove
+        let path = tmpdir.path().join("fanout.sock");
+        remove_socket(&path).await?;
+
+        let listener = UnixListener::bind(&path)?;
+        drop(listener);
+        remove_socket(&path).await?;
+        assert!(!path.exists());
+
+        // a regular file is never removed
+        let path = tmpdir.path().join("data.db");
+        tokio::fs::write(&path, b"data").await?;
+        let err = remove_socket(&path).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(path.exists());
+
+        Ok(())
+    }
+}
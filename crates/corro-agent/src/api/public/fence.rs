@@ -0,0 +1,72 @@
+//! Tells clients when to retry writes rejected while a schema migration
+//! fences them, see [`corro_types::fence`].
+
+use axum::{
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use corro_types::{agent::Agent, fence::WriteFence};
+use hyper::StatusCode;
+
+pub async fn retry_fenced(
+    Extension(agent): Extension<Agent>,
+    request: Request<hyper::Body>,
+    next: Next<hyper::Body>,
+) -> Response {
+    let mut res = next.run(request).await;
+    set_retry_after(agent.write_fence(), &mut res);
+    res
+}
+
+/// Fenced writes are rejected while the fence is still raised, other 503s
+/// returned meanwhile are just as likely to go away once it's lowered
+fn set_retry_after(fence: &WriteFence, res: &mut Response) {
+    if res.status() != StatusCode::SERVICE_UNAVAILABLE
+        || res.headers().contains_key(header::RETRY_AFTER)
+    {
+        return;
+    }
+    if let Some(retry_after) = fence.retry_after() {
+        res.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(retry_after.as_secs()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    #[test]
+    fn test_set_retry_after() {
+        let fence = WriteFence::default();
+
+        let mut res = StatusCode::SERVICE_UNAVAILABLE.into_response();
+        set_retry_after(&fence, &mut res);
+        assert!(res.headers().get(header::RETRY_AFTER).is_none());
+
+        let _guard = fence.raise();
+
+        let mut res = StatusCode::SERVICE_UNAVAILABLE.into_response();
+        set_retry_after(&fence, &mut res);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+
+        let mut res = StatusCode::OK.into_response();
+        set_retry_after(&fence, &mut res);
+        assert!(res.headers().get(header::RETRY_AFTER).is_none());
+
+        // shed requests already say when to retry
+        let mut res = (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "5")],
+        )
+            .into_response();
+        set_retry_after(&fence, &mut res);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "5");
+    }
+}
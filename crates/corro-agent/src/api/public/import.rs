@@ -0,0 +1,1073 @@
+//! CSV and NDJSON imports: the header, or the first JSON row, maps fields to
+//! the table's columns, values are coerced to the columns' types and rows are
+//! applied in bounded transactions, with progress streamed back to the
+//! client. Rows are read on a blocking thread, while earlier batches are
+//! applied, so bodies streamed to `/v1/tables/:name/import` are never
+//! buffered whole.
+//!
+//! Bulk upserts of JSON rows share the statements of imports, but apply all
+//! of their rows in a single transaction.
+
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, Read},
+    time::Instant,
+};
+
+use axum::{
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    Extension,
+};
+use bytes::{BufMut, BytesMut};
+use corro_types::{
+    agent::Agent,
+    api::{
+        ErrorCode, ImportEvent, Real, SqliteValue, UpsertRequest, UpsertResponse, UpsertResult,
+        UpsertRowError,
+    },
+    error::{ApiError, ChangeError},
+    schema::{Column, SqliteType},
+};
+use futures::TryStreamExt;
+use hyper::StatusCode;
+use itertools::Itertools;
+use metrics::counter;
+use rusqlite::params_from_iter;
+use serde::Deserialize;
+use spawn::{spawn_blocking_counted_w_handle, spawn_named, Shutdown};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{debug, error, info, warn};
+use tripwire::Tripwire;
+
+use super::{
+    commit_broadcastable_changes, error::HttpApiError, export::CSV_NULL,
+    make_broadcastable_changes, Broadcast,
+};
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const MAX_BATCH_SIZE: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+pub struct ImportCsvParams {
+    pub table: String,
+    /// Maximum number of rows applied per transaction
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ImportTableParams {
+    /// Maximum number of rows applied per transaction
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// Don't broadcast the imported rows, other nodes get them when they
+    /// sync with this one
+    #[serde(default)]
+    pub skip_broadcast: bool,
+}
+
+/// Format of the rows of an import, CSV with a header or newline-delimited
+/// JSON objects keyed by column name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ImportFormat {
+    /// CSV, unless the content type says otherwise
+    fn from_headers(headers: &HeaderMap) -> Result<Self, ImportError> {
+        let Some(content_type) = headers.get(header::CONTENT_TYPE) else {
+            return Ok(ImportFormat::Csv);
+        };
+        let content_type = content_type.to_str().unwrap_or_default();
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "text/csv" => Ok(ImportFormat::Csv),
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                Ok(ImportFormat::Ndjson)
+            }
+            other => Err(ImportError::UnsupportedContentType(other.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("table '{0}' does not exist")]
+    UnknownTable(String),
+    #[error("column '{column}' does not exist in table '{table}'")]
+    UnknownColumn { table: String, column: String },
+    #[error("column '{0}' appears more than once")]
+    DuplicateColumn(String),
+    #[error("column '{0}' is generated and can't be imported")]
+    Generated(String),
+    #[error("primary key column '{0}' is missing")]
+    MissingPrimaryKey(String),
+    #[error("at least 1 upsert is required")]
+    NoUpserts,
+    #[error("invalid value for column '{column}': {reason}")]
+    InvalidValue { column: String, reason: String },
+    #[error("unsupported content type '{0}', expected text/csv or application/x-ndjson")]
+    UnsupportedContentType(String),
+    #[error("row doesn't have the columns of the first row")]
+    ColumnMismatch,
+    #[error("could not read rows: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid JSON row: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("import stopped unexpectedly")]
+    Interrupted,
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Change(#[from] ChangeError),
+}
+
+impl ApiError for ImportError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ImportError::Interrupted => ErrorCode::Internal,
+            ImportError::Change(e) => e.code(),
+            _ => ErrorCode::BadRequest,
+        }
+    }
+}
+
+impl From<ImportError> for hyper::Response<hyper::Body> {
+    fn from(e: ImportError) -> Self {
+        (e.status(), axum::Json(e.exec_result())).into_response()
+    }
+}
+
+/// Columns named by the header, in order, and the statement upserting a row
+#[derive(Clone)]
+struct ImportSql {
+    columns: Vec<Column>,
+    upsert: String,
+}
+
+impl ImportSql {
+    fn new<'a>(
+        agent: &Agent,
+        table_name: &str,
+        header: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, ImportError> {
+        let schema = agent.schema().read();
+        let table = schema
+            .tables
+            .get(table_name)
+            .ok_or_else(|| ImportError::UnknownTable(table_name.to_owned()))?;
+
+        let mut seen = HashSet::new();
+        let columns = header
+            .into_iter()
+            .map(|name| {
+                let name = name.trim();
+                let column = table
+                    .columns
+                    .get(name)
+                    .ok_or_else(|| ImportError::UnknownColumn {
+                        table: table_name.to_owned(),
+                        column: name.to_owned(),
+                    })?;
+                if !seen.insert(name) {
+                    return Err(ImportError::DuplicateColumn(name.to_owned()));
+                }
+                if column.generated.is_some() {
+                    return Err(ImportError::Generated(name.to_owned()));
+                }
+                Ok(column.clone())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(pk) = table.pk.iter().find(|pk| !seen.contains(pk.as_str())) {
+            return Err(ImportError::MissingPrimaryKey(pk.clone()));
+        }
+
+        let cols = columns
+            .iter()
+            .map(|col| format!("\"{}\"", col.name))
+            .join(",");
+        let placeholders = columns.iter().map(|_| "?").join(",");
+        let pk_cols = table.pk.iter().map(|pk| format!("\"{pk}\"")).join(",");
+
+        // rows already present are updated in place, only the imported
+        // columns change
+        let updates = columns
+            .iter()
+            .filter(|col| !col.primary_key)
+            .map(|col| format!("\"{0}\" = excluded.\"{0}\"", col.name))
+            .join(",");
+        let on_conflict = if updates.is_empty() {
+            "DO NOTHING".to_owned()
+        } else {
+            format!("DO UPDATE SET {updates}")
+        };
+
+        Ok(Self {
+            columns,
+            upsert: format!(
+                "INSERT INTO \"{table_name}\" ({cols}) VALUES ({placeholders}) ON CONFLICT ({pk_cols}) {on_conflict}"
+            ),
+        })
+    }
+}
+
+/// Converts a CSV field to the type of its column. `\N` is NULL for
+/// nullable columns, and so are empty fields unless the column holds text.
+fn coerce(column: &Column, field: &str) -> Result<SqliteValue, ImportError> {
+    if column.nullable && !column.primary_key {
+        let text = matches!(column.sql_type.0, SqliteType::Text | SqliteType::Null);
+        if field == CSV_NULL || (field.is_empty() && !text) {
+            return Ok(SqliteValue::Null);
+        }
+    }
+
+    let invalid = |expected: &str| ImportError::InvalidValue {
+        column: column.name.clone(),
+        reason: format!("expected {expected}, got '{field}'"),
+    };
+
+    Ok(match column.sql_type.0 {
+        SqliteType::Integer => {
+            SqliteValue::Integer(field.trim().parse().map_err(|_| invalid("an integer"))?)
+        }
+        SqliteType::Real => {
+            SqliteValue::Real(Real(field.trim().parse().map_err(|_| invalid("a number"))?))
+        }
+        SqliteType::Numeric => {
+            if let Ok(i) = field.trim().parse() {
+                SqliteValue::Integer(i)
+            } else if let Ok(f) = field.trim().parse() {
+                SqliteValue::Real(Real(f))
+            } else {
+                SqliteValue::Text(field.into())
+            }
+        }
+        SqliteType::Blob => SqliteValue::Blob(
+            hex::decode(field)
+                .map_err(|_| invalid("hex-encoded bytes"))?
+                .into(),
+        ),
+        SqliteType::Text | SqliteType::Null => SqliteValue::Text(field.into()),
+    })
+}
+
+/// Coerces the fields of a record, errors come with the line they're on
+fn parse_record(
+    columns: &[Column],
+    record: csv::Result<csv::StringRecord>,
+) -> Result<Vec<SqliteValue>, (Option<u64>, ImportError)> {
+    let record = record.map_err(|e| (e.position().map(|pos| pos.line()), ImportError::from(e)))?;
+    record
+        .iter()
+        .zip(columns)
+        .map(|(field, column)| coerce(column, field))
+        .collect::<Result<_, _>>()
+        .map_err(|e| (record.position().map(|pos| pos.line()), e))
+}
+
+/// Converts a JSON value to the type of its column, strings are converted
+/// like CSV fields
+fn coerce_json(column: &Column, value: serde_json::Value) -> Result<SqliteValue, ImportError> {
+    use serde_json::Value;
+
+    Ok(match value {
+        Value::Null => SqliteValue::Null,
+        Value::Bool(b) => SqliteValue::Integer(b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqliteValue::Integer(i),
+            None => SqliteValue::Real(Real(n.as_f64().unwrap_or_default())),
+        },
+        Value::String(s) => match column.sql_type.0 {
+            SqliteType::Text | SqliteType::Null => SqliteValue::Text(s.into()),
+            _ => coerce(column, &s)?,
+        },
+        Value::Array(_) | Value::Object(_) => {
+            return Err(ImportError::InvalidValue {
+                column: column.name.clone(),
+                reason: "expected a string, a number, a boolean or null".into(),
+            })
+        }
+    })
+}
+
+type JsonRow = serde_json::Map<String, serde_json::Value>;
+
+/// Coerces the values of a JSON row, which must have the imported columns
+/// and no others
+fn parse_json_row(
+    columns: &[Column],
+    line: io::Result<String>,
+    line_number: u64,
+) -> Result<Vec<SqliteValue>, (Option<u64>, ImportError)> {
+    let with_line = |e: ImportError| (Some(line_number), e);
+
+    let line = line.map_err(|e| with_line(e.into()))?;
+    let mut row: JsonRow = serde_json::from_str(&line).map_err(|e| with_line(e.into()))?;
+    if row.len() != columns.len() {
+        return Err(with_line(ImportError::ColumnMismatch));
+    }
+    columns
+        .iter()
+        .map(|column| {
+            let value = row
+                .remove(&column.name)
+                .ok_or(ImportError::ColumnMismatch)?;
+            coerce_json(column, value)
+        })
+        .collect::<Result<_, _>>()
+        .map_err(with_line)
+}
+
+/// Rows applied in a transaction, or why reading them stopped
+type RowBatch = Result<Vec<Vec<SqliteValue>>, (Option<u64>, ImportError)>;
+
+/// Reads the rows of an import, blocking on the body. The upsert statement
+/// is sent as soon as the header (or the first JSON row) was read, then rows
+/// are sent in batches until the body ends or a row is invalid.
+fn read_rows<R: Read + 'static>(
+    agent: &Agent,
+    table: &str,
+    format: ImportFormat,
+    reader: R,
+    batch_size: usize,
+    sql_tx: oneshot::Sender<Result<ImportSql, ImportError>>,
+    batch_tx: mpsc::Sender<RowBatch>,
+) {
+    let send_sql = |sql: Result<ImportSql, ImportError>| {
+        let columns = sql.as_ref().ok().map(|sql| sql.columns.clone());
+        // nobody's waiting for the rows if the header was invalid
+        sql_tx.send(sql).ok().and(columns)
+    };
+
+    let mut rows: Box<dyn Iterator<Item = Result<Vec<SqliteValue>, (Option<u64>, ImportError)>>> =
+        match format {
+            ImportFormat::Csv => {
+                let mut reader = csv::Reader::from_reader(reader);
+                let sql = reader
+                    .headers()
+                    .map_err(ImportError::from)
+                    .and_then(|header| ImportSql::new(agent, table, header.iter()));
+                let Some(columns) = send_sql(sql) else {
+                    return;
+                };
+                Box::new(
+                    reader
+                        .into_records()
+                        .map(move |record| parse_record(&columns, record)),
+                )
+            }
+            ImportFormat::Ndjson => {
+                let mut lines = BufReader::new(reader)
+                    .lines()
+                    .zip(1..)
+                    .filter(|(line, _)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                    .peekable();
+                // the first row names the columns of every row
+                let header = match lines.peek() {
+                    Some((Ok(line), _)) => match serde_json::from_str::<JsonRow>(line) {
+                        Ok(row) => row.into_iter().map(|(name, _)| name).collect(),
+                        Err(e) => {
+                            send_sql(Err(e.into()));
+                            return;
+                        }
+                    },
+                    Some((Err(e), _)) => {
+                        send_sql(Err(io::Error::new(e.kind(), e.to_string()).into()));
+                        return;
+                    }
+                    None => vec![],
+                };
+                let sql = ImportSql::new(agent, table, header.iter().map(String::as_str));
+                let Some(columns) = send_sql(sql) else {
+                    return;
+                };
+                Box::new(
+                    lines.map(move |(line, line_number)| {
+                        parse_json_row(&columns, line, line_number)
+                    }),
+                )
+            }
+        };
+
+    loop {
+        let mut batch = Vec::with_capacity(batch_size);
+        for row in rows.by_ref().take(batch_size) {
+            match row {
+                Ok(values) => batch.push(values),
+                Err(e) => {
+                    _ = batch_tx.blocking_send(Err(e));
+                    return;
+                }
+            }
+        }
+
+        // closing the channel ends the import
+        if batch.is_empty() || batch_tx.blocking_send(Ok(batch)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Import CSV rows into a table, streaming progress as newline-delimited
+/// events. Rows are upserted: existing rows only have the imported columns
+/// updated. The body is read row by row, as batches are applied.
+pub async fn api_v1_import_csv(
+    Extension(agent): Extension<Agent>,
+    Extension(tripwire): Extension<Tripwire>,
+    axum::extract::Query(params): axum::extract::Query<ImportCsvParams>,
+    body: hyper::Body,
+) -> hyper::Response<hyper::Body> {
+    let body = body.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+    import_rows(
+        agent,
+        tripwire,
+        params.table,
+        ImportFormat::Csv,
+        SyncIoBridge::new(StreamReader::new(body)),
+        params.batch_size,
+        false,
+    )
+    .await
+}
+
+/// Import CSV or NDJSON rows into a table, like `api_v1_import_csv`. The
+/// body isn't bounded by the API's maximum body size.
+pub async fn api_v1_table_import(
+    Extension(agent): Extension<Agent>,
+    Extension(tripwire): Extension<Tripwire>,
+    axum::extract::Path(table): axum::extract::Path<String>,
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<ImportTableParams>,
+    body: hyper::Body,
+) -> hyper::Response<hyper::Body> {
+    let format = match ImportFormat::from_headers(&headers) {
+        Ok(format) => format,
+        Err(e) => return e.into(),
+    };
+
+    let body = body.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+    import_rows(
+        agent,
+        tripwire,
+        table,
+        format,
+        SyncIoBridge::new(StreamReader::new(body)),
+        params.batch_size,
+        params.skip_broadcast,
+    )
+    .await
+}
+
+async fn import_rows<R: Read + Send + 'static>(
+    agent: Agent,
+    tripwire: Tripwire,
+    table: String,
+    format: ImportFormat,
+    reader: R,
+    batch_size: Option<usize>,
+    skip_broadcast: bool,
+) -> hyper::Response<hyper::Body> {
+    let batch_size = batch_size
+        .unwrap_or(DEFAULT_BATCH_SIZE)
+        .clamp(1, MAX_BATCH_SIZE);
+
+    let (sql_tx, sql_rx) = oneshot::channel();
+    // the next batch is read while the current one is applied
+    let (batch_tx, batch_rx) = mpsc::channel(1);
+
+    spawn_blocking_counted_w_handle(
+        {
+            let agent = agent.clone();
+            let table = table.clone();
+            move || read_rows(&agent, &table, format, reader, batch_size, sql_tx, batch_tx)
+        },
+        &tokio::runtime::Handle::current(),
+    );
+
+    let sql = match sql_rx.await {
+        Ok(Ok(sql)) => sql,
+        Ok(Err(e)) => return e.into(),
+        Err(_) => return ImportError::Interrupted.into(),
+    };
+
+    let (evt_tx, mut evt_rx) = mpsc::channel(64);
+
+    spawn_named(
+        "import",
+        Shutdown::Graceful,
+        run_import(
+            agent,
+            table,
+            sql,
+            skip_broadcast,
+            batch_rx,
+            evt_tx,
+            tripwire,
+        ),
+    );
+
+    let (mut body_tx, body) = hyper::Body::channel();
+
+    spawn_named("import_response_body", Shutdown::Abortable, async move {
+        let mut buf = BytesMut::new();
+
+        while let Some(evt) = evt_rx.recv().await {
+            {
+                let mut writer = (&mut buf).writer();
+                if let Err(e) = serde_json::to_writer(&mut writer, &evt) {
+                    error!("could not serialize import event: {e}");
+                    return;
+                }
+            }
+
+            buf.extend_from_slice(b"\n");
+
+            if let Err(e) = body_tx.send_data(buf.split().freeze()).await {
+                debug!("could not send data through body's channel: {e}");
+                return;
+            }
+        }
+    });
+
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .body(body)
+        .expect("could not build import response body")
+}
+
+async fn run_import(
+    agent: Agent,
+    table: String,
+    sql: ImportSql,
+    skip_broadcast: bool,
+    mut batch_rx: mpsc::Receiver<RowBatch>,
+    evt_tx: mpsc::Sender<ImportEvent>,
+    tripwire: Tripwire,
+) {
+    let actor_id = agent.actor_id();
+    let start = Instant::now();
+
+    let mut rows = 0;
+    let mut batches = 0;
+
+    loop {
+        if tripwire.is_shutting_down() {
+            _ = evt_tx
+                .send(ImportEvent::Error {
+                    line: None,
+                    error: "import interrupted by shutdown".into(),
+                })
+                .await;
+            return;
+        }
+
+        let batch = match batch_rx.recv().await {
+            Some(Ok(batch)) => batch,
+            Some(Err((line, e))) => {
+                warn!(%table, "stopping import: {e}");
+                _ = evt_tx
+                    .send(ImportEvent::Error {
+                        line,
+                        error: e.to_string(),
+                    })
+                    .await;
+                return;
+            }
+            None => {
+                info!(%table, "imported {rows} rows in {batches} batches");
+                _ = evt_tx
+                    .send(ImportEvent::Done {
+                        rows,
+                        batches,
+                        time: start.elapsed().as_secs_f64(),
+                    })
+                    .await;
+                return;
+            }
+        };
+
+        let broadcast = if skip_broadcast {
+            Broadcast::Skip
+        } else {
+            Broadcast::Changes
+        };
+        let res = commit_broadcastable_changes(
+            &agent,
+            &[],
+            |tx| {
+                let map_err = |source: rusqlite::Error| ChangeError::Rusqlite {
+                    source,
+                    actor_id: Some(actor_id),
+                    version: None,
+                };
+
+                let mut prepped = tx.prepare_cached(&sql.upsert).map_err(&map_err)?;
+                for values in batch.iter() {
+                    prepped
+                        .execute(params_from_iter(values.iter()))
+                        .map_err(&map_err)?;
+                }
+                Ok(())
+            },
+            broadcast,
+        )
+        .await;
+
+        if let Err(e) = res {
+            error!(%table, "import failed: {e}");
+            _ = evt_tx
+                .send(ImportEvent::Error {
+                    line: None,
+                    error: ImportError::from(e).to_string(),
+                })
+                .await;
+            return;
+        }
+
+        rows += batch.len() as u64;
+        batches += 1;
+        counter!("corro.import.rows", "table" => table.clone()).increment(batch.len() as u64);
+
+        if evt_tx
+            .send(ImportEvent::Progress { rows, batches })
+            .await
+            .is_err()
+        {
+            warn!(%table, "client went away, stopping import after {rows} rows");
+            return;
+        }
+    }
+}
+
+/// Upserts rows in one transaction. Rows failing to apply, on a constraint
+/// for example, are reported by index and the others are committed.
+pub async fn api_v1_upserts(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(reqs): axum::extract::Json<Vec<UpsertRequest>>,
+) -> hyper::Response<hyper::Body> {
+    if reqs.is_empty() {
+        return ImportError::NoUpserts.into();
+    }
+
+    let sqls = match reqs
+        .iter()
+        .map(|req| ImportSql::new(&agent, &req.table, req.columns.iter().map(String::as_str)))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(sqls) => sqls,
+        Err(e) => return e.into(),
+    };
+
+    let actor_id = agent.actor_id();
+    let start = Instant::now();
+
+    let res = make_broadcastable_changes(&agent, |tx| {
+        let map_err = |source: rusqlite::Error| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: None,
+        };
+
+        let mut results = Vec::with_capacity(reqs.len());
+        for (req, sql) in reqs.iter().zip(sqls.iter()) {
+            let mut prepped = tx.prepare_cached(&sql.upsert).map_err(map_err)?;
+            let mut result = UpsertResult::default();
+            for (i, values) in req.rows.iter().enumerate() {
+                if values.len() != sql.columns.len() {
+                    result.errors.push(UpsertRowError {
+                        row: i,
+                        error: format!(
+                            "expected {} values, got {}",
+                            sql.columns.len(),
+                            values.len()
+                        ),
+                    });
+                    continue;
+                }
+                // a failed statement has no effect, the transaction goes on
+                match prepped.execute(params_from_iter(values.iter())) {
+                    Ok(rows_affected) => result.rows_affected += rows_affected,
+                    Err(e) => result.errors.push(UpsertRowError {
+                        row: i,
+                        error: e.to_string(),
+                    }),
+                }
+            }
+            results.push(result);
+        }
+        Ok(results)
+    })
+    .await;
+
+    let results = match res {
+        Ok((results, _)) => results,
+        Err(e) => {
+            if e.status().is_server_error() && !matches!(e, ChangeError::Fenced(_)) {
+                error!("could not upsert rows: {e}");
+            }
+            return e.into_exec_response().into_response();
+        }
+    };
+
+    for (req, result) in reqs.iter().zip(results.iter()) {
+        counter!("corro.import.rows", "table" => req.table.clone())
+            .increment((req.rows.len() - result.errors.len()) as u64);
+    }
+
+    axum::Json(UpsertResponse {
+        results,
+        time: start.elapsed().as_secs_f64(),
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::config::Config;
+
+    use super::*;
+    use crate::{agent::setup, api::public::api_v1_db_schema};
+
+    async fn import_csv(
+        agent: &Agent,
+        tripwire: &Tripwire,
+        batch_size: usize,
+        csv: &'static str,
+    ) -> eyre::Result<(StatusCode, Vec<ImportEvent>)> {
+        let res = api_v1_import_csv(
+            Extension(agent.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Query(ImportCsvParams {
+                table: "tests".into(),
+                batch_size: Some(batch_size),
+            }),
+            hyper::Body::from(csv),
+        )
+        .await;
+        let status = res.status();
+        if status != StatusCode::OK {
+            return Ok((status, vec![]));
+        }
+
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok((
+            status,
+            body.split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(serde_json::from_slice)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_import_csv() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _) = import_csv(&agent, &tripwire, 2, "id,nope\n1,a\n").await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let (status_code, _) = import_csv(&agent, &tripwire, 2, "text\na\n").await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let (status_code, events) = import_csv(
+            &agent,
+            &tripwire,
+            2,
+            "id,text\n1,one\n2,two\n3,three\n4,four\n5,five\n",
+        )
+        .await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(
+            &events[..3],
+            &[
+                ImportEvent::Progress {
+                    rows: 2,
+                    batches: 1
+                },
+                ImportEvent::Progress {
+                    rows: 4,
+                    batches: 2
+                },
+                ImportEvent::Progress {
+                    rows: 5,
+                    batches: 3
+                },
+            ]
+        );
+        assert!(matches!(
+            events[3],
+            ImportEvent::Done {
+                rows: 5,
+                batches: 3,
+                ..
+            }
+        ));
+
+        // rows before the invalid one are kept, existing rows are updated
+        let (status_code, events) =
+            import_csv(&agent, &tripwire, 1, "text,id\nuno,1\ndos,two\n").await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(
+            events,
+            vec![
+                ImportEvent::Progress {
+                    rows: 1,
+                    batches: 1
+                },
+                ImportEvent::Error {
+                    line: Some(3),
+                    error: "invalid value for column 'id': expected an integer, got 'two'".into()
+                },
+            ]
+        );
+
+        let conn = agent.pool().read().await?;
+        let texts: Vec<String> = conn
+            .prepare("SELECT text FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(texts, vec!["uno", "two", "three", "four", "five"]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_table_import() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let import = |content_type: &'static str, body: &'static str| {
+            let agent = agent.clone();
+            let tripwire = tripwire.clone();
+            async move {
+                let mut headers = HeaderMap::new();
+                headers.insert(header::CONTENT_TYPE, content_type.parse()?);
+                let res = api_v1_table_import(
+                    Extension(agent),
+                    Extension(tripwire),
+                    axum::extract::Path("tests".into()),
+                    headers,
+                    axum::extract::Query(ImportTableParams {
+                        batch_size: Some(2),
+                        skip_broadcast: true,
+                    }),
+                    hyper::Body::from(body),
+                )
+                .await;
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                if status != StatusCode::OK {
+                    return Ok::<_, eyre::Report>((status, vec![]));
+                }
+                let events: Vec<ImportEvent> = body
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(serde_json::from_slice)
+                    .collect::<Result<_, _>>()?;
+                Ok((status, events))
+            }
+        };
+
+        let (status_code, _) = import("application/xml", "<rows/>").await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let (status_code, _) = import("application/x-ndjson", "{\"text\":\"a\"}\n").await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let (status_code, events) = import(
+            "application/x-ndjson",
+            "{\"id\":1,\"text\":\"one\"}\n\n{\"text\":\"two\",\"id\":\"2\"}\n{\"id\":3,\"text\":\"three\"}\n",
+        )
+        .await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(
+            &events[..2],
+            &[
+                ImportEvent::Progress {
+                    rows: 2,
+                    batches: 1
+                },
+                ImportEvent::Progress {
+                    rows: 3,
+                    batches: 2
+                },
+            ]
+        );
+        assert!(matches!(
+            events[2],
+            ImportEvent::Done {
+                rows: 3,
+                batches: 2,
+                ..
+            }
+        ));
+
+        // rows need the columns of the first one, lines are counted from 1
+        let (status_code, events) = import(
+            "application/x-ndjson",
+            "{\"id\":1,\"text\":\"uno\"}\n{\"id\":2}\n",
+        )
+        .await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(
+            events,
+            vec![ImportEvent::Error {
+                line: Some(2),
+                error: "row doesn't have the columns of the first row".into()
+            }]
+        );
+
+        let (status_code, events) = import("text/csv", "id,text\n4,four\n").await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(matches!(events[..], [_, ImportEvent::Done { rows: 1, .. }]));
+
+        let conn = agent.pool().read().await?;
+        let texts: Vec<String> = conn
+            .prepare("SELECT text FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(texts, vec!["one", "two", "three", "four"]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upserts() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let upserts = |reqs: Vec<UpsertRequest>| {
+            let agent = agent.clone();
+            async move {
+                let res = api_v1_upserts(Extension(agent), axum::Json(reqs)).await;
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                Ok::<_, eyre::Report>((status, body))
+            }
+        };
+
+        let (status_code, _) = upserts(vec![UpsertRequest {
+            table: "tests".into(),
+            columns: vec!["text".into()],
+            rows: vec![vec!["a".into()]],
+        }])
+        .await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let (status_code, body) = upserts(vec![
+            UpsertRequest {
+                table: "tests".into(),
+                columns: vec!["id".into(), "text".into()],
+                rows: vec![
+                    vec![SqliteValue::Integer(1), "one".into()],
+                    vec![SqliteValue::Integer(2)],
+                    vec![SqliteValue::Null, "null".into()],
+                    vec![SqliteValue::Integer(1), "uno".into()],
+                ],
+            },
+            UpsertRequest {
+                table: "tests2".into(),
+                columns: vec!["id".into(), "text".into()],
+                rows: vec![vec![SqliteValue::Integer(1), "one".into()]],
+            },
+        ])
+        .await?;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let res: UpsertResponse = serde_json::from_slice(&body)?;
+        assert_eq!(res.results.len(), 2);
+        assert_eq!(res.results[0].rows_affected, 2);
+        assert_eq!(
+            res.results[0]
+                .errors
+                .iter()
+                .map(|e| e.row)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            res.results[1],
+            UpsertResult {
+                rows_affected: 1,
+                errors: vec![]
+            }
+        );
+
+        let conn = agent.pool().read().await?;
+        let texts: Vec<String> = conn
+            .prepare("SELECT text FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(texts, vec!["uno"]);
+
+        Ok(())
+    }
+}
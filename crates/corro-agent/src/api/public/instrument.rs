@@ -0,0 +1,184 @@
+//! Per-endpoint metrics of the public API, labeled by route, method and
+//! status code: latency until the response headers are sent, request and
+//! response body sizes, time spent streaming the response, statements per
+//! request and rows streamed.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    body::{BoxBody, Bytes},
+    extract::MatchedPath,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Body, SizeHint};
+use metrics::{histogram, Label};
+
+tokio::task_local! {
+    static REQUEST_STATS: Arc<RequestStats>;
+}
+
+/// Counters filled by handlers while serving a request, recorded once its
+/// response body is done
+#[derive(Debug, Default)]
+pub struct RequestStats {
+    statements: AtomicUsize,
+    rows: AtomicUsize,
+}
+
+impl RequestStats {
+    /// Stats of the request handled by the current task, to be moved into
+    /// tasks streaming its response. `None` outside of the middleware.
+    pub fn current() -> Option<Arc<Self>> {
+        REQUEST_STATS.try_with(Arc::clone).ok()
+    }
+
+    pub fn add_rows(&self, n: usize) {
+        self.rows.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// Records the number of statements of the request handled by the current task
+pub fn record_statements(n: usize) {
+    _ = REQUEST_STATS.try_with(|stats| stats.statements.fetch_add(n, Ordering::Relaxed));
+}
+
+pub async fn instrument(request: Request<hyper::Body>, next: Next<hyper::Body>) -> Response {
+    let start = Instant::now();
+
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let method = request.method().as_str().to_owned();
+    let request_bytes = request.body().size_hint().exact();
+
+    let stats = Arc::new(RequestStats::default());
+    let response = REQUEST_STATS.scope(stats.clone(), next.run(request)).await;
+
+    let labels = vec![
+        Label::new("endpoint", endpoint),
+        Label::new("method", method),
+        Label::new("status", response.status().as_u16().to_string()),
+    ];
+    histogram!("corro.api.request.seconds", labels.clone()).record(start.elapsed());
+    if let Some(bytes) = request_bytes {
+        histogram!("corro.api.request.bytes", labels.clone()).record(bytes as f64);
+    }
+
+    response.map(|inner| {
+        axum::body::boxed(MeteredBody {
+            inner,
+            labels,
+            stats,
+            bytes: 0,
+            start: Instant::now(),
+        })
+    })
+}
+
+/// Response body recording what went through it when dropped, whether it
+/// was fully sent or the client went away
+struct MeteredBody {
+    inner: BoxBody,
+    labels: Vec<Label>,
+    stats: Arc<RequestStats>,
+    bytes: usize,
+    start: Instant,
+}
+
+impl Body for MeteredBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let res = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(data))) = &res {
+            self.bytes += data.len();
+        }
+        res
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        let labels = std::mem::take(&mut self.labels);
+        histogram!("corro.api.response.bytes", labels.clone()).record(self.bytes as f64);
+        histogram!("corro.api.response.seconds", labels.clone()).record(self.start.elapsed());
+
+        let statements = self.stats.statements.load(Ordering::Relaxed);
+        if statements > 0 {
+            histogram!("corro.api.request.statements", labels.clone()).record(statements as f64);
+        }
+        let rows = self.stats.rows.load(Ordering::Relaxed);
+        if rows > 0 {
+            histogram!("corro.api.response.rows", labels).record(rows as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_stats() -> Result<(), Box<dyn std::error::Error>> {
+        async fn handler() -> &'static str {
+            record_statements(3);
+            let stats = RequestStats::current().expect("no request stats");
+            stats.add_rows(2);
+            "ok"
+        }
+
+        let app = Router::new()
+            .route("/v1/things/:id", post(handler))
+            .layer(axum::middleware::from_fn(instrument));
+
+        let res = app
+            .oneshot(
+                Request::post("/v1/things/1")
+                    .body(hyper::Body::from("hello"))
+                    .unwrap(),
+            )
+            .await?;
+        assert!(res.status().is_success());
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, "ok");
+
+        // outside of the middleware, nothing is recorded
+        record_statements(1);
+        assert!(RequestStats::current().is_none());
+
+        Ok(())
+    }
+}
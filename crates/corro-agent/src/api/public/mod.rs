@@ -1,28 +1,47 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use axum::{response::IntoResponse, Extension};
-use bytes::{BufMut, BytesMut};
+use axum::{http::HeaderMap, response::IntoResponse, Extension};
+use bytes::BytesMut;
 use compact_str::ToCompactString;
 use corro_types::{
-    agent::{Agent, ChangeError, CurrentVersion, KnownDbVersion},
+    activity::ActivityKind,
+    agent::{Agent, CurrentVersion, KnownDbVersion},
     api::{
-        row_to_change, ColumnName, ExecResponse, ExecResult, QueryEvent, Statement,
-        TableStatRequest, TableStatResponse,
+        row_to_change, BackfillRequest, ColumnName, ColumnSchema, ErrorCode, ExecResponse,
+        ExecResult, IndexSchema, MigrationResponse, Precondition, QueryEvent, SchemaChangeResponse,
+        SchemaColumnRef, SchemaPlan, SchemaResponse, Statement, TableSchema, TableStatRequest,
+        TableStatResponse, TransactionRequest, TruncateRequest,
     },
     base::{CrsqlDbVersion, CrsqlSeq},
     broadcast::{ChangeV1, Changeset, Timestamp},
-    change::{ChunkedChanges, SqliteValue, MAX_CHANGES_BYTE_SIZE},
-    schema::{apply_schema, parse_sql},
-    sqlite::SqlitePoolError,
+    causality::{row_meta, with_pk_columns, RowMetaError},
+    change::{without_large_values, ChunkedChanges, SqliteValue, MAX_CHANGES_BYTE_SIZE},
+    config::ReadStatements,
+    error::{ApiError, ChangeError, QueryError, SchemaError},
+    pagination::{
+        decode_cursor, encode_cursor, order_deterministically, paginate, Page, PaginationError,
+    },
+    schema::{
+        apply_schema, drop_tables, init_schema, parse_schema_change, plan_schema, ApplySchemaError,
+        ApplySchemaOptions, Schema, SchemaChange, Table,
+    },
+    sqlite::retry_busy,
+    validation::{ChangeSummary, PendingTransaction},
 };
 use hyper::StatusCode;
 use itertools::Itertools;
 use metrics::counter;
-use rusqlite::{named_params, params_from_iter, ToSql, Transaction};
-use spawn::spawn_counted;
+use rusqlite::{params_from_iter, Connection, OptionalExtension, ToSql, Transaction};
+use serde::{Deserialize, Serialize};
+use spawn::{spawn_counted, spawn_named, Shutdown};
+use sqlite3_parser::{
+    ast::{As, Cmd, OneSelect, SelectTable, Stmt},
+    lexer::sql::Parser,
+};
 use tokio::{
     sync::{
         mpsc::{self, channel},
@@ -31,10 +50,45 @@ use tokio::{
     task::block_in_place,
 };
 use tracing::{debug, error, info, trace};
+use tripwire::Tripwire;
+use uuid::Uuid;
 
 use corro_types::broadcast::{BroadcastInput, BroadcastV1};
 
+use backfill::{start_backfill, SharedBackfills};
+use cancel::{RunningQueries, QUERY_ID_HEADER};
+use compression::{BodySender, ContentEncoding};
+use encoding::Encoding;
+use envelope::{
+    flag, rqlite_json, rqlite_query_response, Envelope, EnvelopeParams, Enveloped, RqliteOptions,
+    RqliteQueryResult,
+};
+use error::{status_code, ApiErrorResponse, HttpApiError};
+use instrument::{record_statements, RequestStats};
+use snapshot::{SharedSnapshots, Snapshot};
+
+pub mod backfill;
+pub mod cancel;
+pub mod changes;
+pub mod compression;
+pub mod digest;
+pub mod encoding;
+pub mod envelope;
+pub mod error;
+pub mod export;
+pub mod fanout;
+pub mod fence;
+pub mod import;
+pub mod instrument;
 pub mod pubsub;
+pub mod sessions;
+pub mod shed;
+pub mod snapshot;
+pub mod statements;
+pub mod tokens;
+pub mod v2;
+pub mod watches;
+pub mod ws;
 
 pub async fn make_broadcastable_changes<F, T>(
     agent: &Agent,
@@ -43,167 +97,245 @@ pub async fn make_broadcastable_changes<F, T>(
 where
     F: Fn(&Transaction) -> Result<T, ChangeError>,
 {
+    commit_broadcastable_changes(agent, &[], f, Broadcast::Changes).await
+}
+
+/// How committed changes reach other nodes
+enum Broadcast {
+    Changes,
+    /// Nothing is broadcast, nodes get the changes when they sync with this
+    /// one. Local subscriptions and watches still see them.
+    Skip,
+}
+
+/// Commits the changes made by `f`, once the agent's validators accepted
+/// them, and broadcasts them as told. `statements` are only passed on to
+/// validators.
+async fn commit_broadcastable_changes<F, T>(
+    agent: &Agent,
+    statements: &[Statement],
+    f: F,
+    broadcast: Broadcast,
+) -> Result<(T, Duration), ChangeError>
+where
+    F: Fn(&Transaction) -> Result<T, ChangeError>,
+{
+    let fence_wait = Duration::from_millis(agent.config().api.write_fence_wait_ms);
+    agent.write_fence().wait(fence_wait).await?;
+
     trace!("getting conn...");
     let mut conn = agent.pool().write_priority().await?;
     trace!("got conn");
 
     let actor_id = agent.actor_id();
-    // maybe we should do this earlier, but there can only ever be 1 write conn at a time,
-    // so it probably doesn't matter too much, except for reads of internal state
-    let mut book_writer = agent
-        .booked()
-        .write("make_broadcastable_changes(booked writer)")
-        .await;
 
     let start = Instant::now();
     block_in_place(move || {
-        let tx = conn
-            .immediate_transaction()
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: None,
-            })?;
+        let tx =
+            conn.immediate_transaction_retry("api")
+                .map_err(|source| ChangeError::Rusqlite {
+                    source,
+                    actor_id: Some(actor_id),
+                    version: None,
+                })?;
 
         // Execute whatever might mutate state data
         let ret = f(&tx)?;
 
-        let ts = Timestamp::from(agent.clock().new_timestamp());
+        let elapsed = commit_changes(agent, tx, statements, broadcast, start)?;
+        Ok((ret, elapsed))
+    })
+}
 
-        let db_version: CrsqlDbVersion = tx
-            .prepare_cached("SELECT crsql_next_db_version()")
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: None,
-            })?
-            .query_row((), |row| row.get(0))
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: None,
-            })?;
+/// Commits the changes made within `tx` once the agent's validators
+/// accepted them, recording them under a new version. The bookkeeping lock
+/// is only held from then on.
+fn commit_changes(
+    agent: &Agent,
+    tx: Transaction,
+    statements: &[Statement],
+    broadcast: Broadcast,
+    start: Instant,
+) -> Result<Duration, ChangeError> {
+    let actor_id = agent.actor_id();
 
-        let has_changes: bool = tx
-            .prepare_cached("SELECT EXISTS(SELECT 1 FROM crsql_changes WHERE db_version = ?);")
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: None,
-            })?
-            .query_row([db_version], |row| row.get(0))
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: None,
-            })?;
+    let ts = Timestamp::from(agent.clock().new_timestamp());
+
+    let db_version: CrsqlDbVersion = tx
+        .prepare_cached("SELECT crsql_next_db_version()")
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: None,
+        })?
+        .query_row((), |row| row.get(0))
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: None,
+        })?;
+
+    let has_changes: bool = tx
+        .prepare_cached("SELECT EXISTS(SELECT 1 FROM crsql_changes WHERE db_version = ?);")
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: None,
+        })?
+        .query_row([db_version], |row| row.get(0))
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: None,
+        })?;
+
+    if !has_changes {
+        tx.commit().map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: None,
+        })?;
+        return Ok(start.elapsed());
+    }
 
-        if !has_changes {
-            tx.commit().map_err(|source| ChangeError::Rusqlite {
+    let validators = agent.validators();
+    if !validators.is_empty() {
+        let changes =
+            ChangeSummary::read(&tx, db_version).map_err(|source| ChangeError::Rusqlite {
                 source,
                 actor_id: Some(actor_id),
                 version: None,
             })?;
-            return Ok((ret, start.elapsed()));
-        }
-
-        let last_version = book_writer.last().unwrap_or_default();
-        trace!("last_version: {last_version}");
-        let version = last_version + 1;
-        trace!("version: {version}");
+        // dropping the transaction rolls it back
+        validators.validate(&PendingTransaction {
+            statements,
+            changes: &changes,
+            tx: &tx,
+        })?;
+    }
 
-        let last_seq: CrsqlSeq = tx
-            .prepare_cached("SELECT MAX(seq) FROM crsql_changes WHERE db_version = ?")
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: Some(version),
-            })?
-            .query_row([db_version], |row| row.get(0))
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
+    let mut book_writer = agent
+        .booked()
+        .blocking_write("commit_changes(booked writer)");
+
+    let last_version = book_writer.last().unwrap_or_default();
+    trace!("last_version: {last_version}");
+    let version = last_version + 1;
+    trace!("version: {version}");
+
+    let last_seq: CrsqlSeq = tx
+        .prepare_cached("SELECT MAX(seq) FROM crsql_changes WHERE db_version = ?")
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: Some(version),
+        })?
+        .query_row([db_version], |row| row.get(0))
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: Some(version),
+        })?;
+
+    let elapsed = {
+        agent
+            .bookkeeping()
+            .insert_current(
+                &tx,
+                actor_id,
+                version,
+                &CurrentVersion {
+                    db_version,
+                    last_seq,
+                    ts,
+                },
+            )
+            .map_err(|e| ChangeError::Rusqlite {
+                source: e.into(),
                 actor_id: Some(actor_id),
                 version: Some(version),
             })?;
 
-        let elapsed = {
-            tx.prepare_cached(
+        debug!(%actor_id, %version, %db_version, "inserted local bookkeeping row!");
+
+        tx.commit().map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: Some(version),
+        })?;
+        start.elapsed()
+    };
+
+    trace!("committed tx, db_version: {db_version}, last_seq: {last_seq:?}");
+
+    book_writer.insert(
+        version,
+        KnownDbVersion::Current(CurrentVersion {
+            db_version,
+            last_seq,
+            ts,
+        }),
+    );
+    drop(book_writer);
+
+    agent.history().record_write(last_seq.0 + 1);
+
+    agent.activity().publish(ActivityKind::Committed {
+        version,
+        db_version,
+    });
+
+    let agent = agent.clone();
+
+    spawn_counted(async move {
+        let conn = agent.pool().read().await?;
+
+        block_in_place(|| {
+            // TODO: make this more generic so both sync and local changes can use it.
+            let mut prepped = conn.prepare_cached(
                 r#"
-                INSERT INTO __corro_bookkeeping (actor_id, start_version, db_version, last_seq, ts)
-                    VALUES (:actor_id, :start_version, :db_version, :last_seq, :ts);
+                SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl
+                    FROM crsql_changes
+                    WHERE db_version = ?
+                    ORDER BY seq ASC
             "#,
-            )
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: Some(version),
-            })?
-            .execute(named_params! {
-                ":actor_id": actor_id,
-                ":start_version": version,
-                ":db_version": db_version,
-                ":last_seq": last_seq,
-                ":ts": ts
-            })
-            .map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: Some(version),
-            })?;
+            )?;
+            let rows = prepped.query_map([db_version], row_to_change)?;
+            let chunked = ChunkedChanges::new(rows, CrsqlSeq(0), last_seq, MAX_CHANGES_BYTE_SIZE);
+            for changes_seqs in chunked {
+                match changes_seqs {
+                    Ok((changes, seqs)) => {
+                        for (table_name, count) in changes.iter().counts_by(|change| &change.table)
+                        {
+                            counter!("corro.changes.committed", "table" => table_name.to_string(), "source" => "local").increment(count as u64);
+                        }
 
-            debug!(%actor_id, %version, %db_version, "inserted local bookkeeping row!");
+                        trace!("broadcasting changes: {changes:?} for seq: {seqs:?}");
 
-            tx.commit().map_err(|source| ChangeError::Rusqlite {
-                source,
-                actor_id: Some(actor_id),
-                version: Some(version),
-            })?;
-            start.elapsed()
-        };
+                        agent.subs_manager().match_changes(&changes, db_version);
+                        agent.key_watches().match_changes(&changes);
 
-        trace!("committed tx, db_version: {db_version}, last_seq: {last_seq:?}");
+                        if !matches!(broadcast, Broadcast::Changes) {
+                            // peers get these when they sync with us
+                            continue;
+                        }
 
-        book_writer.insert(
-            version,
-            KnownDbVersion::Current(CurrentVersion {
-                db_version,
-                last_seq,
-                ts,
-            }),
-        );
-        drop(book_writer);
-
-        let agent = agent.clone();
-
-        spawn_counted(async move {
-            let conn = agent.pool().read().await?;
-
-            block_in_place(|| {
-                // TODO: make this more generic so both sync and local changes can use it.
-                let mut prepped = conn.prepare_cached(
-                    r#"
-                    SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl
-                        FROM crsql_changes
-                        WHERE db_version = ?
-                        ORDER BY seq ASC
-                "#,
-                )?;
-                let rows = prepped.query_map([db_version], row_to_change)?;
-                let chunked =
-                    ChunkedChanges::new(rows, CrsqlSeq(0), last_seq, MAX_CHANGES_BYTE_SIZE);
-                for changes_seqs in chunked {
-                    match changes_seqs {
-                        Ok((changes, seqs)) => {
-                            for (table_name, count) in
-                                changes.iter().counts_by(|change| &change.table)
-                            {
-                                counter!("corro.changes.committed", "table" => table_name.to_string(), "source" => "local").increment(count as u64);
+                        let chunks = match agent.config().perf.bcast_max_value_bytes {
+                            Some(max_value_bytes) => {
+                                let count = changes.len();
+                                let chunks = without_large_values(changes, seqs, max_value_bytes);
+                                let withheld =
+                                    count - chunks.iter().map(|(c, _)| c.len()).sum::<usize>();
+                                if withheld > 0 {
+                                    counter!("corro.broadcast.withheld.changes")
+                                        .increment(withheld as u64);
+                                }
+                                chunks
                             }
+                            None => vec![(changes, seqs)],
+                        };
 
-                            trace!("broadcasting changes: {changes:?} for seq: {seqs:?}");
-
-                            agent.subs_manager().match_changes(&changes, db_version);
-
+                        for (changes, seqs) in chunks {
                             let tx_bcast = agent.tx_bcast().clone();
                             tokio::spawn(async move {
                                 if let Err(e) = tx_bcast
@@ -225,111 +357,381 @@ where
                                 }
                             });
                         }
-                        Err(e) => {
-                            error!("could not process crsql change (db_version: {db_version}) for broadcast: {e}");
-                            break;
-                        }
+                    }
+                    Err(e) => {
+                        error!("could not process crsql change (db_version: {db_version}) for broadcast: {e}");
+                        break;
                     }
                 }
-                Ok::<_, rusqlite::Error>(())
-            })?;
+            }
 
-            Ok::<_, eyre::Report>(())
-        });
+            Ok::<_, rusqlite::Error>(())
+        })?;
 
-        Ok::<_, ChangeError>((ret, elapsed))
-    })
+        Ok::<_, eyre::Report>(())
+    });
+
+    Ok(elapsed)
+}
+
+/// Outcome of a write statement
+enum Executed {
+    RowsAffected(usize),
+    /// Rows returned by a statement with a `RETURNING` clause
+    Returning {
+        columns: Vec<ColumnName>,
+        rows: Vec<Vec<SqliteValue>>,
+    },
 }
 
 #[tracing::instrument(skip_all, err)]
-fn execute_statement(tx: &Transaction, stmt: &Statement) -> rusqlite::Result<usize> {
-    let mut prepped = tx.prepare(stmt.query())?;
-
-    match stmt {
-        Statement::Simple(_)
-        | Statement::Verbose {
-            params: None,
-            named_params: None,
-            ..
-        } => prepped.execute([]),
-        Statement::WithParams(_, params)
-        | Statement::Verbose {
-            params: Some(params),
-            ..
-        } => prepped.execute(params_from_iter(params)),
-        Statement::WithNamedParams(_, params)
-        | Statement::Verbose {
-            named_params: Some(params),
-            ..
-        } => prepped.execute(
-            params
-                .iter()
-                .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
-                .collect::<Vec<(&str, &dyn ToSql)>>()
-                .as_slice(),
-        ),
+fn execute_statement(tx: &Transaction, stmt: &Statement) -> rusqlite::Result<Executed> {
+    retry_busy("api", || {
+        let mut prepped = tx.prepare(stmt.query())?;
+        execute_prepared(&mut prepped, stmt)
+    })
+}
+
+/// Runs a write statement, `prepped` from its query
+fn execute_prepared(
+    prepped: &mut rusqlite::Statement<'_>,
+    stmt: &Statement,
+) -> rusqlite::Result<Executed> {
+    stmt.bind_parameters(prepped)?;
+
+    // reads are still refused, they go through queries
+    if prepped.column_count() == 0 || prepped.readonly() {
+        return prepped.raw_execute().map(Executed::RowsAffected);
+    }
+
+    let columns: Vec<ColumnName> = prepped
+        .columns()
+        .into_iter()
+        .map(|col| ColumnName(col.name().to_compact_string()))
+        .collect();
+
+    // changes are all made by the first step, before any row is returned
+    let mut rows = prepped.raw_query();
+    let mut values = vec![];
+    while let Some(row) = rows.next()? {
+        values.push(
+            (0..columns.len())
+                .map(|i| row.get::<_, SqliteValue>(i))
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+        );
+    }
+
+    Ok(Executed::Returning {
+        columns,
+        rows: values,
+    })
+}
+
+/// Runs a read-only statement, collecting all of its rows
+fn query_statement(conn: &Connection, stmt: &Statement) -> rusqlite::Result<ExecResult> {
+    let start = Instant::now();
+    let mut prepped = conn.prepare(stmt.query())?;
+
+    let columns: Vec<ColumnName> = prepped
+        .columns()
+        .into_iter()
+        .map(|col| ColumnName(col.name().to_compact_string()))
+        .collect();
+
+    stmt.bind_parameters(&mut prepped)?;
+    let mut rows = prepped.raw_query();
+
+    let mut values = vec![];
+    while let Some(row) = rows.next()? {
+        values.push(
+            (0..columns.len())
+                .map(|i| row.get::<_, SqliteValue>(i))
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+        );
     }
+
+    Ok(ExecResult::Query {
+        columns,
+        rows: values,
+        time: start.elapsed().as_secs_f64(),
+    })
 }
 
 #[tracing::instrument(skip_all)]
 pub async fn api_v1_transactions(
-    // axum::extract::RawQuery(raw_query): axum::extract::RawQuery,
     Extension(agent): Extension<Agent>,
-    axum::extract::Json(statements): axum::extract::Json<Vec<Statement>>,
+    headers: HeaderMap,
+    axum::extract::Query(mut params): axum::extract::Query<EnvelopeParams>,
+    axum::extract::Json(req): axum::extract::Json<TransactionRequest>,
+) -> (StatusCode, Enveloped<ExecResponse>) {
+    params.encoding = Encoding::from_headers(&headers);
+    let (statements, preconditions) = req.into_parts();
+    let (status_code, axum::Json(res)) = transact(&agent, params, statements, preconditions).await;
+    (status_code, Enveloped(res, params))
+}
+
+/// Checks the preconditions of a transaction from within it, before its
+/// statements run
+fn check_preconditions(
+    agent: &Agent,
+    tx: &Transaction,
+    preconditions: &[Precondition],
+) -> Result<(), ChangeError> {
+    for (index, precondition) in preconditions.iter().enumerate() {
+        let invalid = |reason: String| ChangeError::InvalidPrecondition { index, reason };
+
+        match precondition {
+            Precondition::Rows { statement, rows } => {
+                let mut prepped = tx
+                    .prepare(statement.query())
+                    .map_err(|e| invalid(e.to_string()))?;
+                if !prepped.readonly() {
+                    return Err(invalid("statement is not read-only".into()));
+                }
+                statement
+                    .bind_parameters(&mut prepped)
+                    .map_err(|e| invalid(e.to_string()))?;
+
+                let column_count = prepped.column_count();
+                let got = prepped
+                    .raw_query()
+                    .mapped(|row| {
+                        (0..column_count)
+                            .map(|i| row.get::<_, SqliteValue>(i))
+                            .collect::<rusqlite::Result<Vec<_>>>()
+                    })
+                    .collect::<rusqlite::Result<Vec<_>>>()
+                    .map_err(|e| invalid(e.to_string()))?;
+
+                if got != *rows {
+                    return Err(ChangeError::PreconditionFailed {
+                        index,
+                        reason: format!("expected rows {rows:?}, got {got:?}"),
+                    });
+                }
+            }
+            Precondition::ColVersion {
+                table,
+                pk,
+                column,
+                col_version,
+            } => {
+                let pk_cols = match agent.schema().read().tables.get(table) {
+                    Some(tbl) if tbl.columns.contains_key(column) => {
+                        tbl.pk.iter().cloned().collect::<Vec<_>>()
+                    }
+                    Some(_) => {
+                        return Err(invalid(format!(
+                            "column '{column}' of table '{table}' does not exist"
+                        )))
+                    }
+                    None => return Err(invalid(format!("table '{table}' does not exist"))),
+                };
+                if pk.len() != pk_cols.len() {
+                    return Err(invalid(format!(
+                        "table '{table}' has {} primary key columns, got {}",
+                        pk_cols.len(),
+                        pk.len()
+                    )));
+                }
+
+                let filter = pk_cols
+                    .iter()
+                    .map(|col| format!("p.\"{col}\" IS ?"))
+                    .collect::<Vec<_>>()
+                    .join(" AND ");
+                let mut params = vec![column as &dyn ToSql];
+                params.extend(pk.iter().map(|value| value as &dyn ToSql));
+
+                // deleted rows have no clock left but their sentinel's
+                let version = tx
+                    .query_row(
+                        &format!(
+                            "SELECT c.col_version FROM \"{table}__crsql_pks\" AS p
+                                INNER JOIN \"{table}__crsql_clock\" AS c ON c.key = p.__crsql_key AND c.col_name = ?
+                                WHERE {filter}"
+                        ),
+                        params_from_iter(params),
+                        |row| row.get::<_, i64>(0),
+                    )
+                    .optional()
+                    .map_err(|source| ChangeError::Rusqlite {
+                        source,
+                        actor_id: Some(agent.actor_id()),
+                        version: None,
+                    })?
+                    .unwrap_or(0);
+
+                if version != *col_version {
+                    return Err(ChangeError::PreconditionFailed {
+                        index,
+                        reason: format!(
+                            "column '{column}' of row {pk:?} in '{table}' is at version {version}, expected {col_version}"
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn transact(
+    agent: &Agent,
+    params: EnvelopeParams,
+    statements: Vec<Statement>,
+    preconditions: Vec<Precondition>,
 ) -> (StatusCode, axum::Json<ExecResponse>) {
+    record_statements(statements.len());
     if statements.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            axum::Json(ExecResponse {
-                results: vec![ExecResult::Error {
-                    error: "at least 1 statement is required".into(),
-                }],
-                time: 0.0,
-            }),
-        );
+        return error_response(ErrorCode::BadRequest, "at least 1 statement is required");
     }
 
-    let res = make_broadcastable_changes(&agent, move |tx| {
-        let mut total_rows_affected = 0;
+    let read_statements = agent.config().api.read_statements;
 
-        let results = statements
-            .iter()
-            .map(|stmt| {
-                let start = Instant::now();
-                let res = execute_statement(tx, stmt);
-
-                match res {
-                    Ok(rows_affected) => {
-                        total_rows_affected += rows_affected;
-                        ExecResult::Execute {
-                            rows_affected,
-                            time: start.elapsed().as_secs_f64(),
+    let mut read_conn = None;
+    let readonly: Vec<bool> = if read_statements == ReadStatements::Write {
+        vec![false; statements.len()]
+    } else {
+        let conn = match agent.pool().read().await {
+            Ok(conn) => conn,
+            Err(e) => return QueryError::Pool(e).into_exec_response(),
+        };
+        // statements which don't prepare are left to the write connection,
+        // which returns the error
+        let readonly = block_in_place(|| {
+            statements
+                .iter()
+                .map(|stmt| {
+                    agent
+                        .statement_cache()
+                        .get_or_describe(&conn, stmt.query())
+                        .map_or(false, |info| info.readonly)
+                })
+                .collect()
+        });
+        read_conn = Some(conn);
+        readonly
+    };
+
+    if read_statements == ReadStatements::Reject {
+        if let Some(i) = readonly.iter().position(|readonly| *readonly) {
+            return error_response(
+                ErrorCode::BadRequest,
+                format!("statement #{i} is read-only, send it to /v1/queries instead"),
+            );
+        }
+    }
+
+    // only reads, they don't need the write connection
+    if preconditions.is_empty() && readonly.iter().all(|readonly| *readonly) {
+        if let Some(conn) = read_conn {
+            let start = Instant::now();
+            let results = block_in_place(|| {
+                statements
+                    .iter()
+                    .map(|stmt| {
+                        query_statement(&conn, stmt)
+                            .unwrap_or_else(|e| ExecResult::error(ErrorCode::StatementFailed, e))
+                    })
+                    .collect()
+            });
+            return (
+                StatusCode::OK,
+                axum::Json(ExecResponse {
+                    results,
+                    time: start.elapsed().as_secs_f64(),
+                }),
+            );
+        }
+    }
+    drop(read_conn);
+
+    // validators only get to see the writes
+    let writes: Vec<Statement> = statements
+        .iter()
+        .zip(readonly.iter())
+        .filter_map(|(stmt, readonly)| (!readonly).then(|| stmt.clone()))
+        .collect();
+
+    // statements run in order within the transaction, reads see the writes
+    // preceding them
+    let res = commit_broadcastable_changes(
+        agent,
+        &writes,
+        |tx| {
+            check_preconditions(agent, tx, &preconditions)?;
+
+            let mut total_rows_affected = 0;
+
+            let results = statements
+                .iter()
+                .zip(readonly.iter())
+                .enumerate()
+                .map(|(index, (stmt, readonly))| {
+                    let start = Instant::now();
+                    if *readonly {
+                        return query_statement(tx, stmt).or_else(|source| {
+                            if params.transaction {
+                                Err(ChangeError::StatementFailed { index, source })
+                            } else {
+                                Ok(ExecResult::error(ErrorCode::StatementFailed, source))
+                            }
+                        });
+                    }
+
+                    let res = execute_statement(tx, stmt);
+
+                    let last_insert_id =
+                        (params.envelope == Envelope::Rqlite).then(|| tx.last_insert_rowid());
+                    match res {
+                        Ok(Executed::RowsAffected(rows_affected)) => {
+                            total_rows_affected += rows_affected;
+                            Ok(ExecResult::Execute {
+                                rows_affected,
+                                last_insert_id,
+                                time: start.elapsed().as_secs_f64(),
+                            })
+                        }
+                        // a row is returned for each row written
+                        Ok(Executed::Returning { columns, rows }) => {
+                            total_rows_affected += rows.len();
+                            Ok(ExecResult::Returning {
+                                rows_affected: rows.len(),
+                                last_insert_id,
+                                columns,
+                                rows,
+                                time: start.elapsed().as_secs_f64(),
+                            })
+                        }
+                        // returning an error rolls back the whole transaction
+                        Err(source) if params.transaction => {
+                            Err(ChangeError::StatementFailed { index, source })
                         }
+                        Err(e) => Ok(ExecResult::error(ErrorCode::StatementFailed, e)),
                     }
-                    Err(e) => ExecResult::Error {
-                        error: e.to_string(),
-                    },
-                }
-            })
-            .collect::<Vec<ExecResult>>();
+                })
+                .collect::<Result<Vec<ExecResult>, ChangeError>>()?;
 
-        Ok(results)
-    })
+            Ok(results)
+        },
+        Broadcast::Changes,
+    )
     .await;
 
     let (results, elapsed) = match res {
         Ok(res) => res,
         Err(e) => {
-            error!("could not execute statement(s): {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(ExecResponse {
-                    results: vec![ExecResult::Error {
-                        error: e.to_string(),
-                    }],
-                    time: 0.0,
-                }),
-            );
+            match e {
+                ChangeError::Vetoed(_) => info!("transaction rejected: {e}"),
+                ChangeError::StatementFailed { .. }
+                | ChangeError::PreconditionFailed { .. }
+                | ChangeError::InvalidPrecondition { .. } => debug!("{e}"),
+                ChangeError::Fenced(_) => info!("transaction fenced: {e}"),
+                _ => error!("could not execute statement(s): {e}"),
+            }
+            return e.into_exec_response();
         }
     };
 
@@ -342,271 +744,763 @@ pub async fn api_v1_transactions(
     )
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum QueryError {
-    #[error("pool connection acquisition error")]
-    Pool(#[from] SqlitePoolError),
-    #[error("sqlite error: {0}")]
-    Rusqlite(#[from] rusqlite::Error),
+/// SQL condition selecting the rows a truncation deletes, given the quoted
+/// name of the table's first primary key column, and its params
+fn truncation_filter<'a>(pk: &str, req: &'a TruncateRequest) -> (String, Vec<&'a SqliteValue>) {
+    let mut conds = vec![];
+    let mut params = vec![];
+    if let Some(start) = req.start.as_ref() {
+        conds.push(format!("{pk} >= ?"));
+        params.push(start);
+    }
+    if let Some(end) = req.end.as_ref() {
+        conds.push(format!("{pk} < ?"));
+        params.push(end);
+    }
+    if conds.is_empty() {
+        return ("1".into(), params);
+    }
+    (conds.join(" AND "), params)
+}
+
+/// Deletes every row of a table, or the ones in a range of its first primary
+/// key column. The deletes replicate like any other: a compact marker
+/// applied by each node to the rows it has wouldn't converge with the
+/// writes it raced with.
+pub async fn api_v1_truncations(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(req): axum::extract::Json<TruncateRequest>,
+) -> (StatusCode, axum::Json<ExecResponse>) {
+    let pk = match agent.schema().read().tables.get(&req.table) {
+        Some(table) => table.pk.first().cloned(),
+        None => None,
+    };
+    let Some(pk) = pk else {
+        return error_response(
+            ErrorCode::BadRequest,
+            format!("table '{}' does not exist", req.table),
+        );
+    };
+
+    let res = commit_broadcastable_changes(
+        &agent,
+        &[],
+        |tx| {
+            let start = Instant::now();
+            let (filter, params) = truncation_filter(&format!("\"{pk}\""), &req);
+            let rows_affected = tx
+                .execute(
+                    &format!("DELETE FROM \"{}\" WHERE {filter}", req.table),
+                    params_from_iter(params),
+                )
+                .map_err(|source| ChangeError::Rusqlite {
+                    source,
+                    actor_id: Some(agent.actor_id()),
+                    version: None,
+                })?;
+            Ok(ExecResult::Execute {
+                rows_affected,
+                last_insert_id: None,
+                time: start.elapsed().as_secs_f64(),
+            })
+        },
+        Broadcast::Changes,
+    )
+    .await;
+
+    match res {
+        Ok((result, elapsed)) => {
+            counter!("corro.truncations", "table" => req.table).increment(1);
+            (
+                StatusCode::OK,
+                axum::Json(ExecResponse {
+                    results: vec![result],
+                    time: elapsed.as_secs_f64(),
+                }),
+            )
+        }
+        Err(e) => {
+            match e {
+                ChangeError::Vetoed(_) => info!("truncation rejected: {e}"),
+                ChangeError::Fenced(_) => info!("truncation fenced: {e}"),
+                _ => error!("could not truncate table: {e}"),
+            }
+            e.into_exec_response()
+        }
+    }
+}
+
+/// Response to errors which aren't [`ApiError`]s, like invalid requests
+fn error_response(code: ErrorCode, error: impl ToString) -> (StatusCode, axum::Json<ExecResponse>) {
+    (
+        status_code(code),
+        axum::Json(ExecResponse {
+            results: vec![ExecResult::error(code, error)],
+            time: 0.0,
+        }),
+    )
+}
+
+/// Response to errors of `/v1/queries`, before rows are streamed
+fn query_error_response(e: QueryError) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(e.status())
+        .body(
+            serde_json::to_vec(&e.exec_result())
+                .expect("could not serialize query error response")
+                .into(),
+        )
+        .expect("could not build query response body")
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn build_query_rows_response(
     agent: &Agent,
     data_tx: mpsc::Sender<QueryEvent>,
     stmt: Statement,
-) -> Result<(), (StatusCode, ExecResult)> {
+    snapshot: Option<Arc<Snapshot>>,
+    meta_tables: Option<Vec<Table>>,
+    page: Option<Page>,
+    progress: bool,
+    (queries, id, timeout): (RunningQueries, Uuid, Option<Duration>),
+) -> Result<(), QueryError> {
     let (res_tx, res_rx) = oneshot::channel();
 
-    let pool = agent.pool().clone();
+    let agent = agent.clone();
 
-    tokio::spawn(async move {
-        let conn = match pool.read().await {
+    spawn_named("query_statement", Shutdown::Abortable, async move {
+        let meta = meta_tables.as_deref().map(|tables| (&agent, tables));
+
+        if let Some(snapshot) = snapshot {
+            let conn = snapshot.conn().await;
+            let _running = queries.register(id, &conn, timeout);
+            query_rows(&conn, stmt, meta, page, progress, data_tx, res_tx);
+            return;
+        }
+
+        let conn = match agent.pool().read().await {
             Ok(conn) => conn,
             Err(e) => {
-                _ = res_tx.send(Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    ExecResult::Error {
-                        error: e.to_string(),
-                    },
-                )));
+                _ = res_tx.send(Err(QueryError::Pool(e)));
                 return;
             }
         };
+        // unregistered before the connection goes back to the pool
+        let _running = queries.register(id, &conn, timeout);
 
-        let prepped_res = block_in_place(|| conn.prepare(stmt.query()));
+        query_rows(&conn, stmt, meta, page, progress, data_tx, res_tx);
+    });
 
-        let mut prepped = match prepped_res {
-            Ok(prepped) => prepped,
-            Err(e) => {
-                _ = res_tx.send(Err((
-                    StatusCode::BAD_REQUEST,
-                    ExecResult::Error {
-                        error: e.to_string(),
-                    },
-                )));
-                return;
-            }
-        };
+    res_rx.await.unwrap_or(Err(QueryError::Aborted))
+}
 
-        if !prepped.readonly() {
-            _ = res_tx.send(Err((
-                StatusCode::BAD_REQUEST,
-                ExecResult::Error {
-                    error: "statement is not readonly".into(),
-                },
-            )));
-            return;
-        }
+/// Rewrites the statement to also select the primary keys of the tables it
+/// reads from, returning these tables in the order their keys were added.
+fn with_meta_columns(
+    agent: &Agent,
+    mut stmt: Statement,
+) -> Result<(Statement, Vec<Table>), RowMetaError> {
+    let schema = agent.schema().read();
+    let (sql, pk_cols) = with_pk_columns(stmt.query(), &schema)?;
+    *stmt.query_mut() = sql;
+
+    let tables = pk_cols
+        .tables
+        .iter()
+        .filter_map(|name| schema.tables.get(name).cloned())
+        .collect();
+
+    Ok((stmt, tables))
+}
 
-        block_in_place(|| {
-            let col_count = prepped.column_count();
-            trace!("inside block in place, col count: {col_count}");
-
-            if let Err(e) = data_tx.blocking_send(QueryEvent::Columns(
-                prepped
-                    .columns()
-                    .into_iter()
-                    .map(|col| ColumnName(col.name().to_compact_string()))
-                    .collect(),
-            )) {
-                error!("could not send back columns: {e}");
-                return;
-            }
+/// Rewrites the statement to fetch the page after `cursor`
+fn with_page(
+    agent: &Agent,
+    mut stmt: Statement,
+    cursor: Option<&str>,
+    size: usize,
+) -> Result<(Statement, Page), PaginationError> {
+    let after = cursor.map(decode_cursor).transpose()?;
+    let mut page = paginate(stmt.query(), &agent.schema().read(), after.as_deref(), size)?;
+    *stmt.query_mut() = std::mem::take(&mut page.sql);
+
+    Ok((stmt, page))
+}
 
-            let start = Instant::now();
+/// Rewrites the statement to return its rows in a deterministic order
+fn with_deterministic_order(
+    agent: &Agent,
+    mut stmt: Statement,
+) -> Result<Statement, PaginationError> {
+    let sql = order_deterministically(stmt.query(), &agent.schema().read())?;
+    *stmt.query_mut() = sql;
 
-            let query = match stmt {
-                Statement::Simple(_)
-                | Statement::Verbose {
-                    params: None,
-                    named_params: None,
-                    ..
-                } => prepped.query(()),
-                Statement::WithParams(_, params)
-                | Statement::Verbose {
-                    params: Some(params),
-                    ..
-                } => prepped.query(params_from_iter(params)),
-                Statement::WithNamedParams(_, params)
-                | Statement::Verbose {
-                    named_params: Some(params),
-                    ..
-                } => prepped.query(
-                    params
-                        .iter()
-                        .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
-                        .collect::<Vec<(&str, &dyn ToSql)>>()
-                        .as_slice(),
-                ),
-            };
-
-            let mut rows = match query {
-                Ok(rows) => rows,
-                Err(e) => {
-                    _ = res_tx.send(Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        ExecResult::Error {
-                            error: e.to_string(),
-                        },
-                    )));
-                    return;
-                }
-            };
-            let elapsed = start.elapsed();
+    Ok(stmt)
+}
 
-            if let Err(_e) = res_tx.send(Ok(())) {
-                error!("could not send back response through oneshot channel, aborting");
-                return;
+/// Interval between two `progress` events of a query
+const QUERY_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rough number of rows returned by a query: the number of rows of the
+/// largest table it scans, from `sqlite_stat1` if the table was analyzed or
+/// from its highest rowid. Queries that only search through indexes are not
+/// estimated, they shouldn't take long anyway.
+/// Maps the aliases of the tables `sql` reads from back to the tables, query
+/// plans name an aliased table by its alias.
+fn table_aliases(sql: &str) -> HashMap<String, String> {
+    let mut aliases = HashMap::new();
+    let Ok(Some(Cmd::Stmt(Stmt::Select(select)))) = Parser::new(sql.as_bytes()).next() else {
+        return aliases;
+    };
+
+    let selects = std::iter::once(&select.body.select).chain(
+        select
+            .body
+            .compounds
+            .iter()
+            .flatten()
+            .map(|compound| &compound.select),
+    );
+    for one in selects {
+        let OneSelect::Select {
+            from: Some(from), ..
+        } = one
+        else {
+            continue;
+        };
+        let tables = from
+            .select
+            .as_deref()
+            .into_iter()
+            .chain(from.joins.iter().flatten().map(|join| &join.table));
+        for table in tables {
+            if let SelectTable::Table(name, Some(As::As(alias) | As::Elided(alias)), _) = table {
+                aliases.insert(alias.0.clone(), name.name.0.clone());
             }
+        }
+    }
 
-            let mut rowid = 1;
+    aliases
+}
 
-            trace!("about to loop through rows!");
+fn estimate_rows(conn: &Connection, sql: &str) -> Option<u64> {
+    let mut plan = conn.prepare(&format!("EXPLAIN QUERY PLAN {sql}")).ok()?;
+    // parameters are left unbound, they don't change the plan's shape
+    let mut rows = plan.raw_query();
+    let aliases = table_aliases(sql);
 
-            loop {
-                match rows.next() {
-                    Ok(Some(row)) => {
-                        trace!("got a row: {row:?}");
-                        match (0..col_count)
-                            .map(|i| row.get::<_, SqliteValue>(i))
-                            .collect::<rusqlite::Result<Vec<_>>>()
-                        {
-                            Ok(cells) => {
+    let mut scanned = vec![];
+    while let Ok(Some(row)) = rows.next() {
+        let Ok(detail) = row.get::<_, String>(3) else {
+            continue;
+        };
+        let Some(rest) = detail.strip_prefix("SCAN ") else {
+            continue;
+        };
+        let rest = rest.strip_prefix("TABLE ").unwrap_or(rest);
+        if let Some(tbl) = rest.split_whitespace().next() {
+            let tbl = aliases.get(tbl).map(String::as_str).unwrap_or(tbl);
+            scanned.push(tbl.replace('"', "\"\""));
+        }
+    }
+
+    scanned
+        .iter()
+        .filter_map(|tbl| {
+            let analyzed = conn
+                .query_row(
+                    "SELECT stat FROM sqlite_stat1 WHERE tbl = ? LIMIT 1",
+                    [tbl],
+                    |row| row.get::<_, String>(0),
+                )
+                .ok()
+                .and_then(|stat| stat.split_whitespace().next()?.parse().ok());
+
+            // tables without rowid still have one for their cr-sqlite keys
+            analyzed.or_else(|| {
+                [
+                    format!("SELECT max(rowid) FROM \"{tbl}\""),
+                    format!("SELECT max(__crsql_key) FROM \"{tbl}__crsql_pks\""),
+                ]
+                .iter()
+                .find_map(|sql| {
+                    conn.query_row(sql, [], |row| row.get::<_, Option<u64>>(0))
+                        .ok()
+                        .flatten()
+                })
+            })
+        })
+        .max()
+}
+
+fn query_rows(
+    conn: &Connection,
+    stmt: Statement,
+    meta: Option<(&Agent, &[Table])>,
+    page: Option<Page>,
+    progress: bool,
+    data_tx: mpsc::Sender<QueryEvent>,
+    res_tx: oneshot::Sender<Result<(), QueryError>>,
+) {
+    let prepped_res = block_in_place(|| conn.prepare(stmt.query()));
+
+    let mut prepped = match prepped_res {
+        Ok(prepped) => prepped,
+        Err(e) => {
+            _ = res_tx.send(Err(QueryError::InvalidStatement(e)));
+            return;
+        }
+    };
+
+    if !prepped.readonly() {
+        _ = res_tx.send(Err(QueryError::NotReadonly));
+        return;
+    }
+
+    block_in_place(|| {
+        // primary keys selected for the metadata come after the user's
+        // columns, followed by the ones selected for the page's cursor
+        let meta_pk_count: usize = meta
+            .map(|(_, tables)| tables.iter().map(|table| table.pk.len()).sum())
+            .unwrap_or(0);
+        let page_pk_count = page.as_ref().map_or(0, |page| page.pk_len);
+        let pk_count = meta_pk_count + page_pk_count;
+        let col_count = prepped.column_count() - pk_count;
+        trace!("inside block in place, col count: {col_count}");
+
+        if let Err(e) = data_tx.blocking_send(QueryEvent::Columns(
+            prepped
+                .columns()
+                .into_iter()
+                .take(col_count)
+                .map(|col| ColumnName(col.name().to_compact_string()))
+                .collect(),
+        )) {
+            error!("could not send back columns: {e}");
+            return;
+        }
+
+        if progress {
+            if let Some(rows) = estimate_rows(conn, stmt.query()) {
+                if let Err(e) = data_tx.blocking_send(QueryEvent::Estimate { rows }) {
+                    error!("could not send back rows estimate: {e}");
+                    return;
+                }
+            }
+        }
+
+        let start = Instant::now();
+
+        // parameters that don't match the statement are the client's doing
+        if let Err(e) = stmt.bind_parameters(&mut prepped) {
+            _ = res_tx.send(Err(QueryError::InvalidStatement(e)));
+            return;
+        }
+        let mut rows = prepped.raw_query();
+        let elapsed = start.elapsed();
+
+        if let Err(_e) = res_tx.send(Ok(())) {
+            error!("could not send back response through oneshot channel, aborting");
+            return;
+        }
+
+        let mut rowid = 1;
+        let mut last_progress = Instant::now();
+        let mut last_pks = vec![];
+
+        trace!("about to loop through rows!");
+
+        loop {
+            match rows.next() {
+                Ok(Some(row)) => {
+                    trace!("got a row: {row:?}");
+                    match (0..col_count + pk_count)
+                        .map(|i| row.get::<_, SqliteValue>(i))
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                    {
+                        Ok(mut cells) => {
+                            let mut pks = cells.split_off(col_count);
+                            last_pks = pks.split_off(meta_pk_count);
+                            if let Err(e) =
+                                data_tx.blocking_send(QueryEvent::Row(rowid.into(), cells))
+                            {
+                                error!("could not send back row: {e}");
+                                return;
+                            }
+                            if let Some((agent, tables)) = meta {
+                                let mut metas = vec![];
+                                let mut pks = pks.as_slice();
+                                for table in tables {
+                                    let (table_pks, rest) = pks.split_at(table.pk.len());
+                                    pks = rest;
+                                    match row_meta(conn, agent.bookkeeping(), table, table_pks) {
+                                        Ok(cells_meta) => metas.extend(cells_meta),
+                                        Err(e) => {
+                                            _ = data_tx.blocking_send(QueryEvent::Error(
+                                                e.to_compact_string(),
+                                            ));
+                                            return;
+                                        }
+                                    }
+                                }
                                 if let Err(e) =
-                                    data_tx.blocking_send(QueryEvent::Row(rowid.into(), cells))
+                                    data_tx.blocking_send(QueryEvent::Meta(rowid.into(), metas))
                                 {
-                                    error!("could not send back row: {e}");
+                                    error!("could not send back row metadata: {e}");
                                     return;
                                 }
-                                rowid += 1;
                             }
-                            Err(e) => {
-                                _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
-                                return;
+                            rowid += 1;
+
+                            if progress && last_progress.elapsed() >= QUERY_PROGRESS_INTERVAL {
+                                last_progress = Instant::now();
+                                if let Err(e) = data_tx.blocking_send(QueryEvent::Progress {
+                                    rows: rowid - 1,
+                                    time: start.elapsed().as_secs_f64(),
+                                }) {
+                                    error!("could not send back query progress: {e}");
+                                    return;
+                                }
                             }
                         }
+                        Err(e) => {
+                            _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
+                            return;
+                        }
                     }
-                    Ok(None) => {
-                        // done!
-                        break;
-                    }
-                    Err(e) => {
-                        _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
-                        return;
-                    }
+                }
+                Ok(None) => {
+                    // done!
+                    break;
+                }
+                Err(e)
+                    if e.sqlite_error_code() == Some(rusqlite::ErrorCode::OperationInterrupted) =>
+                {
+                    _ = data_tx.blocking_send(QueryEvent::Error(
+                        "query interrupted, it was cancelled or timed out".into(),
+                    ));
+                    return;
+                }
+                Err(e) => {
+                    _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
+                    return;
                 }
             }
+        }
 
-            _ = data_tx.blocking_send(QueryEvent::EndOfQuery {
-                time: elapsed.as_secs_f64(),
-                change_id: None,
-            });
+        // a partial page is the last one
+        let cursor = page
+            .filter(|page| rowid - 1 == page.size as u64)
+            .map(|_| encode_cursor(&last_pks));
+
+        _ = data_tx.blocking_send(QueryEvent::EndOfQuery {
+            time: elapsed.as_secs_f64(),
+            change_id: None,
+            cursor,
         });
     });
+}
 
-    match res_rx.await {
-        Ok(res) => res,
-        Err(e) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            ExecResult::Error {
-                error: e.to_string(),
-            },
-        )),
+/// Rows per page when only a cursor is given
+const DEFAULT_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct QueryParams {
+    /// Run the query against a snapshot opened via `/v1/snapshots`
+    #[serde(default)]
+    snapshot: Option<Uuid>,
+    /// Follow each row with the replication metadata of its cells
+    #[serde(default)]
+    meta: bool,
+    /// Send an estimate of the number of rows before them, and the number
+    /// of rows sent so far every second
+    #[serde(default)]
+    progress: bool,
+    #[serde(default)]
+    envelope: Envelope,
+    // rqlite's options, not flattened: query strings only deserialize into
+    // flattened structs as strings, failing every other field
+    #[serde(default, deserialize_with = "flag")]
+    pretty: bool,
+    #[serde(default, deserialize_with = "flag")]
+    timings: bool,
+    #[serde(default, deserialize_with = "flag")]
+    associative: bool,
+    /// Paginate the results by primary key, with this many rows per page
+    #[serde(default, alias = "limit")]
+    page_size: Option<usize>,
+    /// Cursor returned at the end of the previous page
+    #[serde(default)]
+    cursor: Option<String>,
+    /// Order rows the same way on every node when the query doesn't
+    #[serde(default)]
+    ordered: bool,
+    /// Interrupt the query if it's still running after this many
+    /// milliseconds
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+impl QueryParams {
+    fn rqlite(&self) -> RqliteOptions {
+        RqliteOptions {
+            pretty: self.pretty,
+            timings: self.timings,
+            associative: self.associative,
+        }
     }
 }
 
 pub async fn api_v1_queries(
     Extension(agent): Extension<Agent>,
+    Extension(snapshots): Extension<SharedSnapshots>,
+    Extension(queries): Extension<RunningQueries>,
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<QueryParams>,
     axum::extract::Json(stmt): axum::extract::Json<Statement>,
 ) -> impl IntoResponse {
-    let (mut tx, body) = hyper::Body::channel();
+    record_statements(1);
+    let encoding = Encoding::from_headers(&headers);
+    let snapshot = match params.snapshot {
+        Some(id) => match snapshots.get(&id) {
+            Some(snapshot) => Some(snapshot),
+            None => return query_error_response(QueryError::SnapshotNotFound(id)),
+        },
+        None => None,
+    };
+
+    let (stmt, meta_tables) = if params.meta {
+        match with_meta_columns(&agent, stmt) {
+            Ok((stmt, tables)) => (stmt, Some(tables)),
+            Err(e) => return query_error_response(e.into()),
+        }
+    } else {
+        (stmt, None)
+    };
+
+    let (stmt, page) = if params.page_size.is_some() || params.cursor.is_some() {
+        let size = params.page_size.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+        match with_page(&agent, stmt, params.cursor.as_deref(), size) {
+            Ok((stmt, page)) => (stmt, Some(page)),
+            Err(e) => return query_error_response(e.into()),
+        }
+    } else {
+        (stmt, None)
+    };
+
+    // pages are already ordered by primary key
+    let stmt = if params.ordered && page.is_none() {
+        match with_deterministic_order(&agent, stmt) {
+            Ok(stmt) => stmt,
+            Err(e) => return query_error_response(e.into()),
+        }
+    } else {
+        stmt
+    };
 
     // TODO: timeout on data send instead of infinitely waiting for channel space.
     let (data_tx, mut data_rx) = channel(512);
 
-    tokio::spawn(async move {
+    // returned so the query can be cancelled while it runs
+    let id = Uuid::new_v4();
+    let timeout = params.timeout_ms.map(Duration::from_millis);
+
+    if params.envelope == Envelope::Rqlite {
+        // buffered, rqlite returns all rows in a single object
+        let (status, (res, cursor)) = match build_query_rows_response(
+            &agent,
+            data_tx,
+            stmt,
+            snapshot,
+            meta_tables,
+            page,
+            params.progress,
+            (queries, id, timeout),
+        )
+        .await
+        {
+            Ok(_) => (StatusCode::OK, rqlite_query_response(data_rx).await),
+            Err(e) => (e.status(), (RqliteQueryResult::error(e), None)),
+        };
+        let mut builder = hyper::Response::builder()
+            .status(status)
+            .header(QUERY_ID_HEADER, id.to_string());
+        // rows are returned as a single object, without a trailing event
+        if let Some(cursor) = cursor {
+            builder = builder.header("corro-next-cursor", cursor);
+        }
+        return builder
+            .body(rqlite_json(&res, params.rqlite()).into())
+            .expect("could not build query response body");
+    }
+
+    let (tx, body) = hyper::Body::channel();
+    let (mut tx, content_encoding) = BodySender::new(tx, ContentEncoding::from_headers(&headers));
+    let stats = RequestStats::current();
+
+    let body_queries = queries.clone();
+    spawn_named("query_response_body", Shutdown::Abortable, async move {
         let mut buf = BytesMut::new();
 
         while let Some(row_res) = data_rx.recv().await {
-            {
-                let mut writer = (&mut buf).writer();
-                if let Err(e) = serde_json::to_writer(&mut writer, &row_res) {
-                    _ = tx
-                        .send_data(
-                            serde_json::to_vec(&serde_json::json!(QueryEvent::Error(
-                                e.to_compact_string()
-                            )))
-                            .expect("could not serialize error json")
-                            .into(),
-                        )
-                        .await;
-                    return;
-                }
+            if let (QueryEvent::Row(..), Some(stats)) = (&row_res, stats.as_ref()) {
+                stats.add_rows(1);
+            }
+            if let Err(e) = encoding.encode_event(&mut buf, &row_res) {
+                buf.clear();
+                encoding
+                    .encode_event(&mut buf, &QueryEvent::Error(e.to_compact_string()))
+                    .expect("could not serialize error event");
+                _ = tx.send_data(buf.split().freeze()).await;
+                tx.finish().await;
+                return;
             }
-
-            buf.extend_from_slice(b"\n");
 
             if let Err(e) = tx.send_data(buf.split().freeze()).await {
                 error!("could not send data through body's channel: {e}");
+                // nobody's reading the rows anymore
+                body_queries.cancel(&id);
                 return;
             }
         }
+        tx.finish().await;
         debug!("query body channel done");
     });
 
     trace!("building query rows response...");
 
-    match build_query_rows_response(&agent, data_tx, stmt).await {
+    match build_query_rows_response(
+        &agent,
+        data_tx,
+        stmt,
+        snapshot,
+        meta_tables,
+        page,
+        params.progress,
+        (queries, id, timeout),
+    )
+    .await
+    {
         Ok(_) => {
-            #[allow(clippy::needless_return)]
-            return hyper::Response::builder()
+            let mut builder = hyper::Response::builder()
                 .status(StatusCode::OK)
+                .header(QUERY_ID_HEADER, id.to_string());
+            if let Some(content_type) = encoding.content_type() {
+                builder = builder.header(hyper::header::CONTENT_TYPE, content_type);
+            }
+            if let Some(content_encoding) = content_encoding {
+                builder = builder.header(hyper::header::CONTENT_ENCODING, content_encoding);
+            }
+            #[allow(clippy::needless_return)]
+            return builder
                 .body(body)
                 .expect("could not build query response body");
         }
-        Err((status, res)) => {
+        Err(e) => {
             #[allow(clippy::needless_return)]
-            return hyper::Response::builder()
-                .status(status)
-                .body(
-                    serde_json::to_vec(&res)
-                        .expect("could not serialize query error response")
-                        .into(),
-                )
-                .expect("could not build query response body");
+            return query_error_response(e);
         }
     }
 }
 
-async fn execute_schema(agent: &Agent, statements: Vec<String>) -> eyre::Result<()> {
+/// A column added to an existing table with a default value. Rows that
+/// existed before read the default from the local table definition, no
+/// change was replicated for them.
+#[derive(Debug)]
+struct DefaultedColumn {
+    table: String,
+    column: String,
+    default: String,
+}
+
+async fn execute_schema(
+    agent: &Agent,
+    statements: Vec<String>,
+    allow_destructive: bool,
+) -> Result<Vec<DefaultedColumn>, SchemaError> {
     let new_sql: String = statements.join(";");
 
-    let partial_schema = parse_sql(&new_sql)?;
+    let change = parse_schema_change(&new_sql)?;
 
     let mut conn = agent.pool().write_priority().await?;
+    // writes queued behind this migration wait for it, up to a point
+    let _fence = agent.write_fence().raise();
 
     // hold onto this lock so nothing else makes changes
     let mut schema_write = agent.schema().write();
 
     // clone the previous schema and apply
-    let mut new_schema = {
-        let mut schema = schema_write.clone();
-        for (name, def) in partial_schema.tables.iter() {
-            // overwrite table because users are expected to return a full table def
-            schema.tables.insert(name.clone(), def.clone());
-        }
-        schema
+    let MigrationSchemas {
+        base,
+        mut new_schema,
+        dropped,
+    } = migration_schemas(&schema_write, &change, allow_destructive)?;
+
+    let options = ApplySchemaOptions {
+        allow_destructive,
+        renamed: change.renamed.clone(),
     };
-
-    new_schema.constrain()?;
+    let plan = plan_schema(&base, &new_schema, &options)?;
+
+    // renamed columns keep their values, they aren't added
+    let defaulted = plan
+        .columns_added
+        .iter()
+        .filter_map(|added| {
+            let col = new_schema
+                .tables
+                .get(&added.table)?
+                .columns
+                .get(&added.column)?;
+            if col.generated.is_some() {
+                return None;
+            }
+            Some(DefaultedColumn {
+                table: added.table.clone(),
+                column: added.column.clone(),
+                default: col.default_value.clone()?,
+            })
+        })
+        .collect();
 
     block_in_place(|| {
         let tx = conn.immediate_transaction()?;
 
-        apply_schema(&tx, &schema_write, &mut new_schema)?;
+        drop_tables(&tx, &dropped)?;
+        for tbl_name in dropped.iter() {
+            tx.execute("DELETE FROM __corro_schema WHERE tbl_name = ?", [tbl_name])?;
+            tx.execute(
+                "INSERT OR IGNORE INTO __corro_dropped_tables (name) VALUES (?)",
+                [tbl_name],
+            )?;
+            tx.execute(
+                "DELETE FROM __corro_dropped_columns WHERE tbl_name = ?",
+                [tbl_name],
+            )?;
+        }
+
+        apply_schema(&tx, &base, &mut new_schema, &plan)?;
+
+        // nodes which haven't migrated yet still send changes to them
+        for col in plan.columns_dropped.iter() {
+            tx.execute(
+                "INSERT OR REPLACE INTO __corro_dropped_columns (tbl_name, name, renamed_to) VALUES (?, ?, NULL)",
+                [&col.table, &col.column],
+            )?;
+        }
+        for col in plan.columns_renamed.iter() {
+            tx.execute(
+                "INSERT OR REPLACE INTO __corro_dropped_columns (tbl_name, name, renamed_to) VALUES (?, ?, ?)",
+                [&col.table, &col.from, &col.to],
+            )?;
+        }
 
-        for tbl_name in partial_schema.tables.keys() {
+        for tbl_name in change.schema.tables.keys() {
             tx.execute("DELETE FROM __corro_schema WHERE tbl_name = ?", [tbl_name])?;
+            tx.execute(
+                "DELETE FROM __corro_dropped_tables WHERE name = ?",
+                [tbl_name],
+            )?;
 
             let n = tx.execute("INSERT INTO __corro_schema SELECT tbl_name, type, name, sql, 'api' AS source FROM sqlite_schema WHERE tbl_name = ? AND type IN ('table', 'index') AND name IS NOT NULL AND sql IS NOT NULL", [tbl_name])?;
             info!("Updated {n} rows in __corro_schema for table {tbl_name}");
@@ -614,54 +1508,441 @@ async fn execute_schema(agent: &Agent, statements: Vec<String>) -> eyre::Result<
 
         tx.commit()?;
 
-        Ok::<_, eyre::Report>(())
+        Ok::<_, SchemaError>(())
     })?;
 
+    new_schema.record_dropped_columns(&plan);
+    for tbl_name in dropped.iter() {
+        new_schema.dropped_columns.remove(tbl_name);
+    }
     *schema_write = new_schema;
+    drop(schema_write);
 
-    Ok(())
+    // nothing will ever match them again
+    for tbl_name in dropped.iter() {
+        let subs = agent.subs_manager().close_table(tbl_name);
+        let watches = agent.key_watches().close_table(tbl_name);
+        info!("Dropped table '{tbl_name}', closed {subs} subscriptions and {watches} key watches");
+    }
+
+    Ok(defaulted)
+}
+
+/// Schemas a change goes from and to
+struct MigrationSchemas {
+    /// The current schema, without the tables dropped by the change
+    base: Schema,
+    /// `base` with the tables defined by the change replacing theirs
+    new_schema: Schema,
+    dropped: Vec<String>,
+}
+
+fn migration_schemas(
+    schema: &Schema,
+    change: &SchemaChange,
+    allow_destructive: bool,
+) -> Result<MigrationSchemas, SchemaError> {
+    let mut base = schema.clone();
+    let mut dropped = vec![];
+    for table in change.dropped.iter() {
+        if base.tables.shift_remove(&table.name).is_some() {
+            if !allow_destructive {
+                return Err(
+                    ApplySchemaError::DropTableWithoutDestructiveFlag(table.name.clone()).into(),
+                );
+            }
+            base.dropped.insert(table.name.clone());
+            dropped.push(table.name.clone());
+        } else if !table.if_exists && !dropped.contains(&table.name) {
+            return Err(ApplySchemaError::DropUnknownTable(table.name.clone()).into());
+        }
+    }
+
+    let mut new_schema = base.clone();
+    for (name, def) in change.schema.tables.iter() {
+        // overwrite table because users are expected to return a full table def
+        new_schema.tables.insert(name.clone(), def.clone());
+        new_schema.dropped.remove(name);
+    }
+
+    new_schema.constrain()?;
+
+    Ok(MigrationSchemas {
+        base,
+        new_schema,
+        dropped,
+    })
+}
+
+/// What applying `statements` would change, without applying them
+fn plan_migration(
+    agent: &Agent,
+    statements: Vec<String>,
+    allow_destructive: bool,
+) -> Result<SchemaPlan, SchemaError> {
+    if statements.is_empty() {
+        return Err(SchemaError::NoStatements);
+    }
+
+    let change = parse_schema_change(&statements.join(";"))?;
+
+    let schema = agent.schema().read();
+    let MigrationSchemas {
+        base,
+        new_schema,
+        dropped,
+    } = migration_schemas(&schema, &change, allow_destructive)?;
+
+    let options = ApplySchemaOptions {
+        allow_destructive,
+        renamed: change.renamed,
+    };
+
+    Ok(SchemaPlan {
+        tables_dropped: dropped,
+        ..plan_schema(&base, &new_schema, &options)?
+    })
+}
+
+/// What [`resync_schema`] found out of date in `__corro_schema`
+#[derive(Debug, Default, Serialize)]
+pub struct SchemaResync {
+    /// Definitions that changed in the database
+    pub updated: usize,
+    /// Tables and indexes that don't exist anymore
+    pub removed: usize,
+    /// Indexes of known tables that weren't recorded
+    pub added: usize,
+    /// Tables in the rebuilt schema
+    pub tables: usize,
+}
+
+/// Brings `__corro_schema` in line with `sqlite_schema` and rebuilds the
+/// in-memory schema from it, for when the database was changed behind the
+/// agent's back. Nothing is changed if the resulting schema is invalid.
+/// Subscriptions to tables that don't exist anymore are closed.
+pub async fn resync_schema(agent: &Agent) -> Result<SchemaResync, SchemaError> {
+    let mut conn = agent.pool().write_priority().await?;
+    // altered tables are rebuilt by cr-sqlite, like during migrations
+    let _fence = agent.write_fence().raise();
+
+    // hold onto this lock so nothing else makes changes
+    let mut schema_write = agent.schema().write();
+
+    let (new_schema, dropped, resync) = block_in_place(|| {
+        let tx = conn.immediate_transaction()?;
+
+        let dropped: Vec<String> = tx
+            .prepare(
+                "SELECT name FROM __corro_schema WHERE type = 'table' AND name NOT IN (
+                    SELECT name FROM sqlite_schema WHERE type = 'table'
+                )",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let altered: Vec<String> = tx
+            .prepare(
+                "SELECT s.name FROM sqlite_schema AS s
+                    JOIN __corro_schema AS c ON c.type = 'table' AND c.name = s.name
+                    WHERE s.type = 'table' AND s.sql IS NOT c.sql",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        // the tables were altered without cr-sqlite knowing, going through
+        // an alter now rebuilds their clock tables and triggers for their
+        // current columns
+        for name in altered.iter() {
+            info!("Altering crsql for table {name}, changed outside of the agent");
+            tx.execute_batch(&format!(
+                "SELECT crsql_begin_alter('{name}'); SELECT crsql_commit_alter('{name}');"
+            ))?;
+        }
+
+        let removed = tx.execute(
+            "DELETE FROM __corro_schema WHERE (type, name) NOT IN (
+                SELECT type, name FROM sqlite_schema WHERE sql IS NOT NULL
+            )",
+            [],
+        )?;
+
+        let updated = tx.execute(
+            "UPDATE __corro_schema SET sql = s.sql
+                FROM (SELECT type, name, sql FROM sqlite_schema) AS s
+                WHERE s.type = __corro_schema.type
+                  AND s.name = __corro_schema.name
+                  AND s.sql IS NOT __corro_schema.sql",
+            [],
+        )?;
+
+        let added = tx.execute(
+            "INSERT INTO __corro_schema
+                SELECT tbl_name, type, name, sql, 'admin' AS source FROM sqlite_schema
+                    WHERE type = 'index'
+                      AND sql IS NOT NULL
+                      AND tbl_name IN (SELECT tbl_name FROM __corro_schema WHERE type = 'table')
+                      AND (type, name) NOT IN (SELECT type, name FROM __corro_schema)",
+            [],
+        )?;
+
+        let mut new_schema = init_schema(&tx)?;
+        new_schema.constrain()?;
+
+        tx.commit()?;
+
+        let tables = new_schema.tables.len();
+        Ok::<_, SchemaError>((
+            new_schema,
+            dropped,
+            SchemaResync {
+                updated,
+                removed,
+                added,
+                tables,
+            },
+        ))
+    })?;
+
+    info!(
+        "Resynced schema with the database: {} updated, {} removed, {} added, {} tables",
+        resync.updated, resync.removed, resync.added, resync.tables
+    );
+
+    *schema_write = new_schema;
+    drop(schema_write);
+
+    // nothing will ever match them again
+    for tbl_name in dropped.iter() {
+        let subs = agent.subs_manager().close_table(tbl_name);
+        let watches = agent.key_watches().close_table(tbl_name);
+        info!(
+            "Table '{tbl_name}' was dropped, closed {subs} subscriptions and {watches} key watches"
+        );
+    }
+
+    Ok(resync)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SchemaParams {
+    /// Return what the statements would change instead of applying them
+    #[serde(default)]
+    dry_run: bool,
+    /// Allow `DROP TABLE` statements
+    #[serde(default)]
+    allow_destructive: bool,
 }
 
 pub async fn api_v1_db_schema(
     Extension(agent): Extension<Agent>,
+    axum::extract::Query(params): axum::extract::Query<SchemaParams>,
     axum::extract::Json(statements): axum::extract::Json<Vec<String>>,
-) -> (StatusCode, axum::Json<ExecResponse>) {
-    if statements.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            axum::Json(ExecResponse {
-                results: vec![ExecResult::Error {
-                    error: "at least 1 statement is required".into(),
-                }],
-                time: 0.0,
+) -> (StatusCode, axum::Json<SchemaChangeResponse>) {
+    let start = Instant::now();
+
+    let res = if params.dry_run {
+        plan_migration(&agent, statements, params.allow_destructive).map(Some)
+    } else {
+        migrate(&agent, statements, params.allow_destructive)
+            .await
+            .map(|_| None)
+    };
+
+    match res {
+        Ok(plan) => (
+            StatusCode::OK,
+            axum::Json(SchemaChangeResponse {
+                results: vec![],
+                time: start.elapsed().as_secs_f64(),
+                plan,
             }),
-        );
+        ),
+        Err(e) => {
+            let (status_code, axum::Json(ExecResponse { results, time })) = e.into_exec_response();
+            (
+                status_code,
+                axum::Json(SchemaChangeResponse {
+                    results,
+                    time,
+                    plan: None,
+                }),
+            )
+        }
     }
+}
+
+/// The schema applied to this node, as parsed from its statements, with
+/// where each table and index came from as tracked in `__corro_schema`
+pub async fn api_v1_db_schema_get(
+    Extension(agent): Extension<Agent>,
+) -> Result<axum::Json<SchemaResponse>, ApiErrorResponse<QueryError>> {
+    let conn = agent.pool().read().await.map_err(QueryError::from)?;
+
+    let sources: HashMap<(String, String), String> = block_in_place(|| {
+        conn.prepare_cached("SELECT type, name, source FROM __corro_schema")?
+            .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()
+    })
+    .map_err(QueryError::from)?;
+    let source = |kind: &str, name: &str| sources.get(&(kind.to_owned(), name.to_owned())).cloned();
+
+    let schema = agent.schema().read();
+
+    let tables = schema
+        .tables
+        .values()
+        .map(|table| TableSchema {
+            name: table.name.clone(),
+            pk: table.pk.iter().cloned().collect(),
+            columns: table
+                .columns
+                .values()
+                .map(|column| ColumnSchema {
+                    name: column.name.clone(),
+                    sql_type: column.sql_type.1.clone(),
+                    nullable: column.nullable,
+                    primary_key: column.primary_key,
+                    default_value: column.default_value.clone(),
+                    generated: column.generated.clone(),
+                })
+                .collect(),
+            indexes: table
+                .indexes
+                .values()
+                .map(|index| IndexSchema {
+                    name: index.name.clone(),
+                    sql: index.to_string(),
+                    source: source("index", &index.name),
+                })
+                .collect(),
+            sql: table.to_string(),
+            source: source("table", &table.name),
+        })
+        .collect();
+
+    Ok(axum::Json(SchemaResponse { tables }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MigrationParams {
+    /// Replicate the default value of columns added to existing tables for
+    /// every pre-existing row, through forced backfills started on this node
+    #[serde(default)]
+    backfill_defaults: bool,
+    /// Allow `DROP TABLE` statements
+    #[serde(default)]
+    allow_destructive: bool,
+}
 
+/// Applies a schema like [`api_v1_db_schema`], optionally backfilling the
+/// columns it added with a default value
+pub async fn api_v1_migrations(
+    Extension(agent): Extension<Agent>,
+    Extension(backfills): Extension<SharedBackfills>,
+    Extension(tripwire): Extension<Tripwire>,
+    axum::extract::Query(params): axum::extract::Query<MigrationParams>,
+    axum::extract::Json(statements): axum::extract::Json<Vec<String>>,
+) -> (StatusCode, axum::Json<MigrationResponse>) {
     let start = Instant::now();
 
-    if let Err(e) = execute_schema(&agent, statements).await {
-        error!("could not merge schemas: {e}");
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            axum::Json(ExecResponse {
-                results: vec![ExecResult::Error {
-                    error: e.to_string(),
-                }],
-                time: 0.0,
-            }),
-        );
+    let defaulted = match migrate(&agent, statements, params.allow_destructive).await {
+        Ok(defaulted) => defaulted,
+        Err(e) => {
+            let (status_code, axum::Json(ExecResponse { results, time })) = e.into_exec_response();
+            return (
+                status_code,
+                axum::Json(MigrationResponse {
+                    results,
+                    time,
+                    backfills: vec![],
+                }),
+            );
+        }
+    };
+
+    let mut results = vec![];
+    let mut started = vec![];
+    for DefaultedColumn {
+        table,
+        column,
+        default,
+    } in defaulted
+    {
+        if !params.backfill_defaults {
+            info!("column {table}.{column} was added with default {default}, pre-existing rows were not backfilled");
+            continue;
+        }
+
+        let req = BackfillRequest {
+            table,
+            column,
+            expr: default,
+            filter: None,
+            batch_size: None,
+            interval_ms: None,
+            // rows already read as the default, they have to be rewritten
+            // to produce changes and settle on this node's values
+            force: true,
+        };
+        match start_backfill(agent.clone(), &backfills, tripwire.clone(), req).await {
+            Ok(status) => started.push(status),
+            Err(e) => {
+                error!("could not start backfill of added column: {e}");
+                results.push(e.exec_result());
+            }
+        }
     }
 
     (
         StatusCode::OK,
-        axum::Json(ExecResponse {
-            results: vec![],
+        axum::Json(MigrationResponse {
+            results,
             time: start.elapsed().as_secs_f64(),
+            backfills: started,
         }),
     )
 }
 
+async fn migrate(
+    agent: &Agent,
+    statements: Vec<String>,
+    allow_destructive: bool,
+) -> Result<Vec<DefaultedColumn>, SchemaError> {
+    if statements.is_empty() {
+        return Err(SchemaError::NoStatements);
+    }
+
+    let defaulted = match execute_schema(agent, statements, allow_destructive).await {
+        Ok(defaulted) => defaulted,
+        Err(e) => {
+            error!("could not merge schemas: {e}");
+            return Err(e);
+        }
+    };
+
+    retry_schema_behind(agent).await;
+
+    Ok(defaulted)
+}
+
+/// Re-submits changesets from peers which were held until the local schema
+/// caught up with theirs
+async fn retry_schema_behind(agent: &Agent) {
+    let ready = agent.schema_behind().take_ready(&agent.schema().read());
+    if ready.is_empty() {
+        return;
+    }
+
+    info!("schema caught up, retrying {} held changesets", ready.len());
+    for (change, src) in ready {
+        if let Err(e) = agent.tx_changes().send((change, src)).await {
+            error!("could not retry changeset held until the schema caught up: {e}");
+            break;
+        }
+    }
+}
+
 /// Query the table status of the current node
 ///
 /// Currently this endpoint only supports querying the row count for a
@@ -674,11 +1955,11 @@ pub async fn api_v1_table_stats(
     async fn count_table_lengths(
         agent: &Agent,
         ts_req: TableStatRequest,
-    ) -> eyre::Result<(i64, Vec<String>)> {
+    ) -> Result<(i64, Vec<String>), QueryError> {
         debug!("Querying row count for {} tables", ts_req.tables.len());
         let conn = agent.pool().read().await?;
 
-        block_in_place(move || -> eyre::Result<(i64, Vec<String>)> {
+        block_in_place(move || -> Result<(i64, Vec<String>), QueryError> {
             let valid_tables: BTreeSet<String> = conn
                 .prepare_cached("select name from sqlite_schema where type = 'table'")?
                 .query_map([], |row| row.get(0))?
@@ -712,8 +1993,8 @@ pub async fn api_v1_table_stats(
                 invalid_tables,
             }),
         ),
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
+        Err(e) => (
+            e.status(),
             axum::Json(TableStatResponse {
                 total_row_count: 0,
                 // Since we don't know what error occured or if any
@@ -727,7 +2008,17 @@ pub async fn api_v1_table_stats(
 #[cfg(test)]
 mod tests {
     use bytes::Bytes;
-    use corro_types::{api::RowId, base::Version, config::Config, schema::SqliteType};
+    use corro_types::{
+        actor::ActorId,
+        agent::Bookie,
+        api::{Change, CloseReason, KeyWatchEvent, RowId, SchemaColumnRename, TableName},
+        base::Version,
+        broadcast::ChangeSource,
+        config::Config,
+        pubsub::pack_columns,
+        schema::{DroppedColumn, SqliteType},
+        validation::{TxValidator, Veto},
+    };
     use futures::Stream;
     use http_body::{combinators::UnsyncBoxBody, Body};
     use tokio::sync::mpsc::error::TryRecvError;
@@ -736,7 +2027,7 @@ mod tests {
 
     use super::*;
 
-    use crate::agent::setup;
+    use crate::agent::{process_multiple_changes, setup};
 
     struct UnsyncBodyStream(std::pin::Pin<Box<UnsyncBoxBody<Bytes, axum::Error>>>);
 
@@ -751,6 +2042,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_query_params() {
+        let params = |query: &str| {
+            let uri = format!("/v1/queries?{query}").parse().unwrap();
+            let axum::extract::Query(params) =
+                axum::extract::Query::<QueryParams>::try_from_uri(&uri).unwrap();
+            params
+        };
+
+        assert!(params("meta=true").meta);
+        assert!(params("progress=true").progress);
+        assert!(params("ordered=true").ordered);
+        assert_eq!(params("page_size=10").page_size, Some(10));
+        assert_eq!(params("limit=10").page_size, Some(10));
+        assert_eq!(params("cursor=abc").cursor.as_deref(), Some("abc"));
+        assert_eq!(params("timeout_ms=500").timeout_ms, Some(500));
+        assert_eq!(params("envelope=rqlite").envelope, Envelope::Rqlite);
+        let id = Uuid::new_v4();
+        assert_eq!(params(&format!("snapshot={id}")).snapshot, Some(id));
+
+        let params = params("limit=10&meta=true&pretty&timings=1&associative=false");
+        assert_eq!(params.page_size, Some(10));
+        assert!(params.meta);
+        assert!(params.pretty);
+        assert!(params.timings);
+        assert!(!params.associative);
+    }
+
+    #[test]
+    fn test_truncation_filter() {
+        let req = TruncateRequest {
+            table: "tests".into(),
+            start: None,
+            end: None,
+        };
+        assert_eq!(truncation_filter("\"id\"", &req), ("1".into(), vec![]));
+
+        let start = SqliteValue::Integer(10);
+        let end = SqliteValue::Integer(20);
+        let req = TruncateRequest {
+            start: Some(start.clone()),
+            end: Some(end.clone()),
+            ..req
+        };
+        assert_eq!(
+            truncation_filter("p.\"id\"", &req),
+            ("p.\"id\" >= ? AND p.\"id\" < ?".into(), vec![&start, &end])
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_api_db_execute() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
@@ -773,6 +2114,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
             axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
         )
         .await;
@@ -781,10 +2123,15 @@ mod tests {
 
         let (status_code, body) = api_v1_transactions(
             Extension(agent.clone()),
-            axum::Json(vec![Statement::WithParams(
-                "insert into tests (id, text) values (?,?)".into(),
-                vec!["service-id".into(), "service-name".into()],
-            )]),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec!["service-id".into(), "service-name".into()],
+                )]
+                .into(),
+            ),
         )
         .await;
 
@@ -816,10 +2163,15 @@ mod tests {
 
         let (status_code, body) = api_v1_transactions(
             Extension(agent.clone()),
-            axum::Json(vec![Statement::WithParams(
-                "update tests SET text = ? where id = ?".into(),
-                vec!["service-name".into(), "service-id".into()],
-            )]),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "update tests SET text = ? where id = ?".into(),
+                    vec!["service-name".into(), "service-id".into()],
+                )]
+                .into(),
+            ),
         )
         .await;
 
@@ -835,13 +2187,1004 @@ mod tests {
         Ok(())
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_api_db_query() -> eyre::Result<()> {
-        _ = tracing_subscriber::fmt::try_init();
+    struct NoDeletes;
 
-        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+    impl TxValidator for NoDeletes {
+        fn name(&self) -> &str {
+            "no_deletes"
+        }
 
-        let dir = tempfile::tempdir()?;
+        fn validate(&self, pending: &PendingTransaction<'_>) -> Result<(), Veto> {
+            match pending.changes.tables.iter().find(|(_, t)| t.deletes > 0) {
+                Some((table, _)) => Err(Veto::new(
+                    "delete_forbidden",
+                    format!("rows of '{table}' can't be deleted"),
+                )),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_vetoed() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        agent.validators().register(Arc::new(NoDeletes));
+
+        let rx_bcast = &mut agent_options.rx_bcast;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec!["service-id".into(), "service-name".into()],
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(rx_bcast.recv().await.is_some());
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "delete from tests where id = ?".into(),
+                    vec!["service-id".into()],
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(matches!(
+            &body.0.results[..],
+            [ExecResult::Error { error, code: Some(ErrorCode::Vetoed) }] if error.contains("delete_forbidden")
+        ));
+
+        // rolled back and never broadcast
+        assert!(matches!(rx_bcast.try_recv(), Err(TryRecvError::Empty)));
+        assert_eq!(agent.booked().read("test").await.last(), Some(Version(1)));
+
+        let conn = agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?;
+        assert_eq!(count, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_atomic() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let rx_bcast = &mut agent_options.rx_bcast;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let statements = vec![
+            Statement::WithParams(
+                "insert into tests (id, text) values (?,?)".into(),
+                vec!["service-id".into(), "service-name".into()],
+            ),
+            Statement::Simple("insert into nope (id) values (1)".into()),
+        ];
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(EnvelopeParams {
+                transaction: true,
+                ..Default::default()
+            }),
+            axum::Json(statements.clone().into()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            &body.0.results[..],
+            [ExecResult::Error { error, code: Some(ErrorCode::StatementFailed) }] if error.starts_with("statement #1 failed")
+        ));
+
+        // rolled back and never broadcast
+        assert!(matches!(rx_bcast.try_recv(), Err(TryRecvError::Empty)));
+        let conn = agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?;
+        assert_eq!(count, 0);
+
+        // without it, statements before the failing one are committed
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(statements.into()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(matches!(
+            &body.0.results[..],
+            [ExecResult::Execute { .. }, ExecResult::Error { .. }]
+        ));
+        assert!(rx_bcast.recv().await.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_returning() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![
+                    Statement::Simple(
+                        "insert into tests (id, text) values (1, 'a'), (2, 'b') returning id"
+                            .into(),
+                    ),
+                    Statement::Simple(
+                        "update tests set text = 'c' where id = 1 returning text".into(),
+                    ),
+                    Statement::Simple("delete from tests where id = 2".into()),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        match &body.0.results[..] {
+            [ExecResult::Returning {
+                rows_affected: 2,
+                columns,
+                rows,
+                ..
+            }, ExecResult::Returning {
+                rows_affected: 1,
+                rows: updated,
+                ..
+            }, ExecResult::Execute {
+                rows_affected: 1, ..
+            }] => {
+                assert_eq!(columns, &vec![ColumnName("id".into())]);
+                assert_eq!(
+                    rows,
+                    &vec![vec![SqliteValue::Integer(1)], vec![SqliteValue::Integer(2)]]
+                );
+                assert_eq!(updated, &vec![vec![SqliteValue::Text("c".into())]]);
+            }
+            results => panic!("unexpected results: {results:?}"),
+        }
+
+        // written like any other statement
+        assert!(agent_options.rx_bcast.recv().await.is_some());
+
+        // returned rows are not mistaken for rows affected
+        let json = serde_json::to_string(&body.0.results[0])?;
+        let res: ExecResult = serde_json::from_str(&json)?;
+        assert!(matches!(res, ExecResult::Returning { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_preconditions() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let update = |text: &str, precondition: Precondition| TransactionRequest::Conditional {
+            statements: vec![Statement::WithParams(
+                "UPDATE tests SET text = ? WHERE id = 1".into(),
+                vec![text.into()],
+            )],
+            preconditions: vec![precondition],
+        };
+        let text_is = |text: &str| Precondition::Rows {
+            statement: Statement::Simple("SELECT text FROM tests WHERE id = 1".into()),
+            rows: vec![vec![text.into()]],
+        };
+        let text_version = |col_version: i64| Precondition::ColVersion {
+            table: "tests".into(),
+            pk: vec![1i64.into()],
+            column: "text".into(),
+            col_version,
+        };
+
+        // the row doesn't exist yet
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(TransactionRequest::Conditional {
+                statements: vec![Statement::Simple(
+                    "INSERT INTO tests (id, text) VALUES (1, 'hello')".into(),
+                )],
+                preconditions: vec![text_version(0)],
+            }),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(update("world", text_is("hello"))),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // someone else got there first
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(update("nope", text_is("hello"))),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::PRECONDITION_FAILED);
+        assert!(matches!(
+            body.0.results.as_slice(),
+            [ExecResult::Error {
+                code: Some(ErrorCode::PreconditionFailed),
+                ..
+            }]
+        ));
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(update("again", text_version(2))),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(update("nope", text_version(2))),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::PRECONDITION_FAILED);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(update(
+                "nope",
+                Precondition::Rows {
+                    statement: Statement::Simple("DELETE FROM tests".into()),
+                    rows: vec![],
+                },
+            )),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let conn = agent.pool().read().await?;
+        let text: String =
+            conn.query_row("SELECT text FROM tests WHERE id = 1", [], |row| row.get(0))?;
+        assert_eq!(text, "again");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_read_statements() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let mut config = Config::builder()
+            .db_path(dir.path().join("corrosion.db").display().to_string())
+            .gossip_addr("127.0.0.1:0".parse()?)
+            .api_addr("127.0.0.1:0".parse()?)
+            .build()?;
+        config.api.read_statements = ReadStatements::Query;
+
+        let (agent, _agent_options) = setup(config, tripwire).await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "select text from tests where id = ?".into(),
+                        vec!["service-id".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::Simple("select count(*) from tests".into()),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        match &body.0.results[..] {
+            [ExecResult::Query {
+                columns: first_cols,
+                rows: first_rows,
+                ..
+            }, ExecResult::Execute {
+                rows_affected: 1, ..
+            }, ExecResult::Query { rows, .. }] => {
+                assert_eq!(first_cols, &vec![ColumnName("text".into())]);
+                // statements run in order, the first read precedes the write
+                assert!(first_rows.is_empty());
+                assert_eq!(rows, &vec![vec![SqliteValue::Integer(1)]]);
+            }
+            results => panic!("unexpected results: {results:?}"),
+        }
+
+        let mut config = (*agent.config()).clone();
+        config.api.read_statements = ReadStatements::Reject;
+        agent.set_config(config);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![Statement::Simple("select count(*) from tests".into())].into()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            &body.0.results[..],
+            [ExecResult::Error { error, code: Some(ErrorCode::BadRequest) }] if error.contains("read-only")
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+
+        // println!("{body:?}");
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(body.0.results.len() == 2);
+
+        println!("transaction body: {body:?}");
+
+        let res = api_v1_queries(
+            Extension(agent.clone()),
+            Extension(Default::default()),
+            Extension(Default::default()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut body = res.into_body();
+
+        let mut lines = LinesCodec::new();
+
+        let mut buf = BytesMut::new();
+
+        buf.extend_from_slice(&body.data().await.unwrap()?);
+
+        let s = lines.decode(&mut buf).unwrap().unwrap();
+
+        let cols: QueryEvent = serde_json::from_str(&s).unwrap();
+
+        assert_eq!(cols, QueryEvent::Columns(vec!["id".into(), "text".into()]));
+
+        buf.extend_from_slice(&body.data().await.unwrap()?);
+
+        let s = lines.decode(&mut buf).unwrap().unwrap();
+
+        let row: QueryEvent = serde_json::from_str(&s).unwrap();
+
+        assert_eq!(
+            row,
+            QueryEvent::Row(RowId(1), vec!["service-id".into(), "service-name".into()])
+        );
+
+        buf.extend_from_slice(&body.data().await.unwrap()?);
+
+        let s = lines.decode(&mut buf).unwrap().unwrap();
+
+        let row: QueryEvent = serde_json::from_str(&s).unwrap();
+
+        assert_eq!(
+            row,
+            QueryEvent::Row(
+                RowId(2),
+                vec!["service-id-2".into(), "service-name-2".into()]
+            )
+        );
+
+        buf.extend_from_slice(&body.data().await.unwrap()?);
+
+        let s = lines.decode(&mut buf).unwrap().unwrap();
+
+        let query_evt: QueryEvent = serde_json::from_str(&s).unwrap();
+
+        assert!(matches!(query_evt, QueryEvent::EndOfQuery { .. }));
+
+        assert!(body.data().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query_params() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(body.0.results.len() == 2);
+
+        let res = api_v1_queries(
+            Extension(agent.clone()),
+            Extension(Default::default()),
+            Extension(Default::default()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(Statement::WithNamedParams(
+                "select text from tests where id = :id".into(),
+                [("id".to_string(), "service-id-2".into())].into(),
+            )),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let events = body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<Vec<QueryEvent>, _>>()?;
+        assert_eq!(
+            events[1],
+            QueryEvent::Row(RowId(1), vec!["service-name-2".into()])
+        );
+        assert!(matches!(events[2], QueryEvent::EndOfQuery { .. }));
+
+        // parameters not matching the statement are rejected
+        let res = api_v1_queries(
+            Extension(agent.clone()),
+            Extension(Default::default()),
+            Extension(Default::default()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(Statement::WithParams(
+                "select text from tests where id = ?".into(),
+                vec!["service-id".into(), "service-id-2".into()],
+            )),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query_pages() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(body.0.results.len() == 2);
+
+        let mut cursor = None;
+        let mut ids = vec![];
+        loop {
+            let res = api_v1_queries(
+                Extension(agent.clone()),
+                Extension(Default::default()),
+                Extension(Default::default()),
+                Default::default(),
+                axum::extract::Query(QueryParams {
+                    page_size: Some(1),
+                    cursor: cursor.take(),
+                    ..Default::default()
+                }),
+                axum::Json(Statement::Simple("select id from tests".into())),
+            )
+            .await
+            .into_response();
+
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            for line in body.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+                match serde_json::from_slice(line)? {
+                    QueryEvent::Row(_, cells) => ids.push(cells[0].clone()),
+                    QueryEvent::EndOfQuery { cursor: next, .. } => cursor = next,
+                    _ => {}
+                }
+            }
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            ids,
+            vec![
+                SqliteValue::Text("service-id".into()),
+                SqliteValue::Text("service-id-2".into())
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query_progress() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        // query plans name aliased tables by their alias
+        for query in ["select * from tests", "select t.id from tests as t"] {
+            let res = api_v1_queries(
+                Extension(agent.clone()),
+                Extension(Default::default()),
+                Extension(Default::default()),
+                Default::default(),
+                axum::extract::Query(QueryParams {
+                    progress: true,
+                    ..Default::default()
+                }),
+                axum::Json(Statement::Simple(query.into())),
+            )
+            .await
+            .into_response();
+
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            let events = body
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(serde_json::from_slice)
+                .collect::<Result<Vec<QueryEvent>, _>>()?;
+
+            // estimated from the table's cr-sqlite keys, it's not analyzed
+            assert_eq!(events[1], QueryEvent::Estimate { rows: 2 }, "{query}");
+            assert!(matches!(events[2], QueryEvent::Row(RowId(1), _)));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_resync_schema() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+                "CREATE INDEX tests_foo ON tests (foo);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // out-of-band changes
+        {
+            let conn = agent.pool().write_priority().await?;
+            conn.execute_batch(
+                "DROP INDEX tests_foo; CREATE INDEX tests_foo_id ON tests (foo, id);",
+            )?;
+        }
+
+        assert!(agent.schema().read().tables["tests"]
+            .indexes
+            .contains_key("tests_foo"));
+
+        let resync = resync_schema(&agent).await?;
+        assert_eq!(resync.removed, 1);
+        assert_eq!(resync.added, 1);
+        assert_eq!(resync.updated, 0);
+        assert_eq!(resync.tables, 1);
+
+        {
+            let schema = agent.schema().read();
+            let indexes = &schema.tables["tests"].indexes;
+            assert!(!indexes.contains_key("tests_foo"));
+            assert!(indexes.contains_key("tests_foo_id"));
+        }
+
+        let resync = resync_schema(&agent).await?;
+        assert_eq!(resync.removed + resync.added + resync.updated, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_resync_schema_altered() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+                "CREATE TABLE tests2 (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (_matcher, created) = agent.subs_manager().get_or_insert(
+            "SELECT foo FROM tests2",
+            &agent.config().db.subscriptions_path(),
+            &agent.schema().read(),
+            agent.pool(),
+            Tripwire::new_simple().0,
+        )?;
+        let mut rx = created.unwrap().evt_rx;
+
+        // out-of-band changes
+        {
+            let conn = agent.pool().write_priority().await?;
+            conn.execute_batch("ALTER TABLE tests ADD COLUMN bar TEXT; DROP TABLE tests2;")?;
+        }
+
+        let resync = resync_schema(&agent).await?;
+        assert_eq!(resync.updated, 1);
+        assert_eq!(resync.removed, 1);
+        assert_eq!(resync.tables, 1);
+
+        // writes to the new column are tracked by cr-sqlite
+        {
+            let conn = agent.pool().write_priority().await?;
+            conn.execute(
+                "INSERT INTO tests (id, foo, bar) VALUES (1, 'foo', 'bar')",
+                [],
+            )?;
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM crsql_changes WHERE \"table\" = 'tests' AND cid = 'bar'",
+                [],
+                |row| row.get(0),
+            )?;
+            assert_eq!(count, 1);
+        }
+
+        // the subscription to the dropped table was closed
+        loop {
+            match rx.recv().await {
+                Some(QueryEvent::Closed { reason }) => {
+                    assert_eq!(reason, CloseReason::TableDropped);
+                    break;
+                }
+                Some(_) => continue,
+                None => panic!("subscription ended without a closed event"),
+            }
+        }
+        assert!(agent.subs_manager().definitions().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_schema_get() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
 
         let (agent, _agent_options) = setup(
             Config::builder()
@@ -853,94 +3196,507 @@ mod tests {
         )
         .await?;
 
+        let axum::Json(res) = api_v1_db_schema_get(Extension(agent.clone())).await?;
+        assert!(res.tables.is_empty());
+
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
-            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT NOT NULL DEFAULT 'bar');".into(),
+                "CREATE INDEX tests_foo ON tests (foo);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let axum::Json(res) = api_v1_db_schema_get(Extension(agent.clone())).await?;
+        assert_eq!(res.tables.len(), 1);
+
+        let tests = &res.tables[0];
+        assert_eq!(tests.name, "tests");
+        assert_eq!(tests.pk, vec!["id".to_string()]);
+        assert!(tests.sql.starts_with("CREATE TABLE tests"));
+        assert_eq!(tests.source.as_deref(), Some("api"));
+
+        assert_eq!(
+            tests.columns,
+            vec![
+                ColumnSchema {
+                    name: "id".into(),
+                    sql_type: Some("BIGINT".into()),
+                    nullable: false,
+                    primary_key: true,
+                    default_value: None,
+                    generated: None,
+                },
+                ColumnSchema {
+                    name: "foo".into(),
+                    sql_type: Some("TEXT".into()),
+                    nullable: false,
+                    primary_key: false,
+                    default_value: Some("'bar'".into()),
+                    generated: None,
+                },
+            ]
+        );
+
+        assert_eq!(tests.indexes.len(), 1);
+        assert_eq!(tests.indexes[0].name, "tests_foo");
+        assert!(tests.indexes[0]
+            .sql
+            .starts_with("CREATE INDEX tests_foo ON tests"));
+        assert_eq!(tests.indexes[0].source.as_deref(), Some("api"));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_schema_dry_run() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+                "CREATE INDEX tests_foo ON tests (foo);".into(),
+            ]),
         )
         .await;
+        assert_eq!(status_code, StatusCode::OK);
 
+        let (status_code, axum::Json(res)) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(SchemaParams {
+                dry_run: true,
+                allow_destructive: false,
+            }),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT, bar INTEGER NOT NULL DEFAULT 0);".into(),
+                "CREATE INDEX tests_bar ON tests (bar);".into(),
+                "CREATE TABLE tests2 (id BIGINT NOT NULL PRIMARY KEY);".into(),
+            ]),
+        )
+        .await;
         assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(
+            res.plan,
+            Some(SchemaPlan {
+                tables_added: vec!["tests2".into()],
+                tables_dropped: vec![],
+                columns_added: vec![SchemaColumnRef {
+                    table: "tests".into(),
+                    column: "bar".into(),
+                }],
+                columns_dropped: vec![],
+                columns_renamed: vec![],
+                tables_rebuilt: vec![],
+                indexes_added: vec!["tests_bar".into()],
+                indexes_dropped: vec!["tests_foo".into()],
+                indexes_replaced: vec![],
+            })
+        );
 
-        let (status_code, body) = api_v1_transactions(
+        // nothing was applied
+        {
+            let schema = agent.schema().read();
+            assert!(!schema.tables.contains_key("tests2"));
+            let tests = schema.tables.get("tests").unwrap();
+            assert!(!tests.columns.contains_key("bar"));
+            assert!(tests.indexes.contains_key("tests_foo"));
+        }
+
+        // destructive changes are rejected like they would be when applied
+        let (status_code, axum::Json(res)) = api_v1_db_schema(
             Extension(agent.clone()),
+            axum::extract::Query(SchemaParams {
+                dry_run: true,
+                allow_destructive: false,
+            }),
             axum::Json(vec![
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id".into(), "service-name".into()],
-                ),
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-2".into(), "service-name-2".into()],
-                ),
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY);".into()
             ]),
         )
         .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert!(res.plan.is_none());
+        assert!(matches!(
+            res.results.as_slice(),
+            [ExecResult::Error {
+                code: Some(ErrorCode::InvalidSchema),
+                ..
+            }]
+        ));
 
-        // println!("{body:?}");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_schema_drop_table() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
 
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
         assert_eq!(status_code, StatusCode::OK);
 
-        assert!(body.0.results.len() == 2);
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::Simple(
+                    "INSERT INTO tests2 (id, text) VALUES (1, 'hello')".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
 
-        println!("transaction body: {body:?}");
+        let (watch_tx, mut watch_rx) = mpsc::channel(8);
+        let _guard = agent.key_watches().watch(
+            TableName("tests2".into()),
+            vec![vec![1i64.into()]],
+            watch_tx,
+        )?;
 
-        let res = api_v1_queries(
+        // tables are only dropped when asked to
+        let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
-            axum::Json(Statement::Simple("select * from tests".into())),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec!["DROP TABLE tests2".into()]),
         )
-        .await
-        .into_response();
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert!(agent.schema().read().tables.contains_key("tests2"));
 
-        assert_eq!(res.status(), StatusCode::OK);
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(SchemaParams {
+                dry_run: false,
+                allow_destructive: true,
+            }),
+            axum::Json(vec!["DROP TABLE nope".into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
 
-        let mut body = res.into_body();
+        let (status_code, axum::Json(res)) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(SchemaParams {
+                dry_run: true,
+                allow_destructive: true,
+            }),
+            axum::Json(vec![
+                "DROP TABLE tests2".into(),
+                "DROP TABLE IF EXISTS nope".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(res.plan.unwrap().tables_dropped, vec!["tests2".to_string()]);
+        assert!(agent.schema().read().tables.contains_key("tests2"));
 
-        let mut lines = LinesCodec::new();
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(SchemaParams {
+                dry_run: false,
+                allow_destructive: true,
+            }),
+            axum::Json(vec![
+                "DROP TABLE tests2".into(),
+                "DROP TABLE IF EXISTS nope".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
 
-        let mut buf = BytesMut::new();
+        {
+            let schema = agent.schema().read();
+            assert!(!schema.tables.contains_key("tests2"));
+            assert!(schema.tables.contains_key("tests"));
+        }
 
-        buf.extend_from_slice(&body.data().await.unwrap()?);
+        assert_eq!(
+            watch_rx.recv().await,
+            Some(KeyWatchEvent::Closed {
+                reason: CloseReason::TableDropped
+            })
+        );
+        assert!(agent.key_watches().is_empty());
 
-        let s = lines.decode(&mut buf).unwrap().unwrap();
+        let conn = agent.pool().read().await?;
+        let tables: Vec<String> = conn
+            .prepare("SELECT name FROM sqlite_schema WHERE tbl_name LIKE 'tests2%'")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert!(tables.is_empty(), "leftover tables: {tables:?}");
+
+        let recorded: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM __corro_schema WHERE tbl_name = 'tests2'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(recorded, 0);
+
+        let dropped: Vec<String> = conn
+            .prepare("SELECT name FROM __corro_dropped_tables")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(dropped, vec!["tests2".to_string()]);
+        assert!(agent.schema().read().is_dropped("tests2"));
+
+        // changes to the dropped table are skipped instead of waiting for
+        // the schema, the rest of their changeset is applied
+        let actor_id = ActorId(Uuid::new_v4());
+        let change = |table: &str, db_version| -> eyre::Result<Change> {
+            Ok(Change {
+                table: TableName(table.into()),
+                pk: pack_columns(&[1i64.into()])?,
+                cid: ColumnName("text".into()),
+                val: "remote".into(),
+                col_version: 1,
+                db_version: CrsqlDbVersion(db_version),
+                seq: CrsqlSeq(0),
+                site_id: actor_id.to_bytes(),
+                cl: 1,
+            })
+        };
+        let changeset = |version, changes: Vec<Change>| {
+            let last_seq = CrsqlSeq(changes.len() as u64 - 1);
+            (
+                ChangeV1 {
+                    actor_id,
+                    changeset: Changeset::Full {
+                        version: Version(version),
+                        changes,
+                        seqs: CrsqlSeq(0)..=last_seq,
+                        last_seq,
+                        ts: agent.clock().new_timestamp().into(),
+                    },
+                },
+                ChangeSource::Sync,
+                Instant::now(),
+            )
+        };
 
-        let cols: QueryEvent = serde_json::from_str(&s).unwrap();
+        let mut mixed = change("tests", 1)?;
+        mixed.seq = CrsqlSeq(1);
+        let bookie = Bookie::new(Default::default());
+        process_multiple_changes(
+            agent.clone(),
+            bookie.clone(),
+            vec![
+                changeset(1, vec![change("tests2", 1)?, mixed]),
+                changeset(2, vec![change("tests2", 2)?]),
+            ],
+        )
+        .await?;
 
-        assert_eq!(cols, QueryEvent::Columns(vec!["id".into(), "text".into()]));
+        assert!(agent.schema_behind().is_empty());
 
-        buf.extend_from_slice(&body.data().await.unwrap()?);
+        let booked = bookie.read("test").await.get(&actor_id).cloned().unwrap();
+        let read = booked.read("test").await;
+        assert!(read.contains_all(Version(1)..=Version(2), None));
+        drop(read);
 
-        let s = lines.decode(&mut buf).unwrap().unwrap();
+        let text: String =
+            conn.query_row("SELECT text FROM tests WHERE id = 1", [], |row| row.get(0))?;
+        assert_eq!(text, "remote");
 
-        let row: QueryEvent = serde_json::from_str(&s).unwrap();
+        Ok(())
+    }
 
-        assert_eq!(
-            row,
-            QueryEvent::Row(RowId(1), vec!["service-id".into(), "service-name".into()])
-        );
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_schema_drop_rename_column() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
 
-        buf.extend_from_slice(&body.data().await.unwrap()?);
+        let dir = tempfile::tempdir()?;
 
-        let s = lines.decode(&mut buf).unwrap().unwrap();
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
 
-        let row: QueryEvent = serde_json::from_str(&s).unwrap();
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT, bar TEXT);".into(),
+                "CREATE INDEX tests_foo ON tests (foo);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::Simple(
+                    "INSERT INTO tests (id, foo, bar) VALUES (1, 'hello', 'world')".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // columns are only dropped when asked to
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![
+                "ALTER TABLE tests RENAME COLUMN nope TO baz".into(),
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT, bar TEXT, baz TEXT);"
+                    .into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
 
+        let statements: Vec<String> = vec![
+            "ALTER TABLE tests RENAME COLUMN foo TO baz".into(),
+            "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, baz TEXT);".into(),
+            "CREATE INDEX tests_foo ON tests (baz);".into(),
+        ];
+
+        let (status_code, axum::Json(res)) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(SchemaParams {
+                dry_run: true,
+                allow_destructive: true,
+            }),
+            axum::Json(statements.clone()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
         assert_eq!(
-            row,
-            QueryEvent::Row(
-                RowId(2),
-                vec!["service-id-2".into(), "service-name-2".into()]
-            )
+            res.plan,
+            Some(SchemaPlan {
+                tables_added: vec![],
+                tables_dropped: vec![],
+                columns_added: vec![],
+                columns_dropped: vec![SchemaColumnRef {
+                    table: "tests".into(),
+                    column: "bar".into(),
+                }],
+                columns_renamed: vec![SchemaColumnRename {
+                    table: "tests".into(),
+                    from: "foo".into(),
+                    to: "baz".into(),
+                }],
+                tables_rebuilt: vec!["tests".into()],
+                indexes_added: vec![],
+                indexes_dropped: vec![],
+                indexes_replaced: vec!["tests_foo".into()],
+            })
         );
 
-        buf.extend_from_slice(&body.data().await.unwrap()?);
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(SchemaParams {
+                dry_run: false,
+                allow_destructive: true,
+            }),
+            axum::Json(statements),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
 
-        let s = lines.decode(&mut buf).unwrap().unwrap();
+        {
+            let schema = agent.schema().read();
+            let tests = schema.tables.get("tests").unwrap();
+            assert_eq!(tests.columns.keys().collect::<Vec<_>>(), vec!["id", "baz"]);
 
-        let query_evt: QueryEvent = serde_json::from_str(&s).unwrap();
+            // changes from nodes which haven't migrated yet have somewhere to go
+            assert_eq!(
+                schema.dropped_column("tests", "bar"),
+                Some(DroppedColumn::Dropped)
+            );
+            assert_eq!(
+                schema.dropped_column("tests", "foo"),
+                Some(DroppedColumn::Renamed("baz"))
+            );
+        }
 
-        assert!(matches!(query_evt, QueryEvent::EndOfQuery { .. }));
+        let conn = agent.pool().read().await?;
 
-        assert!(body.data().await.is_none());
+        // and still do after a restart
+        assert_eq!(
+            init_schema(&conn)?.dropped_columns,
+            agent.schema().read().dropped_columns
+        );
+
+        // values of the renamed column were kept
+        let baz: String =
+            conn.query_row("SELECT baz FROM tests WHERE id = 1", [], |row| row.get(0))?;
+        assert_eq!(baz, "hello");
+
+        // so were their clocks, under the new name
+        let clocks: Vec<String> = conn
+            .prepare(
+                "SELECT col_name FROM tests__crsql_clock WHERE col_name != '-1' ORDER BY col_name",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(clocks, vec!["baz".to_string()]);
+
+        // indexes were created again along with the table
+        let indexes: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_schema WHERE type = 'index' AND name = 'tests_foo'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(indexes, 1);
 
         Ok(())
     }
@@ -964,6 +3720,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
             axum::Json(vec![
                 "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
             ]),
@@ -995,6 +3752,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
             axum::Json(vec![
                 "CREATE TABLE tests2 (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
                 "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
@@ -1068,6 +3826,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
             axum::Json(vec![create_stmt.into()]),
         )
         .await;
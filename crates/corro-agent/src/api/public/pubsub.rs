@@ -1,20 +1,42 @@
-use std::{collections::HashMap, io::Write, sync::Arc, time::Duration};
+use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    io::Write,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
-use axum::{http::StatusCode, response::IntoResponse, Extension};
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
 use bytes::{BufMut, Bytes, BytesMut};
 use compact_str::{format_compact, ToCompactString};
 use corro_types::{
     agent::Agent,
-    api::{ChangeId, QueryEvent, QueryEventMeta, Statement},
-    pubsub::{MatcherCreated, MatcherError, MatcherHandle, NormalizeStatementError, SubsManager},
+    api::{
+        CellMeta, ChangeId, CloseReason, ColumnName, QueryEvent, QueryEventMeta, RowId,
+        SqliteValue, Statement,
+    },
+    budget::{BufferKind, MemoryBudget},
+    causality::{row_meta, RowMetaError},
+    config::SubscriptionsConfig,
+    pubsub::{
+        ChangeType, MatcherCreated, MatcherError, MatcherHandle, NormalizeStatementError,
+        SubsManager,
+    },
     sqlite::SqlitePoolError,
 };
 use futures::future::poll_fn;
+use metrics::counter;
+use parking_lot::Mutex;
 use rusqlite::Connection;
 use serde::Deserialize;
+use spawn::{spawn_named, Shutdown};
 use tokio::{
     sync::{
-        broadcast,
+        broadcast::{self, error::RecvError},
         mpsc::{self, error::TryRecvError},
         RwLock as TokioRwLock,
     },
@@ -25,12 +47,472 @@ use tracing::{debug, error, info, warn};
 use tripwire::Tripwire;
 use uuid::Uuid;
 
-#[derive(Clone, Copy, Debug, Default, Deserialize)]
+use crate::api::public::{
+    compression::{with_content_encoding, BodySender, ContentEncoding},
+    encoding::{EncodeError, Encoding},
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct SubParams {
     #[serde(default)]
     from: Option<ChangeId>,
     #[serde(default)]
     skip_rows: bool,
+    /// Only forward changes of this type, the ids of changes left out are
+    /// sent in `skipped` events
+    #[serde(default)]
+    changes: ChangesFilter,
+    /// Comma-separated list of columns to include in rows and changes
+    #[serde(default)]
+    columns: Option<String>,
+    /// Follow rows and changes with the replication metadata of their cells
+    #[serde(default)]
+    meta: bool,
+    /// Follow updates with the values their row had before
+    #[serde(default)]
+    previous: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangesFilter {
+    #[default]
+    All,
+    Upsert,
+    Delete,
+}
+
+impl ChangesFilter {
+    fn matches(&self, change_type: ChangeType) -> bool {
+        match self {
+            ChangesFilter::All => true,
+            ChangesFilter::Upsert => {
+                matches!(change_type, ChangeType::Insert | ChangeType::Update)
+            }
+            ChangesFilter::Delete => change_type == ChangeType::Delete,
+        }
+    }
+}
+
+/// How events are written to a subscription's response body
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventFormat {
+    /// One JSON-encoded event per line
+    #[default]
+    Ndjson,
+    /// Server-Sent Events named after their type, holding the JSON-encoded
+    /// event. Changes and the end of the initial query use their change id
+    /// as the event id, browsers resume from it on reconnection.
+    Sse,
+    /// MessagePack or CBOR encoded events, one after the other
+    Binary(Encoding),
+}
+
+impl EventFormat {
+    const SSE_MEDIA_TYPE: &'static str = "text/event-stream";
+
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let sse = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|accept| accept.split(','))
+            .any(|range| {
+                range.split(';').next().unwrap_or_default().trim() == Self::SSE_MEDIA_TYPE
+            });
+
+        if sse {
+            return EventFormat::Sse;
+        }
+        match Encoding::from_headers(headers) {
+            Encoding::Json => EventFormat::Ndjson,
+            encoding => EventFormat::Binary(encoding),
+        }
+    }
+
+    /// Appends an event, as encoded by `make_query_event_bytes`
+    fn encode(self, buf: &mut BytesMut, event_buf: &Bytes, meta: QueryEventMeta) {
+        match self {
+            EventFormat::Ndjson => buf.extend_from_slice(event_buf),
+            EventFormat::Sse => {
+                buf.put_slice(b"event: ");
+                buf.put_slice(sse_event_name(meta).as_bytes());
+                buf.put_slice(b"\n");
+                if let QueryEventMeta::Change(change_id)
+                | QueryEventMeta::EndOfQuery(Some(change_id))
+                | QueryEventMeta::Skipped(change_id) = meta
+                {
+                    buf.put_slice(format!("id: {change_id}\n").as_bytes());
+                }
+                // serialized JSON never holds a raw new line
+                buf.put_slice(b"data: ");
+                buf.put_slice(event_buf.strip_suffix(b"\n").unwrap_or(event_buf));
+                buf.put_slice(b"\n\n");
+            }
+            EventFormat::Binary(encoding) => {
+                // events are encoded once as JSON, shared by every subscriber
+                let cached = binary_events().lock().get(event_buf, encoding);
+                let encoded = match cached {
+                    Some(encoded) => encoded,
+                    None => {
+                        let mut encoded = BytesMut::new();
+                        let res = serde_json::from_slice::<QueryEvent>(event_buf)
+                            .map_err(EncodeError::from)
+                            .and_then(|evt| encoding.encode_event(&mut encoded, &evt));
+                        if let Err(e) = res {
+                            warn!("could not encode subscription event: {e}");
+                            return;
+                        }
+                        let encoded = encoded.freeze();
+                        binary_events()
+                            .lock()
+                            .insert(event_buf.clone(), encoding, encoded.clone());
+                        encoded
+                    }
+                };
+                buf.extend_from_slice(&encoded);
+            }
+        }
+    }
+
+    fn response_builder(self) -> axum::http::response::Builder {
+        let builder = hyper::Response::builder();
+        match self {
+            EventFormat::Ndjson => builder,
+            EventFormat::Sse => builder
+                .header(header::CONTENT_TYPE, Self::SSE_MEDIA_TYPE)
+                .header(header::CACHE_CONTROL, "no-cache"),
+            EventFormat::Binary(encoding) => match encoding.content_type() {
+                Some(content_type) => builder.header(header::CONTENT_TYPE, content_type),
+                None => builder,
+            },
+        }
+    }
+}
+
+/// Binary encodings kept around, listeners of a subscription are rarely
+/// further apart than this many events
+const BINARY_EVENTS_CACHED: usize = 512;
+
+/// Binary encodings of recent events. Listeners of a subscription share the
+/// JSON bytes of its events, so they're told apart by the allocation of
+/// these bytes and only decoded and encoded once per encoding. Holding the
+/// bytes keeps them from being reused for another event.
+#[derive(Default)]
+struct BinaryEvents {
+    encoded: HashMap<(usize, usize, Encoding), (Bytes, Bytes)>,
+    order: VecDeque<(usize, usize, Encoding)>,
+}
+
+impl BinaryEvents {
+    fn key(event_buf: &Bytes, encoding: Encoding) -> (usize, usize, Encoding) {
+        (event_buf.as_ptr() as usize, event_buf.len(), encoding)
+    }
+
+    fn get(&self, event_buf: &Bytes, encoding: Encoding) -> Option<Bytes> {
+        self.encoded
+            .get(&Self::key(event_buf, encoding))
+            .map(|(_, encoded)| encoded.clone())
+    }
+
+    fn insert(&mut self, event_buf: Bytes, encoding: Encoding, encoded: Bytes) {
+        let key = Self::key(&event_buf, encoding);
+        if self.encoded.insert(key, (event_buf, encoded)).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > BINARY_EVENTS_CACHED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.encoded.remove(&oldest);
+            }
+        }
+    }
+}
+
+fn binary_events() -> &'static Mutex<BinaryEvents> {
+    static BINARY_EVENTS: OnceLock<Mutex<BinaryEvents>> = OnceLock::new();
+    BINARY_EVENTS.get_or_init(Default::default)
+}
+
+fn sse_event_name(meta: QueryEventMeta) -> &'static str {
+    match meta {
+        QueryEventMeta::Columns => "columns",
+        QueryEventMeta::Estimate => "estimate",
+        QueryEventMeta::Progress => "progress",
+        QueryEventMeta::Row(_) => "row",
+        QueryEventMeta::EndOfQuery(_) => "end_of_query",
+        QueryEventMeta::Change(_) => "change",
+        QueryEventMeta::Meta(_) => "meta",
+        QueryEventMeta::Previous(_) => "previous",
+        QueryEventMeta::Resync => "resync",
+        QueryEventMeta::Dropped => "dropped",
+        QueryEventMeta::Skipped(_) => "skipped",
+        QueryEventMeta::Closed => "closed",
+        QueryEventMeta::Moved => "moved",
+        QueryEventMeta::Error => "error",
+    }
+}
+
+/// Change id of the last event an SSE client received, sent when it
+/// reconnects
+fn last_event_id(headers: &HeaderMap) -> Option<ChangeId> {
+    headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|id| id.trim().parse().ok())
+        .map(ChangeId)
+}
+
+/// Per-subscriber filtering of events, applied right before sending them
+/// to the client since the encoded events are shared by every subscriber
+///
+/// Changes left out keep their ids to the ones sent, a `skipped` event
+/// carrying the last left out change id is sent before the next change so
+/// clients can still tell missed changes apart
+#[derive(Debug)]
+struct EventFilter {
+    changes: ChangesFilter,
+    /// indexes of the selected columns, in the query's column order
+    columns: Option<Vec<usize>>,
+    /// last change left out since a change was sent
+    skipped: Option<ChangeId>,
+    buf: BytesMut,
+}
+
+impl EventFilter {
+    fn new(
+        params: &SubParams,
+        col_names: &[ColumnName],
+    ) -> Result<Option<Self>, MatcherUpsertError> {
+        let columns = match params.columns.as_deref() {
+            None => None,
+            Some(columns) => Some(
+                columns
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(|name| {
+                        col_names
+                            .iter()
+                            .position(|col| col.as_str() == name)
+                            .ok_or_else(|| MatcherUpsertError::UnknownColumn(name.into()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+        };
+
+        if params.changes == ChangesFilter::All && columns.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            changes: params.changes,
+            columns,
+            skipped: None,
+            buf: BytesMut::new(),
+        }))
+    }
+
+    fn project<T>(&self, values: Vec<T>) -> Vec<T> {
+        match self.columns.as_ref() {
+            None => values,
+            Some(columns) => {
+                let mut values: Vec<Option<T>> = values.into_iter().map(Some).collect();
+                columns
+                    .iter()
+                    .filter_map(|i| values.get_mut(*i).and_then(Option::take))
+                    .collect()
+            }
+        }
+    }
+
+    /// Returns `None` if the event should be skipped
+    fn apply(&mut self, event_buf: Bytes, meta: QueryEventMeta) -> Option<Bytes> {
+        match meta {
+            QueryEventMeta::Estimate
+            | QueryEventMeta::Progress
+            | QueryEventMeta::EndOfQuery(_)
+            | QueryEventMeta::Resync
+            | QueryEventMeta::Dropped
+            | QueryEventMeta::Skipped(_)
+            | QueryEventMeta::Closed
+            | QueryEventMeta::Moved
+            | QueryEventMeta::Error => return Some(event_buf),
+            QueryEventMeta::Columns | QueryEventMeta::Row(_) if self.columns.is_none() => {
+                return Some(event_buf)
+            }
+            _ => {}
+        }
+
+        let evt: QueryEvent = match serde_json::from_slice(&event_buf) {
+            Ok(evt) => evt,
+            Err(e) => {
+                warn!("could not decode query event for filtering: {e}");
+                return Some(event_buf);
+            }
+        };
+
+        let evt = match evt {
+            QueryEvent::Change(change_type, _, _, change_id, _)
+                if !self.changes.matches(change_type) =>
+            {
+                self.skipped = Some(change_id);
+                return None;
+            }
+            QueryEvent::Change(change_type, rowid, cells, change_id, actor_id) => {
+                QueryEvent::Change(change_type, rowid, self.project(cells), change_id, actor_id)
+            }
+            QueryEvent::Columns(columns) => QueryEvent::Columns(self.project(columns)),
+            QueryEvent::Row(rowid, cells) => QueryEvent::Row(rowid, self.project(cells)),
+            evt => evt,
+        };
+
+        match make_query_event_bytes(&mut self.buf, &evt) {
+            Ok((bytes, _)) => Some(bytes),
+            Err(e) => Some(error_to_query_event_bytes(&mut self.buf, e)),
+        }
+    }
+
+    /// The `skipped` event to send before a change, when changes were left
+    /// out since the last one sent
+    fn take_skipped(&mut self, meta: QueryEventMeta) -> Option<(Bytes, QueryEventMeta)> {
+        if !matches!(meta, QueryEventMeta::Change(_)) {
+            return None;
+        }
+        let change_id = self.skipped.take()?;
+        match make_query_event_bytes(&mut self.buf, &QueryEvent::Skipped { change_id }) {
+            Ok(res) => Some(res),
+            Err(e) => Some((
+                error_to_query_event_bytes(&mut self.buf, e),
+                QueryEventMeta::Error,
+            )),
+        }
+    }
+}
+
+/// Looks up the replication metadata of the rows sent to a subscriber
+struct RowMetaSource {
+    agent: Agent,
+    matcher: MatcherHandle,
+    buf: BytesMut,
+}
+
+impl RowMetaSource {
+    fn new(agent: &Agent, matcher: &MatcherHandle, params: &SubParams) -> Option<Self> {
+        params.meta.then(|| Self {
+            agent: agent.clone(),
+            matcher: matcher.clone(),
+            buf: BytesMut::new(),
+        })
+    }
+
+    /// Encoded `meta` event following a row or a change, if any
+    async fn event_for(
+        &mut self,
+        event_buf: &Bytes,
+        meta: QueryEventMeta,
+    ) -> Option<(Bytes, QueryEventMeta)> {
+        let rowid = match meta {
+            QueryEventMeta::Row(rowid) => rowid,
+            QueryEventMeta::Change(_) => match serde_json::from_slice(event_buf) {
+                // deleted rows have no metadata left to show
+                Ok(QueryEvent::Change(change_type, rowid, ..))
+                    if change_type != ChangeType::Delete =>
+                {
+                    rowid
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let evt = match self.lookup(rowid).await {
+            Ok(cells) => QueryEvent::Meta(rowid, cells),
+            Err(e) => return Some(error_to_query_event_bytes_with_meta(&mut self.buf, e)),
+        };
+
+        match make_query_event_bytes(&mut self.buf, &evt) {
+            Ok(event) => Some(event),
+            Err(e) => Some(error_to_query_event_bytes_with_meta(&mut self.buf, e)),
+        }
+    }
+
+    async fn lookup(&self, rowid: RowId) -> Result<Vec<CellMeta>, CatchUpError> {
+        let row_pks = {
+            let conn = self.matcher.pool().get().await?;
+            block_in_place(|| self.matcher.row_pks(&conn, rowid))?
+        };
+
+        let conn = self.agent.pool().read().await?;
+        block_in_place(|| {
+            let mut cells = vec![];
+            for (tbl_name, pks) in row_pks {
+                let Some(table) = self.agent.schema().read().tables.get(&tbl_name).cloned() else {
+                    continue;
+                };
+                cells.extend(row_meta(&conn, self.agent.bookkeeping(), &table, &pks)?);
+            }
+            Ok(cells)
+        })
+    }
+}
+
+/// Looks up the values rows had before the updates sent to a subscriber
+struct PreviousSource {
+    matcher: MatcherHandle,
+    buf: BytesMut,
+}
+
+impl PreviousSource {
+    /// Matchers only record previous values once a subscriber asks for them
+    fn new(matcher: &MatcherHandle, params: &SubParams) -> Option<Self> {
+        params.previous.then(|| {
+            matcher.keep_previous();
+            Self {
+                matcher: matcher.clone(),
+                buf: BytesMut::new(),
+            }
+        })
+    }
+
+    /// Encoded `previous` event following an update, if any. Cells are
+    /// selected like the update's.
+    async fn event_for(
+        &mut self,
+        event_buf: &Bytes,
+        meta: QueryEventMeta,
+        filter: Option<&EventFilter>,
+    ) -> Option<(Bytes, QueryEventMeta)> {
+        let QueryEventMeta::Change(change_id) = meta else {
+            return None;
+        };
+        let rowid = match serde_json::from_slice(event_buf) {
+            Ok(QueryEvent::Change(ChangeType::Update, rowid, ..)) => rowid,
+            _ => return None,
+        };
+
+        let cells = match self.lookup(change_id).await {
+            Ok(Some(cells)) => cells,
+            Ok(None) => return None,
+            Err(e) => return Some(error_to_query_event_bytes_with_meta(&mut self.buf, e)),
+        };
+        let cells = match filter {
+            Some(filter) => filter.project(cells),
+            None => cells,
+        };
+
+        match make_query_event_bytes(&mut self.buf, &QueryEvent::Previous(rowid, cells)) {
+            Ok(event) => Some(event),
+            Err(e) => Some(error_to_query_event_bytes_with_meta(&mut self.buf, e)),
+        }
+    }
+
+    async fn lookup(&self, change_id: ChangeId) -> Result<Option<Vec<SqliteValue>>, CatchUpError> {
+        let conn = self.matcher.pool().get().await?;
+        Ok(block_in_place(|| {
+            self.matcher.previous_values(&conn, change_id)
+        })?)
+    }
 }
 
 pub async fn api_v1_sub_by_id(
@@ -38,18 +520,45 @@ pub async fn api_v1_sub_by_id(
     Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
     Extension(tripwire): Extension<Tripwire>,
     axum::extract::Path(id): axum::extract::Path<Uuid>,
-    axum::extract::Query(params): axum::extract::Query<SubParams>,
+    headers: HeaderMap,
+    axum::extract::Query(mut params): axum::extract::Query<SubParams>,
 ) -> impl IntoResponse {
-    sub_by_id(agent.subs_manager(), id, params, &bcast_cache, tripwire).await
+    // a reconnecting SSE client knows better than the URL it was given
+    if let Some(change_id) = last_event_id(&headers) {
+        params.from = Some(change_id);
+    }
+    let format = EventFormat::from_headers(&headers);
+    let encoding = ContentEncoding::from_headers(&headers);
+
+    sub_by_id(&agent, id, params, format, encoding, &bcast_cache, tripwire).await
 }
 
 async fn sub_by_id(
-    subs: &SubsManager,
+    agent: &Agent,
     id: Uuid,
     params: SubParams,
+    format: EventFormat,
+    encoding: Option<ContentEncoding>,
     bcast_cache: &SharedMatcherBroadcastCache,
     tripwire: Tripwire,
 ) -> hyper::Response<hyper::Body> {
+    let subs = agent.subs_manager();
+
+    // listeners that missed the hand-off are told where it went
+    if let Some((addr, new_id)) = subs.moved(&id) {
+        let mut buf = BytesMut::new();
+        let moved = QueryEvent::Moved { addr, id: new_id };
+        let (bytes, meta) =
+            make_query_event_bytes(&mut buf, &moved).expect("could not serialize moved event");
+        format.encode(&mut buf, &bytes, meta);
+        return format
+            .response_builder()
+            .status(StatusCode::OK)
+            .header("corro-query-id", id.to_string())
+            .body(buf.freeze().into())
+            .expect("could not build moved response");
+    }
+
     let matcher_rx = bcast_cache.read().await.get(&id).and_then(|tx| {
         subs.get(&id).map(|matcher| {
             debug!("found matcher by id {id}");
@@ -74,7 +583,11 @@ async fn sub_by_id(
                 bcast_cache_write.remove(&id);
                 if let Some(handle) = subs.remove(&id) {
                     info!(sub_id = %id, "Removed subscription from sub_by_id");
-                    tokio::spawn(handle.cleanup());
+                    spawn_named(
+                        "subscription_cleanup",
+                        Shutdown::Abortable,
+                        handle.cleanup(),
+                    );
                 }
 
                 return hyper::Response::builder()
@@ -91,17 +604,40 @@ async fn sub_by_id(
         }
     };
 
-    let (evt_tx, evt_rx) = mpsc::channel(512);
+    if let Some(from) = params.from {
+        if let Err(e) = check_resumable(&matcher, from).await {
+            return not_resumable_response(e);
+        }
+    }
+
+    let filter = match EventFilter::new(&params, matcher.col_names()) {
+        Ok(filter) => filter,
+        Err(e) => return hyper::Response::<hyper::Body>::from(e),
+    };
+    let row_meta = RowMetaSource::new(agent, &matcher, &params);
+    let previous = PreviousSource::new(&matcher, &params);
+
+    let (evt_tx, evt_rx) = sub_event_channel(agent.budget(), 512);
 
-    tokio::spawn(catch_up_sub(matcher, params, rx, evt_tx));
+    spawn_named(
+        "catch_up_sub",
+        Shutdown::Abortable,
+        catch_up_sub(matcher, params, rx, evt_tx),
+    );
 
     let (tx, body) = hyper::Body::channel();
+    let (tx, content_encoding) = BodySender::new(tx, encoding);
 
-    tokio::spawn(forward_bytes_to_body_sender(id, evt_rx, tx, tripwire));
+    spawn_named(
+        "subscription_response_body",
+        Shutdown::Abortable,
+        forward_bytes_to_body_sender(id, evt_rx, tx, format, filter, row_meta, previous, tripwire),
+    );
 
-    hyper::Response::builder()
+    with_content_encoding(format.response_builder(), content_encoding)
         .status(StatusCode::OK)
         .header("corro-query-id", id.to_string())
+        .header("corro-actor-id", agent.actor_id().to_string())
         .body(body)
         .expect("could not build query response body")
 }
@@ -123,27 +659,55 @@ fn make_query_event_bytes(
     Ok((buf.split().freeze(), query_evt.meta()))
 }
 
-const MAX_UNSUB_TIME: Duration = Duration::from_secs(120);
-// this should be a fraction of the MAX_UNSUB_TIME
-const RECEIVERS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// receivers are checked this many times within the idle timeout
+const RECEIVERS_CHECKS_PER_IDLE_TIMEOUT: u32 = 4;
 
 pub async fn process_sub_channel(
     subs: SubsManager,
     id: Uuid,
     tx: broadcast::Sender<(Bytes, QueryEventMeta)>,
     mut evt_rx: mpsc::Receiver<QueryEvent>,
+    config: SubscriptionsConfig,
 ) {
     let mut buf = BytesMut::new();
 
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+
     let mut deadline = if tx.receiver_count() == 0 {
-        Some(Box::pin(tokio::time::sleep(MAX_UNSUB_TIME)))
+        Some(Box::pin(tokio::time::sleep(idle_timeout)))
     } else {
         None
     };
 
+    let lifetime_deadline = async {
+        match config.max_lifetime_secs {
+            Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+            None => futures::future::pending().await,
+        }
+    };
+    tokio::pin!(lifetime_deadline);
+
     // even if there are no more subscribers
     // useful for queries that don't change often so we can cleanup...
-    let mut subs_check = tokio::time::interval(RECEIVERS_CHECK_INTERVAL);
+    let mut subs_check = tokio::time::interval(cmp::max(
+        idle_timeout / RECEIVERS_CHECKS_PER_IDLE_TIMEOUT,
+        Duration::from_secs(1),
+    ));
+
+    let mut handoff = subs.handoff();
+
+    // cancelled when the subscription is deleted through the API
+    let matcher = subs.get(&id);
+    let deleted = async {
+        match matcher.as_ref() {
+            Some(matcher) => matcher.cancelled().await,
+            None => futures::future::pending().await,
+        }
+    };
+    tokio::pin!(deleted);
+
+    // sent to listeners once the subscription ends, rather than an interruption
+    let mut last_event = None;
 
     loop {
         let deadline_check = async {
@@ -159,7 +723,8 @@ pub async fn process_sub_channel(
             Some(query_evt) = evt_rx.recv() => query_evt,
             _ = deadline_check => {
                 if tx.receiver_count() == 0 {
-                    info!(sub_id = %id, "All listeners for subscription are gone and didn't come back within {MAX_UNSUB_TIME:?}");
+                    info!(sub_id = %id, "All listeners for subscription are gone and didn't come back within {idle_timeout:?}");
+                    last_event = Some(QueryEvent::Closed { reason: CloseReason::Idle });
                     break;
                 }
 
@@ -167,16 +732,38 @@ pub async fn process_sub_channel(
                 deadline = None;
                 continue;
             },
+            _ = &mut lifetime_deadline => {
+                info!(sub_id = %id, "Subscription reached its maximum lifetime");
+                last_event = Some(QueryEvent::Closed { reason: CloseReason::MaxLifetime });
+                break;
+            },
+            _ = &mut deleted => {
+                let reason = matcher.as_ref().and_then(|matcher| matcher.close_reason()).unwrap_or(CloseReason::Deleted);
+                info!(sub_id = %id, "Subscription was closed: {reason:?}");
+                last_event = Some(QueryEvent::Closed { reason });
+                break;
+            },
             _ = subs_check.tick() => {
                 if tx.receiver_count() == 0 {
                     if deadline.is_none() {
-                        deadline = Some(Box::pin(tokio::time::sleep(MAX_UNSUB_TIME)));
+                        deadline = Some(Box::pin(tokio::time::sleep(idle_timeout)));
                     }
                 } else {
                     deadline = None;
                 };
                 continue;
             },
+            Ok(()) = handoff.changed() => {
+                let moved = handoff.borrow_and_update().get(&id).copied();
+                match moved {
+                    Some((addr, new_id)) => {
+                        info!(sub_id = %id, %new_id, "Subscription handed off to {addr}");
+                        last_event = Some(QueryEvent::Moved { addr, id: new_id });
+                        break;
+                    }
+                    None => continue,
+                }
+            },
             else => {
                 break;
             }
@@ -198,13 +785,19 @@ pub async fn process_sub_channel(
         } else {
             debug!(sub_id = %id, "no active listeners to receive subscription event: {query_evt:?}");
             if deadline.is_none() {
-                deadline = Some(Box::pin(tokio::time::sleep(MAX_UNSUB_TIME)));
+                deadline = Some(Box::pin(tokio::time::sleep(idle_timeout)));
             }
         }
     }
 
     warn!(sub_id = %id, "subscription query channel done");
 
+    if let Some(last_event) = &last_event {
+        if let Ok(b) = make_query_event_bytes(&mut buf, last_event) {
+            _ = tx.send(b);
+        }
+    }
+
     // remove and get handle from the agent's "matchers"
     let handle = match subs.remove(&id) {
         Some(h) => {
@@ -212,7 +805,15 @@ pub async fn process_sub_channel(
             h
         }
         None => {
-            warn!(sub_id = %id, "subscription handle was already gone. odd!");
+            // deleted subscriptions were already removed
+            if !matches!(
+                last_event,
+                Some(QueryEvent::Closed {
+                    reason: CloseReason::Deleted | CloseReason::TableDropped
+                })
+            ) {
+                warn!(sub_id = %id, "subscription handle was already gone. odd!");
+            }
             return;
         }
     };
@@ -281,18 +882,28 @@ pub enum MatcherUpsertError {
     SubFromWithoutMatcher,
     #[error("found a subscription, but missing broadcaster")]
     MissingBroadcaster,
+    #[error("unknown column '{0}' in `columns` filter")]
+    UnknownColumn(String),
+    #[error(transparent)]
+    CatchUp(#[from] CatchUpError),
 }
 
 impl MatcherUpsertError {
     fn status_code(&self) -> StatusCode {
         match self {
+            MatcherUpsertError::CatchUp(CatchUpError::TooOld { .. }) => StatusCode::GONE,
             MatcherUpsertError::Pool(_)
             | MatcherUpsertError::CouldNotExpand
-            | MatcherUpsertError::MissingBroadcaster => StatusCode::INTERNAL_SERVER_ERROR,
+            | MatcherUpsertError::MissingBroadcaster
+            | MatcherUpsertError::CatchUp(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            MatcherUpsertError::Matcher(MatcherError::TooManySubscriptions(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             MatcherUpsertError::Sqlite(_)
             | MatcherUpsertError::NormalizeStatement(_)
             | MatcherUpsertError::Matcher(_)
-            | MatcherUpsertError::SubFromWithoutMatcher => StatusCode::BAD_REQUEST,
+            | MatcherUpsertError::SubFromWithoutMatcher
+            | MatcherUpsertError::UnknownColumn(_) => StatusCode::BAD_REQUEST,
         }
     }
 }
@@ -326,6 +937,36 @@ pub enum CatchUpError {
     Matcher(#[from] MatcherError),
     #[error(transparent)]
     Join(#[from] JoinError),
+    #[error(transparent)]
+    RowMeta(#[from] RowMetaError),
+    #[error("change {from} is too old, the oldest change kept is {oldest}: subscribe again without `from`")]
+    TooOld { from: ChangeId, oldest: ChangeId },
+}
+
+/// Fails if changes after `from` were purged, the listener has to start
+/// over from the subscription's current rows
+async fn check_resumable(matcher: &MatcherHandle, from: ChangeId) -> Result<(), CatchUpError> {
+    let conn = matcher.pool().get().await?;
+    match block_in_place(|| matcher.min_change_id(&conn))? {
+        Some(oldest) if from.0 + 1 < oldest.0 => Err(CatchUpError::TooOld { from, oldest }),
+        _ => Ok(()),
+    }
+}
+
+/// Response to a listener that can't resume from where it was
+fn not_resumable_response(e: CatchUpError) -> hyper::Response<hyper::Body> {
+    let status = match e {
+        CatchUpError::TooOld { .. } => StatusCode::GONE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    hyper::Response::builder()
+        .status(status)
+        .body(
+            serde_json::to_vec(&QueryEvent::Error(e.to_compact_string()))
+                .expect("could not serialize subscription error")
+                .into(),
+        )
+        .expect("could not build error response")
 }
 
 fn error_to_query_event_bytes<E: ToCompactString>(buf: &mut BytesMut, e: E) -> Bytes {
@@ -352,7 +993,7 @@ fn error_to_query_event_bytes_with_meta<E: ToCompactString>(
 
 async fn catch_up_sub_anew(
     matcher: &MatcherHandle,
-    evt_tx: &mpsc::Sender<(Bytes, QueryEventMeta)>,
+    evt_tx: &SubEventSender,
 ) -> Result<ChangeId, CatchUpError> {
     let (q_tx, mut q_rx) = mpsc::channel(10240);
 
@@ -382,8 +1023,11 @@ async fn catch_up_sub_anew(
 async fn catch_up_sub_from(
     matcher: &MatcherHandle,
     from: ChangeId,
-    evt_tx: &mpsc::Sender<(Bytes, QueryEventMeta)>,
+    evt_tx: &SubEventSender,
 ) -> Result<ChangeId, CatchUpError> {
+    // changes could have been purged since the request was accepted
+    check_resumable(matcher, from).await?;
+
     let (q_tx, mut q_rx) = mpsc::channel(10240);
 
     let task = tokio::spawn({
@@ -413,7 +1057,7 @@ pub async fn catch_up_sub(
     matcher: MatcherHandle,
     params: SubParams,
     mut sub_rx: broadcast::Receiver<(Bytes, QueryEventMeta)>,
-    evt_tx: mpsc::Sender<(Bytes, QueryEventMeta)>,
+    evt_tx: SubEventSender,
 ) {
     debug!("catching up sub {} params: {:?}", matcher.id(), params);
 
@@ -429,13 +1073,22 @@ pub async fn catch_up_sub(
     let queue_task = tokio::spawn({
         let cancel = cancel.clone();
         async move {
+            // events missed while buffering, reported once caught up
+            let mut dropped = 0;
             loop {
-                let (buf, meta) = tokio::select! {
+                let res = tokio::select! {
                     _ = cancel.cancelled() => {
                         break;
                     },
-                    Ok(res) = sub_rx.recv() => res,
-                    else => break
+                    res = sub_rx.recv() => res,
+                };
+                let (buf, meta) = match res {
+                    Ok(res) => res,
+                    Err(RecvError::Lagged(count)) => {
+                        dropped += count;
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
                 };
 
                 if let QueryEventMeta::Change(change_id) = meta {
@@ -444,7 +1097,7 @@ pub async fn catch_up_sub(
                     }
                 }
             }
-            Ok(sub_rx)
+            Ok((sub_rx, dropped))
         }
     });
 
@@ -519,7 +1172,8 @@ pub async fn catch_up_sub(
     if let Some(change_id) = last_sub_change_id {
         debug!(sub_id = %matcher.id(), "got a change to check: {change_id:?}");
         for i in 0..5 {
-            if change_id > min_change_id {
+            // the gap shrinks with every catch up
+            if change_id > last_change_id + 1 {
                 // missed some updates!
                 info!(sub_id = %matcher.id(), "attempt #{} to catch up subcription from change id: {change_id:?} (last: {last_change_id:?})", i+1);
 
@@ -542,7 +1196,7 @@ pub async fn catch_up_sub(
             // sleep 100 millis
             tokio::time::sleep(Duration::from_millis(100)).await;
         }
-        if change_id > min_change_id {
+        if change_id > last_change_id + 1 {
             _ = evt_tx
                 .send(error_to_query_event_bytes_with_meta(
                     &mut buf,
@@ -586,8 +1240,8 @@ pub async fn catch_up_sub(
         }
     }
 
-    let sub_rx = match queue_task.await {
-        Ok(Ok(sub_rx)) => sub_rx,
+    let (sub_rx, dropped) = match queue_task.await {
+        Ok(Ok(res)) => res,
         Ok(Err(e)) => {
             _ = evt_tx
                 .send(error_to_query_event_bytes_with_meta(&mut buf, e))
@@ -602,6 +1256,13 @@ pub async fn catch_up_sub(
         }
     };
 
+    if dropped > 0 {
+        warn!(sub_id = %matcher.id(), "subscriber missed {dropped} events while catching up");
+        if let Err(_e) = send_dropped(&mut buf, dropped, &evt_tx).await {
+            return;
+        }
+    }
+
     forward_sub_to_sender(matcher, sub_rx, evt_tx, params.skip_rows).await
 }
 
@@ -611,7 +1272,8 @@ pub async fn upsert_sub(
     subs: &SubsManager,
     bcast_write: &mut MatcherBroadcastCache,
     params: SubParams,
-    tx: mpsc::Sender<(Bytes, QueryEventMeta)>,
+    tx: SubEventSender,
+    config: SubscriptionsConfig,
 ) -> Result<Uuid, MatcherUpsertError> {
     if let Some(created) = maybe_created {
         if params.from.is_some() {
@@ -620,21 +1282,19 @@ pub async fn upsert_sub(
 
         let (sub_tx, sub_rx) = broadcast::channel(10240);
 
-        tokio::spawn(forward_sub_to_sender(
-            handle.clone(),
-            sub_rx,
-            tx,
-            params.skip_rows,
-        ));
+        spawn_named(
+            "forward_sub_to_sender",
+            Shutdown::Abortable,
+            forward_sub_to_sender(handle.clone(), sub_rx, tx, params.skip_rows),
+        );
 
         bcast_write.insert(handle.id(), sub_tx.clone());
 
-        tokio::spawn(process_sub_channel(
-            subs.clone(),
-            handle.id(),
-            sub_tx,
-            created.evt_rx,
-        ));
+        spawn_named(
+            "process_sub_channel",
+            Shutdown::Abortable,
+            process_sub_channel(subs.clone(), handle.id(), sub_tx, created.evt_rx, config),
+        );
 
         Ok(handle.id())
     } else {
@@ -645,7 +1305,11 @@ pub async fn upsert_sub(
             .ok_or(MatcherUpsertError::MissingBroadcaster)?;
         debug!("found matcher handle");
 
-        tokio::spawn(catch_up_sub(handle, params, sub_tx.subscribe(), tx));
+        spawn_named(
+            "catch_up_sub",
+            Shutdown::Abortable,
+            catch_up_sub(handle, params, sub_tx.subscribe(), tx),
+        );
 
         Ok(id)
     }
@@ -655,9 +1319,99 @@ pub async fn api_v1_subs(
     Extension(agent): Extension<Agent>,
     Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
     Extension(tripwire): Extension<Tripwire>,
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<SubParams>,
+    axum::extract::Json(stmt): axum::extract::Json<Statement>,
+) -> impl IntoResponse {
+    let format = EventFormat::from_headers(&headers);
+    let encoding = ContentEncoding::from_headers(&headers);
+    subscribe(
+        agent,
+        bcast_cache,
+        tripwire,
+        params,
+        format,
+        encoding,
+        stmt,
+        false,
+    )
+    .await
+}
+
+/// Ends a subscription and deletes its state, its listeners get a `closed`
+/// event
+pub async fn api_v1_watch_delete(
+    Extension(agent): Extension<Agent>,
+    Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> hyper::Response<hyper::Body> {
+    let handle = {
+        let mut bcast_cache_write = bcast_cache.write().await;
+        bcast_cache_write.remove(&id);
+        agent.subs_manager().remove(&id)
+    };
+
+    let Some(handle) = handle else {
+        return hyper::Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(
+                serde_json::to_vec(&QueryEvent::Error(format_compact!(
+                    "could not find subscription with id {id}"
+                )))
+                .expect("could not serialize subscription deletion error")
+                .into(),
+            )
+            .expect("could not build error response");
+    };
+
+    info!(sub_id = %id, "Deleting subscription through the API");
+    counter!("corro.subs.deleted").increment(1);
+    // the matcher removes its database once it's done
+    handle.cleanup().await;
+
+    hyper::Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(hyper::Body::empty())
+        .expect("could not build subscription deletion response")
+}
+
+/// Subscribe to a query with an id derived from its normalized SQL, so every
+/// client subscribing to the same query shares one subscription and knows
+/// its id upfront
+pub async fn api_v1_subs_by_hash(
+    Extension(agent): Extension<Agent>,
+    Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
+    Extension(tripwire): Extension<Tripwire>,
+    headers: HeaderMap,
     axum::extract::Query(params): axum::extract::Query<SubParams>,
     axum::extract::Json(stmt): axum::extract::Json<Statement>,
 ) -> impl IntoResponse {
+    let format = EventFormat::from_headers(&headers);
+    let encoding = ContentEncoding::from_headers(&headers);
+    subscribe(
+        agent,
+        bcast_cache,
+        tripwire,
+        params,
+        format,
+        encoding,
+        stmt,
+        true,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn subscribe(
+    agent: Agent,
+    bcast_cache: SharedMatcherBroadcastCache,
+    tripwire: Tripwire,
+    params: SubParams,
+    format: EventFormat,
+    encoding: Option<ContentEncoding>,
+    stmt: Statement,
+    by_hash: bool,
+) -> hyper::Response<hyper::Body> {
     let stmt = match expand_sql(&agent, &stmt).await {
         Ok(stmt) => stmt,
         Err(e) => return hyper::Response::<hyper::Body>::from(e),
@@ -669,28 +1423,62 @@ pub async fn api_v1_subs(
 
     let subs = agent.subs_manager();
 
-    let upsert_res = subs.get_or_insert(
-        &stmt,
-        &agent.config().db.subscriptions_path(),
-        &agent.schema().read(),
-        agent.pool(),
-        tripwire.clone(),
-    );
+    let upsert_res = if by_hash {
+        subs.get_or_insert_by_hash(
+            &stmt,
+            &agent.config().db.subscriptions_path(),
+            &agent.schema().read(),
+            agent.pool(),
+            tripwire.clone(),
+        )
+    } else {
+        subs.get_or_insert(
+            &stmt,
+            &agent.config().db.subscriptions_path(),
+            &agent.schema().read(),
+            agent.pool(),
+            tripwire.clone(),
+        )
+    };
 
     let (handle, maybe_created) = match upsert_res {
         Ok(res) => res,
         Err(e) => return hyper::Response::<hyper::Body>::from(MatcherUpsertError::from(e)),
     };
 
+    // new subscriptions have no changes to resume from yet
+    if let (Some(from), None) = (params.from, &maybe_created) {
+        if let Err(e) = check_resumable(&handle, from).await {
+            return not_resumable_response(e);
+        }
+    }
+
+    let filter = match EventFilter::new(&params, handle.col_names()) {
+        Ok(filter) => filter,
+        Err(e) => return hyper::Response::<hyper::Body>::from(e),
+    };
+    let row_meta = RowMetaSource::new(&agent, &handle, &params);
+    let previous = PreviousSource::new(&handle, &params);
+    let query_hash = handle.hash().to_owned();
+
     let (tx, body) = hyper::Body::channel();
-    let (forward_tx, forward_rx) = mpsc::channel(10240);
+    let (tx, content_encoding) = BodySender::new(tx, encoding);
+    let (forward_tx, forward_rx) = sub_event_channel(agent.budget(), 10240);
 
-    tokio::spawn(forward_bytes_to_body_sender(
-        handle.id(),
-        forward_rx,
-        tx,
-        tripwire,
-    ));
+    spawn_named(
+        "subscription_response_body",
+        Shutdown::Abortable,
+        forward_bytes_to_body_sender(
+            handle.id(),
+            forward_rx,
+            tx,
+            format,
+            filter,
+            row_meta,
+            previous,
+            tripwire,
+        ),
+    );
 
     let matcher_id = match upsert_sub(
         handle,
@@ -699,6 +1487,7 @@ pub async fn api_v1_subs(
         &mut bcast_write,
         params,
         forward_tx,
+        agent.config().subscriptions.clone(),
     )
     .await
     {
@@ -706,33 +1495,212 @@ pub async fn api_v1_subs(
         Err(e) => return hyper::Response::<hyper::Body>::from(e),
     };
 
-    hyper::Response::builder()
+    with_content_encoding(format.response_builder(), content_encoding)
         .status(StatusCode::OK)
         .header("corro-query-id", matcher_id.to_string())
+        .header("corro-query-hash", query_hash)
+        .header("corro-actor-id", agent.actor_id().to_string())
         .body(body)
         .expect("could not generate ok http response for query request")
 }
 
+/// Subscribe to several queries at once, all hydrated from the same snapshot
+/// of the database. Events of every subscription are streamed in the same
+/// response, tagged with the id of the subscription they belong to.
+pub async fn api_v1_watch_groups(
+    Extension(agent): Extension<Agent>,
+    Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
+    Extension(tripwire): Extension<Tripwire>,
+    headers: HeaderMap,
+    axum::extract::Json(stmts): axum::extract::Json<Vec<Statement>>,
+) -> hyper::Response<hyper::Body> {
+    let mut sqls = Vec::with_capacity(stmts.len());
+    for stmt in stmts.iter() {
+        match expand_sql(&agent, stmt).await {
+            Ok(sql) => sqls.push(sql),
+            Err(e) => return hyper::Response::<hyper::Body>::from(e),
+        }
+    }
+
+    info!("Received watch group request for {} queries", sqls.len());
+
+    let mut bcast_write = bcast_cache.write().await;
+
+    let subs = agent.subs_manager();
+
+    let group = match subs.insert_group(
+        &sqls,
+        &agent.config().db.subscriptions_path(),
+        &agent.schema().read(),
+        agent.pool(),
+        tripwire.clone(),
+    ) {
+        Ok(group) => group,
+        Err(e) => return hyper::Response::<hyper::Body>::from(MatcherUpsertError::from(e)),
+    };
+
+    let (tx, body) = hyper::Body::channel();
+    let (tx, content_encoding) = BodySender::new(tx, ContentEncoding::from_headers(&headers));
+    let (group_tx, group_rx) = sub_event_channel(agent.budget(), 10240);
+
+    let mut ids = Vec::with_capacity(group.len());
+    for (handle, created) in group {
+        let (forward_tx, forward_rx) = sub_event_channel(agent.budget(), 10240);
+
+        spawn_named(
+            "watch_group_member",
+            Shutdown::Abortable,
+            tag_group_events(handle.id(), forward_rx, group_tx.clone()),
+        );
+
+        match upsert_sub(
+            handle,
+            Some(created),
+            subs,
+            &mut bcast_write,
+            SubParams::default(),
+            forward_tx,
+            agent.config().subscriptions.clone(),
+        )
+        .await
+        {
+            Ok(id) => ids.push(id.to_string()),
+            Err(e) => return hyper::Response::<hyper::Body>::from(e),
+        }
+    }
+    drop(group_tx);
+
+    spawn_named(
+        "watch_group_response_body",
+        Shutdown::Abortable,
+        forward_group_to_body_sender(group_rx, tx, tripwire),
+    );
+
+    with_content_encoding(hyper::Response::builder(), content_encoding)
+        .status(StatusCode::OK)
+        .header("corro-query-ids", ids.join(","))
+        .header("corro-actor-id", agent.actor_id().to_string())
+        .body(body)
+        .expect("could not generate ok http response for watch group request")
+}
+
+/// Wraps the events of a subscription as
+/// `{"id":"<subscription id>","event":<event>}`, for responses streaming the
+/// events of several subscriptions
+pub async fn tag_group_events(id: Uuid, mut rx: SubEventReceiver, tx: SubEventSender) {
+    let prefix = format!("{{\"id\":\"{id}\",\"event\":");
+    let mut buf = BytesMut::new();
+
+    while let Some((event_buf, meta)) = rx.recv().await {
+        // every event is a line of JSON
+        buf.extend_from_slice(prefix.as_bytes());
+        buf.extend_from_slice(event_buf.strip_suffix(b"\n").unwrap_or(&event_buf));
+        buf.extend_from_slice(b"}\n");
+
+        if tx.send((buf.split().freeze(), meta)).await.is_err() {
+            debug!(sub_id = %id, "watch group response is gone");
+            break;
+        }
+    }
+}
+
+async fn forward_group_to_body_sender(
+    mut rx: SubEventReceiver,
+    mut tx: BodySender,
+    mut tripwire: Tripwire,
+) {
+    loop {
+        let event_buf = tokio::select! {
+            biased;
+            Some((event_buf, _)) = rx.recv() => event_buf,
+            _ = &mut tripwire => break,
+            else => break,
+        };
+
+        if let Err(e) = tx.send_data(event_buf).await {
+            warn!("could not forward watch group event to receiver: {e}");
+            return;
+        }
+    }
+    tx.finish().await;
+}
+
+/// Subscribe a consumer living in the agent to a query, it receives events
+/// as they'd be written to a subscription's response body
+pub async fn subscribe_receiver(
+    agent: &Agent,
+    bcast_cache: &SharedMatcherBroadcastCache,
+    tripwire: Tripwire,
+    stmt: &Statement,
+    params: SubParams,
+) -> Result<(Uuid, SubEventReceiver), MatcherUpsertError> {
+    let stmt = expand_sql(agent, stmt).await?;
+
+    let mut bcast_write = bcast_cache.write().await;
+
+    let subs = agent.subs_manager();
+    let (handle, maybe_created) = subs.get_or_insert(
+        &stmt,
+        &agent.config().db.subscriptions_path(),
+        &agent.schema().read(),
+        agent.pool(),
+        tripwire,
+    )?;
+
+    // new subscriptions have no changes to resume from yet
+    if let (Some(from), None) = (params.from, &maybe_created) {
+        check_resumable(&handle, from).await?;
+    }
+
+    let (tx, rx) = sub_event_channel(agent.budget(), 10240);
+
+    let id = upsert_sub(
+        handle,
+        maybe_created,
+        subs,
+        &mut bcast_write,
+        params,
+        tx,
+        agent.config().subscriptions.clone(),
+    )
+    .await?;
+
+    Ok((id, rx))
+}
+
 const MAX_EVENTS_BUFFER_SIZE: usize = 1024;
 
 async fn forward_sub_to_sender(
     handle: MatcherHandle,
     mut sub_rx: broadcast::Receiver<(Bytes, QueryEventMeta)>,
-    tx: mpsc::Sender<(Bytes, QueryEventMeta)>,
+    tx: SubEventSender,
     skip_rows: bool,
 ) {
     info!(sub_id = %handle.id(), "forwarding subscription events to a sender");
 
+    let mut buf = BytesMut::new();
+
     loop {
-        let (event_buf, meta) = tokio::select! {
-            Ok((event_buf, meta)) = sub_rx.recv() => {
-                (event_buf, meta)
-            },
+        let res = tokio::select! {
+            // events sent right before cancellation still need to go out
+            biased;
+            res = sub_rx.recv() => res,
             _ = handle.cancelled() => {
                 info!(sub_id = %handle.id(), "subscription cancelled, aborting forwarding bytes to subscriber");
                 return;
             },
-            else => {
+        };
+        let (event_buf, meta) = match res {
+            Ok(res) => res,
+            Err(RecvError::Lagged(count)) => {
+                warn!(sub_id = %handle.id(), "subscriber lagged behind, {count} events were dropped");
+                if let Err(e) = send_dropped(&mut buf, count, &tx).await {
+                    warn!(sub_id = %handle.id(), "could not send subscription event to channel: {e}");
+                    return;
+                }
+                continue;
+            }
+            Err(RecvError::Closed) => {
                 info!(sub_id = %handle.id(), "events subcription ran out");
                 return;
             }
@@ -746,6 +1714,18 @@ async fn forward_sub_to_sender(
         {
             continue;
         }
+        if tx.is_lagging() {
+            // the subscriber can resume from its last change id
+            warn!(sub_id = %handle.id(), "subscriber is too slow, subscription events are over their memory budget");
+            counter!("corro.subs.shed").increment(1);
+            _ = tx
+                .send(error_to_query_event_bytes_with_meta(
+                    &mut buf,
+                    "subscriber is too slow, events exceeded their memory budget",
+                ))
+                .await;
+            return;
+        }
         if let Err(e) = tx.send((event_buf, meta)).await {
             warn!(sub_id = %handle.id(), "could not send subscription event to channel: {e}");
             return;
@@ -753,10 +1733,97 @@ async fn forward_sub_to_sender(
     }
 }
 
+/// Lets a subscriber know it missed `count` events, so it doesn't silently
+/// diverge from the subscription
+async fn send_dropped(
+    buf: &mut BytesMut,
+    count: u64,
+    tx: &SubEventSender,
+) -> Result<(), mpsc::error::SendError<(Bytes, QueryEventMeta)>> {
+    counter!("corro.subs.dropped.events").increment(count);
+    let evt = make_query_event_bytes(buf, &QueryEvent::Dropped { count })
+        .expect("could not serialize dropped event");
+    tx.send(evt).await
+}
+
+/// Sends events to a subscriber, accounting for them in the memory budget
+/// until they're written to its response body
+#[derive(Clone)]
+pub struct SubEventSender {
+    tx: mpsc::Sender<(Bytes, QueryEventMeta)>,
+    budget: MemoryBudget,
+}
+
+impl SubEventSender {
+    pub async fn send(
+        &self,
+        evt: (Bytes, QueryEventMeta),
+    ) -> Result<(), mpsc::error::SendError<(Bytes, QueryEventMeta)>> {
+        let len = evt.0.len();
+        self.budget.reserve(BufferKind::Subscriptions, len);
+        self.tx.send(evt).await.map_err(|e| {
+            self.budget.release(BufferKind::Subscriptions, len);
+            e
+        })
+    }
+
+    /// Whether the budget is exhausted while this subscriber has events it
+    /// hasn't received yet, subscribers keeping up are never shed
+    pub fn is_lagging(&self) -> bool {
+        self.budget.is_exhausted(BufferKind::Subscriptions)
+            && self.tx.capacity() < self.tx.max_capacity()
+    }
+}
+
+pub struct SubEventReceiver {
+    rx: mpsc::Receiver<(Bytes, QueryEventMeta)>,
+    budget: MemoryBudget,
+}
+
+impl SubEventReceiver {
+    pub async fn recv(&mut self) -> Option<(Bytes, QueryEventMeta)> {
+        let evt = self.rx.recv().await?;
+        self.budget.release(BufferKind::Subscriptions, evt.0.len());
+        Some(evt)
+    }
+}
+
+impl Drop for SubEventReceiver {
+    fn drop(&mut self) {
+        self.rx.close();
+        while let Ok((event_buf, _)) = self.rx.try_recv() {
+            self.budget
+                .release(BufferKind::Subscriptions, event_buf.len());
+        }
+    }
+}
+
+pub fn sub_event_channel(
+    budget: &MemoryBudget,
+    capacity: usize,
+) -> (SubEventSender, SubEventReceiver) {
+    let (tx, rx) = mpsc::channel(capacity);
+    (
+        SubEventSender {
+            tx,
+            budget: budget.clone(),
+        },
+        SubEventReceiver {
+            rx,
+            budget: budget.clone(),
+        },
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn forward_bytes_to_body_sender(
     sub_id: Uuid,
-    mut rx: mpsc::Receiver<(Bytes, QueryEventMeta)>,
-    mut tx: hyper::body::Sender,
+    mut rx: SubEventReceiver,
+    mut tx: BodySender,
+    format: EventFormat,
+    mut filter: Option<EventFilter>,
+    mut row_meta: Option<RowMetaSource>,
+    mut previous: Option<PreviousSource>,
     mut tripwire: Tripwire,
 ) {
     let mut buf = BytesMut::new();
@@ -786,7 +1853,33 @@ async fn forward_bytes_to_body_sender(
                         // do nothing
                     }
                 }
-                buf.extend_from_slice(&event_buf);
+                let event_buf = match filter.as_mut() {
+                    Some(filter) => match filter.apply(event_buf, meta) {
+                        Some(event_buf) => {
+                            if let Some((skipped_buf, skipped_meta)) = filter.take_skipped(meta) {
+                                format.encode(&mut buf, &skipped_buf, skipped_meta);
+                            }
+                            event_buf
+                        }
+                        None => continue,
+                    },
+                    None => event_buf,
+                };
+                format.encode(&mut buf, &event_buf, meta);
+                if let Some(previous) = previous.as_mut() {
+                    if let Some((previous_buf, previous_meta)) = previous.event_for(&event_buf, meta, filter.as_ref()).await {
+                        format.encode(&mut buf, &previous_buf, previous_meta);
+                    }
+                }
+                if let Some(row_meta) = row_meta.as_mut() {
+                    if let Some((meta_buf, meta_meta)) = row_meta.event_for(&event_buf, meta).await {
+                        format.encode(&mut buf, &meta_buf, meta_meta);
+                    }
+                }
+                if matches!(meta, QueryEventMeta::Closed | QueryEventMeta::Moved) {
+                    // last event of the subscription, flushed below
+                    break;
+                }
                 if buf.len() >= 64*1024 {
                     buf.split().freeze()
                 } else {
@@ -819,16 +1912,20 @@ async fn forward_bytes_to_body_sender(
     if !buf.is_empty() {
         if let Err(e) = tx.send_data(buf.freeze()).await {
             warn!(%sub_id, "could not forward last subscription query event to receiver: {e}");
+            return;
         }
     }
+    tx.finish().await;
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
+
     use corro_types::{
-        api::{ChangeId, RowId},
+        api::{ChangeId, RowId, SqliteValue},
         config::Config,
-        pubsub::ChangeType,
+        pubsub::{ChangeType, Handoff, Matcher},
     };
     use http_body::Body;
     use tokio_util::codec::{Decoder, LinesCodec};
@@ -859,8 +1956,12 @@ mod tests {
         )
         .await?;
 
+        // every change is written through this node
+        let actor_id = Some(agent.actor_id().to_compact_string());
+
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
             axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
         )
         .await;
@@ -869,16 +1970,21 @@ mod tests {
 
         let (status_code, body) = api_v1_transactions(
             Extension(agent.clone()),
-            axum::Json(vec![
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id".into(), "service-name".into()],
-                ),
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-2".into(), "service-name-2".into()],
-                ),
-            ]),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
         )
         .await;
 
@@ -893,6 +1999,7 @@ mod tests {
                 Extension(agent.clone()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                Default::default(),
                 axum::extract::Query(SubParams::default()),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
@@ -908,10 +2015,15 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-3".into(), "service-name-3".into()],
-                )]),
+                Default::default(),
+                axum::extract::Query(Default::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-3".into(), "service-name-3".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -953,16 +2065,22 @@ mod tests {
                     ChangeType::Insert,
                     RowId(3),
                     vec!["service-id-3".into(), "service-name-3".into()],
-                    ChangeId(1)
+                    ChangeId(1),
+                    actor_id.clone(),
                 )
             );
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-4".into(), "service-name-4".into()],
-                )]),
+                Default::default(),
+                axum::extract::Query(Default::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-4".into(), "service-name-4".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -974,7 +2092,8 @@ mod tests {
                     ChangeType::Insert,
                     RowId(4),
                     vec!["service-id-4".into(), "service-name-4".into()],
-                    ChangeId(2)
+                    ChangeId(2),
+                    actor_id.clone(),
                 )
             );
 
@@ -982,6 +2101,7 @@ mod tests {
                 Extension(agent.clone()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                Default::default(),
                 axum::extract::Query(SubParams {
                     from: Some(1.into()),
                     ..Default::default()
@@ -1011,16 +2131,22 @@ mod tests {
                     ChangeType::Insert,
                     RowId(4),
                     vec!["service-id-4".into(), "service-name-4".into()],
-                    ChangeId(2)
+                    ChangeId(2),
+                    actor_id.clone(),
                 )
             );
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-5".into(), "service-name-5".into()],
-                )]),
+                Default::default(),
+                axum::extract::Query(Default::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-5".into(), "service-name-5".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -1031,6 +2157,7 @@ mod tests {
                 RowId(5),
                 vec!["service-id-5".into(), "service-name-5".into()],
                 ChangeId(3),
+                actor_id.clone(),
             );
 
             assert_eq!(rows.recv().await.unwrap().unwrap(), query_evt);
@@ -1043,6 +2170,7 @@ mod tests {
                 Extension(agent.clone()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                Default::default(),
                 axum::extract::Query(SubParams::default()),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
@@ -1112,6 +2240,7 @@ mod tests {
             Extension(agent.clone()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            Default::default(),
             axum::extract::Query(SubParams {
                 from: Some(1.into()),
                 ..Default::default()
@@ -1141,7 +2270,8 @@ mod tests {
                 ChangeType::Insert,
                 RowId(4),
                 vec!["service-id-4".into(), "service-name-4".into()],
-                ChangeId(2)
+                ChangeId(2),
+                actor_id.clone(),
             )
         );
 
@@ -1152,6 +2282,7 @@ mod tests {
                 RowId(5),
                 vec!["service-id-5".into(), "service-name-5".into()],
                 ChangeId(3),
+                actor_id.clone(),
             )
         );
 
@@ -1161,6 +2292,7 @@ mod tests {
             Extension(agent.clone()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            Default::default(),
             axum::extract::Query(SubParams {
                 skip_rows: true,
                 ..Default::default()
@@ -1186,10 +2318,15 @@ mod tests {
 
         let (status_code, _) = api_v1_transactions(
             Extension(agent.clone()),
-            axum::Json(vec![Statement::WithParams(
-                "insert into tests (id, text) values (?,?)".into(),
-                vec!["service-id-6".into(), "service-name-6".into()],
-            )]),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec!["service-id-6".into(), "service-name-6".into()],
+                )]
+                .into(),
+            ),
         )
         .await;
 
@@ -1202,6 +2339,7 @@ mod tests {
                 RowId(6),
                 vec!["service-id-6".into(), "service-name-6".into()],
                 ChangeId(4),
+                actor_id.clone(),
             )
         );
 
@@ -1211,9 +2349,11 @@ mod tests {
             Extension(agent.clone()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            Default::default(),
             axum::extract::Query(SubParams {
                 skip_rows: true,
                 from: Some(ChangeId(3)),
+                ..Default::default()
             }),
             axum::Json(Statement::Simple("select * from tests".into())),
         )
@@ -1241,58 +2381,766 @@ mod tests {
                 RowId(6),
                 vec!["service-id-6".into(), "service-name-6".into()],
                 ChangeId(4),
+                actor_id.clone(),
             )
         );
 
         Ok(())
     }
 
-    struct RowsIter {
-        body: axum::body::BoxBody,
-        codec: LinesCodec,
-        buf: BytesMut,
-        done: bool,
-    }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_resume_purged() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
 
-    impl RowsIter {
-        async fn recv(&mut self) -> Option<eyre::Result<QueryEvent>> {
-            if self.done {
-                return None;
-            }
-            loop {
-                match self.codec.decode(&mut self.buf) {
-                    Ok(Some(line)) => match serde_json::from_str(&line) {
-                        Ok(res) => return Some(Ok(res)),
-                        Err(e) => {
-                            self.done = true;
-                            return Some(Err(e.into()));
-                        }
-                    },
-                    Ok(None) => {
-                        // fall through
-                    }
-                    Err(e) => {
-                        self.done = true;
-                        return Some(Err(e.into()));
-                    }
-                }
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
 
-                let bytes_res = self.body.data().await;
-                match bytes_res {
-                    Some(Ok(b)) => {
-                        // debug!("read {} bytes", b.len());
-                        self.buf.extend_from_slice(&b)
-                    }
-                    Some(Err(e)) => {
-                        self.done = true;
-                        return Some(Err(e.into()));
-                    }
-                    None => {
-                        self.done = true;
-                        return None;
-                    }
-                }
-            }
-        }
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let subscribe = |from: ChangeId| {
+            api_v1_subs(
+                Extension(agent.clone()),
+                Extension(bcast_cache.clone()),
+                Extension(tripwire.clone()),
+                Default::default(),
+                axum::extract::Query(SubParams {
+                    from: Some(from),
+                    ..Default::default()
+                }),
+                axum::Json(Statement::Simple("select * from tests".into())),
+            )
+        };
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Default::default(),
+            axum::extract::Query(SubParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Columns(vec!["id".into(), "text".into()])
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        for i in 1..=4 {
+            let (status_code, _) = api_v1_transactions(
+                Extension(agent.clone()),
+                Default::default(),
+                axum::extract::Query(Default::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec![format!("service-id-{i}").into(), "service-name".into()],
+                    )]
+                    .into(),
+                ),
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+
+            assert!(matches!(
+                rows.recv().await.unwrap().unwrap(),
+                QueryEvent::Change(ChangeType::Insert, _, _, change_id, _) if change_id == ChangeId(i)
+            ));
+        }
+
+        // changes before the 4th are purged
+        let id = agent
+            .subs_manager()
+            .get_by_query("select * from tests")
+            .unwrap()
+            .id();
+        rusqlite::Connection::open(Matcher::sub_db_path(
+            &agent.config().db.subscriptions_path(),
+            id,
+        ))?
+        .execute("DELETE FROM changes WHERE id < 4", [])?;
+
+        // the listener would miss the 3rd change, it has to start over
+        let res = subscribe(ChangeId(2)).await.into_response();
+        assert_eq!(res.status(), StatusCode::GONE);
+
+        // nothing it needs was purged
+        let res = subscribe(ChangeId(3)).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_hand_off() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Default::default(),
+            axum::extract::Query(SubParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Columns(vec!["id".into(), "text".into()])
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        // hand-off to another node: listeners are told where it went
+
+        let id = agent
+            .subs_manager()
+            .get_by_query("select * from tests")
+            .unwrap()
+            .id();
+        let addr: SocketAddr = "127.0.0.1:8081".parse()?;
+        let new_id = Uuid::new_v4();
+
+        let moved = agent.subs_manager().hand_off(Handoff {
+            addr,
+            ids: [(id, new_id), (Uuid::new_v4(), Uuid::new_v4())].into(),
+        });
+        assert_eq!(moved, 1);
+
+        let moved_evt = QueryEvent::Moved { addr, id: new_id };
+        assert_eq!(rows.recv().await.unwrap().unwrap(), moved_evt);
+        assert!(rows.recv().await.is_none());
+
+        // a later hand-off doesn't forget about earlier ones
+        let moved = agent.subs_manager().hand_off(Handoff {
+            addr: "127.0.0.1:8082".parse()?,
+            ids: [(Uuid::new_v4(), Uuid::new_v4())].into(),
+        });
+        assert_eq!(moved, 0);
+
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Path(id),
+            HeaderMap::new(),
+            axum::extract::Query(SubParams::default()),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(rows.recv().await.unwrap().unwrap(), moved_evt);
+        assert!(rows.recv().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_binary() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |id: &str| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                Default::default(),
+                axum::extract::Query(Default::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec![id.into(), "service-name".into()],
+                    )]
+                    .into(),
+                ),
+            )
+        };
+
+        let (status_code, _) = insert("service-id").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "application/msgpack".parse()?);
+
+        let mut listeners = vec![];
+        for _ in 0..2 {
+            let res = api_v1_subs(
+                Extension(agent.clone()),
+                Extension(bcast_cache.clone()),
+                Extension(tripwire.clone()),
+                headers.clone(),
+                axum::extract::Query(SubParams::default()),
+                axum::Json(Statement::Simple("select * from tests".into())),
+            )
+            .await
+            .into_response();
+
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[header::CONTENT_TYPE], "application/msgpack");
+            listeners.push((res.into_body(), BytesMut::new()));
+        }
+
+        // MessagePack events are written one after the other
+        async fn next_event(
+            (body, buf): &mut (axum::body::BoxBody, BytesMut),
+        ) -> eyre::Result<QueryEvent> {
+            loop {
+                let mut reader = &buf[..];
+                if let Ok(evt) = rmp_serde::from_read::<_, QueryEvent>(&mut reader) {
+                    let read = buf.len() - reader.len();
+                    bytes::Buf::advance(buf, read);
+                    return Ok(evt);
+                }
+                let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                    .await?
+                    .ok_or_else(|| eyre::eyre!("subscription body ended"))??;
+                buf.extend_from_slice(&chunk);
+            }
+        }
+
+        for listener in listeners.iter_mut() {
+            assert_eq!(
+                next_event(listener).await?,
+                QueryEvent::Columns(vec!["id".into(), "text".into()])
+            );
+            assert_eq!(
+                next_event(listener).await?,
+                QueryEvent::Row(RowId(1), vec!["service-id".into(), "service-name".into()])
+            );
+            assert!(matches!(
+                next_event(listener).await?,
+                QueryEvent::EndOfQuery { .. }
+            ));
+        }
+
+        let (status_code, _) = insert("service-id-2").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // both listeners get the change, encoded once
+        for listener in listeners.iter_mut() {
+            assert!(matches!(
+                next_event(listener).await?,
+                QueryEvent::Change(ChangeType::Insert, RowId(2), row, ChangeId(1), _)
+                    if row == vec![SqliteValue::from("service-id-2"), SqliteValue::from("service-name")]
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_events() {
+        let mut events = BinaryEvents::default();
+        let mut buf = BytesMut::new();
+        let columns = QueryEvent::Columns(vec!["id".into()]);
+        let (bytes, _) = make_query_event_bytes(&mut buf, &columns).unwrap();
+        let encoded = Bytes::from_static(b"encoded");
+
+        events.insert(bytes.clone(), Encoding::MsgPack, encoded.clone());
+        // listeners share the bytes of an event
+        assert_eq!(
+            events.get(&bytes.clone(), Encoding::MsgPack),
+            Some(encoded.clone())
+        );
+        assert_eq!(events.get(&bytes, Encoding::Cbor), None);
+        // an event with the same content is another event
+        let (other, _) = make_query_event_bytes(&mut buf, &columns).unwrap();
+        assert_eq!(events.get(&other, Encoding::MsgPack), None);
+
+        for i in 0..BINARY_EVENTS_CACHED {
+            events.insert(
+                Bytes::from(i.to_string()),
+                Encoding::MsgPack,
+                encoded.clone(),
+            );
+        }
+        assert_eq!(events.get(&bytes, Encoding::MsgPack), None);
+        assert_eq!(events.encoded.len(), BINARY_EVENTS_CACHED);
+    }
+
+    #[test]
+    fn test_sse_events() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(EventFormat::from_headers(&headers), EventFormat::Ndjson);
+        assert_eq!(last_event_id(&headers), None);
+
+        headers.insert(
+            header::ACCEPT,
+            "application/json, text/event-stream;q=0.9".parse().unwrap(),
+        );
+        headers.insert("last-event-id", "42".parse().unwrap());
+        let format = EventFormat::from_headers(&headers);
+        assert_eq!(format, EventFormat::Sse);
+        assert_eq!(last_event_id(&headers), Some(ChangeId(42)));
+
+        let mut buf = BytesMut::new();
+        let mut out = BytesMut::new();
+        for evt in [
+            QueryEvent::Columns(vec!["id".into()]),
+            QueryEvent::Row(RowId(1), vec![SqliteValue::Integer(1)]),
+            QueryEvent::EndOfQuery {
+                time: 0.0,
+                change_id: Some(ChangeId(42)),
+                cursor: None,
+            },
+        ] {
+            let (bytes, meta) = make_query_event_bytes(&mut buf, &evt).unwrap();
+            format.encode(&mut out, &bytes, meta);
+        }
+
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "event: columns\ndata: {\"columns\":[\"id\"]}\n\n\
+             event: row\ndata: {\"row\":[1,[1]]}\n\n\
+             event: end_of_query\nid: 42\ndata: {\"eoq\":{\"time\":0.0,\"change_id\":42}}\n\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_watch_groups() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec![1i64.into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests2 (id, text) values (?,?)".into(),
+                        vec![1i64.into(), "check-name".into()],
+                    ),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let res = api_v1_watch_groups(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Default::default(),
+            axum::Json(vec![
+                Statement::Simple("select text from tests".into()),
+                Statement::Simple("select text from tests2".into()),
+            ]),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let ids = res
+            .headers()
+            .get("corro-query-ids")
+            .unwrap()
+            .to_str()?
+            .split(',')
+            .map(Uuid::parse_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(ids.len(), 2);
+
+        #[derive(Deserialize)]
+        struct GroupEvent {
+            id: Uuid,
+            event: QueryEvent,
+        }
+
+        let mut body = res.into_body();
+        let mut codec = LinesCodec::new();
+        let mut buf = BytesMut::new();
+        let mut rows: HashMap<Uuid, Vec<Vec<SqliteValue>>> = HashMap::new();
+        let mut done = 0;
+        while done < ids.len() {
+            let line = match codec.decode(&mut buf)? {
+                Some(line) => line,
+                None => {
+                    buf.extend_from_slice(&body.data().await.unwrap()?);
+                    continue;
+                }
+            };
+            let evt: GroupEvent = serde_json::from_str(&line)?;
+            match evt.event {
+                QueryEvent::Row(_, cells) => rows.entry(evt.id).or_default().push(cells),
+                QueryEvent::EndOfQuery { .. } => done += 1,
+                _ => {}
+            }
+        }
+
+        assert_eq!(rows[&ids[0]], vec![vec!["service-name".into()]]);
+        assert_eq!(rows[&ids[1]], vec![vec!["check-name".into()]]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_watch_delete() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Default::default(),
+            axum::extract::Query(SubParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let id: Uuid = res.headers()["corro-query-id"].to_str()?.parse()?;
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+        assert!(matches!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Columns(_)
+        ));
+        assert!(matches!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        let res = api_v1_watch_delete(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Path(id),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        assert_eq!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Closed {
+                reason: CloseReason::Deleted
+            }
+        );
+        assert!(agent.subs_manager().get(&id).is_none());
+
+        let res = api_v1_watch_delete(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Path(id),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    struct RowsIter {
+        body: axum::body::BoxBody,
+        codec: LinesCodec,
+        buf: BytesMut,
+        done: bool,
+    }
+
+    impl RowsIter {
+        async fn recv(&mut self) -> Option<eyre::Result<QueryEvent>> {
+            if self.done {
+                return None;
+            }
+            loop {
+                match self.codec.decode(&mut self.buf) {
+                    Ok(Some(line)) => match serde_json::from_str(&line) {
+                        Ok(res) => return Some(Ok(res)),
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(e.into()));
+                        }
+                    },
+                    Ok(None) => {
+                        // fall through
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e.into()));
+                    }
+                }
+
+                let bytes_res = self.body.data().await;
+                match bytes_res {
+                    Some(Ok(b)) => {
+                        // debug!("read {} bytes", b.len());
+                        self.buf.extend_from_slice(&b)
+                    }
+                    Some(Err(e)) => {
+                        self.done = true;
+                        return Some(Err(e.into()));
+                    }
+                    None => {
+                        self.done = true;
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_event_filter() -> eyre::Result<()> {
+        let col_names: Vec<ColumnName> = vec!["id".into(), "text".into(), "other".into()];
+
+        let params = SubParams {
+            columns: Some("nope".into()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            EventFilter::new(&params, &col_names),
+            Err(MatcherUpsertError::UnknownColumn(_))
+        ));
+
+        assert!(EventFilter::new(&SubParams::default(), &col_names)?.is_none());
+
+        let params = SubParams {
+            changes: ChangesFilter::Delete,
+            columns: Some("other,id".into()),
+            ..Default::default()
+        };
+        let mut filter = EventFilter::new(&params, &col_names)?.expect("expected a filter");
+
+        let mut buf = BytesMut::new();
+        let mut apply = |evt: QueryEvent| {
+            let (bytes, meta) = make_query_event_bytes(&mut buf, &evt).unwrap();
+            filter
+                .apply(bytes, meta)
+                .map(|bytes| serde_json::from_slice::<QueryEvent>(&bytes).unwrap())
+        };
+
+        assert!(matches!(
+            apply(QueryEvent::Columns(col_names.clone())),
+            Some(QueryEvent::Columns(cols)) if cols == vec![ColumnName::from("other"), ColumnName::from("id")]
+        ));
+
+        assert!(apply(QueryEvent::Change(
+            ChangeType::Update,
+            RowId(1),
+            vec![1i64.into(), "hello".into(), 2i64.into()],
+            ChangeId(1),
+            None,
+        ))
+        .is_none());
+
+        assert!(matches!(
+            apply(QueryEvent::Change(
+                ChangeType::Delete,
+                RowId(1),
+                vec![1i64.into(), "hello".into(), 2i64.into()],
+                ChangeId(2),
+                None,
+            )),
+            Some(QueryEvent::Change(ChangeType::Delete, RowId(1), cells, ChangeId(2), None))
+                if cells == vec![SqliteValue::Integer(2), SqliteValue::Integer(1)]
+        ));
+
+        Ok(())
+    }
+    #[test]
+    fn test_event_filter_skipped() -> eyre::Result<()> {
+        let col_names: Vec<ColumnName> = vec!["id".into(), "text".into()];
+
+        let params = SubParams {
+            changes: ChangesFilter::Insert,
+            ..Default::default()
+        };
+        let mut filter = EventFilter::new(&params, &col_names)?.expect("expected a filter");
+
+        let mut buf = BytesMut::new();
+        let mut send = |change_type: ChangeType, change_id: i64| {
+            let evt = QueryEvent::Change(
+                change_type,
+                RowId(1),
+                vec![1i64.into(), "hello".into()],
+                ChangeId(change_id),
+                None,
+            );
+            let (bytes, meta) = make_query_event_bytes(&mut buf, &evt).unwrap();
+            if filter.apply(bytes, meta).is_none() {
+                return (false, None);
+            }
+            let skipped = filter
+                .take_skipped(meta)
+                .map(|(bytes, _)| serde_json::from_slice::<QueryEvent>(&bytes).unwrap());
+            (true, skipped)
+        };
+
+        assert!(matches!(send(ChangeType::Insert, 1), (true, None)));
+        assert!(matches!(send(ChangeType::Update, 2), (false, None)));
+        assert!(matches!(send(ChangeType::Delete, 3), (false, None)));
+
+        // the client learns changes 2 and 3 were left out on purpose
+        assert!(matches!(
+            send(ChangeType::Insert, 4),
+            (
+                true,
+                Some(QueryEvent::Skipped {
+                    change_id: ChangeId(3)
+                })
+            )
+        ));
+        assert!(matches!(send(ChangeType::Insert, 5), (true, None)));
+
+        Ok(())
     }
 }
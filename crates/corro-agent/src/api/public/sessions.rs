@@ -0,0 +1,515 @@
+//! Interactive transactions: a session holds the write connection inside a
+//! transaction across requests, so statements can depend on the results of
+//! earlier ones. Changes are only validated, committed and broadcast when
+//! the session is committed.
+//!
+//! Every other write waits for the session to end, sessions are rolled back
+//! when they're idle for too long and can't outlive `MAX_SESSION_LIFETIME`.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::Extension;
+use corro_types::{
+    agent::{Agent, WriteConn},
+    api::{ErrorCode, ExecResponse, ExecResult, Statement},
+    error::{ApiError, ChangeError},
+};
+use hyper::StatusCode;
+use parking_lot::Mutex;
+use rusqlite::Transaction;
+use serde::{Deserialize, Serialize};
+use spawn::{spawn_blocking_counted_w_handle, spawn_counted};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use super::{
+    commit_changes, error::impl_into_response, execute_statement, query_statement, Broadcast,
+    Executed,
+};
+
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_SESSION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Sessions block every other write, even busy ones are rolled back after this
+const MAX_SESSION_LIFETIME: Duration = Duration::from_secs(10);
+
+pub type SharedSessions = Arc<Sessions>;
+
+type EndReply = oneshot::Sender<Result<Duration, ChangeError>>;
+
+enum SessionCommand {
+    Execute(Vec<Statement>, oneshot::Sender<Vec<ExecResult>>),
+    /// Commits or rolls back the transaction, replying once it's done
+    End {
+        commit: bool,
+        reply: EndReply,
+    },
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<Uuid, mpsc::UnboundedSender<SessionCommand>>>,
+}
+
+impl Sessions {
+    fn send(&self, id: Uuid, cmd: SessionCommand) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock();
+        let sent = match cmd {
+            // nothing can be sent to an ended session
+            SessionCommand::End { .. } => sessions.remove(&id).map(|tx| tx.send(cmd)),
+            SessionCommand::Execute(..) => sessions.get(&id).map(|tx| tx.send(cmd)),
+        };
+        match sent {
+            Some(Ok(())) => Ok(()),
+            Some(Err(_)) => {
+                sessions.remove(&id);
+                Err(SessionError::NotFound(id))
+            }
+            None => Err(SessionError::NotFound(id)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SessionParams {
+    /// Seconds without statements before the transaction is rolled back
+    #[serde(default)]
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub timeout: f64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("transaction {0} not found, it was committed, rolled back or timed out")]
+    NotFound(Uuid),
+    #[error(transparent)]
+    Change(#[from] ChangeError),
+    #[error("transaction stopped before it could be opened")]
+    Aborted,
+}
+
+impl ApiError for SessionError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            SessionError::NotFound(_) => ErrorCode::NotFound,
+            SessionError::Change(e) => e.code(),
+            SessionError::Aborted => ErrorCode::Internal,
+        }
+    }
+}
+
+impl_into_response!(SessionError);
+
+/// Runs the statements sent to the session until it's ended, returning
+/// whether to commit its transaction and who to tell once it's done.
+/// Returning an error rolls the transaction back.
+fn session_loop(
+    agent: &Agent,
+    tx: &Transaction,
+    commands: &mut mpsc::UnboundedReceiver<SessionCommand>,
+    timeout: Duration,
+    handle: &Handle,
+) -> Result<(bool, EndReply), ChangeError> {
+    let deadline = Instant::now() + MAX_SESSION_LIFETIME;
+
+    loop {
+        let wait = timeout.min(deadline.saturating_duration_since(Instant::now()));
+        match handle.block_on(tokio::time::timeout(wait, commands.recv())) {
+            Ok(Some(SessionCommand::Execute(statements, reply))) => {
+                let results = statements
+                    .iter()
+                    .map(|stmt| run_statement(agent, tx, stmt))
+                    .collect();
+                _ = reply.send(results);
+            }
+            Ok(Some(SessionCommand::End { commit, reply })) => return Ok((commit, reply)),
+            Ok(None) => return Err(ChangeError::RolledBack("session was dropped")),
+            Err(_) => return Err(ChangeError::RolledBack("session timed out")),
+        }
+    }
+}
+
+/// Statements which fail don't end the session, their error is returned in
+/// their place
+fn run_statement(agent: &Agent, tx: &Transaction, stmt: &Statement) -> ExecResult {
+    let start = Instant::now();
+
+    // reads return their rows, they see the session's uncommitted writes
+    let readonly = agent
+        .statement_cache()
+        .get_or_describe(tx, stmt.query())
+        .map_or(false, |info| info.readonly);
+    let res = if readonly {
+        query_statement(tx, stmt)
+    } else {
+        execute_statement(tx, stmt).map(|executed| match executed {
+            Executed::RowsAffected(rows_affected) => ExecResult::Execute {
+                rows_affected,
+                last_insert_id: None,
+                time: start.elapsed().as_secs_f64(),
+            },
+            Executed::Returning { columns, rows } => ExecResult::Returning {
+                rows_affected: rows.len(),
+                last_insert_id: None,
+                columns,
+                rows,
+                time: start.elapsed().as_secs_f64(),
+            },
+        })
+    };
+
+    res.unwrap_or_else(|e| ExecResult::error(ErrorCode::StatementFailed, e))
+}
+
+/// Holds the write connection in a transaction until the session ends, on
+/// a blocking thread so waiting for statements doesn't hold up the runtime.
+/// `ready` is notified once the transaction is open.
+fn session_transaction(
+    agent: &Agent,
+    mut conn: WriteConn,
+    mut commands: mpsc::UnboundedReceiver<SessionCommand>,
+    ready: oneshot::Sender<()>,
+    timeout: Duration,
+    handle: &Handle,
+) -> (Option<EndReply>, Result<Duration, ChangeError>) {
+    let start = Instant::now();
+    let tx = match conn.immediate_transaction_retry("api") {
+        Ok(tx) => tx,
+        Err(source) => {
+            return (
+                None,
+                Err(ChangeError::Rusqlite {
+                    source,
+                    actor_id: Some(agent.actor_id()),
+                    version: None,
+                }),
+            )
+        }
+    };
+
+    // the client went away while waiting for the connection
+    if ready.send(()).is_err() {
+        return (None, Err(ChangeError::RolledBack("session was dropped")));
+    }
+
+    match session_loop(agent, &tx, &mut commands, timeout, handle) {
+        Ok((true, reply)) => (
+            Some(reply),
+            commit_changes(agent, tx, &[], Broadcast::Changes, start),
+        ),
+        Ok((false, reply)) => (
+            Some(reply),
+            Err(ChangeError::RolledBack("requested by the client")),
+        ),
+        Err(e) => (None, Err(e)),
+    }
+}
+
+/// Waits for the write connection, then hands it to the session's
+/// transaction
+async fn run_session(
+    agent: Agent,
+    sessions: SharedSessions,
+    id: Uuid,
+    commands: mpsc::UnboundedReceiver<SessionCommand>,
+    ready: oneshot::Sender<()>,
+    timeout: Duration,
+) -> Result<(), ChangeError> {
+    let fence_wait = Duration::from_millis(agent.config().api.write_fence_wait_ms);
+    agent.write_fence().wait(fence_wait).await?;
+
+    let conn = agent.pool().write_priority().await?;
+
+    let handle = Handle::current();
+    let (reply, res) = spawn_blocking_counted_w_handle(
+        {
+            let agent = agent.clone();
+            let handle = handle.clone();
+            move || session_transaction(&agent, conn, commands, ready, timeout, &handle)
+        },
+        &handle,
+    )
+    .await
+    .unwrap_or_else(|_| {
+        (
+            None,
+            Err(ChangeError::RolledBack("session stopped unexpectedly")),
+        )
+    });
+
+    sessions.sessions.lock().remove(&id);
+
+    match res {
+        Ok(elapsed) => {
+            info!(%id, "committed interactive transaction after {elapsed:?}");
+            if let Some(reply) = reply {
+                _ = reply.send(Ok(elapsed));
+            }
+            Ok(())
+        }
+        Err(e) => {
+            debug!(%id, "interactive transaction was not committed: {e}");
+            match reply {
+                Some(reply) => {
+                    _ = reply.send(Err(e));
+                    Ok(())
+                }
+                None => Err(e),
+            }
+        }
+    }
+}
+
+/// Opens an interactive transaction, once the write connection is free
+pub async fn api_v1_transactions_begin(
+    Extension(agent): Extension<Agent>,
+    Extension(sessions): Extension<SharedSessions>,
+    axum::extract::Query(params): axum::extract::Query<SessionParams>,
+) -> Result<(StatusCode, axum::Json<SessionResponse>), SessionError> {
+    let timeout = params
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SESSION_TIMEOUT)
+        .min(MAX_SESSION_TIMEOUT);
+
+    let id = Uuid::new_v4();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let (ready_tx, ready_rx) = oneshot::channel();
+
+    let task = spawn_counted(run_session(
+        agent,
+        sessions.clone(),
+        id,
+        commands_rx,
+        ready_tx,
+        timeout,
+    ));
+
+    if ready_rx.await.is_err() {
+        return Err(match task.await {
+            Ok(Err(e)) => e.into(),
+            _ => SessionError::Aborted,
+        });
+    }
+
+    sessions.sessions.lock().insert(id, commands_tx);
+    debug!(%id, "opened interactive transaction, times out after {timeout:?}");
+
+    Ok((
+        StatusCode::CREATED,
+        axum::Json(SessionResponse {
+            id,
+            timeout: timeout.as_secs_f64(),
+        }),
+    ))
+}
+
+/// Runs statements in an interactive transaction
+pub async fn api_v1_transactions_session(
+    Extension(sessions): Extension<SharedSessions>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    axum::extract::Json(statements): axum::extract::Json<Vec<Statement>>,
+) -> Result<axum::Json<ExecResponse>, SessionError> {
+    let start = Instant::now();
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    sessions.send(id, SessionCommand::Execute(statements, reply_tx))?;
+    // the session timed out in the meantime
+    let results = reply_rx.await.map_err(|_| SessionError::NotFound(id))?;
+
+    Ok(axum::Json(ExecResponse {
+        results,
+        time: start.elapsed().as_secs_f64(),
+    }))
+}
+
+async fn end_session(
+    sessions: &Sessions,
+    id: Uuid,
+    commit: bool,
+) -> Result<Duration, SessionError> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    sessions.send(
+        id,
+        SessionCommand::End {
+            commit,
+            reply: reply_tx,
+        },
+    )?;
+    let res = reply_rx.await.map_err(|_| SessionError::NotFound(id))?;
+    Ok(res?)
+}
+
+/// Commits an interactive transaction, its changes are broadcast
+pub async fn api_v1_transactions_commit(
+    Extension(sessions): Extension<SharedSessions>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<axum::Json<ExecResponse>, SessionError> {
+    let elapsed = end_session(&sessions, id, true).await?;
+
+    Ok(axum::Json(ExecResponse {
+        results: vec![],
+        time: elapsed.as_secs_f64(),
+    }))
+}
+
+/// Rolls back an interactive transaction, none of its changes are kept
+pub async fn api_v1_transactions_rollback(
+    Extension(sessions): Extension<SharedSessions>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<StatusCode, SessionError> {
+    match end_session(&sessions, id, false).await {
+        Ok(_) | Err(SessionError::Change(ChangeError::RolledBack(_))) => Ok(StatusCode::NO_CONTENT),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::{api::SqliteValue, config::Config};
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::{agent::setup, api::public::api_v1_db_schema};
+
+    async fn begin(
+        agent: &Agent,
+        sessions: &SharedSessions,
+        timeout: Option<u64>,
+    ) -> Result<Uuid, SessionError> {
+        let (status, axum::Json(res)) = api_v1_transactions_begin(
+            Extension(agent.clone()),
+            Extension(sessions.clone()),
+            axum::extract::Query(SessionParams { timeout }),
+        )
+        .await?;
+        assert_eq!(status, StatusCode::CREATED);
+        Ok(res.id)
+    }
+
+    async fn run(
+        sessions: &SharedSessions,
+        id: Uuid,
+        sql: &str,
+    ) -> Result<ExecResult, SessionError> {
+        let axum::Json(mut res) = api_v1_transactions_session(
+            Extension(sessions.clone()),
+            axum::extract::Path(id),
+            axum::Json(vec![Statement::Simple(sql.into())]),
+        )
+        .await?;
+        Ok(res.results.remove(0))
+    }
+
+    async fn count_committed(agent: &Agent) -> eyre::Result<i64> {
+        let conn = agent.pool().read().await?;
+        Ok(conn.query_row("SELECT count(*) FROM tests", [], |row| row.get(0))?)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_interactive_transactions() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let sessions = SharedSessions::default();
+
+        let id = begin(&agent, &sessions, None).await?;
+        run(
+            &sessions,
+            id,
+            "INSERT INTO tests (id, text) VALUES (1, 'a')",
+        )
+        .await?;
+
+        // the session reads its own writes, nobody else does until it commits
+        match run(&sessions, id, "SELECT text FROM tests WHERE id = 1").await? {
+            ExecResult::Query { rows, .. } => {
+                assert_eq!(rows, vec![vec![SqliteValue::Text("a".into())]])
+            }
+            res => panic!("unexpected result: {res:?}"),
+        }
+        assert_eq!(count_committed(&agent).await?, 0);
+
+        // failed statements don't end the session
+        assert!(matches!(
+            run(&sessions, id, "INSERT INTO nope (id) VALUES (1)").await?,
+            ExecResult::Error { .. }
+        ));
+
+        api_v1_transactions_commit(Extension(sessions.clone()), axum::extract::Path(id)).await?;
+        assert_eq!(count_committed(&agent).await?, 1);
+        assert!(agent_options.rx_bcast.recv().await.is_some());
+
+        // ended sessions are gone
+        assert!(matches!(
+            run(&sessions, id, "SELECT 1").await,
+            Err(SessionError::NotFound(_))
+        ));
+
+        let id = begin(&agent, &sessions, None).await?;
+        run(
+            &sessions,
+            id,
+            "INSERT INTO tests (id, text) VALUES (2, 'b')",
+        )
+        .await?;
+        let status =
+            api_v1_transactions_rollback(Extension(sessions.clone()), axum::extract::Path(id))
+                .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(count_committed(&agent).await?, 1);
+
+        // abandoned sessions are rolled back, freeing the write connection
+        let id = begin(&agent, &sessions, Some(1)).await?;
+        run(
+            &sessions,
+            id,
+            "INSERT INTO tests (id, text) VALUES (3, 'c')",
+        )
+        .await?;
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(matches!(
+            run(&sessions, id, "SELECT 1").await,
+            Err(SessionError::NotFound(_))
+        ));
+
+        let id = begin(&agent, &sessions, None).await?;
+        api_v1_transactions_commit(Extension(sessions.clone()), axum::extract::Path(id)).await?;
+        assert_eq!(count_committed(&agent).await?, 1);
+
+        Ok(())
+    }
+}
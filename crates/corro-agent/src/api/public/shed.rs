@@ -0,0 +1,125 @@
+//! Rejects low priority requests with a 503 while the node is overloaded,
+//! see [`corro_types::load`]. Requests not listed here, like health checks,
+//! migrations or the ends of interactive transactions, are never shed.
+
+use axum::{
+    http::{header, Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use corro_types::{
+    agent::{Agent, WriteClass},
+    api::{ErrorCode, ExecResponse, ExecResult},
+    load::RequestPriority,
+};
+use hyper::StatusCode;
+use metrics::counter;
+use tracing::debug;
+
+/// Seconds shed requests are told to wait before retrying
+const RETRY_AFTER_SECS: u64 = 1;
+
+pub async fn shed_load(
+    Extension(agent): Extension<Agent>,
+    request: Request<hyper::Body>,
+    next: Next<hyper::Body>,
+) -> Response {
+    let Some(priority) = request_priority(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    let pressure = {
+        let config = agent.config();
+        config.api.load_shedding.enabled.then(|| {
+            let write_queue = agent.pool().queue_depth(WriteClass::Interactive)
+                + agent.pool().queue_depth(WriteClass::Apply);
+            agent
+                .load()
+                .pressure(write_queue, &config.api.load_shedding)
+        })
+    };
+    let Some(pressure) = pressure.filter(|pressure| pressure.sheds(priority)) else {
+        return next.run(request).await;
+    };
+
+    debug!(
+        "shedding {} request, {} pressure at {:.2}",
+        priority.as_str(),
+        pressure.signal,
+        pressure.value
+    );
+    counter!(
+        "corro.api.requests.shed",
+        "priority" => priority.as_str(),
+        "signal" => pressure.signal
+    )
+    .increment(1);
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        axum::Json(ExecResponse {
+            results: vec![ExecResult::error(
+                ErrorCode::Unavailable,
+                format!("node is overloaded ({}), retry later", pressure.signal),
+            )],
+            time: 0.0,
+        }),
+    )
+        .into_response()
+}
+
+fn request_priority(method: &Method, path: &str) -> Option<RequestPriority> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        (&Method::POST, ["v1", "subscriptions"])
+        | (&Method::GET, ["v1", "subscriptions", _])
+        | (&Method::PUT, ["v1", "watches", "by-hash"])
+        | (&Method::POST, ["v1", "watches", "groups" | "keys"])
+        | (&Method::GET, ["v1", "ws"]) => Some(RequestPriority::Watch),
+        (&Method::POST, ["v1" | "v2", "queries"])
+        | (&Method::POST, ["v1", "snapshots" | "table_stats"])
+        | (&Method::GET, ["v1", "tables", _, "export"]) => Some(RequestPriority::Query),
+        // statements of a started transaction go through, it would hold the
+        // write connection for longer otherwise
+        (&Method::POST, ["v1" | "v2", "transactions"])
+        | (&Method::POST, ["v1", "transactions", "begin"])
+        | (&Method::POST, ["v1", "truncations" | "upserts" | "changes"])
+        | (&Method::POST, ["v1", "import", "csv"])
+        | (&Method::POST, ["v1", "tables", _, "import"])
+        | (&Method::POST, ["v1", "statements", _]) => Some(RequestPriority::Write),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_priority() {
+        assert_eq!(
+            request_priority(&Method::POST, "/v1/subscriptions"),
+            Some(RequestPriority::Watch)
+        );
+        assert_eq!(
+            request_priority(&Method::POST, "/v2/queries"),
+            Some(RequestPriority::Query)
+        );
+        assert_eq!(
+            request_priority(&Method::POST, "/v1/transactions"),
+            Some(RequestPriority::Write)
+        );
+        assert_eq!(
+            request_priority(
+                &Method::POST,
+                "/v1/transactions/00000000-0000-0000-0000-000000000000/commit"
+            ),
+            None
+        );
+        assert_eq!(request_priority(&Method::GET, "/v1/health"), None);
+        assert_eq!(request_priority(&Method::POST, "/v1/migrations"), None);
+    }
+}
@@ -0,0 +1,289 @@
+//! Snapshots pin a read connection inside a transaction, so several
+//! queries can observe the exact same database state.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::Extension;
+use corro_types::{
+    agent::Agent,
+    api::{ErrorCode, ExecResult},
+    error::ApiError,
+    sqlite::{SqlitePoolError, SqlitePooledConn},
+};
+use hyper::StatusCode;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Mutex as TokioMutex, MutexGuard as TokioMutexGuard},
+    task::block_in_place,
+};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+const DEFAULT_SNAPSHOT_TTL: Duration = Duration::from_secs(30);
+const MAX_SNAPSHOT_TTL: Duration = Duration::from_secs(600);
+/// Every snapshot holds onto a read connection, don't starve the pool
+const MAX_SNAPSHOTS: usize = 8;
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type SharedSnapshots = Arc<Snapshots>;
+
+#[derive(Default)]
+pub struct Snapshots {
+    snapshots: Mutex<HashMap<Uuid, Arc<Snapshot>>>,
+}
+
+impl Snapshots {
+    /// Fetches a live snapshot, extending its lifetime by its TTL
+    pub fn get(&self, id: &Uuid) -> Option<Arc<Snapshot>> {
+        let snapshots = self.snapshots.lock();
+        let snapshot = snapshots.get(id)?;
+        if snapshot.is_expired() {
+            return None;
+        }
+        snapshot.touch();
+        Some(snapshot.clone())
+    }
+
+    fn remove_expired(&self) {
+        self.snapshots.lock().retain(|id, snapshot| {
+            let expired = snapshot.is_expired();
+            if expired {
+                debug!(%id, "snapshot expired");
+            }
+            !expired
+        });
+    }
+
+    /// Periodically releases expired snapshots
+    pub async fn expire_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            // the api is gone
+            if Arc::strong_count(&self) == 1 {
+                break;
+            }
+            self.remove_expired();
+        }
+    }
+}
+
+pub struct Snapshot {
+    conn: TokioMutex<SqlitePooledConn>,
+    ttl: Duration,
+    expires_at: Mutex<Instant>,
+}
+
+impl Snapshot {
+    /// Exclusive access to the snapshot's connection, queries on the same
+    /// snapshot run one after the other.
+    pub async fn conn(&self) -> TokioMutexGuard<'_, SqlitePooledConn> {
+        self.conn.lock().await
+    }
+
+    fn touch(&self) {
+        *self.expires_at.lock() = Instant::now() + self.ttl;
+    }
+
+    fn is_expired(&self) -> bool {
+        *self.expires_at.lock() <= Instant::now()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        // the connection goes back to the pool, it can't stay in a transaction
+        if let Err(e) = self.conn.get_mut().execute_batch("ROLLBACK") {
+            warn!("could not rollback snapshot transaction: {e}");
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SnapshotParams {
+    /// Seconds of inactivity before the snapshot is released
+    #[serde(default)]
+    ttl: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    pub id: Uuid,
+    pub ttl: f64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("too many open snapshots (max: {MAX_SNAPSHOTS})")]
+    TooMany,
+    #[error(transparent)]
+    Pool(#[from] SqlitePoolError),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+impl ApiError for SnapshotError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            SnapshotError::TooMany | SnapshotError::Pool(_) => ErrorCode::Unavailable,
+            SnapshotError::Sqlite(_) => ErrorCode::Database,
+        }
+    }
+}
+
+impl From<SnapshotError> for (StatusCode, axum::Json<ExecResult>) {
+    fn from(e: SnapshotError) -> Self {
+        (e.status(), axum::Json(e.exec_result()))
+    }
+}
+
+async fn open_snapshot(
+    agent: &Agent,
+    snapshots: &Snapshots,
+    ttl: Duration,
+) -> Result<Uuid, SnapshotError> {
+    if snapshots.snapshots.lock().len() >= MAX_SNAPSHOTS {
+        snapshots.remove_expired();
+        if snapshots.snapshots.lock().len() >= MAX_SNAPSHOTS {
+            return Err(SnapshotError::TooMany);
+        }
+    }
+
+    let conn = agent.pool().read().await?;
+
+    block_in_place(|| {
+        // a deferred transaction only takes its snapshot on the first read
+        conn.execute_batch("BEGIN DEFERRED")?;
+        if let Err(e) = conn.query_row("SELECT COUNT(*) FROM sqlite_schema", [], |_row| Ok(())) {
+            _ = conn.execute_batch("ROLLBACK");
+            return Err(e);
+        }
+        Ok::<_, rusqlite::Error>(())
+    })?;
+
+    let id = Uuid::new_v4();
+    snapshots.snapshots.lock().insert(
+        id,
+        Arc::new(Snapshot {
+            conn: TokioMutex::new(conn),
+            ttl,
+            expires_at: Mutex::new(Instant::now() + ttl),
+        }),
+    );
+
+    Ok(id)
+}
+
+/// Pin the current state of the database for subsequent queries
+pub async fn api_v1_snapshots(
+    Extension(agent): Extension<Agent>,
+    Extension(snapshots): Extension<SharedSnapshots>,
+    axum::extract::Query(params): axum::extract::Query<SnapshotParams>,
+) -> Result<(StatusCode, axum::Json<SnapshotResponse>), (StatusCode, axum::Json<ExecResult>)> {
+    let ttl = params
+        .ttl
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SNAPSHOT_TTL)
+        .min(MAX_SNAPSHOT_TTL);
+
+    let id = open_snapshot(&agent, &snapshots, ttl).await?;
+
+    debug!(%id, "opened snapshot for {ttl:?}");
+
+    Ok((
+        StatusCode::CREATED,
+        axum::Json(SnapshotResponse {
+            id,
+            ttl: ttl.as_secs_f64(),
+        }),
+    ))
+}
+
+/// Release a snapshot before its expiry
+pub async fn api_v1_snapshot_release(
+    Extension(snapshots): Extension<SharedSnapshots>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> StatusCode {
+    match snapshots.snapshots.lock().remove(&id) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::{api::Statement, config::Config};
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::{
+        agent::setup,
+        api::public::{api_v1_db_schema, api_v1_transactions},
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_snapshot_isolation() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let snapshots = SharedSnapshots::default();
+        let id = open_snapshot(&agent, &snapshots, Duration::from_secs(30)).await?;
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Default::default(),
+            axum::extract::Query(Default::default()),
+            axum::Json(
+                vec![Statement::Simple(
+                    "INSERT INTO tests (id, text) VALUES (1, 'hello')".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let count = |conn: &rusqlite::Connection| -> rusqlite::Result<i64> {
+            conn.query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))
+        };
+
+        let snapshot = snapshots.get(&id).expect("snapshot should be live");
+        assert_eq!(count(&*snapshot.conn().await)?, 0);
+        assert_eq!(count(&*agent.pool().read().await?)?, 1);
+        drop(snapshot);
+
+        assert_eq!(
+            api_v1_snapshot_release(Extension(snapshots.clone()), axum::extract::Path(id)).await,
+            StatusCode::NO_CONTENT
+        );
+        assert!(snapshots.get(&id).is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+//! Statements registered by name, run with only their parameters. Writes
+//! keep a cached prepared statement on the write connection, so hot write
+//! paths aren't parsed again on every request. Registrations are broadcast
+//! like any other change.
+
+use std::{collections::HashMap, time::Instant};
+
+use axum::Extension;
+use bytes::Bytes;
+use corro_types::{
+    agent::Agent,
+    api::{ErrorCode, ExecResponse, ExecResult, SqliteParam, Statement},
+    error::{ApiError, ChangeError},
+    prepared::{self, NamedStatement},
+    sqlite::{retry_busy, SqlitePoolError},
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+use tokio::task::block_in_place;
+use tracing::info;
+
+use super::{
+    commit_broadcastable_changes, error::impl_into_response, execute_prepared,
+    make_broadcastable_changes, query_statement, Broadcast, Executed,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    pub sql: String,
+}
+
+/// Parameters a named statement is run with
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum NamedParams {
+    Positional(Vec<SqliteParam>),
+    Named(HashMap<String, SqliteParam>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatementsError {
+    #[error("statement '{0}' is not registered")]
+    NotFound(String),
+    #[error("invalid statement name '{0}', only letters, digits, '-' and '_' are allowed")]
+    InvalidName(String),
+    #[error(transparent)]
+    InvalidStatement(rusqlite::Error),
+    #[error("only statements reading or writing rows can be registered")]
+    NotAllowed,
+    #[error("invalid parameters: {0}")]
+    Params(serde_json::Error),
+    #[error(transparent)]
+    Failed(rusqlite::Error),
+    #[error(transparent)]
+    Pool(#[from] SqlitePoolError),
+    #[error(transparent)]
+    Change(#[from] ChangeError),
+    #[error(transparent)]
+    Rusqlite(#[from] rusqlite::Error),
+}
+
+impl ApiError for StatementsError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            StatementsError::NotFound(_) => ErrorCode::NotFound,
+            StatementsError::InvalidName(_)
+            | StatementsError::NotAllowed
+            | StatementsError::Params(_) => ErrorCode::BadRequest,
+            StatementsError::InvalidStatement(_) => ErrorCode::InvalidStatement,
+            StatementsError::Failed(_) => ErrorCode::StatementFailed,
+            StatementsError::Pool(_) => ErrorCode::Unavailable,
+            StatementsError::Change(e) => e.code(),
+            StatementsError::Rusqlite(_) => ErrorCode::Database,
+        }
+    }
+}
+
+impl_into_response!(StatementsError);
+
+/// Register a statement under a name, replacing the one previously
+/// registered under it
+pub async fn api_v1_statement_register(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::Json(req): axum::extract::Json<RegisterRequest>,
+) -> Result<axum::Json<NamedStatement>, StatementsError> {
+    if !prepared::is_valid_name(&name) {
+        return Err(StatementsError::InvalidName(name));
+    }
+
+    let conn = agent.pool().read().await?;
+    let info = block_in_place(|| agent.statement_cache().get_or_describe(&conn, &req.sql))
+        .map_err(StatementsError::InvalidStatement)?;
+    drop(conn);
+    // schema changes go through migrations
+    if info.other {
+        return Err(StatementsError::NotAllowed);
+    }
+
+    let (statement, _) = make_broadcastable_changes(&agent, |tx| {
+        prepared::register(tx, &name, &req.sql).map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: None,
+            version: None,
+        })
+    })
+    .await?;
+
+    info!(%name, "registered named statement");
+
+    Ok(axum::Json(statement))
+}
+
+/// List named statements
+pub async fn api_v1_statements(
+    Extension(agent): Extension<Agent>,
+) -> Result<axum::Json<Vec<NamedStatement>>, StatementsError> {
+    let conn = agent.pool().read().await?;
+    Ok(axum::Json(block_in_place(|| prepared::list(&conn))?))
+}
+
+/// Unregister a named statement
+pub async fn api_v1_statement_remove(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<StatusCode, StatementsError> {
+    let (removed, _) = make_broadcastable_changes(&agent, |tx| {
+        prepared::remove(tx, &name).map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: None,
+            version: None,
+        })
+    })
+    .await?;
+    if !removed {
+        return Err(StatementsError::NotFound(name));
+    }
+
+    info!(%name, "removed named statement");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Run a named statement with the parameters in the body, positional (a
+/// list) or named (an object). Reads return their rows, writes are
+/// committed and broadcast like transactions.
+pub async fn api_v1_statement_run(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    body: Bytes,
+) -> Result<axum::Json<ExecResponse>, StatementsError> {
+    let params: Option<NamedParams> = if body.iter().all(u8::is_ascii_whitespace) {
+        None
+    } else {
+        Some(serde_json::from_slice(&body).map_err(StatementsError::Params)?)
+    };
+
+    let conn = agent.pool().read().await?;
+    let (statement, info) = block_in_place(|| {
+        let statement =
+            prepared::get(&conn, &name)?.ok_or_else(|| StatementsError::NotFound(name.clone()))?;
+        // it may not prepare anymore, since the schema changed
+        let info = agent
+            .statement_cache()
+            .get_or_describe(&conn, &statement.sql)
+            .map_err(StatementsError::InvalidStatement)?;
+        Ok::<_, StatementsError>((statement, info))
+    })?;
+
+    let stmt = match params {
+        None => Statement::Simple(statement.sql),
+        Some(NamedParams::Positional(params)) => Statement::WithParams(statement.sql, params),
+        Some(NamedParams::Named(params)) => Statement::WithNamedParams(statement.sql, params),
+    };
+
+    let start = Instant::now();
+
+    if info.readonly {
+        let result =
+            block_in_place(|| query_statement(&conn, &stmt)).map_err(StatementsError::Failed)?;
+        return Ok(axum::Json(ExecResponse {
+            results: vec![result],
+            time: start.elapsed().as_secs_f64(),
+        }));
+    }
+    drop(conn);
+
+    let (result, elapsed) = commit_broadcastable_changes(
+        &agent,
+        std::slice::from_ref(&stmt),
+        |tx| {
+            let start = Instant::now();
+            let executed = retry_busy("api", || {
+                let mut prepped = tx.prepare_cached(stmt.query())?;
+                execute_prepared(&mut prepped, &stmt)
+            })
+            .map_err(|source| ChangeError::StatementFailed { index: 0, source })?;
+
+            Ok(match executed {
+                Executed::RowsAffected(rows_affected) => ExecResult::Execute {
+                    rows_affected,
+                    last_insert_id: None,
+                    time: start.elapsed().as_secs_f64(),
+                },
+                Executed::Returning { columns, rows } => ExecResult::Returning {
+                    rows_affected: rows.len(),
+                    last_insert_id: None,
+                    columns,
+                    rows,
+                    time: start.elapsed().as_secs_f64(),
+                },
+            })
+        },
+        Broadcast::Changes,
+    )
+    .await?;
+
+    Ok(axum::Json(ExecResponse {
+        results: vec![result],
+        time: elapsed.as_secs_f64(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use corro_types::{
+        api::SqliteValue,
+        broadcast::{BroadcastInput, BroadcastV1},
+        config::Config,
+        prepared::STATEMENTS_TABLE,
+    };
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::{agent::setup, api::public::api_v1_db_schema};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_named_statements() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let register = |name: &str, sql: &str| {
+            api_v1_statement_register(
+                Extension(agent.clone()),
+                axum::extract::Path(name.to_owned()),
+                axum::Json(RegisterRequest {
+                    sql: sql.to_owned(),
+                }),
+            )
+        };
+        let run = |name: &str, body: &str| {
+            api_v1_statement_run(
+                Extension(agent.clone()),
+                axum::extract::Path(name.to_owned()),
+                Bytes::from(body.to_owned()),
+            )
+        };
+
+        register(
+            "upsert_test",
+            "INSERT OR REPLACE INTO tests (id, text) VALUES (?, ?)",
+        )
+        .await?;
+        register("get_test", "SELECT text FROM tests WHERE id = :id").await?;
+
+        let axum::Json(res) = run("upsert_test", r#"[1, "hello"]"#).await?;
+        assert!(matches!(
+            res.results[..],
+            [ExecResult::Execute {
+                rows_affected: 1,
+                ..
+            }]
+        ));
+        assert!(agent_options.rx_bcast.recv().await.is_some());
+
+        let axum::Json(res) = run("get_test", r#"{"id": 1}"#).await?;
+        match &res.results[..] {
+            [ExecResult::Query { rows, .. }] => {
+                assert_eq!(rows, &vec![vec![SqliteValue::Text("hello".into())]])
+            }
+            results => panic!("unexpected results: {results:?}"),
+        }
+
+        let axum::Json(statements) = api_v1_statements(Extension(agent.clone())).await?;
+        assert_eq!(
+            statements
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["get_test", "upsert_test"]
+        );
+
+        assert!(matches!(
+            register("bad name", "SELECT 1").await,
+            Err(StatementsError::InvalidName(_))
+        ));
+        assert!(matches!(
+            register("nope", "SELECT * FROM nope").await,
+            Err(StatementsError::InvalidStatement(_))
+        ));
+        assert!(matches!(
+            register("drop", "DROP TABLE tests").await,
+            Err(StatementsError::NotAllowed)
+        ));
+        assert!(matches!(
+            run("upsert_test", r#"[1]"#).await,
+            Err(StatementsError::Change(ChangeError::StatementFailed { .. }))
+        ));
+
+        let status = api_v1_statement_remove(
+            Extension(agent.clone()),
+            axum::extract::Path("get_test".into()),
+        )
+        .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(matches!(
+            run("get_test", r#"{"id": 1}"#).await,
+            Err(StatementsError::NotFound(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_named_statements_broadcast() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let broadcast_tables = |input: Option<BroadcastInput>| match input {
+            Some(BroadcastInput::AddBroadcast(BroadcastV1::Change(change))) => change
+                .changes()
+                .iter()
+                .map(|change| change.table.as_str().to_owned())
+                .collect::<BTreeSet<_>>(),
+            other => panic!("expected a broadcast of changes, got: {other:?}"),
+        };
+
+        api_v1_statement_register(
+            Extension(agent.clone()),
+            axum::extract::Path("one".into()),
+            axum::Json(RegisterRequest {
+                sql: "SELECT 1".into(),
+            }),
+        )
+        .await?;
+        assert_eq!(
+            broadcast_tables(agent_options.rx_bcast.recv().await),
+            [STATEMENTS_TABLE.to_owned()].into()
+        );
+
+        api_v1_statement_remove(Extension(agent.clone()), axum::extract::Path("one".into()))
+            .await?;
+        assert_eq!(
+            broadcast_tables(agent_options.rx_bcast.recv().await),
+            [STATEMENTS_TABLE.to_owned()].into()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,381 @@
+//! Minting, rotation and revocation of scoped API tokens, and the checks
+//! applied to requests made with them.
+
+use std::time::Duration;
+
+use axum::Extension;
+use bytes::Bytes;
+use corro_types::{
+    agent::{Agent, PoolError},
+    api::{
+        ErrorCode, ExecResult, KeyWatchRequest, Precondition, Statement, TableStatRequest,
+        TransactionRequest, TruncateRequest, UpsertRequest,
+    },
+    error::ApiError,
+    sqlite::SqlitePoolError,
+    tokens::{self, ApiToken, RowFilterError, TokenError, TokenScope, TokenVerb},
+};
+use hyper::{http::request::Parts, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::task::block_in_place;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+const DEFAULT_ROTATION_GRACE: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub scope: TokenScope,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenResponse {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    /// Only ever returned when the token is minted or rotated
+    pub secret: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RotateParams {
+    /// Seconds the previous secret keeps working
+    #[serde(default)]
+    grace: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokensApiError {
+    #[error("token not found")]
+    NotFound,
+    #[error(transparent)]
+    Pool(#[from] SqlitePoolError),
+    #[error(transparent)]
+    WritePool(#[from] PoolError),
+    #[error(transparent)]
+    Token(#[from] TokenError),
+    #[error(transparent)]
+    RowFilter(#[from] RowFilterError),
+}
+
+impl ApiError for TokensApiError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            TokensApiError::NotFound => ErrorCode::NotFound,
+            TokensApiError::RowFilter(_) => ErrorCode::BadRequest,
+            TokensApiError::Pool(_) | TokensApiError::WritePool(_) => ErrorCode::Unavailable,
+            TokensApiError::Token(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<TokensApiError> for (StatusCode, axum::Json<ExecResult>) {
+    fn from(e: TokensApiError) -> Self {
+        (e.status(), axum::Json(e.exec_result()))
+    }
+}
+
+/// Mint a new scoped token
+pub async fn api_v1_tokens_create(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(req): axum::extract::Json<TokenRequest>,
+) -> Result<(StatusCode, axum::Json<TokenResponse>), (StatusCode, axum::Json<ExecResult>)> {
+    let conn = agent
+        .pool()
+        .write_priority()
+        .await
+        .map_err(TokensApiError::from)?;
+    let (token, secret) = block_in_place(|| {
+        tokens::validate_row_filters(&conn, &req.scope, &agent.schema().read())?;
+        Ok::<_, TokensApiError>(tokens::mint(&conn, req.name, req.scope)?)
+    })?;
+
+    info!(id = %token.id, "minted api token");
+
+    Ok((
+        StatusCode::CREATED,
+        axum::Json(TokenResponse { token, secret }),
+    ))
+}
+
+/// List tokens, without their secrets
+pub async fn api_v1_tokens(
+    Extension(agent): Extension<Agent>,
+) -> Result<axum::Json<Vec<ApiToken>>, (StatusCode, axum::Json<ExecResult>)> {
+    let conn = agent.pool().read().await.map_err(TokensApiError::from)?;
+    let tokens = block_in_place(|| tokens::list(&conn)).map_err(TokensApiError::from)?;
+    Ok(axum::Json(tokens))
+}
+
+/// Revoke a token, it's rejected right away
+pub async fn api_v1_token_revoke(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<StatusCode, (StatusCode, axum::Json<ExecResult>)> {
+    let conn = agent
+        .pool()
+        .write_priority()
+        .await
+        .map_err(TokensApiError::from)?;
+    if !block_in_place(|| tokens::revoke(&conn, id)).map_err(TokensApiError::from)? {
+        return Err(TokensApiError::NotFound.into());
+    }
+
+    info!(%id, "revoked api token");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replace the secret of a token, the previous one keeps working for a
+/// grace period
+pub async fn api_v1_token_rotate(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    axum::extract::Query(params): axum::extract::Query<RotateParams>,
+) -> Result<axum::Json<TokenResponse>, (StatusCode, axum::Json<ExecResult>)> {
+    let grace = params
+        .grace
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_ROTATION_GRACE);
+
+    let conn = agent
+        .pool()
+        .write_priority()
+        .await
+        .map_err(TokensApiError::from)?;
+
+    let res = block_in_place(|| {
+        let Some(secret) = tokens::rotate(&conn, id, grace)? else {
+            return Ok(None);
+        };
+        Ok::<_, TokenError>(tokens::find(&conn, &secret)?.map(|token| (token, secret)))
+    })
+    .map_err(TokensApiError::from)?;
+
+    let Some((token, secret)) = res else {
+        return Err(TokensApiError::NotFound.into());
+    };
+
+    info!(%id, "rotated api token, previous secret valid for {grace:?}");
+
+    Ok(axum::Json(TokenResponse { token, secret }))
+}
+
+/// Finds the scoped token with this secret, if it's valid
+pub async fn find_token(agent: &Agent, secret: &str) -> Result<Option<ApiToken>, StatusCode> {
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    block_in_place(|| tokens::find(&conn, secret)).map_err(|e| {
+        debug!("could not look up api token: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Checks a request made with a scoped token is within its scope. Endpoints
+/// not listed here, like migrations or tokens, require the root token.
+/// Returns the body to pass on, with its statements rewritten to apply the
+/// token's row filters. Preconditions of transactions only read, they're
+/// checked and filtered like queries.
+pub async fn authorize_request(
+    agent: &Agent,
+    token: &ApiToken,
+    parts: &Parts,
+    body: Bytes,
+) -> Result<Bytes, StatusCode> {
+    let segments: Vec<&str> = parts.uri.path().trim_matches('/').split('/').collect();
+
+    let (verb, write) = match (&parts.method, segments.as_slice()) {
+        (&Method::POST, ["v1", "queries" | "table_stats" | "snapshots"])
+        | (&Method::POST, ["v2", "queries"])
+        | (&Method::POST, ["v1", "queries", _, "cancel"])
+        | (&Method::DELETE, ["v1", "snapshots", _]) => (TokenVerb::Read, false),
+        (&Method::POST, ["v1", "subscriptions"])
+        | (&Method::GET, ["v1", "subscriptions", _])
+        | (&Method::PUT, ["v1", "watches", "by-hash"])
+        | (&Method::POST, ["v1", "watches", "keys"]) => (TokenVerb::Subscribe, false),
+        (&Method::POST, ["v1", "transactions" | "truncations" | "upserts"])
+        | (&Method::POST, ["v2", "transactions"])
+        | (&Method::POST, ["v1", "transactions", _])
+        | (&Method::POST, ["v1", "transactions", _, "commit" | "rollback"]) => {
+            (TokenVerb::Write, true)
+        }
+        _ => return Err(StatusCode::FORBIDDEN),
+    };
+
+    if !token.scope.allows_verb(verb) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let (stmts, mut preconditions, single) = match segments.as_slice() {
+        ["v1" | "v2", "queries"] | ["v1", "subscriptions"] | ["v1", "watches", "by-hash"] => (
+            vec![serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?],
+            vec![],
+            true,
+        ),
+        // statements of interactive transactions are checked one request at a time
+        ["v1", "transactions", "begin"] | ["v1", "transactions", _, _] => return Ok(body),
+        ["v1" | "v2", "transactions"] => {
+            let req: TransactionRequest =
+                serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+            let (stmts, preconditions) = req.into_parts();
+            (stmts, preconditions, false)
+        }
+        ["v1", "transactions", _] => (
+            serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?,
+            vec![],
+            false,
+        ),
+        ["v1", "truncations"] => {
+            let req: TruncateRequest =
+                serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+            check_unfiltered_tables(&token.scope, [req.table.as_str()])?;
+            return Ok(body);
+        }
+        ["v1", "upserts"] => {
+            let reqs: Vec<UpsertRequest> =
+                serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+            check_unfiltered_tables(&token.scope, reqs.iter().map(|req| req.table.as_str()))?;
+            return Ok(body);
+        }
+        ["v1", "table_stats"] => {
+            let req: TableStatRequest =
+                serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+            check_unfiltered_tables(&token.scope, req.tables.iter().map(String::as_str))?;
+            return Ok(body);
+        }
+        ["v1", "watches", "keys"] => {
+            let req: KeyWatchRequest =
+                serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+            check_unfiltered_tables(&token.scope, [req.table.as_str()])?;
+            return Ok(body);
+        }
+        ["v1", "subscriptions", id] => {
+            let id: Uuid = id.parse().map_err(|_| StatusCode::NOT_FOUND)?;
+            let matcher = agent.subs_manager().get(&id).ok_or(StatusCode::NOT_FOUND)?;
+            // the subscription could have been created without the filters
+            check_unfiltered_tables(&token.scope, matcher.table_names())?;
+            return Ok(body);
+        }
+        // snapshots hold every row
+        ["v1", "snapshots", ..] if !token.scope.row_filters.is_empty() => {
+            return Err(StatusCode::FORBIDDEN)
+        }
+        // snapshots and query cancellations don't touch any table by themselves
+        _ => return Ok(body),
+    };
+
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let reads = preconditions
+        .iter()
+        .any(|precondition| matches!(precondition, Precondition::Rows { .. }));
+    if reads && !token.scope.allows_verb(TokenVerb::Read) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // the tables statements touch are found when preparing them, hot
+    // statements are only prepared once
+    block_in_place(|| {
+        let authorize = |stmt: &Statement, write: bool| match agent
+            .statement_cache()
+            .get_or_describe(&conn, stmt.query())
+        {
+            Ok(info) if token.scope.authorizes(&info, write) => Ok(()),
+            Ok(_) => {
+                debug!(id = %token.id, "statement not allowed for api token");
+                Err(StatusCode::FORBIDDEN)
+            }
+            Err(e) => {
+                debug!(id = %token.id, "statement not allowed for api token: {e}");
+                Err(StatusCode::FORBIDDEN)
+            }
+        };
+        for stmt in stmts.iter() {
+            authorize(stmt, write)?;
+        }
+        for precondition in preconditions.iter() {
+            match precondition {
+                Precondition::Rows { statement, .. } => authorize(statement, false)?,
+                // compared to any row, not only those the filters let through
+                Precondition::ColVersion { table, .. } => {
+                    check_unfiltered_tables(&token.scope, [table.as_str()])?
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    if token.scope.row_filters.is_empty() || (write && !reads) {
+        return Ok(body);
+    }
+
+    let mut stmts = stmts;
+    {
+        let schema = agent.schema().read();
+        let filter = |stmt: &mut Statement| {
+            let query =
+                tokens::apply_row_filters(stmt.query(), &token.scope, &schema).map_err(|e| {
+                    debug!(id = %token.id, "could not apply row filters of api token: {e}");
+                    StatusCode::FORBIDDEN
+                })?;
+            *stmt.query_mut() = query;
+            Ok::<_, StatusCode>(())
+        };
+        if !write {
+            for stmt in stmts.iter_mut() {
+                filter(stmt)?;
+            }
+        }
+        for precondition in preconditions.iter_mut() {
+            if let Precondition::Rows { statement, .. } = precondition {
+                filter(statement)?;
+            }
+        }
+    }
+
+    let body = if single {
+        serde_json::to_vec(&stmts[0])
+    } else if preconditions.is_empty() {
+        serde_json::to_vec(&stmts)
+    } else {
+        serde_json::to_vec(&TransactionRequest::Conditional {
+            statements: stmts,
+            preconditions,
+        })
+    };
+    body.map(Bytes::from)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn check_tables<'a>(
+    scope: &TokenScope,
+    tables: impl IntoIterator<Item = &'a str>,
+) -> Result<(), StatusCode> {
+    if tables.into_iter().all(|table| scope.allows_table(table)) {
+        Ok(())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+/// Endpoints which can't apply row filters are refused for filtered tables
+fn check_unfiltered_tables<'a>(
+    scope: &TokenScope,
+    tables: impl IntoIterator<Item = &'a str>,
+) -> Result<(), StatusCode> {
+    let tables = tables.into_iter().collect::<Vec<_>>();
+    check_tables(scope, tables.iter().copied())?;
+    if tables.iter().any(|table| scope.row_filter(table).is_some()) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
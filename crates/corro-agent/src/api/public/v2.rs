@@ -0,0 +1,287 @@
+//! Version 2 of the API. Its endpoints share their internals with their v1
+//! counterparts, only the shape of their responses changes, so breaking
+//! changes to responses don't strand v1 clients. So far:
+//!
+//! - errors failing a whole request are returned as a structured
+//!   [`ErrorResponse`] instead of an error result in place of the results.
+//!
+//! v1 endpoints superseded by a v2 endpoint are marked deprecated by
+//! [`deprecate_v1`].
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use corro_types::{
+    agent::Agent,
+    api::{
+        ErrorBody, ErrorCode, ErrorResponse, ExecResponse, ExecResult, Statement,
+        TransactionRequest,
+    },
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+
+use super::{
+    api_v1_queries,
+    cancel::RunningQueries,
+    envelope::{Envelope, EnvelopeParams},
+    error::status_code,
+    snapshot::SharedSnapshots,
+    transact, QueryParams,
+};
+
+/// Media type of v2 responses, clients can ask for it explicitly
+pub const V2_MEDIA_TYPE: &str = "application/vnd.corrosion.v2+json";
+
+/// v1 endpoints superseded by a v2 endpoint
+const SUCCESSORS: &[(&str, &str)] = &[
+    ("/v1/queries", "/v2/queries"),
+    ("/v1/transactions", "/v2/transactions"),
+];
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TransactionParams {
+    /// Roll back every statement if any fails
+    #[serde(default)]
+    pub transaction: bool,
+}
+
+pub async fn api_v2_transactions(
+    Extension(agent): Extension<Agent>,
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<TransactionParams>,
+    axum::extract::Json(req): axum::extract::Json<TransactionRequest>,
+) -> Response {
+    let Some(content_type) = negotiate(&headers) else {
+        return StatusCode::NOT_ACCEPTABLE.into_response();
+    };
+
+    let params = EnvelopeParams {
+        transaction: params.transaction,
+        ..Default::default()
+    };
+    let (statements, preconditions) = req.into_parts();
+    let (status, axum::Json(res)) = transact(&agent, params, statements, preconditions).await;
+
+    exec_response(status, res, content_type)
+}
+
+pub async fn api_v2_queries(
+    agent: Extension<Agent>,
+    snapshots: Extension<SharedSnapshots>,
+    queries: Extension<RunningQueries>,
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<QueryParams>,
+    stmt: axum::extract::Json<Statement>,
+) -> Response {
+    let Some(content_type) = negotiate(&headers) else {
+        return StatusCode::NOT_ACCEPTABLE.into_response();
+    };
+    if params.envelope != Envelope::Corro {
+        return error_response(
+            ErrorCode::BadRequest,
+            "envelopes are only supported by /v1/queries".into(),
+            content_type,
+        );
+    }
+
+    // v2 streams are always JSON
+    let res = api_v1_queries(
+        agent,
+        snapshots,
+        queries,
+        HeaderMap::new(),
+        axum::extract::Query(params),
+        stmt,
+    )
+    .await
+    .into_response();
+    // errors past the first row are still sent as events of the stream
+    if res.status().is_success() {
+        return res;
+    }
+
+    let status = res.status();
+    let result = hyper::body::to_bytes(res.into_body())
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice::<ExecResult>(&body).ok());
+    match result {
+        Some(ExecResult::Error { error, code }) => {
+            error_response(code.unwrap_or(ErrorCode::Unknown), error, content_type)
+        }
+        _ => status.into_response(),
+    }
+}
+
+/// Picks the content type of a response from the request's `Accept` header,
+/// `None` if the client doesn't accept JSON at all
+fn negotiate(headers: &HeaderMap) -> Option<&'static str> {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Some("application/json");
+    };
+
+    let mut json = false;
+    for range in accept.split(',') {
+        match range.split(';').next().unwrap_or_default().trim() {
+            V2_MEDIA_TYPE => return Some(V2_MEDIA_TYPE),
+            "application/json" | "application/*" | "*/*" => json = true,
+            _ => {}
+        }
+    }
+    json.then_some("application/json")
+}
+
+/// Failed requests hold a single error result, it's returned as a
+/// structured error instead
+fn exec_response(status: StatusCode, res: ExecResponse, content_type: &'static str) -> Response {
+    if !status.is_success() {
+        if let [ExecResult::Error { error, code }] = &res.results[..] {
+            return error_response(
+                code.unwrap_or(ErrorCode::Unknown),
+                error.clone(),
+                content_type,
+            );
+        }
+    }
+
+    (
+        status,
+        [(header::CONTENT_TYPE, content_type)],
+        serde_json::to_vec(&res).expect("could not serialize response"),
+    )
+        .into_response()
+}
+
+fn error_response(code: ErrorCode, message: String, content_type: &'static str) -> Response {
+    let body = ErrorResponse {
+        error: ErrorBody { code, message },
+    };
+    (
+        status_code(code),
+        [(header::CONTENT_TYPE, content_type)],
+        serde_json::to_vec(&body).expect("could not serialize error"),
+    )
+        .into_response()
+}
+
+/// Marks responses of v1 endpoints superseded by a v2 endpoint as
+/// deprecated, with a link to their successor
+pub async fn deprecate_v1(request: Request<hyper::Body>, next: Next<hyper::Body>) -> Response {
+    let successor = SUCCESSORS
+        .iter()
+        .find(|(v1, _)| *v1 == request.uri().path())
+        .map(|(_, v2)| *v2);
+
+    let mut res = next.run(request).await;
+    if let Some(successor) = successor {
+        let headers = res.headers_mut();
+        headers.insert("deprecation", HeaderValue::from_static("true"));
+        if let Ok(link) =
+            HeaderValue::from_str(&format!("<{successor}>; rel=\"successor-version\""))
+        {
+            headers.insert(header::LINK, link);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::config::Config;
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::{agent::setup, api::public::api_v1_db_schema};
+
+    #[test]
+    fn test_negotiate() {
+        let accept = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, value.parse().unwrap());
+            negotiate(&headers)
+        };
+
+        assert_eq!(negotiate(&HeaderMap::new()), Some("application/json"));
+        assert_eq!(accept("*/*"), Some("application/json"));
+        assert_eq!(
+            accept("application/json, application/vnd.corrosion.v2+json;q=0.9"),
+            Some(V2_MEDIA_TYPE)
+        );
+        assert_eq!(accept("text/html"), None);
+        assert_eq!(accept("application/vnd.corrosion.v1+json"), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_v2_errors() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let res = api_v2_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::extract::Query(TransactionParams { transaction: true }),
+            axum::Json(
+                vec![
+                    Statement::Simple("INSERT INTO tests (id, text) VALUES (1, 'one')".into()),
+                    Statement::Simple("INSERT INTO nope (id) VALUES (1)".into()),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let res: ErrorResponse = serde_json::from_slice(&body)?;
+        assert_eq!(res.error.code, ErrorCode::StatementFailed);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, V2_MEDIA_TYPE.parse()?);
+        let res = api_v2_queries(
+            Extension(agent.clone()),
+            Extension(SharedSnapshots::default()),
+            Extension(RunningQueries::default()),
+            headers,
+            axum::extract::Query(QueryParams {
+                cursor: Some("not a cursor".into()),
+                ..Default::default()
+            }),
+            axum::Json(Statement::Simple("SELECT * FROM tests".into())),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], V2_MEDIA_TYPE);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let res: ErrorResponse = serde_json::from_slice(&body)?;
+        assert_eq!(res.error.code, ErrorCode::BadRequest);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Watches on rows of a table by primary key, streamed as newline-delimited
+//! JSON events.
+
+use axum::{http::HeaderMap, response::IntoResponse, Extension};
+use bytes::{BufMut, BytesMut};
+use corro_types::{
+    agent::Agent,
+    api::{ErrorCode, KeyWatchEvent, KeyWatchRequest, TableName},
+    error::ApiError,
+    pubsub::PackError,
+    watches::KeyWatchGuard,
+};
+use hyper::StatusCode;
+use spawn::{spawn_named, Shutdown};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+use tripwire::Tripwire;
+
+use crate::api::public::{
+    compression::{with_content_encoding, BodySender, ContentEncoding},
+    error::HttpApiError,
+};
+
+const MAX_WATCHED_KEYS: usize = 100_000;
+const KEY_WATCH_BUFFER_SIZE: usize = 10240;
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyWatchError {
+    #[error("table '{0}' does not exist")]
+    UnknownTable(String),
+    #[error("at least 1 primary key is required")]
+    NoKeys,
+    #[error("too many primary keys: {0}, at most {MAX_WATCHED_KEYS} can be watched at once")]
+    TooManyKeys(usize),
+    #[error("primary keys of table '{table}' have {expected} columns, got {got}")]
+    KeyLength {
+        table: String,
+        expected: usize,
+        got: usize,
+    },
+    #[error(transparent)]
+    Pack(#[from] PackError),
+}
+
+impl ApiError for KeyWatchError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::BadRequest
+    }
+}
+
+impl From<KeyWatchError> for hyper::Response<hyper::Body> {
+    fn from(e: KeyWatchError) -> Self {
+        (e.status(), axum::Json(e.exec_result())).into_response()
+    }
+}
+
+/// Stream changes to a set of rows, given by primary key. Events only carry
+/// the columns which changed, clients wanting the whole row read it first.
+pub async fn api_v1_watch_keys(
+    Extension(agent): Extension<Agent>,
+    Extension(tripwire): Extension<Tripwire>,
+    headers: HeaderMap,
+    axum::extract::Json(req): axum::extract::Json<KeyWatchRequest>,
+) -> hyper::Response<hyper::Body> {
+    let (evt_tx, evt_rx) = mpsc::channel(KEY_WATCH_BUFFER_SIZE);
+
+    let guard = match watch_keys(&agent, req, evt_tx) {
+        Ok(guard) => guard,
+        Err(e) => return e.into(),
+    };
+    let id = guard.id();
+
+    let (body_tx, body) = hyper::Body::channel();
+    let (body_tx, content_encoding) =
+        BodySender::new(body_tx, ContentEncoding::from_headers(&headers));
+
+    spawn_named(
+        "key_watch_response_body",
+        Shutdown::Abortable,
+        forward_key_watch(guard, evt_rx, body_tx, tripwire),
+    );
+
+    with_content_encoding(hyper::Response::builder(), content_encoding)
+        .status(StatusCode::OK)
+        .header("corro-watch-id", id.to_string())
+        // events carry the actor they originated from, this tells clients
+        // which of them are this node's own writes
+        .header("corro-actor-id", agent.actor_id().to_string())
+        .body(body)
+        .expect("could not generate ok http response for key watch request")
+}
+
+fn watch_keys(
+    agent: &Agent,
+    req: KeyWatchRequest,
+    tx: mpsc::Sender<KeyWatchEvent>,
+) -> Result<KeyWatchGuard, KeyWatchError> {
+    if req.pks.is_empty() {
+        return Err(KeyWatchError::NoKeys);
+    }
+    if req.pks.len() > MAX_WATCHED_KEYS {
+        return Err(KeyWatchError::TooManyKeys(req.pks.len()));
+    }
+
+    {
+        let schema = agent.schema().read();
+        let table = schema
+            .tables
+            .get(&req.table)
+            .ok_or_else(|| KeyWatchError::UnknownTable(req.table.clone()))?;
+
+        if let Some(pk) = req.pks.iter().find(|pk| pk.len() != table.pk.len()) {
+            return Err(KeyWatchError::KeyLength {
+                table: req.table,
+                expected: table.pk.len(),
+                got: pk.len(),
+            });
+        }
+    }
+
+    info!(table = %req.table, "watching {} rows", req.pks.len());
+
+    Ok(agent
+        .key_watches()
+        .watch(TableName(req.table.into()), req.pks, tx)?)
+}
+
+async fn forward_key_watch(
+    guard: KeyWatchGuard,
+    mut evt_rx: mpsc::Receiver<KeyWatchEvent>,
+    mut body_tx: BodySender,
+    mut tripwire: Tripwire,
+) {
+    let mut buf = BytesMut::new();
+
+    loop {
+        let evt = tokio::select! {
+            evt = evt_rx.recv() => match evt {
+                Some(evt) => evt,
+                // the watch was removed from under us, it fell behind
+                None => KeyWatchEvent::Error("watch fell behind, it needs to be restarted".into()),
+            },
+            _ = &mut tripwire => {
+                debug!(watch_id = %guard.id(), "tripped, stopping key watch");
+                break;
+            }
+        };
+
+        let is_last = matches!(evt, KeyWatchEvent::Closed { .. } | KeyWatchEvent::Error(_));
+
+        let mut writer = (&mut buf).writer();
+        if let Err(e) = serde_json::to_writer(&mut writer, &evt) {
+            debug!(watch_id = %guard.id(), "could not serialize key watch event: {e}");
+            break;
+        }
+        buf.put_u8(b'\n');
+
+        if let Err(e) = body_tx.send_data(buf.split().freeze()).await {
+            debug!(watch_id = %guard.id(), "could not send key watch event, client is gone: {e}");
+            break;
+        }
+
+        if is_last {
+            break;
+        }
+    }
+    body_tx.finish().await;
+}
@@ -0,0 +1,315 @@
+//! Many subscriptions over a single WebSocket. Clients subscribe and
+//! unsubscribe with JSON text frames, events of every subscription are sent
+//! as text frames tagged with the id of the subscription they belong to,
+//! like those of watch groups.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    response::Response,
+    Extension,
+};
+use corro_types::{agent::Agent, api::Statement};
+use serde::{Deserialize, Serialize};
+use spawn::{spawn_named, Shutdown};
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+use tripwire::Tripwire;
+use uuid::Uuid;
+
+use crate::api::public::pubsub::{
+    sub_event_channel, subscribe_receiver, tag_group_events, SharedMatcherBroadcastCache,
+    SubEventReceiver, SubEventSender, SubParams,
+};
+
+const WS_EVENTS_BUFFER_SIZE: usize = 10240;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum WsRequest {
+    /// Subscribes to a statement, `ref` is chosen by the client to tell
+    /// which request a response answers
+    Subscribe {
+        #[serde(rename = "ref")]
+        reference: u64,
+        statement: Statement,
+        #[serde(flatten)]
+        params: SubParams,
+    },
+    Unsubscribe {
+        id: Uuid,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum WsResponse {
+    Subscribed {
+        #[serde(rename = "ref")]
+        reference: u64,
+        id: Uuid,
+    },
+    Unsubscribed {
+        id: Uuid,
+    },
+    Error {
+        #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+        reference: Option<u64>,
+        error: String,
+    },
+}
+
+pub async fn api_v1_ws(
+    Extension(agent): Extension<Agent>,
+    Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
+    Extension(tripwire): Extension<Tripwire>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| serve_socket(agent, bcast_cache, tripwire, socket))
+}
+
+async fn serve_socket(
+    agent: Agent,
+    bcast_cache: SharedMatcherBroadcastCache,
+    mut tripwire: Tripwire,
+    mut socket: WebSocket,
+) {
+    let (mut session, mut events_rx) = WsSession::new(agent, bcast_cache, tripwire.clone());
+
+    loop {
+        let msg = tokio::select! {
+            biased;
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let res = session.handle(&text).await;
+                    Message::Text(serde_json::to_string(&res).expect("could not serialize websocket response"))
+                },
+                // pings are answered by the socket itself
+                Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Err(e)) => {
+                    debug!("could not receive from websocket: {e}");
+                    break;
+                }
+            },
+            Some((event_buf, _)) = events_rx.recv() => {
+                // tagged events are lines of JSON
+                let event_buf = event_buf.strip_suffix(b"\n").unwrap_or(&event_buf);
+                Message::Text(String::from_utf8_lossy(event_buf).into_owned())
+            },
+            _ = &mut tripwire => break,
+        };
+
+        if let Err(e) = socket.send(msg).await {
+            debug!("could not send to websocket: {e}");
+            break;
+        }
+    }
+
+    session.close();
+}
+
+/// Subscriptions of a WebSocket, their events are sent to the same channel
+struct WsSession {
+    agent: Agent,
+    bcast_cache: SharedMatcherBroadcastCache,
+    tripwire: Tripwire,
+    events_tx: SubEventSender,
+    /// tasks tagging the events of each subscription, aborting one drops
+    /// the subscriber
+    subs: HashMap<Uuid, JoinHandle<()>>,
+}
+
+impl WsSession {
+    fn new(
+        agent: Agent,
+        bcast_cache: SharedMatcherBroadcastCache,
+        tripwire: Tripwire,
+    ) -> (Self, SubEventReceiver) {
+        let (events_tx, events_rx) = sub_event_channel(agent.budget(), WS_EVENTS_BUFFER_SIZE);
+        (
+            Self {
+                agent,
+                bcast_cache,
+                tripwire,
+                events_tx,
+                subs: HashMap::new(),
+            },
+            events_rx,
+        )
+    }
+
+    async fn handle(&mut self, text: &str) -> WsResponse {
+        let req = match serde_json::from_str(text) {
+            Ok(req) => req,
+            Err(e) => {
+                return WsResponse::Error {
+                    reference: None,
+                    error: format!("invalid request: {e}"),
+                }
+            }
+        };
+
+        match req {
+            WsRequest::Subscribe {
+                reference,
+                statement,
+                params,
+            } => {
+                let (id, rx) = match subscribe_receiver(
+                    &self.agent,
+                    &self.bcast_cache,
+                    self.tripwire.clone(),
+                    &statement,
+                    params,
+                )
+                .await
+                {
+                    Ok(res) => res,
+                    Err(e) => {
+                        return WsResponse::Error {
+                            reference: Some(reference),
+                            error: e.to_string(),
+                        }
+                    }
+                };
+
+                // every event would be sent twice otherwise
+                if self.subs.contains_key(&id) {
+                    return WsResponse::Error {
+                        reference: Some(reference),
+                        error: format!("already subscribed to {id}"),
+                    };
+                }
+
+                info!(sub_id = %id, "Subscribed over websocket");
+                let task = spawn_named(
+                    "ws_subscription",
+                    Shutdown::Abortable,
+                    tag_group_events(id, rx, self.events_tx.clone()),
+                );
+                self.subs.insert(id, task);
+
+                WsResponse::Subscribed { reference, id }
+            }
+            WsRequest::Unsubscribe { id } => match self.subs.remove(&id) {
+                Some(task) => {
+                    task.abort();
+                    WsResponse::Unsubscribed { id }
+                }
+                None => WsResponse::Error {
+                    reference: None,
+                    error: format!("not subscribed to {id}"),
+                },
+            },
+        }
+    }
+
+    fn close(self) {
+        for task in self.subs.into_values() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::{api::QueryEvent, config::Config};
+    use hyper::StatusCode;
+
+    use crate::{agent::setup, api::public::api_v1_db_schema};
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_ws_session() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (mut session, mut events_rx) =
+            WsSession::new(agent.clone(), Default::default(), tripwire.clone());
+
+        let res = session
+            .handle(r#"{"subscribe":{"ref":1,"statement":"select * from tests"}}"#)
+            .await;
+        let WsResponse::Subscribed { reference: 1, id } = res else {
+            panic!("unexpected response: {res:?}");
+        };
+
+        #[derive(Deserialize)]
+        struct Tagged {
+            id: Uuid,
+            event: QueryEvent,
+        }
+
+        let (event_buf, _) = events_rx.recv().await.unwrap();
+        let tagged: Tagged = serde_json::from_slice(&event_buf)?;
+        assert_eq!(tagged.id, id);
+        assert_eq!(
+            tagged.event,
+            QueryEvent::Columns(vec!["id".into(), "text".into()])
+        );
+
+        let res = session
+            .handle(r#"{"subscribe":{"ref":2,"statement":"select * from tests"}}"#)
+            .await;
+        assert_eq!(
+            res,
+            WsResponse::Error {
+                reference: Some(2),
+                error: format!("already subscribed to {id}"),
+            }
+        );
+
+        let res = session
+            .handle(&format!(r#"{{"unsubscribe":{{"id":"{id}"}}}}"#))
+            .await;
+        assert_eq!(res, WsResponse::Unsubscribed { id });
+
+        let res = session
+            .handle(&format!(r#"{{"unsubscribe":{{"id":"{id}"}}}}"#))
+            .await;
+        assert!(matches!(
+            res,
+            WsResponse::Error {
+                reference: None,
+                ..
+            }
+        ));
+
+        let res = session.handle("not json").await;
+        assert!(matches!(
+            res,
+            WsResponse::Error {
+                reference: None,
+                ..
+            }
+        ));
+
+        session.close();
+
+        Ok(())
+    }
+}
@@ -37,7 +37,10 @@ use tripwire::Tripwire;
 use corro_types::{
     actor::{Actor, ActorId},
     agent::Agent,
-    broadcast::{BroadcastInput, DispatchRuntime, FocaCmd, FocaInput, UniPayload, UniPayloadV1},
+    broadcast::{
+        BroadcastInput, BroadcastV1, DispatchRuntime, FocaCmd, FocaInput, UniPayload, UniPayloadV1,
+    },
+    budget::BufferKind,
     channel::{bounded, CorroReceiver, CorroSender},
 };
 
@@ -456,17 +459,25 @@ pub fn runtime_loop(
                     trace!("handling Branch::Broadcast");
                     let (bcast, is_local) = match input {
                         BroadcastInput::Rebroadcast(bcast) => (bcast, false),
-                        BroadcastInput::AddBroadcast(bcast) => (bcast, true),
+                        BroadcastInput::AddBroadcast(bcast) => {
+                            relay_broadcast(&agent, &transport, bcast.clone(), vec![]);
+                            (bcast, true)
+                        }
+                        BroadcastInput::Relay(bcast, relayed_by) => {
+                            relay_broadcast(&agent, &transport, bcast, relayed_by);
+                            continue;
+                        }
                     };
                     trace!("adding broadcast: {bcast:?}, local? {is_local}");
 
-                    if let Err(e) = (UniPayload::V1 {
-                        data: UniPayloadV1::Broadcast(bcast.clone()),
-                        cluster_id: agent.cluster_id(),
-                    })
+                    if let Err(e) = UniPayload::new(
+                        UniPayloadV1::Broadcast(bcast.clone()),
+                        agent.cluster_id(),
+                        agent.config().gossip.tagged_payloads,
+                    )
                     .write_to_stream((&mut ser_buf).writer())
                     {
-                        error!("could not encode UniPayload::V1 Broadcast: {e}");
+                        error!("could not encode UniPayload Broadcast: {e}");
                         ser_buf.clear();
                         continue;
                     }
@@ -482,6 +493,8 @@ pub fn runtime_loop(
                         }
 
                         let payload = single_bcast_buf.split().freeze();
+                        // local changes only go out through broadcasts, never shed them
+                        agent.budget().reserve(BufferKind::Broadcast, payload.len());
 
                         local_bcast_buf.extend_from_slice(&payload);
 
@@ -503,13 +516,25 @@ pub fn runtime_loop(
                             ));
                         }
                     } else {
+                        let buffered = bcast_buf.len();
                         if let Err(e) = bcast_codec.encode(ser_buf.split().freeze(), &mut bcast_buf)
                         {
                             error!("could not encode broadcast: {e}");
+                            agent.budget().release(BufferKind::Broadcast, buffered);
                             bcast_buf.clear();
                             continue;
                         }
 
+                        // peers that miss a rebroadcast get it when syncing
+                        if !agent
+                            .budget()
+                            .try_reserve(BufferKind::Broadcast, bcast_buf.len() - buffered)
+                        {
+                            bcast_buf.truncate(buffered);
+                            counter!("corro.broadcast.shed").increment(1);
+                            continue;
+                        }
+
                         if bcast_buf.len() >= BROADCAST_CUTOFF {
                             to_broadcast.push(PendingBroadcast::new(bcast_buf.split().freeze()));
                         }
@@ -522,6 +547,15 @@ pub fn runtime_loop(
                 Branch::Metrics => {
                     trace!("handling Branch::Metrics");
                     gauge!("corro.broadcast.pending.count").set(idle_pendings.len() as f64);
+                    {
+                        let replay = agent.replay().lock();
+                        gauge!("corro.broadcast.duplicates.rate")
+                            .set(replay.duplicate_stats().rate);
+                        for (ip, stats) in replay.peer_duplicate_stats() {
+                            gauge!("corro.broadcast.peer.duplicates.rate", "ip" => ip.to_string())
+                                .set(stats.rate);
+                        }
+                    }
                     gauge!("corro.broadcast.buffer.capacity").set(bcast_buf.capacity() as f64);
                     gauge!("corro.broadcast.serialization.buffer.capacity")
                         .set(ser_buf.capacity() as f64);
@@ -537,11 +571,13 @@ pub fn runtime_loop(
                     let count = members.states.len();
                     let ring0_count = members.ring0(agent.cluster_id()).count();
                     let max_transmissions = config.max_transmissions.get();
+                    let fanout = std::cmp::max(
+                        config.num_indirect_probes.get(),
+                        (count - ring0_count) / (max_transmissions as usize * 10),
+                    );
+                    // peers already know most of what they're sent in dense clusters
                     (
-                        std::cmp::max(
-                            config.num_indirect_probes.get(),
-                            (count - ring0_count) / (max_transmissions as usize * 10),
-                        ),
+                        agent.replay().lock().duplicate_stats().adapt_fanout(fanout),
                         max_transmissions,
                     )
                 };
@@ -591,8 +627,13 @@ pub fn runtime_loop(
                             tokio::time::sleep(Duration::from_millis(500)).await;
                             pending
                         }));
+                        continue;
                     }
                 }
+
+                agent
+                    .budget()
+                    .release(BufferKind::Broadcast, pending.payload.len());
             }
         }
         info!("broadcasts are done");
@@ -772,6 +813,49 @@ impl PendingBroadcast {
     }
 }
 
+/// Sends a broadcast once to the relays of other network segments, if this
+/// node is a relay. They broadcast it within their own segment.
+fn relay_broadcast(
+    agent: &Agent,
+    transport: &Transport,
+    bcast: BroadcastV1,
+    mut relayed_by: Vec<ActorId>,
+) {
+    let Some(relay) = agent.config().gossip.relay.clone() else {
+        return;
+    };
+
+    if relayed_by.len() >= relay.max_hops {
+        counter!("corro.relay.max_hops").increment(1);
+        return;
+    }
+    relayed_by.push(agent.actor_id());
+
+    let mut ser_buf = BytesMut::new();
+    if let Err(e) = UniPayload::new(
+        UniPayloadV1::Relayed { bcast, relayed_by },
+        agent.cluster_id(),
+        agent.config().gossip.tagged_payloads,
+    )
+    .write_to_stream((&mut ser_buf).writer())
+    {
+        error!("could not encode UniPayload Relayed: {e}");
+        return;
+    }
+
+    let mut buf = BytesMut::new();
+    if let Err(e) = LengthDelimitedCodec::new().encode(ser_buf.freeze(), &mut buf) {
+        error!("could not encode relayed broadcast: {e}");
+        return;
+    }
+    let payload = buf.freeze();
+
+    for addr in relay.peers {
+        tokio::spawn(transmit_broadcast(payload.clone(), transport.clone(), addr));
+        counter!("corro.relay.sent").increment(1);
+    }
+}
+
 #[tracing::instrument(skip(payload, transport), fields(buf_size = payload.len()), level = "debug")]
 async fn transmit_broadcast(payload: Bytes, transport: Transport, addr: SocketAddr) {
     trace!("singly broadcasting to {addr}");
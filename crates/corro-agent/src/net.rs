@@ -0,0 +1,79 @@
+//! Sockets for the gossip and API listeners. IPv6 addresses are bound
+//! dual-stack unless configured otherwise, instead of relying on the OS
+//! default (e.g. `net.ipv6.bindv6only` on Linux).
+
+use std::{io, net::SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+fn new_socket(
+    addr: SocketAddr,
+    ty: Type,
+    protocol: Protocol,
+    ipv6_only: bool,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub fn bind_udp(addr: SocketAddr, ipv6_only: bool) -> io::Result<std::net::UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP, ipv6_only)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Same as [`tokio::net::TcpListener::bind`], with control over dual-stack
+pub fn bind_tcp(addr: SocketAddr, ipv6_only: bool) -> io::Result<tokio::net::TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, Protocol::TCP, ipv6_only)?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Whether a socket bound to `local` can send to `remote`
+pub fn can_reach(local: SocketAddr, ipv6_only: bool, remote: SocketAddr) -> bool {
+    match (local, remote) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => true,
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => false,
+        // IPv4 is mapped into IPv6 on dual-stack sockets bound to any address
+        (SocketAddr::V6(local), SocketAddr::V4(_)) => !ipv6_only && local.ip().is_unspecified(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_can_reach() {
+        let any_v4: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let any_v6: SocketAddr = "[::]:0".parse().unwrap();
+        let loopback_v6: SocketAddr = "[::1]:0".parse().unwrap();
+        let v4: SocketAddr = "10.0.0.1:8787".parse().unwrap();
+        let v6: SocketAddr = "[fdaa::1]:8787".parse().unwrap();
+
+        assert!(can_reach(any_v4, false, v4));
+        assert!(!can_reach(any_v4, false, v6));
+        assert!(can_reach(any_v6, false, v4));
+        assert!(can_reach(any_v6, false, v6));
+        assert!(!can_reach(any_v6, true, v4));
+        assert!(!can_reach(loopback_v6, false, v4));
+    }
+
+    #[tokio::test]
+    async fn test_bind_dual_stack() -> io::Result<()> {
+        let udp = bind_udp("[::]:0".parse().unwrap(), false)?;
+        assert!(!socket2::SockRef::from(&udp).only_v6()?);
+
+        let tcp = bind_tcp("[::]:0".parse().unwrap(), true)?;
+        assert!(socket2::SockRef::from(&tcp).only_v6()?);
+
+        Ok(())
+    }
+}
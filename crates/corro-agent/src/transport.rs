@@ -161,6 +161,18 @@ impl Transport {
             .await?)
     }
 
+    /// RTT and estimated bandwidth, in bytes per second, of the connection
+    /// to `addr`
+    pub async fn path_stats(&self, addr: SocketAddr) -> Option<(Duration, u64)> {
+        let conn_lock = self.0.conns.read().await.get(&addr).cloned()?;
+        let lock = conn_lock.lock().await;
+        let conn = lock.as_ref().filter(|conn| test_conn(conn))?;
+        let rtt = conn.rtt();
+        // a congestion window's worth of bytes is in flight every round trip
+        let bandwidth = (conn.stats().path.cwnd as f64 / rtt.as_secs_f64().max(1e-6)) as u64;
+        Some((rtt, bandwidth))
+    }
+
     async fn measured_connect(
         &self,
         addr: SocketAddr,
@@ -1,13 +1,14 @@
 use std::{
     borrow::Borrow,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::{self, Write},
     hash::Hash,
+    net::SocketAddr,
     ops::{AddAssign, Deref},
 };
 
 use compact_str::CompactString;
-use corro_base_types::{CrsqlDbVersion, CrsqlSeq};
+use corro_base_types::{CrsqlDbVersion, CrsqlSeq, Version};
 use rusqlite::{
     types::{FromSql, FromSqlError, ToSqlOutput, Value, ValueRef},
     Row, ToSql,
@@ -17,6 +18,7 @@ use serde_json::value::RawValue;
 use smallvec::{SmallVec, ToSmallVec};
 use speedy::{Context, Readable, Reader, Writable, Writer};
 use sqlite::ChangeType;
+use uuid::Uuid;
 
 pub mod sqlite;
 
@@ -26,24 +28,80 @@ pub type QueryEvent = TypedQueryEvent<Vec<SqliteValue>>;
 #[serde(rename_all = "snake_case")]
 pub enum TypedQueryEvent<T> {
     Columns(Vec<ColumnName>),
+    /// Rough number of rows the query will return, sent before its rows
+    Estimate {
+        rows: u64,
+    },
+    /// Rows returned so far, sent periodically during long queries
+    Progress {
+        rows: u64,
+        time: f64,
+    },
     Row(RowId, T),
     #[serde(rename = "eoq")]
     EndOfQuery {
         time: f64,
         #[serde(skip_serializing_if = "Option::is_none")]
         change_id: Option<ChangeId>,
+        /// Cursor of the next page, when the query was paginated and more
+        /// rows may follow
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
+    },
+    /// A row changed, the last value is the actor the change originated
+    /// from, unknown when the change was found by recomputing the query
+    Change(ChangeType, RowId, T, ChangeId, Option<CompactString>),
+    /// Replication metadata of the row sent right before, when requested
+    Meta(RowId, Vec<CellMeta>),
+    /// Values of the row updated by the change sent right before, as they
+    /// were before the update, when requested
+    Previous(RowId, T),
+    /// The subscription recovered from an error by recomputing its rows, the
+    /// changes that follow bring them back in line
+    Resync {
+        reason: CompactString,
+    },
+    /// The server dropped `count` events this listener was too slow to
+    /// receive: rows it knows about may be stale until it resumes from its
+    /// last change id or subscribes again
+    Dropped {
+        count: u64,
+    },
+    /// Last event of a subscription ended by the server
+    Closed {
+        reason: CloseReason,
+    },
+    /// Last event of a subscription handed off to another node: listeners
+    /// should reconnect to subscription `id` at `addr`, which sends its rows
+    /// again from the start
+    Moved {
+        addr: SocketAddr,
+        id: Uuid,
     },
-    Change(ChangeType, RowId, T, ChangeId),
     Error(CompactString),
+    /// Changes up to `change_id` didn't match the listener's filter and were
+    /// left out, the next change follows it
+    Skipped {
+        change_id: ChangeId,
+    },
 }
 
 impl<T> TypedQueryEvent<T> {
     pub fn meta(&self) -> QueryEventMeta {
         match self {
             TypedQueryEvent::Columns(_) => QueryEventMeta::Columns,
+            TypedQueryEvent::Estimate { .. } => QueryEventMeta::Estimate,
+            TypedQueryEvent::Progress { .. } => QueryEventMeta::Progress,
             TypedQueryEvent::Row(rowid, _) => QueryEventMeta::Row(*rowid),
             TypedQueryEvent::EndOfQuery { change_id, .. } => QueryEventMeta::EndOfQuery(*change_id),
-            TypedQueryEvent::Change(_, _, _, id) => QueryEventMeta::Change(*id),
+            TypedQueryEvent::Change(_, _, _, id, _) => QueryEventMeta::Change(*id),
+            TypedQueryEvent::Meta(rowid, _) => QueryEventMeta::Meta(*rowid),
+            TypedQueryEvent::Previous(rowid, _) => QueryEventMeta::Previous(*rowid),
+            TypedQueryEvent::Resync { .. } => QueryEventMeta::Resync,
+            TypedQueryEvent::Dropped { .. } => QueryEventMeta::Dropped,
+            TypedQueryEvent::Skipped { change_id } => QueryEventMeta::Skipped(*change_id),
+            TypedQueryEvent::Closed { .. } => QueryEventMeta::Closed,
+            TypedQueryEvent::Moved { .. } => QueryEventMeta::Moved,
             TypedQueryEvent::Error(_) => QueryEventMeta::Error,
         }
     }
@@ -52,12 +110,53 @@ impl<T> TypedQueryEvent<T> {
 #[derive(Debug, Clone, Copy)]
 pub enum QueryEventMeta {
     Columns,
+    Estimate,
+    Progress,
     Row(RowId),
     EndOfQuery(Option<ChangeId>),
     Change(ChangeId),
+    Meta(RowId),
+    Previous(RowId),
+    Resync,
+    Dropped,
+    Skipped(ChangeId),
+    Closed,
+    Moved,
     Error,
 }
 
+/// Why the server ended a subscription
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The subscription reached its maximum lifetime
+    MaxLifetime,
+    /// Nobody listened to the subscription for too long
+    Idle,
+    /// The subscription was deleted through the API
+    Deleted,
+    /// A table the subscription reads from was dropped
+    TableDropped,
+}
+
+/// Replication metadata of a table cell: where its current value came from
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CellMeta {
+    pub table: TableName,
+    pub column: ColumnName,
+    /// Actor the value originated from
+    pub actor_id: CompactString,
+    /// Version of the originating actor, unknown once cleared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+    /// Local db version the value was written at
+    pub db_version: CrsqlDbVersion,
+    pub col_version: i64,
+    /// HLC timestamp of the originating transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<CompactString>,
+}
+
 /// RowId newtype to differentiate from ChangeId
 #[derive(
     Debug,
@@ -198,6 +297,78 @@ impl Statement {
             | Statement::WithNamedParams(query, _) => query,
         }
     }
+
+    pub fn query_mut(&mut self) -> &mut String {
+        match self {
+            Statement::Verbose { query, .. }
+            | Statement::Simple(query)
+            | Statement::WithParams(query, _)
+            | Statement::WithNamedParams(query, _) => query,
+        }
+    }
+
+    /// Binds the statement's parameters to `prepped`, prepared from its
+    /// query. Named parameters can be given with or without their `:`, `@`
+    /// or `$` prefix. Positional parameters fill the query's `?` slots in
+    /// order, or every slot when no named parameters are given. Unknown
+    /// names and a wrong number of positional parameters are errors.
+    pub fn bind_parameters(&self, prepped: &mut rusqlite::Statement<'_>) -> rusqlite::Result<()> {
+        let (params, named_params) = match self {
+            Statement::Simple(_) => (None, None),
+            Statement::WithParams(_, params) => (Some(params), None),
+            Statement::WithNamedParams(_, params) => (None, Some(params)),
+            Statement::Verbose {
+                params,
+                named_params,
+                ..
+            } => (params.as_ref(), named_params.as_ref()),
+        };
+
+        if let Some(params) = params {
+            // slots are numbered in the order parameters appear in the query,
+            // named and positional ones alike
+            let slots: Vec<usize> = (1..=prepped.parameter_count())
+                .filter(|idx| {
+                    named_params.is_none()
+                        || prepped
+                            .parameter_name(*idx)
+                            .map_or(true, |name| name.starts_with('?'))
+                })
+                .collect();
+            if params.len() != slots.len() {
+                return Err(rusqlite::Error::InvalidParameterCount(
+                    params.len(),
+                    slots.len(),
+                ));
+            }
+            for (idx, param) in slots.into_iter().zip(params.iter()) {
+                prepped.raw_bind_parameter(idx, param)?;
+            }
+        }
+
+        for (name, param) in named_params.into_iter().flatten() {
+            let idx = named_parameter_index(prepped, name)?
+                .ok_or_else(|| rusqlite::Error::InvalidParameterName(name.clone()))?;
+            prepped.raw_bind_parameter(idx, param)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn named_parameter_index(
+    prepped: &rusqlite::Statement<'_>,
+    name: &str,
+) -> rusqlite::Result<Option<usize>> {
+    if name.starts_with([':', '@', '$']) {
+        return prepped.parameter_index(name);
+    }
+    for prefix in [':', '@', '$'] {
+        if let Some(idx) = prepped.parameter_index(&format!("{prefix}{name}"))? {
+            return Ok(Some(idx));
+        }
+    }
+    Ok(None)
 }
 
 impl From<&str> for Statement {
@@ -206,17 +377,302 @@ impl From<&str> for Statement {
     }
 }
 
+/// Body of `POST /v1/transactions`: statements alone, or along with
+/// preconditions checked before they run
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum TransactionRequest {
+    Statements(Vec<Statement>),
+    Conditional {
+        statements: Vec<Statement>,
+        #[serde(default)]
+        preconditions: Vec<Precondition>,
+    },
+}
+
+impl TransactionRequest {
+    pub fn into_parts(self) -> (Vec<Statement>, Vec<Precondition>) {
+        match self {
+            TransactionRequest::Statements(statements) => (statements, vec![]),
+            TransactionRequest::Conditional {
+                statements,
+                preconditions,
+            } => (statements, preconditions),
+        }
+    }
+}
+
+impl From<Vec<Statement>> for TransactionRequest {
+    fn from(statements: Vec<Statement>) -> Self {
+        TransactionRequest::Statements(statements)
+    }
+}
+
+/// Dispatches on the shape of the body so errors point at what's wrong in
+/// it, rather than saying it matches no variant
+impl<'de> Deserialize<'de> for TransactionRequest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{
+            value::{MapAccessDeserializer, SeqAccessDeserializer},
+            MapAccess, SeqAccess, Visitor,
+        };
+
+        #[derive(Deserialize)]
+        struct Conditional {
+            statements: Vec<Statement>,
+            #[serde(default)]
+            preconditions: Vec<Precondition>,
+        }
+
+        struct RequestVisitor;
+
+        impl<'de> Visitor<'de> for RequestVisitor {
+            type Value = TransactionRequest;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(
+                    "an array of statements, or an object with statements and preconditions",
+                )
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq))
+                    .map(TransactionRequest::Statements)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let Conditional {
+                    statements,
+                    preconditions,
+                } = Conditional::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(TransactionRequest::Conditional {
+                    statements,
+                    preconditions,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(RequestVisitor)
+    }
+}
+
+/// Checked within a transaction, before its statements run. The whole
+/// transaction is rolled back if it doesn't hold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Precondition {
+    /// The statement returns exactly these rows, in order
+    Rows {
+        statement: Statement,
+        rows: Vec<Vec<SqliteValue>>,
+    },
+    /// cr-sqlite's version of a column of a row, `0` when it was never
+    /// written or the row was deleted
+    ColVersion {
+        table: String,
+        pk: Vec<SqliteValue>,
+        column: String,
+        col_version: i64,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExecResponse {
     pub results: Vec<ExecResult>,
     pub time: f64,
 }
 
+/// Response to a migration, an [`ExecResponse`] with the backfills it started
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MigrationResponse {
+    pub results: Vec<ExecResult>,
+    pub time: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backfills: Vec<BackfillStatus>,
+}
+
+/// Response to a schema change, dry runs return what the change would do
+/// instead of applying it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SchemaChangeResponse {
+    pub results: Vec<ExecResult>,
+    pub time: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<SchemaPlan>,
+}
+
+/// Operations a schema change applies to the current schema
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SchemaPlan {
+    pub tables_added: Vec<String>,
+    /// Tables dropped with `DROP TABLE`, along with their rows
+    pub tables_dropped: Vec<String>,
+    pub columns_added: Vec<SchemaColumnRef>,
+    /// Columns removed from their table's definition, along with their values
+    pub columns_dropped: Vec<SchemaColumnRef>,
+    pub columns_renamed: Vec<SchemaColumnRename>,
+    /// Tables recreated because some of their columns were changed, dropped
+    /// or renamed, their rows are copied over to the new definition
+    pub tables_rebuilt: Vec<String>,
+    pub indexes_added: Vec<String>,
+    pub indexes_dropped: Vec<String>,
+    /// Indexes dropped and created again because their definition changed
+    pub indexes_replaced: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SchemaColumnRef {
+    pub table: String,
+    pub column: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SchemaColumnRename {
+    pub table: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// Tables of the schema applied to a node, in the order they were created
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SchemaResponse {
+    pub tables: Vec<TableSchema>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TableSchema {
+    pub name: String,
+    /// Primary key columns, in order
+    pub pk: Vec<String>,
+    pub columns: Vec<ColumnSchema>,
+    pub indexes: Vec<IndexSchema>,
+    /// `CREATE TABLE` statement of the table
+    pub sql: String,
+    /// How the table was applied, as tracked by the node: `api` for schema
+    /// statements
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ColumnSchema {
+    pub name: String,
+    /// Declared type, as written in the schema
+    #[serde(rename = "type")]
+    pub sql_type: Option<String>,
+    pub nullable: bool,
+    pub primary_key: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_value: Option<String>,
+    /// Expression of generated columns
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct IndexSchema {
+    pub name: String,
+    /// `CREATE INDEX` statement of the index
+    pub sql: String,
+    /// How the index was applied: `api` for schema statements, `admin` for
+    /// indexes picked up by a schema resync
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum ExecResult {
-    Execute { rows_affected: usize, time: f64 },
-    Error { error: String },
+    /// Result of a write statement with a `RETURNING` clause, holding the
+    /// rows it returned. It comes first: it would otherwise be read as an
+    /// [`ExecResult::Execute`]
+    Returning {
+        rows_affected: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_insert_id: Option<i64>,
+        columns: Vec<ColumnName>,
+        rows: Vec<Vec<SqliteValue>>,
+        time: f64,
+    },
+    Execute {
+        rows_affected: usize,
+        /// Rowid of the last row inserted by the connection, only returned
+        /// with the rqlite envelope
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_insert_id: Option<i64>,
+        time: f64,
+    },
+    /// Rows returned by a read-only statement, when the agent is
+    /// configured to run them on a read connection
+    Query {
+        columns: Vec<ColumnName>,
+        rows: Vec<Vec<SqliteValue>>,
+        time: f64,
+    },
+    Error {
+        error: String,
+        /// Absent from the responses of older agents
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+}
+
+impl ExecResult {
+    pub fn error(code: ErrorCode, error: impl ToString) -> Self {
+        ExecResult::Error {
+            error: error.to_string(),
+            code: Some(code),
+        }
+    }
+}
+
+/// Stable identifiers of the errors returned by the API, clients can match
+/// on them rather than on error messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request is malformed or refers to tables, columns or snapshots
+    /// that don't exist
+    BadRequest,
+    NotFound,
+    /// A statement could not be prepared, or its parameters don't match it
+    InvalidStatement,
+    /// A statement failed to run. In an all-or-nothing transaction,
+    /// nothing was committed
+    StatementFailed,
+    /// The changes were rejected by a validator
+    Vetoed,
+    /// A precondition of the transaction didn't hold, nothing was committed
+    PreconditionFailed,
+    /// Writes are held back by a schema migration, retry later
+    Fenced,
+    /// No connection could be acquired, the agent is overloaded or shutting
+    /// down
+    Unavailable,
+    /// The schema could not be parsed or applied
+    InvalidSchema,
+    /// Syncing with a peer failed
+    SyncFailed,
+    /// The peer rejected the sync
+    SyncRejected,
+    /// Any other database error
+    Database,
+    Internal,
+    /// Returned by a newer agent
+    #[serde(other)]
+    Unknown,
+}
+
+/// Body of a failed request to a v2 endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TableStatRequest {
@@ -230,6 +686,188 @@ pub struct TableStatResponse {
     pub invalid_tables: Vec<String>,
 }
 
+/// Delete every row of a table, or the rows whose first primary key column
+/// is within `start..end`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TruncateRequest {
+    pub table: String,
+    /// Inclusive lower bound of the first primary key column
+    #[serde(default)]
+    pub start: Option<SqliteValue>,
+    /// Exclusive upper bound of the first primary key column
+    #[serde(default)]
+    pub end: Option<SqliteValue>,
+}
+
+/// Progressively set a column for every row of a table, in small
+/// transactions, so the changes don't flood the cluster
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillRequest {
+    pub table: String,
+    pub column: String,
+    /// SQL expression evaluated for each row, e.g. `'pending'` or `lower(name)`
+    pub expr: String,
+    /// SQL condition restricting which rows are updated
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// Maximum number of rows scanned per transaction
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// Pause between transactions, in milliseconds
+    #[serde(default)]
+    pub interval_ms: Option<u64>,
+    /// Also rewrite rows that already hold the value, so they're replicated
+    /// and replace whatever other nodes have for them
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackfillState {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillStatus {
+    pub id: u64,
+    pub table: String,
+    pub column: String,
+    pub state: BackfillState,
+    /// Rows in the table when the backfill started
+    pub total_rows: u64,
+    pub rows_scanned: u64,
+    pub rows_updated: u64,
+    pub batches: u64,
+    pub elapsed: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Progress of an import, streamed while its rows are applied
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportEvent {
+    /// Rows applied so far, sent after every transaction
+    Progress { rows: u64, batches: u64 },
+    /// Every row was applied
+    Done { rows: u64, batches: u64, time: f64 },
+    /// The import stopped, transactions committed before the error are kept
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        line: Option<u64>,
+        error: String,
+    },
+}
+
+/// Rows to insert in a table, or to update when a row with their primary key
+/// exists. `columns` names the column of each value of the rows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpsertRequest {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<SqliteValue>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct UpsertResult {
+    pub rows_affected: usize,
+    /// Rows which weren't applied, the others are
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<UpsertRowError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UpsertRowError {
+    /// Index of the row in its request
+    pub row: usize,
+    pub error: String,
+}
+
+/// Results of upserts, in the order of their requests
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UpsertResponse {
+    pub results: Vec<UpsertResult>,
+    pub time: f64,
+}
+
+/// Digest the contents of tables, to compare them with another node's
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DigestRequest {
+    /// Tables to digest, every table of the schema when empty
+    #[serde(default)]
+    pub tables: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TableDigest {
+    pub table: String,
+    pub rows: u64,
+    /// Rows are spread in buckets by primary key, each bucket's digest
+    /// combines the digests of its rows
+    pub buckets: Vec<u64>,
+}
+
+/// Digest every row of the given buckets of a table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RowDigestRequest {
+    pub table: String,
+    pub buckets: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RowDigest {
+    pub pk: Vec<SqliteValue>,
+    pub digest: u64,
+}
+
+/// Watch rows of a table by primary key, each key listing the values of the
+/// primary key columns in order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyWatchRequest {
+    pub table: String,
+    pub pks: Vec<Vec<SqliteValue>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyWatchEvent {
+    /// The row was inserted or updated, `cells` only holds the columns which
+    /// changed
+    Upsert {
+        table: TableName,
+        pk: Vec<SqliteValue>,
+        cells: BTreeMap<ColumnName, SqliteValue>,
+        /// Actor the change originated from, compare it with the node's own
+        /// actor id to tell local writes from remote ones
+        #[serde(default)]
+        actor_id: CompactString,
+    },
+    Delete {
+        table: TableName,
+        pk: Vec<SqliteValue>,
+        /// Actor the change originated from
+        #[serde(default)]
+        actor_id: CompactString,
+    },
+    /// Last event of a watch the server ended
+    Closed {
+        reason: CloseReason,
+    },
+    Error(CompactString),
+}
+
+/// An actor whose versions a node knows, to find out whose changes to poll
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChangesActor {
+    pub actor_id: Uuid,
+    /// Highest version of the actor known to the node
+    pub last_version: Version,
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, Readable, Writable, PartialEq)]
 pub struct Change {
     pub table: TableName,
@@ -894,6 +1532,141 @@ impl ToSql for ColumnName {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_bind_parameters() -> rusqlite::Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE tests (id INTEGER PRIMARY KEY, text TEXT);
+             INSERT INTO tests VALUES (1, 'one'), (2, 'two'), (3, 'three');",
+        )?;
+
+        let query = |stmt: Statement| -> rusqlite::Result<Vec<i64>> {
+            let mut prepped = conn.prepare(stmt.query())?;
+            stmt.bind_parameters(&mut prepped)?;
+            let mut rows = prepped.raw_query();
+            let mut ids = vec![];
+            while let Some(row) = rows.next()? {
+                ids.push(row.get(0)?);
+            }
+            Ok(ids)
+        };
+
+        assert_eq!(
+            query(Statement::WithParams(
+                "SELECT id FROM tests WHERE id > ? AND text != ?".into(),
+                vec![1i64.into(), "two".into()],
+            ))?,
+            vec![3]
+        );
+
+        // bound values are never read as SQL
+        assert_eq!(
+            query(Statement::WithParams(
+                "SELECT id FROM tests WHERE text = ?".into(),
+                vec!["one' OR '1'='1".into()],
+            ))?,
+            Vec::<i64>::new()
+        );
+
+        assert_eq!(
+            query(Statement::WithNamedParams(
+                "SELECT id FROM tests WHERE id >= :min AND id <= @max".into(),
+                [
+                    ("min".to_string(), 2i64.into()),
+                    ("@max".to_string(), 3i64.into())
+                ]
+                .into(),
+            ))?,
+            vec![2, 3]
+        );
+
+        assert_eq!(
+            query(Statement::Verbose {
+                query: "SELECT id FROM tests WHERE id > ? AND text != $text".into(),
+                params: Some(vec![1i64.into()]),
+                named_params: Some([("text".to_string(), "three".into())].into()),
+            })?,
+            vec![2]
+        );
+
+        assert!(matches!(
+            query(Statement::WithParams(
+                "SELECT id FROM tests WHERE id > ?".into(),
+                vec![1i64.into(), 2i64.into()],
+            )),
+            Err(rusqlite::Error::InvalidParameterCount(2, 1))
+        ));
+        assert!(matches!(
+            query(Statement::WithNamedParams(
+                "SELECT id FROM tests WHERE id > :min".into(),
+                [("max".to_string(), 1i64.into())].into(),
+            )),
+            Err(rusqlite::Error::InvalidParameterName(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_mixed_parameters() -> rusqlite::Result<()> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE tests (id INTEGER PRIMARY KEY, text TEXT);
+             INSERT INTO tests VALUES (1, 'one'), (2, 'two'), (3, 'three');",
+        )?;
+
+        let query = |stmt: Statement| -> rusqlite::Result<Vec<i64>> {
+            let mut prepped = conn.prepare(stmt.query())?;
+            stmt.bind_parameters(&mut prepped)?;
+            let mut rows = prepped.raw_query();
+            let mut ids = vec![];
+            while let Some(row) = rows.next()? {
+                ids.push(row.get(0)?);
+            }
+            Ok(ids)
+        };
+
+        // the named parameter takes the first slot
+        assert_eq!(
+            query(Statement::Verbose {
+                query: "SELECT id FROM tests WHERE text != :text AND id > ? AND id < ?".into(),
+                params: Some(vec![1i64.into(), 3i64.into()]),
+                named_params: Some([("text".to_string(), "three".into())].into()),
+            })?,
+            vec![2]
+        );
+
+        // numbered slots are positional too
+        assert_eq!(
+            query(Statement::Verbose {
+                query: "SELECT id FROM tests WHERE text != @text AND id = ?1".into(),
+                params: Some(vec![3i64.into()]),
+                named_params: Some([("text".to_string(), "one".into())].into()),
+            })?,
+            vec![3]
+        );
+
+        // without named parameters, named slots are filled by position
+        assert_eq!(
+            query(Statement::WithParams(
+                "SELECT id FROM tests WHERE id = :id".into(),
+                vec![2i64.into()],
+            ))?,
+            vec![2]
+        );
+
+        assert!(matches!(
+            query(Statement::Verbose {
+                query: "SELECT id FROM tests WHERE text != :text AND id > ?".into(),
+                params: Some(vec![1i64.into(), 2i64.into()]),
+                named_params: Some([("text".to_string(), "one".into())].into()),
+            }),
+            Err(rusqlite::Error::InvalidParameterCount(2, 1))
+        ));
+
+        Ok(())
+    }
+
     #[test]
     fn test_statement_serialization() {
         let s = serde_json::to_string(&vec![Statement::WithParams(
@@ -924,4 +1697,28 @@ mod tests {
         let stmts: Vec<Statement> = serde_json::from_str(json).unwrap();
         println!("stmts: {stmts:?}");
     }
+
+    #[test]
+    fn test_transaction_request_deserialization() {
+        let req: TransactionRequest = serde_json::from_str(r#"["select 1"]"#).unwrap();
+        assert!(matches!(req, TransactionRequest::Statements(statements) if statements.len() == 1));
+
+        let req: TransactionRequest = serde_json::from_str(
+            r#"{"statements": ["select 1"], "preconditions": [{"table": "tests", "pk": [1], "column": "text", "col_version": 0}]}"#,
+        )
+        .unwrap();
+        let (statements, preconditions) = req.into_parts();
+        assert_eq!(statements.len(), 1);
+        assert!(matches!(
+            preconditions[..],
+            [Precondition::ColVersion { .. }]
+        ));
+
+        // errors say what's wrong with the body's own shape
+        let e = serde_json::from_str::<TransactionRequest>(r#"{"preconditions": []}"#).unwrap_err();
+        assert!(e.to_string().contains("missing field `statements`"), "{e}");
+
+        let e = serde_json::from_str::<TransactionRequest>(r#""select 1""#).unwrap_err();
+        assert!(e.to_string().contains("an array of statements"), "{e}");
+    }
 }
@@ -0,0 +1,251 @@
+//! Blocking facade over [`CorrosionApiClient`], for programs that don't run
+//! tokio themselves. Requests are driven by a dedicated runtime, so these
+//! functions must not be called from within an async context.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use corro_api_types::{
+    ChangeId, ColumnName, ExecResponse, SqliteValue, Statement, TypedQueryEvent,
+};
+use futures::{FutureExt, StreamExt};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+use crate::{
+    sub::{SubscriptionError, SubscriptionStream},
+    CorrosionApiClient, Error,
+};
+
+/// Columns and rows of a query
+#[derive(Debug, Clone, Default)]
+pub struct QueryRows {
+    pub columns: Vec<ColumnName>,
+    pub rows: Vec<Vec<SqliteValue>>,
+}
+
+#[derive(Clone)]
+pub struct BlockingClient {
+    client: CorrosionApiClient,
+    rt: Arc<Runtime>,
+}
+
+impl BlockingClient {
+    pub fn new(api_addr: SocketAddr) -> io::Result<Self> {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("corro-client")
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            client: CorrosionApiClient::new(api_addr),
+            rt: Arc::new(rt),
+        })
+    }
+
+    pub fn execute(&self, statements: &[Statement]) -> Result<ExecResponse, Error> {
+        self.rt.block_on(self.client.execute(statements))
+    }
+
+    /// Runs a query and collects all of its rows
+    pub fn query_all(&self, statement: &Statement) -> Result<QueryRows, Error> {
+        self.rt.block_on(async {
+            let mut stream = self.client.query(statement).await?;
+            let mut rows = QueryRows::default();
+            while let Some(evt) = stream.next().await {
+                match evt? {
+                    TypedQueryEvent::Columns(columns) => rows.columns = columns,
+                    TypedQueryEvent::Row(_, row) => rows.rows.push(row),
+                    TypedQueryEvent::EndOfQuery { .. } => break,
+                    TypedQueryEvent::Error(e) => return Err(Error::ResponseError(e.to_string())),
+                    _ => {}
+                }
+            }
+            Ok(rows)
+        })
+    }
+
+    pub fn subscribe(
+        &self,
+        statement: &Statement,
+        skip_rows: bool,
+        from: Option<ChangeId>,
+    ) -> Result<BlockingSubscription, Error> {
+        let stream = self
+            .rt
+            .block_on(self.client.subscribe(statement, skip_rows, from))?;
+        Ok(BlockingSubscription {
+            rt: self.rt.clone(),
+            stream,
+            closed: false,
+        })
+    }
+
+    pub fn subscription(
+        &self,
+        id: Uuid,
+        skip_rows: bool,
+        from: Option<ChangeId>,
+    ) -> Result<BlockingSubscription, Error> {
+        let stream = self
+            .rt
+            .block_on(self.client.subscription(id, skip_rows, from))?;
+        Ok(BlockingSubscription {
+            rt: self.rt.clone(),
+            stream,
+            closed: false,
+        })
+    }
+}
+
+/// Subscription polled for events, it reconnects on its own like
+/// [`SubscriptionStream`]
+pub struct BlockingSubscription {
+    rt: Arc<Runtime>,
+    stream: SubscriptionStream<Vec<SqliteValue>>,
+    closed: bool,
+}
+
+impl BlockingSubscription {
+    pub fn id(&self) -> Uuid {
+        self.stream.id()
+    }
+
+    /// Whether the subscription ended, no more events will be received
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Waits up to `timeout` for events, and returns every event received
+    /// by then. Empty if none arrived in time or the subscription ended.
+    pub fn poll(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<TypedQueryEvent<Vec<SqliteValue>>>, SubscriptionError> {
+        let Self { rt, stream, closed } = self;
+        if *closed {
+            return Ok(vec![]);
+        }
+
+        rt.block_on(async {
+            let mut events = vec![];
+            match tokio::time::timeout(timeout, stream.next()).await {
+                Ok(Some(evt)) => events.push(evt?),
+                Ok(None) => *closed = true,
+                Err(_) => return Ok(events),
+            }
+
+            // take what's already buffered without waiting further
+            while !*closed {
+                match stream.next().now_or_never() {
+                    Some(Some(evt)) => events.push(evt?),
+                    Some(None) => *closed = true,
+                    None => break,
+                }
+            }
+
+            Ok(events)
+        })
+    }
+}
+
+impl Iterator for BlockingSubscription {
+    type Item = Result<TypedQueryEvent<Vec<SqliteValue>>, SubscriptionError>;
+
+    /// Blocks until the next event
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.closed {
+            return None;
+        }
+        let res = self.rt.block_on(self.stream.next());
+        self.closed = res.is_none();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_api_types::{sqlite::ChangeType, ExecResult};
+    use corro_tests::launch_test_agent;
+    use tripwire::Tripwire;
+
+    use super::*;
+
+    #[test]
+    fn test_blocking_client() {
+        // the agent runs on its own runtime, the client must not be called
+        // from within one
+        let rt = Runtime::new().unwrap();
+        let (tripwire, _trip_worker, _trip_sender) = rt.block_on(async { Tripwire::new_simple() });
+        let ta = rt
+            .block_on(launch_test_agent(|conf| conf.build(), tripwire.clone()))
+            .unwrap();
+
+        let client = BlockingClient::new(ta.agent.api_addr()).unwrap();
+
+        let insert = |id: i64, text: &str| {
+            client
+                .execute(&[Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![id.into(), text.into()],
+                )])
+                .unwrap()
+        };
+
+        let res = insert(1, "one");
+        assert!(matches!(
+            res.results[..],
+            [ExecResult::Execute {
+                rows_affected: 1,
+                ..
+            }]
+        ));
+
+        let rows = client
+            .query_all(&Statement::Simple("select id, text from tests".into()))
+            .unwrap();
+        assert_eq!(
+            rows.columns,
+            vec![ColumnName("id".into()), ColumnName("text".into())]
+        );
+        assert_eq!(rows.rows, vec![vec![1i64.into(), "one".into()]]);
+
+        let mut sub = client
+            .subscribe(
+                &Statement::Simple("select text from tests".into()),
+                false,
+                None,
+            )
+            .unwrap();
+
+        let mut events = vec![];
+        while !events
+            .iter()
+            .any(|evt| matches!(evt, TypedQueryEvent::EndOfQuery { .. }))
+        {
+            let polled = sub.poll(Duration::from_secs(5)).unwrap();
+            assert!(!polled.is_empty(), "timed out waiting for the rows");
+            events.extend(polled);
+        }
+        assert!(matches!(events[0], TypedQueryEvent::Columns(_)));
+        assert!(matches!(
+            &events[1],
+            TypedQueryEvent::Row(_, row) if row == &vec![SqliteValue::from("one")]
+        ));
+
+        // nothing happens
+        assert!(sub.poll(Duration::from_millis(100)).unwrap().is_empty());
+
+        insert(2, "two");
+
+        let evt = sub
+            .find(|evt| !matches!(evt, Ok(TypedQueryEvent::Meta(..))))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            evt,
+            TypedQueryEvent::Change(ChangeType::Insert, _, row, _, _) if row == vec![SqliteValue::from("two")]
+        ));
+        assert!(!sub.is_closed());
+    }
+}
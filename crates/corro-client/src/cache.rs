@@ -0,0 +1,299 @@
+//! Local, in-memory materialization of a subscription
+//!
+//! [`MaterializedCache`] consumes a subscription stream and maintains
+//! the current result set of its query, indexed by `RowId` and,
+//! optionally, by an application-defined key (usually the primary key).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::{Arc, RwLock},
+};
+
+use corro_api_types::{sqlite::ChangeType, ChangeId, ColumnName, RowId, TypedQueryEvent};
+use futures::{Stream, StreamExt};
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, warn};
+
+const NOTIFICATIONS_CAPACITY: usize = 1024;
+
+/// Notification of a change applied to a [`MaterializedCache`]
+#[derive(Debug, Clone)]
+pub enum CacheEvent<T> {
+    Inserted(RowId),
+    Updated(RowId),
+    /// The row as it was last known to the cache before its deletion
+    Deleted(RowId, T),
+    /// The subscription was (re)started from scratch, every row was cleared
+    Reset,
+    /// The underlying stream ended, no more updates will be applied
+    Closed,
+}
+
+type KeyFn<T, K> = Box<dyn Fn(&T) -> K + Send + Sync>;
+
+struct CacheState<T, K> {
+    columns: Vec<ColumnName>,
+    rows: BTreeMap<RowId, T>,
+    keys: HashMap<K, RowId>,
+    last_change_id: Option<ChangeId>,
+}
+
+impl<T, K> Default for CacheState<T, K> {
+    fn default() -> Self {
+        Self {
+            columns: vec![],
+            rows: BTreeMap::new(),
+            keys: HashMap::new(),
+            last_change_id: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MaterializedCache<T, K = ()> {
+    state: Arc<RwLock<CacheState<T, K>>>,
+    notifications: broadcast::Sender<CacheEvent<T>>,
+    ready_rx: watch::Receiver<bool>,
+}
+
+impl<T> MaterializedCache<T, ()>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    /// Materialize a subscription, only indexing rows by `RowId`
+    pub fn new<S, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<TypedQueryEvent<T>, E>> + Send + Unpin + 'static,
+        E: std::fmt::Display + Send,
+    {
+        Self::spawn(stream, None)
+    }
+}
+
+impl<T, K> MaterializedCache<T, K>
+where
+    T: Clone + Send + Sync + 'static,
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// Materialize a subscription, also indexing rows by the key
+    /// returned from `key_fn`
+    pub fn with_key<S, E, F>(stream: S, key_fn: F) -> Self
+    where
+        S: Stream<Item = Result<TypedQueryEvent<T>, E>> + Send + Unpin + 'static,
+        E: std::fmt::Display + Send,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        Self::spawn(stream, Some(Box::new(key_fn)))
+    }
+
+    fn spawn<S, E>(mut stream: S, key_fn: Option<KeyFn<T, K>>) -> Self
+    where
+        S: Stream<Item = Result<TypedQueryEvent<T>, E>> + Send + Unpin + 'static,
+        E: std::fmt::Display + Send,
+    {
+        let state: Arc<RwLock<CacheState<T, K>>> = Default::default();
+        let (notifications, _) = broadcast::channel(NOTIFICATIONS_CAPACITY);
+        let (ready_tx, ready_rx) = watch::channel(false);
+
+        tokio::spawn({
+            let state = Arc::downgrade(&state);
+            let notifications = notifications.clone();
+            async move {
+                while let Some(res) = stream.next().await {
+                    let evt = match res {
+                        Ok(evt) => evt,
+                        Err(e) => {
+                            warn!("materialized cache stream errored: {e}");
+                            break;
+                        }
+                    };
+
+                    // every handle on the cache is gone
+                    let Some(state) = state.upgrade() else {
+                        return;
+                    };
+
+                    let notification = {
+                        let mut state = state.write().unwrap();
+                        apply_event(&mut state, key_fn.as_deref(), &ready_tx, evt)
+                    };
+
+                    if let Some(notification) = notification {
+                        _ = notifications.send(notification);
+                    }
+                }
+                debug!("materialized cache stream is done");
+                _ = notifications.send(CacheEvent::Closed);
+            }
+        });
+
+        Self {
+            state,
+            notifications,
+            ready_rx,
+        }
+    }
+
+    /// Wait until the initial query results have all been received
+    pub async fn ready(&self) {
+        let mut ready_rx = self.ready_rx.clone();
+        // an error means the stream is done, ready or not
+        _ = ready_rx.wait_for(|ready| *ready).await;
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.ready_rx.borrow()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CacheEvent<T>> {
+        self.notifications.subscribe()
+    }
+
+    pub fn columns(&self) -> Vec<ColumnName> {
+        self.state.read().unwrap().columns.clone()
+    }
+
+    pub fn get(&self, rowid: RowId) -> Option<T> {
+        self.state.read().unwrap().rows.get(&rowid).cloned()
+    }
+
+    pub fn get_by_key(&self, key: &K) -> Option<T> {
+        let state = self.state.read().unwrap();
+        state
+            .keys
+            .get(key)
+            .and_then(|rowid| state.rows.get(rowid))
+            .cloned()
+    }
+
+    /// Snapshot of every row, ordered by `RowId`
+    pub fn rows(&self) -> Vec<(RowId, T)> {
+        self.state
+            .read()
+            .unwrap()
+            .rows
+            .iter()
+            .map(|(rowid, row)| (*rowid, row.clone()))
+            .collect()
+    }
+
+    pub fn last_change_id(&self) -> Option<ChangeId> {
+        self.state.read().unwrap().last_change_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn upsert_row<T, K>(
+    state: &mut CacheState<T, K>,
+    key_fn: Option<&(dyn Fn(&T) -> K + Send + Sync)>,
+    rowid: RowId,
+    row: T,
+) -> Option<T>
+where
+    K: Hash + Eq,
+{
+    if let Some(key_fn) = key_fn {
+        let key = key_fn(&row);
+        if let Some(prev) = state.rows.get(&rowid) {
+            // the key might have changed, don't leave a dangling entry behind
+            let prev_key = key_fn(prev);
+            if prev_key != key && state.keys.get(&prev_key) == Some(&rowid) {
+                state.keys.remove(&prev_key);
+            }
+        }
+        state.keys.insert(key, rowid);
+    }
+    state.rows.insert(rowid, row)
+}
+
+fn apply_event<T, K>(
+    state: &mut CacheState<T, K>,
+    key_fn: Option<&(dyn Fn(&T) -> K + Send + Sync)>,
+    ready_tx: &watch::Sender<bool>,
+    evt: TypedQueryEvent<T>,
+) -> Option<CacheEvent<T>>
+where
+    K: Hash + Eq,
+{
+    match evt {
+        TypedQueryEvent::Columns(columns) => {
+            let had_rows = !state.rows.is_empty() || *ready_tx.borrow();
+            state.columns = columns;
+            state.rows.clear();
+            state.keys.clear();
+            ready_tx.send_replace(false);
+            had_rows.then_some(CacheEvent::Reset)
+        }
+        TypedQueryEvent::Row(rowid, row) => Some(match upsert_row(state, key_fn, rowid, row) {
+            Some(_) => CacheEvent::Updated(rowid),
+            None => CacheEvent::Inserted(rowid),
+        }),
+        TypedQueryEvent::EndOfQuery { change_id, .. } => {
+            if change_id.is_some() {
+                state.last_change_id = change_id;
+            }
+            ready_tx.send_replace(true);
+            None
+        }
+        TypedQueryEvent::Change(change_type, rowid, row, change_id, _) => {
+            state.last_change_id = Some(change_id);
+            match change_type {
+                ChangeType::Delete => {
+                    // use the row we know about rather than the one sent
+                    // along the deletion to clean up the key index
+                    let prev = state.rows.remove(&rowid)?;
+                    if let Some(key_fn) = key_fn {
+                        let key = key_fn(&prev);
+                        if state.keys.get(&key) == Some(&rowid) {
+                            state.keys.remove(&key);
+                        }
+                    }
+                    Some(CacheEvent::Deleted(rowid, prev))
+                }
+                ChangeType::Insert | ChangeType::Update => {
+                    Some(match upsert_row(state, key_fn, rowid, row) {
+                        Some(_) => CacheEvent::Updated(rowid),
+                        None => CacheEvent::Inserted(rowid),
+                    })
+                }
+            }
+        }
+        TypedQueryEvent::Estimate { .. }
+        | TypedQueryEvent::Progress { .. }
+        | TypedQueryEvent::Meta(_, _)
+        | TypedQueryEvent::Previous(_, _)
+        | TypedQueryEvent::Skipped { .. } => None,
+        TypedQueryEvent::Resync { reason } => {
+            // the changes that follow reconcile the cached rows
+            warn!("materialized cache subscription is being resynced: {reason}");
+            None
+        }
+        TypedQueryEvent::Dropped { count } => {
+            // the stream resumes from the last change received, the
+            // changes that follow bring the rows back in line
+            warn!("materialized cache missed {count} events dropped by the server, resuming");
+            None
+        }
+        TypedQueryEvent::Closed { reason } => {
+            warn!("materialized cache subscription was closed by the server: {reason:?}");
+            None
+        }
+        TypedQueryEvent::Moved { addr, .. } => {
+            // rows are sent again by the new node, starting with its columns
+            debug!("materialized cache subscription moved to {addr}");
+            None
+        }
+        TypedQueryEvent::Error(e) => {
+            warn!("materialized cache received an error event: {e}");
+            None
+        }
+    }
+}
@@ -1,8 +1,18 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cache;
+pub mod manager;
 pub mod sub;
+pub mod supervise;
 
 use std::{net::SocketAddr, ops::Deref, path::Path};
 
-use corro_api_types::{ChangeId, ExecResponse, ExecResult, SqliteValue, Statement};
+use bytes::Bytes;
+use corro_api_types::{
+    BackfillRequest, BackfillStatus, ChangeId, ChangesActor, DigestRequest, ErrorCode,
+    ExecResponse, ExecResult, Precondition, RowDigest, RowDigestRequest, SchemaResponse,
+    SqliteValue, Statement, TableDigest, TransactionRequest,
+};
 use http::uri::PathAndQuery;
 use hyper::{client::HttpConnector, http::HeaderName, Body, StatusCode};
 use serde::de::DeserializeOwned;
@@ -28,9 +38,47 @@ impl CorrosionApiClient {
         &self,
         statement: &Statement,
     ) -> Result<QueryStream<T>, Error> {
+        self.query_at("/v1/queries".try_into()?, statement).await
+    }
+
+    /// Query a page of `limit` rows, ordered by primary key. The
+    /// `EndOfQuery` event holds the cursor of the next page, if any.
+    pub async fn query_page_typed<T: DeserializeOwned + Unpin>(
+        &self,
+        statement: &Statement,
+        limit: usize,
+        cursor: Option<&str>,
+    ) -> Result<QueryStream<T>, Error> {
+        let p_and_q: PathAndQuery = match cursor {
+            Some(cursor) => format!("/v1/queries?limit={limit}&cursor={cursor}").try_into()?,
+            None => format!("/v1/queries?limit={limit}").try_into()?,
+        };
+        self.query_at(p_and_q, statement).await
+    }
+
+    pub async fn query_page(
+        &self,
+        statement: &Statement,
+        limit: usize,
+        cursor: Option<&str>,
+    ) -> Result<QueryStream<Vec<SqliteValue>>, Error> {
+        self.query_page_typed(statement, limit, cursor).await
+    }
+
+    async fn query_at<T: DeserializeOwned + Unpin>(
+        &self,
+        p_and_q: PathAndQuery,
+        statement: &Statement,
+    ) -> Result<QueryStream<T>, Error> {
+        let url = hyper::Uri::builder()
+            .scheme("http")
+            .authority(self.api_addr.to_string())
+            .path_and_query(p_and_q)
+            .build()?;
+
         let req = hyper::Request::builder()
             .method(hyper::Method::POST)
-            .uri(format!("http://{}/v1/queries", self.api_addr))
+            .uri(url)
             .header(hyper::header::CONTENT_TYPE, "application/json")
             .header(hyper::header::ACCEPT, "application/json")
             .body(Body::from(serde_json::to_vec(statement)?))?;
@@ -42,7 +90,9 @@ impl CorrosionApiClient {
             match hyper::body::to_bytes(res.into_body()).await {
                 Ok(b) => match serde_json::from_slice(&b) {
                     Ok(res) => match res {
-                        ExecResult::Error { error } => return Err(Error::ResponseError(error)),
+                        ExecResult::Error { error, code } => {
+                            return Err(Error::from_response(error, code))
+                        }
                         res => return Err(Error::UnexpectedResult(res)),
                     },
                     Err(e) => {
@@ -131,6 +181,46 @@ impl CorrosionApiClient {
         self.subscribe_typed(statement, skip_rows, from).await
     }
 
+    /// Subscribes with an id derived from the statement's normalized SQL,
+    /// sharing the subscription with every client subscribing to it
+    pub async fn subscribe_by_hash(
+        &self,
+        statement: &Statement,
+        skip_rows: bool,
+    ) -> Result<SubscriptionStream<Vec<SqliteValue>>, Error> {
+        let url = hyper::Uri::builder()
+            .scheme("http")
+            .authority(self.api_addr.to_string())
+            .path_and_query(format!("/v1/watches/by-hash?skip_rows={}", skip_rows))
+            .build()?;
+
+        let req = hyper::Request::builder()
+            .method(hyper::Method::PUT)
+            .uri(url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(statement)?))?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            return Err(Error::UnexpectedStatusCode(res.status()));
+        }
+
+        let id = res
+            .headers()
+            .get(HeaderName::from_static("corro-query-id"))
+            .and_then(|v| v.to_str().ok().and_then(|v| v.parse().ok()))
+            .ok_or(Error::ExpectedQueryId)?;
+
+        Ok(SubscriptionStream::new(
+            id,
+            self.api_client.clone(),
+            self.api_addr,
+            res.into_body(),
+        ))
+    }
+
     pub async fn subscription_typed<T: DeserializeOwned + Unpin>(
         &self,
         id: Uuid,
@@ -192,7 +282,36 @@ impl CorrosionApiClient {
         let res = self.api_client.request(req).await?;
 
         if !res.status().is_success() {
-            return Err(Error::UnexpectedStatusCode(res.status()));
+            return Err(exec_response_error(res).await);
+        }
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Executes the statements only if every precondition holds, failing
+    /// with the `precondition_failed` code otherwise
+    pub async fn execute_if(
+        &self,
+        statements: &[Statement],
+        preconditions: &[Precondition],
+    ) -> Result<ExecResponse, Error> {
+        let body = TransactionRequest::Conditional {
+            statements: statements.to_vec(),
+            preconditions: preconditions.to_vec(),
+        };
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(format!("http://{}/v1/transactions", self.api_addr))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(&body)?))?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            return Err(exec_response_error(res).await);
         }
 
         let bytes = hyper::body::to_bytes(res.into_body()).await?;
@@ -210,6 +329,68 @@ impl CorrosionApiClient {
 
         let res = self.api_client.request(req).await?;
 
+        if !res.status().is_success() {
+            return Err(exec_response_error(res).await);
+        }
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// The schema applied to the agent, table by table
+    pub async fn schema_tables(&self) -> Result<SchemaResponse, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(format!("http://{}/v1/db/schema", self.api_addr))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            return Err(exec_response_error(res).await);
+        }
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Start a backfill, changes are applied in the background
+    pub async fn backfill(&self, req: &BackfillRequest) -> Result<BackfillStatus, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(format!("http://{}/v1/backfills", self.api_addr))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(req)?))?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let bytes = hyper::body::to_bytes(res.into_body()).await?;
+            return match serde_json::from_slice(&bytes) {
+                Ok(ExecResult::Error { error, code }) => Err(Error::from_response(error, code)),
+                _ => Err(Error::UnexpectedStatusCode(status)),
+            };
+        }
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    pub async fn backfill_status(&self, id: u64) -> Result<BackfillStatus, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(format!("http://{}/v1/backfills/{id}", self.api_addr))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = self.api_client.request(req).await?;
+
         if !res.status().is_success() {
             return Err(Error::UnexpectedStatusCode(res.status()));
         }
@@ -219,6 +400,116 @@ impl CorrosionApiClient {
         Ok(serde_json::from_slice(&bytes)?)
     }
 
+    /// Digest the contents of tables, every table when `req.tables` is empty
+    pub async fn digests(&self, req: &DigestRequest) -> Result<Vec<TableDigest>, Error> {
+        self.post_json("/v1/digests", req).await
+    }
+
+    /// Digest the rows of some buckets of a table
+    pub async fn row_digests(&self, req: &RowDigestRequest) -> Result<Vec<RowDigest>, Error> {
+        self.post_json("/v1/digests/rows", req).await
+    }
+
+    /// Actors the agent knows versions of, with the last of them
+    pub async fn changes_actors(&self) -> Result<Vec<ChangesActor>, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(format!("http://{}/v1/changes/actors", self.api_addr))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            return Err(exec_response_error(res).await);
+        }
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Newline-delimited changesets of an actor after `since_version`, at
+    /// most `limit` versions, in the shape they're broadcast
+    pub async fn changes(
+        &self,
+        actor_id: Uuid,
+        since_version: u64,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(format!(
+                "http://{}/v1/changes?actor={actor_id}&since_version={since_version}&limit={limit}",
+                self.api_addr
+            ))
+            .body(Body::empty())?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let bytes = hyper::body::to_bytes(res.into_body()).await?;
+            return match serde_json::from_slice(&bytes) {
+                Ok(ExecResult::Error { error, code }) => Err(Error::from_response(error, code)),
+                _ => Err(Error::UnexpectedStatusCode(status)),
+            };
+        }
+
+        Ok(hyper::body::to_bytes(res.into_body()).await?)
+    }
+
+    /// Applies newline-delimited changesets, as returned by [`Self::changes`],
+    /// on behalf of `producer`. They're queued, not applied yet, once this
+    /// returns.
+    pub async fn ingest_changes(&self, producer: &str, changesets: Bytes) -> Result<(), Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(format!(
+                "http://{}/v1/changes?producer={producer}",
+                self.api_addr
+            ))
+            .header(hyper::header::CONTENT_TYPE, "application/x-ndjson")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(changesets))?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            return Err(exec_response_error(res).await);
+        }
+
+        Ok(())
+    }
+
+    async fn post_json<B: serde::Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<T, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(format!("http://{}{path}", self.api_addr))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(body)?))?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let bytes = hyper::body::to_bytes(res.into_body()).await?;
+            return match serde_json::from_slice(&bytes) {
+                Ok(ExecResult::Error { error, code }) => Err(Error::from_response(error, code)),
+                _ => Err(Error::UnexpectedStatusCode(status)),
+            };
+        }
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
     pub async fn schema_from_paths<P: AsRef<Path>>(
         &self,
         schema_paths: &[P],
@@ -346,6 +637,8 @@ pub enum Error {
     Http(#[from] hyper::http::Error),
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    Query(#[from] sub::QueryError),
 
     #[error("received unexpected response code: {0}")]
     UnexpectedStatusCode(StatusCode),
@@ -353,9 +646,44 @@ pub enum Error {
     #[error("{0}")]
     ResponseError(String),
 
+    #[error("{error}")]
+    Api { code: ErrorCode, error: String },
+
     #[error("unexpected result: {0:?}")]
     UnexpectedResult(ExecResult),
 
     #[error("could not retrieve subscription id from headers")]
     ExpectedQueryId,
 }
+
+impl Error {
+    fn from_response(error: String, code: Option<ErrorCode>) -> Self {
+        match code {
+            Some(code) => Error::Api { code, error },
+            None => Error::ResponseError(error),
+        }
+    }
+
+    /// Stable code of the error returned by the agent, older agents don't
+    /// send one
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Api { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+/// Error held by the [`ExecResponse`] of an unsuccessful response
+async fn exec_response_error(res: hyper::Response<Body>) -> Error {
+    let status = res.status();
+    let error = hyper::body::to_bytes(res.into_body())
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<ExecResponse>(&bytes).ok())
+        .and_then(|res| res.results.into_iter().next());
+    match error {
+        Some(ExecResult::Error { error, code }) => Error::from_response(error, code),
+        _ => Error::UnexpectedStatusCode(status),
+    }
+}
@@ -0,0 +1,353 @@
+//! In-process de-duplication of subscriptions
+//!
+//! Many components of an application tend to subscribe to the exact
+//! same queries. The [`SubscriptionManager`] makes sure only a single
+//! server-side subscription exists per statement and fans its events
+//! out to every local subscriber. When the underlying stream breaks,
+//! the manager resumes (or re-creates) the server-side subscription
+//! on its own.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Weak},
+};
+
+use corro_api_types::{
+    sqlite::ChangeType, ChangeId, ColumnName, QueryEvent, RowId, SqliteValue, Statement,
+    TypedQueryEvent,
+};
+use futures::StreamExt;
+use tokio::sync::{broadcast, oneshot, Mutex as TokioMutex};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{sub::SubscriptionStream, supervise::supervise, CorrosionApiClient, Error};
+
+const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Clone)]
+pub struct SubscriptionManager {
+    client: CorrosionApiClient,
+    subs: Arc<TokioMutex<HashMap<String, Weak<SharedHandle>>>>,
+    capacity: usize,
+}
+
+impl SubscriptionManager {
+    pub fn new(client: CorrosionApiClient) -> Self {
+        Self::with_capacity(client, DEFAULT_CHANNEL_CAPACITY)
+    }
+
+    /// `capacity` is the number of events buffered per shared
+    /// subscription before slow local subscribers start lagging.
+    pub fn with_capacity(client: CorrosionApiClient, capacity: usize) -> Self {
+        Self {
+            client,
+            subs: Default::default(),
+            capacity,
+        }
+    }
+
+    /// Subscribe to a statement, re-using an existing server-side
+    /// subscription if an identical statement is already subscribed
+    /// to from this process.
+    ///
+    /// Late subscribers receive a snapshot of the current rows (as
+    /// `Columns`, `Row` and `EndOfQuery` events) before live changes.
+    pub async fn subscribe(&self, statement: &Statement) -> Result<ManagedSubscription, Error> {
+        let key = serde_json::to_string(statement)?;
+
+        let mut subs = self.subs.lock().await;
+
+        if let Some(handle) = subs.get(&key).and_then(Weak::upgrade) {
+            debug!(sub_id = %handle.inner.id(), "re-using existing subscription");
+            return Ok(ManagedSubscription::new(handle));
+        }
+
+        let stream = self.client.subscribe(statement, false, None).await?;
+
+        let (tx, _) = broadcast::channel(self.capacity);
+        let inner = Arc::new(SharedInner {
+            statement: statement.clone(),
+            state: std::sync::Mutex::new(SharedState::new(stream.id())),
+            tx,
+        });
+
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        tokio::spawn(drive_subscription(
+            self.client.clone(),
+            inner.clone(),
+            stream,
+            cancel_rx,
+        ));
+
+        let handle = Arc::new(SharedHandle {
+            inner,
+            _cancel: cancel_tx,
+        });
+
+        // clean up entries for which every subscriber is gone
+        subs.retain(|_, weak| weak.strong_count() > 0);
+        subs.insert(key, Arc::downgrade(&handle));
+
+        Ok(ManagedSubscription::new(handle))
+    }
+
+    /// Number of distinct server-side subscriptions currently held.
+    pub async fn len(&self) -> usize {
+        self.subs
+            .lock()
+            .await
+            .values()
+            .filter(|weak| weak.strong_count() > 0)
+            .count()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+}
+
+struct SharedHandle {
+    inner: Arc<SharedInner>,
+    // dropped when the last local subscriber goes away, stopping the
+    // task driving the server-side subscription
+    _cancel: oneshot::Sender<()>,
+}
+
+struct SharedInner {
+    statement: Statement,
+    state: std::sync::Mutex<SharedState>,
+    tx: broadcast::Sender<QueryEvent>,
+}
+
+impl SharedInner {
+    fn id(&self) -> Uuid {
+        self.state.lock().unwrap().id
+    }
+
+    fn apply(&self, evt: QueryEvent) {
+        // state is updated and the event is sent while holding the lock
+        // so new subscribers can't miss (or double-receive) an event
+        let mut state = self.state.lock().unwrap();
+        match &evt {
+            TypedQueryEvent::Columns(cols) => {
+                state.columns = Some(cols.clone());
+                state.rows.clear();
+                state.observed_eoq = false;
+            }
+            TypedQueryEvent::Row(rowid, row) => {
+                state.rows.insert(*rowid, row.clone());
+            }
+            TypedQueryEvent::EndOfQuery { change_id, .. } => {
+                state.observed_eoq = true;
+                if change_id.is_some() {
+                    state.last_change_id = *change_id;
+                }
+            }
+            TypedQueryEvent::Change(change_type, rowid, row, change_id, _) => {
+                match change_type {
+                    ChangeType::Delete => {
+                        state.rows.remove(rowid);
+                    }
+                    ChangeType::Insert | ChangeType::Update => {
+                        state.rows.insert(*rowid, row.clone());
+                    }
+                }
+                state.last_change_id = Some(*change_id);
+            }
+            TypedQueryEvent::Moved { id, .. } => {
+                // the stream follows the subscription, its rows come again
+                state.id = *id;
+                state.observed_eoq = false;
+                state.last_change_id = None;
+            }
+            TypedQueryEvent::Skipped { change_id } => {
+                state.last_change_id = Some(*change_id);
+            }
+            TypedQueryEvent::Estimate { .. }
+            | TypedQueryEvent::Progress { .. }
+            | TypedQueryEvent::Meta(_, _)
+            | TypedQueryEvent::Previous(_, _)
+            | TypedQueryEvent::Resync { .. }
+            | TypedQueryEvent::Dropped { .. }
+            | TypedQueryEvent::Closed { .. }
+            | TypedQueryEvent::Error(_) => {}
+        }
+        // no receivers is fine, the handle might be going away
+        _ = self.tx.send(evt);
+    }
+}
+
+struct SharedState {
+    id: Uuid,
+    columns: Option<Vec<ColumnName>>,
+    rows: BTreeMap<RowId, Vec<SqliteValue>>,
+    observed_eoq: bool,
+    last_change_id: Option<ChangeId>,
+}
+
+impl SharedState {
+    fn new(id: Uuid) -> Self {
+        Self {
+            id,
+            columns: None,
+            rows: BTreeMap::new(),
+            observed_eoq: false,
+            last_change_id: None,
+        }
+    }
+
+    fn snapshot(&self) -> VecDeque<QueryEvent> {
+        let mut events = VecDeque::new();
+        let Some(columns) = self.columns.as_ref() else {
+            return events;
+        };
+        events.push_back(TypedQueryEvent::Columns(columns.clone()));
+        events.extend(
+            self.rows
+                .iter()
+                .map(|(rowid, row)| TypedQueryEvent::Row(*rowid, row.clone())),
+        );
+        if self.observed_eoq {
+            events.push_back(TypedQueryEvent::EndOfQuery {
+                time: 0.0,
+                change_id: self.last_change_id,
+                cursor: None,
+            });
+        }
+        events
+    }
+}
+
+async fn drive_subscription(
+    client: CorrosionApiClient,
+    inner: Arc<SharedInner>,
+    stream: SubscriptionStream<Vec<SqliteValue>>,
+    mut cancel: oneshot::Receiver<()>,
+) {
+    let mut stream = Some(stream);
+
+    loop {
+        let mut current = match stream.take() {
+            Some(stream) => stream,
+            None => match supervise("resubscribing", &mut cancel, || {
+                resubscribe(&client, &inner)
+            })
+            .await
+            {
+                Some(stream) => stream,
+                None => return,
+            },
+        };
+
+        loop {
+            tokio::select! {
+                _ = &mut cancel => {
+                    debug!(sub_id = %current.id(), "all local subscribers are gone, stopping");
+                    return;
+                },
+                evt = current.next() => match evt {
+                    Some(Ok(evt @ TypedQueryEvent::Dropped { .. })) => {
+                        // the stream resumes from the last change received
+                        // and gets the dropped ones back
+                        warn!(sub_id = %current.id(), "server dropped subscription events, resuming: {evt:?}");
+                        inner.apply(evt);
+                    }
+                    Some(Ok(evt)) => inner.apply(evt),
+                    Some(Err(e)) => {
+                        warn!(sub_id = %current.id(), "subscription stream errored: {e}");
+                        break;
+                    }
+                    None => {
+                        debug!(sub_id = %current.id(), "subscription stream ended");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Resume the existing server-side subscription from the last observed
+/// change if possible, otherwise create it anew (e.g. the server was
+/// restarted and forgot about it).
+async fn resubscribe(
+    client: &CorrosionApiClient,
+    inner: &SharedInner,
+) -> Result<SubscriptionStream<Vec<SqliteValue>>, Error> {
+    let (id, from) = {
+        let state = inner.state.lock().unwrap();
+        (
+            state.id,
+            state.observed_eoq.then_some(state.last_change_id).flatten(),
+        )
+    };
+
+    if let Some(from) = from {
+        match client.subscription(id, true, Some(from)).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!(sub_id = %id, "could not resume subscription, re-creating it: {e}");
+            }
+        }
+    }
+
+    let stream = client.subscribe(&inner.statement, false, None).await?;
+    inner.state.lock().unwrap().id = stream.id();
+    Ok(stream)
+}
+
+/// A local handle on a (possibly shared) server-side subscription.
+///
+/// A `Columns` event received after the initial one means the
+/// subscription had to be re-created from scratch: consumers should
+/// discard the rows they know about.
+pub struct ManagedSubscription {
+    handle: Arc<SharedHandle>,
+    pending: VecDeque<QueryEvent>,
+    rx: broadcast::Receiver<QueryEvent>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManagedSubscriptionError {
+    #[error("subscriber lagged behind and missed {0} events")]
+    Lagged(u64),
+}
+
+impl ManagedSubscription {
+    fn new(handle: Arc<SharedHandle>) -> Self {
+        let (pending, rx) = {
+            let state = handle.inner.state.lock().unwrap();
+            (state.snapshot(), handle.inner.tx.subscribe())
+        };
+        Self {
+            handle,
+            pending,
+            rx,
+        }
+    }
+
+    /// Id of the underlying server-side subscription, it may change
+    /// if the subscription had to be re-created.
+    pub fn id(&self) -> Uuid {
+        self.handle.inner.id()
+    }
+
+    pub fn statement(&self) -> &Statement {
+        &self.handle.inner.statement
+    }
+
+    /// Receive the next event, `None` means the subscription is done.
+    pub async fn recv(&mut self) -> Option<Result<QueryEvent, ManagedSubscriptionError>> {
+        if let Some(evt) = self.pending.pop_front() {
+            return Some(Ok(evt));
+        }
+        match self.rx.recv().await {
+            Ok(evt) => Some(Ok(evt)),
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                Some(Err(ManagedSubscriptionError::Lagged(n)))
+            }
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    }
+}
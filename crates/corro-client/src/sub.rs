@@ -60,6 +60,9 @@ pub struct SubscriptionStream<T> {
     api_addr: SocketAddr,
     observed_eoq: bool,
     last_change_id: Option<ChangeId>,
+    /// Handed off to another node, or events were dropped before the end
+    /// of the initial query: its rows are requested from the start
+    restart: bool,
     stream: Option<FramedBody>,
     backoff: Option<Pin<Box<Sleep>>>,
     backoff_count: u32,
@@ -101,6 +104,7 @@ where
             api_addr,
             observed_eoq: false,
             last_change_id: None,
+            restart: false,
             stream: Some(FramedRead::new(
                 StreamReader::new(IoBodyStream { body }),
                 LinesBytesCodec::default(),
@@ -140,14 +144,36 @@ where
                 Ok(evt) => {
                     if let TypedQueryEvent::EndOfQuery { change_id, .. } = &evt {
                         self.observed_eoq = true;
+                        self.restart = false;
                         self.last_change_id = *change_id;
                     }
-                    if let TypedQueryEvent::Change(_, _, _, change_id) = &evt {
+                    if let TypedQueryEvent::Change(_, _, _, change_id, _) = &evt {
                         if matches!(self.last_change_id, Some(id) if id.0 + 1 != change_id.0) {
                             return Poll::Ready(Some(Err(SubscriptionError::MissedChange)));
                         }
                         self.last_change_id = Some(*change_id);
                     }
+                    if let TypedQueryEvent::Skipped { change_id } = &evt {
+                        // left out by the subscription's filter
+                        self.last_change_id = Some(*change_id);
+                    }
+                    if let TypedQueryEvent::Moved { addr, id } = &evt {
+                        // change ids of the new subscription have nothing to
+                        // do with ours, follow it from its initial rows
+                        self.api_addr = *addr;
+                        self.id = *id;
+                        self.observed_eoq = false;
+                        self.last_change_id = None;
+                        self.restart = true;
+                        self.stream = None;
+                    }
+                    if let TypedQueryEvent::Dropped { .. } = &evt {
+                        // the server keeps sending changes after the ones it
+                        // dropped, resume from the last one received to get
+                        // them back, or from the start if rows were missed
+                        self.restart = !self.observed_eoq;
+                        self.stream = None;
+                    }
                     Poll::Ready(Some(Ok(evt)))
                 }
                 Err(e) => Poll::Ready(Some(Err(e.into()))),
@@ -192,15 +218,20 @@ where
                         Poll::Ready(Err(io_err.into()))
                     }
                 };
-            } else if self.observed_eoq {
-                let req = hyper::Request::builder()
-                    .method(hyper::Method::GET)
-                    .uri(format!(
+            } else if self.observed_eoq || self.restart {
+                let uri = if self.restart {
+                    format!("http://{}/v1/subscriptions/{}", self.api_addr, self.id)
+                } else {
+                    format!(
                         "http://{}/v1/subscriptions/{}?from={}",
                         self.api_addr,
                         self.id,
                         self.last_change_id.unwrap_or_default()
-                    ))
+                    )
+                };
+                let req = hyper::Request::builder()
+                    .method(hyper::Method::GET)
+                    .uri(uri)
                     .header(hyper::header::ACCEPT, "application/json")
                     .body(hyper::Body::empty())?;
 
@@ -429,3 +460,86 @@ fn without_carriage_return(s: &mut BytesMut) {
         s.truncate(s.len() - 1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Arc};
+
+    use corro_api_types::{sqlite::ChangeType, ColumnName, RowId, SqliteValue};
+    use futures::StreamExt;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Request, Response, Server,
+    };
+
+    use super::*;
+
+    fn lines(events: &[TypedQueryEvent<Vec<SqliteValue>>]) -> String {
+        events
+            .iter()
+            .map(|evt| serde_json::to_string(evt).unwrap() + "\n")
+            .collect()
+    }
+
+    fn change(id: u64) -> TypedQueryEvent<Vec<SqliteValue>> {
+        TypedQueryEvent::Change(
+            ChangeType::Insert,
+            RowId(id),
+            vec![SqliteValue::Integer(id as i64)],
+            ChangeId(id),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_dropped() {
+        let uris = Arc::new(std::sync::Mutex::new(vec![]));
+        let make_svc = make_service_fn({
+            let uris = uris.clone();
+            move |_| {
+                let uris = uris.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        uris.lock().unwrap().push(req.uri().to_string());
+                        // changes 3 and 4 were dropped from the first response
+                        let body = lines(&[change(3), change(4), change(5)]);
+                        async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                    }))
+                }
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let api_addr = server.local_addr();
+        tokio::spawn(server);
+
+        let id = Uuid::new_v4();
+        let body = lines(&[
+            TypedQueryEvent::Columns(vec![ColumnName("id".into())]),
+            TypedQueryEvent::EndOfQuery {
+                time: 0.0,
+                change_id: Some(ChangeId(1)),
+                cursor: None,
+            },
+            change(2),
+            TypedQueryEvent::Dropped { count: 2 },
+            change(5),
+        ]);
+        let mut stream = SubscriptionStream::<Vec<SqliteValue>>::new(
+            id,
+            hyper::Client::new(),
+            api_addr,
+            Body::from(body),
+        );
+
+        let mut events = vec![];
+        for _ in 0..7 {
+            events.push(stream.next().await.unwrap().unwrap());
+        }
+        assert_eq!(events[3], TypedQueryEvent::Dropped { count: 2 });
+        assert_eq!(&events[4..], &[change(3), change(4), change(5)]);
+        assert_eq!(
+            *uris.lock().unwrap(),
+            vec![format!("/v1/subscriptions/{id}?from=2")]
+        );
+    }
+}
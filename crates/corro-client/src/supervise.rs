@@ -0,0 +1,41 @@
+//! Restarting the tasks consumers of subscriptions depend on
+//!
+//! Re-creating or resuming a subscription fails for as long as the agent is
+//! unreachable. [`supervise`] retries such a task with an exponential
+//! backoff until it succeeds, or until its consumer goes away.
+
+use std::{fmt::Display, future::Future, time::Duration};
+
+use tracing::warn;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Runs `task` until it succeeds, waiting a little longer after every
+/// failure, up to 30 seconds. Returns `None` if `cancel` resolves first.
+pub async fn supervise<T, E, F, Fut, C>(name: &str, cancel: &mut C, mut task: F) -> Option<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display,
+    C: Future + Unpin,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let res = tokio::select! {
+            _ = &mut *cancel => return None,
+            res = task() => res,
+        };
+        match res {
+            Ok(value) => return Some(value),
+            Err(e) => {
+                warn!("{name} failed, retrying in {backoff:?}: {e}");
+                tokio::select! {
+                    _ = &mut *cancel => return None,
+                    _ = tokio::time::sleep(backoff) => {},
+                }
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+    }
+}
@@ -15,11 +15,13 @@ use chrono::NaiveDateTime;
 use compact_str::CompactString;
 use corro_types::{
     agent::{Agent, CurrentVersion, KnownDbVersion},
+    api::Statement as ApiStatement,
     base::{CrsqlDbVersion, CrsqlSeq},
     broadcast::{BroadcastInput, BroadcastV1, ChangeV1, Changeset, Timestamp},
     change::{row_to_change, ChunkedChanges, MAX_CHANGES_BYTE_SIZE},
     config::PgConfig,
     schema::{parse_sql, Column, Schema, SchemaError, SqliteType, Table},
+    validation::{ChangeSummary, PendingTransaction, ValidationError},
 };
 use fallible_iterator::FallibleIterator;
 use futures::{SinkExt, StreamExt};
@@ -46,8 +48,7 @@ use pgwire::{
 };
 use postgres_types::{FromSql, Type};
 use rusqlite::{
-    functions::FunctionFlags, named_params, types::ValueRef, vtab::eponymous_only_module,
-    Connection, Statement,
+    functions::FunctionFlags, types::ValueRef, vtab::eponymous_only_module, Connection, Statement,
 };
 use spawn::spawn_counted;
 use sqlite3_parser::ast::{
@@ -706,6 +707,7 @@ pub async fn start(
                     let mut session = Session {
                         agent,
                         tx_state: TxState::default(),
+                        statements: vec![],
                     };
 
                     'outer: while let Some(msg) = front_rx.blocking_recv() {
@@ -1741,6 +1743,8 @@ pub async fn start(
 struct Session {
     agent: Agent,
     tx_state: TxState,
+    /// Writing statements of the open transaction, for validators
+    statements: Vec<ApiStatement>,
 }
 
 impl Session {
@@ -1800,6 +1804,7 @@ impl Session {
             0
         } else if cmd.is_rollback() {
             let _permit = self.tx_state.end();
+            self.statements.clear();
             conn.execute_batch("ROLLBACK")?;
             0
         } else {
@@ -1842,6 +1847,9 @@ impl Session {
                 self.tx_state
                     .set_write_permit(self.agent.write_permit_blocking()?);
             }
+            if !prepped.readonly() {
+                self.statements.push(ApiStatement::Simple(cmd.to_string()));
+            }
 
             let mut rows = prepped.raw_query();
             let ncols = schema.len();
@@ -1955,6 +1963,10 @@ impl Session {
                 self.tx_state
                     .set_write_permit(self.agent.write_permit_blocking()?);
             }
+            if !prepped.readonly() {
+                let sql = prepped.expanded_sql().unwrap_or_else(|| cmd.to_string());
+                self.statements.push(ApiStatement::Simple(sql));
+            }
             let mut rows = prepped.raw_query();
             loop {
                 if count >= max_rows {
@@ -2111,9 +2123,10 @@ impl Session {
         Ok(())
     }
 
-    fn handle_commit(&self, conn: &Connection) -> rusqlite::Result<()> {
+    fn handle_commit(&mut self, conn: &Connection) -> Result<(), QueryError> {
         trace!("HANDLE COMMIT");
         let actor_id = self.agent.actor_id();
+        let statements = std::mem::take(&mut self.statements);
 
         let ts = Timestamp::from(self.agent.clock().new_timestamp());
 
@@ -2130,6 +2143,20 @@ impl Session {
             return Ok(());
         }
 
+        // the same validators as transactions sent over HTTP
+        let validators = self.agent.validators();
+        if !validators.is_empty() {
+            let changes = ChangeSummary::read(conn, db_version)?;
+            if let Err(e) = validators.validate(&PendingTransaction {
+                statements: &statements,
+                changes: &changes,
+                tx: conn,
+            }) {
+                conn.execute_batch("ROLLBACK")?;
+                return Err(e.into());
+            }
+        }
+
         let last_seq: CrsqlSeq = conn
             .prepare_cached("SELECT MAX(seq) FROM crsql_changes WHERE db_version = ?")?
             .query_row([db_version], |row| row.get(0))?;
@@ -2144,19 +2171,14 @@ impl Session {
         let version = last_version + 1;
         trace!("version: {version}");
 
-        conn.prepare_cached(
-            r#"
-                INSERT INTO __corro_bookkeeping (actor_id, start_version, db_version, last_seq, ts)
-                    VALUES (:actor_id, :start_version, :db_version, :last_seq, :ts);
-            "#,
-        )?
-        .execute(named_params! {
-            ":actor_id": actor_id,
-            ":start_version": version,
-            ":db_version": db_version,
-            ":last_seq": last_seq,
-            ":ts": ts
-        })?;
+        let current = CurrentVersion {
+            db_version,
+            last_seq,
+            ts,
+        };
+        self.agent
+            .bookkeeping()
+            .insert_current(conn, actor_id, version, &current)?;
 
         debug!(%actor_id, %version, %db_version, "inserted local bookkeeping row!");
 
@@ -2164,14 +2186,7 @@ impl Session {
 
         trace!("committed tx, db_version: {db_version}, last_seq: {last_seq:?}");
 
-        book_writer.insert(
-            version,
-            KnownDbVersion::Current(CurrentVersion {
-                db_version,
-                last_seq,
-                ts,
-            }),
-        );
+        book_writer.insert(version, KnownDbVersion::Current(current));
 
         drop(book_writer);
 
@@ -2206,6 +2221,7 @@ impl Session {
                                 trace!("broadcasting changes: {changes:?} for seq: {seqs:?}");
 
                                 agent.subs_manager().match_changes(&changes, db_version);
+                                agent.key_watches().match_changes(&changes);
 
                                 let tx_bcast = agent.tx_bcast().clone();
                                 tokio::spawn(async move {
@@ -2256,11 +2272,20 @@ fn send_ready(
         if discard_until_sync {
             // an error occured, rollback implicit tx!
             warn!("receive Sync message w/ an error to send, rolling back implicit tx");
+            session.statements.clear();
             conn.execute_batch("ROLLBACK")?;
         } else {
             // no error, commit implicit tx
             warn!("receive Sync message, committing implicit tx");
-            session.handle_commit(conn)?;
+            match session.handle_commit(conn) {
+                Err(e @ QueryError::Vetoed(_)) => {
+                    back_tx.blocking_send(BackendResponse::Message {
+                        message: e.try_into()?,
+                        flush: true,
+                    })?;
+                }
+                res => res?,
+            }
         }
 
         READY_STATUS_IDLE
@@ -2299,6 +2324,8 @@ enum QueryError {
     BackendResponseSendFailed,
     #[error("could not acquire write permit")]
     PermitAcquire(#[from] AcquireError),
+    #[error(transparent)]
+    Vetoed(#[from] ValidationError),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -2323,6 +2350,10 @@ impl TryFrom<QueryError> for PgWireBackendMessage {
             e @ QueryError::PermitAcquire(_) => {
                 ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), e.to_string()).into()
             }
+            // raised like an exception from a trigger would be
+            e @ QueryError::Vetoed(_) => {
+                ErrorInfo::new("ERROR".to_owned(), "P0001".to_owned(), e.to_string()).into()
+            }
             QueryError::BackendResponseSendFailed => return Err(ChannelClosed),
         }))
     }
@@ -3130,6 +3161,7 @@ mod tests {
 
     use chrono::{DateTime, Utc};
     use corro_tests::launch_test_agent;
+    use corro_types::validation::{TxValidator, Veto};
     use spawn::wait_for_all_pending_handles;
     use tokio_postgres::NoTls;
     use tripwire::Tripwire;
@@ -3335,4 +3367,76 @@ mod tests {
 
         Ok(())
     }
+
+    struct NoDeletes;
+
+    impl TxValidator for NoDeletes {
+        fn name(&self) -> &str {
+            "no_deletes"
+        }
+
+        fn validate(&self, pending: &PendingTransaction<'_>) -> Result<(), Veto> {
+            if pending.changes.tables.values().any(|t| t.deletes > 0) {
+                return Err(Veto::new("delete_forbidden", "rows can't be deleted"));
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pg_vetoed() -> Result<(), BoxError> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|builder| builder.build(), tripwire.clone()).await?;
+        ta.agent.validators().register(Arc::new(NoDeletes));
+
+        let server = start(
+            ta.agent.clone(),
+            PgConfig {
+                bind_addr: "127.0.0.1:0".parse()?,
+            },
+            tripwire,
+        )
+        .await?;
+
+        let conn_str = format!(
+            "host={} port={} user=testuser",
+            server.local_addr.ip(),
+            server.local_addr.port()
+        );
+
+        {
+            let (mut client, client_conn) = tokio_postgres::connect(&conn_str, NoTls).await?;
+            tokio::spawn(client_conn);
+
+            client
+                .execute("INSERT INTO tests VALUES (1, 'hello')", &[])
+                .await?;
+
+            // implicit transaction
+            let err = client
+                .execute("DELETE FROM tests WHERE id = 1", &[])
+                .await
+                .unwrap_err();
+            assert_eq!(err.code().map(|c| c.code()), Some("P0001"));
+            assert!(err.to_string().contains("delete_forbidden"), "{err}");
+
+            // explicit transaction, vetoed on commit
+            let tx = client.transaction().await?;
+            tx.execute("DELETE FROM tests WHERE id = $1", &[&1i64])
+                .await?;
+            let err = tx.commit().await.unwrap_err();
+            assert_eq!(err.code().map(|c| c.code()), Some("P0001"));
+
+            let row = client.query_one("SELECT count(*) FROM tests", &[]).await?;
+            assert_eq!(row.try_get::<_, i64>(0)?, 1);
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,307 @@
+//! Functions available to templates, on top of Rhai's own: sorting and
+//! grouping rows, JSON access and IP address math, the usual needs when
+//! rendering proxy or load balancer configs.
+
+use std::{cmp::Ordering, net::IpAddr};
+
+use ipnet::IpNet;
+use rhai::{Array, Dynamic, EvalAltResult, Map};
+
+fn error(msg: impl ToString) -> Box<EvalAltResult> {
+    Box::new(EvalAltResult::from(msg.to_string()))
+}
+
+pub(crate) fn register(engine: &mut rhai::Engine) {
+    engine.register_fn("sort_by", sort_by);
+    engine.register_fn("group_by", group_by);
+    engine.register_fn("join", join);
+
+    engine.register_fn("from_json", from_json);
+    engine.register_fn("to_json", |map: Map| to_json(&Dynamic::from_map(map)));
+    engine.register_fn("to_json", |array: Array| {
+        to_json(&Dynamic::from_array(array))
+    });
+    engine.register_fn("json_get", json_get);
+
+    engine.register_fn("ip_add", ip_add);
+    engine.register_fn("cidr_contains", cidr_contains);
+    engine.register_fn("cidr_host", cidr_host);
+    engine.register_fn("cidr_subnet", cidr_subnet);
+    engine.register_fn("cidr_network", |cidr: &str| {
+        Ok::<_, Box<EvalAltResult>>(parse_cidr(cidr)?.network().to_string())
+    });
+    engine.register_fn("cidr_broadcast", |cidr: &str| {
+        Ok::<_, Box<EvalAltResult>>(parse_cidr(cidr)?.broadcast().to_string())
+    });
+    engine.register_fn("cidr_netmask", |cidr: &str| {
+        Ok::<_, Box<EvalAltResult>>(parse_cidr(cidr)?.netmask().to_string())
+    });
+    engine.register_fn("cidr_prefix", |cidr: &str| {
+        Ok::<_, Box<EvalAltResult>>(parse_cidr(cidr)?.prefix_len() as i64)
+    });
+}
+
+/// Numbers compare by value, anything else by its string representation
+fn cmp_dynamic(a: &Dynamic, b: &Dynamic) -> Ordering {
+    if let (Ok(a), Ok(b)) = (a.as_int(), b.as_int()) {
+        return a.cmp(&b);
+    }
+    let as_number = |d: &Dynamic| {
+        d.as_float()
+            .ok()
+            .or_else(|| d.as_int().ok().map(|i| i as f64))
+    };
+    if let (Some(a), Some(b)) = (as_number(a), as_number(b)) {
+        return a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+    }
+    a.to_string().cmp(&b.to_string())
+}
+
+fn property(item: &Dynamic, prop: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    let map = item
+        .read_lock::<Map>()
+        .ok_or_else(|| error(format!("expected an object map, got {}", item.type_name())))?;
+    Ok(map.get(prop).cloned().unwrap_or(Dynamic::UNIT))
+}
+
+/// Sorts object maps, e.g. rows, by one of their properties
+fn sort_by(mut items: Array, prop: &str) -> Result<Array, Box<EvalAltResult>> {
+    let mut keyed = items
+        .drain(..)
+        .map(|item| Ok((property(&item, prop)?, item)))
+        .collect::<Result<Vec<_>, Box<EvalAltResult>>>()?;
+    keyed.sort_by(|(a, _), (b, _)| cmp_dynamic(a, b));
+    Ok(keyed.into_iter().map(|(_, item)| item).collect())
+}
+
+/// Groups object maps by the value of one of their properties, keeping
+/// their order within each group
+fn group_by(items: Array, prop: &str) -> Result<Map, Box<EvalAltResult>> {
+    let mut groups = Map::new();
+    for item in items {
+        let key = property(&item, prop)?.to_string();
+        let group = groups
+            .entry(key.into())
+            .or_insert_with(|| Dynamic::from_array(Array::new()));
+        if let Some(mut group) = group.write_lock::<Array>() {
+            group.push(item);
+        }
+    }
+    Ok(groups)
+}
+
+fn join(items: Array, sep: &str) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+fn json_to_dynamic(value: serde_json::Value) -> Dynamic {
+    match value {
+        serde_json::Value::Null => Dynamic::UNIT,
+        serde_json::Value::Bool(b) => b.into(),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+        },
+        serde_json::Value::String(s) => s.into(),
+        serde_json::Value::Array(values) => {
+            Dynamic::from_array(values.into_iter().map(json_to_dynamic).collect())
+        }
+        serde_json::Value::Object(values) => Dynamic::from_map(
+            values
+                .into_iter()
+                .map(|(k, v)| (k.into(), json_to_dynamic(v)))
+                .collect(),
+        ),
+    }
+}
+
+fn dynamic_to_json(value: &Dynamic) -> serde_json::Value {
+    if value.is_unit() {
+        serde_json::Value::Null
+    } else if let Ok(b) = value.as_bool() {
+        b.into()
+    } else if let Ok(i) = value.as_int() {
+        i.into()
+    } else if let Ok(f) = value.as_float() {
+        f.into()
+    } else if let Some(array) = value.read_lock::<Array>() {
+        array.iter().map(dynamic_to_json).collect()
+    } else if let Some(map) = value.read_lock::<Map>() {
+        serde_json::Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.to_string(), dynamic_to_json(v)))
+                .collect(),
+        )
+    } else {
+        value.to_string().into()
+    }
+}
+
+/// Parses JSON, e.g. stored in a TEXT column, into maps and arrays
+fn from_json(json: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    serde_json::from_str(json)
+        .map(json_to_dynamic)
+        .map_err(|e| error(format!("invalid JSON: {e}")))
+}
+
+fn to_json(value: &Dynamic) -> String {
+    dynamic_to_json(value).to_string()
+}
+
+/// Looks up a dot-separated path, e.g. `ports.0.number`, in maps and
+/// arrays. Returns `()` when any part of the path is missing.
+fn json_get(value: Dynamic, path: &str) -> Dynamic {
+    let mut current = value;
+    for part in path.split('.').filter(|part| !part.is_empty()) {
+        let next = if let Some(map) = current.read_lock::<Map>() {
+            map.get(part).cloned()
+        } else if let Some(array) = current.read_lock::<Array>() {
+            part.parse::<usize>()
+                .ok()
+                .and_then(|i| array.get(i).cloned())
+        } else {
+            None
+        };
+        match next {
+            Some(next) => current = next,
+            None => return Dynamic::UNIT,
+        }
+    }
+    current
+}
+
+fn parse_ip(ip: &str) -> Result<IpAddr, Box<EvalAltResult>> {
+    ip.parse()
+        .map_err(|_| error(format!("invalid IP address: '{ip}'")))
+}
+
+fn parse_cidr(cidr: &str) -> Result<IpNet, Box<EvalAltResult>> {
+    cidr.parse()
+        .map_err(|_| error(format!("invalid CIDR: '{cidr}'")))
+}
+
+fn offset_ip(ip: IpAddr, n: i64) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip) as i64)
+            .checked_add(n)
+            .and_then(|v| u32::try_from(v).ok())
+            .map(|v| IpAddr::V4(v.into())),
+        IpAddr::V6(ip) => u128::from(ip)
+            .checked_add_signed(n as i128)
+            .map(|v| IpAddr::V6(v.into())),
+    }
+}
+
+/// Adds `n`, which can be negative, to an IP address
+fn ip_add(ip: &str, n: i64) -> Result<String, Box<EvalAltResult>> {
+    offset_ip(parse_ip(ip)?, n)
+        .map(|ip| ip.to_string())
+        .ok_or_else(|| error(format!("{ip} + {n} is out of range")))
+}
+
+fn cidr_contains(cidr: &str, ip: &str) -> Result<bool, Box<EvalAltResult>> {
+    Ok(parse_cidr(cidr)?.contains(&parse_ip(ip)?))
+}
+
+/// The `n`th address of a network, e.g. `cidr_host("10.0.0.0/24", 1)` is
+/// `10.0.0.1`
+fn cidr_host(cidr: &str, n: i64) -> Result<String, Box<EvalAltResult>> {
+    let net = parse_cidr(cidr)?;
+    offset_ip(net.network(), n)
+        .filter(|ip| net.contains(ip))
+        .map(|ip| ip.to_string())
+        .ok_or_else(|| error(format!("host {n} is out of range for {cidr}")))
+}
+
+/// The `n`th subnet of a network, `new_bits` longer than its prefix, e.g.
+/// `cidr_subnet("10.0.0.0/16", 8, 2)` is `10.0.2.0/24`
+fn cidr_subnet(cidr: &str, new_bits: i64, n: i64) -> Result<String, Box<EvalAltResult>> {
+    let net = parse_cidr(cidr)?;
+    let out_of_range = || {
+        error(format!(
+            "subnet {n} (+{new_bits} bits) is out of range for {cidr}"
+        ))
+    };
+
+    let prefix = u8::try_from(new_bits)
+        .ok()
+        .and_then(|bits| net.prefix_len().checked_add(bits))
+        .ok_or_else(out_of_range)?;
+    let n = usize::try_from(n).map_err(|_| out_of_range())?;
+
+    net.subnets(prefix)
+        .map_err(|_| out_of_range())?
+        .nth(n)
+        .map(|subnet| subnet.to_string())
+        .ok_or_else(out_of_range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine() -> rhai::Engine {
+        let mut engine = rhai::Engine::new();
+        register(&mut engine);
+        engine
+    }
+
+    #[test]
+    fn test_collections() {
+        let engine = engine();
+        let res = engine
+            .eval::<String>(
+                r#"
+                let rows = [#{name: "b", port: 10}, #{name: "a", port: 9}, #{name: "c", port: 10}];
+                let sorted = rows.sort_by("port");
+                let groups = rows.group_by("port");
+                join(sorted.map(|r| r.name), ",") + "|" + join(groups["10"].map(|r| r.name), ",")
+                "#,
+            )
+            .unwrap();
+        assert_eq!(res, "a,b,c|b,c");
+    }
+
+    #[test]
+    fn test_json() {
+        let engine = engine();
+        let res = engine
+            .eval::<String>(
+                r#"
+                let meta = from_json(`{"ports": [{"number": 8080}], "tags": ["web"]}`);
+                `${meta.ports[0].number}|${json_get(meta, "ports.0.number")}|${json_get(meta, "nope.0")}|${to_json(meta.tags)}`
+                "#,
+            )
+            .unwrap();
+        assert_eq!(res, r#"8080|8080||["web"]"#);
+        assert!(engine.eval::<Dynamic>(r#"from_json("{")"#).is_err());
+    }
+
+    #[test]
+    fn test_network() {
+        assert_eq!(ip_add("10.0.0.255", 1).unwrap(), "10.0.1.0");
+        assert_eq!(ip_add("fd00::1", -1).unwrap(), "fd00::");
+        assert!(ip_add("255.255.255.255", 1).is_err());
+
+        assert!(cidr_contains("10.0.0.0/8", "10.1.2.3").unwrap());
+        assert!(!cidr_contains("10.0.0.0/8", "192.168.0.1").unwrap());
+
+        assert_eq!(cidr_host("10.0.0.0/24", 1).unwrap(), "10.0.0.1");
+        assert!(cidr_host("10.0.0.0/24", 256).is_err());
+
+        assert_eq!(cidr_subnet("10.0.0.0/16", 8, 2).unwrap(), "10.0.2.0/24");
+        assert!(cidr_subnet("10.0.0.0/16", 8, 256).is_err());
+
+        let engine = engine();
+        assert_eq!(
+            engine
+                .eval::<String>(r#"cidr_netmask("10.0.0.0/20") + " " + cidr_prefix("10.0.0.0/20")"#)
+                .unwrap(),
+            "255.255.240.0 20"
+        );
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(clippy::wrong_self_convention)]
 
+mod functions;
+
 use std::collections::HashMap;
 use std::fmt;
 use std::io::Write;
@@ -9,14 +11,15 @@ use std::sync::Arc;
 
 use compact_str::ToCompactString;
 use corro_client::sub::SubscriptionStream;
+use corro_client::supervise::supervise;
 use corro_client::CorrosionApiClient;
-use corro_types::api::{ColumnName, QueryEvent, RowId, SqliteParam, Statement};
+use corro_types::api::{ChangeId, ColumnName, QueryEvent, RowId, SqliteParam, Statement};
 use corro_types::change::SqliteValue;
 use futures::StreamExt;
 use indexmap::IndexMap;
 pub use rhai::Dynamic;
 use rhai::NativeCallContext;
-use rhai::{EvalAltResult, Map};
+use rhai::{Array, EvalAltResult, Map};
 use rhai_tpl::TemplateWriter;
 use rhai_tpl::Writer;
 use serde::ser::{SerializeSeq, Serializer};
@@ -73,6 +76,30 @@ impl QueryResponse {
     fn to_csv(&mut self) -> SqlToCsv {
         SqlToCsv { res: self.clone() }
     }
+
+    /// Rows as object maps, to sort, group or filter them with the rest of
+    /// the template's data
+    fn rows(&mut self) -> Result<Array, Box<EvalAltResult>> {
+        self.clone()
+            .into_iter()
+            .map(|row| {
+                let row = row?;
+                Ok(Dynamic::from_map(
+                    row.columns
+                        .iter()
+                        .map(|(name, index)| {
+                            let value = row
+                                .cells
+                                .get(*index as usize)
+                                .map(sql_to_dyn)
+                                .unwrap_or(Dynamic::UNIT);
+                            (name.as_str().into(), value)
+                        })
+                        .collect(),
+                ))
+            })
+            .collect()
+    }
 }
 
 #[derive(Clone)]
@@ -203,6 +230,16 @@ impl SqliteValueWrap {
     }
 }
 
+fn sql_to_dyn(value: &SqliteValue) -> Dynamic {
+    match value {
+        SqliteValue::Null => Dynamic::UNIT,
+        SqliteValue::Integer(i) => (*i).into(),
+        SqliteValue::Real(r) => r.0.into(),
+        SqliteValue::Text(t) => t.to_string().into(),
+        SqliteValue::Blob(b) => hex::encode(b.as_slice()).into(),
+    }
+}
+
 impl fmt::Display for SqliteValueWrap {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.0 {
@@ -268,7 +305,7 @@ impl QueryResponseIter {
                                 .collect(),
                         ))
                     }
-                    QueryEvent::EndOfQuery { .. } => {
+                    QueryEvent::EndOfQuery { change_id, .. } => {
                         match self.body.take() {
                             None => {
                                 self.done = true;
@@ -280,6 +317,8 @@ impl QueryResponseIter {
                                 let qread = self.query.read().await;
                                 tokio::spawn(wait_for_rows(
                                     rows,
+                                    qread.client.clone(),
+                                    change_id,
                                     qread.state.cmd_tx.clone(),
                                     qread.state.cancel.clone(),
                                 ));
@@ -289,7 +328,7 @@ impl QueryResponseIter {
                         self.done = true;
                         return None;
                     }
-                    QueryEvent::Row(rowid, cells) | QueryEvent::Change(_, rowid, cells, _) => {
+                    QueryEvent::Row(rowid, cells) | QueryEvent::Change(_, rowid, cells, ..) => {
                         match self.columns.as_ref() {
                             Some(columns) => {
                                 return Some(Ok(Row {
@@ -306,6 +345,17 @@ impl QueryResponseIter {
                             }
                         }
                     }
+                    QueryEvent::Estimate { .. }
+                    | QueryEvent::Progress { .. }
+                    | QueryEvent::Meta(_, _)
+                    | QueryEvent::Previous(_, _)
+                    | QueryEvent::Resync { .. }
+                    | QueryEvent::Dropped { .. }
+                    | QueryEvent::Skipped { .. } => {}
+                    QueryEvent::Closed { .. } | QueryEvent::Moved { .. } => {
+                        self.done = true;
+                        return None;
+                    }
                     QueryEvent::Error(e) => {
                         self.done = true;
                         return Some(Err(Box::new(EvalAltResult::from(e))));
@@ -358,6 +408,16 @@ impl QueryHandle {
 pub struct TemplateState {
     pub cmd_tx: mpsc::Sender<TemplateCommand>,
     pub cancel: CancellationToken,
+    /// Fail rendering on NULL values instead of rendering them as empty
+    /// strings, see [`set_strict`]
+    pub strict: bool,
+}
+
+/// Strict rendering: writing a NULL value or accessing a property missing
+/// from an object map fails rendering, instead of silently rendering
+/// nothing. `state.strict` must be set too.
+pub fn set_strict(engine: &mut rhai::Engine, strict: bool) {
+    engine.set_fail_on_invalid_map_property(strict);
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -407,38 +467,107 @@ fn write_sql_to_json<W: Write>(
     Ok(rows)
 }
 
+/// Waits for a change to the rows of a subscription before re-rendering. A
+/// broken subscription is resumed from `change_id`, the last change the
+/// rendered rows include, so nothing is missed in between. Rendering again
+/// re-creates it when it can't be resumed.
 async fn wait_for_rows(
     mut rows: SubscriptionStream<Vec<SqliteValue>>,
+    client: CorrosionApiClient,
+    mut change_id: Option<ChangeId>,
     tx: mpsc::Sender<TemplateCommand>,
     cancel: CancellationToken,
 ) {
-    let row_recv = tokio::select! {
-        row_recv = rows.next() => row_recv,
-        _ = cancel.cancelled() => {
-            debug!("template cancellation trigger, returning from tokio task");
-            return
-        },
-    };
-
-    match row_recv {
-        Some(Ok(QueryEvent::Change(_, _, cells, _))) => {
-            trace!("got an updated row! {cells:?}");
+    let cancelled = cancel.cancelled();
+    tokio::pin!(cancelled);
+
+    loop {
+        let row_recv = tokio::select! {
+            row_recv = rows.next() => row_recv,
+            _ = &mut cancelled => {
+                debug!("template cancellation trigger, returning from tokio task");
+                return
+            },
+        };
 
-            if let Err(_e) = tx.send(TemplateCommand::Render).await {
-                debug!("could not send back re-render command, channel must be closed!");
+        match row_recv {
+            Some(Ok(QueryEvent::Change(_, _, cells, ..))) => {
+                trace!("got an updated row! {cells:?}");
+                break;
+            }
+            Some(Ok(QueryEvent::Skipped { change_id: id })) => {
+                change_id = Some(id);
+                continue;
+            }
+            // the changes that follow trigger the re-render
+            Some(Ok(
+                QueryEvent::Meta(_, _) | QueryEvent::Previous(_, _) | QueryEvent::Resync { .. },
+            )) => continue,
+            // rendering queries the rows again, they're up to date
+            Some(Ok(QueryEvent::Dropped { count })) => {
+                warn!("subscription dropped {count} events, re-rendering");
+                break;
+            }
+            Some(Ok(QueryEvent::Closed { reason })) => {
+                warn!("subscription was closed ({reason:?}), re-rendering");
+                break;
+            }
+            Some(Ok(QueryEvent::Moved { addr, .. })) => {
+                warn!("subscription moved to {addr}, re-rendering");
+                break;
+            }
+            Some(Ok(QueryEvent::Error(e))) => {
+                warn!("error from subscription, resuming it: {e}");
+            }
+            Some(Ok(evt)) => {
+                warn!("unexpected event receive: {evt:?}");
+                continue;
+            }
+            Some(Err(e)) => {
+                warn!("error from upstream, resuming subscription: {e}");
+            }
+            None => {
+                warn!("sql stream is done, resuming subscription");
             }
         }
-        Some(Ok(evt)) => {
-            warn!("unexpected event receive: {evt:?}")
-        }
-        Some(Err(e)) => {
-            // TODO: need to re-render possibly...
-            warn!("error from upstream, returning... {e}");
-        }
-        None => {
-            debug!("sql stream is done");
+
+        let Some(from) = change_id else {
+            // rows can only be queried again
+            break;
+        };
+        let id = rows.id();
+        let client = &client;
+        let resumed = supervise(
+            "resuming template subscription",
+            &mut cancelled,
+            || async move {
+                match client.subscription(id, true, Some(from)).await {
+                    Ok(stream) => Ok(Some(stream)),
+                    // gone or too far behind, rendering re-creates it
+                    Err(corro_client::Error::UnexpectedStatusCode(status))
+                        if status.is_client_error() =>
+                    {
+                        Ok(None)
+                    }
+                    Err(e) => Err(e),
+                }
+            },
+        )
+        .await;
+
+        match resumed {
+            Some(Some(stream)) => rows = stream,
+            Some(None) => break,
+            None => {
+                debug!("template cancellation trigger, returning from tokio task");
+                return;
+            }
         }
     }
+
+    if let Err(_e) = tx.send(TemplateCommand::Render).await {
+        debug!("could not send back re-render command, channel must be closed!");
+    }
 }
 
 pub struct Engine {
@@ -473,9 +602,22 @@ impl Engine {
 
         engine.register_fn(
             "write",
-            |tw: &mut TemplateWriter<W, TemplateState>,
+            |cx: NativeCallContext,
+             tw: &mut TemplateWriter<W, TemplateState>,
              sql_value: SqliteValueWrap|
              -> Result<(), Box<EvalAltResult>> {
+                if matches!(sql_value.0, SqliteValue::Null) {
+                    let strict = cx
+                        .tag()
+                        .and_then(|tag| tag.read_lock::<TemplateState>())
+                        .map(|state| state.strict)
+                        .unwrap_or(false);
+                    if strict {
+                        return Err(Box::new(EvalAltResult::from(
+                            "tried to write a NULL value in strict mode",
+                        )));
+                    }
+                }
                 // TODO: make `write_str` public on TemplateWriter to use that
                 tw.write_all(sql_value.to_string().as_bytes())
                     .map_err(|e| Box::new(EvalAltResult::from(e.to_string())))
@@ -487,6 +629,7 @@ impl Engine {
         engine.register_fn("to_json", QueryResponse::to_json);
         engine.register_fn("to_json", QueryResponse::to_json_w_options);
         engine.register_fn("to_csv", QueryResponse::to_csv);
+        engine.register_fn("rows", QueryResponse::rows);
 
         engine.register_type_with_name::<Row>("Row");
         engine.register_indexer_get(Row::get_cell_value);
@@ -602,6 +745,8 @@ impl Engine {
                 .to_string())
         });
 
+        functions::register(&mut engine);
+
         Self { engine }
     }
 }
@@ -756,6 +901,7 @@ mod tests {
                 let state = TemplateState {
                     cmd_tx: tx.clone(),
                     cancel: cancel.clone(),
+                    strict: false,
                 };
                 tpl.evaluator_mut()
                     .set_default_tag(Dynamic::from(state.clone()));
@@ -799,6 +945,7 @@ mod tests {
             let state = TemplateState {
                 cmd_tx: tx,
                 cancel: CancellationToken::new(),
+                strict: false,
             };
             tpl.evaluator_mut()
                 .set_default_tag(Dynamic::from(state.clone()));
@@ -809,4 +956,71 @@ mod tests {
 
         println!("output: {output}");
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_strict() {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _trip_worker, _trip_sender) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone())
+            .await
+            .unwrap();
+
+        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+
+        client
+            .schema(&[Statement::Simple(corro_tests::TEST_SCHEMA.into())])
+            .await
+            .unwrap();
+
+        client
+            .execute(&[Statement::WithParams(
+                "insert into tests (id, text) values (?,?)".into(),
+                vec![1i64.into(), "service-name".into()],
+            )])
+            .await
+            .unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let filepath = tmpdir.path().join("output");
+
+        let mut engine = Engine::new::<std::fs::File>(client.clone());
+
+        let mut render = |input: &str, strict: bool| {
+            let f = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&filepath)
+                .unwrap();
+
+            let (tx, _rx) = mpsc::channel(1);
+
+            block_in_place(|| {
+                let mut tpl = engine.compile_mut(input).unwrap();
+                let state = TemplateState {
+                    cmd_tx: tx,
+                    cancel: CancellationToken::new(),
+                    strict,
+                };
+                let rhai_engine = tpl.evaluator_mut();
+                rhai_engine.set_default_tag(Dynamic::from(state.clone()));
+                set_strict(rhai_engine, strict);
+                tpl.render(f, state)
+                    .map(|_| std::fs::read_to_string(&filepath).unwrap())
+            })
+        };
+
+        let null_input = r#"<% for row in sql("select text, null as nothing from tests") { %>[<%= row["text"] %>|<%= row["nothing"] %>]<% } %>"#;
+        assert_eq!(render(null_input, false).unwrap(), "[service-name|]");
+        assert!(render(null_input, true).is_err());
+
+        let missing_input = r#"<% let host = #{ port: 80 }.host; %>ok"#;
+        assert_eq!(render(missing_input, false).unwrap(), "ok");
+        assert!(render(missing_input, true).is_err());
+
+        // values that are there render the same
+        let input = r#"<% for row in sql("select text from tests") { %><%= row["text"] %><% } %>"#;
+        assert_eq!(render(input, true).unwrap(), "service-name");
+    }
 }
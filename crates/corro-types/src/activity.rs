@@ -0,0 +1,155 @@
+use std::{fmt, ops::RangeInclusive};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+
+use crate::{
+    actor::ActorId,
+    base::{CrsqlDbVersion, Version},
+};
+
+const ACTIVITY_CHANNEL_CAPACITY: usize = 4096;
+
+/// Notable replication events, meant for human consumption (e.g. `corrosion tail`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum ActivityKind {
+    /// A new version was committed locally
+    Committed {
+        version: Version,
+        db_version: CrsqlDbVersion,
+    },
+    /// A changeset from a peer was applied
+    Applied {
+        actor_id: ActorId,
+        versions: RangeInclusive<Version>,
+        rows: usize,
+        source: String,
+    },
+    SyncStarted {
+        peers: Vec<ActorId>,
+    },
+    SyncCompleted {
+        changes: usize,
+        elapsed_secs: f64,
+    },
+    /// A changeset from a peer references tables or columns the local
+    /// schema doesn't have yet, it's held until the schema catches up
+    SchemaBehind {
+        actor_id: ActorId,
+        versions: RangeInclusive<Version>,
+        missing: Vec<String>,
+    },
+    Error {
+        context: String,
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Activity {
+    pub ts: OffsetDateTime,
+    pub kind: ActivityKind,
+}
+
+impl Activity {
+    pub fn is_error(&self) -> bool {
+        matches!(self.kind, ActivityKind::Error { .. })
+    }
+}
+
+impl fmt::Display for ActivityKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActivityKind::Committed {
+                version,
+                db_version,
+            } => write!(
+                f,
+                "committed local version {version} (db_version: {db_version})"
+            ),
+            ActivityKind::Applied {
+                actor_id,
+                versions,
+                rows,
+                source,
+            } => {
+                if versions.start() == versions.end() {
+                    write!(
+                        f,
+                        "applied version {} from {actor_id} ({rows} rows, via {source})",
+                        versions.start()
+                    )
+                } else {
+                    write!(
+                        f,
+                        "applied versions {}..={} from {actor_id} ({rows} rows, via {source})",
+                        versions.start(),
+                        versions.end()
+                    )
+                }
+            }
+            ActivityKind::SyncStarted { peers } => write!(
+                f,
+                "sync started with {}",
+                peers
+                    .iter()
+                    .map(|actor_id| actor_id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ActivityKind::SyncCompleted {
+                changes,
+                elapsed_secs,
+            } => write!(f, "sync completed, {changes} changes in {elapsed_secs}s"),
+            ActivityKind::SchemaBehind {
+                actor_id,
+                versions,
+                missing,
+            } => write!(
+                f,
+                "schema behind, holding versions {}..={} from {actor_id} until it has {}",
+                versions.start(),
+                versions.end(),
+                missing.join(", ")
+            ),
+            ActivityKind::Error { context, error } => write!(f, "error {context}: {error}"),
+        }
+    }
+}
+
+/// Fan-out of [`Activity`] to any interested listener. Publishing is a
+/// no-op when nobody is listening.
+#[derive(Clone)]
+pub struct ActivityFeed {
+    tx: broadcast::Sender<Activity>,
+}
+
+impl Default for ActivityFeed {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(ACTIVITY_CHANNEL_CAPACITY);
+        Self { tx }
+    }
+}
+
+impl ActivityFeed {
+    pub fn publish(&self, kind: ActivityKind) {
+        self.publish_with(|| kind)
+    }
+
+    /// Only builds the activity if there are listeners
+    pub fn publish_with<F: FnOnce() -> ActivityKind>(&self, f: F) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        _ = self.tx.send(Activity {
+            ts: OffsetDateTime::now_utc(),
+            kind: f(),
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Activity> {
+        self.tx.subscribe()
+    }
+}
@@ -15,10 +15,10 @@ use arc_swap::ArcSwap;
 use camino::Utf8PathBuf;
 use compact_str::CompactString;
 use indexmap::IndexMap;
-use metrics::{gauge, histogram};
-use parking_lot::RwLock;
+use metrics::{counter, gauge, histogram};
+use parking_lot::{Mutex, RwLock};
 use rangemap::RangeInclusiveSet;
-use rusqlite::{Connection, Transaction};
+use rusqlite::{Connection, OpenFlags, Transaction};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{
     AcquireError, OwnedRwLockWriteGuard as OwnedTokioRwLockWriteGuard, OwnedSemaphorePermit,
@@ -34,14 +34,30 @@ use tracing::{debug, error};
 use tripwire::Tripwire;
 
 use crate::{
+    activity::ActivityFeed,
     actor::{Actor, ActorId, ClusterId},
     base::{CrsqlDbVersion, CrsqlSeq, Version},
+    behind::SchemaBehind,
+    bookkeeping::{BookkeepingError, BookkeepingStore, SqliteBookkeeping},
     broadcast::{BroadcastInput, ChangeSource, ChangeV1, FocaInput, Timestamp},
+    budget::MemoryBudget,
     channel::{bounded, CorroSender},
+    clock::Clock,
     config::Config,
+    fence::WriteFence,
+    gaps::GapTracker,
+    history::History,
+    load::LoadMonitor,
     pubsub::SubsManager,
+    replay::ReplayWindow,
     schema::Schema,
-    sqlite::{rusqlite_to_crsqlite, setup_conn, CrConn, Migration, SqlitePool, SqlitePoolError},
+    sqlite::{
+        attach_ephemeral, ephemeral_db_uri, rusqlite_to_crsqlite, setup_conn, CrConn, Migration,
+        SqlitePool, SqlitePoolError,
+    },
+    statements::StatementCache,
+    validation::Validators,
+    watches::KeyWatches,
 };
 
 use super::members::Members;
@@ -57,9 +73,10 @@ pub struct AgentConfig {
     pub external_addr: Option<SocketAddr>,
     pub api_addr: SocketAddr,
     pub members: RwLock<Members>,
-    pub clock: Arc<uhlc::HLC>,
+    pub clock: Arc<dyn Clock>,
 
     pub booked: Booked,
+    pub bookkeeping: Arc<dyn BookkeepingStore>,
 
     pub tx_bcast: CorroSender<BroadcastInput>,
     pub tx_apply: CorroSender<(ActorId, Version)>,
@@ -86,8 +103,9 @@ pub struct AgentInner {
     external_addr: Option<SocketAddr>,
     api_addr: SocketAddr,
     members: RwLock<Members>,
-    clock: Arc<uhlc::HLC>,
+    clock: Arc<dyn Clock>,
     booked: Booked,
+    bookkeeping: Arc<dyn BookkeepingStore>,
     tx_bcast: CorroSender<BroadcastInput>,
     tx_apply: CorroSender<(ActorId, Version)>,
     tx_empty: CorroSender<(ActorId, RangeInclusive<Version>)>,
@@ -99,6 +117,17 @@ pub struct AgentInner {
     cluster_id: ArcSwap<ClusterId>,
     limits: Limits,
     subs_manager: SubsManager,
+    activity: ActivityFeed,
+    gaps: Mutex<GapTracker>,
+    replay: Mutex<ReplayWindow>,
+    budget: MemoryBudget,
+    key_watches: KeyWatches,
+    validators: Validators,
+    schema_behind: SchemaBehind,
+    history: History,
+    write_fence: WriteFence,
+    statement_cache: StatementCache,
+    load: LoadMonitor,
 }
 
 #[derive(Debug, Clone)]
@@ -108,6 +137,13 @@ pub struct Limits {
 
 impl Agent {
     pub fn new(config: AgentConfig) -> Self {
+        let (replay_window_len, budget) = {
+            let loaded = config.config.load();
+            (
+                loaded.perf.replay_window_len,
+                MemoryBudget::new(&loaded.perf),
+            )
+        };
         Self(Arc::new(AgentInner {
             actor_id: config.actor_id,
             pool: config.pool,
@@ -118,6 +154,7 @@ impl Agent {
             members: config.members,
             clock: config.clock,
             booked: config.booked,
+            bookkeeping: config.bookkeeping,
             tx_bcast: config.tx_bcast,
             tx_apply: config.tx_apply,
             tx_empty: config.tx_empty,
@@ -131,6 +168,17 @@ impl Agent {
                 sync: Arc::new(Semaphore::new(3)),
             },
             subs_manager: config.subs_manager,
+            activity: ActivityFeed::default(),
+            gaps: Default::default(),
+            replay: Mutex::new(ReplayWindow::new(replay_window_len)),
+            budget,
+            key_watches: KeyWatches::default(),
+            validators: Validators::default(),
+            schema_behind: SchemaBehind::default(),
+            history: History::default(),
+            write_fence: WriteFence::default(),
+            statement_cache: StatementCache::default(),
+            load: LoadMonitor::default(),
         }))
     }
 
@@ -152,7 +200,7 @@ impl Agent {
         self.0.actor_id
     }
 
-    pub fn clock(&self) -> &Arc<uhlc::HLC> {
+    pub fn clock(&self) -> &Arc<dyn Clock> {
         &self.0.clock
     }
 
@@ -208,6 +256,10 @@ impl Agent {
         &self.0.booked
     }
 
+    pub fn bookkeeping(&self) -> &dyn BookkeepingStore {
+        &*self.0.bookkeeping
+    }
+
     pub fn members(&self) -> &RwLock<Members> {
         &self.0.members
     }
@@ -236,6 +288,61 @@ impl Agent {
         &self.0.subs_manager
     }
 
+    pub fn key_watches(&self) -> &KeyWatches {
+        &self.0.key_watches
+    }
+
+    /// Validators run before committing local transactions, register them
+    /// before starting the agent
+    pub fn validators(&self) -> &Validators {
+        &self.0.validators
+    }
+
+    /// Changesets waiting for the local schema to catch up
+    pub fn schema_behind(&self) -> &SchemaBehind {
+        &self.0.schema_behind
+    }
+
+    /// Counts of the current minute, for the history table
+    pub fn history(&self) -> &History {
+        &self.0.history
+    }
+
+    /// Raised by schema migrations, fencing local writes
+    pub fn write_fence(&self) -> &WriteFence {
+        &self.0.write_fence
+    }
+
+    /// Metadata of the statements sent to the API, by normalized SQL
+    pub fn statement_cache(&self) -> &StatementCache {
+        &self.0.statement_cache
+    }
+
+    /// Pressure signals deciding which API requests are shed
+    pub fn load(&self) -> &LoadMonitor {
+        &self.0.load
+    }
+
+    pub fn activity(&self) -> &ActivityFeed {
+        &self.0.activity
+    }
+
+    /// Versions missing from remote actors, and for how long
+    pub fn gaps(&self) -> &Mutex<GapTracker> {
+        &self.0.gaps
+    }
+
+    /// Change frames recently received from broadcasts
+    pub fn replay(&self) -> &Mutex<ReplayWindow> {
+        &self.0.replay
+    }
+
+    /// Bytes buffered in memory while waiting to be broadcast, applied or
+    /// sent to subscribers
+    pub fn budget(&self) -> &MemoryBudget {
+        &self.0.budget
+    }
+
     pub fn set_cluster_id(&self, cluster_id: ClusterId) {
         self.0.cluster_id.store(Arc::new(cluster_id));
     }
@@ -252,6 +359,10 @@ pub fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
         Box::new(create_corro_subs as fn(&Transaction) -> rusqlite::Result<()>),
         Box::new(refactor_corro_members as fn(&Transaction) -> rusqlite::Result<()>),
         Box::new(crsqlite_v0_16_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(create_corro_api_tokens as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(create_corro_statements as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(create_corro_dropped_tables as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(create_corro_dropped_columns as fn(&Transaction) -> rusqlite::Result<()>),
     ];
 
     crate::sqlite::migrate(conn, migrations)
@@ -309,6 +420,66 @@ fn crsqlite_v0_16_migration(tx: &Transaction) -> rusqlite::Result<()> {
     Ok(())
 }
 
+fn create_corro_api_tokens(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        -- scoped api tokens, only their hashes are kept
+        CREATE TABLE __corro_api_tokens (
+            id BLOB NOT NULL PRIMARY KEY,
+            name TEXT,
+            hash TEXT NOT NULL UNIQUE,
+            scope TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            -- secret replaced by the last rotation, valid until prev_expires_at
+            prev_hash TEXT UNIQUE,
+            prev_expires_at INTEGER
+        ) WITHOUT ROWID;
+    "#,
+    )
+}
+
+fn create_corro_statements(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        -- statements registered by name through the api, replicated
+        CREATE TABLE __corro_statements (
+            name TEXT NOT NULL PRIMARY KEY,
+            sql TEXT NOT NULL DEFAULT '',
+            created_at INTEGER NOT NULL DEFAULT 0
+        ) WITHOUT ROWID;
+
+        SELECT crsql_as_crr('__corro_statements');
+        CREATE INDEX IF NOT EXISTS corro___corro_statements__crsql_clock_site_id_dbv ON __corro_statements__crsql_clock (site_id, db_version);
+    "#,
+    )
+}
+
+fn create_corro_dropped_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        -- tables dropped through the api, changes to them are skipped
+        CREATE TABLE __corro_dropped_tables (
+            name TEXT NOT NULL PRIMARY KEY
+        ) WITHOUT ROWID;
+    "#,
+    )
+}
+
+fn create_corro_dropped_columns(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        -- columns dropped or renamed through the api, changes to them are
+        -- skipped or applied to the column they were renamed to
+        CREATE TABLE __corro_dropped_columns (
+            tbl_name TEXT NOT NULL,
+            name TEXT NOT NULL,
+            renamed_to TEXT,
+            PRIMARY KEY (tbl_name, name)
+        ) WITHOUT ROWID;
+    "#,
+    )
+}
+
 fn refactor_corro_members(tx: &Transaction) -> rusqlite::Result<()> {
     tx.execute_batch(
         r#"
@@ -440,9 +611,115 @@ struct SplitPoolInner {
     read: SqlitePool,
     write: SqlitePool,
 
-    priority_tx: CorroSender<oneshot::Sender<CancellationToken>>,
-    normal_tx: CorroSender<oneshot::Sender<CancellationToken>>,
-    low_tx: CorroSender<oneshot::Sender<CancellationToken>>,
+    queues: [CorroSender<oneshot::Sender<CancellationToken>>; WriteClass::COUNT],
+}
+
+/// Kind of work asking for the write connection. Each class has its own
+/// queue, queues are served in a weighted round-robin so a busy class
+/// can't starve the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WriteClass {
+    /// Client input (API writes, schema changes)
+    Interactive,
+    /// Applying changes from the cluster (broadcasts, sync)
+    Apply,
+    /// Background tasks (compaction, cleanups)
+    Maintenance,
+}
+
+impl WriteClass {
+    const COUNT: usize = 3;
+    const ALL: [WriteClass; Self::COUNT] = [
+        WriteClass::Interactive,
+        WriteClass::Apply,
+        WriteClass::Maintenance,
+    ];
+
+    /// Number of grants per scheduling round when every queue is busy
+    fn weight(self) -> u32 {
+        match self {
+            WriteClass::Interactive => 4,
+            WriteClass::Apply => 2,
+            WriteClass::Maintenance => 1,
+        }
+    }
+
+    fn queue_size(self) -> usize {
+        match self {
+            WriteClass::Interactive => 256,
+            WriteClass::Apply => 512,
+            WriteClass::Maintenance => 1024,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WriteClass::Interactive => "interactive",
+            WriteClass::Apply => "apply",
+            WriteClass::Maintenance => "maintenance",
+        }
+    }
+    /// Value of the `queue` label of pool metrics, the name of the priority
+    /// queue the class replaced
+    fn queue_label(self) -> &'static str {
+        match self {
+            WriteClass::Interactive => "priority",
+            WriteClass::Apply => "normal",
+            WriteClass::Maintenance => "low",
+        }
+    }
+}
+
+/// Waiting longer than this for the write connection counts as starvation
+const WRITE_STARVATION_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Weighted round-robin over the write classes: within a round, each class
+/// is granted the connection up to its weight. A round ends when no class
+/// with credits left has a waiter, idle classes don't hold up the others.
+#[derive(Debug)]
+struct FairScheduler {
+    credits: [u32; WriteClass::COUNT],
+}
+
+impl Default for FairScheduler {
+    fn default() -> Self {
+        Self {
+            credits: WriteClass::ALL.map(WriteClass::weight),
+        }
+    }
+}
+
+impl FairScheduler {
+    /// Pick the next waiter, if any, `try_next` pops from a class' queue
+    fn next<T>(
+        &mut self,
+        mut try_next: impl FnMut(WriteClass) -> Option<T>,
+    ) -> Option<(WriteClass, T)> {
+        // a second pass with fresh credits covers classes that used theirs up
+        for _ in 0..2 {
+            for class in WriteClass::ALL {
+                if self.credits[class.index()] == 0 {
+                    continue;
+                }
+                if let Some(item) = try_next(class) {
+                    self.credits[class.index()] -= 1;
+                    return Some((class, item));
+                }
+            }
+            self.credits = WriteClass::ALL.map(WriteClass::weight);
+        }
+        None
+    }
+
+    /// Account for a waiter that was handed out without going through `next`
+    fn granted(&mut self, class: WriteClass) {
+        let credits = &mut self.credits[class.index()];
+        *credits = credits.saturating_sub(1);
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -457,18 +734,6 @@ pub enum PoolError {
     Permit(#[from] AcquireError),
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum ChangeError {
-    #[error("could not acquire pooled connection: {0}")]
-    Pool(#[from] PoolError),
-    #[error("rusqlite: {source} (actor_id: {actor_id:?}, version: {version:?})")]
-    Rusqlite {
-        source: rusqlite::Error,
-        actor_id: Option<ActorId>,
-        version: Option<Version>,
-    },
-}
-
 #[derive(Debug, thiserror::Error)]
 pub enum SplitPoolCreateError {
     #[error(transparent)]
@@ -484,16 +749,25 @@ impl SplitPool {
         path: P,
         write_sema: Arc<Semaphore>,
     ) -> Result<Self, SplitPoolCreateError> {
+        let ephemeral_uri = ephemeral_db_uri(path.as_ref());
+        let transform = move |conn: Connection| -> rusqlite::Result<CrConn> {
+            let conn = rusqlite_to_crsqlite(conn)?;
+            attach_ephemeral(&conn, &ephemeral_uri)?;
+            Ok(conn)
+        };
+
         let rw_pool = sqlite_pool::Config::new(path.as_ref())
             .max_size(1)
-            .create_pool_transform(rusqlite_to_crsqlite)?;
+            .create_pool_transform(transform.clone())?;
 
         debug!("built RW pool");
 
-        let ro_pool = sqlite_pool::Config::new(path.as_ref())
+        let mut ro_config = sqlite_pool::Config::new(path.as_ref())
             .read_only()
-            .max_size(20)
-            .create_pool_transform(rusqlite_to_crsqlite)?;
+            .max_size(20);
+        // the ephemeral database is attached with a URI
+        ro_config.open_flags |= OpenFlags::SQLITE_OPEN_URI;
+        let ro_pool = ro_config.create_pool_transform(transform)?;
         debug!("built RO pool");
 
         Ok(Self::new(
@@ -505,20 +779,49 @@ impl SplitPool {
     }
 
     fn new(path: PathBuf, write_sema: Arc<Semaphore>, read: SqlitePool, write: SqlitePool) -> Self {
-        let (priority_tx, mut priority_rx) = bounded(256, "priority");
-        let (normal_tx, mut normal_rx) = bounded(512, "normal");
-        let (low_tx, mut low_rx) = bounded(1024, "low");
+        let (interactive_tx, mut interactive_rx) = bounded(
+            WriteClass::Interactive.queue_size(),
+            WriteClass::Interactive.as_str(),
+        );
+        let (apply_tx, mut apply_rx) =
+            bounded(WriteClass::Apply.queue_size(), WriteClass::Apply.as_str());
+        let (maintenance_tx, mut maintenance_rx) = bounded(
+            WriteClass::Maintenance.queue_size(),
+            WriteClass::Maintenance.as_str(),
+        );
 
         tokio::spawn(async move {
+            let mut scheduler = FairScheduler::default();
             loop {
-                let tx: oneshot::Sender<CancellationToken> = tokio::select! {
-                    biased;
-
-                    Some(tx) = priority_rx.recv() => tx,
-                    Some(tx) = normal_rx.recv() => tx,
-                    Some(tx) = low_rx.recv() => tx,
+                let next = scheduler.next(|class| {
+                    match class {
+                        WriteClass::Interactive => interactive_rx.try_recv(),
+                        WriteClass::Apply => apply_rx.try_recv(),
+                        WriteClass::Maintenance => maintenance_rx.try_recv(),
+                    }
+                    .ok()
+                });
+
+                let (class, tx) = match next {
+                    Some(next) => next,
+                    // every queue is empty, wait for the first request
+                    None => {
+                        let next = tokio::select! {
+                            biased;
+
+                            Some(tx) = interactive_rx.recv() => (WriteClass::Interactive, tx),
+                            Some(tx) = apply_rx.recv() => (WriteClass::Apply, tx),
+                            Some(tx) = maintenance_rx.recv() => (WriteClass::Maintenance, tx),
+                            else => break,
+                        };
+                        scheduler.granted(next.0);
+                        next
+                    }
                 };
 
+                counter!("corro.sqlite.pool.queue.grants", "queue" => class.queue_label())
+                    .increment(1);
+
                 wait_conn_drop(tx).await
             }
         });
@@ -528,9 +831,7 @@ impl SplitPool {
             write_sema,
             read,
             write,
-            priority_tx,
-            normal_tx,
-            low_tx,
+            queues: [interactive_tx, apply_tx, maintenance_tx],
         }))
     }
 
@@ -544,6 +845,17 @@ impl SplitPool {
         gauge!("corro.sqlite.pool.write.connections").set(write_state.size as f64);
         gauge!("corro.sqlite.pool.write.connections.available").set(write_state.available as f64);
         gauge!("corro.sqlite.pool.write.connections.waiting").set(write_state.waiting as f64);
+
+        for class in WriteClass::ALL {
+            gauge!("corro.sqlite.pool.queue.depth", "queue" => class.queue_label())
+                .set(self.queue_depth(class) as f64);
+        }
+    }
+
+    /// Number of waiters queued for the write connection in this class
+    pub fn queue_depth(&self, class: WriteClass) -> usize {
+        let queue = &self.0.queues[class.index()];
+        queue.max_capacity() - queue.capacity()
     }
 
     // get a read-only connection
@@ -573,32 +885,38 @@ impl SplitPool {
     // get a high priority write connection (e.g. client input)
     #[tracing::instrument(skip(self), level = "debug")]
     pub async fn write_priority(&self) -> Result<WriteConn, PoolError> {
-        self.write_inner(&self.0.priority_tx, "priority").await
+        self.write(WriteClass::Interactive).await
     }
 
     // get a normal priority write connection (e.g. sync process)
     #[tracing::instrument(skip(self), level = "debug")]
     pub async fn write_normal(&self) -> Result<WriteConn, PoolError> {
-        self.write_inner(&self.0.normal_tx, "normal").await
+        self.write(WriteClass::Apply).await
     }
 
     // get a low priority write connection (e.g. background tasks)
     #[tracing::instrument(skip(self), level = "debug")]
     pub async fn write_low(&self) -> Result<WriteConn, PoolError> {
-        self.write_inner(&self.0.low_tx, "low").await
+        self.write(WriteClass::Maintenance).await
     }
 
-    async fn write_inner(
-        &self,
-        chan: &CorroSender<oneshot::Sender<CancellationToken>>,
-        queue: &'static str,
-    ) -> Result<WriteConn, PoolError> {
+    // get a write connection, scheduled fairly with the other classes
+    #[tracing::instrument(skip(self), level = "debug")]
+    pub async fn write(&self, class: WriteClass) -> Result<WriteConn, PoolError> {
         let (tx, rx) = oneshot::channel();
-        chan.send(tx).await.map_err(|_| PoolError::QueueClosed)?;
         let start = Instant::now();
+        self.0.queues[class.index()]
+            .send(tx)
+            .await
+            .map_err(|_| PoolError::QueueClosed)?;
         let token = rx.await.map_err(|_| PoolError::CallbackClosed)?;
-        histogram!("corro.sqlite.pool.queue.seconds", "queue" => queue)
-            .record(start.elapsed().as_secs_f64());
+        let waited = start.elapsed();
+        histogram!("corro.sqlite.pool.queue.seconds", "queue" => class.queue_label())
+            .record(waited.as_secs_f64());
+        if waited >= WRITE_STARVATION_THRESHOLD {
+            counter!("corro.sqlite.pool.queue.starved", "queue" => class.queue_label())
+                .increment(1);
+        }
         let conn = self.0.write.get().await?;
 
         let start = Instant::now();
@@ -1020,33 +1338,24 @@ pub struct BookedVersions {
 }
 
 impl BookedVersions {
-    pub fn from_conn(conn: &Connection, actor_id: ActorId) -> rusqlite::Result<Self> {
-        let mut bv = Self::default();
-        let mut prepped = conn.prepare_cached(
-            "SELECT start_version, end_version, db_version, last_seq, ts FROM __corro_bookkeeping WHERE actor_id = ?",
-        )?;
-        let mut rows = prepped.query([actor_id])?;
+    pub fn from_conn(conn: &Connection, actor_id: ActorId) -> Result<Self, BookkeepingError> {
+        Self::from_store(&SqliteBookkeeping, conn, actor_id)
+    }
 
-        loop {
-            let row = rows.next()?;
-            match row {
-                None => break,
-                Some(row) => {
-                    let start_v = row.get(0)?;
-                    let end_v: Option<Version> = row.get(1)?;
-                    bv.insert_many(
-                        start_v..=end_v.unwrap_or(start_v),
-                        match row.get(2)? {
-                            Some(db_version) => KnownDbVersion::Current(CurrentVersion {
-                                db_version,
-                                last_seq: row.get(3)?,
-                                ts: row.get(4)?,
-                            }),
-                            None => KnownDbVersion::Cleared,
-                        },
-                    );
-                }
-            }
+    pub fn from_store(
+        store: &dyn BookkeepingStore,
+        conn: &Connection,
+        actor_id: ActorId,
+    ) -> Result<Self, BookkeepingError> {
+        let mut bv = Self::default();
+        for (versions, current) in store.versions(conn, actor_id)? {
+            bv.insert_many(
+                versions,
+                match current {
+                    Some(current) => KnownDbVersion::Current(current),
+                    None => KnownDbVersion::Cleared,
+                },
+            );
         }
 
         let mut prepped = conn.prepare_cached(
@@ -1323,3 +1632,81 @@ impl Bookie {
         self.0.registry()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    #[test]
+    fn fair_scheduler_does_not_starve() {
+        let mut scheduler = FairScheduler::default();
+        let mut queues: [VecDeque<usize>; WriteClass::COUNT] =
+            [(0..100).collect(), (0..100).collect(), (0..100).collect()];
+
+        let mut grants = vec![];
+        for _ in 0..14 {
+            let (class, _) = scheduler
+                .next(|class| queues[class.index()].pop_front())
+                .unwrap();
+            grants.push(class);
+        }
+
+        // two full rounds, every class gets its share
+        let count = |class| grants.iter().filter(|c| **c == class).count();
+        assert_eq!(count(WriteClass::Interactive), 8);
+        assert_eq!(count(WriteClass::Apply), 4);
+        assert_eq!(count(WriteClass::Maintenance), 2);
+
+        // idle classes don't hold back a busy one
+        let mut scheduler = FairScheduler::default();
+        let mut apply: VecDeque<usize> = (0..10).collect();
+        for _ in 0..10 {
+            let (class, _) = scheduler
+                .next(|class| match class {
+                    WriteClass::Apply => apply.pop_front(),
+                    _ => None,
+                })
+                .unwrap();
+            assert_eq!(class, WriteClass::Apply);
+        }
+        assert!(scheduler.next(|_| None::<usize>).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_ephemeral_tables() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let pool =
+            SplitPool::create(tmpdir.path().join("test.db"), Arc::new(Semaphore::new(1))).await?;
+        let other =
+            SplitPool::create(tmpdir.path().join("other.db"), Arc::new(Semaphore::new(1))).await?;
+
+        {
+            let conn = pool.write_priority().await?;
+            conn.execute_batch(
+                "
+                CREATE TABLE users (id INTEGER NOT NULL PRIMARY KEY, name TEXT);
+                INSERT INTO users VALUES (1, 'fiona'), (2, 'tom');
+                CREATE TABLE ephemeral.online (user_id INTEGER PRIMARY KEY);
+                INSERT INTO ephemeral.online VALUES (2);
+                ",
+            )?;
+        }
+
+        // visible from read connections and joinable with regular tables
+        let conn = pool.read().await?;
+        let name: String = conn.query_row(
+            "SELECT name FROM users JOIN ephemeral.online ON user_id = id",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(name, "tom");
+
+        // but not from another database's connections
+        let conn = other.read().await?;
+        assert!(conn.prepare("SELECT * FROM ephemeral.online").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+//! Changesets received before the local schema caught up with the peer
+//! which made them. Applying them would fail, so they're held in memory and
+//! retried once the schema changes. Changes to tables and columns dropped
+//! locally are skipped instead, the schema isn't going to catch up with
+//! them, and changes to renamed columns are applied to their new name.
+
+use std::{collections::BTreeSet, fmt, ops::RangeInclusive, sync::Arc};
+
+use corro_api_types::{Change, ColumnName, TableName};
+use indexmap::IndexMap;
+use metrics::gauge;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actor::ActorId,
+    base::{CrsqlSeq, Version},
+    broadcast::{ChangeSource, ChangeV1, Changeset},
+    prepared::STATEMENTS_TABLE,
+    schema::{DroppedColumn, Schema},
+};
+
+/// Changesets held at most, past this they're dropped and fetched again by
+/// syncs once the schema caught up
+pub const MAX_SCHEMA_BEHIND_CHANGESETS: usize = 10_000;
+
+/// Part of a changeset's schema which is missing locally
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingSchema {
+    Table(TableName),
+    Column(TableName, ColumnName),
+}
+
+impl fmt::Display for MissingSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingSchema::Table(table) => write!(f, "table '{table}'"),
+            MissingSchema::Column(table, column) => {
+                write!(f, "column '{table}.{}'", column.as_str())
+            }
+        }
+    }
+}
+
+/// Tables and columns referenced by `changes` which `schema` doesn't have.
+/// Dropped tables and columns aren't missing, see [`skip_dropped_tables`]
+/// and [`skip_dropped_columns`].
+pub fn missing_schema(schema: &Schema, changes: &[Change]) -> BTreeSet<MissingSchema> {
+    let mut missing = BTreeSet::new();
+    for change in changes {
+        // replicated internal tables aren't part of the schema
+        if change.table.as_str() == STATEMENTS_TABLE || schema.is_dropped(change.table.as_str()) {
+            continue;
+        }
+        match schema.tables.get(change.table.as_str()) {
+            None => {
+                missing.insert(MissingSchema::Table(change.table.clone()));
+            }
+            Some(table) => {
+                if !change.cid.is_crsql_sentinel()
+                    && !table.columns.contains_key(change.cid.as_str())
+                    && schema
+                        .dropped_column(change.table.as_str(), change.cid.as_str())
+                        .is_none()
+                {
+                    missing.insert(MissingSchema::Column(
+                        change.table.clone(),
+                        change.cid.clone(),
+                    ));
+                }
+            }
+        }
+    }
+    missing
+}
+
+/// Removes the changes to tables `schema` dropped from `changeset`, returns
+/// how many there were. A changeset left without changes is applied as an
+/// empty one.
+pub fn skip_dropped_tables(schema: &Schema, changeset: &mut Changeset) -> usize {
+    let Changeset::Full { changes, .. } = changeset else {
+        return 0;
+    };
+    let len = changes.len();
+    changes.retain(|change| !schema.is_dropped(change.table.as_str()));
+    len - changes.len()
+}
+
+/// Removes the changes to columns `schema` dropped from `changeset` and
+/// points those to renamed columns to their new name, returns how many
+/// were removed
+pub fn skip_dropped_columns(schema: &Schema, changeset: &mut Changeset) -> usize {
+    let Changeset::Full { changes, .. } = changeset else {
+        return 0;
+    };
+    let len = changes.len();
+    changes.retain_mut(|change| {
+        match schema.dropped_column(change.table.as_str(), change.cid.as_str()) {
+            None => true,
+            Some(DroppedColumn::Dropped) => false,
+            Some(DroppedColumn::Renamed(name)) => {
+                change.cid = ColumnName(name.into());
+                true
+            }
+        }
+    });
+    len - changes.len()
+}
+
+type ChangesetKey = (
+    ActorId,
+    RangeInclusive<Version>,
+    Option<RangeInclusive<CrsqlSeq>>,
+);
+
+#[derive(Clone, Default)]
+pub struct SchemaBehind(Arc<Mutex<IndexMap<ChangesetKey, (ChangeV1, ChangeSource)>>>);
+
+impl SchemaBehind {
+    /// Holds a changeset until the schema catches up. The same changeset
+    /// received again, from a broadcast or a sync, is only held once.
+    /// Returns false if too many changesets are already held.
+    pub fn buffer(&self, change: ChangeV1, src: ChangeSource) -> bool {
+        let key = (change.actor_id, change.versions(), change.seqs().cloned());
+
+        let mut buffered = self.0.lock();
+        if !buffered.contains_key(&key) && buffered.len() >= MAX_SCHEMA_BEHIND_CHANGESETS {
+            return false;
+        }
+        buffered.insert(key, (change, src));
+        gauge!("corro.agent.changes.schema_behind").set(buffered.len() as f64);
+
+        true
+    }
+
+    /// Takes the changesets `schema` can now apply, in the order they were
+    /// received
+    pub fn take_ready(&self, schema: &Schema) -> Vec<(ChangeV1, ChangeSource)> {
+        let mut buffered = self.0.lock();
+
+        let mut ready = vec![];
+        buffered.retain(|_, (change, src)| {
+            if missing_schema(schema, change.changes()).is_empty() {
+                ready.push((change.clone(), src.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        gauge!("corro.agent.changes.schema_behind").set(buffered.len() as f64);
+
+        ready
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        base::CrsqlDbVersion,
+        broadcast::{Changeset, Timestamp},
+        schema::parse_sql,
+    };
+
+    fn change(cid: &str) -> Change {
+        Change {
+            table: TableName("tests".into()),
+            pk: vec![1],
+            cid: ColumnName(cid.into()),
+            val: "hello".into(),
+            col_version: 1,
+            db_version: CrsqlDbVersion(1),
+            seq: CrsqlSeq(0),
+            site_id: [0; 16],
+            cl: 1,
+        }
+    }
+
+    #[test]
+    fn test_schema_behind() {
+        let old = parse_sql("CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY);").unwrap();
+        let new = parse_sql(
+            "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, text TEXT NOT NULL DEFAULT '');",
+        )
+        .unwrap();
+
+        let changes = vec![change("-1"), change("text")];
+        assert_eq!(
+            missing_schema(&old, &changes),
+            [MissingSchema::Column(
+                TableName("tests".into()),
+                ColumnName("text".into())
+            )]
+            .into()
+        );
+        assert!(missing_schema(&new, &changes).is_empty());
+        assert_eq!(
+            missing_schema(&Schema::default(), &changes),
+            [MissingSchema::Table(TableName("tests".into()))].into()
+        );
+
+        let change = ChangeV1 {
+            actor_id: ActorId::default(),
+            changeset: Changeset::Full {
+                version: Version(1),
+                changes,
+                seqs: CrsqlSeq(0)..=CrsqlSeq(1),
+                last_seq: CrsqlSeq(1),
+                ts: Timestamp::default(),
+            },
+        };
+
+        let behind = SchemaBehind::default();
+        assert!(behind.buffer(change.clone(), ChangeSource::Broadcast));
+        assert!(behind.buffer(change.clone(), ChangeSource::Sync));
+        assert_eq!(behind.len(), 1);
+
+        assert!(behind.take_ready(&old).is_empty());
+        assert_eq!(behind.len(), 1);
+
+        let ready = behind.take_ready(&new);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].0, change);
+        assert!(behind.is_empty());
+    }
+
+    #[test]
+    fn test_dropped_tables_skipped() {
+        let mut schema =
+            parse_sql("CREATE TABLE tests2 (id INTEGER NOT NULL PRIMARY KEY);").unwrap();
+        schema.dropped.insert("tests".into());
+
+        let mut other = change("text");
+        other.table = TableName("tests2".into());
+        other.cid = ColumnName("id".into());
+
+        let changes = vec![change("-1"), change("text"), other.clone()];
+        assert!(missing_schema(&schema, &changes).is_empty());
+
+        let mut changeset = Changeset::Full {
+            version: Version(1),
+            changes,
+            seqs: CrsqlSeq(0)..=CrsqlSeq(2),
+            last_seq: CrsqlSeq(2),
+            ts: Timestamp::default(),
+        };
+        assert_eq!(skip_dropped_tables(&schema, &mut changeset), 2);
+        assert_eq!(changeset.changes(), &[other]);
+
+        // created again, its changes are applied
+        let mut schema = parse_sql(
+            "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, text TEXT NOT NULL DEFAULT '');",
+        )
+        .unwrap();
+        schema.dropped.insert("tests".into());
+        let mut changeset = Changeset::Full {
+            version: Version(1),
+            changes: vec![change("text")],
+            seqs: CrsqlSeq(0)..=CrsqlSeq(0),
+            last_seq: CrsqlSeq(0),
+            ts: Timestamp::default(),
+        };
+        assert_eq!(skip_dropped_tables(&schema, &mut changeset), 0);
+        assert!(missing_schema(&schema, changeset.changes()).is_empty());
+    }
+
+    #[test]
+    fn test_dropped_columns_skipped() {
+        let mut schema = parse_sql(
+            "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, body TEXT NOT NULL DEFAULT '');",
+        )
+        .unwrap();
+        schema.dropped_columns.insert(
+            "tests".into(),
+            [
+                ("old".to_string(), None),
+                ("text".to_string(), Some("content".to_string())),
+                ("content".to_string(), Some("body".to_string())),
+            ]
+            .into(),
+        );
+
+        let changes = vec![change("-1"), change("old"), change("text"), change("body")];
+        assert!(missing_schema(&schema, &changes).is_empty());
+        assert_eq!(
+            missing_schema(&schema, &[change("other")]),
+            [MissingSchema::Column(
+                TableName("tests".into()),
+                ColumnName("other".into())
+            )]
+            .into()
+        );
+
+        let mut changeset = Changeset::Full {
+            version: Version(1),
+            changes,
+            seqs: CrsqlSeq(0)..=CrsqlSeq(3),
+            last_seq: CrsqlSeq(3),
+            ts: Timestamp::default(),
+        };
+        assert_eq!(skip_dropped_columns(&schema, &mut changeset), 1);
+        // renamed twice since, the change follows the column
+        assert_eq!(
+            changeset.changes(),
+            &[change("-1"), change("body"), change("body")]
+        );
+    }
+
+    #[test]
+    fn test_internal_tables_not_behind() {
+        let mut change = change("sql");
+        change.table = TableName(STATEMENTS_TABLE.into());
+        assert!(missing_schema(&Schema::default(), &[change]).is_empty());
+    }
+}
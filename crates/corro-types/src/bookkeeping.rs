@@ -0,0 +1,443 @@
+//! Persistence for the versions known from each actor.
+//!
+//! By default, bookkeeping lives in the `__corro_bookkeeping` table of the
+//! main database and is written in the same transaction as the changes it
+//! describes. Other backends implement [`BookkeepingStore`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::RangeInclusive,
+};
+
+use parking_lot::Mutex;
+use rangemap::RangeInclusiveSet;
+use rusqlite::{named_params, params, Connection, OptionalExtension};
+use tracing::{debug, warn};
+
+use crate::{
+    actor::ActorId,
+    agent::CurrentVersion,
+    base::{CrsqlDbVersion, Version},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BookkeepingError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("deleted non-contiguous ranges: {0:?}")]
+    NonContiguous(Vec<RangeInclusive<Version>>),
+}
+
+impl From<BookkeepingError> for rusqlite::Error {
+    fn from(e: BookkeepingError) -> Self {
+        match e {
+            BookkeepingError::Sqlite(e) => e,
+            e => rusqlite::Error::ToSqlConversionFailure(Box::new(e)),
+        }
+    }
+}
+
+/// Reads and writes of known versions. Every method gets the connection
+/// (usually within a transaction) the caller is working with, backends
+/// storing bookkeeping elsewhere are free to ignore it.
+pub trait BookkeepingStore: Send + Sync + 'static {
+    /// Every actor we know versions of
+    fn actor_ids(&self, conn: &Connection) -> Result<Vec<ActorId>, BookkeepingError>;
+
+    /// Known versions of an actor: ranges of cleared versions (`None`) or
+    /// single current versions
+    fn versions(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+    ) -> Result<Vec<(RangeInclusive<Version>, Option<CurrentVersion>)>, BookkeepingError>;
+
+    /// db versions of an actor's current versions
+    fn db_versions(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+    ) -> Result<BTreeSet<CrsqlDbVersion>, BookkeepingError>;
+
+    /// The current version of an actor written at a local db version
+    fn find_current(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+        db_version: CrsqlDbVersion,
+    ) -> Result<Option<(Version, CurrentVersion)>, BookkeepingError>;
+
+    fn insert_current(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+        version: Version,
+        current: &CurrentVersion,
+    ) -> Result<(), BookkeepingError>;
+
+    /// Mark versions as cleared, replacing current versions in the range and
+    /// merging with overlapping or adjacent cleared ranges. Returns the
+    /// number of ranges written.
+    fn insert_cleared(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+        versions: RangeInclusive<Version>,
+    ) -> Result<usize, BookkeepingError>;
+}
+
+/// Bookkeeping in the `__corro_bookkeeping` table, next to the data
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SqliteBookkeeping;
+
+impl BookkeepingStore for SqliteBookkeeping {
+    fn actor_ids(&self, conn: &Connection) -> Result<Vec<ActorId>, BookkeepingError> {
+        Ok(conn
+            .prepare_cached("SELECT DISTINCT actor_id FROM __corro_bookkeeping")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    fn versions(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+    ) -> Result<Vec<(RangeInclusive<Version>, Option<CurrentVersion>)>, BookkeepingError> {
+        Ok(conn
+            .prepare_cached(
+                "SELECT start_version, end_version, db_version, last_seq, ts FROM __corro_bookkeeping WHERE actor_id = ?",
+            )?
+            .query_map([actor_id], |row| {
+                let start_v = row.get(0)?;
+                let end_v: Option<Version> = row.get(1)?;
+                let current = match row.get(2)? {
+                    Some(db_version) => Some(CurrentVersion {
+                        db_version,
+                        last_seq: row.get(3)?,
+                        ts: row.get(4)?,
+                    }),
+                    None => None,
+                };
+                Ok((start_v..=end_v.unwrap_or(start_v), current))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    fn db_versions(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+    ) -> Result<BTreeSet<CrsqlDbVersion>, BookkeepingError> {
+        Ok(conn
+            .prepare_cached(
+                "SELECT DISTINCT db_version FROM __corro_bookkeeping WHERE actor_id = ? AND db_version IS NOT NULL",
+            )?
+            .query_map([actor_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn find_current(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+        db_version: CrsqlDbVersion,
+    ) -> Result<Option<(Version, CurrentVersion)>, BookkeepingError> {
+        Ok(conn
+            .prepare_cached(
+                "SELECT start_version, last_seq, ts FROM __corro_bookkeeping WHERE actor_id = ? AND db_version = ?",
+            )?
+            .query_row(params![actor_id, db_version], |row| {
+                Ok((
+                    row.get(0)?,
+                    CurrentVersion {
+                        db_version,
+                        last_seq: row.get(1)?,
+                        ts: row.get(2)?,
+                    },
+                ))
+            })
+            .optional()?)
+    }
+
+    fn insert_current(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+        version: Version,
+        current: &CurrentVersion,
+    ) -> Result<(), BookkeepingError> {
+        conn.prepare_cached(
+            "
+            INSERT INTO __corro_bookkeeping (actor_id, start_version, db_version, last_seq, ts)
+                VALUES (:actor_id, :start_version, :db_version, :last_seq, :ts);",
+        )?
+        .execute(named_params! {
+            ":actor_id": actor_id,
+            ":start_version": version,
+            ":db_version": current.db_version,
+            ":last_seq": current.last_seq,
+            ":ts": current.ts,
+        })?;
+        Ok(())
+    }
+
+    fn insert_cleared(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+        versions: RangeInclusive<Version>,
+    ) -> Result<usize, BookkeepingError> {
+        // first, delete "current" versions, they're now gone!
+        let deleted: Vec<RangeInclusive<Version>> = conn
+            .prepare_cached(
+                "
+            DELETE FROM __corro_bookkeeping
+                WHERE
+                    actor_id = :actor_id AND
+                    (
+                        -- start_version is between start and end of range AND no end_version
+                        ( start_version BETWEEN :start AND :end AND end_version IS NULL ) OR
+
+                        -- start_version and end_version are within the range
+                        ( start_version >= :start AND end_version <= :end ) OR
+
+                        -- range being inserted is partially contained within another
+                        ( start_version <= :end AND end_version >= :end ) OR
+
+                        -- start_version = end + 1 (to collapse ranges)
+                        ( start_version = :end + 1 AND end_version IS NOT NULL ) OR
+
+                        -- end_version = start - 1 (to collapse ranges)
+                        ( end_version = :start - 1 )
+                    )
+                RETURNING start_version, end_version",
+            )?
+            .query_map(
+                named_params![
+                    ":actor_id": actor_id,
+                    ":start": versions.start(),
+                    ":end": versions.end(),
+                ],
+                |row| {
+                    let start = row.get(0)?;
+                    Ok(start..=row.get::<_, Option<Version>>(1)?.unwrap_or(start))
+                },
+            )
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())?;
+
+        if !deleted.is_empty() {
+            debug!(
+                "deleted {} still-live versions from database's bookkeeping",
+                deleted.len()
+            );
+        }
+
+        // re-compute the ranges
+        let mut new_ranges = RangeInclusiveSet::from_iter(deleted);
+        new_ranges.insert(versions);
+
+        // we should never have deleted non-contiguous ranges, abort!
+        if new_ranges.len() > 1 {
+            warn!("deleted non-contiguous ranges! {new_ranges:?}");
+            // this serves as a failsafe
+            return Err(BookkeepingError::NonContiguous(
+                new_ranges.into_iter().collect(),
+            ));
+        }
+
+        let mut inserted = 0;
+
+        for range in new_ranges {
+            // insert cleared versions
+            inserted += conn
+                .prepare_cached(
+                    "
+                INSERT INTO __corro_bookkeeping (actor_id, start_version, end_version, db_version, last_seq, ts)
+                    VALUES (?, ?, ?, NULL, NULL, NULL);
+                ",
+                )?
+                .execute(params![actor_id, range.start(), range.end()])?;
+        }
+
+        Ok(inserted)
+    }
+}
+
+/// Bookkeeping kept in memory, for simulations and tests. It isn't tied to
+/// the SQLite transaction: rolled back changes stay booked.
+#[derive(Debug, Default)]
+pub struct MemoryBookkeeping {
+    actors: Mutex<BTreeMap<ActorId, MemoryBooked>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryBooked {
+    cleared: RangeInclusiveSet<Version>,
+    current: BTreeMap<Version, CurrentVersion>,
+}
+
+impl BookkeepingStore for MemoryBookkeeping {
+    fn actor_ids(&self, _conn: &Connection) -> Result<Vec<ActorId>, BookkeepingError> {
+        Ok(self.actors.lock().keys().copied().collect())
+    }
+
+    fn versions(
+        &self,
+        _conn: &Connection,
+        actor_id: ActorId,
+    ) -> Result<Vec<(RangeInclusive<Version>, Option<CurrentVersion>)>, BookkeepingError> {
+        let actors = self.actors.lock();
+        let Some(booked) = actors.get(&actor_id) else {
+            return Ok(vec![]);
+        };
+        Ok(booked
+            .cleared
+            .iter()
+            .map(|range| (range.clone(), None))
+            .chain(
+                booked
+                    .current
+                    .iter()
+                    .map(|(version, current)| (*version..=*version, Some(current.clone()))),
+            )
+            .collect())
+    }
+
+    fn db_versions(
+        &self,
+        _conn: &Connection,
+        actor_id: ActorId,
+    ) -> Result<BTreeSet<CrsqlDbVersion>, BookkeepingError> {
+        Ok(self
+            .actors
+            .lock()
+            .get(&actor_id)
+            .map(|booked| {
+                booked
+                    .current
+                    .values()
+                    .map(|current| current.db_version)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn find_current(
+        &self,
+        _conn: &Connection,
+        actor_id: ActorId,
+        db_version: CrsqlDbVersion,
+    ) -> Result<Option<(Version, CurrentVersion)>, BookkeepingError> {
+        Ok(self.actors.lock().get(&actor_id).and_then(|booked| {
+            booked
+                .current
+                .iter()
+                .find(|(_, current)| current.db_version == db_version)
+                .map(|(version, current)| (*version, current.clone()))
+        }))
+    }
+
+    fn insert_current(
+        &self,
+        _conn: &Connection,
+        actor_id: ActorId,
+        version: Version,
+        current: &CurrentVersion,
+    ) -> Result<(), BookkeepingError> {
+        self.actors
+            .lock()
+            .entry(actor_id)
+            .or_default()
+            .current
+            .insert(version, current.clone());
+        Ok(())
+    }
+
+    fn insert_cleared(
+        &self,
+        _conn: &Connection,
+        actor_id: ActorId,
+        versions: RangeInclusive<Version>,
+    ) -> Result<usize, BookkeepingError> {
+        let mut actors = self.actors.lock();
+        let booked = actors.entry(actor_id).or_default();
+        booked
+            .current
+            .retain(|version, _| !versions.contains(version));
+        booked.cleared.insert(versions);
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{base::CrsqlSeq, broadcast::Timestamp};
+
+    use super::*;
+
+    fn check_store(
+        store: &dyn BookkeepingStore,
+        conn: &Connection,
+    ) -> Result<(), BookkeepingError> {
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let current = |db_version| CurrentVersion {
+            db_version: CrsqlDbVersion(db_version),
+            last_seq: CrsqlSeq(0),
+            ts: Timestamp::default(),
+        };
+
+        for v in 1..=5 {
+            store.insert_current(conn, actor_id, Version(v), &current(v))?;
+        }
+        assert_eq!(store.actor_ids(conn)?, vec![actor_id]);
+
+        store.insert_cleared(conn, actor_id, Version(2)..=Version(3))?;
+        // adjacent, gets merged
+        store.insert_cleared(conn, actor_id, Version(4)..=Version(4))?;
+
+        let mut versions = store.versions(conn, actor_id)?;
+        versions.sort_by_key(|(range, _)| *range.start());
+        assert_eq!(
+            versions,
+            vec![
+                (Version(1)..=Version(1), Some(current(1))),
+                (Version(2)..=Version(4), None),
+                (Version(5)..=Version(5), Some(current(5))),
+            ]
+        );
+
+        assert_eq!(
+            store.db_versions(conn, actor_id)?,
+            BTreeSet::from([CrsqlDbVersion(1), CrsqlDbVersion(5)])
+        );
+
+        assert_eq!(
+            store.find_current(conn, actor_id, CrsqlDbVersion(5))?,
+            Some((Version(5), current(5)))
+        );
+        assert_eq!(store.find_current(conn, actor_id, CrsqlDbVersion(3))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bookkeeping_stores() -> Result<(), BookkeepingError> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE __corro_bookkeeping (
+                actor_id BLOB NOT NULL,
+                start_version INTEGER NOT NULL,
+                end_version INTEGER,
+                db_version INTEGER,
+                last_seq INTEGER,
+                ts TEXT,
+                PRIMARY KEY (actor_id, start_version)
+            ) WITHOUT ROWID;",
+        )?;
+
+        check_store(&SqliteBookkeeping, &conn)?;
+        check_store(&MemoryBookkeeping::default(), &conn)?;
+
+        Ok(())
+    }
+}
@@ -14,7 +14,7 @@ use rusqlite::{
     ToSql,
 };
 use serde::{Deserialize, Serialize};
-use speedy::{Context, Readable, Reader, Writable, Writer};
+use speedy::{Context, Endianness, Readable, Reader, Writable, Writer};
 use time::OffsetDateTime;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{error, trace};
@@ -24,7 +24,7 @@ use crate::{
     actor::{Actor, ActorId, ClusterId},
     base::{CrsqlDbVersion, CrsqlSeq, Version},
     channel::CorroSender,
-    sync::SyncTraceContextV1,
+    sync::{SyncCompressionV1, SyncTraceContextV1},
 };
 
 #[derive(Debug, Clone, Readable, Writable)]
@@ -34,11 +34,49 @@ pub enum UniPayload {
         #[speedy(default_on_eof)]
         cluster_id: ClusterId,
     },
+    /// Forward compatible V1, see [`Tagged`]
+    V2 {
+        data: Tagged<UniPayloadV1>,
+        cluster_id: ClusterId,
+    },
+}
+
+impl UniPayload {
+    /// Wraps a message, as V2 when `tagged` and V1 otherwise for peers that
+    /// don't read V2 yet
+    pub fn new(data: UniPayloadV1, cluster_id: ClusterId, tagged: bool) -> Self {
+        if tagged {
+            UniPayload::V2 {
+                data: Tagged::Known(data),
+                cluster_id,
+            }
+        } else {
+            UniPayload::V1 { data, cluster_id }
+        }
+    }
+
+    /// The message, `None` when it's of a type only newer versions know
+    pub fn into_data(self) -> (Option<UniPayloadV1>, ClusterId) {
+        match self {
+            UniPayload::V1 { data, cluster_id } => (Some(data), cluster_id),
+            UniPayload::V2 { data, cluster_id } => (data.known(), cluster_id),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Readable, Writable)]
 pub enum UniPayloadV1 {
     Broadcast(BroadcastV1),
+    /// Broadcast forwarded from another network segment by relays
+    Relayed {
+        bcast: BroadcastV1,
+        /// Relays the broadcast went through, in order
+        relayed_by: Vec<ActorId>,
+    },
+}
+
+impl TaggedMessage for UniPayloadV1 {
+    const KNOWN_TAGS: u32 = 2;
 }
 
 #[derive(Debug, Clone, Readable, Writable)]
@@ -48,6 +86,34 @@ pub enum BiPayload {
         #[speedy(default_on_eof)]
         cluster_id: ClusterId,
     },
+    /// Forward compatible V1, see [`Tagged`]
+    V2 {
+        data: Tagged<BiPayloadV1>,
+        cluster_id: ClusterId,
+    },
+}
+
+impl BiPayload {
+    /// Wraps a message, as V2 when `tagged` and V1 otherwise for peers that
+    /// don't read V2 yet
+    pub fn new(data: BiPayloadV1, cluster_id: ClusterId, tagged: bool) -> Self {
+        if tagged {
+            BiPayload::V2 {
+                data: Tagged::Known(data),
+                cluster_id,
+            }
+        } else {
+            BiPayload::V1 { data, cluster_id }
+        }
+    }
+
+    /// The message, `None` when it's of a type only newer versions know
+    pub fn into_data(self) -> (Option<BiPayloadV1>, ClusterId) {
+        match self {
+            BiPayload::V1 { data, cluster_id } => (Some(data), cluster_id),
+            BiPayload::V2 { data, cluster_id } => (data.known(), cluster_id),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Readable, Writable)]
@@ -56,9 +122,108 @@ pub enum BiPayloadV1 {
         actor_id: ActorId,
         #[speedy(default_on_eof)]
         trace_ctx: SyncTraceContextV1,
+        /// Codec the client wants changesets compressed with, servers that
+        /// don't know about it send them uncompressed
+        #[speedy(default_on_eof)]
+        compression: Option<SyncCompressionV1>,
+        /// Kinds of `SyncRejectionV1` the client can read, servers end the
+        /// stream without a reason rather than sending it newer ones
+        #[speedy(default_on_eof)]
+        rejections: Option<u32>,
+    },
+}
+
+impl TaggedMessage for BiPayloadV1 {
+    const KNOWN_TAGS: u32 = 1;
+}
+
+/// Enum whose variants are read and written by speedy's derive, with the
+/// default `u32` tag. Variants are only ever appended.
+pub trait TaggedMessage {
+    /// Number of variants this version knows about
+    const KNOWN_TAGS: u32;
+}
+
+/// Message encoded with its tag and the length of its fields, so that
+/// readers skip the types of message they don't know instead of failing, and
+/// ignore fields appended to the end of the ones they know. Appended fields
+/// need `#[speedy(default_on_eof)]` for older messages to still be read.
+///
+/// Only the outermost enum is tagged: a new kind of changeset goes out as a
+/// new type of message, older nodes wouldn't skip it otherwise.
+#[derive(Debug, Clone)]
+pub enum Tagged<T> {
+    Known(T),
+    /// Message of a newer version, skipped
+    Unknown {
+        tag: u32,
     },
 }
 
+impl<T> Tagged<T> {
+    pub fn known(self) -> Option<T> {
+        match self {
+            Tagged::Known(msg) => Some(msg),
+            Tagged::Unknown { .. } => None,
+        }
+    }
+}
+
+impl<'a, C, T> Readable<'a, C> for Tagged<T>
+where
+    C: Context,
+    T: TaggedMessage + for<'b> Readable<'b, Endianness>,
+{
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
+        let endianness = reader.context().endianness();
+        let tag = reader.read_u32()?;
+        let len = reader.read_u32()? as usize;
+        if reader.can_read_at_least(len) == Some(false) {
+            return Err(speedy::Error::custom("tagged message is truncated").into());
+        }
+        let mut buf = tag.write_to_vec_with_ctx(endianness)?;
+        let start = buf.len();
+        buf.resize(start + len, 0);
+        reader.read_bytes(&mut buf[start..])?;
+
+        if tag >= T::KNOWN_TAGS {
+            return Ok(Tagged::Unknown { tag });
+        }
+
+        // trailing bytes are fields of a newer version
+        let (res, _) = T::read_with_length_from_buffer_with_ctx(endianness, &buf);
+        Ok(Tagged::Known(res?))
+    }
+
+    #[inline]
+    fn minimum_bytes_needed() -> usize {
+        2 * std::mem::size_of::<u32>()
+    }
+}
+
+impl<C, T> Writable<C> for Tagged<T>
+where
+    C: Context,
+    T: TaggedMessage + Writable<Endianness>,
+{
+    fn write_to<W: ?Sized + Writer<C>>(&self, writer: &mut W) -> Result<(), C::Error> {
+        match self {
+            Tagged::Known(msg) => {
+                let buf = msg.write_to_vec_with_ctx(writer.context().endianness())?;
+                // speedy writes the tag of the variant first
+                let (tag, fields) = buf.split_at(std::mem::size_of::<u32>());
+                writer.write_bytes(tag)?;
+                writer.write_u32(fields.len() as u32)?;
+                writer.write_bytes(fields)
+            }
+            Tagged::Unknown { tag } => Err(speedy::Error::custom(format!(
+                "can't write message of unknown type {tag}"
+            ))
+            .into()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum FocaInput {
     Announce(Actor),
@@ -85,15 +250,20 @@ pub enum BroadcastV1 {
     Change(ChangeV1),
 }
 
-#[derive(Debug, Clone, Copy, strum::IntoStaticStr)]
+#[derive(Debug, Clone, strum::IntoStaticStr)]
 #[strum(serialize_all = "snake_case")]
 pub enum ChangeSource {
     Broadcast,
     Sync,
+    /// Broadcast from another network segment, with the relays it went through
+    Relay(Vec<ActorId>),
+    /// Sent to the API by an external producer, booked under a synthetic
+    /// actor. Nobody else has them yet: they're broadcast, and never shed.
+    Ingest,
 }
 
 // TODO: shrink this by mapping primary keys to integers instead of repeating them
-#[derive(Debug, Clone, PartialEq, Readable, Writable)]
+#[derive(Debug, Clone, PartialEq, Readable, Writable, Serialize, Deserialize)]
 pub struct ChangeV1 {
     pub actor_id: ActorId,
     pub changeset: Changeset,
@@ -107,7 +277,8 @@ impl Deref for ChangeV1 {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Readable, Writable)]
+#[derive(Debug, Clone, PartialEq, Readable, Writable, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Changeset {
     Empty {
         versions: RangeInclusive<Version>,
@@ -206,6 +377,25 @@ impl Changeset {
         }
     }
 
+    /// Rough size of the changeset in memory, mostly made of its changes
+    pub fn estimated_byte_size(&self) -> usize {
+        self.changes()
+            .iter()
+            .map(Change::estimated_byte_size)
+            .sum::<usize>()
+            + 64
+    }
+
+    /// Whether any change in this changeset belongs to one of `tables`
+    pub fn touches_any(&self, tables: &[String]) -> bool {
+        !tables.is_empty()
+            && self.changes().iter().any(|change| {
+                tables
+                    .iter()
+                    .any(|table| table.as_str() == change.table.0.as_str())
+            })
+    }
+
     pub fn into_parts(self) -> Option<ChangesetParts> {
         match self {
             Changeset::Empty { .. } => None,
@@ -354,6 +544,8 @@ pub enum BroadcastDecodeError {
 pub enum BroadcastInput {
     Rebroadcast(BroadcastV1),
     AddBroadcast(BroadcastV1),
+    /// Forward to the relays of other network segments
+    Relay(BroadcastV1, Vec<ActorId>),
 }
 
 pub struct DispatchRuntime<T> {
@@ -417,3 +609,66 @@ impl<T> DispatchRuntime<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Readable, Writable)]
+    enum MessageV1 {
+        Ping { id: u64 },
+    }
+
+    impl TaggedMessage for MessageV1 {
+        const KNOWN_TAGS: u32 = 1;
+    }
+
+    #[derive(Debug, Clone, PartialEq, Readable, Writable)]
+    enum MessageV2 {
+        Ping {
+            id: u64,
+            #[speedy(default_on_eof)]
+            name: String,
+        },
+        Pong {
+            id: u64,
+        },
+    }
+
+    impl TaggedMessage for MessageV2 {
+        const KNOWN_TAGS: u32 = 2;
+    }
+
+    #[test]
+    fn test_tagged_compat() {
+        let roundtrip = |msg: MessageV2| {
+            let buf = (Tagged::Known(msg), 42u64).write_to_vec().unwrap();
+            <(Tagged<MessageV1>, u64)>::read_from_buffer(&buf).unwrap()
+        };
+
+        // appended fields are skipped
+        let (msg, after) = roundtrip(MessageV2::Ping {
+            id: 1,
+            name: "a".into(),
+        });
+        assert_eq!(msg.known(), Some(MessageV1::Ping { id: 1 }));
+        assert_eq!(after, 42);
+
+        // so are unknown messages, without corrupting what follows
+        let (msg, after) = roundtrip(MessageV2::Pong { id: 2 });
+        assert!(matches!(msg, Tagged::Unknown { tag: 1 }));
+        assert_eq!(after, 42);
+
+        // older messages default missing fields
+        let buf = Tagged::Known(MessageV1::Ping { id: 3 })
+            .write_to_vec()
+            .unwrap();
+        assert_eq!(
+            Tagged::<MessageV2>::read_from_buffer(&buf).unwrap().known(),
+            Some(MessageV2::Ping {
+                id: 3,
+                name: String::new()
+            })
+        );
+    }
+}
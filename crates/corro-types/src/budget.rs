@@ -0,0 +1,171 @@
+//! Bytes held by in-memory buffers which are bounded by their number of
+//! items, not by their size. With large rows, a buffer that looks small can
+//! hold a lot of memory, so each kind of buffer can be capped: once a cap is
+//! reached, new items which can be recovered some other way are shed instead
+//! of being buffered.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use metrics::{counter, gauge};
+
+use crate::config::PerfConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferKind {
+    /// Serialized broadcasts, until they've been transmitted enough times
+    Broadcast,
+    /// Changes received from broadcasts, waiting to be applied
+    Apply,
+    /// Subscription events, waiting to be sent to a subscriber
+    Subscriptions,
+}
+
+impl BufferKind {
+    pub const ALL: [BufferKind; 3] = [
+        BufferKind::Broadcast,
+        BufferKind::Apply,
+        BufferKind::Subscriptions,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BufferKind::Broadcast => "broadcast",
+            BufferKind::Apply => "apply",
+            BufferKind::Subscriptions => "subscriptions",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buffered {
+    bytes: AtomicUsize,
+    max_bytes: Option<usize>,
+}
+
+impl Buffered {
+    fn new(max_bytes: Option<usize>) -> Self {
+        Self {
+            bytes: AtomicUsize::new(0),
+            max_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryBudget(Arc<[Buffered; 3]>);
+
+impl MemoryBudget {
+    pub fn new(perf: &PerfConfig) -> Self {
+        Self(Arc::new([
+            Buffered::new(perf.bcast_buffer_max_bytes),
+            Buffered::new(perf.apply_buffer_max_bytes),
+            Buffered::new(perf.subs_buffer_max_bytes),
+        ]))
+    }
+
+    fn buffered(&self, kind: BufferKind) -> &Buffered {
+        match kind {
+            BufferKind::Broadcast => &self.0[0],
+            BufferKind::Apply => &self.0[1],
+            BufferKind::Subscriptions => &self.0[2],
+        }
+    }
+
+    /// Accounts for `bytes` if they fit under the cap, returns `false` if
+    /// they should be shed instead. An item is always accepted when nothing
+    /// is buffered, so an item larger than the cap can't get stuck.
+    pub fn try_reserve(&self, kind: BufferKind, bytes: usize) -> bool {
+        let buffered = self.buffered(kind);
+        let prev = buffered.bytes.fetch_add(bytes, Ordering::Relaxed);
+
+        if let Some(max_bytes) = buffered.max_bytes {
+            if prev > 0 && prev + bytes > max_bytes {
+                buffered.bytes.fetch_sub(bytes, Ordering::Relaxed);
+                counter!("corro.memory.shed.bytes", "kind" => kind.as_str())
+                    .increment(bytes as u64);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Accounts for `bytes` which can't be shed, even past the cap
+    pub fn reserve(&self, kind: BufferKind, bytes: usize) {
+        self.buffered(kind)
+            .bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, kind: BufferKind, bytes: usize) {
+        // never underflows, even if an item was released twice
+        _ = self
+            .buffered(kind)
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |prev| {
+                Some(prev.saturating_sub(bytes))
+            });
+    }
+
+    pub fn bytes(&self, kind: BufferKind) -> usize {
+        self.buffered(kind).bytes.load(Ordering::Relaxed)
+    }
+
+    /// Whether the cap for this kind of buffer has been reached
+    pub fn is_exhausted(&self, kind: BufferKind) -> bool {
+        let buffered = self.buffered(kind);
+        buffered
+            .max_bytes
+            .map_or(false, |max_bytes| self.bytes(kind) >= max_bytes)
+    }
+
+    pub fn emit_metrics(&self) {
+        for kind in BufferKind::ALL {
+            gauge!("corro.memory.buffered.bytes", "kind" => kind.as_str())
+                .set(self.bytes(kind) as f64);
+            if let Some(max_bytes) = self.buffered(kind).max_bytes {
+                gauge!("corro.memory.buffered.max_bytes", "kind" => kind.as_str())
+                    .set(max_bytes as f64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(&PerfConfig {
+            apply_buffer_max_bytes: Some(100),
+            ..Default::default()
+        });
+
+        // larger than the cap, but nothing is buffered yet
+        assert!(budget.try_reserve(BufferKind::Apply, 150));
+        assert!(budget.is_exhausted(BufferKind::Apply));
+        assert!(!budget.try_reserve(BufferKind::Apply, 1));
+        assert_eq!(budget.bytes(BufferKind::Apply), 150);
+
+        budget.release(BufferKind::Apply, 150);
+        assert!(budget.try_reserve(BufferKind::Apply, 60));
+        assert!(!budget.try_reserve(BufferKind::Apply, 60));
+        assert!(budget.try_reserve(BufferKind::Apply, 40));
+
+        // not shed, even over the cap
+        budget.reserve(BufferKind::Apply, 10);
+        assert_eq!(budget.bytes(BufferKind::Apply), 110);
+
+        budget.release(BufferKind::Apply, 1000);
+        assert_eq!(budget.bytes(BufferKind::Apply), 0);
+
+        // no cap
+        assert!(budget.try_reserve(BufferKind::Broadcast, usize::MAX / 2));
+        assert!(budget.try_reserve(BufferKind::Broadcast, 1));
+        assert!(!budget.is_exhausted(BufferKind::Broadcast));
+    }
+}
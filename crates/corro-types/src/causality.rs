@@ -0,0 +1,195 @@
+//! Replication metadata of rows, read from cr-sqlite's clock tables: which
+//! actor last wrote each column of a row, at which version and when.
+
+use compact_str::ToCompactString;
+use corro_api_types::{CellMeta, ColumnName, SqliteValue, TableName};
+use rusqlite::{params_from_iter, Connection};
+use sqlite3_parser::{
+    ast::{As, Cmd, Expr, Name, OneSelect, ResultColumn, SelectTable, Stmt},
+    lexer::sql::Parser,
+};
+
+use crate::{
+    actor::ActorId,
+    base::CrsqlDbVersion,
+    bookkeeping::{BookkeepingError, BookkeepingStore},
+    schema::{Schema, Table},
+};
+
+/// cr-sqlite's clock entry tracking the row's existence, not a column
+const SENTINEL_COL: &str = "-1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum RowMetaError {
+    #[error(transparent)]
+    Lexer(#[from] sqlite3_parser::lexer::sql::Error),
+    #[error("row metadata requires a single SELECT without DISTINCT, GROUP BY or compound")]
+    UnsupportedStatement,
+    #[error(transparent)]
+    Bookkeeping(#[from] BookkeepingError),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Primary key columns appended to a query's own columns
+#[derive(Debug, Clone)]
+pub struct PkColumns {
+    /// Tables in the order their primary keys were appended
+    pub tables: Vec<String>,
+    pub count: usize,
+}
+
+/// Appends the primary keys of every table selected from to the query's
+/// columns, so every row can be traced back to the rows it came from.
+pub fn with_pk_columns(sql: &str, schema: &Schema) -> Result<(String, PkColumns), RowMetaError> {
+    let mut parser = Parser::new(sql.as_bytes());
+    let mut stmt = match parser.next()? {
+        Some(Cmd::Stmt(stmt @ Stmt::Select(_))) => stmt,
+        _ => return Err(RowMetaError::UnsupportedStatement),
+    };
+
+    let mut pk_cols = PkColumns {
+        tables: vec![],
+        count: 0,
+    };
+
+    let Stmt::Select(select) = &mut stmt else {
+        unreachable!()
+    };
+    if select.body.compounds.is_some() {
+        return Err(RowMetaError::UnsupportedStatement);
+    }
+    let OneSelect::Select {
+        columns,
+        from: Some(from),
+        distinctness: None,
+        group_by: None,
+        ..
+    } = &mut select.body.select
+    else {
+        return Err(RowMetaError::UnsupportedStatement);
+    };
+
+    let tables = from
+        .select
+        .iter()
+        .map(|table| table.as_ref())
+        .chain(from.joins.iter().flatten().map(|join| &join.table));
+
+    for table in tables {
+        let SelectTable::Table(name, alias, _) = table else {
+            continue;
+        };
+        let Some(schema_table) = schema.tables.get(name.name.0.as_str()) else {
+            continue;
+        };
+        let qualifier = match alias {
+            Some(As::As(alias) | As::Elided(alias)) => alias.0.clone(),
+            None => name.name.0.clone(),
+        };
+        for pk in schema_table.pk.iter() {
+            columns.push(ResultColumn::Expr(
+                Expr::Qualified(Name(qualifier.clone()), Name(pk.clone())),
+                None,
+            ));
+        }
+        pk_cols.tables.push(schema_table.name.clone());
+        pk_cols.count += schema_table.pk.len();
+    }
+
+    let mut sql = Cmd::Stmt(stmt).to_string();
+    sql.pop(); // remove trailing `;`
+
+    Ok((sql, pk_cols))
+}
+
+/// Metadata of the last write to every column of a row
+pub fn row_meta(
+    conn: &Connection,
+    store: &dyn BookkeepingStore,
+    table: &Table,
+    pks: &[SqliteValue],
+) -> Result<Vec<CellMeta>, RowMetaError> {
+    let where_pks = table
+        .pk
+        .iter()
+        .map(|pk| format!("p.\"{pk}\" = ?"))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+
+    let clocks = conn
+        .prepare_cached(&format!(
+            "SELECT c.col_name, c.col_version, c.db_version, COALESCE(s.site_id, crsql_site_id())
+                FROM \"{tbl}__crsql_clock\" AS c
+                INNER JOIN \"{tbl}__crsql_pks\" AS p ON p.__crsql_key = c.key
+                LEFT JOIN crsql_site_id AS s ON s.ordinal = c.site_id
+                WHERE {where_pks}",
+            tbl = table.name
+        ))?
+        .query_map(
+            params_from_iter(pks.iter().map(SqliteValue::as_ref)),
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, CrsqlDbVersion>(2)?,
+                    row.get::<_, ActorId>(3)?,
+                ))
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    clocks
+        .into_iter()
+        .filter(|(col_name, ..)| col_name != SENTINEL_COL)
+        .map(|(col_name, col_version, db_version, actor_id)| {
+            let current = store.find_current(conn, actor_id, db_version)?;
+            Ok(CellMeta {
+                table: TableName(table.name.to_compact_string()),
+                column: ColumnName(col_name.into()),
+                actor_id: actor_id.to_compact_string(),
+                version: current.as_ref().map(|(version, _)| *version),
+                db_version,
+                col_version,
+                ts: current.map(|(_, current)| current.ts.to_compact_string()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::parse_sql;
+
+    use super::*;
+
+    #[test]
+    fn test_with_pk_columns() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let schema = parse_sql(
+            "CREATE TABLE users (id INTEGER NOT NULL PRIMARY KEY, name TEXT);
+            CREATE TABLE posts (user_id INTEGER NOT NULL, slug TEXT NOT NULL, title TEXT, PRIMARY KEY (user_id, slug));",
+        )?;
+
+        let (sql, pk_cols) = with_pk_columns(
+            "SELECT u.name, title FROM users u JOIN posts ON posts.user_id = u.id",
+            &schema,
+        )?;
+        assert_eq!(pk_cols.tables, vec!["users", "posts"]);
+        assert_eq!(pk_cols.count, 3);
+        assert!(
+            sql.contains("u.id") && sql.contains("posts.user_id") && sql.contains("posts.slug"),
+            "unexpected query: {sql}"
+        );
+
+        assert!(matches!(
+            with_pk_columns("SELECT DISTINCT name FROM users", &schema),
+            Err(RowMetaError::UnsupportedStatement)
+        ));
+        assert!(matches!(
+            with_pk_columns("DELETE FROM users", &schema),
+            Err(RowMetaError::UnsupportedStatement)
+        ));
+
+        Ok(())
+    }
+}
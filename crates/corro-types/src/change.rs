@@ -115,10 +115,83 @@ where
 
 pub const MAX_CHANGES_BYTE_SIZE: usize = 8 * 1024;
 
+/// Splits a chunk of changes around the ones whose value is larger than
+/// `max_value_bytes`. Those are left out, receivers see the gaps in the
+/// sequences and request the missing ones as partial versions when syncing,
+/// from any peer that has them, so broadcasts stay small. Nothing else
+/// announces the withheld values: they're sent whole, by the regular sync.
+pub fn without_large_values(
+    changes: Vec<Change>,
+    seqs: RangeInclusive<CrsqlSeq>,
+    max_value_bytes: usize,
+) -> Vec<(Vec<Change>, RangeInclusive<CrsqlSeq>)> {
+    if changes
+        .iter()
+        .all(|change| change.val.estimated_byte_size() <= max_value_bytes)
+    {
+        return vec![(changes, seqs)];
+    }
+
+    let mut chunks = vec![];
+    let mut start_seq = *seqs.start();
+    let mut chunk = vec![];
+    for change in changes {
+        if change.val.estimated_byte_size() <= max_value_bytes {
+            chunk.push(change);
+            continue;
+        }
+        if !chunk.is_empty() {
+            chunks.push((std::mem::take(&mut chunk), start_seq..=change.seq - 1));
+        }
+        start_seq = change.seq + 1;
+    }
+    if !chunk.is_empty() {
+        chunks.push((chunk, start_seq..=*seqs.end()));
+    }
+
+    chunks
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_without_large_values() {
+        let changes: Vec<Change> = (0..5)
+            .map(|seq| Change {
+                seq: CrsqlSeq(seq),
+                val: if seq == 1 || seq == 4 {
+                    SqliteValue::from(vec![0; 100])
+                } else {
+                    SqliteValue::Integer(seq as i64)
+                },
+                ..Default::default()
+            })
+            .collect();
+
+        assert_eq!(
+            without_large_values(changes.clone(), CrsqlSeq(0)..=CrsqlSeq(4), 1000),
+            vec![(changes.clone(), CrsqlSeq(0)..=CrsqlSeq(4))]
+        );
+
+        assert_eq!(
+            without_large_values(changes.clone(), CrsqlSeq(0)..=CrsqlSeq(4), 50),
+            vec![
+                (vec![changes[0].clone()], CrsqlSeq(0)..=CrsqlSeq(0)),
+                (
+                    vec![changes[2].clone(), changes[3].clone()],
+                    CrsqlSeq(2)..=CrsqlSeq(3)
+                ),
+            ]
+        );
+
+        assert_eq!(
+            without_large_values(vec![changes[1].clone()], CrsqlSeq(1)..=CrsqlSeq(1), 50),
+            vec![]
+        );
+    }
+
     #[test]
     fn test_change_chunker() {
         // empty interator
@@ -124,6 +124,15 @@ impl<T> CorroSender<T> {
             })
     }
 
+    /// Number of messages that can be sent without waiting
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    pub fn max_capacity(&self) -> usize {
+        self.inner.max_capacity()
+    }
+
     pub fn blocking_send(&self, value: T) -> Result<(), SendError<T>> {
         let before = Instant::now();
         self.inner
@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use uhlc::{Timestamp, ID, NTP64};
+
+/// Source of hybrid logical clock timestamps for an agent.
+///
+/// The default implementation is [`uhlc::HLC`], tests and simulations
+/// can swap it for a [`ManualClock`] to control timestamp generation.
+pub trait Clock: Send + Sync + 'static {
+    fn new_timestamp(&self) -> Timestamp;
+    fn update_with_timestamp(&self, timestamp: &Timestamp) -> Result<(), String>;
+}
+
+impl Clock for uhlc::HLC {
+    fn new_timestamp(&self) -> Timestamp {
+        uhlc::HLC::new_timestamp(self)
+    }
+
+    fn update_with_timestamp(&self, timestamp: &Timestamp) -> Result<(), String> {
+        uhlc::HLC::update_with_timestamp(self, timestamp)
+    }
+}
+
+/// A deterministic clock whose physical time only moves when told to.
+///
+/// It follows the same rules as an HLC: timestamps are strictly
+/// increasing, even when the physical time doesn't move.
+pub struct ManualClock {
+    id: ID,
+    inner: Mutex<ManualClockInner>,
+}
+
+struct ManualClockInner {
+    now: NTP64,
+    last: NTP64,
+}
+
+impl ManualClock {
+    pub fn new(id: ID, now: NTP64) -> Self {
+        Self {
+            id,
+            inner: Mutex::new(ManualClockInner {
+                now,
+                last: NTP64(0),
+            }),
+        }
+    }
+
+    /// Current physical time
+    pub fn now(&self) -> NTP64 {
+        self.inner.lock().now
+    }
+
+    /// Set the physical time, it may go backwards
+    pub fn set(&self, now: NTP64) {
+        self.inner.lock().now = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut inner = self.inner.lock();
+        inner.now = NTP64(inner.now.0 + NTP64::from(by).0);
+    }
+}
+
+impl Clock for ManualClock {
+    fn new_timestamp(&self) -> Timestamp {
+        let mut inner = self.inner.lock();
+        inner.last = if inner.now > inner.last {
+            inner.now
+        } else {
+            NTP64(inner.last.0 + 1)
+        };
+        Timestamp::new(inner.last, self.id)
+    }
+
+    fn update_with_timestamp(&self, timestamp: &Timestamp) -> Result<(), String> {
+        let mut inner = self.inner.lock();
+        if *timestamp.get_time() > inner.last {
+            inner.last = *timestamp.get_time();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actor::ActorId;
+
+    #[test]
+    fn manual_clock_is_monotonic() {
+        let id: ID = ActorId(uuid::Uuid::new_v4()).try_into().unwrap();
+        let clock = ManualClock::new(id, NTP64(100));
+
+        let ts1 = clock.new_timestamp();
+        assert_eq!(*ts1.get_time(), NTP64(100));
+
+        // physical time didn't move, logical part increments
+        let ts2 = clock.new_timestamp();
+        assert_eq!(*ts2.get_time(), NTP64(101));
+
+        // physical time going backwards doesn't break ordering
+        clock.set(NTP64(50));
+        let ts3 = clock.new_timestamp();
+        assert!(ts3 > ts2);
+
+        clock
+            .update_with_timestamp(&Timestamp::new(NTP64(1000), *ts3.get_id()))
+            .unwrap();
+        assert_eq!(*clock.new_timestamp().get_time(), NTP64(1001));
+
+        clock.set(NTP64(5000));
+        assert_eq!(*clock.new_timestamp().get_time(), NTP64(5000));
+    }
+}
@@ -1,8 +1,16 @@
-use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    time::Duration,
+};
 
 use camino::Utf8PathBuf;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
+use crate::{actor::ActorId, sync::SyncCompressionV1};
+
 pub const DEFAULT_GOSSIP_PORT: u16 = 4001;
 const DEFAULT_GOSSIP_IDLE_TIMEOUT: u32 = 30;
 
@@ -28,10 +36,22 @@ const fn default_small_channel() -> usize {
     256
 }
 
+const fn default_replay_window() -> usize {
+    100_000
+}
+
 const fn default_apply_timeout() -> usize {
     50
 }
 
+const fn default_causal_window() -> u64 {
+    100
+}
+
+const fn default_analyze_interval() -> u64 {
+    3600
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub db: DbConfig,
@@ -51,13 +71,122 @@ pub struct Config {
     pub log: LogConfig,
     #[serde(default)]
     pub consul: Option<ConsulConfig>,
+    #[serde(default)]
+    pub haproxy: Option<HaproxyConfig>,
+    #[serde(default)]
+    pub bridge: Option<BridgeConfig>,
+    #[serde(default)]
+    pub gaps: GapsConfig,
+    #[serde(default)]
+    pub subscriptions: SubscriptionsConfig,
+}
+
+/// Limits keeping abandoned subscriptions from piling up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionsConfig {
+    /// Maximum number of subscriptions running at once on this node
+    #[serde(default)]
+    pub max_count: Option<usize>,
+    /// Subscriptions are ended this long after being created or restored
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+    /// Subscriptions without any listener are ended after this long
+    #[serde(default = "default_subscriptions_idle_timeout")]
+    pub idle_timeout_secs: u64,
+    /// Queries whose events are written to local unix sockets or FIFOs
+    #[serde(default)]
+    pub fanout: Vec<FanoutConfig>,
+}
+
+impl Default for SubscriptionsConfig {
+    fn default() -> Self {
+        Self {
+            max_count: None,
+            max_lifetime_secs: None,
+            idle_timeout_secs: default_subscriptions_idle_timeout(),
+            fanout: vec![],
+        }
+    }
+}
+
+/// Writes a query's events, one line each, to every consumer of a local
+/// unix socket or FIFO
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FanoutConfig {
+    pub sql: String,
+    pub path: Utf8PathBuf,
+    #[serde(default)]
+    pub kind: FanoutKind,
+    /// Permissions of the unix socket, left to the umask if unset
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FanoutKind {
+    /// The agent listens on a unix socket, every connection gets the events
+    #[default]
+    Socket,
+    /// The agent writes to an existing FIFO whenever it has a reader
+    Fifo,
+}
+
+const fn default_subscriptions_idle_timeout() -> u64 {
+    120
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GapsConfig {
+    /// Alert when versions from an actor are missing for longer than this
+    #[serde(default = "default_gaps_alert_after")]
+    pub alert_after_secs: u64,
+    #[serde(default = "default_gaps_check_interval")]
+    pub check_interval_secs: u64,
+    /// HTTP endpoint receiving a JSON POST for every alert
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+}
+
+impl Default for GapsConfig {
+    fn default() -> Self {
+        Self {
+            alert_after_secs: default_gaps_alert_after(),
+            check_interval_secs: default_gaps_check_interval(),
+            webhook_url: None,
+        }
+    }
+}
+
+const fn default_gaps_alert_after() -> u64 {
+    600
+}
+
+const fn default_gaps_check_interval() -> u64 {
+    30
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct TelemetryConfig {
     pub prometheus: Option<PrometheusConfig>,
+    pub statsd: Option<StatsdConfig>,
     pub open_telemetry: Option<OtelConfig>,
+    /// Per-minute history of writes and replication lag, kept in a local
+    /// table
+    pub history: Option<HistoryConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HistoryConfig {
+    /// Minutes of history kept
+    #[serde(default = "default_history_retention")]
+    pub retention_mins: u64,
+}
+
+const fn default_history_retention() -> u64 {
+    7 * 24 * 60
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,6 +195,25 @@ pub struct PrometheusConfig {
     pub bind_addr: SocketAddr,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StatsdConfig {
+    /// Address of the statsd (or dogstatsd) agent receiving UDP datagrams
+    pub addr: SocketAddr,
+    /// Prepended to every metric name
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Tags added to every metric, next to their labels
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default = "default_statsd_flush_interval")]
+    pub flush_interval_ms: u64,
+}
+
+const fn default_statsd_flush_interval() -> u64 {
+    10_000
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum OtelConfig {
@@ -92,10 +240,23 @@ pub struct DbConfig {
     pub path: Utf8PathBuf,
     #[serde(default)]
     pub schema_paths: Vec<Utf8PathBuf>,
+    /// Apply the schema again when files under `schema_paths` change
+    #[serde(default)]
+    pub watch_schema: bool,
     #[serde(default)]
     pub subscriptions_path: Option<Utf8PathBuf>,
     #[serde(default)]
     pub clear_overwritten_secs: Option<u64>,
+    /// Tables whose changes are applied in timestamp order, across actors
+    #[serde(default)]
+    pub causal_tables: Vec<String>,
+    /// How long changes to causal tables are held back to be reordered
+    #[serde(default = "default_causal_window")]
+    pub causal_window_ms: u64,
+    /// How often tables are checked for stale query planner statistics, 0
+    /// to never refresh them
+    #[serde(default = "default_analyze_interval")]
+    pub analyze_interval_secs: u64,
 }
 
 impl DbConfig {
@@ -116,10 +277,101 @@ impl DbConfig {
 pub struct ApiConfig {
     #[serde(alias = "addr")]
     pub bind_addr: SocketAddr,
+    /// Don't accept IPv4 connections when bound to an IPv6 address
+    #[serde(default)]
+    pub ipv6_only: bool,
     #[serde(alias = "authz", default)]
     pub authorization: Option<AuthzConfig>,
     #[serde(default)]
     pub pg: Option<PgConfig>,
+    /// Requests with a larger body are rejected with a 413
+    #[serde(default = "default_api_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Connections which don't send their request headers in time are closed
+    #[serde(default = "default_api_header_read_timeout")]
+    pub header_read_timeout_secs: u64,
+    /// Requests which don't send their whole body in time get a 408
+    #[serde(default = "default_api_body_read_timeout")]
+    pub body_read_timeout_secs: u64,
+    /// Concurrent requests allowed over a single HTTP/2 connection
+    #[serde(default)]
+    pub max_concurrent_streams: Option<u32>,
+    #[serde(default)]
+    pub read_statements: ReadStatements,
+    /// How long writes wait for a schema migration to finish before being
+    /// rejected with a 503
+    #[serde(default = "default_api_write_fence_wait")]
+    pub write_fence_wait_ms: u64,
+    #[serde(default)]
+    pub load_shedding: LoadSheddingConfig,
+}
+
+/// Thresholds past which requests are shed, see [`crate::load`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadSheddingConfig {
+    #[serde(default = "default_as_true")]
+    pub enabled: bool,
+    /// How late the scheduler runs tasks, on average
+    #[serde(default = "default_max_scheduler_delay")]
+    pub max_scheduler_delay_ms: u64,
+    /// Client and apply writes waiting for the write connection
+    #[serde(default = "default_max_write_queue")]
+    pub max_write_queue: usize,
+    /// Changes received from the cluster, waiting to be applied
+    #[serde(default = "default_max_apply_backlog")]
+    pub max_apply_backlog: usize,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_scheduler_delay_ms: default_max_scheduler_delay(),
+            max_write_queue: default_max_write_queue(),
+            max_apply_backlog: default_max_apply_backlog(),
+        }
+    }
+}
+
+const fn default_max_scheduler_delay() -> u64 {
+    100
+}
+
+const fn default_max_write_queue() -> usize {
+    128
+}
+
+const fn default_max_apply_backlog() -> usize {
+    100_000
+}
+
+/// What to do with read-only statements sent to `/v1/transactions`
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadStatements {
+    /// Run them on the write connection, along with the other statements
+    #[default]
+    Write,
+    /// Run them on a read connection, returning their rows
+    Query,
+    /// Reject transactions containing any
+    Reject,
+}
+
+const fn default_api_max_body_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+const fn default_api_header_read_timeout() -> u64 {
+    10
+}
+
+const fn default_api_body_read_timeout() -> u64 {
+    60
+}
+
+const fn default_api_write_fence_wait() -> u64 {
+    2_000
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -154,6 +406,148 @@ pub struct GossipConfig {
     pub idle_timeout_secs: u32,
     #[serde(default)]
     pub disable_gso: bool,
+    /// Only use IPv6 when bound to IPv6 addresses, instead of dual-stack
+    #[serde(default)]
+    pub ipv6_only: bool,
+    #[serde(default)]
+    pub relay: Option<RelayConfig>,
+    #[serde(default)]
+    pub acl: PeerAclConfig,
+    /// Secret shared by all nodes, used to authenticate actor ids when syncing
+    #[serde(default)]
+    pub cluster_secret: Option<String>,
+    /// Send forward compatible (V2) payloads, which older nodes can't read
+    #[serde(default)]
+    pub tagged_payloads: bool,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+}
+
+/// Compression of the changesets peers send us when syncing, chosen for
+/// each link from its RTT and estimated bandwidth
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default = "default_compression_enabled")]
+    pub enabled: bool,
+    /// Links with a lower RTT aren't compressed
+    #[serde(default = "default_compression_min_rtt")]
+    pub min_rtt_ms: u64,
+    /// zstd level of links which are neither local nor slow
+    #[serde(default = "default_compression_level")]
+    pub level: i32,
+    /// Links with a higher RTT get heavier compression
+    #[serde(default = "default_compression_wan_rtt")]
+    pub wan_rtt_ms: u64,
+    /// Links with a lower estimated bandwidth, in KiB/s, also get heavier
+    /// compression
+    #[serde(default = "default_compression_wan_bandwidth")]
+    pub wan_bandwidth_kib: u64,
+    /// zstd level of slow links
+    #[serde(default = "default_compression_wan_level")]
+    pub wan_level: i32,
+}
+
+const fn default_compression_enabled() -> bool {
+    true
+}
+
+const fn default_compression_min_rtt() -> u64 {
+    5
+}
+
+const fn default_compression_level() -> i32 {
+    3
+}
+
+const fn default_compression_wan_rtt() -> u64 {
+    50
+}
+
+const fn default_compression_wan_bandwidth() -> u64 {
+    1024
+}
+
+const fn default_compression_wan_level() -> i32 {
+    9
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_compression_enabled(),
+            min_rtt_ms: default_compression_min_rtt(),
+            level: default_compression_level(),
+            wan_rtt_ms: default_compression_wan_rtt(),
+            wan_bandwidth_kib: default_compression_wan_bandwidth(),
+            wan_level: default_compression_wan_level(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Codec for a link, `bandwidth` is estimated in bytes per second
+    pub fn choose(&self, rtt: Duration, bandwidth: u64) -> Option<SyncCompressionV1> {
+        if !self.enabled || rtt < Duration::from_millis(self.min_rtt_ms) {
+            return None;
+        }
+        let level = if rtt >= Duration::from_millis(self.wan_rtt_ms)
+            || bandwidth < self.wan_bandwidth_kib * 1024
+        {
+            self.wan_level
+        } else {
+            self.level
+        };
+        Some(SyncCompressionV1::Zstd { level })
+    }
+}
+
+/// Peers allowed to connect over gossip. Denials take precedence over
+/// allowances, and an empty allowlist allows everyone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerAclConfig {
+    #[serde(default)]
+    pub allow_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub deny_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub allow_actors: Vec<ActorId>,
+    #[serde(default)]
+    pub deny_actors: Vec<ActorId>,
+}
+
+impl PeerAclConfig {
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        // IPv4 peers show up as IPv4-mapped IPv6 addresses on dual-stack sockets
+        let ip = ip.to_canonical();
+        !self.deny_cidrs.iter().any(|net| net.contains(&ip))
+            && (self.allow_cidrs.is_empty() || self.allow_cidrs.iter().any(|net| net.contains(&ip)))
+    }
+
+    pub fn allows_actor(&self, actor_id: ActorId) -> bool {
+        !self.deny_actors.contains(&actor_id)
+            && (self.allow_actors.is_empty() || self.allow_actors.contains(&actor_id))
+    }
+
+    /// Whether any actor is denied, for callers that need to decode
+    /// messages to know their actor
+    pub fn filters_actors(&self) -> bool {
+        !self.allow_actors.is_empty() || !self.deny_actors.is_empty()
+    }
+}
+
+/// Makes the node a relay, bridging broadcasts and syncs with network
+/// segments that can't reach its own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// Gossip addresses of relays in other segments
+    pub peers: Vec<SocketAddr>,
+    /// Broadcasts are not forwarded by more relays than this
+    #[serde(default = "default_relay_max_hops")]
+    pub max_hops: usize,
+}
+
+const fn default_relay_max_hops() -> usize {
+    3
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -180,6 +574,19 @@ pub struct PerfConfig {
     pub apply_queue_timeout: usize,
     #[serde(default = "default_apply_queue")]
     pub apply_queue_len: usize,
+    #[serde(default = "default_replay_window")]
+    pub replay_window_len: usize,
+    /// Caps on bytes buffered in memory, see [`crate::budget::MemoryBudget`]
+    #[serde(default)]
+    pub bcast_buffer_max_bytes: Option<usize>,
+    #[serde(default)]
+    pub apply_buffer_max_bytes: Option<usize>,
+    #[serde(default)]
+    pub subs_buffer_max_bytes: Option<usize>,
+    /// Changes with larger values aren't broadcast, peers get them through
+    /// the regular sync
+    #[serde(default)]
+    pub bcast_max_value_bytes: Option<usize>,
 }
 
 impl Default for PerfConfig {
@@ -196,6 +603,11 @@ impl Default for PerfConfig {
             foca_channel_len: default_small_channel(),
             apply_queue_timeout: default_apply_timeout(),
             apply_queue_len: default_apply_queue(),
+            replay_window_len: default_replay_window(),
+            bcast_buffer_max_bytes: None,
+            apply_buffer_max_bytes: None,
+            subs_buffer_max_bytes: None,
+            bcast_max_value_bytes: None,
         }
     }
 }
@@ -258,6 +670,129 @@ fn default_as_true() -> bool {
 pub enum ConfigError {
     #[error(transparent)]
     Config(#[from] config::ConfigError),
+    #[error("invalid configuration:{}", list_problems(.0))]
+    Invalid(Vec<ConfigProblem>),
+}
+
+/// Something wrong with the value at `key`, which would fail the agent or
+/// silently do something else than intended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub key: String,
+    pub msg: String,
+}
+
+impl ConfigProblem {
+    fn new<K: Into<String>, M: Into<String>>(key: K, msg: M) -> Self {
+        Self {
+            key: key.into(),
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.key, self.msg)
+    }
+}
+
+fn list_problems(problems: &[ConfigProblem]) -> String {
+    problems.iter().map(|p| format!("\n  - {p}")).collect()
+}
+
+/// Serde aliases of fields, by the name they're serialized with
+const KEY_ALIASES: &[(&str, &str)] = &[
+    ("addr", "bind_addr"),
+    ("path", "uds_path"),
+    ("authz", "authorization"),
+    ("bearer", "bearer-token"),
+];
+
+/// Socket addresses checked before deserializing, so they're all reported
+/// instead of only the first one failing
+const ADDR_KEYS: &[&[&str]] = &[
+    &["gossip", "addr"],
+    &["gossip", "bind_addr"],
+    &["gossip", "external_addr"],
+    &["gossip", "client_addr"],
+    &["api", "addr"],
+    &["api", "bind_addr"],
+    &["api", "pg", "addr"],
+    &["api", "pg", "bind_addr"],
+    &["telemetry", "prometheus", "addr"],
+    &["telemetry", "prometheus", "bind_addr"],
+    &["telemetry", "statsd", "addr"],
+    &["bridge", "remote-addr"],
+];
+
+fn check_addr(key: String, value: &serde_json::Value, problems: &mut Vec<ConfigProblem>) {
+    if let Some(s) = value.as_str() {
+        if s.parse::<SocketAddr>().is_err() {
+            problems.push(ConfigProblem::new(
+                key,
+                format!("invalid socket address '{s}', expected an ip and a port like '127.0.0.1:8787' or '[::1]:8787'"),
+            ));
+        }
+    }
+}
+
+fn check_addrs(raw: &serde_json::Value, problems: &mut Vec<ConfigProblem>) {
+    for path in ADDR_KEYS {
+        if let Some(value) = path.iter().try_fold(raw, |value, key| value.get(key)) {
+            check_addr(path.join("."), value, problems);
+        }
+    }
+    if let Some(peers) = raw
+        .pointer("/gossip/relay/peers")
+        .and_then(|peers| peers.as_array())
+    {
+        for (i, peer) in peers.iter().enumerate() {
+            check_addr(format!("gossip.relay.peers[{i}]"), peer, problems);
+        }
+    }
+}
+
+/// Reports keys of the config file which are not fields of the parsed
+/// configuration, compared to its serialized form. Values skipped or empty
+/// once serialized are not descended into.
+fn check_unknown_keys(
+    path: &str,
+    raw: &serde_json::Value,
+    known: &serde_json::Value,
+    problems: &mut Vec<ConfigProblem>,
+) {
+    use serde_json::Value;
+
+    match (raw, known) {
+        (Value::Object(raw), Value::Object(known)) => {
+            for (key, value) in raw {
+                let key_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                let known_value = known.get(key).or_else(|| {
+                    KEY_ALIASES
+                        .iter()
+                        .find(|(alias, _)| alias == key)
+                        .and_then(|(_, name)| known.get(*name))
+                });
+                match known_value {
+                    Some(known_value) => {
+                        check_unknown_keys(&key_path, value, known_value, problems)
+                    }
+                    None => problems.push(ConfigProblem::new(key_path, "unknown field")),
+                }
+            }
+        }
+        (Value::Array(raw), Value::Array(known)) => {
+            for (i, (value, known_value)) in raw.iter().zip(known.iter()).enumerate() {
+                check_unknown_keys(&format!("{path}[{i}]"), value, known_value, problems);
+            }
+        }
+        _ => {}
+    }
 }
 
 impl Config {
@@ -267,12 +802,255 @@ impl Config {
 
     /// Reads configuration from a TOML file, given its path. Environment
     /// variables can override whatever is set in the config file.
+    ///
+    /// Every problem found in the file is returned at once, as
+    /// [`ConfigError::Invalid`], instead of stopping at the first one.
     pub fn load(config_path: &str) -> Result<Self, ConfigError> {
-        let config = config::Config::builder()
+        let file = config::Config::builder()
             .add_source(config::File::new(config_path, config::FileFormat::Toml))
+            .build()?;
+        let config = config::Config::builder()
+            .add_source(file.clone())
             .add_source(config::Environment::default().separator("__"))
             .build()?;
-        Ok(config.try_deserialize()?)
+
+        let mut problems = vec![];
+        check_addrs(&config.clone().try_deserialize()?, &mut problems);
+
+        let parsed: Config = match config.try_deserialize() {
+            Ok(parsed) => parsed,
+            // the deserializer stops at the first invalid address, already listed
+            Err(_) if !problems.is_empty() => return Err(ConfigError::Invalid(problems)),
+            Err(e) => return Err(e.into()),
+        };
+
+        // environment variables are not checked: every one of them is a source
+        let known = serde_json::to_value(&parsed).expect("could not serialize config");
+        check_unknown_keys("", &file.try_deserialize()?, &known, &mut problems);
+        problems.extend(parsed.problems());
+
+        if problems.is_empty() {
+            Ok(parsed)
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Conflicting or out of range options, which deserialize fine but would
+    /// fail the agent, or make it ignore part of its configuration
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        let gossip = &self.gossip;
+
+        match (gossip.plaintext, gossip.tls.is_some()) {
+            (true, true) => problems.push(ConfigProblem::new(
+                "gossip.plaintext",
+                "conflicts with `gossip.tls`, remove one of them",
+            )),
+            (false, false) => problems.push(ConfigProblem::new(
+                "gossip.tls",
+                "required unless `gossip.plaintext = true`",
+            )),
+            _ => {}
+        }
+
+        for (i, s) in gossip.bootstrap.iter().enumerate() {
+            let key = format!("gossip.bootstrap[{i}]");
+            let (host_port, dns_server) = match s.split_once('@') {
+                Some((host_port, dns_server)) => (host_port, Some(dns_server)),
+                None => (s.as_str(), None),
+            };
+            if host_port.parse::<SocketAddr>().is_err() {
+                let (host, port) = host_port.rsplit_once(':').unwrap_or((host_port, ""));
+                if host.is_empty() {
+                    problems.push(ConfigProblem::new(&key, format!("missing host in '{s}'")));
+                }
+                if !port.is_empty() && port.parse::<u16>().is_err() {
+                    problems.push(ConfigProblem::new(&key, format!("invalid port in '{s}'")));
+                }
+            }
+            if let Some(dns_server) = dns_server {
+                if dns_server.parse::<SocketAddr>().is_err()
+                    && dns_server.parse::<IpAddr>().is_err()
+                {
+                    problems.push(ConfigProblem::new(
+                        &key,
+                        format!("invalid DNS server '{dns_server}', expected an ip with an optional port"),
+                    ));
+                }
+            }
+        }
+
+        if let Some(relay) = gossip.relay.as_ref() {
+            if relay.peers.is_empty() {
+                problems.push(ConfigProblem::new(
+                    "gossip.relay.peers",
+                    "a relay needs at least one peer",
+                ));
+            }
+            if relay.max_hops == 0 {
+                problems.push(ConfigProblem::new(
+                    "gossip.relay.max_hops",
+                    "must be at least 1, nothing would be relayed",
+                ));
+            }
+        }
+
+        for actor in gossip.acl.allow_actors.iter() {
+            if gossip.acl.deny_actors.contains(actor) {
+                problems.push(ConfigProblem::new(
+                    "gossip.acl.allow_actors",
+                    format!("{actor} is also in `gossip.acl.deny_actors`, which takes precedence"),
+                ));
+            }
+        }
+        for cidr in gossip.acl.allow_cidrs.iter() {
+            if gossip.acl.deny_cidrs.contains(cidr) {
+                problems.push(ConfigProblem::new(
+                    "gossip.acl.allow_cidrs",
+                    format!("{cidr} is also in `gossip.acl.deny_cidrs`, which takes precedence"),
+                ));
+            }
+        }
+
+        if gossip.cluster_secret.as_deref() == Some("") {
+            problems.push(ConfigProblem::new(
+                "gossip.cluster_secret",
+                "must not be empty",
+            ));
+        }
+
+        let levels = zstd::compression_level_range();
+        for (key, level) in [
+            ("gossip.compression.level", gossip.compression.level),
+            ("gossip.compression.wan_level", gossip.compression.wan_level),
+        ] {
+            if !levels.contains(&level) {
+                problems.push(ConfigProblem::new(
+                    key,
+                    format!(
+                        "zstd level {level} is out of range ({} to {})",
+                        levels.start(),
+                        levels.end()
+                    ),
+                ));
+            }
+        }
+        if gossip.compression.min_rtt_ms > gossip.compression.wan_rtt_ms {
+            problems.push(ConfigProblem::new(
+                "gossip.compression.min_rtt_ms",
+                "must not be greater than `gossip.compression.wan_rtt_ms`",
+            ));
+        }
+
+        if let Some(AuthzConfig::BearerToken(token)) = self.api.authorization.as_ref() {
+            if token.is_empty() {
+                problems.push(ConfigProblem::new(
+                    "api.authorization.bearer-token",
+                    "must not be empty",
+                ));
+            }
+        }
+
+        let api_addr = self.api.bind_addr;
+        let other_addrs = [
+            ("api.pg.addr", self.api.pg.as_ref().map(|pg| pg.bind_addr)),
+            (
+                "telemetry.prometheus.addr",
+                self.telemetry.prometheus.as_ref().map(|p| p.bind_addr),
+            ),
+        ];
+        for (key, addr) in other_addrs {
+            if addr == Some(api_addr) && api_addr.port() != 0 {
+                problems.push(ConfigProblem::new(
+                    key,
+                    format!("{api_addr} is already used by `api.addr`"),
+                ));
+            }
+        }
+
+        if self.subscriptions.idle_timeout_secs == 0 {
+            problems.push(ConfigProblem::new(
+                "subscriptions.idle_timeout_secs",
+                "must be at least 1",
+            ));
+        }
+        if self.subscriptions.max_count == Some(0) {
+            problems.push(ConfigProblem::new(
+                "subscriptions.max_count",
+                "must be at least 1, remove it to allow any number of subscriptions",
+            ));
+        }
+        for (i, fanout) in self.subscriptions.fanout.iter().enumerate() {
+            if self.subscriptions.fanout[..i]
+                .iter()
+                .any(|prev| prev.path == fanout.path)
+            {
+                problems.push(ConfigProblem::new(
+                    format!("subscriptions.fanout[{i}].path"),
+                    format!("{} is already the path of another fanout", fanout.path),
+                ));
+            }
+        }
+
+        if let Some(bridge) = self.bridge.as_ref() {
+            for (key, name) in [
+                ("bridge.name", &bridge.name),
+                ("bridge.remote-name", &bridge.remote_name),
+            ] {
+                if name.is_empty()
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    problems.push(ConfigProblem::new(
+                        key,
+                        "must only hold letters, digits, '-' and '_'",
+                    ));
+                }
+            }
+            if bridge.name == bridge.remote_name {
+                problems.push(ConfigProblem::new(
+                    "bridge.remote-name",
+                    "must differ from `bridge.name`, changes would loop between clusters",
+                ));
+            }
+            if bridge.tables.is_empty() {
+                problems.push(ConfigProblem::new(
+                    "bridge.tables",
+                    "at least 1 table is required",
+                ));
+            }
+            if bridge.interval_secs == 0 {
+                problems.push(ConfigProblem::new(
+                    "bridge.interval-secs",
+                    "must be at least 1",
+                ));
+            }
+        }
+
+        let perf = &self.perf;
+        for (key, len) in [
+            ("perf.apply_channel_len", perf.apply_channel_len),
+            ("perf.changes_channel_len", perf.changes_channel_len),
+            ("perf.empties_channel_len", perf.empties_channel_len),
+            ("perf.to_send_channel_len", perf.to_send_channel_len),
+            (
+                "perf.notifications_channel_len",
+                perf.notifications_channel_len,
+            ),
+            ("perf.schedule_channel_len", perf.schedule_channel_len),
+            ("perf.clearbuf_channel_len", perf.clearbuf_channel_len),
+            ("perf.bcast_channel_len", perf.bcast_channel_len),
+            ("perf.foca_channel_len", perf.foca_channel_len),
+            ("perf.apply_queue_len", perf.apply_queue_len),
+        ] {
+            if len == 0 {
+                problems.push(ConfigProblem::new(key, "must be at least 1"));
+            }
+        }
+
+        problems
     }
 }
 
@@ -291,6 +1069,10 @@ pub struct ConfigBuilder {
     consul: Option<ConsulConfig>,
     tls: Option<TlsConfig>,
     perf: Option<PerfConfig>,
+    subscriptions: Option<SubscriptionsConfig>,
+    relay: Option<RelayConfig>,
+    acl: Option<PeerAclConfig>,
+    cluster_secret: Option<String>,
 }
 
 impl ConfigBuilder {
@@ -354,6 +1136,26 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn subscriptions(mut self, config: SubscriptionsConfig) -> Self {
+        self.subscriptions = Some(config);
+        self
+    }
+
+    pub fn relay(mut self, config: RelayConfig) -> Self {
+        self.relay = Some(config);
+        self
+    }
+
+    pub fn acl(mut self, config: PeerAclConfig) -> Self {
+        self.acl = Some(config);
+        self
+    }
+
+    pub fn cluster_secret<S: Into<String>>(mut self, secret: S) -> Self {
+        self.cluster_secret = Some(secret.into());
+        self
+    }
+
     pub fn build(self) -> Result<Config, ConfigBuilderError> {
         let db_path = self.db_path.ok_or(ConfigBuilderError::DbPathRequired)?;
 
@@ -361,20 +1163,34 @@ impl ConfigBuilder {
             prometheus: self
                 .prometheus_addr
                 .map(|bind_addr| PrometheusConfig { bind_addr }),
+            statsd: None,
             open_telemetry: None,
+            history: None,
         };
 
         Ok(Config {
             db: DbConfig {
                 path: db_path,
                 schema_paths: self.schema_paths,
+                watch_schema: false,
                 subscriptions_path: None,
                 clear_overwritten_secs: None,
+                causal_tables: vec![],
+                causal_window_ms: default_causal_window(),
+                analyze_interval_secs: default_analyze_interval(),
             },
             api: ApiConfig {
                 bind_addr: self.api_addr.ok_or(ConfigBuilderError::ApiAddrRequired)?,
+                ipv6_only: false,
                 authorization: None,
                 pg: None,
+                max_body_bytes: default_api_max_body_bytes(),
+                header_read_timeout_secs: default_api_header_read_timeout(),
+                body_read_timeout_secs: default_api_body_read_timeout(),
+                max_concurrent_streams: None,
+                read_statements: ReadStatements::default(),
+                write_fence_wait_ms: default_api_write_fence_wait(),
+                load_shedding: LoadSheddingConfig::default(),
             },
             gossip: GossipConfig {
                 bind_addr: self
@@ -388,6 +1204,12 @@ impl ConfigBuilder {
                 idle_timeout_secs: default_gossip_idle_timeout(),
                 max_mtu: None, // TODO: add a builder function for it
                 disable_gso: false,
+                ipv6_only: false,
+                relay: self.relay,
+                acl: self.acl.unwrap_or_default(),
+                cluster_secret: self.cluster_secret,
+                tagged_payloads: false,
+                compression: Default::default(),
             },
             perf: self.perf.unwrap_or_default(),
             admin: AdminConfig {
@@ -397,6 +1219,10 @@ impl ConfigBuilder {
             log: self.log.unwrap_or_default(),
 
             consul: self.consul,
+            haproxy: None,
+            bridge: None,
+            gaps: GapsConfig::default(),
+            subscriptions: self.subscriptions.unwrap_or_default(),
         })
     }
 }
@@ -426,3 +1252,227 @@ pub enum LogFormat {
 pub struct ConsulConfig {
     pub client: consul_client::Config,
 }
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HaproxyConfig {
+    /// HAProxy Runtime API address: `host:port` or a unix socket path
+    pub runtime_api: String,
+    /// Query returning `backend`, `server`, `addr`, `port` and `weight`
+    /// columns, in that order, for every server to keep in sync
+    pub query: String,
+}
+
+/// Which way a bridge replicates changes
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeDirection {
+    /// From the other cluster to this one
+    #[default]
+    Pull,
+    /// From this cluster to the other one
+    Push,
+    Both,
+}
+
+impl BridgeDirection {
+    pub fn pulls(self) -> bool {
+        matches!(self, BridgeDirection::Pull | BridgeDirection::Both)
+    }
+
+    pub fn pushes(self) -> bool {
+        matches!(self, BridgeDirection::Push | BridgeDirection::Both)
+    }
+}
+
+const fn default_bridge_interval() -> u64 {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BridgeConfig {
+    /// Name of this cluster, its changes are booked under actors derived
+    /// from it in the other cluster
+    pub name: String,
+    /// Name of the other cluster, its changes are booked under actors
+    /// derived from it in this cluster
+    pub remote_name: String,
+    /// API address of an agent of the other cluster
+    pub remote_addr: SocketAddr,
+    /// Tables replicated, they have to exist in both clusters
+    pub tables: Vec<String>,
+    #[serde(default)]
+    pub direction: BridgeDirection,
+    /// Seconds between polls of new changes
+    #[serde(default = "default_bridge_interval")]
+    pub interval_secs: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn test_peer_acl() {
+        let acl = PeerAclConfig {
+            allow_cidrs: vec!["10.0.0.0/8".parse().unwrap(), "fdaa::/16".parse().unwrap()],
+            deny_cidrs: vec!["10.0.5.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+
+        assert!(acl.allows_ip("10.0.0.1".parse().unwrap()));
+        assert!(acl.allows_ip("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(acl.allows_ip("fdaa::1".parse().unwrap()));
+        assert!(!acl.allows_ip("10.0.5.1".parse().unwrap()));
+        assert!(!acl.allows_ip("192.168.0.1".parse().unwrap()));
+        assert!(!acl.filters_actors());
+
+        let denied = ActorId(Uuid::new_v4());
+        let acl = PeerAclConfig {
+            deny_actors: vec![denied],
+            ..Default::default()
+        };
+        assert!(acl.allows_ip("192.168.0.1".parse().unwrap()));
+        assert!(acl.allows_actor(ActorId(Uuid::new_v4())));
+        assert!(!acl.allows_actor(denied));
+        assert!(acl.filters_actors());
+    }
+
+    fn load_str(toml: &str) -> Result<Config, ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, toml).unwrap();
+        Config::load(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_config_problems() {
+        let config = load_str(
+            r#"
+            [db]
+            path = "/var/lib/corrosion/state.db"
+
+            [api]
+            addr = "127.0.0.1:8080"
+
+            [gossip]
+            addr = "[::]:8787"
+            plaintext = true
+            bootstrap = ["my-app.internal:8787", "10.0.0.1:8787@[fdaa::3]:53"]
+
+            [telemetry.statsd]
+            addr = "127.0.0.1:8125"
+            tags = { env = "prod" }
+            "#,
+        )
+        .unwrap();
+        assert!(config.problems().is_empty());
+
+        let Err(ConfigError::Invalid(problems)) = load_str(
+            r#"
+            [db]
+            path = "/var/lib/corrosion/state.db"
+            shema_paths = ["/etc/corrosion/schema"]
+
+            [api]
+            addr = "127.0.0.1:8080"
+            pg = { addr = "127.0.0.1:8080" }
+
+            [gossip]
+            addr = "[::]:8787"
+            plaintext = true
+            bootstrap = [":8787", "my-app.internal:abc", "10.0.0.1:8787@nope"]
+            tls = { cert_file = "cert.pem", key_file = "key.pem", insecure = true, ca = "ca.pem" }
+
+            [gossip.relay]
+            peers = []
+            max_hops = 0
+
+            [subscriptions]
+            idle_timeout_secs = 0
+
+            [bridge]
+            name = "staging"
+            remote-name = "staging"
+            remote-addr = "10.0.0.1:8080"
+            tables = []
+            "#,
+        ) else {
+            panic!("expected an invalid config");
+        };
+
+        let keys: Vec<&str> = problems.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "db.shema_paths",
+                "gossip.tls.ca",
+                "gossip.plaintext",
+                "gossip.bootstrap[0]",
+                "gossip.bootstrap[1]",
+                "gossip.bootstrap[2]",
+                "gossip.relay.peers",
+                "gossip.relay.max_hops",
+                "api.pg.addr",
+                "subscriptions.idle_timeout_secs",
+                "bridge.remote-name",
+                "bridge.tables",
+            ]
+        );
+
+        // every invalid address is reported, not only the first one
+        let Err(ConfigError::Invalid(problems)) = load_str(
+            r#"
+            [db]
+            path = "/var/lib/corrosion/state.db"
+
+            [api]
+            addr = "localhost:8080"
+
+            [gossip]
+            addr = "8787"
+            plaintext = true
+
+            [gossip.relay]
+            peers = ["10.0.0.1:8787", "10.0.0.2"]
+            "#,
+        ) else {
+            panic!("expected an invalid config");
+        };
+
+        let keys: Vec<&str> = problems.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["gossip.addr", "api.addr", "gossip.relay.peers[1]"]
+        );
+    }
+
+    #[test]
+    fn test_compression_choice() {
+        let conf = CompressionConfig::default();
+        let fast = 100 * 1024 * 1024;
+
+        assert_eq!(conf.choose(Duration::from_micros(300), fast), None);
+        assert_eq!(
+            conf.choose(Duration::from_millis(20), fast),
+            Some(SyncCompressionV1::Zstd { level: 3 })
+        );
+        assert_eq!(
+            conf.choose(Duration::from_millis(20), 512 * 1024),
+            Some(SyncCompressionV1::Zstd { level: 9 })
+        );
+        assert_eq!(
+            conf.choose(Duration::from_millis(120), fast),
+            Some(SyncCompressionV1::Zstd { level: 9 })
+        );
+
+        let conf = CompressionConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        assert_eq!(conf.choose(Duration::from_millis(120), fast), None);
+    }
+}
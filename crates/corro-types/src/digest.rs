@@ -0,0 +1,151 @@
+//! Digests of tables' contents. Nodes which converged have the same digests
+//! for every table, comparing them bucket by bucket, then row by row,
+//! narrows differences down to the rows involved.
+
+use corro_api_types::{RowDigest, SqliteValue, TableDigest};
+use itertools::Itertools;
+use rusqlite::Connection;
+
+use crate::{
+    pubsub::{pack_columns, PackError},
+    schema::Table,
+};
+
+/// Buckets rows are spread into, by primary key
+pub const DIGEST_BUCKETS: usize = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DigestError {
+    #[error(transparent)]
+    Rusqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Pack(#[from] PackError),
+}
+
+/// Calls `f` with the bucket, primary key and digest of every row of
+/// `table`. Generated columns are skipped, other columns are digested in
+/// name order so it doesn't depend on the order they were added in.
+fn digest_rows<F>(conn: &Connection, table: &Table, mut f: F) -> Result<(), DigestError>
+where
+    F: FnMut(usize, Vec<SqliteValue>, u64),
+{
+    let cols = table
+        .columns
+        .values()
+        .filter(|col| !col.primary_key && col.generated.is_none())
+        .map(|col| col.name.as_str())
+        .sorted()
+        .collect::<Vec<_>>();
+
+    let select = format!(
+        "SELECT {} FROM \"{}\"",
+        table
+            .pk
+            .iter()
+            .map(String::as_str)
+            .chain(cols.iter().copied())
+            .map(|col| format!("\"{col}\""))
+            .join(","),
+        table.name
+    );
+
+    let pk_len = table.pk.len();
+    let col_count = pk_len + cols.len();
+
+    let mut prepped = conn.prepare(&select)?;
+    let mut rows = prepped.query([])?;
+    while let Some(row) = rows.next()? {
+        let mut values = (0..col_count)
+            .map(|i| row.get::<_, SqliteValue>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let bucket = seahash::hash(&pack_columns(&values[..pk_len])?) as usize % DIGEST_BUCKETS;
+        let digest = seahash::hash(&pack_columns(&values)?);
+
+        values.truncate(pk_len);
+        f(bucket, values, digest);
+    }
+
+    Ok(())
+}
+
+pub fn table_digest(conn: &Connection, table: &Table) -> Result<TableDigest, DigestError> {
+    let mut rows = 0;
+    let mut buckets = vec![0u64; DIGEST_BUCKETS];
+    digest_rows(conn, table, |bucket, _pk, digest| {
+        rows += 1;
+        // rows are unique by primary key, summing digests is enough and
+        // doesn't depend on the order rows are read in
+        buckets[bucket] = buckets[bucket].wrapping_add(digest);
+    })?;
+
+    Ok(TableDigest {
+        table: table.name.clone(),
+        rows,
+        buckets,
+    })
+}
+
+/// Digests of the rows of `table` in the given buckets
+pub fn row_digests(
+    conn: &Connection,
+    table: &Table,
+    buckets: &[usize],
+) -> Result<Vec<RowDigest>, DigestError> {
+    let mut digests = vec![];
+    digest_rows(conn, table, |bucket, pk, digest| {
+        if buckets.contains(&bucket) {
+            digests.push(RowDigest { pk, digest });
+        }
+    })?;
+    Ok(digests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::parse_sql;
+
+    #[test]
+    fn test_table_digest() -> Result<(), Box<dyn std::error::Error>> {
+        let schema =
+            parse_sql("CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, a TEXT, b INTEGER);")?;
+        let table = schema.tables.get("tests").unwrap();
+
+        let left = Connection::open_in_memory()?;
+        // same table, columns declared in a different order
+        let right = Connection::open_in_memory()?;
+        left.execute_batch(
+            "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, a TEXT, b INTEGER);
+            INSERT INTO tests VALUES (1, 'one', 1), (2, 'two', 2), (3, 'three', 3);",
+        )?;
+        right.execute_batch(
+            "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, b INTEGER, a TEXT);
+            INSERT INTO tests VALUES (3, 3, 'three'), (2, 2, 'two'), (1, 1, 'one');",
+        )?;
+
+        let digest = table_digest(&left, table)?;
+        assert_eq!(digest.rows, 3);
+        assert_eq!(digest, table_digest(&right, table)?);
+
+        right.execute("UPDATE tests SET a = 'deux' WHERE id = 2", [])?;
+        let changed = table_digest(&right, table)?;
+        assert_ne!(digest, changed);
+
+        let buckets = digest
+            .buckets
+            .iter()
+            .zip(changed.buckets.iter())
+            .positions(|(left, right)| left != right)
+            .collect::<Vec<_>>();
+        assert_eq!(buckets.len(), 1);
+
+        let left_rows = row_digests(&left, table, &buckets)?;
+        let right_rows = row_digests(&right, table, &buckets)?;
+        assert_eq!(left_rows.len(), right_rows.len());
+        assert!(left_rows.iter().any(|row| row.pk == vec![2i64.into()]));
+        assert_ne!(left_rows, right_rows);
+
+        Ok(())
+    }
+}
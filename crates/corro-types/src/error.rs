@@ -0,0 +1,165 @@
+//! Errors returned by the API, each with a stable [`ErrorCode`], so
+//! handlers map them the same way and clients can tell them apart without
+//! parsing messages. The agent maps codes to status codes.
+
+use crate::{
+    actor::ActorId,
+    agent::PoolError,
+    api::{ErrorCode, ExecResult},
+    base::Version,
+    causality::RowMetaError,
+    fence::Fenced,
+    pagination::PaginationError,
+    schema::{ApplySchemaError, ConstrainedSchemaError},
+    sqlite::SqlitePoolError,
+    validation::ValidationError,
+};
+
+pub trait ApiError: std::error::Error {
+    fn code(&self) -> ErrorCode;
+
+    fn exec_result(&self) -> ExecResult {
+        ExecResult::error(self.code(), self)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChangeError {
+    #[error("could not acquire pooled connection: {0}")]
+    Pool(#[from] PoolError),
+    #[error("rusqlite: {source} (actor_id: {actor_id:?}, version: {version:?})")]
+    Rusqlite {
+        source: rusqlite::Error,
+        actor_id: Option<ActorId>,
+        version: Option<Version>,
+    },
+    #[error(transparent)]
+    Vetoed(#[from] ValidationError),
+    #[error(transparent)]
+    Fenced(#[from] Fenced),
+    #[error("statement #{index} failed, the transaction was rolled back: {source}")]
+    StatementFailed {
+        index: usize,
+        source: rusqlite::Error,
+    },
+    #[error("precondition #{index} failed, the transaction was rolled back: {reason}")]
+    PreconditionFailed { index: usize, reason: String },
+    #[error("precondition #{index} is invalid: {reason}")]
+    InvalidPrecondition { index: usize, reason: String },
+    /// An interactive transaction ended without being committed
+    #[error("transaction was rolled back: {0}")]
+    RolledBack(&'static str),
+}
+
+impl ApiError for ChangeError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ChangeError::Pool(_) => ErrorCode::Unavailable,
+            ChangeError::Rusqlite { .. } => ErrorCode::Database,
+            ChangeError::Vetoed(_) => ErrorCode::Vetoed,
+            ChangeError::Fenced(_) => ErrorCode::Fenced,
+            ChangeError::StatementFailed { .. } => ErrorCode::StatementFailed,
+            ChangeError::PreconditionFailed { .. } => ErrorCode::PreconditionFailed,
+            ChangeError::InvalidPrecondition { .. } => ErrorCode::BadRequest,
+            ChangeError::RolledBack(_) => ErrorCode::BadRequest,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error("pool connection acquisition error: {0}")]
+    Pool(#[from] SqlitePoolError),
+    #[error("snapshot {0} not found or expired")]
+    SnapshotNotFound(uuid::Uuid),
+    #[error("query {0} is not running")]
+    QueryNotFound(uuid::Uuid),
+    /// The statement doesn't prepare, or its parameters don't bind
+    #[error(transparent)]
+    InvalidStatement(rusqlite::Error),
+    #[error("statement is not readonly")]
+    NotReadonly,
+    #[error(transparent)]
+    RowMeta(#[from] RowMetaError),
+    #[error(transparent)]
+    Pagination(#[from] PaginationError),
+    #[error("query stopped before returning rows")]
+    Aborted,
+    #[error("sqlite error: {0}")]
+    Rusqlite(#[from] rusqlite::Error),
+}
+
+impl ApiError for QueryError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            QueryError::Pool(_) => ErrorCode::Unavailable,
+            QueryError::SnapshotNotFound(_) | QueryError::QueryNotFound(_) => ErrorCode::NotFound,
+            QueryError::InvalidStatement(_) => ErrorCode::InvalidStatement,
+            QueryError::NotReadonly | QueryError::RowMeta(_) | QueryError::Pagination(_) => {
+                ErrorCode::BadRequest
+            }
+            QueryError::Aborted => ErrorCode::Internal,
+            QueryError::Rusqlite(_) => ErrorCode::Database,
+        }
+    }
+}
+
+/// Errors of schema changes made through the API or the admin socket
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error("at least 1 statement is required")]
+    NoStatements,
+    #[error(transparent)]
+    Parse(#[from] crate::schema::SchemaError),
+    #[error(transparent)]
+    Constrained(#[from] ConstrainedSchemaError),
+    #[error(transparent)]
+    Apply(#[from] ApplySchemaError),
+    #[error("could not acquire pooled connection: {0}")]
+    Pool(#[from] PoolError),
+    #[error(transparent)]
+    Rusqlite(#[from] rusqlite::Error),
+}
+
+impl ApiError for SchemaError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            SchemaError::NoStatements => ErrorCode::BadRequest,
+            SchemaError::Parse(_) | SchemaError::Constrained(_) | SchemaError::Apply(_) => {
+                ErrorCode::InvalidSchema
+            }
+            SchemaError::Pool(_) => ErrorCode::Unavailable,
+            SchemaError::Rusqlite(_) => ErrorCode::Database,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        let err = QueryError::SnapshotNotFound(uuid::Uuid::nil());
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        assert_eq!(
+            serde_json::to_value(SchemaError::NoStatements.exec_result()).unwrap(),
+            serde_json::json!({
+                "error": "at least 1 statement is required",
+                "code": "bad_request"
+            })
+        );
+
+        // codes added later don't break older clients
+        let res: ExecResult =
+            serde_json::from_str(r#"{"error": "nope", "code": "something_new"}"#).unwrap();
+        assert!(matches!(
+            res,
+            ExecResult::Error {
+                code: Some(ErrorCode::Unknown),
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,155 @@
+//! Fences local writes while a schema migration rebuilds tables. Writes
+//! arriving during a migration wait for it to finish for a little while,
+//! then fail with a hint to retry instead of piling up behind it.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use metrics::counter;
+use tokio::sync::watch;
+
+/// Longest delay suggested to retry fenced writes after
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+#[error("schema migration in progress for {}s, retry in {}s", .elapsed.as_secs(), .retry_after.as_secs())]
+pub struct Fenced {
+    /// How long the migration has been running for
+    pub elapsed: Duration,
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct FenceState {
+    /// Guards currently raising the fence
+    raised: usize,
+    /// When the fence was first raised, reset once every guard is dropped
+    since: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteFence(Arc<watch::Sender<FenceState>>);
+
+impl Default for WriteFence {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(FenceState::default()).0))
+    }
+}
+
+impl WriteFence {
+    /// Fences writes until the returned guard, and every other guard raised
+    /// in the meantime, are dropped
+    pub fn raise(&self) -> FenceGuard {
+        self.0.send_modify(|state| {
+            state.raised += 1;
+            state.since.get_or_insert_with(Instant::now);
+        });
+        FenceGuard(self.0.clone())
+    }
+
+    /// When the current migration started, if any
+    pub fn raised_at(&self) -> Option<Instant> {
+        self.0.borrow().since
+    }
+
+    /// How long fenced writes should wait before retrying, `None` if the
+    /// fence isn't raised
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.raised_at().map(|at| retry_after(at.elapsed()))
+    }
+
+    /// Waits for the fence to be lowered, for up to `max_wait`. Migrations
+    /// running longer make writes fail with a retry hint proportional to how
+    /// long they've been running for.
+    pub async fn wait(&self, max_wait: Duration) -> Result<(), Fenced> {
+        if self.raised_at().is_none() {
+            return Ok(());
+        }
+
+        let mut rx = self.0.subscribe();
+        if tokio::time::timeout(max_wait, rx.wait_for(|state| state.raised == 0))
+            .await
+            .is_ok()
+        {
+            counter!("corro.api.writes.fenced", "outcome" => "waited").increment(1);
+            return Ok(());
+        }
+
+        counter!("corro.api.writes.fenced", "outcome" => "rejected").increment(1);
+        let elapsed = self.raised_at().map(|at| at.elapsed()).unwrap_or_default();
+        Err(Fenced {
+            elapsed,
+            retry_after: retry_after(elapsed),
+        })
+    }
+}
+
+/// Proportional to how long the migration has been running for
+fn retry_after(elapsed: Duration) -> Duration {
+    Duration::from_secs(elapsed.as_secs().max(1)).min(MAX_RETRY_AFTER)
+}
+
+/// Lowers the fence when dropped, letting waiting writes through once no
+/// other guard holds it up
+pub struct FenceGuard(Arc<watch::Sender<FenceState>>);
+
+impl Drop for FenceGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|state| {
+            state.raised -= 1;
+            if state.raised == 0 {
+                state.since = None;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_fence() {
+        let fence = WriteFence::default();
+        fence.wait(Duration::ZERO).await.unwrap();
+
+        let guard = fence.raise();
+        assert!(fence.raised_at().is_some());
+
+        let err = fence.wait(Duration::from_millis(10)).await.unwrap_err();
+        assert_eq!(err.retry_after, Duration::from_secs(1));
+
+        let waiting = tokio::spawn({
+            let fence = fence.clone();
+            async move { fence.wait(Duration::from_secs(5)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(guard);
+
+        waiting.await.unwrap().unwrap();
+        assert!(fence.raised_at().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_fence_overlapping() {
+        let fence = WriteFence::default();
+
+        let first = fence.raise();
+        let raised_at = fence.raised_at();
+        let second = fence.raise();
+        assert_eq!(fence.raised_at(), raised_at);
+
+        // still held up by the second migration
+        drop(first);
+        assert_eq!(fence.raised_at(), raised_at);
+        assert!(fence.wait(Duration::from_millis(10)).await.is_err());
+        assert_eq!(fence.retry_after(), Some(Duration::from_secs(1)));
+
+        drop(second);
+        assert!(fence.raised_at().is_none());
+        assert!(fence.retry_after().is_none());
+        fence.wait(Duration::ZERO).await.unwrap();
+    }
+}
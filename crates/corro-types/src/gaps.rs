@@ -0,0 +1,164 @@
+//! Tracks how long versions from remote actors have been missing. A gap
+//! that syncs don't fill is the earliest symptom of data loss or of a
+//! stuck peer.
+
+use std::{
+    collections::BTreeMap,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use rangemap::RangeInclusiveSet;
+use serde::{Deserialize, Serialize};
+
+use crate::{actor::ActorId, base::Version};
+
+#[derive(Debug, Default)]
+pub struct GapTracker {
+    syncs: u64,
+    actors: BTreeMap<ActorId, Vec<TrackedGap>>,
+}
+
+#[derive(Debug, Clone)]
+struct TrackedGap {
+    versions: RangeInclusive<Version>,
+    since: Instant,
+    // number of syncs completed when the gap was first seen
+    syncs_at: u64,
+    alerted: bool,
+}
+
+/// A range of versions missing from an actor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gap {
+    pub actor_id: ActorId,
+    pub versions: RangeInclusive<Version>,
+    pub missing_secs: f64,
+    /// Syncs completed since the gap was first seen
+    pub syncs: u64,
+    pub alerted: bool,
+}
+
+impl GapTracker {
+    pub fn record_sync(&mut self) {
+        self.syncs += 1;
+    }
+
+    /// Update an actor's gaps from the versions it still needs. Gaps that
+    /// shrink or split keep the age of the gaps they came from.
+    pub fn observe(&mut self, actor_id: ActorId, needs: &RangeInclusiveSet<Version>, now: Instant) {
+        if needs.is_empty() {
+            self.actors.remove(&actor_id);
+            return;
+        }
+
+        let previous = self.actors.remove(&actor_id).unwrap_or_default();
+        let gaps = needs
+            .iter()
+            .map(|versions| {
+                let overlapping = previous.iter().filter(|gap| {
+                    gap.versions.start() <= versions.end() && versions.start() <= gap.versions.end()
+                });
+                let mut tracked = TrackedGap {
+                    versions: versions.clone(),
+                    since: now,
+                    syncs_at: self.syncs,
+                    alerted: false,
+                };
+                for gap in overlapping {
+                    tracked.since = tracked.since.min(gap.since);
+                    tracked.syncs_at = tracked.syncs_at.min(gap.syncs_at);
+                    tracked.alerted |= gap.alerted;
+                }
+                tracked
+            })
+            .collect();
+
+        self.actors.insert(actor_id, gaps);
+    }
+
+    /// Forget about actors that aren't known anymore
+    pub fn retain<F: FnMut(&ActorId) -> bool>(&mut self, mut f: F) {
+        self.actors.retain(|actor_id, _| f(actor_id));
+    }
+
+    /// Gaps older than `alert_after` that survived at least one sync and
+    /// weren't reported yet. They're marked as alerted.
+    pub fn take_stale(&mut self, alert_after: Duration, now: Instant) -> Vec<Gap> {
+        let syncs = self.syncs;
+        let mut stale = vec![];
+        for (actor_id, gaps) in self.actors.iter_mut() {
+            for gap in gaps.iter_mut() {
+                if gap.alerted
+                    || now.duration_since(gap.since) < alert_after
+                    || syncs == gap.syncs_at
+                {
+                    continue;
+                }
+                gap.alerted = true;
+                stale.push(to_gap(*actor_id, gap, syncs, now));
+            }
+        }
+        stale
+    }
+
+    pub fn gaps(&self, now: Instant) -> Vec<Gap> {
+        self.actors
+            .iter()
+            .flat_map(|(actor_id, gaps)| {
+                gaps.iter()
+                    .map(move |gap| to_gap(*actor_id, gap, self.syncs, now))
+            })
+            .collect()
+    }
+}
+
+fn to_gap(actor_id: ActorId, gap: &TrackedGap, syncs: u64, now: Instant) -> Gap {
+    Gap {
+        actor_id,
+        versions: gap.versions.clone(),
+        missing_secs: now.duration_since(gap.since).as_secs_f64(),
+        syncs: syncs - gap.syncs_at,
+        alerted: gap.alerted,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gap_tracking() {
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let mut tracker = GapTracker::default();
+        let start = Instant::now();
+        let alert_after = Duration::from_secs(60);
+
+        let mut needs = RangeInclusiveSet::new();
+        needs.insert(Version(1)..=Version(10));
+        tracker.observe(actor_id, &needs, start);
+
+        // old enough, but no sync had a chance to fill it
+        let later = start + Duration::from_secs(120);
+        assert!(tracker.take_stale(alert_after, later).is_empty());
+
+        tracker.record_sync();
+
+        // partially filled, the remaining gaps keep their age
+        needs.remove(Version(4)..=Version(6));
+        tracker.observe(actor_id, &needs, later);
+
+        let stale = tracker.take_stale(alert_after, later);
+        assert_eq!(stale.len(), 2);
+        assert_eq!(stale[0].versions, Version(1)..=Version(3));
+        assert_eq!(stale[1].versions, Version(7)..=Version(10));
+        assert_eq!(stale[0].syncs, 1);
+
+        // only reported once
+        assert!(tracker.take_stale(alert_after, later).is_empty());
+        assert!(tracker.gaps(later).iter().all(|gap| gap.alerted));
+
+        tracker.observe(actor_id, &RangeInclusiveSet::new(), later);
+        assert!(tracker.gaps(later).is_empty());
+    }
+}
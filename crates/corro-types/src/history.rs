@@ -0,0 +1,179 @@
+//! Per-minute operational history, kept in a local table so small
+//! deployments can look back at writes and replication lag with regular
+//! queries, without running a metrics stack.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+
+/// Local table holding one row per minute, it isn't replicated
+pub const HISTORY_TABLE: &str = "__corro_history";
+
+#[derive(Debug, Default)]
+struct LagSamples {
+    count: u64,
+    sum: f64,
+    max: f64,
+}
+
+#[derive(Debug, Default)]
+struct InnerHistory {
+    writes: AtomicU64,
+    local_changes: AtomicU64,
+    applied_changes: AtomicU64,
+    lag: Mutex<LagSamples>,
+}
+
+/// Counts accumulated since the last bucket was taken
+#[derive(Clone, Default)]
+pub struct History(Arc<InnerHistory>);
+
+/// Activity of a single minute
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MinuteBucket {
+    /// Local transactions committed with changes
+    pub writes: u64,
+    /// Changes (cells) committed locally
+    pub local_changes: u64,
+    /// Changes (cells) applied from peers
+    pub applied_changes: u64,
+    /// Changesets from peers whose commit lag was sampled
+    pub lag_samples: u64,
+    pub lag_avg_secs: Option<f64>,
+    pub lag_max_secs: Option<f64>,
+}
+
+impl History {
+    pub fn record_write(&self, changes: u64) {
+        self.0.writes.fetch_add(1, Ordering::Relaxed);
+        self.0.local_changes.fetch_add(changes, Ordering::Relaxed);
+    }
+
+    /// Records changes applied from a peer, with the time elapsed since the
+    /// peer committed them if known
+    pub fn record_applied(&self, changes: u64, lag: Option<Duration>) {
+        self.0.applied_changes.fetch_add(changes, Ordering::Relaxed);
+        if let Some(lag) = lag {
+            let lag = lag.as_secs_f64();
+            let mut samples = self.0.lag.lock();
+            samples.count += 1;
+            samples.sum += lag;
+            samples.max = samples.max.max(lag);
+        }
+    }
+
+    /// Takes the counts accumulated so far, starting a new bucket
+    pub fn take(&self) -> MinuteBucket {
+        let lag = std::mem::take(&mut *self.0.lag.lock());
+        MinuteBucket {
+            writes: self.0.writes.swap(0, Ordering::Relaxed),
+            local_changes: self.0.local_changes.swap(0, Ordering::Relaxed),
+            applied_changes: self.0.applied_changes.swap(0, Ordering::Relaxed),
+            lag_samples: lag.count,
+            lag_avg_secs: (lag.count > 0).then(|| lag.sum / lag.count as f64),
+            lag_max_secs: (lag.count > 0).then_some(lag.max),
+        }
+    }
+}
+
+/// Created along with corrosion's own tables when the agent starts, if the
+/// history is enabled
+pub fn create_history_table(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!(
+        "
+        CREATE TABLE IF NOT EXISTS {HISTORY_TABLE} (
+            -- unix timestamp of the start of the minute
+            minute INTEGER NOT NULL PRIMARY KEY,
+            writes INTEGER NOT NULL,
+            local_changes INTEGER NOT NULL,
+            applied_changes INTEGER NOT NULL,
+            lag_samples INTEGER NOT NULL,
+            lag_avg_secs REAL,
+            lag_max_secs REAL
+        ) WITHOUT ROWID;
+        "
+    ))
+}
+
+impl MinuteBucket {
+    /// Records the bucket for `minute`, dropping buckets older than
+    /// `retention`
+    pub fn insert(
+        &self,
+        conn: &Connection,
+        minute: i64,
+        retention: Duration,
+    ) -> rusqlite::Result<()> {
+        conn.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO {HISTORY_TABLE} VALUES (?, ?, ?, ?, ?, ?, ?)"
+        ))?
+        .execute(params![
+            minute,
+            self.writes,
+            self.local_changes,
+            self.applied_changes,
+            self.lag_samples,
+            self.lag_avg_secs,
+            self.lag_max_secs,
+        ])?;
+
+        conn.prepare_cached(&format!("DELETE FROM {HISTORY_TABLE} WHERE minute < ?"))?
+            .execute([minute - retention.as_secs() as i64])?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() -> rusqlite::Result<()> {
+        let history = History::default();
+        history.record_write(3);
+        history.record_write(2);
+        history.record_applied(4, Some(Duration::from_secs(1)));
+        history.record_applied(1, Some(Duration::from_secs(3)));
+        history.record_applied(6, None);
+
+        let bucket = history.take();
+        assert_eq!(
+            bucket,
+            MinuteBucket {
+                writes: 2,
+                local_changes: 5,
+                applied_changes: 11,
+                lag_samples: 2,
+                lag_avg_secs: Some(2.0),
+                lag_max_secs: Some(3.0),
+            }
+        );
+        assert_eq!(history.take(), MinuteBucket::default());
+
+        let conn = Connection::open_in_memory()?;
+        create_history_table(&conn)?;
+
+        let retention = Duration::from_secs(120);
+        bucket.insert(&conn, 0, retention)?;
+        MinuteBucket::default().insert(&conn, 60, retention)?;
+        MinuteBucket::default().insert(&conn, 180, retention)?;
+
+        let minutes: Vec<i64> = conn
+            .prepare(&format!(
+                "SELECT minute FROM {HISTORY_TABLE} ORDER BY minute"
+            ))?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(minutes, vec![60, 180]);
+
+        Ok(())
+    }
+}
@@ -1,15 +1,34 @@
 #![allow(clippy::manual_slice_size_calculation, clippy::collapsible_match)]
+pub mod activity;
 pub mod actor;
 pub mod agent;
 pub mod api;
+pub mod behind;
+pub mod bookkeeping;
 pub mod broadcast;
+pub mod budget;
+pub mod causality;
 pub mod change;
 pub mod channel;
+pub mod clock;
 pub mod config;
+pub mod digest;
+pub mod error;
+pub mod fence;
+pub mod gaps;
+pub mod history;
+pub mod load;
 pub mod members;
+pub mod pagination;
+pub mod prepared;
 pub mod pubsub;
+pub mod replay;
 pub mod schema;
 pub mod sqlite;
+pub mod statements;
 pub mod sync;
 pub mod tls;
+pub mod tokens;
+pub mod validation;
+pub mod watches;
 pub use corro_base_types as base;
@@ -0,0 +1,179 @@
+//! Pressure on the node, from how late its scheduler runs tasks, how many
+//! writes wait for the write connection and how many changes wait to be
+//! applied. Past a threshold, the API sheds its lowest priority requests
+//! first (watch creation, then queries, then writes) so the node stays
+//! responsive instead of degrading unpredictably.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::config::LoadSheddingConfig;
+
+/// Weight of a new sample in the scheduler delay's moving average
+const DELAY_SMOOTHING: f64 = 0.2;
+
+/// Requests that can be shed, from the first to go to the last
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestPriority {
+    /// Subscriptions and watches, clients can resume them later
+    Watch,
+    Query,
+    Write,
+}
+
+impl RequestPriority {
+    /// Pressure at which requests of this priority are shed
+    fn max_pressure(self) -> f64 {
+        match self {
+            RequestPriority::Watch => 1.0,
+            RequestPriority::Query => 1.5,
+            RequestPriority::Write => 2.0,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RequestPriority::Watch => "watch",
+            RequestPriority::Query => "query",
+            RequestPriority::Write => "write",
+        }
+    }
+}
+
+/// Pressure from the most loaded signal, relative to its threshold: `1.0`
+/// means a signal reached its threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pressure {
+    pub value: f64,
+    /// Signal the pressure comes from
+    pub signal: &'static str,
+}
+
+impl Pressure {
+    pub fn sheds(&self, priority: RequestPriority) -> bool {
+        self.value >= priority.max_pressure()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadMonitor(Arc<LoadSignals>);
+
+#[derive(Debug, Default)]
+struct LoadSignals {
+    /// Moving average of the scheduler delay, in microseconds
+    scheduler_delay_us: AtomicU64,
+    apply_backlog: AtomicUsize,
+}
+
+impl LoadMonitor {
+    /// Records how late a task woke up compared to when it should have
+    pub fn record_scheduler_delay(&self, delay: Duration) {
+        let sample = delay.as_micros() as f64;
+        // a single writer updates it, no need for a compare and swap
+        let avg = self.0.scheduler_delay_us.load(Ordering::Relaxed) as f64;
+        let avg = avg + DELAY_SMOOTHING * (sample - avg);
+        self.0
+            .scheduler_delay_us
+            .store(avg as u64, Ordering::Relaxed);
+    }
+
+    pub fn scheduler_delay(&self) -> Duration {
+        Duration::from_micros(self.0.scheduler_delay_us.load(Ordering::Relaxed))
+    }
+
+    /// Records the number of changes waiting to be applied
+    pub fn record_apply_backlog(&self, changes: usize) {
+        self.0.apply_backlog.store(changes, Ordering::Relaxed);
+    }
+
+    pub fn apply_backlog(&self) -> usize {
+        self.0.apply_backlog.load(Ordering::Relaxed)
+    }
+
+    /// Current pressure, given the number of writes waiting for the write
+    /// connection, read from the pool when asked
+    pub fn pressure(&self, write_queue: usize, config: &LoadSheddingConfig) -> Pressure {
+        let ratio = |value: f64, max: f64| if max > 0.0 { value / max } else { 0.0 };
+
+        [
+            (
+                ratio(
+                    self.scheduler_delay().as_secs_f64() * 1000.0,
+                    config.max_scheduler_delay_ms as f64,
+                ),
+                "scheduler_delay",
+            ),
+            (
+                ratio(write_queue as f64, config.max_write_queue as f64),
+                "write_queue",
+            ),
+            (
+                ratio(self.apply_backlog() as f64, config.max_apply_backlog as f64),
+                "apply_backlog",
+            ),
+        ]
+        .into_iter()
+        .map(|(value, signal)| Pressure { value, signal })
+        .fold(
+            Pressure {
+                value: 0.0,
+                signal: "none",
+            },
+            |max, pressure| {
+                if pressure.value > max.value {
+                    pressure
+                } else {
+                    max
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pressure() {
+        let config = LoadSheddingConfig {
+            enabled: true,
+            max_scheduler_delay_ms: 100,
+            max_write_queue: 10,
+            max_apply_backlog: 1000,
+        };
+        let monitor = LoadMonitor::default();
+
+        let pressure = monitor.pressure(0, &config);
+        assert_eq!(pressure.signal, "none");
+        assert!(!pressure.sheds(RequestPriority::Watch));
+
+        // watches go first
+        let pressure = monitor.pressure(12, &config);
+        assert_eq!(pressure.signal, "write_queue");
+        assert!(pressure.sheds(RequestPriority::Watch));
+        assert!(!pressure.sheds(RequestPriority::Query));
+
+        monitor.record_apply_backlog(1600);
+        let pressure = monitor.pressure(12, &config);
+        assert_eq!(pressure.signal, "apply_backlog");
+        assert!(pressure.sheds(RequestPriority::Query));
+        assert!(!pressure.sheds(RequestPriority::Write));
+
+        // a single late wake up is smoothed out
+        monitor.record_apply_backlog(0);
+        monitor.record_scheduler_delay(Duration::from_millis(500));
+        assert_eq!(monitor.scheduler_delay(), Duration::from_millis(100));
+        for _ in 0..20 {
+            monitor.record_scheduler_delay(Duration::from_millis(500));
+        }
+        let pressure = monitor.pressure(0, &config);
+        assert_eq!(pressure.signal, "scheduler_delay");
+        assert!(pressure.sheds(RequestPriority::Write));
+    }
+}
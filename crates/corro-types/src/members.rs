@@ -45,6 +45,8 @@ pub struct Members {
     pub states: BTreeMap<ActorId, MemberState>,
     pub by_addr: BTreeMap<SocketAddr, ActorId>,
     pub rtts: BTreeMap<SocketAddr, Rtt>,
+    /// Tables whose schema differs from ours, as of the last sync with each member
+    pub schema_drift: BTreeMap<ActorId, Vec<String>>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -114,6 +116,7 @@ impl Members {
         if effectively_down {
             self.by_addr.remove(&actor.addr());
             self.states.remove(&actor.id());
+            self.schema_drift.remove(&actor.id());
         }
 
         effectively_down
@@ -0,0 +1,405 @@
+//! Keyset pagination of queries reading from a single table: pages are
+//! ordered by the table's primary key, and continue after the primary key of
+//! the last row of the previous page instead of skipping rows with OFFSET.
+//!
+//! Queries can also be given a deterministic order without being paginated,
+//! since nodes may return rows in different orders without an ORDER BY.
+
+use corro_api_types::SqliteValue;
+use enquote::unquote;
+use sqlite3_parser::{
+    ast::{As, Cmd, Expr, Name, OneSelect, Operator, ResultColumn, Select, SelectTable, Stmt},
+    lexer::sql::Parser,
+};
+
+use crate::schema::Schema;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PaginationError {
+    #[error(transparent)]
+    Lexer(#[from] sqlite3_parser::lexer::sql::Error),
+    #[error("pagination requires a single SELECT from one table, without DISTINCT, GROUP BY, ORDER BY, LIMIT or compound")]
+    UnsupportedStatement,
+    #[error("table '{0}' is not in the schema")]
+    UnknownTable(String),
+    #[error("invalid cursor")]
+    InvalidCursor,
+    #[error("rows selected with `*` can only be ordered from tables of the schema, without DISTINCT, GROUP BY or compound")]
+    Unorderable,
+}
+
+/// Query rewritten to fetch a page
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub sql: String,
+    /// Number of primary key columns appended to the query's own columns
+    pub pk_len: usize,
+    pub size: usize,
+}
+
+/// Opaque cursor pointing after the row with these primary key values
+pub fn encode_cursor(pks: &[SqliteValue]) -> String {
+    hex::encode(serde_json::to_vec(pks).expect("could not serialize cursor"))
+}
+
+pub fn decode_cursor(cursor: &str) -> Result<Vec<SqliteValue>, PaginationError> {
+    let buf = hex::decode(cursor).map_err(|_| PaginationError::InvalidCursor)?;
+    serde_json::from_slice(&buf).map_err(|_| PaginationError::InvalidCursor)
+}
+
+fn literal(value: &SqliteValue) -> String {
+    match value {
+        SqliteValue::Null => "NULL".into(),
+        SqliteValue::Integer(i) => i.to_string(),
+        // sqlite reads overflowing literals as infinity and stores NaN as NULL
+        SqliteValue::Real(r) if r.0.is_nan() => "NULL".into(),
+        SqliteValue::Real(r) if r.0 == f64::INFINITY => "9e999".into(),
+        SqliteValue::Real(r) if r.0 == f64::NEG_INFINITY => "-9e999".into(),
+        SqliteValue::Real(r) => format!("{:?}", r.0),
+        SqliteValue::Text(t) => format!("'{}'", t.replace('\'', "''")),
+        SqliteValue::Blob(b) => format!("X'{}'", hex::encode(b.as_slice())),
+    }
+}
+
+fn parse_select(sql: &str) -> Result<Select, PaginationError> {
+    let mut parser = Parser::new(sql.as_bytes());
+    let select = match parser.next()? {
+        Some(Cmd::Stmt(Stmt::Select(select))) => select,
+        _ => return Err(PaginationError::UnsupportedStatement),
+    };
+    if parser.next()?.is_some() {
+        return Err(PaginationError::UnsupportedStatement);
+    }
+    Ok(select)
+}
+
+/// Rewrites a query to return `size` rows ordered by primary key, after the
+/// primary key of the `after` cursor. The primary key of each row is
+/// appended to its columns, to build the cursor of the next page.
+pub fn paginate(
+    sql: &str,
+    schema: &Schema,
+    after: Option<&[SqliteValue]>,
+    size: usize,
+) -> Result<Page, PaginationError> {
+    let mut select = parse_select(sql)?;
+
+    if select.body.compounds.is_some() || select.order_by.is_some() || select.limit.is_some() {
+        return Err(PaginationError::UnsupportedStatement);
+    }
+    let OneSelect::Select {
+        columns,
+        from: Some(from),
+        distinctness: None,
+        where_clause,
+        group_by: None,
+        ..
+    } = &mut select.body.select
+    else {
+        return Err(PaginationError::UnsupportedStatement);
+    };
+    if from.joins.as_ref().map_or(false, |joins| !joins.is_empty()) {
+        return Err(PaginationError::UnsupportedStatement);
+    }
+    let Some(SelectTable::Table(name, alias, _)) = from.select.as_deref() else {
+        return Err(PaginationError::UnsupportedStatement);
+    };
+
+    let tbl_name = unquote(&name.name.0).unwrap_or_else(|_| name.name.0.clone());
+    let table = schema
+        .tables
+        .get(&tbl_name)
+        .ok_or_else(|| PaginationError::UnknownTable(tbl_name.clone()))?;
+    let qualifier = match alias {
+        Some(As::As(alias) | As::Elided(alias)) => alias.0.clone(),
+        None => name.name.0.clone(),
+    };
+
+    let pk_cols: Vec<String> = table
+        .pk
+        .iter()
+        .map(|pk| format!("{qualifier}.\"{}\"", pk.replace('"', "\"\"")))
+        .collect();
+    for pk in table.pk.iter() {
+        columns.push(ResultColumn::Expr(
+            Expr::Qualified(Name(qualifier.clone()), Name(pk.clone())),
+            None,
+        ));
+    }
+
+    // parsed from text so values are rendered the way sqlite reads them
+    let cond = match after {
+        Some(after) => {
+            if after.len() != pk_cols.len() {
+                return Err(PaginationError::InvalidCursor);
+            }
+            format!(
+                "WHERE ({}) > ({})",
+                pk_cols.join(", "),
+                after.iter().map(literal).collect::<Vec<_>>().join(", ")
+            )
+        }
+        None => String::new(),
+    };
+    let clauses = parse_select(&format!(
+        "SELECT 1 FROM {qualifier} {cond} ORDER BY {} LIMIT {size}",
+        pk_cols.join(", ")
+    ))?;
+
+    if let OneSelect::Select {
+        where_clause: Some(after_cond),
+        ..
+    } = clauses.body.select
+    {
+        *where_clause = Some(match where_clause.take() {
+            Some(prev) => Expr::Binary(
+                Box::new(Expr::parenthesized(prev)),
+                Operator::And,
+                Box::new(after_cond),
+            ),
+            None => after_cond,
+        });
+    }
+    select.order_by = clauses.order_by;
+    select.limit = clauses.limit;
+
+    let mut sql = Cmd::Stmt(Stmt::Select(select)).to_string();
+    sql.pop(); // remove trailing `;`
+
+    Ok(Page {
+        sql,
+        pk_len: table.pk.len(),
+        size,
+    })
+}
+
+/// Appends an ORDER BY to a query without one, so its rows come out in the
+/// same order on every node. Rows are ordered by the primary key of each
+/// table they're read from, or by all their columns when they're grouped,
+/// distinct or from a compound select. Queries already ordered are returned
+/// as is.
+pub fn order_deterministically(sql: &str, schema: &Schema) -> Result<String, PaginationError> {
+    let mut select = parse_select(sql)?;
+    if select.order_by.is_some() {
+        return Ok(sql.to_owned());
+    }
+
+    let terms = match pk_order(&select, schema) {
+        Some(terms) => terms,
+        None => {
+            let len = match &select.body.select {
+                OneSelect::Select { columns, .. } => {
+                    if columns
+                        .iter()
+                        .any(|col| !matches!(col, ResultColumn::Expr(..)))
+                    {
+                        return Err(PaginationError::Unorderable);
+                    }
+                    columns.len()
+                }
+                OneSelect::Values(values) => values.first().map_or(0, Vec::len),
+            };
+            (1..=len).map(|i| i.to_string()).collect()
+        }
+    };
+
+    let clauses = parse_select(&format!("SELECT 1 ORDER BY {}", terms.join(", ")))?;
+    select.order_by = clauses.order_by;
+
+    let mut sql = Cmd::Stmt(Stmt::Select(select)).to_string();
+    sql.pop(); // remove trailing `;`
+
+    Ok(sql)
+}
+
+/// Primary key columns of the tables a query reads from, if they identify
+/// its rows
+fn pk_order(select: &Select, schema: &Schema) -> Option<Vec<String>> {
+    if select.body.compounds.is_some() {
+        return None;
+    }
+    let OneSelect::Select {
+        from: Some(from),
+        distinctness: None,
+        group_by: None,
+        ..
+    } = &select.body.select
+    else {
+        return None;
+    };
+
+    let tables = from
+        .select
+        .iter()
+        .map(|table| table.as_ref())
+        .chain(from.joins.iter().flatten().map(|join| &join.table));
+
+    let mut terms = vec![];
+    for table in tables {
+        let SelectTable::Table(name, alias, _) = table else {
+            return None;
+        };
+        let tbl_name = unquote(&name.name.0).unwrap_or_else(|_| name.name.0.clone());
+        let table = schema.tables.get(&tbl_name)?;
+        let qualifier = match alias {
+            Some(As::As(alias) | As::Elided(alias)) => alias.0.clone(),
+            None => name.name.0.clone(),
+        };
+        terms.extend(
+            table
+                .pk
+                .iter()
+                .map(|pk| format!("{qualifier}.\"{}\"", pk.replace('"', "\"\""))),
+        );
+    }
+
+    (!terms.is_empty()).then_some(terms)
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_api_types::Real;
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::schema::parse_sql;
+
+    #[test]
+    fn test_paginate() -> Result<(), Box<dyn std::error::Error>> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE items (kind TEXT NOT NULL, id INTEGER NOT NULL, name TEXT, PRIMARY KEY (kind, id));
+             INSERT INTO items VALUES ('a', 1, 'one'), ('a', 2, 'two'), ('b', 1, 'three'), ('b', 2, NULL), ('c', 1, 'five');",
+        )?;
+        let schema = parse_sql(
+            "CREATE TABLE items (kind TEXT NOT NULL, id INTEGER NOT NULL, name TEXT, PRIMARY KEY (kind, id));",
+        )?;
+
+        let sql = "SELECT name FROM items i WHERE name IS NOT NULL";
+        let mut after = None;
+        let mut names = vec![];
+        loop {
+            let page = paginate(sql, &schema, after.as_deref(), 2)?;
+            assert_eq!(page.pk_len, 2);
+
+            let rows = conn
+                .prepare(&page.sql)?
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        vec![row.get::<_, SqliteValue>(1)?, row.get::<_, SqliteValue>(2)?],
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let full = rows.len() == page.size;
+            if let Some((_, pks)) = rows.last() {
+                let cursor = encode_cursor(pks);
+                after = Some(decode_cursor(&cursor)?);
+            }
+            names.extend(rows.into_iter().map(|(name, _)| name));
+            if !full {
+                break;
+            }
+        }
+        assert_eq!(names, vec!["one", "two", "three", "five"]);
+
+        assert!(matches!(
+            paginate("SELECT * FROM items ORDER BY id", &schema, None, 2),
+            Err(PaginationError::UnsupportedStatement)
+        ));
+        assert!(matches!(
+            paginate("SELECT * FROM nope", &schema, None, 2),
+            Err(PaginationError::UnknownTable(_))
+        ));
+        assert!(matches!(
+            paginate(sql, &schema, Some(&[SqliteValue::Integer(1)]), 2),
+            Err(PaginationError::InvalidCursor)
+        ));
+        assert!(matches!(
+            decode_cursor("not a cursor"),
+            Err(PaginationError::InvalidCursor)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal() -> Result<(), Box<dyn std::error::Error>> {
+        let conn = Connection::open_in_memory()?;
+
+        for value in [
+            SqliteValue::Null,
+            SqliteValue::Integer(i64::MIN),
+            SqliteValue::Real(Real(1.5)),
+            SqliteValue::Real(Real(1e300)),
+            SqliteValue::Real(Real(f64::INFINITY)),
+            SqliteValue::Real(Real(f64::NEG_INFINITY)),
+            SqliteValue::Text("it's".into()),
+            SqliteValue::Blob(vec![0, 255].into()),
+        ] {
+            let read: SqliteValue =
+                conn.query_row(&format!("SELECT {}", literal(&value)), [], |row| row.get(0))?;
+            assert_eq!(read, value);
+        }
+
+        let read: SqliteValue = conn.query_row(
+            &format!("SELECT {}", literal(&SqliteValue::Real(Real(f64::NAN)))),
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(read, SqliteValue::Null);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_deterministically() -> Result<(), Box<dyn std::error::Error>> {
+        let conn = Connection::open_in_memory()?;
+        let schema_sql = "CREATE TABLE items (kind TEXT NOT NULL, id INTEGER NOT NULL, name TEXT, PRIMARY KEY (kind, id));
+             CREATE TABLE tags (id INTEGER NOT NULL PRIMARY KEY, item_id INTEGER, tag TEXT);";
+        conn.execute_batch(schema_sql)?;
+        // inserted out of primary key order
+        conn.execute_batch(
+            "INSERT INTO items VALUES ('b', 2, 'four'), ('a', 2, 'two'), ('b', 1, 'three'), ('a', 1, 'one');
+             INSERT INTO tags VALUES (2, 1, 'red'), (1, 1, 'blue'), (3, 2, 'red');",
+        )?;
+        let schema = parse_sql(schema_sql)?;
+
+        let names = |sql: &str| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            let sql = order_deterministically(sql, &schema)?;
+            Ok(conn
+                .prepare(&sql)?
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?)
+        };
+
+        assert_eq!(
+            names("SELECT name FROM items WHERE name != 'x'")?,
+            vec!["one", "two", "three", "four"]
+        );
+        assert_eq!(
+            names(
+                "SELECT t.tag FROM items i JOIN tags AS t ON t.item_id = i.id WHERE i.kind = 'a'"
+            )?,
+            vec!["blue", "red", "red"]
+        );
+        // ordered by every column instead
+        assert_eq!(
+            names("SELECT DISTINCT tag FROM tags UNION SELECT kind FROM items")?,
+            vec!["a", "b", "blue", "red"]
+        );
+        // applies before the limit
+        assert_eq!(names("SELECT name FROM items LIMIT 1")?, vec!["one"]);
+
+        let sql = "SELECT name FROM items ORDER BY name DESC";
+        assert_eq!(order_deterministically(sql, &schema)?, sql);
+
+        assert!(matches!(
+            order_deterministically("SELECT DISTINCT * FROM tags", &schema),
+            Err(PaginationError::Unorderable)
+        ));
+
+        Ok(())
+    }
+}
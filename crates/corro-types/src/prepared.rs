@@ -0,0 +1,132 @@
+//! Statements registered under a name through the API, so clients run them
+//! by name with only their parameters and operators can audit which SQL
+//! they run. They're stored in a table replicated like the schema's, so
+//! statements registered on one node can be run on every other.
+
+use rusqlite::{named_params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::tokens::now;
+
+/// Internal table holding registered statements, its changes are applied
+/// without being part of the schema
+pub const STATEMENTS_TABLE: &str = "__corro_statements";
+
+/// Longest name a statement can be registered under
+const MAX_NAME_LEN: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NamedStatement {
+    pub name: String,
+    pub sql: String,
+    /// When it was registered, or last replaced
+    pub created_at: u64,
+}
+
+/// Names are made of letters, digits, `-` and `_`, so they can be used as
+/// is in paths
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Registers `sql` under `name`, replacing the statement previously
+/// registered under it
+pub fn register(conn: &Connection, name: &str, sql: &str) -> rusqlite::Result<NamedStatement> {
+    let statement = NamedStatement {
+        name: name.to_owned(),
+        sql: sql.to_owned(),
+        created_at: now(),
+    };
+
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO __corro_statements (name, sql, created_at)
+            VALUES (:name, :sql, :created_at)",
+    )?
+    .execute(named_params! {
+        ":name": statement.name,
+        ":sql": statement.sql,
+        ":created_at": statement.created_at,
+    })?;
+
+    Ok(statement)
+}
+
+pub fn list(conn: &Connection) -> rusqlite::Result<Vec<NamedStatement>> {
+    conn.prepare_cached("SELECT name, sql, created_at FROM __corro_statements ORDER BY name")?
+        .query_map([], |row| {
+            Ok(NamedStatement {
+                name: row.get(0)?,
+                sql: row.get(1)?,
+                created_at: row.get(2)?,
+            })
+        })?
+        .collect()
+}
+
+pub fn get(conn: &Connection, name: &str) -> rusqlite::Result<Option<NamedStatement>> {
+    conn.prepare_cached("SELECT name, sql, created_at FROM __corro_statements WHERE name = ?")?
+        .query_row([name], |row| {
+            Ok(NamedStatement {
+                name: row.get(0)?,
+                sql: row.get(1)?,
+                created_at: row.get(2)?,
+            })
+        })
+        .optional()
+}
+
+/// Returns `false` if there was no such statement
+pub fn remove(conn: &Connection, name: &str) -> rusqlite::Result<bool> {
+    Ok(conn
+        .prepare_cached("DELETE FROM __corro_statements WHERE name = ?")?
+        .execute([name])?
+        > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_statements() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE __corro_statements (
+                name TEXT NOT NULL PRIMARY KEY,
+                sql TEXT NOT NULL DEFAULT '',
+                created_at INTEGER NOT NULL DEFAULT 0
+            ) WITHOUT ROWID;",
+        )?;
+
+        register(&conn, "upsert_node", "INSERT INTO nodes (id) VALUES (?)")?;
+        register(&conn, "count_nodes", "SELECT count(*) FROM nodes")?;
+        // registering again replaces it
+        register(
+            &conn,
+            "upsert_node",
+            "INSERT OR REPLACE INTO nodes (id) VALUES (?)",
+        )?;
+
+        let names: Vec<String> = list(&conn)?.into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["count_nodes", "upsert_node"]);
+        assert_eq!(
+            get(&conn, "upsert_node")?.map(|s| s.sql),
+            Some("INSERT OR REPLACE INTO nodes (id) VALUES (?)".into())
+        );
+
+        assert!(remove(&conn, "count_nodes")?);
+        assert!(!remove(&conn, "count_nodes")?);
+        assert!(get(&conn, "count_nodes")?.is_none());
+
+        assert!(is_valid_name("upsert_node-v2"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("nodes/upsert"));
+        assert!(!is_valid_name(&"a".repeat(129)));
+
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
 use std::{
     cmp,
     collections::{BTreeMap, HashMap, HashSet},
-    sync::Arc,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -9,7 +13,8 @@ use bytes::{Buf, BufMut};
 use camino::{Utf8Path, Utf8PathBuf};
 use compact_str::{format_compact, ToCompactString};
 use corro_api_types::{
-    Change, ChangeId, ColumnName, ColumnType, RowId, SqliteValue, SqliteValueRef, TableName,
+    Change, ChangeId, CloseReason, ColumnName, ColumnType, RowId, SqliteValue, SqliteValueRef,
+    TableName,
 };
 use enquote::unquote;
 use fallible_iterator::FallibleIterator;
@@ -21,7 +26,8 @@ use rusqlite::{
     types::{FromSqlError, ValueRef},
     Connection, OptionalExtension,
 };
-use spawn::spawn_counted;
+use serde::{Deserialize, Serialize};
+use spawn::{spawn_named, Shutdown};
 use sqlite3_parser::{
     ast::{
         As, Cmd, Expr, FromClause, JoinConstraint, JoinOperator, JoinType, JoinedSelectTable, Name,
@@ -40,11 +46,12 @@ use tripwire::{Outcome, PreemptibleFutureExt, Tripwire};
 use uuid::Uuid;
 
 use crate::{
+    actor::ActorId,
     agent::SplitPool,
     api::QueryEvent,
     base::CrsqlDbVersion,
     schema::{Schema, Table},
-    sqlite::CrConn,
+    sqlite::{rusqlite_to_crsqlite, CrConn},
 };
 
 pub use corro_api_types::sqlite::ChangeType;
@@ -52,10 +59,44 @@ pub use corro_api_types::sqlite::ChangeType;
 #[derive(Debug, Default, Clone)]
 pub struct SubsManager(Arc<RwLock<InnerSubsManager>>);
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct InnerSubsManager {
     handles: BTreeMap<Uuid, MatcherHandle>,
     queries: HashMap<String, Uuid>,
+    max_count: Option<usize>,
+    /// Address and id every subscription handed off so far moved to
+    handoff: watch::Sender<HashMap<Uuid, (SocketAddr, Uuid)>>,
+}
+
+impl Default for InnerSubsManager {
+    fn default() -> Self {
+        Self {
+            handles: BTreeMap::new(),
+            queries: HashMap::new(),
+            max_count: None,
+            handoff: watch::channel(HashMap::new()).0,
+        }
+    }
+}
+
+/// What's needed to create a subscription again, on another node or after
+/// its state was lost
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubDefinition {
+    pub id: Uuid,
+    pub sql: String,
+    /// Created with an id derived from its SQL, see [`sql_id`]
+    #[serde(default)]
+    pub by_hash: bool,
+}
+
+/// Subscriptions of a draining node, created again on another node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handoff {
+    /// API address of the node taking over
+    pub addr: SocketAddr,
+    /// Id of each subscription on that node, by its id on this one
+    pub ids: HashMap<Uuid, Uuid>,
 }
 
 // tools to bootstrap a new subscriber
@@ -66,6 +107,14 @@ pub struct MatcherCreated {
 const SUB_EVENT_CHANNEL_CAP: usize = 512;
 
 impl SubsManager {
+    /// Refuses new subscriptions once `max_count` of them are running
+    pub fn with_max_count(max_count: Option<usize>) -> Self {
+        Self(Arc::new(RwLock::new(InnerSubsManager {
+            max_count,
+            ..Default::default()
+        })))
+    }
+
     pub fn get(&self, id: &Uuid) -> Option<MatcherHandle> {
         self.0.read().get(id)
     }
@@ -91,35 +140,87 @@ impl SubsManager {
             return Ok((handle, None));
         }
 
-        let id = Uuid::new_v4();
-        let (evt_tx, evt_rx) = mpsc::channel(SUB_EVENT_CHANNEL_CAP);
+        inner.create(Uuid::new_v4(), sql, subs_path, schema, pool, tripwire, None)
+    }
 
-        let handle_res = Matcher::create(
-            id,
-            subs_path.to_path_buf(),
-            schema,
-            pool.client_dedicated()?,
-            evt_tx,
-            sql,
-            tripwire,
-        );
+    /// Like [`SubsManager::get_or_insert`], but the subscription's id is
+    /// derived from its normalized SQL (see [`sql_id`]): clients subscribing
+    /// to the same query, however it's formatted, all get the same
+    /// subscription, on any node.
+    pub fn get_or_insert_by_hash(
+        &self,
+        sql: &str,
+        subs_path: &Utf8Path,
+        schema: &Schema,
+        pool: &SplitPool,
+        tripwire: Tripwire,
+    ) -> Result<(MatcherHandle, Option<MatcherCreated>), MatcherError> {
+        let sql = normalize_sql(sql)?;
+        let id = sql_id(&sql);
 
-        let handle = match handle_res {
-            Ok(handle) => handle,
-            Err(e) => {
-                error!(sub_id = %id, "could not create subscription: {e}");
-                if let Err(e) = Matcher::cleanup(id, Matcher::sub_path(subs_path, id)) {
-                    error!("could not cleanup subscription: {e}");
-                }
+        if let Some(handle) = self.get(&id) {
+            return Ok((handle, None));
+        }
 
-                return Err(e);
+        let mut inner = self.0.write();
+        if let Some(handle) = inner.get(&id) {
+            return Ok((handle, None));
+        }
+
+        inner.create(id, &sql, subs_path, schema, pool, tripwire, None)
+    }
+
+    /// Creates a new subscription for each query, all hydrated from the same
+    /// snapshot of the database so their initial rows are consistent with
+    /// each other. Existing subscriptions to the same queries are not reused,
+    /// they were hydrated at another point in time.
+    pub fn insert_group(
+        &self,
+        sqls: &[String],
+        subs_path: &Utf8Path,
+        schema: &Schema,
+        pool: &SplitPool,
+        tripwire: Tripwire,
+    ) -> Result<Vec<(MatcherHandle, MatcherCreated)>, MatcherError> {
+        if sqls.is_empty() {
+            return Err(MatcherError::StatementRequired);
+        }
+
+        let mut inner = self.0.write();
+        if let Some(max_count) = inner.max_count {
+            if inner.handles.len() + sqls.len() > max_count {
+                return Err(MatcherError::TooManySubscriptions(max_count));
             }
-        };
+        }
 
-        inner.handles.insert(id, handle.clone());
-        inner.queries.insert(sql.to_owned(), id);
+        let snapshot = Arc::new(GroupSnapshot::open(pool.client_dedicated()?)?);
+
+        let mut group = Vec::with_capacity(sqls.len());
+        for sql in sqls {
+            let res = inner.create(
+                Uuid::new_v4(),
+                sql,
+                subs_path,
+                schema,
+                pool,
+                tripwire.clone(),
+                Some(snapshot.clone()),
+            );
+            match res {
+                Ok((handle, Some(created))) => group.push((handle, created)),
+                Ok((_, None)) => unreachable!("subscriptions are always created"),
+                Err(e) => {
+                    // all or nothing
+                    for (handle, _) in group {
+                        inner.remove(&handle.id());
+                        spawn_named("matcher_cleanup", Shutdown::Graceful, handle.cleanup());
+                    }
+                    return Err(e);
+                }
+            }
+        }
 
-        Ok((handle, Some(MatcherCreated { evt_rx })))
+        Ok(group)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -149,7 +250,7 @@ impl SubsManager {
         )?;
 
         inner.handles.insert(id, handle.clone());
-        inner.queries.insert(handle.inner.sql.clone(), id);
+        inner.queries.entry(handle.inner.sql.clone()).or_insert(id);
 
         Ok((handle, MatcherCreated { evt_rx }))
     }
@@ -159,6 +260,83 @@ impl SubsManager {
         inner.remove(id)
     }
 
+    /// Hands subscriptions off to another node: they end with a `moved`
+    /// event telling their listeners where to reconnect. Subscriptions
+    /// handed off before keep pointing where they moved to. Returns how many
+    /// running subscriptions are moved.
+    pub fn hand_off(&self, handoff: Handoff) -> usize {
+        let inner = self.0.read();
+        let moved = handoff
+            .ids
+            .keys()
+            .filter(|id| inner.handles.contains_key(id))
+            .count();
+        inner.handoff.send_modify(|handed_off| {
+            handed_off.extend(
+                handoff
+                    .ids
+                    .iter()
+                    .map(|(id, new_id)| (*id, (handoff.addr, *new_id))),
+            );
+        });
+        moved
+    }
+
+    /// Notified of hand-offs, with where each subscription handed off so far
+    /// moved to, see [`SubsManager::hand_off`]
+    pub fn handoff(&self) -> watch::Receiver<HashMap<Uuid, (SocketAddr, Uuid)>> {
+        self.0.read().handoff.subscribe()
+    }
+
+    /// Where subscription `id` was handed off to, if it was
+    pub fn moved(&self, id: &Uuid) -> Option<(SocketAddr, Uuid)> {
+        self.0.read().handoff.borrow().get(id).copied()
+    }
+
+    /// Ends the subscriptions reading from `table`, for when it's dropped:
+    /// their listeners get a `closed` event with the `table_dropped` reason.
+    /// Returns how many subscriptions ended.
+    pub fn close_table(&self, table: &str) -> usize {
+        let handles = {
+            let mut inner = self.0.write();
+            let ids = inner
+                .handles
+                .values()
+                .filter(|handle| handle.table_names().any(|name| name == table))
+                .map(MatcherHandle::id)
+                .collect::<Vec<_>>();
+            ids.iter()
+                .filter_map(|id| inner.remove(id))
+                .collect::<Vec<_>>()
+        };
+
+        for handle in handles.iter() {
+            info!(sub_id = %handle.id(), "Closing subscription, table '{table}' was dropped");
+            handle.close(CloseReason::TableDropped);
+        }
+
+        handles.len()
+    }
+
+    /// Definitions of every running subscription
+    pub fn definitions(&self) -> Vec<SubDefinition> {
+        self.0
+            .read()
+            .handles
+            .values()
+            .map(|handle| {
+                let by_hash = normalize_sql(&handle.inner.sql)
+                    .map(|sql| sql_id(&sql) == handle.id())
+                    .unwrap_or(false);
+                SubDefinition {
+                    id: handle.id(),
+                    sql: handle.inner.sql.clone(),
+                    by_hash,
+                }
+            })
+            .collect()
+    }
+
     pub fn match_changes(&self, changes: &[Change], db_version: CrsqlDbVersion) {
         trace!(
             %db_version,
@@ -218,6 +396,7 @@ struct MatchableChange<'a> {
     table: &'a TableName,
     pk: &'a [u8],
     column: &'a ColumnName,
+    actor_id: ActorId,
 }
 
 impl<'a> From<&'a Change> for MatchableChange<'a> {
@@ -226,6 +405,7 @@ impl<'a> From<&'a Change> for MatchableChange<'a> {
             table: &value.table,
             pk: &value.pk,
             column: &value.cid,
+            actor_id: ActorId::from_bytes(value.site_id),
         }
     }
 }
@@ -241,9 +421,61 @@ impl InnerSubsManager {
             .and_then(|id| self.handles.get(id).cloned())
     }
 
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        &mut self,
+        id: Uuid,
+        sql: &str,
+        subs_path: &Utf8Path,
+        schema: &Schema,
+        pool: &SplitPool,
+        tripwire: Tripwire,
+        snapshot: Option<Arc<GroupSnapshot>>,
+    ) -> Result<(MatcherHandle, Option<MatcherCreated>), MatcherError> {
+        if let Some(max_count) = self.max_count {
+            if self.handles.len() >= max_count {
+                return Err(MatcherError::TooManySubscriptions(max_count));
+            }
+        }
+
+        let (evt_tx, evt_rx) = mpsc::channel(SUB_EVENT_CHANNEL_CAP);
+
+        let handle_res = Matcher::create(
+            id,
+            subs_path.to_path_buf(),
+            schema,
+            pool.client_dedicated()?,
+            evt_tx,
+            sql,
+            tripwire,
+            snapshot,
+        );
+
+        let handle = match handle_res {
+            Ok(handle) => handle,
+            Err(e) => {
+                error!(sub_id = %id, "could not create subscription: {e}");
+                if let Err(e) = Matcher::cleanup(id, Matcher::sub_path(subs_path, id)) {
+                    error!("could not cleanup subscription: {e}");
+                }
+
+                return Err(e);
+            }
+        };
+
+        self.handles.insert(id, handle.clone());
+        // a subscription by hash can share its query with a regular one,
+        // the latter keeps being the one found by query
+        self.queries.entry(sql.to_owned()).or_insert(id);
+
+        Ok((handle, Some(MatcherCreated { evt_rx })))
+    }
+
     fn remove(&mut self, id: &Uuid) -> Option<MatcherHandle> {
         let handle = self.handles.remove(id)?;
-        self.queries.remove(&handle.inner.sql);
+        if self.queries.get(&handle.inner.sql) == Some(id) {
+            self.queries.remove(&handle.inner.sql);
+        }
         Some(handle)
     }
 }
@@ -274,18 +506,64 @@ struct InnerMatcherHandle {
     pool: sqlite_pool::RusqlitePool,
     parsed: ParsedSelect,
     col_names: Vec<ColumnName>,
+    pks: IndexMap<String, Vec<String>>,
     cancel: CancellationToken,
+    /// Why the subscription was cancelled, `deleted` unless set
+    close_reason: Mutex<Option<CloseReason>>,
     changes_tx: mpsc::Sender<(MatchCandidates, CrsqlDbVersion)>,
     last_change_rx: watch::Receiver<ChangeId>,
+    keep_previous: Arc<AtomicBool>,
+}
+
+/// Primary keys of the rows changed, by table, with the actor the change
+/// originated from
+type MatchCandidates = IndexMap<TableName, IndexMap<Vec<u8>, ActorId>>;
+
+/// Read transaction pinned on a connection, the subscriptions of a group run
+/// their initial query on it one after the other. It's released once the last
+/// of them is done.
+pub struct GroupSnapshot(Mutex<CrConn>);
+
+impl GroupSnapshot {
+    fn open(conn: CrConn) -> rusqlite::Result<Self> {
+        // a deferred transaction only takes its snapshot on the first read
+        conn.execute_batch("BEGIN DEFERRED")?;
+        conn.query_row("SELECT COUNT(*) FROM sqlite_schema", [], |_row| Ok(()))?;
+        Ok(Self(Mutex::new(conn)))
+    }
+}
+
+impl Drop for GroupSnapshot {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.get_mut().execute_batch("ROLLBACK") {
+            warn!("could not rollback group snapshot transaction: {e}");
+        }
+    }
 }
 
-type MatchCandidates = IndexMap<TableName, IndexSet<Vec<u8>>>;
+impl std::fmt::Debug for GroupSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GroupSnapshot").finish_non_exhaustive()
+    }
+}
 
 impl MatcherHandle {
     pub fn id(&self) -> Uuid {
         self.inner.id
     }
 
+    /// Records the values updated rows had before from now on, for
+    /// subscribers asking for them. It stays on for the rest of the
+    /// subscription's life.
+    pub fn keep_previous(&self) {
+        self.inner.keep_previous.store(true, Ordering::Relaxed);
+    }
+
+    /// Short hash of the subscription's SQL, a friendlier name than its id
+    pub fn hash(&self) -> &str {
+        &self.inner.hash
+    }
+
     pub fn parsed_columns(&self) -> &[ResultColumn] {
         &self.inner.parsed.columns
     }
@@ -294,11 +572,27 @@ impl MatcherHandle {
         &self.inner.col_names
     }
 
+    /// Tables the subscription's query reads from
+    pub fn table_names(&self) -> impl Iterator<Item = &str> {
+        self.inner.parsed.table_columns.keys().map(String::as_str)
+    }
+
     pub async fn cleanup(self) {
         self.inner.cancel.cancel();
         info!(sub_id = %self.inner.id, "Canceled subscription");
     }
 
+    /// Cancels the subscription, its listeners are told why
+    pub fn close(&self, reason: CloseReason) {
+        *self.inner.close_reason.lock() = Some(reason);
+        self.inner.cancel.cancel();
+    }
+
+    /// Why the subscription was cancelled, if it was closed with a reason
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        *self.inner.close_reason.lock()
+    }
+
     pub fn pool(&self) -> &RusqlitePool {
         &self.inner.pool
     }
@@ -321,6 +615,14 @@ impl MatcherHandle {
         prepped.query_row([], |row| row.get(0))
     }
 
+    /// Oldest change kept, older ones are purged: resuming from before it
+    /// would miss changes
+    pub fn min_change_id(&self, conn: &Connection) -> rusqlite::Result<Option<ChangeId>> {
+        self.wait_for_running_state();
+        let mut prepped = conn.prepare_cached("SELECT MIN(id) FROM changes")?;
+        prepped.query_row([], |row| row.get(0))
+    }
+
     pub fn last_change_id_sent(&self) -> ChangeId {
         *self.inner.last_change_rx.borrow()
     }
@@ -332,6 +634,43 @@ impl MatcherHandle {
         prepped.query_row([], |row| row.get(0))
     }
 
+    /// Primary keys, per table, of the rows that make up a query row
+    pub fn row_pks(
+        &self,
+        conn: &Connection,
+        rowid: RowId,
+    ) -> rusqlite::Result<Vec<(String, Vec<SqliteValue>)>> {
+        let aliases = self
+            .inner
+            .pks
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut prepped = conn.prepare_cached(&format!(
+            "SELECT {} FROM query WHERE __corro_rowid = ?",
+            aliases.join(",")
+        ))?;
+
+        let Some(mut values) = prepped
+            .query_row([rowid], |row| {
+                (0..aliases.len())
+                    .map(|i| row.get::<_, SqliteValue>(i))
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .optional()?
+        else {
+            return Ok(vec![]);
+        };
+
+        Ok(self
+            .inner
+            .pks
+            .iter()
+            .map(|(table, pks)| (table.clone(), values.drain(..pks.len()).collect()))
+            .collect())
+    }
+
     pub fn changes_since(
         &self,
         since: ChangeId,
@@ -344,7 +683,7 @@ impl MatcherHandle {
             query_cols.push(format!("col_{i}"));
         }
         let mut prepped = conn.prepare_cached(&format!(
-            "SELECT id, type, __corro_rowid, {} FROM changes WHERE id > ? ORDER BY id ASC",
+            "SELECT id, type, __corro_rowid, {ACTOR_COL}, {} FROM changes WHERE id > ? ORDER BY id ASC",
             query_cols.join(",")
         ))?;
 
@@ -368,10 +707,12 @@ impl MatcherHandle {
             if let Err(e) = tx.blocking_send(QueryEvent::Change(
                 row.get(1)?,
                 row.get(2)?,
-                (3..col_count)
+                (4..col_count)
                     .map(|i| row.get::<_, SqliteValue>(i))
                     .collect::<rusqlite::Result<Vec<_>>>()?,
                 change_id,
+                row.get::<_, Option<ActorId>>(3)?
+                    .map(|actor_id| actor_id.to_compact_string()),
             )) {
                 error!("could not send change to channel: {e}");
                 break;
@@ -381,16 +722,57 @@ impl MatcherHandle {
         Ok(max_change_id)
     }
 
+    /// Values of the row before an update, `None` for other changes
+    pub fn previous_values(
+        &self,
+        conn: &Connection,
+        change_id: ChangeId,
+    ) -> Result<Option<Vec<SqliteValue>>, MatcherError> {
+        self.wait_for_running_state();
+        let old: Option<Vec<u8>> = conn
+            .prepare_cached(&format!(
+                "SELECT {PREVIOUS_COL} FROM changes WHERE {CHANGE_ID_COL} = ?"
+            ))?
+            .query_row([change_id], |row| row.get(0))
+            .optional()?
+            .flatten();
+
+        match old {
+            Some(old) => Ok(Some(
+                unpack_columns(&old)?
+                    .iter()
+                    .map(SqliteValueRef::to_owned)
+                    .collect(),
+            )),
+            None => Ok(None),
+        }
+    }
+
     pub fn all_rows(
         &self,
         conn: &Connection,
-        tx: mpsc::Sender<QueryEvent>,
+        evt_tx: mpsc::Sender<QueryEvent>,
     ) -> Result<ChangeId, MatcherError> {
         self.wait_for_running_state();
         let mut query_cols = vec![];
         for i in 0..(self.parsed_columns().len()) {
             query_cols.push(format!("col_{i}"));
         }
+
+        // rows and the last change id must come from the same snapshot: a
+        // change committed in between would be neither in the rows nor after
+        // the change id subscribers resume from. A transaction the caller
+        // already opened is one.
+        let tx = if conn.is_autocommit() {
+            Some(conn.unchecked_transaction()?)
+        } else {
+            None
+        };
+
+        let max_change_id: ChangeId = conn
+            .prepare_cached("SELECT COALESCE(MAX(id),0) FROM changes")?
+            .query_row([], |row| row.get(0))?;
+
         let mut prepped = conn.prepare_cached(&format!(
             "SELECT __corro_rowid, {} FROM query",
             query_cols.join(",")
@@ -398,7 +780,8 @@ impl MatcherHandle {
 
         let col_count = prepped.column_count();
 
-        tx.blocking_send(QueryEvent::Columns(self.col_names().to_vec()))
+        evt_tx
+            .blocking_send(QueryEvent::Columns(self.col_names().to_vec()))
             .map_err(|_| MatcherError::EventReceiverClosed)?;
 
         let start = Instant::now();
@@ -413,27 +796,32 @@ impl MatcherHandle {
                 None => break,
             };
 
-            tx.blocking_send(QueryEvent::Row(
-                row.get(0)?,
-                (1..col_count)
-                    .map(|i| row.get::<_, SqliteValue>(i))
-                    .collect::<rusqlite::Result<Vec<_>>>()?,
-            ))
-            .map_err(|_| MatcherError::EventReceiverClosed)?;
+            evt_tx
+                .blocking_send(QueryEvent::Row(
+                    row.get(0)?,
+                    (1..col_count)
+                        .map(|i| row.get::<_, SqliteValue>(i))
+                        .collect::<rusqlite::Result<Vec<_>>>()?,
+                ))
+                .map_err(|_| MatcherError::EventReceiverClosed)?;
             count += 1;
         }
 
         trace!("sent {count} rows");
 
-        let max_change_id = conn
-            .prepare("SELECT COALESCE(MAX(id),0) FROM changes")?
-            .query_row([], |row| row.get(0))?;
+        drop(rows);
+        drop(prepped);
+        if let Some(tx) = tx {
+            tx.commit()?;
+        }
 
-        tx.blocking_send(QueryEvent::EndOfQuery {
-            time: elapsed.as_secs_f64(),
-            change_id: Some(max_change_id),
-        })
-        .map_err(|_| MatcherError::EventReceiverClosed)?;
+        evt_tx
+            .blocking_send(QueryEvent::EndOfQuery {
+                time: elapsed.as_secs_f64(),
+                change_id: Some(max_change_id),
+                cursor: None,
+            })
+            .map_err(|_| MatcherError::EventReceiverClosed)?;
 
         Ok(max_change_id)
     }
@@ -446,7 +834,7 @@ impl MatcherHandle {
         // don't double process the same pk
         if candidates
             .get(change.table)
-            .map(|pks| pks.contains(change.pk))
+            .map(|pks| pks.contains_key(change.pk))
             .unwrap_or_default()
         {
             return false;
@@ -465,9 +853,12 @@ impl MatcherHandle {
         }
 
         if let Some(v) = candidates.get_mut(change.table) {
-            v.insert(change.pk.to_vec())
+            v.insert(change.pk.to_vec(), change.actor_id).is_none()
         } else {
-            candidates.insert(change.table.clone(), [change.pk.to_vec()].into());
+            candidates.insert(
+                change.table.clone(),
+                [(change.pk.to_vec(), change.actor_id)].into(),
+            );
             true
         }
     }
@@ -485,12 +876,26 @@ pub struct Matcher {
     pub evt_tx: mpsc::Sender<QueryEvent>,
     pub col_names: Vec<ColumnName>,
     pub last_rowid: u64,
+    partition_key: Option<PartitionKey>,
+    /// Hydrate from this snapshot instead of the state connection
+    snapshot: Option<Arc<GroupSnapshot>>,
     conn: Connection,
     base_path: Utf8PathBuf,
     cancel: CancellationToken,
     state: StateLock,
     last_change_tx: watch::Sender<ChangeId>,
     changes_rx: mpsc::Receiver<(MatchCandidates, CrsqlDbVersion)>,
+    /// Set once a subscriber asked for the previous values of updates
+    keep_previous: Arc<AtomicBool>,
+}
+
+/// Column the initial query can be split on: the first primary key of the
+/// first table, selected as `alias`.
+#[derive(Debug, Clone)]
+struct PartitionKey {
+    table: String,
+    pk: String,
+    alias: String,
 }
 
 #[derive(Debug, Clone)]
@@ -501,11 +906,22 @@ pub struct MatcherStmt {
 
 const CHANGE_ID_COL: &str = "id";
 const CHANGE_TYPE_COL: &str = "type";
+/// Packed values of updated rows before the update
+const PREVIOUS_COL: &str = "old";
+/// Actor the change originated from, unknown for changes found by rebuilding
+/// the query
+const ACTOR_COL: &str = "actor_id";
 
 pub const QUERY_TABLE_NAME: &str = "query";
 
 pub const SUB_DB_PATH: &str = "sub.sqlite";
 
+/// Read connections used to hydrate large subscriptions
+const HYDRATION_PARTITIONS: usize = 4;
+/// Below this many rows in the first table, hydrate with a single connection
+const PARALLEL_HYDRATION_MIN_ROWS: i64 = 100_000;
+const HYDRATION_ROWS_CAP: usize = 1024;
+
 impl Matcher {
     fn new(
         id: Uuid,
@@ -698,6 +1114,18 @@ impl Matcher {
             );
         }
 
+        let partition_key = if is_partitionable(&stmt) {
+            pks.first().and_then(|(tbl_name, aliases)| {
+                Some(PartitionKey {
+                    table: tbl_name.clone(),
+                    pk: schema.tables.get(tbl_name)?.pk.first()?.clone(),
+                    alias: aliases.first()?.clone(),
+                })
+            })
+        } else {
+            None
+        };
+
         let cancel = CancellationToken::new();
 
         let state = Arc::new((Mutex::new(MatcherState::Created), Condvar::new()));
@@ -709,6 +1137,8 @@ impl Matcher {
 
         let sql_hash = hex::encode(seahash::hash(sql.as_bytes()).to_be_bytes());
 
+        let keep_previous = Arc::new(AtomicBool::new(false));
+
         let handle = MatcherHandle {
             inner: Arc::new(InnerMatcherHandle {
                 id,
@@ -721,9 +1151,12 @@ impl Matcher {
                     .expect("could not build pool, this can't fail because we specified a runtime"),
                 parsed: parsed.clone(),
                 col_names: col_names.clone(),
+                pks: pks.clone(),
                 cancel: cancel.clone(),
+                close_reason: Mutex::new(None),
                 last_change_rx,
                 changes_tx,
+                keep_previous: keep_previous.clone(),
             }),
             state: state.clone(),
         };
@@ -738,12 +1171,15 @@ impl Matcher {
             evt_tx,
             col_names,
             last_rowid: 0,
+            partition_key,
+            snapshot: None,
             conn,
             base_path: sub_path,
             cancel,
             state,
             last_change_tx,
             changes_rx,
+            keep_previous,
         };
 
         Ok((matcher, handle))
@@ -803,7 +1239,11 @@ impl Matcher {
 
         let (matcher, handle) = Self::new(id, subs_path, schema, &state_conn, evt_tx, &sql)?;
 
-        spawn_counted(matcher.run_restore(state_conn, tripwire));
+        spawn_named(
+            "matcher_restore",
+            Shutdown::Graceful,
+            matcher.run_restore(state_conn, tripwire),
+        );
 
         Ok(handle)
     }
@@ -817,8 +1257,10 @@ impl Matcher {
         evt_tx: mpsc::Sender<QueryEvent>,
         sql: &str,
         tripwire: Tripwire,
+        snapshot: Option<Arc<GroupSnapshot>>,
     ) -> Result<MatcherHandle, MatcherError> {
         let (mut matcher, handle) = Self::new(id, subs_path, schema, &state_conn, evt_tx, sql)?;
+        matcher.snapshot = snapshot;
 
         let pk_cols = matcher
             .pks
@@ -851,7 +1293,9 @@ impl Matcher {
                     {CHANGE_ID_COL} INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
                     __corro_rowid INTEGER NOT NULL,
                     {CHANGE_TYPE_COL} INTEGER NOT NULL,
-                    {actual_columns}
+                    {actual_columns},
+                    {PREVIOUS_COL} BLOB,
+                    {ACTOR_COL} BLOB
                 );
 
                 CREATE TABLE meta (
@@ -919,7 +1363,11 @@ impl Matcher {
             Ok::<_, MatcherError>(())
         })?;
 
-        spawn_counted(matcher.run(state_conn, tripwire));
+        spawn_named(
+            "matcher",
+            Shutdown::Graceful,
+            matcher.run(state_conn, tripwire),
+        );
 
         Ok(handle)
     }
@@ -942,6 +1390,37 @@ impl Matcher {
                 .prepare_cached("SELECT COALESCE(MAX(id), 0) FROM changes")?
                 .query_row([], |row| row.get(0))?;
 
+            // created before previous values were kept
+            let has_previous: bool = self.conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM pragma_table_info('changes') WHERE name = ?)",
+                [PREVIOUS_COL],
+                |row| row.get(0),
+            )?;
+            if !has_previous {
+                self.conn.execute_batch(&format!(
+                    "ALTER TABLE changes ADD COLUMN {PREVIOUS_COL} BLOB"
+                ))?;
+            }
+            // and before their actor was
+            let has_actor: bool = self.conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM pragma_table_info('changes') WHERE name = ?)",
+                [ACTOR_COL],
+                |row| row.get(0),
+            )?;
+            if !has_actor {
+                self.conn
+                    .execute_batch(&format!("ALTER TABLE changes ADD COLUMN {ACTOR_COL} BLOB"))?;
+            }
+
+            let keep_previous: bool = self.conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM meta WHERE key = 'previous')",
+                [],
+                |row| row.get(0),
+            )?;
+            if keep_previous {
+                self.keep_previous.store(true, Ordering::Relaxed);
+            }
+
             _ = self.last_change_tx.send(max_change_id);
 
             Ok::<_, MatcherError>(())
@@ -1055,8 +1534,8 @@ impl Matcher {
                 Some((candidates, db_version)) = self.changes_rx.recv() => {
                     for (table, pks) in  candidates {
                         let buffed = buf.entry(table).or_default();
-                        for pk in pks {
-                            if buffed.insert(pk) {
+                        for (pk, actor_id) in pks {
+                            if buffed.insert(pk, actor_id).is_none() {
                                 buf_count += 1;
                             }
                         }
@@ -1093,15 +1572,25 @@ impl Matcher {
 
             match branch {
                 Branch::NewCandidates((candidates, db_version)) => {
-                    if let Err(e) = block_in_place(|| {
-                        self.handle_candidates(&mut state_conn, candidates, db_version)
-                    }) {
-                        if !matches!(e, MatcherError::EventReceiverClosed) {
-                            error!(sub_id = %self.id, "could not handle change: {e}");
+                    let res = block_in_place(|| {
+                        match self.handle_candidates(&mut state_conn, candidates, db_version) {
+                            Err(e) if !e.is_event_recv_closed() => {
+                                error!(sub_id = %self.id, "could not handle change: {e}");
+                                self.quarantine(&mut state_conn, &e)
+                            }
+                            res => res,
+                        }
+                    });
+                    buf_count = 0;
+                    if let Err(e) = res {
+                        if !e.is_event_recv_closed() {
+                            error!(sub_id = %self.id, "could not rebuild subscription: {e}");
+                            _ = self
+                                .evt_tx
+                                .try_send(QueryEvent::Error(e.to_compact_string()));
                         }
                         break;
                     }
-                    buf_count = 0;
                 }
                 Branch::PurgeOldChanges => {
                     let res = block_in_place(|| {
@@ -1156,6 +1645,8 @@ impl Matcher {
         let mut candidates = MatchCandidates::new();
         let mut last_db_version = None;
 
+        let snapshot = self.snapshot.take();
+
         let res = block_in_place(|| {
             let tx = self.conn.transaction()?;
 
@@ -1183,22 +1674,34 @@ impl Matcher {
                 all_cols.join(",")
             ))?;
 
-            info!(sub_id = %self.id, "Starting state conn read transaction for initial query");
-            // this is read transaction up until the end!
-            let state_tx = state_conn.transaction()?;
-
-            let elapsed = {
-                debug!("select stmt: {stmt_str:?}");
+            let group_conn = snapshot.as_ref().map(|snapshot| snapshot.0.lock());
+            let owned_tx;
+            let state_tx: &Connection = match group_conn.as_deref() {
+                Some(conn) => {
+                    info!(sub_id = %self.id, "Using the group's snapshot for initial query");
+                    &**conn
+                }
+                None => {
+                    info!(sub_id = %self.id, "Starting state conn read transaction for initial query");
+                    // this is read transaction up until the end!
+                    owned_tx = state_conn.transaction()?;
+                    &*owned_tx
+                }
+            };
 
-                let mut select = state_tx.prepare(&stmt_str)?;
-                let start = Instant::now();
-                let mut select_rows = {
-                    let _guard = interrupt_deadline_guard(&state_tx, Duration::from_secs(15));
-                    select.query(())?
-                };
-                let elapsed = start.elapsed();
-                info!(sub_id = %self.id, "Initial query done in {elapsed:?}");
+            let bounds = match (&self.partition_key, &snapshot) {
+                // partitions read from their own connections, not from the
+                // group's snapshot
+                (Some(key), None) => hydration_bounds(
+                    state_tx,
+                    key,
+                    HYDRATION_PARTITIONS,
+                    PARALLEL_HYDRATION_MIN_ROWS,
+                )?,
+                _ => vec![],
+            };
 
+            let elapsed = {
                 let insert_into = format!(
                     "INSERT INTO query ({}) VALUES ({}) RETURNING __corro_rowid,{}",
                     all_cols.join(","),
@@ -1211,76 +1714,152 @@ impl Matcher {
                 );
                 trace!("insert stmt: {insert_into:?}");
 
-                {
-                    let mut insert = tx.prepare(&insert_into)?;
-
-                    loop {
-                        match select_rows.next() {
-                            Ok(Some(row)) => {
-                                for i in 0..all_cols.len() {
-                                    insert.raw_bind_parameter(
-                                        i + 1,
-                                        SqliteValueRef::from(row.get_ref(i)?),
-                                    )?;
-                                }
-
-                                let mut rows = insert.raw_query();
+                let mut insert = tx.prepare(&insert_into)?;
 
-                                let row = match rows.next()? {
-                                    Some(row) => row,
-                                    None => continue,
-                                };
+                let (elapsed, db_version) = if bounds.is_empty() {
+                    debug!("select stmt: {stmt_str:?}");
 
-                                let rowid = row.get(0)?;
-                                let cells = (1..=query_cols.len())
-                                    .map(|i| row.get::<_, SqliteValue>(i))
-                                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                    let mut select = state_tx.prepare(&stmt_str)?;
+                    let start = Instant::now();
+                    let mut select_rows = {
+                        let _guard = interrupt_deadline_guard(state_tx, Duration::from_secs(15));
+                        select.query(())?
+                    };
+                    let elapsed = start.elapsed();
+                    info!(sub_id = %self.id, "Initial query done in {elapsed:?}");
 
-                                if let Err(e) = self
-                                    .evt_tx
-                                    .blocking_send(QueryEvent::Row(RowId(rowid), cells))
-                                {
-                                    error!(sub_id = %self.id, "could not send back row: {e}");
-                                    return Err(MatcherError::EventReceiverClosed);
-                                }
+                    while let Some(row) = select_rows.next()? {
+                        for i in 0..all_cols.len() {
+                            insert
+                                .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
+                        }
 
-                                last_rowid = cmp::max(rowid, last_rowid);
-                            }
-                            Ok(None) => {
-                                info!(sub_id = %self.id, "Done iterating through rows for initial query");
-                                // done!
-                                break;
-                            }
-                            Err(e) => {
-                                return Err(e.into());
-                            }
+                        if let Some(rowid) =
+                            insert_query_row(&mut insert, &self.evt_tx, self.id, query_cols.len())?
+                        {
+                            last_rowid = cmp::max(rowid, last_rowid);
                         }
 
                         // drain this channel so it doesn't fill up
-                        while let Ok((new_candidates, db_version)) = self.changes_rx.try_recv() {
-                            last_db_version = Some(db_version);
-                            if first_buffered_db_version.is_none() {
-                                first_buffered_db_version = Some(db_version);
-                            }
-                            for (table, pks) in new_candidates {
-                                candidates.entry(table).or_default().extend(pks);
-                            }
-                        }
+                        drain_buffered_changes(
+                            &mut self.changes_rx,
+                            &mut candidates,
+                            &mut first_buffered_db_version,
+                            &mut last_db_version,
+                        );
                     }
-                }
+                    info!(sub_id = %self.id, "Done iterating through rows for initial query");
 
-                tx.execute_batch("DROP TABLE IF EXISTS state_rows;")?;
+                    let db_version: CrsqlDbVersion =
+                        state_tx.query_row("SELECT crsql_db_version()", [], |row| row.get(0))?;
 
-                let db_version: CrsqlDbVersion =
-                    state_tx.query_row("SELECT crsql_db_version()", [], |row| row.get(0))?;
-                update_last_db_version(&tx, db_version)?;
+                    (elapsed, db_version)
+                } else {
+                    let path = state_tx
+                        .path()
+                        .map(ToOwned::to_owned)
+                        .ok_or(MatcherError::NoDatabasePath)?;
+                    let key = self
+                        .partition_key
+                        .as_ref()
+                        .map(|key| key.alias.clone())
+                        .ok_or(MatcherError::MissingPrimaryKeys)?;
+
+                    info!(sub_id = %self.id, "Hydrating with {} partitions on {key}", bounds.len() + 1);
+
+                    let start = Instant::now();
+                    let rt = tokio::runtime::Handle::current();
+
+                    // every partition reads from its own snapshot, keep the
+                    // oldest one: changes past it are caught up afterwards
+                    let db_version = std::thread::scope(|s| {
+                        let (rows_tx, rows_rx) =
+                            std::sync::mpsc::sync_channel::<Vec<SqliteValue>>(HYDRATION_ROWS_CAP);
+
+                        let lower = std::iter::once(None).chain(bounds.iter().map(Some));
+                        let upper = bounds.iter().map(Some).chain(std::iter::once(None));
+                        let workers = lower
+                            .zip(upper)
+                            .map(|(after, until)| {
+                                let rows_tx = rows_tx.clone();
+                                let (path, stmt_str, key, rt) = (&path, &stmt_str, &key, &rt);
+                                let cols = all_cols.len();
+                                s.spawn(move || {
+                                    let _rt = rt.enter();
+                                    hydrate_partition(
+                                        path, stmt_str, key, after, until, cols, rows_tx,
+                                    )
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        drop(rows_tx);
 
-                tx.execute(
-                    "INSERT OR REPLACE INTO meta (key, value) VALUES ('state', 'running')",
-                    [],
-                )?;
+                        let merged = (|| {
+                            for values in rows_rx {
+                                for (i, value) in values.iter().enumerate() {
+                                    insert.raw_bind_parameter(i + 1, value.as_ref())?;
+                                }
 
-                tx.commit()?;
+                                if let Some(rowid) = insert_query_row(
+                                    &mut insert,
+                                    &self.evt_tx,
+                                    self.id,
+                                    query_cols.len(),
+                                )? {
+                                    last_rowid = cmp::max(rowid, last_rowid);
+                                }
+
+                                drain_buffered_changes(
+                                    &mut self.changes_rx,
+                                    &mut candidates,
+                                    &mut first_buffered_db_version,
+                                    &mut last_db_version,
+                                );
+                            }
+                            Ok::<_, MatcherError>(())
+                        })();
+
+                        let mut db_version: Option<CrsqlDbVersion> = None;
+                        let mut worker_err = None;
+                        for worker in workers {
+                            match worker.join() {
+                                Ok(Ok(Some(v))) => {
+                                    db_version = Some(db_version.map_or(v, |prev| prev.min(v)));
+                                }
+                                // stopped early because merging failed
+                                Ok(Ok(None)) => {}
+                                Ok(Err(e)) => {
+                                    worker_err.get_or_insert(e);
+                                }
+                                Err(panic) => std::panic::resume_unwind(panic),
+                            }
+                        }
+
+                        merged?;
+                        if let Some(e) = worker_err {
+                            return Err(e.into());
+                        }
+                        db_version.ok_or(MatcherError::MissingPrimaryKeys)
+                    })?;
+
+                    let elapsed = start.elapsed();
+                    info!(sub_id = %self.id, "Initial query done in {elapsed:?} over {} partitions", bounds.len() + 1);
+
+                    (elapsed, db_version)
+                };
+
+                drop(insert);
+
+                tx.execute_batch("DROP TABLE IF EXISTS state_rows;")?;
+
+                update_last_db_version(&tx, db_version)?;
+
+                tx.execute(
+                    "INSERT OR REPLACE INTO meta (key, value) VALUES ('state', 'running')",
+                    [],
+                )?;
+
+                tx.commit()?;
 
                 (elapsed, db_version)
             };
@@ -1290,6 +1869,9 @@ impl Matcher {
             Ok::<_, MatcherError>(elapsed)
         });
 
+        // let the group's other subscriptions hydrate
+        drop(snapshot);
+
         let db_version = match res {
             Ok((elapsed, db_version)) => {
                 trace!(
@@ -1301,6 +1883,7 @@ impl Matcher {
                     .send(QueryEvent::EndOfQuery {
                         time: elapsed.as_secs_f64(),
                         change_id: Some(ChangeId(0)),
+                        cursor: None,
                     })
                     .await
                 {
@@ -1374,9 +1957,9 @@ impl Matcher {
         );
 
         let tx = self.conn.transaction()?;
-        for (table, pks) in candidates {
+        for (table, pks) in candidates.iter() {
             let pks = pks
-                .iter()
+                .keys()
                 .map(|pk| unpack_columns(pk))
                 .collect::<Result<Vec<Vec<SqliteValueRef>>, _>>()?;
 
@@ -1427,6 +2010,8 @@ impl Matcher {
             query_cols.push(col_name);
         }
 
+        let keep_previous = self.keep_previous.load(Ordering::Relaxed);
+
         // start a new tx
         let tx = self.conn.transaction()?;
 
@@ -1437,6 +2022,9 @@ impl Matcher {
         ))?;
 
         let mut new_last_rowid = self.last_rowid;
+        // only sent once committed, an error midway must not leak changes
+        // which were rolled back
+        let mut pending = vec![];
 
         {
             // read-only!
@@ -1480,6 +2068,15 @@ impl Matcher {
                     .map(|pk| format!("coalesce({pk},\"\")"))
                     .collect::<Vec<_>>()
                     .join(",");
+                // the table's primary key follows the columns, to find the
+                // actor of the change
+                let return_cols = query_cols
+                    .iter()
+                    .chain(self.pks.get(table.as_str()).into_iter().flatten())
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(",");
+
                 let sql = format!(
                     "INSERT INTO query ({insert_cols})
                         SELECT * FROM (
@@ -1504,7 +2101,6 @@ impl Matcher {
                         .map(|i| format!("col_{i} IS NOT excluded.col_{i}"))
                         .collect::<Vec<_>>()
                         .join(" OR "),
-                    return_cols = query_cols.join(",")
                 );
 
                 trace!("INSERT SQL: {sql}");
@@ -1523,15 +2119,20 @@ impl Matcher {
                     pks = coalesced_pks,
                     select_pks = coalesced_pks,
                     query_query = stmt.temp_query,
-                    return_cols = query_cols.join(",")
                 );
 
                 trace!("DELETE SQL: {sql}");
 
                 let delete_prepped = tx.prepare_cached(&sql)?;
 
+                let previous = if keep_previous {
+                    previous_values(&tx, &coalesced_pks, &query_cols)?
+                } else {
+                    HashMap::new()
+                };
+
                 let mut change_insert_stmt = tx.prepare_cached(&format!(
-                    "INSERT INTO changes (__corro_rowid, {CHANGE_TYPE_COL}, {}) VALUES (?, ?, {}) RETURNING {CHANGE_ID_COL}",
+                    "INSERT INTO changes (__corro_rowid, {CHANGE_TYPE_COL}, {}, {PREVIOUS_COL}, {ACTOR_COL}) VALUES (?, ?, {}, ?, ?) RETURNING {CHANGE_ID_COL}",
                     query_cols.join(","),
                     (0..query_cols.len())
                         .map(|_i| "?")
@@ -1543,60 +2144,18 @@ impl Matcher {
                     (None, insert_prepped),
                     (Some(ChangeType::Delete), delete_prepped),
                 ] {
-                    let col_count = prepped.column_count();
-
-                    let mut rows = prepped.raw_query();
-
-                    while let Ok(Some(row)) = rows.next() {
-                        let rowid: RowId = row.get(0)?;
-
-                        let change_type = change_type.clone().take().unwrap_or({
-                            if rowid.0 > self.last_rowid {
-                                ChangeType::Insert
-                            } else {
-                                ChangeType::Update
-                            }
-                        });
-
-                        new_last_rowid = cmp::max(new_last_rowid, rowid.0);
-
-                        match (1..col_count)
-                            .map(|i| row.get::<_, SqliteValue>(i))
-                            .collect::<rusqlite::Result<Vec<_>>>()
-                        {
-                            Ok(cells) => {
-                                change_insert_stmt.raw_bind_parameter(1, rowid)?;
-                                change_insert_stmt.raw_bind_parameter(2, change_type)?;
-                                for (i, cell) in cells.iter().enumerate() {
-                                    // increment index by 3 because that's where we're starting...
-                                    change_insert_stmt.raw_bind_parameter(i + 3, cell)?;
-                                }
-
-                                let mut change_rows = change_insert_stmt.raw_query();
-
-                                let change_id: ChangeId = change_rows
-                                    .next()?
-                                    .ok_or(MatcherError::NoChangeInserted)?
-                                    .get(0)?;
-
-                                trace!("got change id: {change_id}");
-
-                                if let Err(e) = self.evt_tx.blocking_send(QueryEvent::Change(
-                                    change_type,
-                                    rowid,
-                                    cells,
-                                    change_id,
-                                )) {
-                                    debug!("could not send back row to matcher sub sender: {e}");
-                                    return Err(MatcherError::EventReceiverClosed);
-                                }
-                                _ = self.last_change_tx.send(change_id);
-                            }
-                            Err(e) => {
-                                error!("could not deserialize row's cells: {e}");
-                                return Ok(());
-                            }
-                        }
+                    if !record_changes(
+                        &mut prepped,
+                        &mut change_insert_stmt,
+                        change_type,
+                        &previous,
+                        query_cols.len(),
+                        candidates.get(table),
+                        self.last_rowid,
+                        &mut new_last_rowid,
+                        &mut pending,
+                    )? {
+                        return Ok(());
                     }
                 }
                 // clean that up
@@ -1612,6 +2171,14 @@ impl Matcher {
             }
         }
 
+        if keep_previous {
+            // a restored subscription keeps recording them
+            tx.execute(
+                "INSERT OR IGNORE INTO meta (key, value) VALUES ('previous', 1)",
+                [],
+            )?;
+        }
+
         update_last_db_version(&tx, last_db_version)?;
 
         trace!("inserted new db version: {last_db_version}");
@@ -1622,9 +2189,195 @@ impl Matcher {
 
         self.last_rowid = new_last_rowid;
 
+        self.send_changes(pending)
+    }
+
+    fn send_changes(&self, changes: Vec<PendingChange>) -> Result<(), MatcherError> {
+        for (change_type, rowid, cells, change_id, actor_id) in changes {
+            if let Err(e) = self.evt_tx.blocking_send(QueryEvent::Change(
+                change_type,
+                rowid,
+                cells,
+                change_id,
+                actor_id.map(|actor_id| actor_id.to_compact_string()),
+            )) {
+                debug!("could not send back row to matcher sub sender: {e}");
+                return Err(MatcherError::EventReceiverClosed);
+            }
+            _ = self.last_change_tx.send(change_id);
+        }
         Ok(())
     }
 
+    /// Recovers from an error processing changes, which may have left the
+    /// temporary tables in an inconsistent state: they're rebuilt and the
+    /// whole query is recomputed, the differences with the query table are
+    /// sent as changes after a `resync` event.
+    fn quarantine(
+        &mut self,
+        state_conn: &mut Connection,
+        error: &MatcherError,
+    ) -> Result<(), MatcherError> {
+        warn!(sub_id = %self.id, "quarantining subscription after error: {error}");
+        counter!("corro.subs.quarantined").increment(1);
+
+        let pk_cols = self
+            .pks
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<String>>();
+
+        let mut query_cols = vec![];
+        let mut all_cols = pk_cols.clone();
+        for i in 0..(self.parsed.columns.len()) {
+            let col_name = format!("col_{i}");
+            all_cols.push(col_name.clone());
+            query_cols.push(col_name);
+        }
+
+        let keep_previous = self.keep_previous.load(Ordering::Relaxed);
+
+        let tx = self.conn.transaction()?;
+
+        for (table, pks) in self.pks.iter() {
+            tx.execute_batch(&format!(
+                "DROP TABLE IF EXISTS temp_{table}; CREATE TABLE temp_{table} ({});",
+                pks.join(",")
+            ))?;
+        }
+
+        tx.execute_batch(&format!(
+            "DROP TABLE IF EXISTS state_results; CREATE TEMP TABLE state_results ({});",
+            all_cols.join(",")
+        ))?;
+
+        let db_version = {
+            // read-only!
+            let state_tx = state_conn.transaction()?;
+
+            let mut stmt_str = Cmd::Stmt(self.query.clone()).to_string();
+            stmt_str.pop(); // remove trailing `;`
+
+            let mut insert = tx.prepare(&format!(
+                "INSERT INTO state_results VALUES ({})",
+                (0..all_cols.len())
+                    .map(|_| "?")
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ))?;
+
+            let mut select = state_tx.prepare(&stmt_str)?;
+            let mut rows = {
+                let _guard = interrupt_deadline_guard(&state_tx, Duration::from_secs(15));
+                select.raw_query()
+            };
+            while let Some(row) = rows.next()? {
+                for i in 0..all_cols.len() {
+                    insert.raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
+                }
+                insert.raw_execute()?;
+            }
+
+            state_tx.query_row("SELECT crsql_db_version()", [], |row| row.get(0))?
+        };
+
+        let coalesced_pks = pk_cols
+            .iter()
+            .map(|pk| format!("coalesce({pk},\"\")"))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut new_last_rowid = self.last_rowid;
+        let mut pending = vec![];
+
+        {
+            let insert_prepped = tx.prepare(&format!(
+                "INSERT INTO query ({insert_cols})
+                    SELECT * FROM (
+                        SELECT * FROM state_results
+                        EXCEPT
+                        SELECT {insert_cols} FROM query
+                    ) WHERE 1
+                    ON CONFLICT({coalesced_pks})
+                        DO UPDATE SET
+                            {excluded}
+                        WHERE {excluded_not_same}
+                    RETURNING __corro_rowid,{return_cols}",
+                insert_cols = all_cols.join(","),
+                excluded = (0..(self.parsed.columns.len()))
+                    .map(|i| format!("col_{i} = excluded.col_{i}"))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                excluded_not_same = (0..(self.parsed.columns.len()))
+                    .map(|i| format!("col_{i} IS NOT excluded.col_{i}"))
+                    .collect::<Vec<_>>()
+                    .join(" OR "),
+                return_cols = query_cols.join(",")
+            ))?;
+
+            let delete_prepped = tx.prepare(&format!(
+                "DELETE FROM query WHERE ({coalesced_pks}) NOT IN (
+                    SELECT {coalesced_pks} FROM state_results
+                ) RETURNING __corro_rowid,{return_cols}",
+                return_cols = query_cols.join(",")
+            ))?;
+
+            let previous = if keep_previous {
+                previous_values(&tx, &coalesced_pks, &query_cols)?
+            } else {
+                HashMap::new()
+            };
+
+            let mut change_insert_stmt = tx.prepare_cached(&format!(
+                "INSERT INTO changes (__corro_rowid, {CHANGE_TYPE_COL}, {}, {PREVIOUS_COL}, {ACTOR_COL}) VALUES (?, ?, {}, ?, ?) RETURNING {CHANGE_ID_COL}",
+                query_cols.join(","),
+                (0..query_cols.len())
+                    .map(|_i| "?")
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ))?;
+
+            for (change_type, mut prepped) in [
+                (None, insert_prepped),
+                (Some(ChangeType::Delete), delete_prepped),
+            ] {
+                // rebuilt from the whole query, the changes can't be traced
+                // back to an actor
+                if !record_changes(
+                    &mut prepped,
+                    &mut change_insert_stmt,
+                    change_type,
+                    &previous,
+                    query_cols.len(),
+                    None,
+                    self.last_rowid,
+                    &mut new_last_rowid,
+                    &mut pending,
+                )? {
+                    return Err(MatcherError::UnreadableRow);
+                }
+            }
+        }
+
+        tx.execute_batch("DELETE FROM state_results")?;
+        update_last_db_version(&tx, db_version)?;
+        tx.commit()?;
+
+        self.last_rowid = new_last_rowid;
+
+        info!(sub_id = %self.id, "rebuilt subscription at db version {db_version}, sending {} changes", pending.len());
+
+        if let Err(e) = self.evt_tx.blocking_send(QueryEvent::Resync {
+            reason: error.to_compact_string(),
+        }) {
+            debug!("could not send resync event to matcher sub sender: {e}");
+            return Err(MatcherError::EventReceiverClosed);
+        }
+
+        self.send_changes(pending)
+    }
+
     fn handle_change(
         &mut self,
         state_conn: &mut Connection,
@@ -1637,7 +2390,7 @@ impl Matcher {
         {
             let mut changes_prepped = state_conn.prepare_cached(
                 r#"
-            SELECT DISTINCT "table", pk
+            SELECT DISTINCT "table", pk, site_id, MAX(db_version)
                 FROM crsql_changes
                     WHERE db_version > ?
                       AND db_version <= ? -- TODO: allow going over?
@@ -1646,12 +2399,13 @@ impl Matcher {
         "#,
             )?;
 
+            // the site of the last change of each row comes along with max()
             let mut rows = changes_prepped.query([start_db_version, end_db_version])?;
             while let Ok(Some(row)) = rows.next() {
                 candidates
                     .entry(row.get(0)?)
                     .or_default()
-                    .insert(row.get(1)?);
+                    .insert(row.get(1)?, row.get(2)?);
             }
         }
 
@@ -1659,6 +2413,127 @@ impl Matcher {
     }
 }
 
+/// Packed values of the query rows about to be replaced by `state_results`,
+/// by rowid, kept with the changes updating them
+fn previous_values(
+    conn: &Connection,
+    coalesced_pks: &str,
+    query_cols: &[String],
+) -> rusqlite::Result<HashMap<RowId, Vec<u8>>> {
+    let mut prepped = conn.prepare_cached(&format!(
+        "SELECT __corro_rowid,{} FROM query WHERE ({coalesced_pks}) IN (
+            SELECT {coalesced_pks} FROM state_results
+        )",
+        query_cols.join(",")
+    ))?;
+    let mut rows = prepped.raw_query();
+
+    let mut previous = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let cells = (1..=query_cols.len())
+            .map(|i| row.get::<_, SqliteValue>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        // too many columns to pack, the update goes without them
+        if let Ok(packed) = pack_columns(&cells) {
+            previous.insert(row.get(0)?, packed);
+        }
+    }
+    Ok(previous)
+}
+
+/// Change recorded by a matcher, about to be sent to its subscribers
+type PendingChange = (
+    ChangeType,
+    RowId,
+    Vec<SqliteValue>,
+    ChangeId,
+    Option<ActorId>,
+);
+
+/// Records the rows returned by `prepped` (a rowid followed by the query's
+/// `cell_count` columns, then the primary key of the changed table when
+/// `actors` are given) in the changes table. Returns `false` if a row
+/// couldn't be read and nothing should be committed.
+#[allow(clippy::too_many_arguments)]
+fn record_changes(
+    prepped: &mut rusqlite::Statement,
+    change_insert_stmt: &mut rusqlite::Statement,
+    change_type: Option<ChangeType>,
+    previous: &HashMap<RowId, Vec<u8>>,
+    cell_count: usize,
+    actors: Option<&IndexMap<Vec<u8>, ActorId>>,
+    last_rowid: u64,
+    new_last_rowid: &mut u64,
+    pending: &mut Vec<PendingChange>,
+) -> Result<bool, MatcherError> {
+    let col_count = prepped.column_count();
+
+    let mut rows = prepped.raw_query();
+
+    while let Ok(Some(row)) = rows.next() {
+        let rowid: RowId = row.get(0)?;
+
+        let change_type = change_type.clone().unwrap_or({
+            if rowid.0 > last_rowid {
+                ChangeType::Insert
+            } else {
+                ChangeType::Update
+            }
+        });
+
+        *new_last_rowid = cmp::max(*new_last_rowid, rowid.0);
+
+        let actor_id = match actors {
+            Some(actors) => (cell_count + 1..col_count)
+                .map(|i| row.get::<_, SqliteValue>(i))
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .ok()
+                .and_then(|pk| pack_columns(&pk).ok())
+                .and_then(|pk| actors.get(&pk).copied()),
+            None => None,
+        };
+
+        match (1..=cell_count)
+            .map(|i| row.get::<_, SqliteValue>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()
+        {
+            Ok(cells) => {
+                change_insert_stmt.raw_bind_parameter(1, rowid)?;
+                change_insert_stmt.raw_bind_parameter(2, change_type)?;
+                for (i, cell) in cells.iter().enumerate() {
+                    // increment index by 3 because that's where we're starting...
+                    change_insert_stmt.raw_bind_parameter(i + 3, cell)?;
+                }
+                change_insert_stmt.raw_bind_parameter(
+                    cells.len() + 3,
+                    match change_type {
+                        ChangeType::Update => previous.get(&rowid),
+                        _ => None,
+                    },
+                )?;
+                change_insert_stmt.raw_bind_parameter(cells.len() + 4, actor_id)?;
+
+                let mut change_rows = change_insert_stmt.raw_query();
+
+                let change_id: ChangeId = change_rows
+                    .next()?
+                    .ok_or(MatcherError::NoChangeInserted)?
+                    .get(0)?;
+
+                trace!("got change id: {change_id}");
+
+                pending.push((change_type, rowid, cells, change_id, actor_id));
+            }
+            Err(e) => {
+                error!("could not deserialize row's cells: {e}");
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
 fn dump_query_plan(
     conn: &mut Connection,
     query: &str,
@@ -1721,6 +2596,242 @@ fn interrupt_deadline_guard(conn: &Connection, dur: Duration) -> DropGuard {
     cancel.drop_guard()
 }
 
+/// Runs the insert (parameters already bound) of an initial query row into
+/// the query table and sends it to subscribers. Returns the inserted rowid.
+fn insert_query_row(
+    insert: &mut rusqlite::Statement,
+    evt_tx: &mpsc::Sender<QueryEvent>,
+    sub_id: Uuid,
+    query_cols: usize,
+) -> Result<Option<u64>, MatcherError> {
+    let mut rows = insert.raw_query();
+
+    let row = match rows.next()? {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let rowid = row.get(0)?;
+    let cells = (1..=query_cols)
+        .map(|i| row.get::<_, SqliteValue>(i))
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    if let Err(e) = evt_tx.blocking_send(QueryEvent::Row(RowId(rowid), cells)) {
+        error!(%sub_id, "could not send back row: {e}");
+        return Err(MatcherError::EventReceiverClosed);
+    }
+
+    Ok(Some(rowid))
+}
+
+fn drain_buffered_changes(
+    changes_rx: &mut mpsc::Receiver<(MatchCandidates, CrsqlDbVersion)>,
+    candidates: &mut MatchCandidates,
+    first_db_version: &mut Option<CrsqlDbVersion>,
+    last_db_version: &mut Option<CrsqlDbVersion>,
+) {
+    while let Ok((new_candidates, db_version)) = changes_rx.try_recv() {
+        *last_db_version = Some(db_version);
+        if first_db_version.is_none() {
+            *first_db_version = Some(db_version);
+        }
+        for (table, pks) in new_candidates {
+            candidates.entry(table).or_default().extend(pks);
+        }
+    }
+}
+
+/// Only plain selects can be split by primary key ranges and give the same
+/// rows: aggregates, DISTINCT, LIMIT and compound selects need every row at
+/// once.
+fn is_partitionable(stmt: &Stmt) -> bool {
+    let Stmt::Select(select) = stmt else {
+        return false;
+    };
+    if select.limit.is_some() || select.body.compounds.is_some() {
+        return false;
+    }
+    if let Some(order_by) = select.order_by.as_ref() {
+        if order_by.iter().any(|col| has_aggregate(&col.expr)) {
+            return false;
+        }
+    }
+    match &select.body.select {
+        OneSelect::Select {
+            columns,
+            distinctness,
+            where_clause,
+            group_by,
+            window_clause,
+            ..
+        } => {
+            distinctness.is_none()
+                && group_by.is_none()
+                && window_clause.is_none()
+                && !where_clause.as_ref().map_or(false, has_aggregate)
+                && !columns.iter().any(|col| match col {
+                    ResultColumn::Expr(expr, _) => has_aggregate(expr),
+                    _ => false,
+                })
+        }
+        OneSelect::Values(_) => false,
+    }
+}
+
+/// Whether an aggregate or window function is called anywhere in `expr`.
+/// Subqueries aggregate their own rows, they're left alone.
+fn has_aggregate(expr: &Expr) -> bool {
+    const AGGREGATES: &[&str] = &[
+        "avg",
+        "count",
+        "group_concat",
+        "json_group_array",
+        "json_group_object",
+        "max",
+        "min",
+        "string_agg",
+        "sum",
+        "total",
+    ];
+
+    match expr {
+        Expr::FunctionCallStar { .. } => true,
+        Expr::FunctionCall {
+            name,
+            args,
+            filter_over,
+            ..
+        } => {
+            // FILTER and OVER only apply to aggregate and window functions
+            filter_over.is_some()
+                || AGGREGATES.contains(&name.0.to_ascii_lowercase().as_str())
+                || args.iter().flatten().any(has_aggregate)
+        }
+        Expr::Between {
+            lhs, start, end, ..
+        } => has_aggregate(lhs) || has_aggregate(start) || has_aggregate(end),
+        Expr::Binary(lhs, _, rhs) => has_aggregate(lhs) || has_aggregate(rhs),
+        Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            base.as_deref().map_or(false, has_aggregate)
+                || when_then_pairs
+                    .iter()
+                    .any(|(when, then)| has_aggregate(when) || has_aggregate(then))
+                || else_expr.as_deref().map_or(false, has_aggregate)
+        }
+        Expr::Cast { expr, .. } | Expr::Collate(expr, _) | Expr::Unary(_, expr) => {
+            has_aggregate(expr)
+        }
+        Expr::IsNull(expr) | Expr::NotNull(expr) => has_aggregate(expr),
+        Expr::InList { lhs, rhs, .. } => {
+            has_aggregate(lhs) || rhs.iter().flatten().any(has_aggregate)
+        }
+        Expr::InSelect { lhs, .. } | Expr::InTable { lhs, .. } => has_aggregate(lhs),
+        Expr::Like {
+            lhs, rhs, escape, ..
+        } => {
+            has_aggregate(lhs)
+                || has_aggregate(rhs)
+                || escape.as_deref().map_or(false, has_aggregate)
+        }
+        Expr::Parenthesized(exprs) => exprs.iter().any(has_aggregate),
+        _ => false,
+    }
+}
+
+/// Values of the partition key splitting the first table in `partitions`
+/// ranges of about the same size, none if the table is too small to bother.
+fn hydration_bounds(
+    conn: &Connection,
+    key: &PartitionKey,
+    partitions: usize,
+    min_rows: i64,
+) -> rusqlite::Result<Vec<SqliteValue>> {
+    let count: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM {}", key.table), [], |row| {
+        row.get(0)
+    })?;
+    if partitions < 2 || count < min_rows {
+        return Ok(vec![]);
+    }
+
+    let step = count / partitions as i64;
+    let mut prepped = conn.prepare(&format!(
+        "SELECT {pk} FROM {table} ORDER BY {pk} LIMIT 1 OFFSET ?",
+        pk = key.pk,
+        table = key.table
+    ))?;
+
+    let mut bounds: Vec<SqliteValue> = vec![];
+    for i in 1..partitions as i64 {
+        let Some(value) = prepped
+            .query_row([step * i - 1], |row| row.get(0))
+            .optional()?
+        else {
+            break;
+        };
+        // composite primary keys can repeat their first column
+        if bounds.last() != Some(&value) {
+            bounds.push(value);
+        }
+    }
+
+    Ok(bounds)
+}
+
+/// Reads the initial query rows whose partition key is in `(after, until]`
+/// from a dedicated connection. Returns the db version of the snapshot it
+/// read from, or nothing if the rows weren't wanted anymore.
+fn hydrate_partition(
+    path: &str,
+    stmt_str: &str,
+    key: &str,
+    after: Option<&SqliteValue>,
+    until: Option<&SqliteValue>,
+    cols: usize,
+    rows_tx: std::sync::mpsc::SyncSender<Vec<SqliteValue>>,
+) -> rusqlite::Result<Option<CrsqlDbVersion>> {
+    let mut conn = rusqlite_to_crsqlite(Connection::open(path)?)?;
+    let tx = conn.transaction()?;
+
+    let mut conds = vec![];
+    let mut params = vec![];
+    if let Some(after) = after {
+        conds.push(format!("{key} > ?"));
+        params.push(after.as_ref());
+    }
+    if let Some(until) = until {
+        conds.push(format!("{key} <= ?"));
+        params.push(until.as_ref());
+    }
+
+    let db_version = {
+        let mut select = tx.prepare(&format!(
+            "SELECT * FROM ({stmt_str}) WHERE {}",
+            conds.join(" AND ")
+        ))?;
+        let mut rows = {
+            let _guard = interrupt_deadline_guard(&tx, Duration::from_secs(15));
+            select.query(params_from_iter(params))?
+        };
+
+        while let Some(row) = rows.next()? {
+            let values = (0..cols)
+                .map(|i| row.get::<_, SqliteValue>(i))
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            if rows_tx.send(values).is_err() {
+                return Ok(None);
+            }
+        }
+
+        tx.query_row("SELECT crsql_db_version()", [], |row| row.get(0))?
+    };
+
+    Ok(Some(db_version))
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct ParsedSelect {
     table_columns: IndexMap<String, HashSet<String>>,
@@ -2122,12 +3233,16 @@ pub enum MatcherError {
     TableForColumnNotFound { col_name: String },
     #[error("missing primary keys, this shouldn't happen")]
     MissingPrimaryKeys,
+    #[error("could not determine the database path to open hydration connections")]
+    NoDatabasePath,
     #[error("change queue has been closed or is full")]
     ChangeQueueClosedOrFull,
     #[error("no change was inserted, this is not supposed to happen")]
     NoChangeInserted,
     #[error("change receiver is closed")]
     EventReceiverClosed,
+    #[error("could not read the cells of a row")]
+    UnreadableRow,
     #[error(transparent)]
     Unpack(#[from] UnpackError),
     #[error("did not insert subscription")]
@@ -2142,12 +3257,16 @@ pub enum MatcherError {
     QueueFull,
     #[error("cannot restore existing subscription")]
     CannotRestoreExisting,
+    #[error("too many subscriptions running (max: {0})")]
+    TooManySubscriptions(usize),
     #[error("could not acquire write permit")]
     WritePermitAcquire(#[from] AcquireError),
     #[error("subscription is not running")]
     NotRunning,
     #[error("subscription restore is missing SQL query")]
     MissingSql,
+    #[error(transparent)]
+    NormalizeStatement(#[from] NormalizeStatementError),
 }
 
 impl MatcherError {
@@ -2168,6 +3287,17 @@ pub enum NormalizeStatementError {
     NoStatement,
 }
 
+/// Id of a subscription created by hash: a version 8 UUID made of the first
+/// bytes of the SHA-256 of its normalized SQL
+pub fn sql_id(normalized_sql: &str) -> Uuid {
+    let digest = ring::digest::digest(&ring::digest::SHA256, normalized_sql.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest.as_ref()[..16]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Uuid::from_bytes(bytes)
+}
+
 pub fn normalize_sql(sql: &str) -> Result<String, NormalizeStatementError> {
     let mut parser = Parser::new(sql.as_bytes());
 
@@ -2356,7 +3486,7 @@ mod tests {
     use crate::{
         actor::ActorId,
         agent::migrate,
-        schema::{apply_schema, parse_sql},
+        schema::{apply_schema, parse_sql, plan_schema},
         sqlite::{setup_conn, CrConn},
     };
 
@@ -2384,7 +3514,8 @@ mod tests {
             setup_conn(&mut conn)?;
             migrate(&mut conn)?;
             let tx = conn.transaction()?;
-            apply_schema(&tx, &Schema::default(), &mut schema)?;
+            let plan = plan_schema(&Schema::default(), &schema, &Default::default())?;
+            apply_schema(&tx, &Schema::default(), &mut schema, &plan)?;
             tx.commit()?;
         }
 
@@ -2408,19 +3539,459 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_diff() {
+    async fn test_matcher_max_count(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
         _ = tracing_subscriber::fmt::try_init();
-
         let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let schema_sql = "CREATE TABLE sw (pk TEXT NOT NULL PRIMARY KEY, sandwich TEXT);";
+        let mut schema = parse_sql(schema_sql)?;
 
-        let sql = "SELECT json_object(
-            'targets', json_array(cs.address||':'||cs.port),
-            'labels',  json_object(
-              '__metrics_path__', JSON_EXTRACT(cs.meta, '$.path'),
-              'app',            cs.app_name,
-              'vm_account_id',  cs.organization_id,
-              'instance',       cs.instance_id
-            )
+        let sql = "SELECT sandwich FROM sw WHERE pk=\"mad\"";
+
+        let subs = SubsManager::with_max_count(Some(1));
+
+        let tmpdir = tempfile::tempdir()?;
+        let db_path = tmpdir.path().join("test.db");
+        let subscriptions_path: Utf8PathBuf =
+            tmpdir.path().join("subs").display().to_string().into();
+
+        let pool = SplitPool::create(db_path, Arc::new(Semaphore::new(1))).await?;
+        {
+            let mut conn = pool.write_priority().await?;
+            setup_conn(&mut conn)?;
+            migrate(&mut conn)?;
+            let tx = conn.transaction()?;
+            let plan = plan_schema(&Schema::default(), &schema, &Default::default())?;
+            apply_schema(&tx, &Schema::default(), &mut schema, &plan)?;
+            tx.commit()?;
+        }
+
+        let (handle, maybe_created) = subs.get_or_insert(
+            sql,
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+
+        assert!(maybe_created.is_some());
+
+        // the same query reuses the running matcher
+        let (_, maybe_created) = subs.get_or_insert(
+            sql,
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        assert!(maybe_created.is_none());
+
+        assert!(matches!(
+            subs.get_or_insert(
+                "SELECT sandwich FROM sw",
+                subscriptions_path.as_path(),
+                &schema,
+                &pool,
+                tripwire.clone(),
+            ),
+            Err(MatcherError::TooManySubscriptions(1))
+        ));
+
+        handle.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_matcher_by_hash() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+    {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let schema_sql = "CREATE TABLE sw (pk TEXT NOT NULL PRIMARY KEY, sandwich TEXT);";
+        let mut schema = parse_sql(schema_sql)?;
+
+        let subs = SubsManager::default();
+
+        let tmpdir = tempfile::tempdir()?;
+        let db_path = tmpdir.path().join("test.db");
+        let subscriptions_path: Utf8PathBuf =
+            tmpdir.path().join("subs").display().to_string().into();
+
+        let pool = SplitPool::create(db_path, Arc::new(Semaphore::new(1))).await?;
+        {
+            let mut conn = pool.write_priority().await?;
+            setup_conn(&mut conn)?;
+            migrate(&mut conn)?;
+            let tx = conn.transaction()?;
+            let plan = plan_schema(&Schema::default(), &schema, &Default::default())?;
+            apply_schema(&tx, &Schema::default(), &mut schema, &plan)?;
+            tx.commit()?;
+        }
+
+        let (handle, maybe_created) = subs.get_or_insert_by_hash(
+            "SELECT sandwich FROM sw",
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        assert!(maybe_created.is_some());
+        assert_eq!(
+            handle.id(),
+            sql_id(&normalize_sql("SELECT sandwich FROM sw")?)
+        );
+
+        // formatting doesn't matter, the same subscription is returned
+        let (same, maybe_created) = subs.get_or_insert_by_hash(
+            "select   sandwich\n  from sw",
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        assert!(maybe_created.is_none());
+        assert_eq!(same.id(), handle.id());
+
+        let (regular, _) = subs.get_or_insert(
+            "SELECT pk FROM sw",
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+
+        let mut definitions = subs.definitions();
+        definitions.sort_by_key(|def| def.by_hash);
+        assert_eq!(
+            definitions,
+            vec![
+                SubDefinition {
+                    id: regular.id(),
+                    sql: "SELECT pk FROM sw".into(),
+                    by_hash: false,
+                },
+                SubDefinition {
+                    id: handle.id(),
+                    sql: normalize_sql("SELECT sandwich FROM sw")?,
+                    by_hash: true,
+                },
+            ]
+        );
+
+        regular.cleanup().await;
+        handle.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_matcher_quarantine(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let schema_sql = "CREATE TABLE sw (pk TEXT NOT NULL PRIMARY KEY, sandwich TEXT);";
+        let mut schema = parse_sql(schema_sql)?;
+
+        let subs = SubsManager::default();
+
+        let tmpdir = tempfile::tempdir()?;
+        let db_path = tmpdir.path().join("test.db");
+        let subscriptions_path: Utf8PathBuf =
+            tmpdir.path().join("subs").display().to_string().into();
+
+        let pool = SplitPool::create(db_path, Arc::new(Semaphore::new(1))).await?;
+        let mut conn = pool.write_priority().await?;
+        setup_conn(&mut conn)?;
+        migrate(&mut conn)?;
+        {
+            let tx = conn.transaction()?;
+            let plan = plan_schema(&Schema::default(), &schema, &Default::default())?;
+            apply_schema(&tx, &Schema::default(), &mut schema, &plan)?;
+            tx.execute("INSERT INTO sw VALUES ('mad', 'burger')", ())?;
+            tx.commit()?;
+        }
+
+        let (matcher, maybe_created) = subs.get_or_insert(
+            "SELECT sandwich FROM sw",
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        let mut rx = maybe_created.unwrap().evt_rx;
+
+        assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Columns(_)));
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            QueryEvent::Row(RowId(1), vec![SqliteValue::Text("burger".into())])
+        );
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        // break the temporary table used to process changes
+        {
+            let sub_conn = rusqlite::Connection::open(
+                subscriptions_path
+                    .join(matcher.id().as_simple().to_string())
+                    .join(SUB_DB_PATH),
+            )?;
+            sub_conn.execute_batch("DROP TABLE temp_sw; CREATE TABLE temp_sw (a, b);")?;
+        }
+
+        conn.execute("INSERT INTO sw VALUES ('ham', 'ham sandwich')", ())?;
+        filter_changes_from_db(&matcher, &conn, None, CrsqlDbVersion(2))?;
+
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            QueryEvent::Resync { .. }
+        ));
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(2),
+                vec![SqliteValue::Text("ham sandwich".into())],
+                ChangeId(1),
+                None
+            )
+        );
+
+        // changes are processed normally after the rebuild
+        conn.execute(
+            "UPDATE sw SET sandwich = 'cheeseburger' WHERE pk = 'mad'",
+            (),
+        )?;
+        filter_changes_from_db(&matcher, &conn, None, CrsqlDbVersion(3))?;
+
+        let site_id: ActorId = conn.query_row("SELECT crsql_site_id()", [], |row| row.get(0))?;
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            QueryEvent::Change(
+                ChangeType::Update,
+                RowId(1),
+                vec![SqliteValue::Text("cheeseburger".into())],
+                ChangeId(2),
+                Some(site_id.to_compact_string())
+            )
+        );
+
+        matcher.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_matcher_previous(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let schema_sql = "CREATE TABLE sw (pk TEXT NOT NULL PRIMARY KEY, sandwich TEXT);";
+        let mut schema = parse_sql(schema_sql)?;
+
+        let subs = SubsManager::default();
+
+        let tmpdir = tempfile::tempdir()?;
+        let db_path = tmpdir.path().join("test.db");
+        let subscriptions_path: Utf8PathBuf =
+            tmpdir.path().join("subs").display().to_string().into();
+
+        let pool = SplitPool::create(db_path, Arc::new(Semaphore::new(1))).await?;
+        let mut conn = pool.write_priority().await?;
+        setup_conn(&mut conn)?;
+        migrate(&mut conn)?;
+        {
+            let tx = conn.transaction()?;
+            let plan = plan_schema(&Schema::default(), &schema, &Default::default())?;
+            apply_schema(&tx, &Schema::default(), &mut schema, &plan)?;
+            tx.execute("INSERT INTO sw VALUES ('mad', 'burger')", ())?;
+            tx.commit()?;
+        }
+
+        let (matcher, maybe_created) = subs.get_or_insert(
+            "SELECT sandwich FROM sw",
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        let mut rx = maybe_created.unwrap().evt_rx;
+
+        assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Columns(_)));
+        assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Row(..)));
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        let sub_conn = matcher.pool().get().await.unwrap();
+
+        // nobody asked for them yet
+        conn.execute(
+            "UPDATE sw SET sandwich = 'cheeseburger' WHERE pk = 'mad'",
+            (),
+        )?;
+        filter_changes_from_db(&matcher, &conn, None, CrsqlDbVersion(2))?;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            QueryEvent::Change(ChangeType::Update, _, _, ChangeId(1), _)
+        ));
+        assert_eq!(
+            block_in_place(|| matcher.previous_values(&sub_conn, ChangeId(1)))?,
+            None
+        );
+
+        matcher.keep_previous();
+
+        conn.execute(
+            "UPDATE sw SET sandwich = 'double cheeseburger' WHERE pk = 'mad'",
+            (),
+        )?;
+        filter_changes_from_db(&matcher, &conn, None, CrsqlDbVersion(3))?;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            QueryEvent::Change(ChangeType::Update, _, _, ChangeId(2), _)
+        ));
+        assert_eq!(
+            block_in_place(|| matcher.previous_values(&sub_conn, ChangeId(2)))?,
+            Some(vec![SqliteValue::Text("cheeseburger".into())])
+        );
+
+        // restoring the subscription keeps recording them
+        let kept: bool = sub_conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM meta WHERE key = 'previous')",
+            [],
+            |row| row.get(0),
+        )?;
+        assert!(kept);
+
+        drop(sub_conn);
+        matcher.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_all_rows_snapshot(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let schema_sql = "CREATE TABLE sw (pk TEXT NOT NULL PRIMARY KEY, sandwich TEXT);";
+        let mut schema = parse_sql(schema_sql)?;
+
+        let subs = SubsManager::default();
+
+        let tmpdir = tempfile::tempdir()?;
+        let db_path = tmpdir.path().join("test.db");
+        let subscriptions_path: Utf8PathBuf =
+            tmpdir.path().join("subs").display().to_string().into();
+
+        let pool = SplitPool::create(db_path, Arc::new(Semaphore::new(1))).await?;
+        let mut conn = pool.write_priority().await?;
+        setup_conn(&mut conn)?;
+        migrate(&mut conn)?;
+        {
+            let tx = conn.transaction()?;
+            let plan = plan_schema(&Schema::default(), &schema, &Default::default())?;
+            apply_schema(&tx, &Schema::default(), &mut schema, &plan)?;
+            tx.execute("INSERT INTO sw VALUES ('mad', 'burger')", ())?;
+            tx.commit()?;
+        }
+
+        let (matcher, maybe_created) = subs.get_or_insert(
+            "SELECT sandwich FROM sw",
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        let mut rx = maybe_created.unwrap().evt_rx;
+
+        assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Columns(_)));
+        assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Row(..)));
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        // a single slot: reading rows stops until they're received
+        let (catch_up_tx, mut catch_up_rx) = mpsc::channel(1);
+
+        let all_rows = tokio::spawn({
+            let matcher = matcher.clone();
+            async move {
+                let conn = matcher.pool().get().await.unwrap();
+                block_in_place(|| matcher.all_rows(&conn, catch_up_tx))
+            }
+        });
+
+        assert!(matches!(
+            catch_up_rx.recv().await.unwrap(),
+            QueryEvent::Columns(_)
+        ));
+
+        // commit a change while the rows are being read
+        conn.execute("INSERT INTO sw VALUES ('ham', 'ham sandwich')", ())?;
+        filter_changes_from_db(&matcher, &conn, None, CrsqlDbVersion(2))?;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            QueryEvent::Change(ChangeType::Insert, _, _, ChangeId(1), _)
+        ));
+
+        // neither the rows nor the change id include it, so it's sent once,
+        // as the first change after the rows
+        let mut rows = vec![];
+        let eoq_change_id = loop {
+            match catch_up_rx.recv().await.unwrap() {
+                QueryEvent::Row(_, cells) => rows.push(cells),
+                QueryEvent::EndOfQuery { change_id, .. } => break change_id,
+                evt => panic!("unexpected event: {evt:?}"),
+            }
+        };
+        assert_eq!(rows, vec![vec![SqliteValue::Text("burger".into())]]);
+        assert_eq!(eoq_change_id, Some(ChangeId(0)));
+        assert_eq!(all_rows.await?.unwrap(), ChangeId(0));
+
+        matcher.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_diff() {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let sql = "SELECT json_object(
+            'targets', json_array(cs.address||':'||cs.port),
+            'labels',  json_object(
+              '__metrics_path__', JSON_EXTRACT(cs.meta, '$.path'),
+              'app',            cs.app_name,
+              'vm_account_id',  cs.organization_id,
+              'instance',       cs.instance_id
+            )
           )
           FROM consul_services cs
             LEFT JOIN machines m                   ON m.id = cs.instance_id
@@ -2505,10 +4076,16 @@ mod tests {
             setup_conn(&mut conn).unwrap();
             migrate(&mut conn).unwrap();
             let tx = conn.transaction().unwrap();
-            apply_schema(&tx, &Schema::default(), &mut schema).unwrap();
+            let plan = plan_schema(&Schema::default(), &schema, &Default::default()).unwrap();
+            apply_schema(&tx, &Schema::default(), &mut schema, &plan).unwrap();
             tx.commit().unwrap();
         }
 
+        let site_id: ActorId = conn
+            .query_row("SELECT crsql_site_id()", [], |row| row.get(0))
+            .unwrap();
+        let actor_id = Some(site_id.to_compact_string());
+
         // let's seed some data in there
         {
             let tx = conn.transaction().unwrap();
@@ -2543,7 +4120,8 @@ mod tests {
 
             {
                 let tx = conn2.transaction().unwrap();
-                apply_schema(&tx, &Schema::default(), &mut schema).unwrap();
+                let plan = plan_schema(&Schema::default(), &schema, &Default::default()).unwrap();
+                apply_schema(&tx, &Schema::default(), &mut schema, &plan).unwrap();
                 tx.commit().unwrap();
             }
 
@@ -2647,7 +4225,13 @@ mod tests {
 
             assert_eq!(
                 rx.recv().await.unwrap(),
-                QueryEvent::Change(ChangeType::Insert, RowId(2), cells, ChangeId(1))
+                QueryEvent::Change(
+                    ChangeType::Insert,
+                    RowId(2),
+                    cells,
+                    ChangeId(1),
+                    actor_id.clone()
+                )
             );
 
             println!("received change");
@@ -2669,7 +4253,13 @@ mod tests {
 
             assert_eq!(
                 rx.recv().await.unwrap(),
-                QueryEvent::Change(ChangeType::Delete, RowId(1), cells, ChangeId(2))
+                QueryEvent::Change(
+                    ChangeType::Delete,
+                    RowId(1),
+                    cells,
+                    ChangeId(2),
+                    actor_id.clone()
+                )
             );
 
             println!("got change (A)");
@@ -2692,7 +4282,13 @@ mod tests {
 
             assert_eq!(
                 rx.recv().await.unwrap(),
-                QueryEvent::Change(ChangeType::Update, RowId(2), cells, ChangeId(3))
+                QueryEvent::Change(
+                    ChangeType::Update,
+                    RowId(2),
+                    cells,
+                    ChangeId(3),
+                    actor_id.clone()
+                )
             );
 
             println!("got change (B)");
@@ -2747,7 +4343,9 @@ mod tests {
 
             let start = Instant::now();
             for _ in range {
-                if let QueryEvent::Change(change_type, _, _, change_id) = rx.recv().await.unwrap() {
+                if let QueryEvent::Change(change_type, _, _, change_id, _) =
+                    rx.recv().await.unwrap()
+                {
                     assert_eq!(change_type, ChangeType::Insert);
                     last_change_id = Some(change_id);
                 }
@@ -2844,7 +4442,7 @@ mod tests {
                 match catch_up_rx.recv().await.unwrap() {
                     QueryEvent::EndOfQuery { change_id, .. } => eoq_change_id = Some(change_id),
                     QueryEvent::Row(_, _) => rows_count += 1,
-                    QueryEvent::Change(_, _, _, _) => {
+                    QueryEvent::Change(..) => {
                         panic!("received a change intertwined w/ rows");
                     }
                     _ => {}
@@ -2860,7 +4458,13 @@ mod tests {
 
             assert_eq!(
                 rx.recv().await.unwrap(),
-                QueryEvent::Change(ChangeType::Delete, RowId(2), cells, ChangeId(1000))
+                QueryEvent::Change(
+                    ChangeType::Delete,
+                    RowId(2),
+                    cells,
+                    ChangeId(1000),
+                    actor_id.clone()
+                )
             );
 
             assert!(rx.try_recv().is_err());
@@ -2871,6 +4475,140 @@ mod tests {
         wait_for_all_pending_handles().await;
     }
 
+    #[test]
+    fn test_hydration_partitions() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+    {
+        let parse = |sql: &str| match Parser::new(sql.as_bytes()).next() {
+            Ok(Some(Cmd::Stmt(stmt))) => stmt,
+            _ => unreachable!(),
+        };
+        assert!(is_partitionable(&parse(
+            "SELECT id, upper(text) FROM tests WHERE id > 10"
+        )));
+        assert!(!is_partitionable(&parse("SELECT id FROM tests LIMIT 10")));
+        assert!(!is_partitionable(&parse("SELECT DISTINCT text FROM tests")));
+        assert!(!is_partitionable(&parse("SELECT id, COUNT(*) FROM tests")));
+        assert!(!is_partitionable(&parse("SELECT id, max(text) FROM tests")));
+
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, text TEXT);
+            WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < 100)
+            INSERT INTO tests (id) SELECT id FROM ids;",
+        )?;
+
+        let key = PartitionKey {
+            table: "tests".into(),
+            pk: "id".into(),
+            alias: "__corro_pk_tests_id".into(),
+        };
+
+        assert!(hydration_bounds(&conn, &key, 4, 1000)?.is_empty());
+        assert_eq!(
+            hydration_bounds(&conn, &key, 4, 10)?,
+            vec![
+                SqliteValue::Integer(25),
+                SqliteValue::Integer(50),
+                SqliteValue::Integer(75)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partitioned_hydration_rows(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let parse = |sql: &str| match Parser::new(sql.as_bytes()).next() {
+            Ok(Some(Cmd::Stmt(stmt))) => stmt,
+            _ => unreachable!(),
+        };
+
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, text TEXT);
+            WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < 100)
+            INSERT INTO tests (id, text) SELECT id, 'text ' || (id % 7) FROM ids;",
+        )?;
+
+        let key = PartitionKey {
+            table: "tests".into(),
+            pk: "id".into(),
+            alias: "k".into(),
+        };
+        let bounds = hydration_bounds(&conn, &key, 4, 10)?;
+        assert_eq!(bounds.len(), 3);
+
+        let rows = |sql: &str, params: Vec<&SqliteValue>| -> rusqlite::Result<Vec<String>> {
+            let mut prepped = conn.prepare(sql)?;
+            let cols = prepped.column_count();
+            let mut rows = prepped
+                .query_map(params_from_iter(params), |row| {
+                    (0..cols)
+                        .map(|i| row.get::<_, SqliteValue>(i).map(|v| format!("{v:?}")))
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                        .map(|values| values.join(","))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows.sort();
+            Ok(rows)
+        };
+
+        for (sql, partitionable) in [
+            (
+                "SELECT id AS k, upper(text) FROM tests WHERE id % 3 = 0",
+                true,
+            ),
+            (
+                "SELECT id AS k, (SELECT max(id) FROM tests) FROM tests",
+                true,
+            ),
+            ("SELECT id AS k, count(*) + 1 FROM tests", false),
+            ("SELECT id AS k, coalesce(max(text), '') FROM tests", false),
+            (
+                "SELECT id AS k, CASE WHEN sum(id) > 10 THEN 1 END FROM tests",
+                false,
+            ),
+            (
+                "SELECT id AS k, sum(id) OVER (ORDER BY id) FROM tests",
+                false,
+            ),
+            ("SELECT id AS k, text FROM tests ORDER BY count(*)", false),
+            (
+                "SELECT id AS k, text FROM tests GROUP BY text HAVING count(*) > 1",
+                false,
+            ),
+        ] {
+            assert_eq!(is_partitionable(&parse(sql)), partitionable, "{sql}");
+            if !partitionable {
+                continue;
+            }
+
+            let whole = rows(sql, vec![])?;
+            let mut partitioned = vec![];
+            for i in 0..=bounds.len() {
+                let mut conds = vec![];
+                let mut params = vec![];
+                if let Some(after) = i.checked_sub(1).and_then(|i| bounds.get(i)) {
+                    conds.push("k > ?");
+                    params.push(after);
+                }
+                if let Some(until) = bounds.get(i) {
+                    conds.push("k <= ?");
+                    params.push(until);
+                }
+                partitioned.extend(rows(
+                    &format!("SELECT * FROM ({sql}) WHERE {}", conds.join(" AND ")),
+                    params,
+                )?);
+            }
+            partitioned.sort();
+            assert_eq!(partitioned, whole, "{sql}");
+        }
+
+        Ok(())
+    }
+
     fn filter_changes_from_db(
         matcher: &MatcherHandle,
         state_conn: &Connection,
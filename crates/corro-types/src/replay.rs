@@ -0,0 +1,223 @@
+//! Recently received change frames. Broadcasts are often received more than
+//! once: from several peers, duplicated by the network or replayed from
+//! captured traffic. Frames are identified by what they carry, the actor,
+//! versions and sequences of their changes, rather than by anything the
+//! sender picks: a frame already in the window is dropped right away,
+//! before being queued for processing, whoever sends it. Within the window,
+//! each frame is processed at most once. Changes that failed to apply the
+//! first time are fetched again by syncs, like any missed broadcast.
+//!
+//! How many of the frames received from each peer were duplicates is
+//! tracked too, broadcasts are sent to fewer peers when most of what they
+//! carry is already known.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    ops::RangeInclusive,
+};
+
+use crate::{
+    actor::ActorId,
+    base::{CrsqlSeq, Version},
+    broadcast::ChangeV1,
+};
+
+type Frame = (
+    ActorId,
+    RangeInclusive<Version>,
+    Option<RangeInclusive<CrsqlSeq>>,
+);
+
+/// Weight of the latest frame in duplicate rates
+const DUPLICATE_RATE_ALPHA: f64 = 0.01;
+
+/// Duplicate rate up to which broadcasts are sent to as many peers as usual
+const DUPLICATE_RATE_TARGET: f64 = 0.5;
+
+/// Fewest peers broadcasts are sent to when reducing their fanout
+const MIN_ADAPTED_FANOUT: usize = 2;
+
+/// Frames received and how many of them were already known
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DuplicateStats {
+    pub received: u64,
+    pub duplicates: u64,
+    /// Moving average of the share of duplicates, weighing recent frames more
+    pub rate: f64,
+}
+
+impl DuplicateStats {
+    fn record(&mut self, duplicate: bool) {
+        self.received += 1;
+        if duplicate {
+            self.duplicates += 1;
+        }
+        let sample = if duplicate { 1.0 } else { 0.0 };
+        self.rate += DUPLICATE_RATE_ALPHA * (sample - self.rate);
+    }
+
+    /// Number of peers to broadcast to instead of `fanout`. Past the target
+    /// rate, the fanout shrinks with the share of frames that are new.
+    pub fn adapt_fanout(&self, fanout: usize) -> usize {
+        if self.rate <= DUPLICATE_RATE_TARGET || fanout <= MIN_ADAPTED_FANOUT {
+            return fanout;
+        }
+        let scale = (1.0 - self.rate) / (1.0 - DUPLICATE_RATE_TARGET);
+        ((fanout as f64 * scale).round() as usize).max(MIN_ADAPTED_FANOUT)
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplayWindow {
+    capacity: usize,
+    frames: HashSet<Frame>,
+    // oldest first, evicted when the window is full
+    order: VecDeque<Frame>,
+    stats: DuplicateStats,
+    peer_stats: HashMap<IpAddr, DuplicateStats>,
+}
+
+impl ReplayWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            stats: DuplicateStats::default(),
+            peer_stats: HashMap::new(),
+        }
+    }
+
+    /// Records a frame `peer` sent, returns `false` if it was already in the
+    /// window, from this peer or another. Peers are told apart by IP, they
+    /// may connect from any port, and the frame counts as a duplicate for
+    /// them.
+    pub fn insert_from(&mut self, peer: IpAddr, change: &ChangeV1) -> bool {
+        if self.capacity == 0 {
+            self.stats.record(false);
+            self.peer_stats.entry(peer).or_default().record(false);
+            return true;
+        }
+
+        let frame = (change.actor_id, change.versions(), change.seqs().cloned());
+        let known = !self.frames.insert(frame.clone());
+        self.stats.record(known);
+        self.peer_stats.entry(peer).or_default().record(known);
+        if known {
+            return false;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.frames.remove(&oldest);
+            }
+        }
+        self.order.push_back(frame);
+
+        true
+    }
+
+    /// Duplicates among the frames received from all peers
+    pub fn duplicate_stats(&self) -> DuplicateStats {
+        self.stats
+    }
+
+    pub fn peer_duplicate_stats(&self) -> impl Iterator<Item = (&IpAddr, &DuplicateStats)> {
+        self.peer_stats.iter()
+    }
+
+    /// Forgets the stats of a peer that left
+    pub fn remove_peer(&mut self, peer: &IpAddr) {
+        self.peer_stats.remove(peer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broadcast::Changeset;
+
+    use super::*;
+
+    fn empty_change(actor_id: ActorId, version: u64) -> ChangeV1 {
+        ChangeV1 {
+            actor_id,
+            changeset: Changeset::Empty {
+                versions: Version(version)..=Version(version),
+            },
+        }
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let peer_a: IpAddr = "127.0.0.1".parse().unwrap();
+        let peer_b: IpAddr = "127.0.0.2".parse().unwrap();
+        let mut window = ReplayWindow::new(2);
+
+        assert!(window.insert_from(peer_a, &empty_change(actor_id, 1)));
+        // duplicated or replayed, whoever sends it
+        assert!(!window.insert_from(peer_a, &empty_change(actor_id, 1)));
+        assert!(!window.insert_from(peer_b, &empty_change(actor_id, 1)));
+        assert!(window.insert_from(peer_a, &empty_change(actor_id, 2)));
+        assert!(window.insert_from(peer_a, &empty_change(ActorId(uuid::Uuid::new_v4()), 1)));
+        assert_eq!(window.len(), 2);
+
+        // evicted from the window
+        assert!(window.insert_from(peer_a, &empty_change(actor_id, 1)));
+
+        let mut disabled = ReplayWindow::new(0);
+        assert!(disabled.insert_from(peer_a, &empty_change(actor_id, 1)));
+        assert!(disabled.insert_from(peer_a, &empty_change(actor_id, 1)));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_stats() {
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let peer_a: IpAddr = "127.0.0.1".parse().unwrap();
+        let peer_b: IpAddr = "127.0.0.2".parse().unwrap();
+        let mut window = ReplayWindow::new(1000);
+
+        for v in 1..=100 {
+            assert!(window.insert_from(peer_a, &empty_change(actor_id, v)));
+            assert!(!window.insert_from(peer_b, &empty_change(actor_id, v)));
+        }
+
+        let stats = window.duplicate_stats();
+        assert_eq!(stats.received, 200);
+        assert_eq!(stats.duplicates, 100);
+
+        let peers = window.peer_duplicate_stats().collect::<HashMap<_, _>>();
+        assert_eq!(peers[&peer_a].duplicates, 0);
+        assert_eq!(peers[&peer_a].rate, 0.0);
+        assert_eq!(peers[&peer_b].duplicates, 100);
+        assert!(peers[&peer_b].rate > 0.6);
+
+        window.remove_peer(&peer_b);
+        assert_eq!(window.peer_duplicate_stats().count(), 1);
+    }
+
+    #[test]
+    fn test_adapt_fanout() {
+        let stats = |rate| DuplicateStats {
+            rate,
+            ..Default::default()
+        };
+
+        assert_eq!(stats(0.0).adapt_fanout(10), 10);
+        assert_eq!(stats(0.5).adapt_fanout(10), 10);
+        assert_eq!(stats(0.75).adapt_fanout(10), 5);
+        assert_eq!(stats(1.0).adapt_fanout(10), 2);
+        // already small
+        assert_eq!(stats(1.0).adapt_fanout(1), 1);
+    }
+}
@@ -1,17 +1,20 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
     fmt,
+    hash::Hasher,
     time::{Instant, SystemTime},
 };
 
+use corro_api_types::{SchemaColumnRef, SchemaColumnRename, SchemaPlan};
 use enquote::unquote;
 use fallible_iterator::FallibleIterator;
 use indexmap::{IndexMap, IndexSet};
 use rusqlite::{Connection, Transaction};
 use serde::{Deserialize, Serialize};
 use sqlite3_parser::ast::{
-    Cmd, ColumnConstraint, ColumnDefinition, CreateTableBody, Expr, Name, NamedTableConstraint,
-    QualifiedName, SortedColumn, Stmt, TableConstraint, TableOptions, ToTokens,
+    AlterTableBody, Cmd, ColumnConstraint, ColumnDefinition, CreateTableBody, Expr, Name,
+    NamedTableConstraint, QualifiedName, SortedColumn, Stmt, TableConstraint, TableOptions,
+    ToTokens,
 };
 use tracing::{debug, info, trace};
 
@@ -98,12 +101,83 @@ pub struct Index {
     pub unique: bool,
 }
 
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Cmd::Stmt(Stmt::CreateIndex {
+            unique: self.unique,
+            if_not_exists: false,
+            idx_name: QualifiedName::single(Name(self.name.clone())),
+            tbl_name: Name(self.tbl_name.clone()),
+            columns: self.columns.clone(),
+            where_clause: self.where_clause.clone(),
+        })
+        .to_fmt(f)
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Schema {
     pub tables: IndexMap<String, Table>,
+    /// Tables dropped from the schema and not created again since
+    pub dropped: BTreeSet<String>,
+    /// Columns dropped from the schema's tables, by table, along with the
+    /// column they were renamed to if they were
+    pub dropped_columns: BTreeMap<String, BTreeMap<String, Option<String>>>,
+}
+
+/// What became of a column a table doesn't have anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DroppedColumn<'a> {
+    Dropped,
+    Renamed(&'a str),
 }
 
 impl Schema {
+    /// Whether changes to `tbl_name` have nowhere to go, the table having
+    /// been dropped
+    pub fn is_dropped(&self, tbl_name: &str) -> bool {
+        self.dropped.contains(tbl_name) && !self.tables.contains_key(tbl_name)
+    }
+
+    /// What became of `col_name` if `tbl_name` doesn't have it anymore,
+    /// following it through the renames since
+    pub fn dropped_column(&self, tbl_name: &str, col_name: &str) -> Option<DroppedColumn<'_>> {
+        let table = self.tables.get(tbl_name)?;
+        let dropped = self.dropped_columns.get(tbl_name)?;
+        if table.columns.contains_key(col_name) {
+            return None;
+        }
+
+        let mut to = dropped.get(col_name)?.as_deref();
+        // bounded, renames can't loop back to a column the table has
+        for _ in 0..dropped.len() {
+            let Some(name) = to else {
+                return Some(DroppedColumn::Dropped);
+            };
+            if table.columns.contains_key(name) {
+                return Some(DroppedColumn::Renamed(name));
+            }
+            to = dropped.get(name)?.as_deref();
+        }
+        None
+    }
+
+    /// Records the columns `plan` drops and renames
+    pub fn record_dropped_columns(&mut self, plan: &SchemaPlan) {
+        for col in plan.columns_dropped.iter() {
+            self.dropped_columns
+                .entry(col.table.clone())
+                .or_default()
+                .insert(col.column.clone(), None);
+        }
+        for col in plan.columns_renamed.iter() {
+            self.dropped_columns
+                .entry(col.table.clone())
+                .or_default()
+                .insert(col.from.clone(), Some(col.to.clone()));
+        }
+    }
+
     pub fn constrain(&mut self) -> Result<(), ConstrainedSchemaError> {
         self.tables.retain(|name, _table| {
             !(name.contains("crsql") && name.contains("sqlite") && name.starts_with("__corro"))
@@ -224,7 +298,70 @@ pub fn init_schema(conn: &Connection) -> Result<Schema, SchemaError> {
         dump.push(';');
     }
 
-    parse_sql(dump.as_str())
+    let mut schema = parse_sql(dump.as_str())?;
+
+    schema.dropped = conn
+        .prepare("SELECT name FROM __corro_dropped_tables")?
+        .query_map((), |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let rows = conn
+        .prepare("SELECT tbl_name, name, renamed_to FROM __corro_dropped_columns")?
+        .query_map((), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (tbl_name, name, renamed_to) in rows {
+        schema
+            .dropped_columns
+            .entry(tbl_name)
+            .or_default()
+            .insert(name, renamed_to);
+    }
+
+    Ok(schema)
+}
+
+/// Hash of every table's schema (its own and its indexes' definitions) as
+/// recorded in `__corro_schema`, to compare schemas between nodes
+pub fn schema_digest(conn: &Connection) -> rusqlite::Result<BTreeMap<String, u64>> {
+    let mut hashers: BTreeMap<String, seahash::SeaHasher> = BTreeMap::new();
+
+    let mut prepped = conn.prepare_cached(
+        "SELECT tbl_name, type, name, sql FROM __corro_schema ORDER BY tbl_name, type, name",
+    )?;
+    let mut rows = prepped.query(())?;
+    while let Some(row) = rows.next()? {
+        let hasher = hashers
+            .entry(row.get(0)?)
+            .or_insert_with(seahash::SeaHasher::new);
+        for i in 1..=3 {
+            hasher.write(row.get::<_, String>(i)?.as_bytes());
+            hasher.write_u8(0);
+        }
+    }
+
+    Ok(hashers
+        .into_iter()
+        .map(|(tbl_name, hasher)| (tbl_name, hasher.finish()))
+        .collect())
+}
+
+/// Tables missing from either schema or defined differently
+pub fn schema_drift(ours: &BTreeMap<String, u64>, theirs: &BTreeMap<String, u64>) -> Vec<String> {
+    ours.keys()
+        .chain(
+            theirs
+                .keys()
+                .filter(|tbl_name| !ours.contains_key(*tbl_name)),
+        )
+        .filter(|tbl_name| ours.get(*tbl_name) != theirs.get(*tbl_name))
+        .cloned()
+        .collect()
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -237,8 +374,18 @@ pub enum ApplySchemaError {
     ConstrainedSchema(#[from] ConstrainedSchemaError),
     #[error("won't drop table without the destructive flag set (table: '{0}')")]
     DropTableWithoutDestructiveFlag(String),
+    #[error("can't drop table '{0}', it does not exist")]
+    DropUnknownTable(String),
     #[error("won't remove column without the destructive flag set (table: '{0}', column: '{1}')")]
     RemoveColumnWithoutDestructiveFlag(String, String),
+    #[error("can't rename column '{1}' of table '{0}', it does not exist")]
+    RenameUnknownColumn(String, String),
+    #[error("can't rename column '{from}' to '{to}' (table: '{tbl_name}'), only the new name has to be defined")]
+    InvalidRename {
+        tbl_name: String,
+        from: String,
+        to: String,
+    },
     #[error("can't add a primary key (table: '{0}', column: '{1}')")]
     AddPrimaryKey(String, String),
     #[error("can't modify primary keys (table: '{0}')")]
@@ -262,42 +409,33 @@ pub enum ApplySchemaError {
     },
 }
 
+/// Changes [`plan_schema`] only plans when asked to
+#[derive(Debug, Clone, Default)]
+pub struct ApplySchemaOptions {
+    /// Allows removing columns, their values are lost
+    pub allow_destructive: bool,
+    /// Columns to rename, new name by previous name, by table
+    pub renamed: IndexMap<String, IndexMap<String, String>>,
+}
+
+/// Applies the changes [`plan_schema`] planned to go from `schema` to
+/// `new_schema`, the plan is where they were checked
 #[allow(clippy::result_large_err)]
 pub fn apply_schema(
     tx: &Transaction,
     schema: &Schema,
     new_schema: &mut Schema,
+    plan: &SchemaPlan,
 ) -> Result<(), ApplySchemaError> {
-    if let Some(name) = schema
-        .tables
-        .keys()
-        .collect::<HashSet<_>>()
-        .difference(&new_schema.tables.keys().collect::<HashSet<_>>())
-        .next()
-    {
-        // TODO: add options and check flag
-        return Err(ApplySchemaError::DropTableWithoutDestructiveFlag(
-            (*name).clone(),
-        ));
-    }
-
     let mut schema_to_merge = Schema::default();
 
     {
-        let new_table_names = new_schema
-            .tables
-            .keys()
-            .collect::<HashSet<_>>()
-            .difference(&schema.tables.keys().collect::<HashSet<_>>())
-            .cloned()
-            .collect::<HashSet<_>>();
-
-        debug!("new table names: {new_table_names:?}");
+        debug!("new table names: {:?}", plan.tables_added);
 
         let new_tables_iter = new_schema
             .tables
             .iter()
-            .filter(|(table, _)| new_table_names.contains(table));
+            .filter(|(table, _)| plan.tables_added.contains(*table));
 
         for (name, table) in new_tables_iter {
             info!("creating table '{name}'");
@@ -380,202 +518,78 @@ pub fn apply_schema(
     }
 
     // iterate intersecting tables
-    for name in new_schema
-        .tables
-        .keys()
-        .collect::<HashSet<_>>()
-        .intersection(&schema.tables.keys().collect::<HashSet<_>>())
-        .cloned()
-    {
+    for (name, new_table) in new_schema.tables.iter() {
+        let Some(table) = schema.tables.get(name) else {
+            continue;
+        };
         debug!("processing table '{name}'");
-        let table = schema.tables.get(name).unwrap();
         debug!(
             "current cols: {:?}",
             table.columns.keys().collect::<Vec<&String>>()
         );
-        let new_table = new_schema.tables.get(name).unwrap();
         debug!(
             "new cols: {:?}",
             new_table.columns.keys().collect::<Vec<&String>>()
         );
 
-        // 1. Check column drops... don't allow unless flag is passed
-
-        let dropped_cols = table
-            .columns
-            .keys()
-            .collect::<HashSet<_>>()
-            .difference(&new_table.columns.keys().collect::<HashSet<_>>())
-            .cloned()
-            .collect::<HashSet<_>>();
-
-        debug!("dropped cols: {dropped_cols:?}");
-
-        if let Some(col_name) = dropped_cols.into_iter().next() {
-            return Err(ApplySchemaError::RemoveColumnWithoutDestructiveFlag(
-                name.clone(),
-                col_name.clone(),
-            ));
-        }
-
-        // 2. check for changed columns
-
-        let changed_cols: HashMap<String, Column> = table
-            .columns
-            .iter()
-            .filter_map(|(name, col)| {
-                new_table
-                    .columns
-                    .get(name)
-                    .and_then(|new_col| (new_col != col).then(|| (name.clone(), col.clone())))
-            })
-            .collect();
+        let migration = TableMigration::planned(plan, new_table);
 
         debug!(
-            "changed cols: {:?}",
-            changed_cols.keys().collect::<Vec<_>>()
+            "dropped cols: {:?}, renamed cols: {:?}",
+            migration.dropped, migration.renamed
+        );
+        info!(
+            "new columns: {:?}",
+            migration
+                .added
+                .iter()
+                .map(|col| &col.name)
+                .collect::<Vec<_>>()
         );
 
-        let new_col_names = new_table
-            .columns
-            .keys()
-            .collect::<HashSet<_>>()
-            .difference(&table.columns.keys().collect::<HashSet<_>>())
-            .cloned()
-            .collect::<HashSet<_>>();
-
-        info!("new columns: {new_col_names:?}");
-
-        if changed_cols.is_empty() {
-            // 2.1. no changed columns, add missing ones
-
-            if new_col_names.is_empty() {
-                // nothing to do
-            } else {
-                info!("Altering crsql for table {}", table.name);
-                let start = Instant::now();
-                let new_cols = new_table
-                    .columns
-                    .iter()
-                    .filter(|(col_name, _)| new_col_names.contains(col_name))
-                    .collect::<Vec<_>>();
-
-                // if all columns are generated, we don't need a migration
-                let require_migration = !new_cols.iter().all(|(_, col)| col.generated.is_some());
-
-                if require_migration {
-                    tx.execute_batch(&format!("SELECT crsql_begin_alter('{name}');"))?;
-                }
-
-                for (col_name, col) in new_cols {
-                    info!("adding column '{col_name}'");
-                    if col.primary_key {
-                        return Err(ApplySchemaError::AddPrimaryKey(
-                            name.clone(),
-                            col_name.clone(),
-                        ));
-                    }
-                    if !col.nullable && col.default_value.is_none() {
-                        return Err(ConstrainedSchemaError::NotNullableColumnNeedsDefault {
-                            tbl_name: name.clone(),
-                            name: col_name.clone(),
-                        }
-                        .into());
-                    }
-                    tx.execute_batch(&format!("ALTER TABLE {name} ADD COLUMN {}", col))?;
-                }
+        if migration.rebuild {
+            rebuild_table(tx, table, new_table, &migration)?;
 
-                if require_migration {
-                    tx.execute_batch(&format!("SELECT crsql_commit_alter('{name}');"))?;
-                }
-                info!(
-                    "Altering crsql for table {} took {:?}",
-                    table.name,
-                    start.elapsed()
-                );
+            // indexes went away with the previous table
+            for (idx_name, index) in new_table.indexes.iter() {
+                info!("creating index '{idx_name}'");
+                tx.execute_batch(&index.to_string())?;
             }
-        } else {
-            // 2.2 we do have changed columns, try to do something about that
+            continue;
+        }
 
-            info!("Columns have changed... replacing table {}", table.name);
+        if !migration.added.is_empty() {
+            info!("Altering crsql for table {}", table.name);
             let start = Instant::now();
 
-            let primary_keys = table
-                .columns
-                .values()
-                .filter_map(|col| col.primary_key.then_some(&col.name))
-                .collect::<Vec<&String>>();
-
-            let new_primary_keys = new_table
-                .columns
-                .values()
-                .filter_map(|col| col.primary_key.then_some(&col.name))
-                .collect::<Vec<&String>>();
+            // if all columns are generated, we don't need a migration
+            let require_migration = !migration.added.iter().all(|col| col.generated.is_some());
 
-            if primary_keys != new_primary_keys {
-                return Err(ApplySchemaError::ModifyPrimaryKeys(name.clone()));
+            if require_migration {
+                tx.execute_batch(&format!("SELECT crsql_begin_alter('{name}');"))?;
             }
 
-            // "12-step" process to modifying a table
+            // pre-existing rows aren't written to, they read the column's
+            // default from the table definition
+            for col in migration.added.iter() {
+                info!("adding column '{}'", col.name);
+                tx.execute_batch(&format!("ALTER TABLE {name} ADD COLUMN {}", col))?;
+            }
 
-            // first, create our new table with a temp name
-            let tmp_name = format!(
-                "{name}_{}",
-                SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis()
+            if require_migration {
+                tx.execute_batch(&format!("SELECT crsql_commit_alter('{name}');"))?;
+            }
+            info!(
+                "Altering crsql for table {} took {:?}",
+                table.name,
+                start.elapsed()
             );
-
-            let create_tmp_table = Cmd::Stmt(Stmt::CreateTable {
-                temporary: false,
-                if_not_exists: false,
-                tbl_name: QualifiedName::single(Name(tmp_name.clone())),
-                body: new_table.raw.clone(),
-            });
-
-            tx.execute_batch("SELECT crsql_begin_alter('{name}');")?;
-
-            info!("creating tmp table '{tmp_name}'");
-            tx.execute_batch(&create_tmp_table.to_string())?;
-
-            let col_names = table
-                .columns
-                .keys()
-                .cloned()
-                .collect::<Vec<String>>()
-                .join(",");
-
-            info!("inserting data from '{name}' into '{tmp_name}'");
-            let inserted = tx.execute(
-                &format!("INSERT INTO {tmp_name} ({col_names}) SELECT {col_names} FROM {name}"),
-                (),
-            )?;
-
-            info!("re-inserted {inserted} rows into the new table for {name}");
-
-            info!("dropping old table '{name}', renaming '{tmp_name}' to '{name}'");
-            tx.execute_batch(&format!(
-                "DROP TABLE {name};
-                 ALTER TABLE {tmp_name} RENAME TO {name}"
-            ))?;
-
-            tx.execute_batch(&format!("SELECT crsql_commit_alter('{name}');"))?;
-            info!("Replacing table {} took {:?}", table.name, start.elapsed());
         }
 
-        let new_index_names = new_table
-            .indexes
-            .keys()
-            .collect::<HashSet<_>>()
-            .difference(&table.indexes.keys().collect::<HashSet<_>>())
-            .cloned()
-            .collect::<HashSet<_>>();
-
         let new_indexes_iter = new_table
             .indexes
             .iter()
-            .filter(|(index, _)| new_index_names.contains(index));
+            .filter(|(index, _)| plan.indexes_added.contains(*index));
 
         for (idx_name, index) in new_indexes_iter {
             info!("creating new index '{idx_name}'");
@@ -595,24 +609,17 @@ pub fn apply_schema(
         let dropped_indexes = table
             .indexes
             .keys()
-            .collect::<HashSet<_>>()
-            .difference(&new_table.indexes.keys().collect::<HashSet<_>>())
-            .cloned()
-            .collect::<HashSet<_>>();
+            .filter(|idx_name| plan.indexes_dropped.contains(*idx_name));
 
         for idx_name in dropped_indexes {
             info!("dropping index '{idx_name}'");
             tx.execute_batch(&format!("DROP INDEX {idx_name}"))?;
         }
 
-        let changed_indexes_iter = table.indexes.iter().filter_map(|(idx_name, index)| {
-            let pindex = new_table.indexes.get(idx_name)?;
-            if pindex != index {
-                Some((idx_name, pindex))
-            } else {
-                None
-            }
-        });
+        let changed_indexes_iter = new_table
+            .indexes
+            .iter()
+            .filter(|(idx_name, _)| plan.indexes_replaced.contains(*idx_name));
 
         for (idx_name, index) in changed_indexes_iter {
             info!("replacing index '{idx_name}' (drop + create)");
@@ -634,6 +641,373 @@ pub fn apply_schema(
     Ok(())
 }
 
+/// Changes to the columns of an existing table
+#[derive(Debug)]
+struct TableMigration<'a> {
+    /// Columns removed from the table, along with their values
+    dropped: Vec<&'a String>,
+    /// Renamed columns, previous name first
+    renamed: Vec<(&'a String, &'a String)>,
+    /// Columns new to the table, renamed ones aside
+    added: Vec<&'a Column>,
+    /// `ALTER TABLE` can't make these changes, the table has to be created
+    /// again with its new definition
+    rebuild: bool,
+}
+
+impl<'a> TableMigration<'a> {
+    /// The migration of `new_table`, as checked by [`plan_schema`]
+    fn planned(plan: &'a SchemaPlan, new_table: &'a Table) -> Self {
+        let name = &new_table.name;
+        TableMigration {
+            dropped: plan
+                .columns_dropped
+                .iter()
+                .filter(|col| &col.table == name)
+                .map(|col| &col.column)
+                .collect(),
+            renamed: plan
+                .columns_renamed
+                .iter()
+                .filter(|col| &col.table == name)
+                .map(|col| (&col.from, &col.to))
+                .collect(),
+            added: plan
+                .columns_added
+                .iter()
+                .filter(|col| &col.table == name)
+                .filter_map(|col| new_table.columns.get(&col.column))
+                .collect(),
+            rebuild: plan.tables_rebuilt.contains(name),
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn check_renamed_tables(
+    schema: &Schema,
+    options: &ApplySchemaOptions,
+) -> Result<(), ApplySchemaError> {
+    for (tbl_name, renamed) in options.renamed.iter() {
+        if schema.tables.contains_key(tbl_name) {
+            continue;
+        }
+        if let Some(old) = renamed.keys().next() {
+            return Err(ApplySchemaError::RenameUnknownColumn(
+                tbl_name.clone(),
+                old.clone(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn table_migration<'a>(
+    table: &'a Table,
+    new_table: &'a Table,
+    options: &'a ApplySchemaOptions,
+) -> Result<TableMigration<'a>, ApplySchemaError> {
+    let name = &table.name;
+
+    // 1. renamed columns have to exist, under their new name only in the
+    // new definition
+
+    let renamed = options
+        .renamed
+        .get(name)
+        .into_iter()
+        .flatten()
+        .map(|(old, new)| {
+            if !table.columns.contains_key(old) {
+                return Err(ApplySchemaError::RenameUnknownColumn(
+                    name.clone(),
+                    old.clone(),
+                ));
+            }
+            if new_table.columns.contains_key(old)
+                || !new_table.columns.contains_key(new)
+                || table.columns.contains_key(new)
+            {
+                return Err(ApplySchemaError::InvalidRename {
+                    tbl_name: name.clone(),
+                    from: old.clone(),
+                    to: new.clone(),
+                });
+            }
+            Ok((old, new))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // 2. check column drops... don't allow unless flag is passed
+
+    let dropped = table
+        .columns
+        .keys()
+        .filter(|col_name| {
+            !new_table.columns.contains_key(*col_name)
+                && !renamed.iter().any(|(old, _)| old == col_name)
+        })
+        .collect::<Vec<_>>();
+
+    if !options.allow_destructive {
+        if let Some(col_name) = dropped.first() {
+            return Err(ApplySchemaError::RemoveColumnWithoutDestructiveFlag(
+                name.clone(),
+                (*col_name).clone(),
+            ));
+        }
+    }
+
+    // 3. new columns can't be part of the primary key and need a value for
+    // existing rows
+
+    let added = new_table
+        .columns
+        .values()
+        .filter(|col| {
+            !table.columns.contains_key(&col.name)
+                && !renamed.iter().any(|(_, new)| *new == &col.name)
+        })
+        .collect::<Vec<_>>();
+
+    for col in added.iter() {
+        if col.primary_key {
+            return Err(ApplySchemaError::AddPrimaryKey(
+                name.clone(),
+                col.name.clone(),
+            ));
+        }
+        if !col.nullable && col.default_value.is_none() {
+            return Err(ConstrainedSchemaError::NotNullableColumnNeedsDefault {
+                tbl_name: name.clone(),
+                name: col.name.clone(),
+            }
+            .into());
+        }
+    }
+
+    // 4. changed, dropped or renamed columns mean replacing the table, as
+    // long as its primary key stays the same
+
+    let changed = table.columns.iter().any(|(col_name, col)| {
+        new_table
+            .columns
+            .get(col_name)
+            .is_some_and(|new_col| new_col != col)
+    });
+
+    let rebuild = changed || !dropped.is_empty() || !renamed.is_empty();
+
+    if rebuild {
+        let primary_keys = table
+            .columns
+            .values()
+            .filter_map(|col| col.primary_key.then_some(&col.name))
+            .collect::<Vec<&String>>();
+
+        let new_primary_keys = new_table
+            .columns
+            .values()
+            .filter_map(|col| col.primary_key.then_some(&col.name))
+            .collect::<Vec<&String>>();
+
+        if primary_keys != new_primary_keys {
+            return Err(ApplySchemaError::ModifyPrimaryKeys(name.clone()));
+        }
+    }
+
+    Ok(TableMigration {
+        dropped,
+        renamed,
+        added,
+        rebuild,
+    })
+}
+
+/// Replaces a table with a copy using its new definition. Rows keep their
+/// clocks: those of renamed columns follow them, those of dropped columns
+/// are removed.
+#[allow(clippy::result_large_err)]
+fn rebuild_table(
+    tx: &Transaction,
+    table: &Table,
+    new_table: &Table,
+    migration: &TableMigration,
+) -> Result<(), ApplySchemaError> {
+    let name = &table.name;
+
+    info!("Columns have changed... replacing table {name}");
+    let start = Instant::now();
+
+    // "12-step" process to modifying a table
+
+    // first, create our new table with a temp name
+    let tmp_name = format!(
+        "{name}_{}",
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    );
+
+    let create_tmp_table = Cmd::Stmt(Stmt::CreateTable {
+        temporary: false,
+        if_not_exists: false,
+        tbl_name: QualifiedName::single(Name(tmp_name.clone())),
+        body: new_table.raw.clone(),
+    });
+
+    tx.execute_batch(&format!("SELECT crsql_begin_alter('{name}');"))?;
+
+    info!("creating tmp table '{tmp_name}'");
+    tx.execute_batch(&create_tmp_table.to_string())?;
+
+    // generated columns can't be inserted into, new columns get their
+    // default value
+    let (dst_cols, src_cols): (Vec<&str>, Vec<&str>) = new_table
+        .columns
+        .values()
+        .filter(|col| col.generated.is_none())
+        .filter_map(|col| {
+            let src = migration
+                .renamed
+                .iter()
+                .find(|(_, new)| *new == &col.name)
+                .map(|(old, _)| *old)
+                .or_else(|| {
+                    table
+                        .columns
+                        .get(&col.name)
+                        .filter(|prev| prev.generated.is_none())
+                        .map(|prev| &prev.name)
+                })?;
+            Some((col.name.as_str(), src.as_str()))
+        })
+        .unzip();
+
+    info!("inserting data from '{name}' into '{tmp_name}'");
+    let inserted = tx.execute(
+        &format!(
+            "INSERT INTO {tmp_name} ({}) SELECT {} FROM {name}",
+            dst_cols.join(","),
+            src_cols.join(",")
+        ),
+        (),
+    )?;
+
+    info!("re-inserted {inserted} rows into the new table for {name}");
+
+    info!("dropping old table '{name}', renaming '{tmp_name}' to '{name}'");
+    tx.execute_batch(&format!(
+        "DROP TABLE {name};
+         ALTER TABLE {tmp_name} RENAME TO {name}"
+    ))?;
+
+    // clocks are kept by column name
+    for (old, new) in migration.renamed.iter() {
+        info!("moving clocks of column '{old}' to '{new}'");
+        tx.execute(
+            &format!("UPDATE \"{name}__crsql_clock\" SET col_name = ? WHERE col_name = ?"),
+            [new, old],
+        )?;
+    }
+
+    for col_name in migration.dropped.iter() {
+        info!("removing clocks of dropped column '{col_name}'");
+        tx.execute(
+            &format!("DELETE FROM \"{name}__crsql_clock\" WHERE col_name = ?"),
+            [col_name],
+        )?;
+    }
+
+    tx.execute_batch(&format!("SELECT crsql_commit_alter('{name}');"))?;
+    info!("Replacing table {name} took {:?}", start.elapsed());
+
+    Ok(())
+}
+
+/// What [`apply_schema`] has to do to go from `schema` to `new_schema`,
+/// without touching the database. Changes that can't be applied are errors.
+#[allow(clippy::result_large_err)]
+pub fn plan_schema(
+    schema: &Schema,
+    new_schema: &Schema,
+    options: &ApplySchemaOptions,
+) -> Result<SchemaPlan, ApplySchemaError> {
+    if let Some(name) = schema
+        .tables
+        .keys()
+        .find(|name| !new_schema.tables.contains_key(*name))
+    {
+        // tables are dropped before applying the schema, see `drop_tables`
+        return Err(ApplySchemaError::DropTableWithoutDestructiveFlag(
+            name.clone(),
+        ));
+    }
+
+    check_renamed_tables(schema, options)?;
+
+    let mut plan = SchemaPlan::default();
+
+    for (name, new_table) in new_schema.tables.iter() {
+        let Some(table) = schema.tables.get(name) else {
+            plan.tables_added.push(name.clone());
+            plan.indexes_added.extend(new_table.indexes.keys().cloned());
+            continue;
+        };
+
+        let migration = table_migration(table, new_table, options)?;
+
+        if migration.rebuild {
+            plan.tables_rebuilt.push(name.clone());
+        }
+
+        plan.columns_added
+            .extend(migration.added.iter().map(|col| SchemaColumnRef {
+                table: name.clone(),
+                column: col.name.clone(),
+            }));
+
+        plan.columns_dropped
+            .extend(migration.dropped.iter().map(|col_name| SchemaColumnRef {
+                table: name.clone(),
+                column: (*col_name).clone(),
+            }));
+
+        plan.columns_renamed
+            .extend(
+                migration
+                    .renamed
+                    .iter()
+                    .map(|(old, new)| SchemaColumnRename {
+                        table: name.clone(),
+                        from: (*old).clone(),
+                        to: (*new).clone(),
+                    }),
+            );
+
+        for (idx_name, index) in new_table.indexes.iter() {
+            match table.indexes.get(idx_name) {
+                None => plan.indexes_added.push(idx_name.clone()),
+                Some(prev) if prev != index => plan.indexes_replaced.push(idx_name.clone()),
+                Some(_) => {}
+            }
+        }
+
+        plan.indexes_dropped.extend(
+            table
+                .indexes
+                .keys()
+                .filter(|idx_name| !new_table.indexes.contains_key(*idx_name))
+                .cloned(),
+        );
+    }
+
+    Ok(plan)
+}
+
 #[allow(clippy::result_large_err)]
 pub fn parse_sql_to_schema(schema: &mut Schema, sql: &str) -> Result<(), SchemaError> {
     trace!("parsing {sql}");
@@ -646,65 +1020,143 @@ pub fn parse_sql_to_schema(schema: &mut Schema, sql: &str) -> Result<(), SchemaE
                 eprintln!("Err: {err}");
                 return Err(err.into());
             }
-            Ok(Some(ref cmd @ Cmd::Stmt(ref stmt))) => match stmt {
-                Stmt::CreateTable {
-                    temporary: true, ..
-                } => return Err(SchemaError::TemporaryTable(cmd.clone())),
-                Stmt::CreateTable {
-                    body: CreateTableBody::AsSelect(_),
-                    ..
-                } => return Err(SchemaError::TemporaryTable(cmd.clone())),
-                Stmt::CreateTable {
-                    temporary: false,
-                    if_not_exists: _,
-                    tbl_name,
-                    body:
-                        CreateTableBody::ColumnsAndConstraints {
-                            columns,
-                            constraints,
-                            options,
+            Ok(Some(cmd)) => parse_cmd(schema, cmd)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_cmd(schema: &mut Schema, cmd: Cmd) -> Result<(), SchemaError> {
+    match cmd {
+        ref cmd @ Cmd::Stmt(ref stmt) => match stmt {
+            Stmt::CreateTable {
+                temporary: true, ..
+            } => return Err(SchemaError::TemporaryTable(cmd.clone())),
+            Stmt::CreateTable {
+                body: CreateTableBody::AsSelect(_),
+                ..
+            } => return Err(SchemaError::TemporaryTable(cmd.clone())),
+            Stmt::CreateTable {
+                temporary: false,
+                if_not_exists: _,
+                tbl_name,
+                body:
+                    CreateTableBody::ColumnsAndConstraints {
+                        columns,
+                        constraints,
+                        options,
+                    },
+            } => {
+                let table = prepare_table(tbl_name, columns, constraints.as_ref(), options);
+                schema.tables.insert(table.name.clone(), table);
+                trace!("inserted table: {}", tbl_name.name.0);
+            }
+            Stmt::CreateIndex {
+                unique,
+                idx_name,
+                tbl_name,
+                columns,
+                where_clause,
+                ..
+            } => {
+                let tbl_name = unquote(tbl_name.0.as_str()).unwrap_or_else(|_| tbl_name.0.clone());
+                let idx_name =
+                    unquote(idx_name.name.0.as_str()).unwrap_or_else(|_| idx_name.name.0.clone());
+                if let Some(table) = schema.tables.get_mut(tbl_name.as_str()) {
+                    table.indexes.insert(
+                        idx_name.clone(),
+                        Index {
+                            name: idx_name,
+                            tbl_name,
+                            columns: columns.to_vec(),
+                            where_clause: where_clause.clone(),
+                            unique: *unique,
                         },
-                } => {
-                    let table = prepare_table(tbl_name, columns, constraints.as_ref(), options);
-                    schema.tables.insert(table.name.clone(), table);
-                    trace!("inserted table: {}", tbl_name.name.0);
-                }
-                Stmt::CreateIndex {
-                    unique,
-                    idx_name,
-                    tbl_name,
-                    columns,
-                    where_clause,
-                    ..
-                } => {
-                    let tbl_name =
-                        unquote(tbl_name.0.as_str()).unwrap_or_else(|_| tbl_name.0.clone());
-                    let idx_name = unquote(idx_name.name.0.as_str())
-                        .unwrap_or_else(|_| idx_name.name.0.clone());
-                    if let Some(table) = schema.tables.get_mut(tbl_name.as_str()) {
-                        table.indexes.insert(
-                            idx_name.clone(),
-                            Index {
-                                name: idx_name,
-                                tbl_name,
-                                columns: columns.to_vec(),
-                                where_clause: where_clause.clone(),
-                                unique: *unique,
-                            },
-                        );
-                    } else {
-                        return Err(SchemaError::IndexWithoutTable {
-                            tbl_name: tbl_name.clone(),
-                            name: idx_name.clone(),
-                        });
-                    }
+                    );
+                } else {
+                    return Err(SchemaError::IndexWithoutTable {
+                        tbl_name: tbl_name.clone(),
+                        name: idx_name.clone(),
+                    });
                 }
-                _ => return Err(SchemaError::UnsupportedCmd(cmd.clone())),
-            },
-            Ok(Some(cmd)) => return Err(SchemaError::UnsupportedCmd(cmd)),
+            }
+            _ => return Err(SchemaError::UnsupportedCmd(cmd.clone())),
+        },
+        cmd => return Err(SchemaError::UnsupportedCmd(cmd)),
+    }
+
+    Ok(())
+}
+
+/// A `DROP TABLE` statement of a schema change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DropTable {
+    pub name: String,
+    pub if_exists: bool,
+}
+
+/// Statements of a schema change: tables (re)defined and tables dropped.
+/// Unlike schemas, changes can drop tables.
+#[derive(Debug, Clone, Default)]
+pub struct SchemaChange {
+    pub schema: Schema,
+    pub dropped: Vec<DropTable>,
+    /// Columns renamed with `ALTER TABLE ... RENAME COLUMN`, new name by
+    /// previous name, by table
+    pub renamed: IndexMap<String, IndexMap<String, String>>,
+}
+
+#[allow(clippy::result_large_err)]
+pub fn parse_schema_change(sql: &str) -> Result<SchemaChange, SchemaError> {
+    trace!("parsing {sql}");
+    let mut parser = sqlite3_parser::lexer::sql::Parser::new(sql.as_bytes());
+
+    let mut change = SchemaChange::default();
+    loop {
+        match parser.next()? {
+            None => break,
+            Some(Cmd::Stmt(Stmt::DropTable {
+                if_exists,
+                tbl_name,
+            })) => {
+                change.dropped.push(DropTable {
+                    name: unquote(&tbl_name.name.0).unwrap_or_else(|_| tbl_name.name.0.clone()),
+                    if_exists,
+                });
+            }
+            Some(Cmd::Stmt(Stmt::AlterTable(
+                tbl_name,
+                AlterTableBody::RenameColumn { old, new },
+            ))) => {
+                let unquoted = |name: &Name| unquote(&name.0).unwrap_or_else(|_| name.0.clone());
+                change
+                    .renamed
+                    .entry(unquoted(&tbl_name.name))
+                    .or_default()
+                    .insert(unquoted(&old), unquoted(&new));
+            }
+            Some(cmd) => parse_cmd(&mut change.schema, cmd)?,
         }
     }
 
+    Ok(change)
+}
+
+/// Drops tables along with what cr-sqlite keeps to track their changes.
+/// Their indexes and triggers go with them.
+#[allow(clippy::result_large_err)]
+pub fn drop_tables(tx: &Transaction, names: &[String]) -> Result<(), ApplySchemaError> {
+    for name in names {
+        info!("dropping table '{name}'");
+        tx.execute_batch(&format!(
+            "DROP TABLE \"{name}\";
+             DROP TABLE IF EXISTS \"{name}__crsql_clock\";
+             DROP TABLE IF EXISTS \"{name}__crsql_pks\";"
+        ))?;
+    }
+
     Ok(())
 }
 
@@ -844,3 +1296,46 @@ fn prepare_table(
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_drift() -> rusqlite::Result<()> {
+        let digest = |sqls: &[(&str, &str)]| -> rusqlite::Result<BTreeMap<String, u64>> {
+            let conn = Connection::open_in_memory()?;
+            conn.execute_batch(
+                "CREATE TABLE __corro_schema (tbl_name TEXT NOT NULL, type TEXT NOT NULL, name TEXT NOT NULL, sql TEXT NOT NULL, source TEXT NOT NULL, PRIMARY KEY (tbl_name, type, name));",
+            )?;
+            for (name, sql) in sqls {
+                conn.execute(
+                    "INSERT INTO __corro_schema VALUES (?, 'table', ?, ?, 'fs')",
+                    [name, name, sql],
+                )?;
+            }
+            schema_digest(&conn)
+        };
+
+        let ours = digest(&[
+            (
+                "users",
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT)",
+            ),
+            ("posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY)"),
+        ])?;
+        let theirs = digest(&[
+            (
+                "users",
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, email TEXT)",
+            ),
+            ("posts", "CREATE TABLE posts (id INTEGER PRIMARY KEY)"),
+            ("tags", "CREATE TABLE tags (id INTEGER PRIMARY KEY)"),
+        ])?;
+
+        assert!(schema_drift(&ours, &ours).is_empty());
+        assert_eq!(schema_drift(&ours, &theirs), vec!["users", "tags"]);
+
+        Ok(())
+    }
+}
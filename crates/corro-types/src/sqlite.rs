@@ -1,16 +1,19 @@
 use std::{
     ops::{Deref, DerefMut},
-    time::Instant,
+    path::Path,
+    time::{Duration, Instant},
 };
 
+use metrics::counter;
 use once_cell::sync::Lazy;
-use rusqlite::{params, Connection, Transaction};
+use rusqlite::{params, Connection, ErrorCode, Transaction, TransactionBehavior};
 use sqlite_pool::SqliteConn;
 use tempfile::TempDir;
 use tracing::{error, info, trace};
 
 pub type SqlitePool = sqlite_pool::Pool<CrConn>;
 pub type SqlitePoolError = sqlite_pool::PoolError;
+pub type SqlitePooledConn = sqlite_pool::Connection<CrConn>;
 
 const CRSQL_EXT_GENERIC_NAME: &str = "crsqlite";
 
@@ -58,6 +61,20 @@ impl CrConn {
         self.0
             .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
     }
+
+    /// Like `immediate_transaction`, retrying while the database is busy or
+    /// locked, see [`retry_busy`]
+    pub fn immediate_transaction_retry(
+        &mut self,
+        path: &'static str,
+    ) -> rusqlite::Result<Transaction> {
+        // holding `&mut self` won't do here, a busy attempt would keep it
+        // borrowed for the next one
+        let conn = &self.0;
+        retry_busy(path, || {
+            Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+        })
+    }
 }
 
 impl SqliteConn for CrConn {
@@ -108,6 +125,28 @@ fn init_cr_conn(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     Ok(())
 }
 
+/// Schema of the node-local, in-memory database attached to pooled
+/// connections. Its tables aren't replicated and are lost on restart, but can
+/// be joined with replicated tables.
+pub const EPHEMERAL_SCHEMA: &str = "ephemeral";
+
+/// URI of the in-memory database holding ephemeral tables. memdb databases
+/// whose name starts with a `/` are shared by every connection of the
+/// process, naming it after the main database keeps agents of a same process
+/// apart.
+pub fn ephemeral_db_uri(db_path: &Path) -> String {
+    let hash = seahash::hash(db_path.to_string_lossy().as_bytes());
+    format!(
+        "file:/corro-ephemeral-{}?vfs=memdb",
+        hex::encode(hash.to_be_bytes())
+    )
+}
+
+pub fn attach_ephemeral(conn: &Connection, uri: &str) -> rusqlite::Result<()> {
+    conn.execute(&format!("ATTACH DATABASE ? AS {EPHEMERAL_SCHEMA}"), [uri])?;
+    Ok(())
+}
+
 pub fn setup_conn(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     // WAL journal mode and synchronous NORMAL for best performance / crash resilience compromise
     conn.execute_batch(
@@ -121,6 +160,57 @@ pub fn setup_conn(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     Ok(())
 }
 
+/// How many times an operation failing with SQLITE_BUSY or SQLITE_LOCKED is
+/// attempted before giving up
+pub const BUSY_RETRY_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled for every following one
+const BUSY_RETRY_BASE_DELAY: Duration = Duration::from_millis(10);
+
+/// Whether an error is transient write contention
+pub fn is_busy(e: &rusqlite::Error) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(e, _)
+            if matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+/// Runs `f`, retrying with a jittered exponential backoff while it fails
+/// because the database is busy or locked. `path` labels the retry metrics.
+///
+/// This sleeps the current thread, call it from `block_in_place`. A statement
+/// failing with SQLITE_BUSY has no effect, retrying it within a transaction
+/// is fine.
+pub fn retry_busy<T, F>(path: &'static str, mut f: F) -> rusqlite::Result<T>
+where
+    F: FnMut() -> rusqlite::Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(e) if is_busy(&e) => {
+                if attempt >= BUSY_RETRY_ATTEMPTS {
+                    counter!("corro.sqlite.busy.exhausted", "path" => path).increment(1);
+                    return Err(e);
+                }
+                counter!("corro.sqlite.busy.retries", "path" => path).increment(1);
+
+                // sleep between half and all of the backoff so contending
+                // writers don't retry in lockstep
+                let backoff = BUSY_RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+                let half = backoff.as_micros() as u64 / 2;
+                let delay = half + rand::random::<u64>() % (half + 1);
+                trace!("database busy, retrying in {delay}us (attempt {attempt}): {e}");
+                std::thread::sleep(Duration::from_micros(delay));
+
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
 pub trait Migration {
     fn migrate(&self, tx: &Transaction) -> rusqlite::Result<()>;
 }
@@ -242,6 +332,44 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_retry_busy() {
+        let busy = || {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            )
+        };
+
+        let mut calls = 0;
+        let res = retry_busy("test", || {
+            calls += 1;
+            if calls < 3 {
+                Err(busy())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+
+        let mut calls = 0;
+        let res: rusqlite::Result<()> = retry_busy("test", || {
+            calls += 1;
+            Err(busy())
+        });
+        assert!(res.as_ref().is_err_and(is_busy));
+        assert_eq!(calls, BUSY_RETRY_ATTEMPTS);
+
+        // other errors are returned right away
+        let mut calls = 0;
+        let res: rusqlite::Result<()> = retry_busy("test", || {
+            calls += 1;
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        });
+        assert!(matches!(res, Err(rusqlite::Error::QueryReturnedNoRows)));
+        assert_eq!(calls, 1);
+    }
+
     #[derive(Debug, thiserror::Error)]
     enum TestError {
         #[error(transparent)]
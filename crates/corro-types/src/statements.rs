@@ -0,0 +1,289 @@
+//! Metadata of the statements sent to the API: how many parameters they
+//! take, whether they only read and which tables they touch. It's cached by
+//! normalized SQL, so hot statements are only prepared once to be classified
+//! and authorized. The cache is emptied when the database's schema changes.
+
+use std::sync::{Arc, Mutex as StdMutex};
+
+use indexmap::IndexMap;
+use metrics::counter;
+use parking_lot::Mutex;
+use rusqlite::{
+    hooks::{AuthAction, AuthContext, Authorization},
+    Connection,
+};
+
+/// Statements kept in the cache, the oldest ones are evicted first
+const STATEMENT_CACHE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableAccess {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TouchedTable {
+    pub name: String,
+    pub access: TableAccess,
+    /// Touched by a view or a trigger, rather than by the statement itself
+    pub indirect: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementInfo {
+    pub param_count: usize,
+    pub readonly: bool,
+    pub tables: Vec<TouchedTable>,
+    /// Does more than reading and writing rows: changes the schema, runs a
+    /// pragma, attaches a database...
+    pub other: bool,
+}
+
+impl StatementInfo {
+    /// Prepares `sql` (without running it) to find out what it does
+    pub fn describe(conn: &Connection, sql: &str) -> rusqlite::Result<Self> {
+        // the authorizer has to be unwind safe
+        let touched: Arc<StdMutex<(Vec<TouchedTable>, bool)>> = Default::default();
+
+        conn.authorizer(Some({
+            let touched = touched.clone();
+            move |ctx: AuthContext<'_>| {
+                let mut touched = touched.lock().unwrap();
+                let (name, access) = match ctx.action {
+                    AuthAction::Read { table_name, .. } => (table_name, TableAccess::Read),
+                    AuthAction::Insert { table_name }
+                    | AuthAction::Update { table_name, .. }
+                    | AuthAction::Delete { table_name } => (table_name, TableAccess::Write),
+                    AuthAction::Select
+                    | AuthAction::Function { .. }
+                    | AuthAction::Recursive
+                    | AuthAction::Transaction { .. }
+                    | AuthAction::Savepoint { .. } => return Authorization::Allow,
+                    _ => {
+                        touched.1 = true;
+                        return Authorization::Allow;
+                    }
+                };
+                let indirect = ctx.accessor.is_some();
+                if !touched.0.iter().any(|table| {
+                    table.name == name && table.access == access && table.indirect == indirect
+                }) {
+                    touched.0.push(TouchedTable {
+                        name: name.to_owned(),
+                        access,
+                        indirect,
+                    });
+                }
+                Authorization::Allow
+            }
+        }));
+
+        let prepared = conn
+            .prepare(sql)
+            .map(|prepped| (prepped.parameter_count(), prepped.readonly()));
+
+        conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+
+        let (param_count, readonly) = prepared?;
+        let (tables, other) = std::mem::take(&mut *touched.lock().unwrap());
+
+        Ok(Self {
+            param_count,
+            readonly,
+            tables,
+            other,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StatementCache(Mutex<InnerStatementCache>);
+
+#[derive(Debug, Default)]
+struct InnerStatementCache {
+    /// Of the database when the cached statements were described
+    schema_version: i64,
+    statements: IndexMap<String, Arc<StatementInfo>>,
+}
+
+impl StatementCache {
+    /// Metadata of `sql`, described with `conn` if it isn't cached yet or
+    /// the schema changed since it was. Statements which don't prepare are
+    /// not cached.
+    pub fn get_or_describe(
+        &self,
+        conn: &Connection,
+        sql: &str,
+    ) -> rusqlite::Result<Arc<StatementInfo>> {
+        let schema_version: i64 =
+            conn.pragma_query_value(None, "schema_version", |row| row.get(0))?;
+        let key = normalize(sql);
+
+        {
+            let mut inner = self.0.lock();
+            if inner.schema_version != schema_version {
+                inner.statements.clear();
+                inner.schema_version = schema_version;
+            }
+            if let Some(info) = inner.statements.get(&key) {
+                counter!("corro.api.statement_cache.lookups", "outcome" => "hit").increment(1);
+                return Ok(info.clone());
+            }
+        }
+
+        counter!("corro.api.statement_cache.lookups", "outcome" => "miss").increment(1);
+        let info = Arc::new(StatementInfo::describe(conn, sql)?);
+
+        let mut inner = self.0.lock();
+        if inner.schema_version == schema_version {
+            if inner.statements.len() >= STATEMENT_CACHE_CAPACITY {
+                inner.statements.shift_remove_index(0);
+            }
+            inner.statements.insert(key, info.clone());
+        }
+
+        Ok(info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Collapses whitespace outside of quotes and comments, so statements only
+/// differing by their formatting share a cache entry. Comments are kept
+/// as-is: a line comment ends with its line break.
+fn normalize(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.trim().chars().peekable();
+    let mut space = false;
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            space = true;
+            continue;
+        }
+        if space {
+            out.push(' ');
+            space = false;
+        }
+        out.push(c);
+
+        let end = match (c, chars.peek()) {
+            ('\'' | '"' | '`', _) => c,
+            ('[', _) => ']',
+            ('-', Some('-')) => '\n',
+            ('/', Some('*')) => {
+                out.push(chars.next().unwrap_or_default());
+                // closed by the `/` of `*/`
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    out.push(c);
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        // doubled quotes are read as a closing and an opening quote
+        for c in chars.by_ref() {
+            out.push(c);
+            if c == end {
+                break;
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("  SELECT *\n\tFROM  tests  WHERE text = 'a  b'  "),
+            "SELECT * FROM tests WHERE text = 'a  b'"
+        );
+        assert_eq!(
+            normalize("SELECT \"my  col\", [other  col] FROM t WHERE a = 'it''s  ok'"),
+            "SELECT \"my  col\", [other  col] FROM t WHERE a = 'it''s  ok'"
+        );
+        // line breaks end comments, they can't be collapsed
+        assert_ne!(
+            normalize("SELECT * FROM a -- x\nUNION SELECT * FROM b"),
+            normalize("SELECT * FROM a -- x UNION SELECT * FROM b")
+        );
+        assert_eq!(
+            normalize("SELECT /* a  comment */  1"),
+            "SELECT /* a  comment */ 1"
+        );
+    }
+
+    #[test]
+    fn test_statement_cache() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE tests (id INTEGER PRIMARY KEY, text TEXT);
+             CREATE TABLE logs (id INTEGER PRIMARY KEY, msg TEXT);
+             CREATE TRIGGER log_tests AFTER INSERT ON tests
+                 BEGIN INSERT INTO logs (msg) VALUES (new.text); END;",
+        )?;
+
+        let cache = StatementCache::default();
+
+        let info = cache.get_or_describe(&conn, "SELECT text FROM tests WHERE id = ?")?;
+        assert_eq!(info.param_count, 1);
+        assert!(info.readonly);
+        assert!(!info.other);
+        assert_eq!(
+            info.tables,
+            vec![TouchedTable {
+                name: "tests".into(),
+                access: TableAccess::Read,
+                indirect: false
+            }]
+        );
+
+        // formatted differently, same entry
+        let again = cache.get_or_describe(&conn, "SELECT text\n  FROM tests WHERE id = ?")?;
+        assert!(Arc::ptr_eq(&info, &again));
+        assert_eq!(cache.len(), 1);
+
+        let info = cache.get_or_describe(&conn, "INSERT INTO tests (text) VALUES (:text)")?;
+        assert_eq!(info.param_count, 1);
+        assert!(!info.readonly);
+        assert!(info.tables.contains(&TouchedTable {
+            name: "tests".into(),
+            access: TableAccess::Write,
+            indirect: false
+        }));
+        assert!(info.tables.contains(&TouchedTable {
+            name: "logs".into(),
+            access: TableAccess::Write,
+            indirect: true
+        }));
+
+        assert!(cache.get_or_describe(&conn, "DROP TABLE logs")?.other);
+        assert!(cache.get_or_describe(&conn, "SELECT * FROM nope").is_err());
+        assert_eq!(cache.len(), 3);
+
+        // statements are described again once the schema changed
+        conn.execute_batch("ALTER TABLE tests ADD COLUMN extra TEXT")?;
+        cache.get_or_describe(&conn, "SELECT text FROM tests WHERE id = ?")?;
+        assert_eq!(cache.len(), 1);
+
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
-use std::{cmp, collections::HashMap, io, ops::RangeInclusive};
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap},
+    io::{self, Read},
+    ops::RangeInclusive,
+};
 
 use bytes::BytesMut;
 use opentelemetry::propagation::{Extractor, Injector};
@@ -27,6 +32,149 @@ pub enum SyncMessageV1 {
     Clock(Timestamp),
     Rejection(SyncRejectionV1),
     Request(SyncRequestV1),
+    Challenge(SyncChallengeV1),
+    ChallengeResponse(SyncChallengeResponseV1),
+    /// Encoded sync message, compressed for clients which asked for it
+    Compressed {
+        codec: SyncCompressionV1,
+        data: Vec<u8>,
+    },
+}
+
+/// Largest a compressed sync message can decompress to, the frame length
+/// limit uncompressed messages are read with
+pub const MAX_DECOMPRESSED_LEN: u64 = 8 * 1024 * 1024;
+
+/// Codec a sync client wants the changesets it receives compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Readable, Writable)]
+pub enum SyncCompressionV1 {
+    Zstd { level: i32 },
+}
+
+impl SyncCompressionV1 {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncCompressionV1::Zstd { .. } => "zstd",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            SyncCompressionV1::Zstd { level } => zstd::bulk::compress(data, *level),
+        }
+    }
+
+    /// Fails with [`io::ErrorKind::InvalidData`] instead of decompressing
+    /// more than [`MAX_DECOMPRESSED_LEN`] bytes
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut raw = vec![];
+        match self {
+            SyncCompressionV1::Zstd { .. } => zstd::Decoder::new(data)?
+                .take(MAX_DECOMPRESSED_LEN + 1)
+                .read_to_end(&mut raw)?,
+        };
+        if raw.len() as u64 > MAX_DECOMPRESSED_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("sync message decompresses to more than {MAX_DECOMPRESSED_LEN} bytes"),
+            ));
+        }
+        Ok(raw)
+    }
+}
+
+/// Random nonce the other side needs to sign with the cluster secret, for
+/// the challenger's actor id
+#[derive(Debug, Clone, PartialEq, Readable, Writable)]
+pub struct SyncChallengeV1 {
+    pub actor_id: ActorId,
+    pub nonce: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Readable, Writable)]
+pub struct SyncChallengeResponseV1 {
+    pub actor_id: ActorId,
+    pub tag: Vec<u8>,
+}
+
+/// Side of the sync answering a challenge, signed along with it so an
+/// answer can't be reflected back to the other side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncRole {
+    Client,
+    Server,
+}
+
+impl SyncRole {
+    fn label(self) -> &'static [u8] {
+        match self {
+            SyncRole::Client => b"client",
+            SyncRole::Server => b"server",
+        }
+    }
+}
+
+/// Binds sync handshakes to the actor ids peers claim: only nodes knowing
+/// the cluster secret can answer challenges for their actor id.
+///
+/// The server only answers the client's challenge once the client answered
+/// its own, so it can't be used to answer challenges for unauthenticated
+/// clients.
+pub struct SyncAuth {
+    key: ring::hmac::Key,
+}
+
+impl SyncAuth {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    pub fn challenge(actor_id: ActorId) -> SyncChallengeV1 {
+        SyncChallengeV1 {
+            actor_id,
+            nonce: rand::random::<[u8; 32]>().to_vec(),
+        }
+    }
+
+    /// Answers a challenge as `actor_id`, on the `role` side of the sync
+    pub fn respond(
+        &self,
+        role: SyncRole,
+        actor_id: ActorId,
+        challenge: &SyncChallengeV1,
+    ) -> Vec<u8> {
+        ring::hmac::sign(&self.key, &Self::message(role, actor_id, challenge))
+            .as_ref()
+            .to_vec()
+    }
+
+    /// Checks a response to a challenge we sent, from the `role` side of the
+    /// sync
+    pub fn verify(
+        &self,
+        role: SyncRole,
+        challenge: &SyncChallengeV1,
+        res: &SyncChallengeResponseV1,
+    ) -> bool {
+        ring::hmac::verify(
+            &self.key,
+            &Self::message(role, res.actor_id, challenge),
+            &res.tag,
+        )
+        .is_ok()
+    }
+
+    fn message(role: SyncRole, actor_id: ActorId, challenge: &SyncChallengeV1) -> Vec<u8> {
+        let label = role.label();
+        let mut msg = Vec::with_capacity(label.len() + 32 + challenge.nonce.len());
+        msg.extend_from_slice(label);
+        msg.extend_from_slice(actor_id.0.as_bytes());
+        msg.extend_from_slice(challenge.actor_id.0.as_bytes());
+        msg.extend_from_slice(&challenge.nonce);
+        msg
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Readable, Writable)]
@@ -74,6 +222,28 @@ pub enum SyncRejectionV1 {
     MaxConcurrencyReached,
     #[error("different cluster")]
     DifferentCluster,
+    #[error("not allowed")]
+    NotAllowed,
+    #[error("could not authenticate actor")]
+    Unauthenticated,
+}
+
+impl SyncRejectionV1 {
+    /// Kinds of rejections known by clients which don't tell which ones they
+    /// can read
+    pub const INITIAL_KINDS: u32 = 2;
+    /// Kinds of rejections this version can read
+    pub const KINDS: u32 = 4;
+
+    /// Tag of the variant on the wire, variants are only ever appended
+    pub fn kind(&self) -> u32 {
+        match self {
+            SyncRejectionV1::MaxConcurrencyReached => 0,
+            SyncRejectionV1::DifferentCluster => 1,
+            SyncRejectionV1::NotAllowed => 2,
+            SyncRejectionV1::Unauthenticated => 3,
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Readable, Writable, Serialize, Deserialize)]
@@ -82,6 +252,10 @@ pub struct SyncStateV1 {
     pub heads: HashMap<ActorId, Version>,
     pub need: HashMap<ActorId, Vec<RangeInclusive<Version>>>,
     pub partial_need: HashMap<ActorId, HashMap<Version, Vec<RangeInclusive<CrsqlSeq>>>>,
+    /// Hash of each table's schema, see `schema::schema_digest`
+    #[speedy(default_on_eof)]
+    #[serde(default)]
+    pub schema: BTreeMap<String, u64>,
 }
 
 impl SyncStateV1 {
@@ -375,6 +549,58 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_compression_roundtrip() -> Result<(), SyncMessageDecodeError> {
+        let msg = SyncMessage::V1(SyncMessageV1::State(SyncStateV1 {
+            schema: (0..100).map(|i| (format!("table_{i}"), i)).collect(),
+            ..Default::default()
+        }));
+        let encoded = msg.write_to_vec()?;
+
+        let codec = SyncCompressionV1::Zstd { level: 3 };
+        let data = codec.compress(&encoded)?;
+        assert!(data.len() < encoded.len());
+
+        let compressed = SyncMessage::V1(SyncMessageV1::Compressed { codec, data });
+        let SyncMessage::V1(SyncMessageV1::Compressed { codec, data }) =
+            SyncMessage::from_slice(compressed.write_to_vec()?)?
+        else {
+            panic!("expected a compressed message");
+        };
+        assert_eq!(SyncMessage::from_slice(codec.decompress(&data)?)?, msg);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompression_limit() -> io::Result<()> {
+        let codec = SyncCompressionV1::Zstd { level: 3 };
+
+        let raw = vec![0u8; MAX_DECOMPRESSED_LEN as usize];
+        assert_eq!(codec.decompress(&codec.compress(&raw)?)?, raw);
+
+        let bomb = codec.compress(&vec![0u8; MAX_DECOMPRESSED_LEN as usize + 1])?;
+        let err = codec.decompress(&bomb).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejection_kinds() {
+        let rejections = [
+            SyncRejectionV1::MaxConcurrencyReached,
+            SyncRejectionV1::DifferentCluster,
+            SyncRejectionV1::NotAllowed,
+            SyncRejectionV1::Unauthenticated,
+        ];
+        assert_eq!(rejections.len() as u32, SyncRejectionV1::KINDS);
+        for rejection in rejections {
+            let buf = rejection.write_to_vec().unwrap();
+            assert_eq!(u32::read_from_buffer(&buf).unwrap(), rejection.kind());
+        }
+    }
+
     #[test]
     fn test_compute_available_needs() {
         let actor1 = ActorId(Uuid::new_v4());
@@ -490,4 +716,45 @@ mod tests {
             .into()
         );
     }
+
+    #[test]
+    fn test_sync_auth() {
+        let client = ActorId(Uuid::new_v4());
+        let server = ActorId(Uuid::new_v4());
+        let auth = SyncAuth::new("s3cr3t");
+
+        let challenge = SyncAuth::challenge(client);
+        let res = SyncChallengeResponseV1 {
+            actor_id: server,
+            tag: auth.respond(SyncRole::Server, server, &challenge),
+        };
+        assert!(auth.verify(SyncRole::Server, &challenge, &res));
+
+        // claiming another actor id
+        let spoofed = SyncChallengeResponseV1 {
+            actor_id: ActorId(Uuid::new_v4()),
+            ..res.clone()
+        };
+        assert!(!auth.verify(SyncRole::Server, &challenge, &spoofed));
+
+        // answering another challenge
+        assert!(!auth.verify(SyncRole::Server, &SyncAuth::challenge(client), &res));
+
+        // answering a challenge sent by someone else
+        let relayed = SyncChallengeV1 {
+            actor_id: ActorId(Uuid::new_v4()),
+            ..challenge.clone()
+        };
+        assert!(!auth.verify(SyncRole::Server, &relayed, &res));
+
+        // reflecting an answer from the other side
+        assert!(!auth.verify(SyncRole::Client, &challenge, &res));
+
+        // using another secret
+        let other = SyncChallengeResponseV1 {
+            tag: SyncAuth::new("other").respond(SyncRole::Server, server, &challenge),
+            ..res
+        };
+        assert!(!auth.verify(SyncRole::Server, &challenge, &other));
+    }
 }
@@ -0,0 +1,866 @@
+//! Scoped API tokens, minted with the root bearer token to delegate limited
+//! access to the API: to some tables, for some verbs, until some time. Only
+//! the hashes of tokens are stored, in a local table which isn't replicated,
+//! so a token is only valid on the node which minted it.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use enquote::unquote;
+use itertools::Itertools;
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use rusqlite::{named_params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sqlite3_parser::{
+    ast::{
+        As, Cmd, Expr, FromClause, JoinConstraint, Name, OneSelect, Operator, QualifiedName,
+        ResultColumn, Select, SelectTable, Stmt,
+    },
+    lexer::sql::Parser,
+};
+use uuid::Uuid;
+
+use crate::{
+    schema::Schema,
+    statements::{StatementInfo, TableAccess},
+};
+
+const TOKEN_PREFIX: &str = "corro_";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("could not generate a random token")]
+    Rand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenVerb {
+    /// Queries and snapshots
+    Read,
+    /// Transactions and truncations
+    Write,
+    /// Subscriptions
+    Subscribe,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenScope {
+    /// Tables the token can access, every table if empty
+    #[serde(default)]
+    pub tables: Vec<String>,
+    pub verbs: Vec<TokenVerb>,
+    /// Unix timestamp (in seconds) after which the token is rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// SQL predicates, by table, rows must match to be visible to the token.
+    /// Filtered tables are read-only for the token.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub row_filters: BTreeMap<String, String>,
+}
+
+impl TokenScope {
+    pub fn allows_verb(&self, verb: TokenVerb) -> bool {
+        self.verbs.contains(&verb)
+    }
+
+    /// Corrosion's and cr-sqlite's own tables are never accessible
+    pub fn allows_table(&self, table: &str) -> bool {
+        !is_internal_table(table)
+            && (self.tables.is_empty() || self.tables.iter().any(|t| t == table))
+    }
+
+    /// Table names are case-insensitive, like in SQLite
+    pub fn row_filter(&self, table: &str) -> Option<(&str, &str)> {
+        self.row_filters
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(table))
+            .map(|(name, predicate)| (name.as_str(), predicate.as_str()))
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+
+    /// Checks every table a statement touches is in the scope. Statements
+    /// changing the schema are never allowed, and neither are writes from
+    /// read-only scopes, nor transactions touching tables with row filters.
+    pub fn authorizes(&self, info: &StatementInfo, write: bool) -> bool {
+        if info.other {
+            return false;
+        }
+
+        info.tables.iter().all(|table| {
+            if table.indirect {
+                // accessed through a view or a trigger
+                if is_internal_table(&table.name) {
+                    return true;
+                }
+            } else {
+                let filtered = self.row_filter(&table.name).is_some();
+                let denied = match table.access {
+                    // row filters are only applied to queries
+                    TableAccess::Read => write && filtered,
+                    TableAccess::Write => !write || filtered,
+                };
+                if denied {
+                    return false;
+                }
+            }
+            self.allows_table(&table.name)
+        })
+    }
+}
+
+fn is_internal_table(table: &str) -> bool {
+    table.starts_with("__corro")
+        || table.starts_with("sqlite_")
+        || table.starts_with("crsql_")
+        || table.contains("__crsql_")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub scope: TokenScope,
+    pub created_at: u64,
+    /// Until when the secret replaced by the last rotation is still valid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_expires_at: Option<u64>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn new_secret() -> Result<String, TokenError> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| TokenError::Rand)?;
+    Ok(format!("{TOKEN_PREFIX}{}", hex::encode(bytes)))
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(digest(&SHA256, secret.as_bytes()))
+}
+
+/// Stores a new token, its secret is only ever returned here
+pub fn mint(
+    conn: &Connection,
+    name: Option<String>,
+    scope: TokenScope,
+) -> Result<(ApiToken, String), TokenError> {
+    let secret = new_secret()?;
+    let token = ApiToken {
+        id: Uuid::new_v4(),
+        name,
+        scope,
+        created_at: now(),
+        prev_expires_at: None,
+    };
+
+    conn.prepare_cached(
+        "INSERT INTO __corro_api_tokens (id, name, hash, scope, created_at)
+            VALUES (:id, :name, :hash, :scope, :created_at)",
+    )?
+    .execute(named_params! {
+        ":id": token.id,
+        ":name": token.name,
+        ":hash": hash_secret(&secret),
+        ":scope": serde_json::to_string(&token.scope)?,
+        ":created_at": token.created_at,
+    })?;
+
+    Ok((token, secret))
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<ApiToken>, TokenError> {
+    let rows = conn
+        .prepare_cached(
+            "SELECT id, name, scope, created_at, prev_expires_at FROM __corro_api_tokens ORDER BY created_at",
+        )?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, Uuid>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, u64>(3)?,
+                row.get::<_, Option<u64>>(4)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    rows.into_iter()
+        .map(|(id, name, scope, created_at, prev_expires_at)| {
+            Ok(ApiToken {
+                id,
+                name,
+                scope: serde_json::from_str(&scope)?,
+                created_at,
+                prev_expires_at,
+            })
+        })
+        .collect()
+}
+
+/// Returns `false` if there was no such token
+pub fn revoke(conn: &Connection, id: Uuid) -> Result<bool, TokenError> {
+    Ok(conn
+        .prepare_cached("DELETE FROM __corro_api_tokens WHERE id = ?")?
+        .execute([id])?
+        > 0)
+}
+
+/// Replaces the secret of a token, keeping its scope. The previous secret
+/// keeps working for `grace`, so clients can switch over without downtime.
+pub fn rotate(conn: &Connection, id: Uuid, grace: Duration) -> Result<Option<String>, TokenError> {
+    let secret = new_secret()?;
+    let updated = conn
+        .prepare_cached(
+            "UPDATE __corro_api_tokens
+                SET prev_hash = hash, prev_expires_at = :prev_expires_at, hash = :hash
+                WHERE id = :id",
+        )?
+        .execute(named_params! {
+            ":id": id,
+            ":hash": hash_secret(&secret),
+            ":prev_expires_at": now() + grace.as_secs(),
+        })?;
+
+    Ok((updated > 0).then_some(secret))
+}
+
+/// Finds the valid token with this secret
+pub fn find(conn: &Connection, secret: &str) -> Result<Option<ApiToken>, TokenError> {
+    if !secret.starts_with(TOKEN_PREFIX) {
+        return Ok(None);
+    }
+
+    let now = now();
+    let hash = hash_secret(secret);
+
+    let row = conn
+        .prepare_cached(
+            "SELECT id, name, scope, created_at, prev_expires_at FROM __corro_api_tokens
+                WHERE hash = :hash OR (prev_hash = :hash AND prev_expires_at > :now)",
+        )?
+        .query_row(named_params! { ":hash": hash, ":now": now }, |row| {
+            Ok((
+                row.get::<_, Uuid>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, u64>(3)?,
+                row.get::<_, Option<u64>>(4)?,
+            ))
+        })
+        .optional()?;
+
+    let Some((id, name, scope, created_at, prev_expires_at)) = row else {
+        return Ok(None);
+    };
+
+    let token = ApiToken {
+        id,
+        name,
+        scope: serde_json::from_str(&scope)?,
+        created_at,
+        prev_expires_at,
+    };
+
+    if token.scope.is_expired(now) {
+        return Ok(None);
+    }
+
+    Ok(Some(token))
+}
+
+/// Prepares the statement to check every table it touches is in the scope,
+/// see [`TokenScope::authorizes`]
+pub fn authorize_statement(
+    conn: &Connection,
+    sql: &str,
+    scope: &TokenScope,
+    write: bool,
+) -> rusqlite::Result<()> {
+    let info = StatementInfo::describe(conn, sql)?;
+    if scope.authorizes(&info, write) {
+        Ok(())
+    } else {
+        Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_AUTH),
+            Some("not authorized".into()),
+        ))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RowFilterError {
+    #[error(transparent)]
+    Parse(#[from] sqlite3_parser::lexer::sql::Error),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("statements on tables with row filters must be a single SELECT")]
+    NotSelect,
+    #[error("table '{0}' does not exist")]
+    TableNotFound(String),
+    #[error("{0} are not supported on tables with row filters")]
+    Unsupported(&'static str),
+}
+
+/// Rewrites a query so rows of filtered tables only show up if they match
+/// their table's predicate. Every reference to a filtered table, including
+/// in subqueries and CTEs, gets its own condition:
+///
+/// ```sql
+/// ("t"."pk" IS NULL OR ("t"."pk") IN (SELECT "pk" FROM main."t" WHERE (<predicate>)))
+/// ```
+///
+/// Outer joins leave NULL primary keys, rows which didn't join anything
+/// aren't filtered out. Constructs the rewrite can't follow are refused.
+pub fn apply_row_filters(
+    sql: &str,
+    scope: &TokenScope,
+    schema: &Schema,
+) -> Result<String, RowFilterError> {
+    if scope.row_filters.is_empty() {
+        return Ok(sql.to_owned());
+    }
+
+    let mut select = parse_select(sql)?;
+    RowFilters { scope, schema }.filter_select(&mut select)?;
+
+    let mut sql = Cmd::Stmt(Stmt::Select(select)).to_string();
+    // trailing semicolon
+    sql.pop();
+    Ok(sql)
+}
+
+/// Checks the filters of a scope apply to existing tables and are valid
+/// predicates, before minting a token
+pub fn validate_row_filters(
+    conn: &Connection,
+    scope: &TokenScope,
+    schema: &Schema,
+) -> Result<(), RowFilterError> {
+    for table in scope.row_filters.keys() {
+        if !schema.tables.contains_key(table) {
+            return Err(RowFilterError::TableNotFound(table.clone()));
+        }
+        let sql = apply_row_filters(&format!("SELECT * FROM {}", ident(table)), scope, schema)?;
+        conn.prepare(&sql)?;
+    }
+    Ok(())
+}
+
+fn parse_select(sql: &str) -> Result<Select, RowFilterError> {
+    let mut parser = Parser::new(sql.as_bytes());
+    let select = match parser.next()? {
+        Some(Cmd::Stmt(Stmt::Select(select))) => select,
+        _ => return Err(RowFilterError::NotSelect),
+    };
+    if parser.next()?.is_some() {
+        return Err(RowFilterError::NotSelect);
+    }
+    Ok(select)
+}
+
+/// Quotes identifiers only when required, the matcher looks tables up by
+/// their unquoted names
+fn ident(name: &str) -> String {
+    let plain = name
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        name.to_owned()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+fn and_where(where_clause: &mut Option<Expr>, filter: Expr) {
+    *where_clause = Some(match where_clause.take() {
+        Some(prev) => Expr::Binary(
+            Box::new(Expr::parenthesized(prev)),
+            Operator::And,
+            Box::new(filter),
+        ),
+        None => filter,
+    });
+}
+
+struct RowFilters<'a> {
+    scope: &'a TokenScope,
+    schema: &'a Schema,
+}
+
+impl RowFilters<'_> {
+    /// Name and predicate of the filter applying to a table reference
+    fn filter_for(&self, name: &QualifiedName) -> Option<(&str, &str)> {
+        let in_main = name.db_name.as_ref().map_or(true, |db| {
+            unquote(&db.0).unwrap_or_else(|_| db.0.clone()) == "main"
+        });
+        if !in_main {
+            return None;
+        }
+        self.scope
+            .row_filter(&unquote(&name.name.0).unwrap_or_else(|_| name.name.0.clone()))
+    }
+
+    fn table_filter(
+        &self,
+        table: &str,
+        predicate: &str,
+        reference: &Name,
+    ) -> Result<Expr, RowFilterError> {
+        let tbl = self
+            .schema
+            .tables
+            .get(table)
+            .ok_or_else(|| RowFilterError::TableNotFound(table.to_owned()))?;
+
+        let pks = tbl.pk.iter().map(|pk| ident(pk)).collect::<Vec<_>>();
+        let Some(first_pk) = pks.first() else {
+            return Err(RowFilterError::TableNotFound(table.to_owned()));
+        };
+
+        let select = parse_select(&format!(
+            "SELECT {} FROM main.{} WHERE ({predicate})",
+            pks.iter().join(", "),
+            ident(table)
+        ))?;
+        let column = |pk: &String| Expr::Qualified(reference.clone(), Name(pk.clone()));
+
+        Ok(Expr::parenthesized(Expr::Binary(
+            Box::new(Expr::IsNull(Box::new(column(first_pk)))),
+            Operator::Or,
+            Box::new(Expr::InSelect {
+                lhs: Box::new(Expr::Parenthesized(pks.iter().map(column).collect())),
+                not: false,
+                rhs: Box::new(select),
+            }),
+        )))
+    }
+
+    fn filter_select(&self, select: &mut Select) -> Result<(), RowFilterError> {
+        if let Some(with) = select.with.as_mut() {
+            for cte in with.ctes.iter_mut() {
+                self.filter_select(&mut cte.select)?;
+            }
+        }
+        self.filter_one_select(&mut select.body.select)?;
+        if let Some(compounds) = select.body.compounds.as_mut() {
+            for compound in compounds.iter_mut() {
+                self.filter_one_select(&mut compound.select)?;
+            }
+        }
+        if let Some(order_by) = select.order_by.as_mut() {
+            for col in order_by.iter_mut() {
+                self.filter_expr(&mut col.expr)?;
+            }
+        }
+        if let Some(limit) = select.limit.as_mut() {
+            self.filter_expr(&mut limit.expr)?;
+            if let Some(offset) = limit.offset.as_mut() {
+                self.filter_expr(offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn filter_one_select(&self, select: &mut OneSelect) -> Result<(), RowFilterError> {
+        match select {
+            OneSelect::Select {
+                columns,
+                from,
+                where_clause,
+                group_by,
+                window_clause,
+                ..
+            } => {
+                if window_clause.is_some() {
+                    return Err(RowFilterError::Unsupported("WINDOW clauses"));
+                }
+                for col in columns.iter_mut() {
+                    if let ResultColumn::Expr(expr, _) = col {
+                        self.filter_expr(expr)?;
+                    }
+                }
+                if let Some(expr) = where_clause.as_mut() {
+                    self.filter_expr(expr)?;
+                }
+                if let Some(group_by) = group_by.as_mut() {
+                    for expr in group_by.exprs.iter_mut() {
+                        self.filter_expr(expr)?;
+                    }
+                    if let Some(having) = group_by.having.as_mut() {
+                        self.filter_expr(having)?;
+                    }
+                }
+
+                let mut filters = vec![];
+                if let Some(from) = from.as_mut() {
+                    self.filter_from(from, &mut filters)?;
+                }
+                for filter in filters {
+                    and_where(where_clause, filter);
+                }
+            }
+            OneSelect::Values(rows) => {
+                for expr in rows.iter_mut().flatten() {
+                    self.filter_expr(expr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the conditions tables referenced by a FROM clause need
+    fn filter_from(
+        &self,
+        from: &mut FromClause,
+        filters: &mut Vec<Expr>,
+    ) -> Result<(), RowFilterError> {
+        if let Some(table) = from.select.as_mut() {
+            self.filter_table(table, filters)?;
+        }
+        if let Some(joins) = from.joins.as_mut() {
+            for join in joins.iter_mut() {
+                self.filter_table(&mut join.table, filters)?;
+                if let Some(JoinConstraint::On(expr)) = join.constraint.as_mut() {
+                    self.filter_expr(expr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn filter_table(
+        &self,
+        table: &mut SelectTable,
+        filters: &mut Vec<Expr>,
+    ) -> Result<(), RowFilterError> {
+        match table {
+            SelectTable::Table(name, alias, _) => {
+                if let Some((table, predicate)) = self.filter_for(name) {
+                    let reference = match alias {
+                        Some(As::As(alias) | As::Elided(alias)) => alias.clone(),
+                        None => name.alias.clone().unwrap_or_else(|| name.name.clone()),
+                    };
+                    filters.push(self.table_filter(table, predicate, &reference)?);
+                }
+            }
+            SelectTable::TableCall(_, args, _) => {
+                for expr in args.iter_mut().flatten() {
+                    self.filter_expr(expr)?;
+                }
+            }
+            SelectTable::Select(select, _) => self.filter_select(select)?,
+            SelectTable::Sub(from, _) => self.filter_from(from, filters)?,
+        }
+        Ok(())
+    }
+
+    fn filter_exprs(&self, exprs: &mut [Expr]) -> Result<(), RowFilterError> {
+        for expr in exprs.iter_mut() {
+            self.filter_expr(expr)?;
+        }
+        Ok(())
+    }
+
+    /// Follows expressions into the subqueries they hold
+    fn filter_expr(&self, expr: &mut Expr) -> Result<(), RowFilterError> {
+        match expr {
+            Expr::Between {
+                lhs, start, end, ..
+            } => {
+                self.filter_expr(lhs)?;
+                self.filter_expr(start)?;
+                self.filter_expr(end)?;
+            }
+            Expr::Binary(lhs, _, rhs) => {
+                self.filter_expr(lhs)?;
+                self.filter_expr(rhs)?;
+            }
+            Expr::Case {
+                base,
+                when_then_pairs,
+                else_expr,
+            } => {
+                if let Some(base) = base {
+                    self.filter_expr(base)?;
+                }
+                for (when_expr, then_expr) in when_then_pairs.iter_mut() {
+                    self.filter_expr(when_expr)?;
+                    self.filter_expr(then_expr)?;
+                }
+                if let Some(else_expr) = else_expr {
+                    self.filter_expr(else_expr)?;
+                }
+            }
+            Expr::Cast { expr, .. }
+            | Expr::Collate(expr, _)
+            | Expr::IsNull(expr)
+            | Expr::NotNull(expr)
+            | Expr::Unary(_, expr) => self.filter_expr(expr)?,
+            Expr::Exists(select) | Expr::Subquery(select) => self.filter_select(select)?,
+            Expr::FunctionCall {
+                args, filter_over, ..
+            } => {
+                if filter_over.is_some() {
+                    return Err(RowFilterError::Unsupported("FILTER and OVER clauses"));
+                }
+                if let Some(args) = args {
+                    self.filter_exprs(args)?;
+                }
+            }
+            Expr::FunctionCallStar { filter_over, .. } => {
+                if filter_over.is_some() {
+                    return Err(RowFilterError::Unsupported("FILTER and OVER clauses"));
+                }
+            }
+            Expr::InList { lhs, rhs, .. } => {
+                self.filter_expr(lhs)?;
+                if let Some(rhs) = rhs {
+                    self.filter_exprs(rhs)?;
+                }
+            }
+            Expr::InSelect { lhs, rhs, .. } => {
+                self.filter_expr(lhs)?;
+                self.filter_select(rhs)?;
+            }
+            Expr::InTable { lhs, rhs, args, .. } => {
+                if self.filter_for(rhs).is_some() {
+                    return Err(RowFilterError::Unsupported("IN <table> operands"));
+                }
+                self.filter_expr(lhs)?;
+                if let Some(args) = args {
+                    self.filter_exprs(args)?;
+                }
+            }
+            Expr::Like {
+                lhs, rhs, escape, ..
+            } => {
+                self.filter_expr(lhs)?;
+                self.filter_expr(rhs)?;
+                if let Some(escape) = escape {
+                    self.filter_expr(escape)?;
+                }
+            }
+            Expr::Parenthesized(exprs) => self.filter_exprs(exprs)?,
+            // no subqueries in there
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> rusqlite::Result<Connection> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE __corro_api_tokens (
+                id BLOB NOT NULL PRIMARY KEY,
+                name TEXT,
+                hash TEXT NOT NULL UNIQUE,
+                scope TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                prev_hash TEXT UNIQUE,
+                prev_expires_at INTEGER
+            ) WITHOUT ROWID;
+            CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+            CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT);",
+        )?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn test_mint_rotate_revoke() -> Result<(), Box<dyn std::error::Error>> {
+        let conn = setup()?;
+
+        let scope = TokenScope {
+            tables: vec!["users".into()],
+            verbs: vec![TokenVerb::Read],
+            expires_at: None,
+            row_filters: BTreeMap::new(),
+        };
+        let (token, secret) = mint(&conn, Some("dashboard".into()), scope.clone())?;
+        assert!(secret.starts_with(TOKEN_PREFIX));
+
+        let found = find(&conn, &secret)?.expect("token not found");
+        assert_eq!(found.id, token.id);
+        assert_eq!(found.scope, scope);
+        assert!(find(&conn, "corro_nope")?.is_none());
+
+        // the old secret remains valid during the grace period
+        let rotated = rotate(&conn, token.id, Duration::from_secs(60))?.unwrap();
+        assert!(find(&conn, &rotated)?.is_some());
+        assert!(find(&conn, &secret)?.is_some());
+
+        let rotated_again = rotate(&conn, token.id, Duration::ZERO)?.unwrap();
+        assert!(find(&conn, &rotated_again)?.is_some());
+        assert!(find(&conn, &rotated)?.is_none());
+        assert!(find(&conn, &secret)?.is_none());
+
+        assert_eq!(list(&conn)?.len(), 1);
+        assert!(revoke(&conn, token.id)?);
+        assert!(!revoke(&conn, token.id)?);
+        assert!(find(&conn, &rotated_again)?.is_none());
+
+        let (_, expired) = mint(
+            &conn,
+            None,
+            TokenScope {
+                expires_at: Some(now() - 1),
+                ..scope
+            },
+        )?;
+        assert!(find(&conn, &expired)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_authorize_statement() -> rusqlite::Result<()> {
+        let conn = setup()?;
+
+        let scope = TokenScope {
+            tables: vec!["users".into()],
+            verbs: vec![TokenVerb::Read, TokenVerb::Write],
+            expires_at: None,
+            row_filters: BTreeMap::new(),
+        };
+
+        authorize_statement(&conn, "SELECT name FROM users", &scope, false)?;
+        authorize_statement(&conn, "SELECT 1", &scope, false)?;
+        authorize_statement(&conn, "UPDATE users SET name = 'x'", &scope, true)?;
+
+        assert!(authorize_statement(&conn, "UPDATE users SET name = 'x'", &scope, false).is_err());
+        assert!(authorize_statement(
+            &conn,
+            "SELECT * FROM users JOIN posts ON posts.id = users.id",
+            &scope,
+            false
+        )
+        .is_err());
+        assert!(
+            authorize_statement(&conn, "SELECT hash FROM __corro_api_tokens", &scope, false)
+                .is_err()
+        );
+        assert!(authorize_statement(&conn, "DROP TABLE users", &scope, true).is_err());
+
+        // every table, but internal ones
+        let all = TokenScope {
+            tables: vec![],
+            ..scope
+        };
+        authorize_statement(&conn, "SELECT * FROM posts", &all, false)?;
+        assert!(
+            authorize_statement(&conn, "SELECT hash FROM __corro_api_tokens", &all, false).is_err()
+        );
+
+        // the authorizer is gone once done
+        conn.prepare("SELECT hash FROM __corro_api_tokens")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_row_filters() -> Result<(), Box<dyn std::error::Error>> {
+        let conn = setup()?;
+        conn.execute_batch(
+            "CREATE TABLE accounts (id INTEGER NOT NULL PRIMARY KEY, tenant TEXT NOT NULL, name TEXT);
+            INSERT INTO accounts VALUES (1, 'acme', 'a1'), (2, 'acme', 'a2'), (3, 'other', 'o1');
+            INSERT INTO users VALUES (1, 'one'), (3, 'three'), (4, 'four');",
+        )?;
+        let schema = crate::schema::parse_sql(
+            "CREATE TABLE accounts (id INTEGER NOT NULL PRIMARY KEY, tenant TEXT NOT NULL, name TEXT);
+            CREATE TABLE users (id INTEGER NOT NULL PRIMARY KEY, name TEXT);",
+        )?;
+
+        let scope = TokenScope {
+            tables: vec![],
+            verbs: vec![TokenVerb::Read, TokenVerb::Write],
+            expires_at: None,
+            row_filters: [("accounts".to_string(), "tenant = 'acme'".to_string())].into(),
+        };
+        validate_row_filters(&conn, &scope, &schema)?;
+
+        let query = |sql: &str| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            let sql = apply_row_filters(sql, &scope, &schema)?;
+            let mut prepped = conn.prepare(&sql)?;
+            let rows = prepped
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(rows)
+        };
+
+        assert_eq!(
+            query("SELECT name FROM accounts ORDER BY id")?,
+            vec!["a1", "a2"]
+        );
+        assert_eq!(
+            query("SELECT a.name FROM main.accounts AS a WHERE a.id > 1 ORDER BY a.id")?,
+            vec!["a2"]
+        );
+        assert_eq!(
+            query("SELECT CAST((SELECT count(*) FROM accounts) AS TEXT)")?,
+            vec!["2"]
+        );
+        // rows which didn't join anything are kept, filtered ones are gone
+        assert_eq!(
+            query("SELECT users.name || ':' || ifnull(accounts.name, '') FROM users LEFT JOIN accounts ON accounts.id = users.id ORDER BY users.id")?,
+            vec!["one:a1", "four:"]
+        );
+        assert_eq!(
+            query("WITH names AS (SELECT name FROM accounts) SELECT name FROM names UNION ALL SELECT name FROM users WHERE id IN (SELECT id FROM accounts) ORDER BY 1")?,
+            vec!["a1", "a2", "one"]
+        );
+
+        assert!(matches!(
+            apply_row_filters("SELECT count(*) OVER () FROM accounts", &scope, &schema),
+            Err(RowFilterError::Unsupported(_))
+        ));
+        assert!(matches!(
+            apply_row_filters("DELETE FROM accounts", &scope, &schema),
+            Err(RowFilterError::NotSelect)
+        ));
+
+        // filtered tables are read-only, and can't be read from transactions
+        authorize_statement(&conn, "SELECT name FROM accounts", &scope, false)?;
+        assert!(
+            authorize_statement(&conn, "UPDATE accounts SET name = 'x'", &scope, true).is_err()
+        );
+        assert!(authorize_statement(
+            &conn,
+            "INSERT INTO users SELECT id, name FROM accounts",
+            &scope,
+            true
+        )
+        .is_err());
+        authorize_statement(&conn, "UPDATE users SET name = 'x'", &scope, true)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! Pre-commit validation of local transactions. Embedders register
+//! validators enforcing their own invariants: they run right before a
+//! transaction with changes is committed and can veto it, in which case it's
+//! rolled back and never replicated.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use corro_api_types::{Statement, TableName};
+use metrics::counter;
+use parking_lot::RwLock;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::base::CrsqlDbVersion;
+
+/// Changes a transaction is about to commit, for a single table
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableChangeSummary {
+    /// Rows inserted, updated or deleted
+    pub rows: u64,
+    /// Rows deleted
+    pub deletes: u64,
+    /// Cells changed, including the row markers of inserts and deletes
+    pub cells: u64,
+}
+
+/// Changes a transaction is about to commit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeSummary {
+    pub db_version: CrsqlDbVersion,
+    pub tables: BTreeMap<TableName, TableChangeSummary>,
+}
+
+impl ChangeSummary {
+    /// Summarizes the changes of `db_version`, `conn` being within the
+    /// transaction producing them
+    pub fn read(conn: &Connection, db_version: CrsqlDbVersion) -> rusqlite::Result<Self> {
+        let mut prepped = conn.prepare_cached(
+            r#"
+            SELECT "table",
+                   COUNT(DISTINCT pk),
+                   COUNT(DISTINCT CASE WHEN cid = '-1' AND cl % 2 = 0 THEN pk END),
+                   COUNT(*)
+                FROM crsql_changes
+                WHERE db_version = ?
+                GROUP BY "table"
+            "#,
+        )?;
+
+        let tables = prepped
+            .query_map([db_version], |row| {
+                Ok((
+                    row.get(0)?,
+                    TableChangeSummary {
+                        rows: row.get(1)?,
+                        deletes: row.get(2)?,
+                        cells: row.get(3)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Self { db_version, tables })
+    }
+}
+
+/// What a validator gets to look at before a transaction is committed
+pub struct PendingTransaction<'a> {
+    /// Statements sent by the client, empty for writes made by corrosion
+    /// itself (truncations, backfills)
+    pub statements: &'a [Statement],
+    pub changes: &'a ChangeSummary,
+    /// Connection within the transaction, to check invariants against the
+    /// state it would commit
+    pub tx: &'a Connection,
+}
+
+/// Reason given by a validator for rejecting a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("{code}: {message}")]
+pub struct Veto {
+    /// Machine-readable reason, e.g. `negative_balance`
+    pub code: String,
+    pub message: String,
+}
+
+impl Veto {
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+}
+
+pub trait TxValidator: Send + Sync + 'static {
+    /// Name identifying the validator in errors and metrics
+    fn name(&self) -> &str;
+
+    fn validate(&self, pending: &PendingTransaction<'_>) -> Result<(), Veto>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("transaction vetoed by validator '{validator}': {veto}")]
+pub struct ValidationError {
+    pub validator: String,
+    #[source]
+    pub veto: Veto,
+}
+
+/// Validators run, in registration order, before committing local
+/// transactions
+#[derive(Clone, Default)]
+pub struct Validators(Arc<RwLock<Vec<Arc<dyn TxValidator>>>>);
+
+impl Validators {
+    pub fn register(&self, validator: Arc<dyn TxValidator>) {
+        self.0.write().push(validator);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().is_empty()
+    }
+
+    /// Runs every validator, stopping at the first veto
+    pub fn validate(&self, pending: &PendingTransaction<'_>) -> Result<(), ValidationError> {
+        for validator in self.0.read().iter() {
+            if let Err(veto) = validator.validate(pending) {
+                counter!("corro.validators.vetoes", "validator" => validator.name().to_string())
+                    .increment(1);
+                return Err(ValidationError {
+                    validator: validator.name().to_string(),
+                    veto,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    struct MaxRows(u64);
+
+    impl TxValidator for MaxRows {
+        fn name(&self) -> &str {
+            "max_rows"
+        }
+
+        fn validate(&self, pending: &PendingTransaction<'_>) -> Result<(), Veto> {
+            let rows: u64 = pending.changes.tables.values().map(|t| t.rows).sum();
+            if rows > self.0 {
+                return Err(Veto::new("too_many_rows", format!("{rows} rows changed")));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_validators() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let tx = conn.transaction().unwrap();
+
+        let mut changes = ChangeSummary {
+            db_version: CrsqlDbVersion(1),
+            tables: BTreeMap::new(),
+        };
+        changes.tables.insert(
+            TableName("tests".into()),
+            TableChangeSummary {
+                rows: 2,
+                deletes: 0,
+                cells: 4,
+            },
+        );
+
+        let validators = Validators::default();
+        validators.register(Arc::new(MaxRows(2)));
+
+        let statements = vec![Statement::from("INSERT INTO tests VALUES (1), (2)")];
+        let pending = PendingTransaction {
+            statements: &statements,
+            changes: &changes,
+            tx: &tx,
+        };
+        assert!(validators.validate(&pending).is_ok());
+
+        validators.register(Arc::new(MaxRows(1)));
+        let err = validators.validate(&pending).unwrap_err();
+        assert_eq!(err.validator, "max_rows");
+        assert_eq!(err.veto.code, "too_many_rows");
+    }
+}
@@ -0,0 +1,317 @@
+//! Watches on a set of rows of a table, identified by their primary keys.
+//! Unlike subscriptions, they don't run any SQL: changes are matched against
+//! the watched keys as they're committed or applied, which keeps watching a
+//! large number of rows cheap.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use compact_str::ToCompactString;
+use corro_api_types::{Change, CloseReason, ColumnName, KeyWatchEvent, SqliteValue, TableName};
+use indexmap::IndexMap;
+use metrics::counter;
+use parking_lot::RwLock;
+use tokio::sync::mpsc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    actor::ActorId,
+    pubsub::{pack_columns, PackError},
+};
+
+#[derive(Clone, Default)]
+pub struct KeyWatches(Arc<RwLock<InnerKeyWatches>>);
+
+#[derive(Default)]
+struct InnerKeyWatches {
+    watches: HashMap<Uuid, KeyWatch>,
+    /// Watch ids by table and packed primary key
+    keys: HashMap<TableName, HashMap<Vec<u8>, Vec<Uuid>>>,
+}
+
+struct KeyWatch {
+    table: TableName,
+    /// Primary keys, packed the way cr-sqlite does, and their values
+    pks: HashMap<Vec<u8>, Vec<SqliteValue>>,
+    tx: mpsc::Sender<KeyWatchEvent>,
+}
+
+/// Removes the watch when dropped
+pub struct KeyWatchGuard {
+    id: Uuid,
+    watches: KeyWatches,
+}
+
+impl KeyWatchGuard {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+impl Drop for KeyWatchGuard {
+    fn drop(&mut self) {
+        self.watches.remove(&self.id);
+    }
+}
+
+/// State of a row after all of its changes in a changeset
+enum RowChange {
+    Upsert(BTreeMap<ColumnName, SqliteValue>),
+    Delete,
+}
+
+impl KeyWatches {
+    /// Watches rows of `table` by primary key. Events are sent through `tx`
+    /// until the returned guard is dropped, or until `tx` is full: a watch
+    /// falling behind is removed, which closes the channel.
+    pub fn watch(
+        &self,
+        table: TableName,
+        pks: Vec<Vec<SqliteValue>>,
+        tx: mpsc::Sender<KeyWatchEvent>,
+    ) -> Result<KeyWatchGuard, PackError> {
+        let pks = pks
+            .into_iter()
+            .map(|pk| Ok((pack_columns(&pk)?, pk)))
+            .collect::<Result<HashMap<_, _>, PackError>>()?;
+
+        let id = Uuid::new_v4();
+        let mut inner = self.0.write();
+
+        let keys = inner.keys.entry(table.clone()).or_default();
+        for packed in pks.keys() {
+            keys.entry(packed.clone()).or_default().push(id);
+        }
+        inner.watches.insert(id, KeyWatch { table, pks, tx });
+
+        Ok(KeyWatchGuard {
+            id,
+            watches: self.clone(),
+        })
+    }
+
+    pub fn remove(&self, id: &Uuid) {
+        let mut inner = self.0.write();
+        let Some(watch) = inner.watches.remove(id) else {
+            return;
+        };
+
+        if let Some(keys) = inner.keys.get_mut(&watch.table) {
+            for packed in watch.pks.keys() {
+                if let Some(ids) = keys.get_mut(packed) {
+                    ids.retain(|watch_id| watch_id != id);
+                    if ids.is_empty() {
+                        keys.remove(packed);
+                    }
+                }
+            }
+            if keys.is_empty() {
+                inner.keys.remove(&watch.table);
+            }
+        }
+    }
+
+    /// Ends the watches on rows of `table`, for when it's dropped: they get a
+    /// `closed` event with the `table_dropped` reason. Returns how many
+    /// watches ended.
+    pub fn close_table(&self, table: &str) -> usize {
+        let ids = {
+            let inner = self.0.read();
+            inner
+                .watches
+                .iter()
+                .filter(|(_, watch)| watch.table.as_str() == table)
+                .map(|(id, watch)| {
+                    _ = watch.tx.try_send(KeyWatchEvent::Closed {
+                        reason: CloseReason::TableDropped,
+                    });
+                    *id
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for id in ids.iter() {
+            self.remove(id);
+        }
+
+        ids.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.read().watches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sends an event to the watches of every row touched by `changes`
+    pub fn match_changes(&self, changes: &[Change]) {
+        let mut lagging = vec![];
+
+        {
+            let inner = self.0.read();
+            if inner.keys.is_empty() {
+                return;
+            }
+
+            // a row can be deleted and re-created in a single changeset,
+            // only its last state (and the actor which made it) counts
+            let mut rows: IndexMap<(&TableName, &[u8]), (RowChange, [u8; 16])> = IndexMap::new();
+            for change in changes {
+                let watched = inner
+                    .keys
+                    .get(&change.table)
+                    .map_or(false, |keys| keys.contains_key(change.pk.as_slice()));
+                if !watched {
+                    continue;
+                }
+
+                let (row, site_id) = rows
+                    .entry((&change.table, change.pk.as_slice()))
+                    .or_insert_with(|| (RowChange::Upsert(BTreeMap::new()), change.site_id));
+                *site_id = change.site_id;
+
+                if change.cid.is_crsql_sentinel() {
+                    // an even causal length means the row is deleted
+                    if change.cl % 2 == 0 {
+                        *row = RowChange::Delete;
+                    } else if matches!(row, RowChange::Delete) {
+                        *row = RowChange::Upsert(BTreeMap::new());
+                    }
+                } else {
+                    match row {
+                        RowChange::Upsert(cells) => {
+                            cells.insert(change.cid.clone(), change.val.clone());
+                        }
+                        RowChange::Delete => {
+                            *row = RowChange::Upsert(
+                                [(change.cid.clone(), change.val.clone())].into(),
+                            );
+                        }
+                    }
+                }
+            }
+
+            for ((table, packed), (row, site_id)) in rows {
+                let actor_id = ActorId::from_bytes(site_id).to_compact_string();
+                let ids = match inner.keys.get(table).and_then(|keys| keys.get(packed)) {
+                    Some(ids) => ids,
+                    None => continue,
+                };
+                for id in ids {
+                    let Some(watch) = inner.watches.get(id) else {
+                        continue;
+                    };
+                    let Some(pk) = watch.pks.get(packed) else {
+                        continue;
+                    };
+                    let evt = match &row {
+                        RowChange::Upsert(cells) => KeyWatchEvent::Upsert {
+                            table: table.clone(),
+                            pk: pk.clone(),
+                            cells: cells.clone(),
+                            actor_id: actor_id.clone(),
+                        },
+                        RowChange::Delete => KeyWatchEvent::Delete {
+                            table: table.clone(),
+                            pk: pk.clone(),
+                            actor_id: actor_id.clone(),
+                        },
+                    };
+                    counter!("corro.watches.keys.events", "table" => table.to_string())
+                        .increment(1);
+                    if watch.tx.try_send(evt).is_err() {
+                        lagging.push(*id);
+                    }
+                }
+            }
+        }
+
+        for id in lagging {
+            warn!(watch_id = %id, "removing key watch which fell behind or is closed");
+            self.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::{CrsqlDbVersion, CrsqlSeq};
+
+    fn change(pk: i64, cid: &str, val: SqliteValue, cl: i64, site_id: u8) -> Change {
+        Change {
+            table: TableName("sessions".into()),
+            pk: pack_columns(&[pk.into()]).unwrap(),
+            cid: ColumnName(cid.into()),
+            val,
+            col_version: 1,
+            db_version: CrsqlDbVersion(1),
+            seq: CrsqlSeq(0),
+            site_id: [site_id; 16],
+            cl,
+        }
+    }
+
+    #[test]
+    fn test_key_watches() {
+        let watches = KeyWatches::default();
+        let (tx, mut rx) = mpsc::channel(8);
+        let guard = watches
+            .watch(
+                TableName("sessions".into()),
+                vec![vec![1i64.into()], vec![2i64.into()]],
+                tx,
+            )
+            .unwrap();
+
+        watches.match_changes(&[
+            change(1, "-1", SqliteValue::Null, 1, 1),
+            change(1, "user", "jane".into(), 1, 1),
+            change(3, "user", "john".into(), 1, 1),
+            change(2, "-1", SqliteValue::Null, 2, 2),
+        ]);
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            KeyWatchEvent::Upsert {
+                table: TableName("sessions".into()),
+                pk: vec![1i64.into()],
+                cells: [(ColumnName("user".into()), "jane".into())].into(),
+                actor_id: ActorId::from_bytes([1; 16]).to_compact_string(),
+            }
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            KeyWatchEvent::Delete {
+                table: TableName("sessions".into()),
+                pk: vec![2i64.into()],
+                actor_id: ActorId::from_bytes([2; 16]).to_compact_string(),
+            }
+        );
+        assert!(rx.try_recv().is_err());
+
+        drop(guard);
+        assert!(watches.is_empty());
+        assert!(watches.0.read().keys.is_empty());
+
+        let (tx, mut rx) = mpsc::channel(8);
+        let _guard = watches
+            .watch(TableName("sessions".into()), vec![vec![1i64.into()]], tx)
+            .unwrap();
+
+        assert_eq!(watches.close_table("users"), 0);
+        assert_eq!(watches.close_table("sessions"), 1);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            KeyWatchEvent::Closed {
+                reason: CloseReason::TableDropped
+            }
+        );
+        assert!(watches.is_empty());
+    }
+}
@@ -30,6 +30,29 @@ impl AdminConn {
     }
 
     pub async fn send_command(&mut self, cmd: Command) -> eyre::Result<()> {
+        self.run(cmd, |json| {
+            println!("{}", serde_json::to_string_pretty(&json).unwrap())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Sends a command and returns the JSON it responded with, instead of
+    /// printing it. Fails if the command did.
+    pub async fn json_command(&mut self, cmd: Command) -> eyre::Result<Vec<serde_json::Value>> {
+        let mut values = vec![];
+        if !self.run(cmd, |json| values.push(json)).await? {
+            eyre::bail!("admin command failed");
+        }
+        Ok(values)
+    }
+
+    /// Returns whether the command succeeded
+    async fn run(
+        &mut self,
+        cmd: Command,
+        mut on_json: impl FnMut(serde_json::Value),
+    ) -> eyre::Result<bool> {
         self.stream.send(cmd).await?;
 
         loop {
@@ -38,7 +61,7 @@ impl AdminConn {
             match res {
                 None => {
                     error!("Failed to get response from Corrosion's admin!");
-                    break;
+                    return Ok(false);
                 }
                 Some(res) => match res {
                     Response::Log { level, msg, ts } => match level {
@@ -50,18 +73,14 @@ impl AdminConn {
                     },
                     Response::Error { msg } => {
                         error!("{msg}");
-                        break;
+                        return Ok(false);
                     }
                     Response::Success => {
-                        break;
-                    }
-                    Response::Json(json) => {
-                        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+                        return Ok(true);
                     }
+                    Response::Json(json) => on_json(json),
                 },
             }
         }
-
-        Ok(())
     }
 }
@@ -3,20 +3,23 @@ use std::{net::SocketAddr, time::Duration};
 use build_info::VersionControl;
 use camino::Utf8PathBuf;
 use corro_admin::AdminConfig;
-use corro_types::config::{Config, PrometheusConfig};
+use corro_types::config::{Config, TelemetryConfig};
 use metrics::gauge;
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
 use spawn::wait_for_all_pending_handles;
 use tokio_metrics::RuntimeMonitor;
 use tracing::{error, info};
 
-use crate::VERSION;
+use crate::{
+    command::reload,
+    statsd::{Fanout, StatsdRecorder},
+    VERSION,
+};
 
 pub async fn run(config: Config, config_path: &Utf8PathBuf) -> eyre::Result<()> {
     info!("Starting Corrosion Agent v{VERSION}");
 
-    if let Some(PrometheusConfig { bind_addr }) = config.telemetry.prometheus {
-        setup_prometheus(bind_addr).expect("could not setup prometheus");
+    if setup_metrics(&config.telemetry).expect("could not setup metrics") {
         let info = crate::version().clone();
 
         // I know this is cloned a lot, but I don't care since it's called once
@@ -55,7 +58,7 @@ pub async fn run(config: Config, config_path: &Utf8PathBuf) -> eyre::Result<()>
             listen_path: config.admin.uds_path.clone(),
             config_path: config_path.clone(),
         },
-        tripwire,
+        tripwire.clone(),
     )?;
 
     if !config.db.schema_paths.is_empty() {
@@ -74,6 +77,16 @@ pub async fn run(config: Config, config_path: &Utf8PathBuf) -> eyre::Result<()>
                 error!("could not apply schema: {e}");
             }
         }
+
+        if config.db.watch_schema {
+            let api_addr = config.api.bind_addr;
+            let schema_paths = config.db.schema_paths.clone();
+            tokio::spawn(async move {
+                if let Err(e) = reload::watch(api_addr, schema_paths, tripwire).await {
+                    error!("could not watch schema files: {e}");
+                }
+            });
+        }
     }
 
     tripwire_worker.await;
@@ -83,8 +96,50 @@ pub async fn run(config: Config, config_path: &Utf8PathBuf) -> eyre::Result<()>
     Ok(())
 }
 
-fn setup_prometheus(addr: SocketAddr) -> eyre::Result<()> {
-    PrometheusBuilder::new()
+/// Installs the configured metrics sinks, returns false if there are none
+fn setup_metrics(telemetry: &TelemetryConfig) -> eyre::Result<bool> {
+    let Some(statsd) = telemetry.statsd.as_ref() else {
+        return match telemetry.prometheus.as_ref() {
+            Some(prometheus) => {
+                prometheus_builder(prometheus.bind_addr)?.install()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        };
+    };
+
+    let statsd_recorder = StatsdRecorder::new(statsd);
+    statsd_recorder.spawn_flusher(
+        statsd.addr,
+        Duration::from_millis(statsd.flush_interval_ms.max(100)),
+    );
+
+    match telemetry.prometheus.as_ref() {
+        Some(prometheus) => {
+            let (prometheus_recorder, exporter) =
+                prometheus_builder(prometheus.bind_addr)?.build()?;
+            tokio::spawn(async move {
+                if let Err(e) = exporter.await {
+                    error!("prometheus exporter failed: {e}");
+                }
+            });
+            metrics::set_global_recorder(Fanout(vec![
+                Box::new(prometheus_recorder),
+                Box::new(statsd_recorder),
+            ]))
+            .map_err(|_| eyre::eyre!("a metrics recorder was already installed"))?;
+        }
+        None => {
+            metrics::set_global_recorder(statsd_recorder)
+                .map_err(|_| eyre::eyre!("a metrics recorder was already installed"))?;
+        }
+    }
+
+    Ok(true)
+}
+
+fn prometheus_builder(addr: SocketAddr) -> eyre::Result<PrometheusBuilder> {
+    Ok(PrometheusBuilder::new()
         .with_http_listener(addr)
         .set_buckets(&[
             0.001, // 1ms
@@ -101,10 +156,36 @@ fn setup_prometheus(addr: SocketAddr) -> eyre::Result<()> {
             10.0,  // 10s :screaming:
             30.0, 60.0,
         ])?
-        .install()?;
-    Ok(())
+        .set_buckets_for_metric(
+            Matcher::Full("corro.api.request.bytes".into()),
+            API_BYTES_BUCKETS,
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full("corro.api.response.bytes".into()),
+            API_BYTES_BUCKETS,
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full("corro.api.request.statements".into()),
+            API_COUNT_BUCKETS,
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full("corro.api.response.rows".into()),
+            API_COUNT_BUCKETS,
+        )?)
 }
 
+const API_BYTES_BUCKETS: &[f64] = &[
+    256.0,
+    1024.0,
+    16.0 * 1024.0,
+    256.0 * 1024.0,
+    1024.0 * 1024.0,
+    16.0 * 1024.0 * 1024.0,
+    64.0 * 1024.0 * 1024.0,
+];
+
+const API_COUNT_BUCKETS: &[f64] = &[1.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0];
+
 fn start_tokio_runtime_reporter() {
     let handle = tokio::runtime::Handle::current();
 
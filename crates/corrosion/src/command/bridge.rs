@@ -0,0 +1,359 @@
+//! Replicates tables between two independent clusters through their changes
+//! API: changes polled from one cluster are ingested by the other, booked
+//! under actors derived from the name of the cluster they came from.
+//!
+//! Those actors are never replicated back to the cluster they're named
+//! after, so bridging both ways doesn't loop changes between clusters.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use corro_agent::api::public::changes::ingest_actor_id;
+use corro_client::CorrosionApiClient;
+use corro_types::{
+    actor::ActorId,
+    api::{Change, ExecResult},
+    base::{CrsqlSeq, Version},
+    broadcast::{ChangeV1, Changeset},
+    config::BridgeConfig,
+};
+use futures::future::select;
+use metrics::counter;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::interval,
+};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+/// Versions read from an actor per request
+const VERSIONS_PER_POLL: usize = 1000;
+
+/// Last version replicated, by actor of the polled cluster
+type Cursors = HashMap<Uuid, Version>;
+
+pub async fn run(config: &BridgeConfig, api_addr: SocketAddr) -> eyre::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    let sigterm_recv = sigterm.recv();
+    tokio::pin!(sigterm_recv);
+    let sigint_recv = sigint.recv();
+    tokio::pin!(sigint_recv);
+
+    let mut stop_signal = select(sigterm_recv, sigint_recv);
+
+    let local = CorrosionApiClient::new(api_addr);
+    let remote = CorrosionApiClient::new(config.remote_addr);
+    let tables: HashSet<String> = config.tables.iter().cloned().collect();
+
+    let mut pulled = Cursors::new();
+    let mut pushed = Cursors::new();
+
+    info!(
+        "Bridging {} tables with {} ({}), direction: {:?}",
+        tables.len(),
+        config.remote_name,
+        config.remote_addr,
+        config.direction
+    );
+
+    let mut interval = interval(Duration::from_secs(config.interval_secs));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = &mut stop_signal => {
+                info!("Received stop signal, stopping bridge");
+                break;
+            }
+        }
+
+        if config.direction.pulls() {
+            let res = replicate(
+                &remote,
+                &local,
+                &config.remote_name,
+                &config.name,
+                &tables,
+                &mut pulled,
+            )
+            .await;
+            if let Err(e) = res {
+                error!("could not pull changes from {}: {e}", config.remote_name);
+                counter!("corro.bridge.errors", "direction" => "pull").increment(1);
+            }
+        }
+
+        if config.direction.pushes() {
+            let res = replicate(
+                &local,
+                &remote,
+                &config.name,
+                &config.remote_name,
+                &tables,
+                &mut pushed,
+            )
+            .await;
+            if let Err(e) = res {
+                error!("could not push changes to {}: {e}", config.remote_name);
+                counter!("corro.bridge.errors", "direction" => "push").increment(1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the changes of `from` to `to`, where they're booked under actors
+/// derived from `from_name`. Actors of `from` derived from `to_name` hold
+/// changes which came from `to` in the first place, they're skipped.
+async fn replicate(
+    from: &CorrosionApiClient,
+    to: &CorrosionApiClient,
+    from_name: &str,
+    to_name: &str,
+    tables: &HashSet<String>,
+    cursors: &mut Cursors,
+) -> eyre::Result<()> {
+    let to_actors = to.changes_actors().await?;
+    let bridged: HashSet<Uuid> = to_actors
+        .iter()
+        .map(|actor| ingest_actor_id(to_name, ActorId(actor.actor_id)).0)
+        .collect();
+    let known: HashMap<Uuid, Version> = to_actors
+        .iter()
+        .map(|actor| (actor.actor_id, actor.last_version))
+        .collect();
+
+    for actor in from.changes_actors().await? {
+        if bridged.contains(&actor.actor_id) {
+            continue;
+        }
+
+        // ingested changes keep their versions, picks up where `to` is
+        let cursor = cursors.entry(actor.actor_id).or_insert_with(|| {
+            let ingested = ingest_actor_id(from_name, ActorId(actor.actor_id));
+            known.get(&ingested.0).copied().unwrap_or_default()
+        });
+
+        while *cursor < actor.last_version {
+            let body = from
+                .changes(actor.actor_id, cursor.0, VERSIONS_PER_POLL)
+                .await?;
+            let (changesets, Some(last)) = bridged_changesets(&body, *cursor, tables)? else {
+                debug!(actor_id = %actor.actor_id, "version after {cursor:?} is not complete yet");
+                break;
+            };
+
+            let mut buf = vec![];
+            for changeset in changesets.iter() {
+                serde_json::to_writer(&mut buf, changeset)?;
+                buf.push(b'\n');
+            }
+            to.ingest_changes(from_name, buf.into()).await?;
+
+            counter!("corro.bridge.changesets", "producer" => from_name.to_owned())
+                .increment(changesets.len() as u64);
+            debug!(actor_id = %actor.actor_id, "replicated versions up to {last:?}");
+            *cursor = last;
+        }
+    }
+
+    Ok(())
+}
+
+/// Changesets read after version `after`, with only the changes to `tables`.
+/// A version's chunks are merged and its changes renumbered, so it's still
+/// complete without the changes left out.
+///
+/// Versions the polled node only partially received are not returned, the
+/// changesets stop at the first missing one. Returns them along with the last
+/// version they cover, if any.
+fn bridged_changesets(
+    body: &[u8],
+    after: Version,
+    tables: &HashSet<String>,
+) -> eyre::Result<(Vec<ChangeV1>, Option<Version>)> {
+    let mut changesets = vec![];
+    let mut next = after + 1;
+    // changes of the version being read, until its last chunk
+    let mut chunks: Vec<Change> = vec![];
+
+    for line in body.split(|b| *b == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let change: ChangeV1 = match serde_json::from_slice(line) {
+            Ok(change) => change,
+            Err(e) => match serde_json::from_slice(line) {
+                // reading changes failed midway
+                Ok(ExecResult::Error { error, .. }) => eyre::bail!("{error}"),
+                _ => return Err(e.into()),
+            },
+        };
+
+        if *change.versions().start() != next {
+            break;
+        }
+
+        let actor_id = change.actor_id;
+        let changeset = match change.changeset {
+            Changeset::Empty { versions } => {
+                next = *versions.end() + 1;
+                Changeset::Empty { versions }
+            }
+            Changeset::Full {
+                version,
+                changes,
+                seqs,
+                last_seq,
+                ts,
+            } => {
+                chunks.extend(
+                    changes
+                        .into_iter()
+                        .filter(|change| tables.contains(change.table.as_str())),
+                );
+                if *seqs.end() != last_seq {
+                    continue;
+                }
+                next = version + 1;
+
+                let changes: Vec<Change> = chunks
+                    .drain(..)
+                    .enumerate()
+                    .map(|(i, mut change)| {
+                        change.seq = CrsqlSeq(i as u64);
+                        change
+                    })
+                    .collect();
+                match changes.len() {
+                    0 => Changeset::Empty {
+                        versions: version..=version,
+                    },
+                    len => {
+                        let last_seq = CrsqlSeq(len as u64 - 1);
+                        Changeset::Full {
+                            version,
+                            changes,
+                            seqs: CrsqlSeq(0)..=last_seq,
+                            last_seq,
+                            ts,
+                        }
+                    }
+                }
+            }
+        };
+
+        changesets.push(ChangeV1 {
+            actor_id,
+            changeset,
+        });
+    }
+
+    let last = (next > after + 1).then(|| next - 1);
+    Ok((changesets, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::broadcast::Timestamp;
+
+    use super::*;
+
+    fn change(table: &str, seq: u64) -> Change {
+        Change {
+            table: table.into(),
+            seq: CrsqlSeq(seq),
+            ..Default::default()
+        }
+    }
+
+    fn full(version: u64, changes: Vec<Change>, last_seq: u64) -> ChangeV1 {
+        let seqs = changes.first().unwrap().seq..=changes.last().unwrap().seq;
+        ChangeV1 {
+            actor_id: ActorId(Uuid::nil()),
+            changeset: Changeset::Full {
+                version: Version(version),
+                changes,
+                seqs,
+                last_seq: CrsqlSeq(last_seq),
+                ts: Timestamp::zero(),
+            },
+        }
+    }
+
+    fn ndjson(changes: &[ChangeV1]) -> Vec<u8> {
+        let mut body = vec![];
+        for change in changes {
+            serde_json::to_writer(&mut body, change).unwrap();
+            body.push(b'\n');
+        }
+        body
+    }
+
+    #[test]
+    fn test_bridged_changesets() -> eyre::Result<()> {
+        let tables: HashSet<String> = ["users".to_string()].into();
+
+        let body = ndjson(&[
+            // split in 2 chunks, with a change to a table not bridged
+            full(3, vec![change("users", 0), change("logs", 1)], 2),
+            full(3, vec![change("users", 2)], 2),
+            ChangeV1 {
+                actor_id: ActorId(Uuid::nil()),
+                changeset: Changeset::Empty {
+                    versions: Version(4)..=Version(5),
+                },
+            },
+            full(6, vec![change("logs", 0)], 0),
+            // version 7 is missing
+            full(8, vec![change("users", 0)], 0),
+        ]);
+
+        let (changesets, last) = bridged_changesets(&body, Version(2), &tables)?;
+        assert_eq!(last, Some(Version(6)));
+        assert_eq!(changesets.len(), 3);
+
+        match &changesets[0].changeset {
+            Changeset::Full {
+                version,
+                changes,
+                seqs,
+                last_seq,
+                ..
+            } => {
+                assert_eq!(*version, Version(3));
+                assert_eq!(
+                    changes.iter().map(|c| c.seq).collect::<Vec<_>>(),
+                    vec![CrsqlSeq(0), CrsqlSeq(1)]
+                );
+                assert_eq!(*seqs, CrsqlSeq(0)..=CrsqlSeq(1));
+                assert_eq!(*last_seq, CrsqlSeq(1));
+            }
+            changeset => panic!("unexpected changeset: {changeset:?}"),
+        }
+        assert!(changesets[0].is_complete());
+        assert_eq!(changesets[1].versions(), Version(4)..=Version(5));
+        // nothing left to replicate, the version is still booked
+        assert!(matches!(
+            changesets[2].changeset,
+            Changeset::Empty { ref versions } if *versions == (Version(6)..=Version(6))
+        ));
+
+        // nothing is returned until the missing version is complete
+        let body = ndjson(&[full(8, vec![change("users", 0)], 0)]);
+        let (changesets, last) = bridged_changesets(&body, Version(6), &tables)?;
+        assert!(changesets.is_empty());
+        assert_eq!(last, None);
+
+        let body = br#"{"error":"database is locked","code":"database"}"#;
+        assert!(bridged_changesets(body, Version(0), &tables).is_err());
+
+        Ok(())
+    }
+}
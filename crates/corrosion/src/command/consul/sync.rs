@@ -541,8 +541,10 @@ async fn execute(
     if !statements.is_empty() {
         if let Some(e) = corrosion.execute(&statements).await?.results.into_iter().find_map(|res| {
             match res {
-                corro_api_types::ExecResult::Execute { .. } => None,
-                corro_api_types::ExecResult::Error { error } => {
+                corro_api_types::ExecResult::Execute { .. }
+                | corro_api_types::ExecResult::Returning { .. }
+                | corro_api_types::ExecResult::Query { .. } => None,
+                corro_api_types::ExecResult::Error { error, .. } => {
                     Some(error)
                 },
             }
@@ -0,0 +1,159 @@
+//! Compare the contents of tables with a peer's, to verify the nodes
+//! converged. Table digests are compared first, only the buckets of rows
+//! which differ are compared row by row.
+
+use std::collections::{BTreeMap, HashMap};
+
+use corro_client::CorrosionApiClient;
+use corro_types::{
+    api::{DigestRequest, RowDigestRequest, SqliteValue, TableDigest},
+    pubsub::pack_columns,
+};
+
+/// Buckets compared row by row in a single request
+const BUCKETS_PER_REQUEST: usize = 16;
+
+#[derive(Default)]
+struct TableDiff {
+    only_local: Vec<Vec<SqliteValue>>,
+    only_peer: Vec<Vec<SqliteValue>>,
+    different: Vec<Vec<SqliteValue>>,
+}
+
+impl TableDiff {
+    fn is_empty(&self) -> bool {
+        self.only_local.is_empty() && self.only_peer.is_empty() && self.different.is_empty()
+    }
+}
+
+fn fmt_pk(pk: &[SqliteValue]) -> String {
+    format!(
+        "({})",
+        pk.iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+fn print_pks(label: &str, pks: &[Vec<SqliteValue>], limit: usize) {
+    if pks.is_empty() {
+        return;
+    }
+    println!("    {} {label}", pks.len());
+    for pk in pks.iter().take(limit) {
+        println!("      {}", fmt_pk(pk));
+    }
+    if pks.len() > limit {
+        println!("      ... and {} more", pks.len() - limit);
+    }
+}
+
+async fn diff_table(
+    local: &CorrosionApiClient,
+    peer: &CorrosionApiClient,
+    local_digest: &TableDigest,
+    peer_digest: &TableDigest,
+) -> eyre::Result<TableDiff> {
+    let buckets = local_digest
+        .buckets
+        .iter()
+        .zip(peer_digest.buckets.iter())
+        .enumerate()
+        .filter_map(|(i, (l, p))| (l != p).then_some(i))
+        .collect::<Vec<_>>();
+
+    let mut diff = TableDiff::default();
+
+    for buckets in buckets.chunks(BUCKETS_PER_REQUEST) {
+        let req = RowDigestRequest {
+            table: local_digest.table.clone(),
+            buckets: buckets.to_vec(),
+        };
+
+        let mut local_rows = HashMap::new();
+        for row in local.row_digests(&req).await? {
+            local_rows.insert(pack_columns(&row.pk)?, row);
+        }
+
+        for row in peer.row_digests(&req).await? {
+            match local_rows.remove(&pack_columns(&row.pk)?) {
+                None => diff.only_peer.push(row.pk),
+                Some(local_row) if local_row.digest != row.digest => diff.different.push(row.pk),
+                Some(_) => {}
+            }
+        }
+
+        diff.only_local
+            .extend(local_rows.into_values().map(|row| row.pk));
+    }
+
+    Ok(diff)
+}
+
+pub async fn run(
+    local: &CorrosionApiClient,
+    peer: &CorrosionApiClient,
+    tables: Vec<String>,
+    limit: usize,
+) -> eyre::Result<()> {
+    let req = DigestRequest { tables };
+
+    let local_digests = local
+        .digests(&req)
+        .await?
+        .into_iter()
+        .map(|digest| (digest.table.clone(), digest))
+        .collect::<BTreeMap<_, _>>();
+    let mut peer_digests = peer
+        .digests(&req)
+        .await?
+        .into_iter()
+        .map(|digest| (digest.table.clone(), digest))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut differing = 0;
+
+    for (table, local_digest) in local_digests.iter() {
+        let Some(peer_digest) = peer_digests.remove(table) else {
+            println!("[error] {table}: only exists locally");
+            differing += 1;
+            continue;
+        };
+
+        if local_digest.buckets == peer_digest.buckets {
+            println!("[  ok ] {table}: {} rows", local_digest.rows);
+            continue;
+        }
+
+        differing += 1;
+        println!(
+            "[error] {table}: {} rows locally, {} rows on peer",
+            local_digest.rows, peer_digest.rows
+        );
+
+        let diff = diff_table(local, peer, local_digest, &peer_digest).await?;
+        if diff.is_empty() {
+            // rows changed while they were being compared
+            println!("    rows changed during the comparison, run it again");
+            continue;
+        }
+        print_pks("row(s) only exist locally", &diff.only_local, limit);
+        print_pks("row(s) only exist on peer", &diff.only_peer, limit);
+        print_pks("row(s) differ", &diff.different, limit);
+    }
+
+    for table in peer_digests.keys() {
+        println!("[error] {table}: only exists on peer");
+        differing += 1;
+    }
+
+    println!();
+    println!("{differing} table(s) differ");
+
+    if differing > 0 {
+        eyre::bail!("data differs from the peer's");
+    }
+
+    Ok(())
+}
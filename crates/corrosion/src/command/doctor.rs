@@ -0,0 +1,376 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use corro_agent::{agent::resolve_bootstrap, api::peer::gossip_client_endpoint};
+use corro_types::{
+    broadcast::Timestamp,
+    config::{Config, ConfigError},
+    schema::parse_sql,
+    sqlite::CrConn,
+};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Ok,
+    Warn,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Ok => f.write_str("  ok "),
+            Severity::Warn => f.write_str("warn "),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+struct Finding {
+    severity: Severity,
+    msg: String,
+    hint: Option<String>,
+}
+
+#[derive(Default)]
+struct Report {
+    findings: Vec<Finding>,
+}
+
+impl Report {
+    fn ok<M: Into<String>>(&mut self, msg: M) {
+        self.push(Severity::Ok, msg, None::<String>)
+    }
+
+    fn warn<M: Into<String>, H: Into<String>>(&mut self, msg: M, hint: H) {
+        self.push(Severity::Warn, msg, Some(hint))
+    }
+
+    fn error<M: Into<String>, H: Into<String>>(&mut self, msg: M, hint: H) {
+        self.push(Severity::Error, msg, Some(hint))
+    }
+
+    fn push<M: Into<String>, H: Into<String>>(
+        &mut self,
+        severity: Severity,
+        msg: M,
+        hint: Option<H>,
+    ) {
+        let finding = Finding {
+            severity,
+            msg: msg.into(),
+            hint: hint.map(Into::into),
+        };
+        // print as we go, some checks (network) can take a little while
+        println!("[{}] {}", finding.severity, finding.msg);
+        if let Some(hint) = finding.hint.as_ref() {
+            println!("        -> {hint}");
+        }
+        self.findings.push(finding);
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .count()
+    }
+}
+
+/// Checks the local setup for common misconfigurations
+pub async fn run(config_path: &Utf8Path) -> eyre::Result<()> {
+    let mut report = Report::default();
+
+    let config = match Config::load(config_path.as_str()) {
+        Ok(config) => {
+            report.ok(format!("config file {config_path} loaded"));
+            Some(config)
+        }
+        Err(ConfigError::Invalid(problems)) => {
+            for problem in problems {
+                report.error(
+                    format!("invalid config: {problem}"),
+                    format!("fix `{}` in {config_path}", problem.key),
+                );
+            }
+            None
+        }
+        Err(e) => {
+            report.error(
+                format!("could not load config file {config_path}: {e}"),
+                "pass the right path with --config and check the TOML syntax",
+            );
+            None
+        }
+    };
+
+    check_crsqlite(&mut report);
+    check_clock(&mut report, config.as_ref().map(|config| &config.db.path));
+
+    if let Some(config) = config.as_ref() {
+        check_data_dir(&mut report, &config.db.path);
+        check_schema(&mut report, &config.db.schema_paths).await;
+        check_bootstrap(&mut report, config).await;
+    }
+
+    let errors = report.count(Severity::Error);
+    let warnings = report.count(Severity::Warn);
+
+    println!();
+    println!("{errors} error(s), {warnings} warning(s)");
+
+    if errors > 0 {
+        eyre::bail!("found {errors} problem(s) that would prevent corrosion from running properly");
+    }
+
+    Ok(())
+}
+
+fn check_crsqlite(report: &mut Report) {
+    let res = Connection::open_in_memory()
+        .and_then(CrConn::init)
+        .and_then(|conn| conn.query_row("SELECT crsql_site_id();", [], |_row| Ok(())));
+    match res {
+        Ok(()) => report.ok("cr-sqlite extension loaded"),
+        Err(e) => report.error(
+            format!("could not load the cr-sqlite extension: {e}"),
+            "this binary might have been built for another platform",
+        ),
+    }
+}
+
+fn check_data_dir(report: &mut Report, db_path: &Utf8Path) {
+    // find the closest existing ancestor, that's where files will be created
+    let Some(mut dir) = db_path.parent().map(Utf8Path::to_path_buf) else {
+        report.error(
+            format!("database path {db_path} has no parent directory"),
+            "set db.path to a file path",
+        );
+        return;
+    };
+
+    if dir.as_str().is_empty() {
+        dir = Utf8PathBuf::from(".");
+    }
+
+    let mut existing = dir.clone();
+    while !existing.exists() {
+        match existing.parent() {
+            Some(parent) if !parent.as_str().is_empty() => existing = parent.to_path_buf(),
+            _ => {
+                existing = Utf8PathBuf::from(".");
+                break;
+            }
+        }
+    }
+
+    if existing != dir {
+        report.warn(
+            format!("data directory {dir} does not exist yet"),
+            format!("it will be created on startup under {existing}"),
+        );
+    }
+
+    match tempfile::tempfile_in(&existing) {
+        Ok(_) => report.ok(format!("data directory {existing} is writable")),
+        Err(e) => {
+            report.error(
+                format!("data directory {existing} is not writable: {e}"),
+                "fix permissions or ownership for the user running corrosion",
+            );
+            return;
+        }
+    }
+
+    match std::fs::metadata(db_path) {
+        Ok(meta) if meta.permissions().readonly() => report.error(
+            format!("database file {db_path} is read-only"),
+            "corrosion needs read-write access to its database",
+        ),
+        Ok(_) => report.ok(format!("database file {db_path} exists")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            report.ok(format!("database file {db_path} will be created on startup"))
+        }
+        Err(e) => report.error(
+            format!("could not stat database file {db_path}: {e}"),
+            "fix permissions or ownership for the user running corrosion",
+        ),
+    }
+}
+
+fn check_clock(report: &mut Report, db_path: Option<&Utf8PathBuf>) {
+    let now = OffsetDateTime::from(SystemTime::now());
+    if now.year() < 2023 {
+        report.error(
+            format!("system clock looks wrong: {now}"),
+            "synchronize the clock (e.g. with NTP), timestamps order changes across the cluster",
+        );
+        return;
+    }
+
+    let latest: Option<Timestamp> = db_path.and_then(|db_path| {
+        let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY).ok()?;
+        conn.query_row("SELECT MAX(ts) FROM __corro_bookkeeping", [], |row| {
+            row.get(0)
+        })
+        .optional()
+        .ok()
+        .flatten()
+        .flatten()
+    });
+
+    match latest.map(|ts| ts.to_time()) {
+        Some(latest) if latest - now > time::Duration::SECOND => report.warn(
+            format!("system clock ({now}) is behind the latest known change ({latest})"),
+            "this node or one of its peers has a skewed clock, synchronize clocks with NTP",
+        ),
+        _ => report.ok(format!("system clock looks sane ({now})")),
+    }
+}
+
+async fn check_schema(report: &mut Report, schema_paths: &[Utf8PathBuf]) {
+    if schema_paths.is_empty() {
+        report.warn(
+            "no schema paths configured",
+            "set db.schema_paths or apply a schema through the API",
+        );
+        return;
+    }
+
+    let mut files = vec![];
+    for path in schema_paths {
+        match tokio::fs::metadata(path).await {
+            Ok(meta) if meta.is_dir() => match std::fs::read_dir(path) {
+                Ok(dir) => {
+                    let mut entries: Vec<_> = dir
+                        .flatten()
+                        .map(|entry| entry.path())
+                        .filter(|path| path.extension().map_or(false, |ext| ext == "sql"))
+                        .collect();
+                    entries.sort();
+                    files.extend(entries);
+                }
+                Err(e) => report.error(
+                    format!("could not read schema directory {path}: {e}"),
+                    "fix permissions for the user running corrosion",
+                ),
+            },
+            Ok(_) => files.push(path.as_std_path().to_path_buf()),
+            Err(e) => report.error(
+                format!("could not read schema path {path}: {e}"),
+                "check db.schema_paths",
+            ),
+        }
+    }
+
+    let mut sql = String::new();
+    for file in files {
+        let s = match tokio::fs::read_to_string(&file).await {
+            Ok(s) => s,
+            Err(e) => {
+                report.error(
+                    format!("could not read schema file {}: {e}", file.display()),
+                    "fix permissions for the user running corrosion",
+                );
+                continue;
+            }
+        };
+        if let Err(e) = parse_sql(&s) {
+            report.error(
+                format!("could not parse schema file {}: {e}", file.display()),
+                "only CREATE TABLE and CREATE INDEX statements are supported",
+            );
+            continue;
+        }
+        sql.push_str(&s);
+        sql.push('\n');
+    }
+
+    match parse_sql(&sql).map(|mut schema| schema.constrain().map(|_| schema)) {
+        Ok(Ok(schema)) => report.ok(format!("schema parsed, {} table(s)", schema.tables.len())),
+        Ok(Err(e)) => report.error(
+            format!("schema does not satisfy corrosion's constraints: {e}"),
+            "tables need a primary key and non-null columns need defaults",
+        ),
+        Err(e) => report.error(
+            format!("could not parse schema: {e}"),
+            "check the CREATE statements",
+        ),
+    }
+}
+
+async fn check_bootstrap(report: &mut Report, config: &Config) {
+    if config.gossip.bootstrap.is_empty() {
+        report.warn(
+            "no bootstrap peers configured",
+            "that's fine for a single node, otherwise set gossip.bootstrap",
+        );
+        return;
+    }
+
+    let addrs = match resolve_bootstrap(&config.gossip, config.gossip.bind_addr).await {
+        Ok(addrs) if addrs.is_empty() => {
+            report.error(
+                "bootstrap peers did not resolve to any address",
+                "check the DNS names and ports in gossip.bootstrap",
+            );
+            return;
+        }
+        Ok(addrs) => addrs,
+        Err(e) => {
+            report.error(
+                format!("could not resolve bootstrap peers: {e}"),
+                "check the DNS names and ports in gossip.bootstrap",
+            );
+            return;
+        }
+    };
+
+    let endpoint = match gossip_client_endpoint(&config.gossip).await {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            report.error(
+                format!("could not create gossip client endpoint: {e}"),
+                "check gossip.client_addr and gossip.tls",
+            );
+            return;
+        }
+    };
+
+    for addr in addrs {
+        let connecting = match endpoint.connect(addr, &addr.ip().to_string()) {
+            Ok(connecting) => connecting,
+            Err(e) => {
+                report.error(
+                    format!("could not connect to bootstrap peer {addr}: {e}"),
+                    "check gossip.tls and gossip.plaintext",
+                );
+                continue;
+            }
+        };
+        match tokio::time::timeout(Duration::from_secs(5), connecting).await {
+            Ok(Ok(conn)) => {
+                report.ok(format!(
+                    "bootstrap peer {addr} is reachable (rtt: {:?})",
+                    conn.rtt()
+                ));
+                conn.close(0u32.into(), b"doctor");
+            }
+            Ok(Err(e)) => report.error(
+                format!("could not handshake with bootstrap peer {addr}: {e}"),
+                "check TLS settings match across the cluster",
+            ),
+            Err(_) => report.error(
+                format!("timed out connecting to bootstrap peer {addr}"),
+                "make sure the gossip port is open (UDP) between nodes",
+            ),
+        }
+    }
+
+    endpoint.wait_idle().await;
+}
@@ -0,0 +1,414 @@
+use std::{fmt::Write, net::SocketAddr};
+
+use camino::Utf8PathBuf;
+use clap::ValueEnum;
+use corro_client::CorrosionApiClient;
+use corro_types::{
+    api::{QueryEvent, SqliteValue, Statement},
+    schema::{parse_sql, Column, Schema, SqliteType, Table},
+};
+use futures::StreamExt;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Language {
+    Rust,
+    Typescript,
+}
+
+pub async fn run(
+    api_addr: SocketAddr,
+    language: Language,
+    output: Option<&Utf8PathBuf>,
+) -> eyre::Result<()> {
+    let client = CorrosionApiClient::new(api_addr);
+
+    let schema = fetch_schema(&client).await?;
+
+    let code = match language {
+        Language::Rust => generate_rust(&schema),
+        Language::Typescript => generate_typescript(&schema),
+    };
+
+    match output {
+        Some(path) => {
+            tokio::fs::write(path, code).await?;
+            info!(
+                "Generated code for {} table(s) in {path}",
+                schema.tables.len()
+            );
+        }
+        None => print!("{code}"),
+    }
+
+    Ok(())
+}
+
+/// Reads the replicated schema as known by the running agent
+async fn fetch_schema(client: &CorrosionApiClient) -> eyre::Result<Schema> {
+    let mut stream = client
+        .query(&Statement::Simple(
+            "SELECT sql FROM __corro_schema WHERE type = 'table' ORDER BY tbl_name".into(),
+        ))
+        .await?;
+
+    let mut sql = String::new();
+    while let Some(res) = stream.next().await {
+        match res? {
+            QueryEvent::Row(_, cells) => {
+                if let Some(SqliteValue::Text(s)) = cells.first() {
+                    sql.push_str(s);
+                    sql.push(';');
+                }
+            }
+            QueryEvent::EndOfQuery { .. } => break,
+            QueryEvent::Error(e) => eyre::bail!("{e}"),
+            QueryEvent::Columns(_)
+            | QueryEvent::Estimate { .. }
+            | QueryEvent::Progress { .. }
+            | QueryEvent::Change(..)
+            | QueryEvent::Meta(_, _)
+            | QueryEvent::Previous(_, _)
+            | QueryEvent::Resync { .. }
+            | QueryEvent::Dropped { .. }
+            | QueryEvent::Skipped { .. }
+            | QueryEvent::Closed { .. }
+            | QueryEvent::Moved { .. } => {}
+        }
+    }
+
+    let mut schema = parse_sql(&sql)?;
+    schema.constrain()?;
+
+    Ok(schema)
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "static", "struct", "trait", "true", "type", "unsafe", "use", "where",
+    "while",
+];
+
+fn pascal_case(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn rust_ident(name: &str) -> String {
+    let ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if RUST_KEYWORDS.contains(&ident.as_str()) {
+        format!("r#{ident}")
+    } else if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{ident}")
+    } else {
+        ident
+    }
+}
+
+fn is_optional(col: &Column) -> bool {
+    col.nullable && !col.primary_key
+}
+
+fn rust_type(col: &Column) -> String {
+    let ty = match col.sql_type().0 {
+        SqliteType::Integer => "i64",
+        SqliteType::Real => "f64",
+        SqliteType::Text => "String",
+        SqliteType::Blob => "Vec<u8>",
+        SqliteType::Numeric | SqliteType::Null => "corro_api_types::SqliteValue",
+    };
+    if is_optional(col) {
+        format!("Option<{ty}>")
+    } else {
+        ty.to_string()
+    }
+}
+
+/// Type and conversion expression for a primary key query parameter
+fn rust_param(col: &Column, ident: &str) -> (&'static str, String) {
+    match col.sql_type().0 {
+        SqliteType::Integer => ("i64", format!("SqliteParam::Integer({ident})")),
+        SqliteType::Real => ("f64", format!("SqliteParam::Real({ident})")),
+        SqliteType::Text => ("&str", format!("SqliteParam::from({ident})")),
+        SqliteType::Blob => ("Vec<u8>", format!("SqliteParam::from({ident})")),
+        SqliteType::Numeric | SqliteType::Null => ("SqliteParam", ident.to_string()),
+    }
+}
+
+fn select_all_sql(table: &Table) -> String {
+    format!(
+        "SELECT {} FROM \\\"{}\\\"",
+        table
+            .columns
+            .keys()
+            .map(|name| format!("\\\"{name}\\\""))
+            .collect::<Vec<_>>()
+            .join(", "),
+        table.name
+    )
+}
+
+fn where_pk_sql(table: &Table) -> String {
+    table
+        .pk
+        .iter()
+        .map(|name| format!("\\\"{name}\\\" = ?"))
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+pub fn generate_rust(schema: &Schema) -> String {
+    let mut out = String::new();
+
+    out.push_str("// Code generated by `corrosion generate rust`, DO NOT EDIT.\n\n");
+    out.push_str("#![allow(dead_code)]\n\n");
+    out.push_str("use corro_api_types::{SqliteParam, Statement};\n");
+    out.push_str("use serde::{Deserialize, Serialize};\n");
+
+    for table in schema.tables.values() {
+        let name = pascal_case(&table.name);
+
+        _ = writeln!(out);
+        _ = writeln!(out, "/// Row of the `{}` table", table.name);
+        _ = writeln!(out, "#[derive(Debug, Clone, Serialize, Deserialize)]");
+        _ = writeln!(out, "pub struct {name} {{");
+        for col in table.columns.values() {
+            let ident = rust_ident(&col.name);
+            if ident.trim_start_matches("r#") != col.name {
+                _ = writeln!(out, "    #[serde(rename = \"{}\")]", col.name);
+            }
+            _ = writeln!(out, "    pub {ident}: {},", rust_type(col));
+        }
+        _ = writeln!(out, "}}");
+
+        _ = writeln!(out);
+        _ = writeln!(out, "impl {name} {{");
+        _ = writeln!(
+            out,
+            "    pub const TABLE: &'static str = \"{}\";",
+            table.name
+        );
+        _ = writeln!(
+            out,
+            "    pub const COLUMNS: &'static [&'static str] = &[{}];",
+            table
+                .columns
+                .keys()
+                .map(|name| format!("\"{name}\""))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        _ = writeln!(out);
+        _ = writeln!(out, "    pub fn select_all() -> Statement {{");
+        _ = writeln!(
+            out,
+            "        Statement::Simple(\"{}\".into())",
+            select_all_sql(table)
+        );
+        _ = writeln!(out, "    }}");
+
+        if !table.pk.is_empty() {
+            let pk_cols: Vec<_> = table
+                .pk
+                .iter()
+                .filter_map(|name| table.columns.get(name))
+                .collect();
+
+            let args = pk_cols
+                .iter()
+                .map(|col| {
+                    let ident = rust_ident(&col.name);
+                    let (ty, _) = rust_param(col, &ident);
+                    format!("{ident}: {ty}")
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let params = pk_cols
+                .iter()
+                .map(|col| rust_param(col, &rust_ident(&col.name)).1)
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            _ = writeln!(out);
+            _ = writeln!(out, "    pub fn select_by_pk({args}) -> Statement {{");
+            _ = writeln!(out, "        Statement::WithParams(");
+            _ = writeln!(
+                out,
+                "            \"{} WHERE {}\".into(),",
+                select_all_sql(table),
+                where_pk_sql(table)
+            );
+            _ = writeln!(out, "            vec![{params}],");
+            _ = writeln!(out, "        )");
+            _ = writeln!(out, "    }}");
+
+            _ = writeln!(out);
+            _ = writeln!(out, "    pub fn delete_by_pk({args}) -> Statement {{");
+            _ = writeln!(out, "        Statement::WithParams(");
+            _ = writeln!(
+                out,
+                "            \"DELETE FROM \\\"{}\\\" WHERE {}\".into(),",
+                table.name,
+                where_pk_sql(table)
+            );
+            _ = writeln!(out, "            vec![{params}],");
+            _ = writeln!(out, "        )");
+            _ = writeln!(out, "    }}");
+        }
+
+        _ = writeln!(out, "}}");
+    }
+
+    out
+}
+
+fn ts_type(col: &Column) -> String {
+    let ty = match col.sql_type().0 {
+        SqliteType::Integer | SqliteType::Real => "number",
+        SqliteType::Text => "string",
+        SqliteType::Blob => "number[]",
+        SqliteType::Numeric | SqliteType::Null => "SqliteValue",
+    };
+    if is_optional(col) {
+        format!("{ty} | null")
+    } else {
+        ty.to_string()
+    }
+}
+
+fn ts_key(name: &str) -> String {
+    if !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        name.to_string()
+    } else {
+        format!("{name:?}")
+    }
+}
+
+fn camel_ident(name: &str) -> String {
+    let pascal = pascal_case(name);
+    let mut chars = pascal.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_lowercase().to_string() + chars.as_str(),
+        None => pascal,
+    }
+}
+
+pub fn generate_typescript(schema: &Schema) -> String {
+    let mut out = String::new();
+
+    out.push_str("// Code generated by `corrosion generate typescript`, DO NOT EDIT.\n\n");
+    out.push_str("export type SqliteValue = null | number | string | number[];\n");
+    out.push_str("export type Statement = string | [string, SqliteValue[]];\n");
+
+    for table in schema.tables.values() {
+        let name = pascal_case(&table.name);
+        let var = camel_ident(&table.name);
+
+        _ = writeln!(out);
+        _ = writeln!(out, "/** Row of the `{}` table */", table.name);
+        _ = writeln!(out, "export interface {name} {{");
+        for col in table.columns.values() {
+            _ = writeln!(out, "  {}: {};", ts_key(&col.name), ts_type(col));
+        }
+        _ = writeln!(out, "}}");
+
+        _ = writeln!(out);
+        _ = writeln!(
+            out,
+            "export const {var}Columns = [{}] as const;",
+            table
+                .columns
+                .keys()
+                .map(|name| format!("{name:?}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        _ = writeln!(out);
+        _ = writeln!(out, "export function selectAll{name}(): Statement {{");
+        _ = writeln!(out, "  return \"{}\";", select_all_sql(table));
+        _ = writeln!(out, "}}");
+
+        if !table.pk.is_empty() {
+            let pk_cols: Vec<_> = table
+                .pk
+                .iter()
+                .filter_map(|name| table.columns.get(name))
+                .collect();
+            let args = pk_cols
+                .iter()
+                .map(|col| format!("{}: {}", camel_ident(&col.name), ts_type(col)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let params = pk_cols
+                .iter()
+                .map(|col| camel_ident(&col.name))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            _ = writeln!(out);
+            _ = writeln!(out, "export function select{name}ByPk({args}): Statement {{");
+            _ = writeln!(
+                out,
+                "  return [\"{} WHERE {}\", [{params}]];",
+                select_all_sql(table),
+                where_pk_sql(table)
+            );
+            _ = writeln!(out, "}}");
+
+            _ = writeln!(out);
+            _ = writeln!(out, "export function delete{name}ByPk({args}): Statement {{");
+            _ = writeln!(
+                out,
+                "  return [\"DELETE FROM \\\"{}\\\" WHERE {}\", [{params}]];",
+                table.name,
+                where_pk_sql(table)
+            );
+            _ = writeln!(out, "}}");
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_typed_code() -> eyre::Result<()> {
+        let schema = parse_sql(corro_tests::TEST_SCHEMA)?;
+
+        let rust = generate_rust(&schema);
+        assert!(rust.contains("pub struct Tests {"));
+        assert!(rust.contains("    pub id: i64,"));
+        assert!(rust.contains("    pub text: String,"));
+        assert!(rust.contains("pub fn select_by_pk(id: i64) -> Statement"));
+        assert!(rust.contains("pub struct Testsblob {"));
+        assert!(rust.contains("pub fn select_by_pk(id: Vec<u8>) -> Statement"));
+
+        let ts = generate_typescript(&schema);
+        assert!(ts.contains("export interface Tests {"));
+        assert!(ts.contains("  id: number;"));
+        assert!(ts.contains("  text: string;"));
+        assert!(ts.contains("export function selectTestsByPk(id: number): Statement"));
+        assert!(ts.contains("export const testsColumns = [\"id\", \"text\"] as const;"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+//! Keeps HAProxy servers in sync with rows from Corrosion through the
+//! Runtime API, turning corrosion into a control plane for the proxies
+//! running next to it.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use corro_api_types::{sqlite::ChangeType, RowId, SqliteValue, TypedQueryEvent};
+use corro_client::{manager::SubscriptionManager, CorrosionApiClient};
+use corro_types::{api::Statement, config::HaproxyConfig};
+use futures::future::select;
+use metrics::counter;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    signal::unix::{signal, SignalKind},
+    time::timeout,
+};
+use tracing::{debug, error, info, warn};
+
+const RUNTIME_API_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server slot in an HAProxy backend, as returned by the query
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Server {
+    backend: String,
+    name: String,
+    addr: String,
+    port: i64,
+    weight: i64,
+}
+
+impl Server {
+    fn from_cells(cells: &[SqliteValue]) -> eyre::Result<Self> {
+        let text = |i: usize, col: &str| {
+            cells
+                .get(i)
+                .and_then(|v| v.as_text())
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| eyre::eyre!("expected text for `{col}` (column {i})"))
+        };
+        let int = |i: usize, col: &str| {
+            cells
+                .get(i)
+                .and_then(|v| v.as_integer())
+                .copied()
+                .ok_or_else(|| eyre::eyre!("expected integer for `{col}` (column {i})"))
+        };
+
+        let server = Self {
+            backend: text(0, "backend")?,
+            name: text(1, "server")?,
+            addr: text(2, "addr")?,
+            port: int(3, "port")?,
+            weight: int(4, "weight")?,
+        };
+        server.validate()?;
+        Ok(server)
+    }
+
+    /// Values end up in Runtime API commands, where spaces and `;` would
+    /// let a row inject commands of its own
+    fn validate(&self) -> eyre::Result<()> {
+        let is_ident = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+        };
+        if !is_ident(&self.backend) {
+            eyre::bail!("invalid backend name: {:?}", self.backend);
+        }
+        if !is_ident(&self.name) {
+            eyre::bail!("invalid server name: {:?}", self.name);
+        }
+        // IPv4, IPv6 or a hostname
+        if !is_ident(&self.addr) || self.addr.contains('_') {
+            eyre::bail!("invalid server address: {:?}", self.addr);
+        }
+        if !(1..=65535).contains(&self.port) {
+            eyre::bail!("invalid server port: {}", self.port);
+        }
+        if !(0..=256).contains(&self.weight) {
+            eyre::bail!("invalid server weight: {}", self.weight);
+        }
+        Ok(())
+    }
+
+    fn id(&self) -> String {
+        format!("{}/{}", self.backend, self.name)
+    }
+
+    /// Runtime API commands bringing the server from `prev` to this state
+    fn commands(&self, prev: Option<&Server>) -> Vec<String> {
+        let id = self.id();
+        let mut cmds = vec![];
+
+        let prev = match prev {
+            // the row now points at another slot, free up the old one
+            Some(prev) if prev.id() != id => {
+                cmds.push(prev.disable());
+                None
+            }
+            prev => prev,
+        };
+
+        if prev.map_or(true, |prev| {
+            prev.addr != self.addr || prev.port != self.port
+        }) {
+            cmds.push(format!(
+                "set server {id} addr {} port {}",
+                self.addr, self.port
+            ));
+        }
+        if prev.map_or(true, |prev| prev.weight != self.weight) {
+            cmds.push(format!("set server {id} weight {}", self.weight));
+        }
+        if prev.is_none() {
+            cmds.push(format!("set server {id} state ready"));
+        }
+
+        cmds
+    }
+
+    fn disable(&self) -> String {
+        format!("set server {} state maint", self.id())
+    }
+}
+
+pub async fn run(config: &HaproxyConfig, api_addr: SocketAddr) -> eyre::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    let sigterm_recv = sigterm.recv();
+    tokio::pin!(sigterm_recv);
+    let sigint_recv = sigint.recv();
+    tokio::pin!(sigint_recv);
+
+    let mut stop_signal = select(sigterm_recv, sigint_recv);
+
+    // the manager resumes or re-creates the subscription when it breaks
+    let manager = SubscriptionManager::new(CorrosionApiClient::new(api_addr));
+
+    info!("Subscribing to haproxy servers query");
+    let mut sub = manager
+        .subscribe(&Statement::Simple(config.query.clone()))
+        .await?;
+
+    // rows of the query, as of the last event
+    let mut rows: HashMap<RowId, Server> = HashMap::new();
+    // servers HAProxy was last told about, by slot
+    let mut applied: BTreeMap<String, Server> = BTreeMap::new();
+    // rows are only complete at the end of the query
+    let mut loading = true;
+
+    loop {
+        let evt = tokio::select! {
+            evt = sub.recv() => match evt {
+                Some(evt) => evt?,
+                None => break,
+            },
+            _ = &mut stop_signal => {
+                info!("Received stop signal, stopping haproxy sync");
+                break;
+            }
+        };
+
+        match evt {
+            TypedQueryEvent::Columns(cols) => {
+                if cols.len() < 5 {
+                    eyre::bail!(
+                        "haproxy query needs backend, server, addr, port and weight columns, got: {cols:?}"
+                    );
+                }
+                if !loading {
+                    // re-created from scratch, with new row ids: servers are
+                    // reconciled by slot once all rows are known again
+                    warn!("haproxy servers subscription was re-created, reloading servers");
+                }
+                rows.clear();
+                loading = true;
+                continue;
+            }
+            TypedQueryEvent::Row(rowid, cells) => {
+                match Server::from_cells(&cells) {
+                    Ok(server) => {
+                        rows.insert(rowid, server);
+                    }
+                    Err(e) => warn!(%rowid, "skipping invalid haproxy server row: {e}"),
+                }
+                continue;
+            }
+            TypedQueryEvent::EndOfQuery { .. } => {
+                info!("Loaded {} haproxy servers", rows.len());
+                loading = false;
+            }
+            TypedQueryEvent::Change(ChangeType::Delete, rowid, ..) => {
+                rows.remove(&rowid);
+            }
+            TypedQueryEvent::Change(_, rowid, cells, ..) => match Server::from_cells(&cells) {
+                Ok(server) => {
+                    rows.insert(rowid, server);
+                }
+                Err(e) => {
+                    // the row doesn't describe a usable server anymore
+                    warn!(%rowid, "skipping invalid haproxy server row: {e}");
+                    rows.remove(&rowid);
+                }
+            },
+            TypedQueryEvent::Estimate { .. }
+            | TypedQueryEvent::Progress { .. }
+            | TypedQueryEvent::Meta(_, _)
+            | TypedQueryEvent::Previous(_, _)
+            | TypedQueryEvent::Skipped { .. } => continue,
+            TypedQueryEvent::Resync { reason } => {
+                warn!("haproxy servers subscription is being resynced: {reason}");
+                continue;
+            }
+            TypedQueryEvent::Dropped { count } => {
+                // the subscription resumes, the missed changes follow
+                warn!("haproxy servers subscription missed {count} events, resuming");
+                continue;
+            }
+            TypedQueryEvent::Closed { reason } => {
+                warn!("haproxy servers subscription was closed by corrosion ({reason:?}), resubscribing");
+                continue;
+            }
+            TypedQueryEvent::Moved { addr, .. } => {
+                // its rows come again from the new node, like a re-creation
+                info!("haproxy servers subscription moved to {addr}");
+                continue;
+            }
+            TypedQueryEvent::Error(e) => {
+                error!("haproxy servers subscription error: {e}");
+                continue;
+            }
+        };
+
+        if loading {
+            continue;
+        }
+
+        let desired = slots(&rows);
+        let cmds = diff_commands(&applied, &desired);
+
+        if cmds.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = send_commands(&config.runtime_api, &cmds).await {
+            // the next event diffs against the same servers, trying again
+            error!("could not update haproxy servers: {e}");
+            counter!("corro.haproxy.commands.errors").increment(cmds.len() as u64);
+            continue;
+        }
+        counter!("corro.haproxy.commands.sent").increment(cmds.len() as u64);
+        applied = desired;
+    }
+
+    Ok(())
+}
+
+/// Servers wanted in HAProxy, by slot. When rows share a slot, the one with
+/// the highest row id wins.
+fn slots(rows: &HashMap<RowId, Server>) -> BTreeMap<String, Server> {
+    let mut rows: Vec<_> = rows.iter().collect();
+    rows.sort_by_key(|(rowid, _)| **rowid);
+    rows.into_iter()
+        .map(|(_, server)| (server.id(), server.clone()))
+        .collect()
+}
+
+/// Runtime API commands bringing HAProxy from the `applied` servers to the
+/// `desired` ones
+fn diff_commands(
+    applied: &BTreeMap<String, Server>,
+    desired: &BTreeMap<String, Server>,
+) -> Vec<String> {
+    let mut cmds: Vec<String> = applied
+        .iter()
+        .filter(|(slot, _)| !desired.contains_key(*slot))
+        .map(|(_, server)| server.disable())
+        .collect();
+    for (slot, server) in desired.iter() {
+        cmds.extend(server.commands(applied.get(slot)));
+    }
+    cmds
+}
+
+/// Sends commands to the Runtime API over a single connection, in
+/// non-interactive mode: HAProxy closes it once all are done.
+async fn send_commands(runtime_api: &str, cmds: &[String]) -> eyre::Result<()> {
+    let mut line = cmds.join(";");
+    line.push('\n');
+    debug!("sending haproxy commands: {line}");
+
+    let res = if runtime_api.starts_with('/') {
+        let conn = timeout(RUNTIME_API_TIMEOUT, UnixStream::connect(runtime_api)).await??;
+        exchange(conn, &line).await?
+    } else {
+        let conn = timeout(RUNTIME_API_TIMEOUT, TcpStream::connect(runtime_api)).await??;
+        exchange(conn, &line).await?
+    };
+
+    // successful `set server` commands don't answer anything
+    for msg in res.lines().map(str::trim).filter(|msg| !msg.is_empty()) {
+        warn!("haproxy: {msg}");
+    }
+
+    Ok(())
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: S,
+    line: &str,
+) -> eyre::Result<String> {
+    conn.write_all(line.as_bytes()).await?;
+    let mut res = String::new();
+    timeout(RUNTIME_API_TIMEOUT, conn.read_to_string(&mut res)).await??;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_commands() {
+        let server = Server {
+            backend: "app".into(),
+            name: "srv1".into(),
+            addr: "10.0.0.1".into(),
+            port: 8080,
+            weight: 100,
+        };
+
+        assert_eq!(
+            server.commands(None),
+            vec![
+                "set server app/srv1 addr 10.0.0.1 port 8080",
+                "set server app/srv1 weight 100",
+                "set server app/srv1 state ready",
+            ]
+        );
+        assert!(server.commands(Some(&server)).is_empty());
+
+        let drained = Server {
+            weight: 0,
+            ..server.clone()
+        };
+        assert_eq!(
+            drained.commands(Some(&server)),
+            vec!["set server app/srv1 weight 0"]
+        );
+
+        let moved = Server {
+            name: "srv2".into(),
+            ..server.clone()
+        };
+        assert_eq!(
+            moved.commands(Some(&server)),
+            vec![
+                "set server app/srv1 state maint",
+                "set server app/srv2 addr 10.0.0.1 port 8080",
+                "set server app/srv2 weight 100",
+                "set server app/srv2 state ready",
+            ]
+        );
+    }
+    #[test]
+    fn test_server_validation() {
+        let cells = |backend: &str, name: &str, addr: &str, port: i64, weight: i64| {
+            vec![
+                SqliteValue::Text(backend.into()),
+                SqliteValue::Text(name.into()),
+                SqliteValue::Text(addr.into()),
+                SqliteValue::Integer(port),
+                SqliteValue::Integer(weight),
+            ]
+        };
+
+        assert!(Server::from_cells(&cells("app", "srv1", "10.0.0.1", 8080, 100)).is_ok());
+        assert!(Server::from_cells(&cells("app", "srv1", "fd00::1", 8080, 100)).is_ok());
+        assert!(Server::from_cells(&cells("app", "srv1", "app.internal", 8080, 100)).is_ok());
+
+        assert!(
+            Server::from_cells(&cells("app", "srv1 state maint", "10.0.0.1", 8080, 100)).is_err()
+        );
+        assert!(Server::from_cells(&cells("app;shutdown", "srv1", "10.0.0.1", 8080, 100)).is_err());
+        assert!(Server::from_cells(&cells("app", "srv1", "10.0.0.1\nshow", 8080, 100)).is_err());
+        assert!(Server::from_cells(&cells("app", "", "10.0.0.1", 8080, 100)).is_err());
+        assert!(Server::from_cells(&cells("app", "srv1", "10.0.0.1", 0, 100)).is_err());
+        assert!(Server::from_cells(&cells("app", "srv1", "10.0.0.1", 8080, 1000)).is_err());
+    }
+
+    #[test]
+    fn test_diff_commands() {
+        let server = |name: &str, addr: &str| Server {
+            backend: "app".into(),
+            name: name.into(),
+            addr: addr.into(),
+            port: 8080,
+            weight: 100,
+        };
+
+        let mut rows = HashMap::new();
+        rows.insert(RowId(1), server("srv1", "10.0.0.1"));
+        rows.insert(RowId(2), server("srv2", "10.0.0.2"));
+        let applied = slots(&rows);
+        assert!(diff_commands(&applied, &applied).is_empty());
+
+        // row 1 moves to the slot of row 2, which is deleted: srv2 stays up
+        // with row 1's address, srv1 goes away
+        rows.remove(&RowId(2));
+        rows.insert(RowId(1), server("srv2", "10.0.0.1"));
+        let desired = slots(&rows);
+        assert_eq!(
+            diff_commands(&applied, &desired),
+            vec![
+                "set server app/srv1 state maint",
+                "set server app/srv2 addr 10.0.0.1 port 8080",
+            ]
+        );
+
+        // two rows for the same slot, the last one wins
+        rows.insert(RowId(3), server("srv2", "10.0.0.3"));
+        let desired = slots(&rows);
+        assert_eq!(desired.len(), 1);
+        assert_eq!(desired["app/srv2"].addr, "10.0.0.3");
+
+        // once every row is gone, every server is put in maintenance
+        assert_eq!(
+            diff_commands(&applied, &BTreeMap::new()),
+            vec![
+                "set server app/srv1 state maint",
+                "set server app/srv2 state maint",
+            ]
+        );
+    }
+}
@@ -1,5 +1,11 @@
 pub mod agent;
+pub mod bridge;
 pub mod consul;
+pub mod diff;
+pub mod doctor;
+pub mod generate;
+pub mod haproxy;
 pub mod reload;
+pub mod subs;
 pub mod tls;
 pub mod tpl;
@@ -1,7 +1,16 @@
-use std::{net::SocketAddr, path::Path};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
+use camino::{Utf8Path, Utf8PathBuf};
 use corro_client::CorrosionApiClient;
-use tracing::info;
+use notify::RecursiveMode;
+use tracing::{error, info};
+use tripwire::Tripwire;
+
+use crate::command::tpl::async_watcher;
 
 pub async fn run<P: AsRef<Path>>(api_addr: SocketAddr, schema_paths: &[P]) -> eyre::Result<()> {
     let client = CorrosionApiClient::new(api_addr);
@@ -11,13 +20,114 @@ pub async fn run<P: AsRef<Path>>(api_addr: SocketAddr, schema_paths: &[P]) -> ey
     Ok(())
 }
 
+/// Applies the schema again whenever one of its files is written, created or
+/// removed, until the tripwire is tripped
+pub async fn watch(
+    api_addr: SocketAddr,
+    schema_paths: Vec<Utf8PathBuf>,
+    mut tripwire: Tripwire,
+) -> eyre::Result<()> {
+    let client = CorrosionApiClient::new(api_addr);
+
+    let (mut debouncer, mut rx) = async_watcher()?;
+
+    // files are often replaced rather than written to, watch their directory
+    let mut watched = HashSet::new();
+    for path in schema_paths.iter() {
+        let dir = if path.is_dir() {
+            path.as_path()
+        } else {
+            match path.parent() {
+                Some(parent) if !parent.as_str().is_empty() => parent,
+                _ => Utf8Path::new("."),
+            }
+        };
+        if watched.insert(dir.to_owned()) {
+            debouncer
+                .watcher()
+                .watch(dir.as_std_path(), RecursiveMode::NonRecursive)?;
+        }
+    }
+
+    info!("Watching schema files for changes");
+
+    loop {
+        let events = tokio::select! {
+            res = rx.recv() => match res {
+                Some(Ok(events)) => events,
+                Some(Err(e)) => {
+                    error!("could not watch schema files: {e:?}");
+                    continue;
+                }
+                None => break,
+            },
+            _ = &mut tripwire => break,
+        };
+
+        if !events
+            .iter()
+            .any(|event| is_schema_file(&schema_paths, &event.path))
+        {
+            continue;
+        }
+
+        info!("Schema files changed, applying schema");
+        match client.schema_from_paths(&schema_paths).await {
+            Ok(Some(res)) => {
+                info!("Applied schema in {}s", res.time);
+            }
+            Ok(None) => {
+                info!("No schema files to apply, skipping.");
+            }
+            Err(e) => {
+                error!("could not apply schema: {e}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `path` is one of the files read by `schema_from_paths`: a schema
+/// path itself or a `.sql` file directly under one. Paths are compared once
+/// resolved, `schema.sql` is `./schema.sql`.
+fn is_schema_file(schema_paths: &[Utf8PathBuf], path: &Path) -> bool {
+    let path = resolve(path);
+    schema_paths.iter().any(|schema_path| {
+        let schema_path = resolve(schema_path.as_std_path());
+        path == schema_path
+            || (path.extension().is_some_and(|ext| ext == "sql")
+                && path.parent() == Some(schema_path.as_path()))
+    })
+}
+
+/// Canonical `path`, removed files are resolved through their directory
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(resolved) = path.canonicalize() {
+        return resolved;
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_owned();
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    match parent.canonicalize() {
+        Ok(parent) => parent.join(name),
+        Err(_) => path.to_owned(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use super::*;
 
     use corro_tests::launch_test_agent;
     use spawn::wait_for_all_pending_handles;
-    use tripwire::Tripwire;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn basic_operations() -> eyre::Result<()> {
@@ -56,4 +166,61 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn relative_schema_files() -> eyre::Result<()> {
+        let cwd = std::env::current_dir()?;
+
+        let schema_paths = vec![Utf8PathBuf::from("Cargo.toml")];
+        assert!(is_schema_file(&schema_paths, Path::new("./Cargo.toml")));
+        assert!(is_schema_file(&schema_paths, &cwd.join("Cargo.toml")));
+
+        // removed files don't exist anymore
+        let schema_paths = vec![Utf8PathBuf::from(".")];
+        assert!(is_schema_file(&schema_paths, Path::new("./gone.sql")));
+        assert!(is_schema_file(&schema_paths, &cwd.join("gone.sql")));
+        assert!(!is_schema_file(&schema_paths, &cwd.join("src/gone.sql")));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn watch_schema_files() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let schema_path = ta.tmpdir.path().join("schema2");
+        tokio::fs::create_dir_all(&schema_path).await?;
+
+        let schema_paths = vec![Utf8PathBuf::try_from(schema_path.clone())?];
+        assert!(is_schema_file(&schema_paths, &schema_path.join("blah.sql")));
+        assert!(!is_schema_file(
+            &schema_paths,
+            &schema_path.join("blah.sql.swp")
+        ));
+
+        tokio::spawn(watch(ta.agent.api_addr(), schema_paths, tripwire.clone()));
+        // give the watcher a chance to start
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        tokio::fs::write(
+            schema_path.join("blah.sql"),
+            b"CREATE TABLE blah (id BIGINT NOT NULL PRIMARY KEY);",
+        )
+        .await?;
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !ta.agent.schema().read().tables.contains_key("blah") {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
 }
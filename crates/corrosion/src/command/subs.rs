@@ -0,0 +1,120 @@
+//! Move long-lived subscriptions between nodes: definitions dumped from a
+//! node's admin socket are created again through another node's API, so they
+//! are hydrated before clients switch over.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use corro_admin::{Command, SubsCommand};
+use corro_api_types::TypedQueryEvent;
+use corro_client::CorrosionApiClient;
+use corro_types::{
+    api::Statement,
+    pubsub::{Handoff, SubDefinition},
+};
+use futures::StreamExt;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::admin::AdminConn;
+
+pub async fn load(client: &CorrosionApiClient, path: &Utf8Path) -> eyre::Result<()> {
+    let definitions: Vec<SubDefinition> = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+
+    info!("Loading {} subscriptions", definitions.len());
+
+    let mut failed = 0;
+    for def in definitions {
+        if create(client, &def).await.is_none() {
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        eyre::bail!("could not load {failed} subscriptions");
+    }
+
+    Ok(())
+}
+
+/// Creates the subscriptions of the node listening on `admin_path` on the
+/// node at `to`, then hands them off: their listeners are told to reconnect
+/// there. Subscriptions that could not be created are left alone, they end
+/// when this node shuts down.
+pub async fn drain(admin_path: Utf8PathBuf, to: SocketAddr) -> eyre::Result<()> {
+    let dumped = AdminConn::connect(&admin_path)
+        .await?
+        .json_command(Command::Subs(SubsCommand::Dump))
+        .await?;
+    let definitions: Vec<SubDefinition> = match dumped.into_iter().next() {
+        Some(json) => serde_json::from_value(json)?,
+        None => eyre::bail!("admin did not return subscription definitions"),
+    };
+
+    info!("Creating {} subscriptions on {to}", definitions.len());
+
+    let client = CorrosionApiClient::new(to);
+    let mut ids = HashMap::with_capacity(definitions.len());
+    for def in definitions.iter() {
+        if let Some(new_id) = create(&client, def).await {
+            ids.insert(def.id, new_id);
+        }
+    }
+    let failed = definitions.len() - ids.len();
+
+    AdminConn::connect(&admin_path)
+        .await?
+        .send_command(Command::Subs(SubsCommand::Handoff(Handoff {
+            addr: to,
+            ids,
+        })))
+        .await?;
+
+    if failed > 0 {
+        eyre::bail!("could not hand off {failed} subscriptions");
+    }
+
+    Ok(())
+}
+
+/// Creates a subscription and waits for it to be hydrated, returning its id
+async fn create(client: &CorrosionApiClient, def: &SubDefinition) -> Option<Uuid> {
+    let stmt = Statement::Simple(def.sql.clone());
+    let res = if def.by_hash {
+        client.subscribe_by_hash(&stmt, true).await
+    } else {
+        client.subscribe(&stmt, true, None).await
+    };
+
+    let mut sub = match res {
+        Ok(sub) => sub,
+        Err(e) => {
+            warn!(id = %def.id, "could not create subscription: {e}");
+            return None;
+        }
+    };
+
+    // the subscription keeps running without listeners until it's idle
+    // for too long, only wait for it to be hydrated
+    loop {
+        match sub.next().await {
+            Some(Ok(TypedQueryEvent::EndOfQuery { .. })) => break,
+            Some(Ok(TypedQueryEvent::Error(e))) => {
+                warn!(id = %def.id, "subscription failed: {e}");
+                return None;
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => {
+                warn!(id = %def.id, "could not read subscription: {e}");
+                return None;
+            }
+            None => {
+                warn!(id = %def.id, "subscription ended before its initial query was done");
+                return None;
+            }
+        }
+    }
+
+    info!(id = %def.id, new_id = %sub.id(), "Created subscription");
+    Some(sub.id())
+}
@@ -24,6 +24,9 @@ use uuid::Uuid;
 pub struct TemplateFlags {
     #[arg(short, long)]
     once: bool,
+    /// Fail rendering on NULL values and missing object map properties
+    #[arg(long)]
+    strict: bool,
 }
 
 pub async fn run(
@@ -85,6 +88,7 @@ pub async fn run(
         let client = client.clone();
 
         let once = flags.once;
+        let strict = flags.strict;
 
         futs.push(async move {
             let mut checksum = crc32fast::hash(input.as_bytes());
@@ -110,10 +114,15 @@ pub async fn run(
                 let cancel = CancellationToken::new();
 
                 let _drop_cancel = cancel.clone().drop_guard();
-                let state = TemplateState { cmd_tx, cancel };
+                let state = TemplateState {
+                    cmd_tx,
+                    cancel,
+                    strict,
+                };
 
                 let rhai_engine = tpl.evaluator_mut();
                 rhai_engine.set_default_tag(Dynamic::from(state.clone()));
+                corro_tpl::set_strict(rhai_engine, strict);
 
                 debug!("rendering template...");
 
@@ -238,8 +247,8 @@ pub async fn run(
     Ok(())
 }
 
-fn async_watcher() -> notify::Result<(Debouncer<RecommendedWatcher>, Receiver<DebounceEventResult>)>
-{
+pub(crate) fn async_watcher(
+) -> notify::Result<(Debouncer<RecommendedWatcher>, Receiver<DebounceEventResult>)> {
     let (tx, rx) = channel(1);
 
     // Automatically select the best implementation for your platform.
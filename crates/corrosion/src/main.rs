@@ -41,6 +41,7 @@ use uuid::Uuid;
 
 pub mod admin;
 pub mod command;
+pub mod statsd;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -350,6 +351,12 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
             ))
             .await?;
         }
+        Command::Bridge => match cli.config()?.bridge.as_ref() {
+            Some(bridge) => command::bridge::run(bridge, cli.api_addr()?).await?,
+            None => {
+                error!("missing `bridge` block in corrosion config");
+            }
+        },
         Command::Consul(cmd) => match cmd {
             ConsulCommand::Sync => match cli.config()?.consul.as_ref() {
                 Some(consul) => {
@@ -360,6 +367,14 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
                 }
             },
         },
+        Command::Haproxy(cmd) => match cmd {
+            HaproxyCommand::Sync => match cli.config()?.haproxy.as_ref() {
+                Some(haproxy) => command::haproxy::run(haproxy, cli.api_addr()?).await?,
+                None => {
+                    error!("missing `haproxy` block in corrosion config");
+                }
+            },
+        },
         Command::Query {
             query,
             columns: show_columns,
@@ -398,9 +413,20 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
                             println!("time: {time}s");
                         }
                     }
-                    Ok(QueryEvent::Change(_, _, _, _)) => {
+                    Ok(QueryEvent::Change(..)) => {
                         break;
                     }
+                    Ok(
+                        QueryEvent::Estimate { .. }
+                        | QueryEvent::Progress { .. }
+                        | QueryEvent::Meta(_, _)
+                        | QueryEvent::Previous(_, _)
+                        | QueryEvent::Resync { .. }
+                        | QueryEvent::Dropped { .. }
+                        | QueryEvent::Skipped { .. }
+                        | QueryEvent::Closed { .. }
+                        | QueryEvent::Moved { .. },
+                    ) => {}
                     Ok(QueryEvent::Error(e)) => {
                         eyre::bail!("{e}");
                     }
@@ -431,13 +457,32 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
                     ExecResult::Execute {
                         rows_affected,
                         time,
+                        ..
+                    } => {
+                        info!("Rows affected: {rows_affected}");
+                        if *timer {
+                            println!("Run Time: real {time}");
+                        }
+                    }
+                    ExecResult::Returning {
+                        rows_affected,
+                        rows,
+                        time,
+                        ..
                     } => {
                         info!("Rows affected: {rows_affected}");
+                        info!("Rows returned: {}", rows.len());
                         if *timer {
                             println!("Run Time: real {time}");
                         }
                     }
-                    ExecResult::Error { error } => {
+                    ExecResult::Query { rows, time, .. } => {
+                        info!("Rows returned: {}", rows.len());
+                        if *timer {
+                            println!("Run Time: real {time}");
+                        }
+                    }
+                    ExecResult::Error { error, .. } => {
                         error!("{error}");
                     }
                 }
@@ -461,6 +506,9 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
         Command::Template { template, flags } => {
             command::tpl::run(cli.api_addr()?, template, flags).await?;
         }
+        Command::Generate { language, output } => {
+            command::generate::run(cli.api_addr()?, *language, output.as_ref()).await?;
+        }
         Command::Tls(tls) => match tls {
             TlsCommand::Ca(TlsCaCommand::Generate) => generate_ca(std::env::current_dir()?).await?,
             TlsCommand::Server(TlsServerCommand::Generate {
@@ -482,11 +530,48 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
             ))
             .await?;
         }
+        Command::Actor(ActorCommand::Gaps) => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Actor(corro_admin::ActorCommand::Gaps))
+                .await?;
+        }
         Command::CompactEmpties => {
             let mut conn = AdminConn::connect(cli.admin_path()).await?;
             conn.send_command(corro_admin::Command::CompactEmpties)
                 .await?;
         }
+        Command::Schema(SchemaCommand::Resync) => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Schema(
+                corro_admin::SchemaCommand::Resync,
+            ))
+            .await?;
+        }
+        Command::Subs(SubsCommand::Dump) => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Subs(corro_admin::SubsCommand::Dump))
+                .await?;
+        }
+        Command::Subs(SubsCommand::Load { path }) => {
+            command::subs::load(&cli.api_client()?, path).await?
+        }
+        Command::Subs(SubsCommand::Drain { to }) => {
+            command::subs::drain(cli.admin_path(), *to).await?
+        }
+        Command::Doctor => command::doctor::run(&cli.config_path).await?,
+        Command::Diff { peer, table, limit } => {
+            command::diff::run(
+                &cli.api_client()?,
+                &CorrosionApiClient::new(*peer),
+                table.clone(),
+                *limit,
+            )
+            .await?
+        }
+        Command::Tail => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Tail).await?;
+        }
     }
 
     Ok(())
@@ -598,10 +683,17 @@ enum Command {
     #[command(subcommand)]
     Cluster(ClusterCommand),
 
+    /// Replicates tables with another cluster, per the `bridge` config block
+    Bridge,
+
     /// Consul interactions
     #[command(subcommand)]
     Consul(ConsulCommand),
 
+    /// HAProxy interactions
+    #[command(subcommand)]
+    Haproxy(HaproxyCommand),
+
     /// Query data from Corrosion w/ a SQL statement
     Query {
         query: String,
@@ -644,12 +736,47 @@ enum Command {
         flags: TemplateFlags,
     },
 
+    /// Generate typed code from the current schema
+    Generate {
+        #[arg(value_enum)]
+        language: command::generate::Language,
+        /// Write the generated code to a file instead of stdout
+        #[arg(long, short)]
+        output: Option<Utf8PathBuf>,
+    },
+
     /// Tls-related commands
     #[command(subcommand)]
     Tls(TlsCommand),
 
     /// Clear overwritten versions
     CompactEmpties,
+
+    /// Schema-related commands
+    #[command(subcommand)]
+    Schema(SchemaCommand),
+
+    /// Subscription-related commands
+    #[command(subcommand)]
+    Subs(SubsCommand),
+
+    /// Check for common misconfigurations
+    Doctor,
+
+    /// Compare the data of this node with a peer's, to check they converged
+    Diff {
+        /// API address of the peer
+        peer: SocketAddr,
+        /// Only compare these tables, every table by default
+        #[arg(long)]
+        table: Vec<String>,
+        /// Maximum number of primary keys listed per kind of difference
+        #[arg(long, default_value = "10")]
+        limit: usize,
+    },
+
+    /// Stream replication activity (commits, applied changes, syncs and errors)
+    Tail,
 }
 
 #[derive(Subcommand)]
@@ -672,16 +799,44 @@ enum ConsulCommand {
     Sync,
 }
 
+#[derive(Subcommand)]
+enum HaproxyCommand {
+    /// Keeps HAProxy servers in sync with the rows of the configured query
+    Sync,
+}
+
 #[derive(Subcommand)]
 enum SyncCommand {
     /// Generate a sync message from the current agent
     Generate,
 }
 
+#[derive(Subcommand)]
+enum SchemaCommand {
+    /// Rebuild the schema from the database, after changes made without the agent
+    Resync,
+}
+
+#[derive(Subcommand)]
+enum SubsCommand {
+    /// Print the definitions of the running subscriptions, as JSON
+    Dump,
+    /// Create subscriptions from definitions printed by `subs dump`
+    Load { path: Utf8PathBuf },
+    /// Create the running subscriptions on another node, then tell their
+    /// listeners to reconnect there, before shutting this node down
+    Drain {
+        /// API address of the node taking over
+        to: SocketAddr,
+    },
+}
+
 #[derive(Subcommand)]
 enum ActorCommand {
     /// Get information about a known version
     Version { actor_id: Uuid, version: u64 },
+    /// List versions missing from remote actors, and for how long
+    Gaps,
 }
 
 #[derive(Subcommand)]
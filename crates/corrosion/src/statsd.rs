@@ -0,0 +1,472 @@
+//! Metrics sink pushing to a statsd (or dogstatsd) agent over UDP, for
+//! fleets that can't scrape Prometheus. Values are aggregated in memory and
+//! flushed periodically, labels are sent as DogStatsD tags.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use corro_types::config::StatsdConfig;
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+/// Stay under the usual MTU, datagrams are not fragmented
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Most values sent per histogram and flush, past it values are sampled
+const MAX_HISTOGRAM_SAMPLES: usize = 512;
+
+#[derive(Clone)]
+pub struct StatsdRecorder {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    prefix: String,
+    // preformatted global tags, `k:v` comma-separated
+    tags: String,
+    metrics: Mutex<HashMap<Key, Metric>>,
+}
+
+enum Metric {
+    Counter(Arc<StatsdCounter>),
+    Gauge(Arc<StatsdGauge>),
+    Histogram(Arc<StatsdHistogram>),
+}
+
+#[derive(Default)]
+struct StatsdCounter {
+    pending: AtomicU64,
+    total: AtomicU64,
+}
+
+impl CounterFn for StatsdCounter {
+    fn increment(&self, value: u64) {
+        self.pending.fetch_add(value, Ordering::Relaxed);
+        self.total.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn absolute(&self, value: u64) {
+        let prev = self.total.swap(value, Ordering::Relaxed);
+        self.pending
+            .fetch_add(value.saturating_sub(prev), Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct StatsdGauge {
+    bits: AtomicU64,
+    set: AtomicBool,
+}
+
+impl StatsdGauge {
+    fn update<F: Fn(f64) -> f64>(&self, f: F) {
+        _ = self
+            .bits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(f(f64::from_bits(bits)).to_bits())
+            });
+        self.set.store(true, Ordering::Relaxed);
+    }
+}
+
+impl GaugeFn for StatsdGauge {
+    fn increment(&self, value: f64) {
+        self.update(|prev| prev + value)
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|prev| prev - value)
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value)
+    }
+}
+
+#[derive(Default)]
+struct StatsdHistogram {
+    samples: Mutex<Samples>,
+}
+
+/// Uniform sample of the values recorded since the last flush
+#[derive(Default)]
+struct Samples {
+    values: Vec<f64>,
+    recorded: u64,
+}
+
+impl HistogramFn for StatsdHistogram {
+    fn record(&self, value: f64) {
+        let mut samples = self.samples.lock();
+        samples.recorded += 1;
+        if samples.values.len() < MAX_HISTOGRAM_SAMPLES {
+            samples.values.push(value);
+        } else {
+            // reservoir sampling, every value has the same chance to be kept
+            let i = rand::thread_rng().gen_range(0..samples.recorded) as usize;
+            if let Some(slot) = samples.values.get_mut(i) {
+                *slot = value;
+            }
+        }
+    }
+}
+
+impl StatsdRecorder {
+    pub fn new(config: &StatsdConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                prefix: config.prefix.clone().unwrap_or_default(),
+                tags: config
+                    .tags
+                    .iter()
+                    .map(|(k, v)| format!("{}:{}", sanitize(k), sanitize(v)))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                metrics: Default::default(),
+            }),
+        }
+    }
+
+    /// Sends aggregated metrics to `addr` every `interval`
+    pub fn spawn_flusher(&self, addr: SocketAddr, interval: Duration) {
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            let bind_addr: SocketAddr = if addr.is_ipv6() {
+                "[::]:0".parse().unwrap()
+            } else {
+                "0.0.0.0:0".parse().unwrap()
+            };
+            let socket = match UdpSocket::bind(bind_addr).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("could not bind statsd socket: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = socket.connect(addr).await {
+                warn!("could not connect statsd socket to {addr}: {e}");
+                return;
+            }
+
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                for datagram in inner.render() {
+                    // dropped datagrams are expected from time to time with UDP
+                    if let Err(e) = socket.send(datagram.as_bytes()).await {
+                        debug!("could not send statsd datagram: {e}");
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Inner {
+    fn line(&self, buf: &mut String, key: &Key, value: impl std::fmt::Display, kind: &str) {
+        _ = write!(buf, "{}{}:{value}|{kind}", self.prefix, key.name());
+
+        let mut sep = "|#";
+        for label in key.labels() {
+            _ = write!(
+                buf,
+                "{sep}{}:{}",
+                sanitize(label.key()),
+                sanitize(label.value())
+            );
+            sep = ",";
+        }
+        if !self.tags.is_empty() {
+            _ = write!(buf, "{sep}{}", self.tags);
+        }
+    }
+
+    /// Takes what accumulated since the last flush, as datagrams of
+    /// newline-separated lines
+    fn render(&self) -> Vec<String> {
+        let mut lines = vec![];
+        {
+            let metrics = self.metrics.lock();
+            for (key, metric) in metrics.iter() {
+                let mut line = String::new();
+                match metric {
+                    Metric::Counter(counter) => {
+                        let value = counter.pending.swap(0, Ordering::Relaxed);
+                        if value == 0 {
+                            continue;
+                        }
+                        self.line(&mut line, key, value, "c");
+                        lines.push(line);
+                    }
+                    Metric::Gauge(gauge) => {
+                        if !gauge.set.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        let value = f64::from_bits(gauge.bits.load(Ordering::Relaxed));
+                        self.line(&mut line, key, value, "g");
+                        lines.push(line);
+                    }
+                    Metric::Histogram(histogram) => {
+                        let samples = std::mem::take(&mut *histogram.samples.lock());
+                        // the agent scales sampled values back up
+                        let kind = if samples.values.len() as u64 == samples.recorded {
+                            "h".to_owned()
+                        } else {
+                            format!(
+                                "h|@{}",
+                                samples.values.len() as f64 / samples.recorded as f64
+                            )
+                        };
+                        for value in samples.values {
+                            let mut line = String::new();
+                            self.line(&mut line, key, value, &kind);
+                            lines.push(line);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut datagrams = vec![];
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+                datagrams.push(std::mem::take(&mut datagram));
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+}
+
+fn sanitize(s: &str) -> String {
+    s.replace([',', '|', '#', ':', '\n'], "_")
+}
+
+impl Recorder for StatsdRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let mut metrics = self.inner.metrics.lock();
+        match metrics
+            .entry(key.clone())
+            .or_insert_with(|| Metric::Counter(Default::default()))
+        {
+            Metric::Counter(counter) => Counter::from_arc(counter.clone()),
+            _ => Counter::noop(),
+        }
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let mut metrics = self.inner.metrics.lock();
+        match metrics
+            .entry(key.clone())
+            .or_insert_with(|| Metric::Gauge(Default::default()))
+        {
+            Metric::Gauge(gauge) => Gauge::from_arc(gauge.clone()),
+            _ => Gauge::noop(),
+        }
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let mut metrics = self.inner.metrics.lock();
+        match metrics
+            .entry(key.clone())
+            .or_insert_with(|| Metric::Histogram(Default::default()))
+        {
+            Metric::Histogram(histogram) => Histogram::from_arc(histogram.clone()),
+            _ => Histogram::noop(),
+        }
+    }
+}
+
+/// Sends metrics to several recorders, e.g. Prometheus and statsd
+pub struct Fanout(pub Vec<Box<dyn Recorder + Send + Sync>>);
+
+struct FanoutCounter(Vec<Counter>);
+
+impl CounterFn for FanoutCounter {
+    fn increment(&self, value: u64) {
+        self.0.iter().for_each(|counter| counter.increment(value))
+    }
+
+    fn absolute(&self, value: u64) {
+        self.0.iter().for_each(|counter| counter.absolute(value))
+    }
+}
+
+struct FanoutGauge(Vec<Gauge>);
+
+impl GaugeFn for FanoutGauge {
+    fn increment(&self, value: f64) {
+        self.0.iter().for_each(|gauge| gauge.increment(value))
+    }
+
+    fn decrement(&self, value: f64) {
+        self.0.iter().for_each(|gauge| gauge.decrement(value))
+    }
+
+    fn set(&self, value: f64) {
+        self.0.iter().for_each(|gauge| gauge.set(value))
+    }
+}
+
+struct FanoutHistogram(Vec<Histogram>);
+
+impl HistogramFn for FanoutHistogram {
+    fn record(&self, value: f64) {
+        self.0.iter().for_each(|histogram| histogram.record(value))
+    }
+}
+
+impl Recorder for Fanout {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        for recorder in self.0.iter() {
+            recorder.describe_counter(key.clone(), unit, description.clone());
+        }
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        for recorder in self.0.iter() {
+            recorder.describe_gauge(key.clone(), unit, description.clone());
+        }
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        for recorder in self.0.iter() {
+            recorder.describe_histogram(key.clone(), unit, description.clone());
+        }
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        Counter::from_arc(Arc::new(FanoutCounter(
+            self.0
+                .iter()
+                .map(|recorder| recorder.register_counter(key, metadata))
+                .collect(),
+        )))
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(Arc::new(FanoutGauge(
+            self.0
+                .iter()
+                .map(|recorder| recorder.register_gauge(key, metadata))
+                .collect(),
+        )))
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        Histogram::from_arc(Arc::new(FanoutHistogram(
+            self.0
+                .iter()
+                .map(|recorder| recorder.register_histogram(key, metadata))
+                .collect(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use metrics::Label;
+
+    use super::*;
+
+    #[test]
+    fn test_statsd_render() {
+        let recorder = StatsdRecorder::new(&StatsdConfig {
+            addr: "127.0.0.1:8125".parse().unwrap(),
+            prefix: Some("corrosion.".into()),
+            tags: BTreeMap::from([("region".to_owned(), "ord".to_owned())]),
+            flush_interval_ms: 1000,
+        });
+        let metadata = Metadata::new("test", metrics::Level::INFO, None);
+
+        let counter = recorder.register_counter(
+            &Key::from_parts("corro.changes", vec![Label::new("source", "local")]),
+            &metadata,
+        );
+        counter.increment(3);
+        counter.increment(2);
+
+        recorder
+            .register_gauge(&Key::from_name("corro.members"), &metadata)
+            .set(4.0);
+
+        let histogram = recorder.register_histogram(&Key::from_name("corro.latency"), &metadata);
+        histogram.record(0.5);
+        histogram.record(1.5);
+
+        let datagrams = recorder.inner.render();
+        assert_eq!(datagrams.len(), 1);
+        let mut lines: Vec<&str> = datagrams[0].lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "corrosion.corro.changes:5|c|#source:local,region:ord",
+                "corrosion.corro.latency:0.5|h|#region:ord",
+                "corrosion.corro.latency:1.5|h|#region:ord",
+                "corrosion.corro.members:4|g|#region:ord",
+            ]
+        );
+
+        // counters and histograms restart from scratch, gauges keep their value
+        let datagrams = recorder.inner.render();
+        assert_eq!(datagrams, vec!["corrosion.corro.members:4|g|#region:ord"]);
+    }
+    #[test]
+    fn test_statsd_histogram_sampling() {
+        let recorder = StatsdRecorder::new(&StatsdConfig {
+            addr: "127.0.0.1:8125".parse().unwrap(),
+            prefix: None,
+            tags: BTreeMap::new(),
+            flush_interval_ms: 1000,
+        });
+        let metadata = Metadata::new("test", metrics::Level::INFO, None);
+
+        let histogram = recorder.register_histogram(&Key::from_name("corro.latency"), &metadata);
+        for _ in 0..MAX_HISTOGRAM_SAMPLES * 4 {
+            histogram.record(1.0);
+        }
+
+        let lines: Vec<String> = recorder
+            .inner
+            .render()
+            .iter()
+            .flat_map(|datagram| datagram.lines().map(ToOwned::to_owned).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(lines.len(), MAX_HISTOGRAM_SAMPLES);
+        assert!(lines.iter().all(|line| line == "corro.latency:1|h|@0.25"));
+
+        // samples restart from scratch after a flush
+        histogram.record(2.0);
+        assert_eq!(recorder.inner.render(), vec!["corro.latency:2|h"]);
+    }
+}
@@ -1,17 +1,137 @@
 //! Keep track of how many futures of a certain kind are running (in an [AtomicUsize])
+//! and which tasks are still alive (in a registry), to report the ones
+//! holding up shutdown.
 
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::Location;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::Future;
 use pin_project_lite::pin_project;
-use tracing::{info, trace};
+use tracing::{debug, info, trace, warn};
 
 /// Global counter for [spawn_counted] and [spawn_counted_w_handle]
 pub static PENDING_HANDLES: AtomicUsize = AtomicUsize::new(0);
 
+/// How long [wait_for_all_pending_handles] waits before giving up
+pub const DEFAULT_SHUTDOWN_DEADLINE: Duration = Duration::from_secs(60);
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+static TASKS: Mutex<BTreeMap<u64, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+/// What a task is expected to do when the process shuts down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// The task is [Tripwire]-aware and must complete before exiting,
+    /// it counts towards [PENDING_HANDLES].
+    Graceful,
+    /// The task can be dropped along with the runtime (e.g. it is tied to
+    /// a client connection), it is only tracked for reporting.
+    Abortable,
+}
+
+/// A task known to the registry
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: &'static str,
+    pub location: &'static Location<'static>,
+    pub shutdown: Shutdown,
+    pub spawned_at: Instant,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (spawned at {}, running for {:?})",
+            self.name,
+            self.location,
+            self.spawned_at.elapsed()
+        )
+    }
+}
+
+/// Every task that hasn't completed (or been dropped) yet, oldest first
+pub fn running_tasks() -> Vec<TaskInfo> {
+    TASKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// Registers a task on creation, unregisters it when dropped
+struct TaskGuard {
+    id: u64,
+}
+
+impl TaskGuard {
+    fn register(
+        name: &'static str,
+        shutdown: Shutdown,
+        location: &'static Location<'static>,
+    ) -> Self {
+        let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+        TASKS.lock().unwrap_or_else(|e| e.into_inner()).insert(
+            id,
+            TaskInfo {
+                id,
+                name,
+                location,
+                shutdown,
+                spawned_at: Instant::now(),
+            },
+        );
+        Self { id }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        TASKS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+pin_project! {
+    /// A future registered in the task registry for as long as it lives
+    pub struct TrackedFut<F> {
+        #[pin]
+        fut: F,
+        _guard: TaskGuard,
+    }
+}
+
+impl<F> TrackedFut<F> {
+    #[track_caller]
+    pub fn new(fut: F, name: &'static str, shutdown: Shutdown) -> Self {
+        Self {
+            fut,
+            _guard: TaskGuard::register(name, shutdown, Location::caller()),
+        }
+    }
+}
+
+impl<F> Future for TrackedFut<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        self.project().fut.poll(cx)
+    }
+}
+
 /// Spawn `fut` as a [CountedFut] (increments/decrements an [AtomicUsize])
 #[track_caller]
 pub fn spawn_counted<F>(fut: F) -> tokio::task::JoinHandle<F::Output>
@@ -19,7 +139,27 @@ where
     F: Future + Send + 'static,
     F::Output: Send,
 {
-    tokio::spawn(CountedFut::new(fut, &PENDING_HANDLES))
+    spawn_named("unnamed", Shutdown::Graceful, fut)
+}
+
+/// Spawn `fut` under a `name` in the task registry. [Shutdown::Graceful]
+/// tasks are also spawned as [CountedFut] and waited on by
+/// [wait_for_all_pending_handles].
+#[track_caller]
+pub fn spawn_named<F>(
+    name: &'static str,
+    shutdown: Shutdown,
+    fut: F,
+) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let fut = TrackedFut::new(fut, name, shutdown);
+    match shutdown {
+        Shutdown::Graceful => tokio::spawn(CountedFut::new(fut, &PENDING_HANDLES)),
+        Shutdown::Abortable => tokio::spawn(fut),
+    }
 }
 
 /// Spawn `fut` as a [CountedFut] (increments/decrements an [AtomicUsize])
@@ -33,7 +173,10 @@ where
     F: Future + Send + 'static,
     F::Output: Send,
 {
-    h.spawn(CountedFut::new(fut, &PENDING_HANDLES))
+    h.spawn(CountedFut::new(
+        TrackedFut::new(fut, "unnamed", Shutdown::Graceful),
+        &PENDING_HANDLES,
+    ))
 }
 
 /// Spawn blocking `fut` as a [CountedFut] (increments/decrements an [AtomicUsize])
@@ -47,7 +190,10 @@ where
     F: FnOnce() -> R + Send + 'static,
     R: Send + 'static,
 {
+    let task_guard =
+        TaskGuard::register("unnamed (blocking)", Shutdown::Graceful, Location::caller());
     h.spawn_blocking(move || {
+        let _task_guard = task_guard;
         let _guard = CountedGuard::new(&PENDING_HANDLES);
         func()
     })
@@ -112,17 +258,28 @@ where
 
 /// Waits for [PENDING_HANDLES] to reach zero. All counted futures must be
 /// [Tripwire]-aware, and the tripwire must've been tripped, otherwise this
-/// is just going to sleep for 600 seconds.
+/// is just going to sleep for [DEFAULT_SHUTDOWN_DEADLINE].
 pub async fn wait_for_all_pending_handles() {
+    wait_for_all_pending_handles_within(DEFAULT_SHUTDOWN_DEADLINE).await;
+}
+
+/// Waits up to `deadline` for [PENDING_HANDLES] to reach zero. Returns the
+/// [Shutdown::Graceful] tasks that did not terminate in time, which are
+/// also logged.
+pub async fn wait_for_all_pending_handles_within(deadline: Duration) -> Vec<TaskInfo> {
+    let started = Instant::now();
     let mut rounds = 0;
 
-    for _ in 0..600 {
+    loop {
         match PENDING_HANDLES.load(Ordering::SeqCst) {
             0 => {
                 info!("All spawned futures done!");
                 break;
             }
             n => {
+                if started.elapsed() >= deadline {
+                    break;
+                }
                 rounds += 1;
                 if rounds % 10 == 0 {
                     info!("Waiting on {n} spawned futures before exiting")
@@ -131,4 +288,27 @@ pub async fn wait_for_all_pending_handles() {
         }
         tokio::time::sleep(Duration::from_millis(100)).await;
     }
+
+    let (stuck, abortable): (Vec<_>, Vec<_>) = running_tasks()
+        .into_iter()
+        .partition(|task| task.shutdown == Shutdown::Graceful);
+
+    if !abortable.is_empty() {
+        debug!(
+            "{} abortable task(s) still running at shutdown",
+            abortable.len()
+        );
+    }
+
+    if !stuck.is_empty() {
+        warn!(
+            "{} task(s) did not terminate within {deadline:?}:",
+            stuck.len()
+        );
+        for task in stuck.iter() {
+            warn!("  {task}");
+        }
+    }
+
+    stuck
 }